@@ -1,7 +1,26 @@
+use std::env;
+
 fn main() {
     // CUDA FFI bindings
     #[cfg(feature = "nvenc")]
     println!("cargo:rustc-link-lib=dylib=cuda");
     #[cfg(feature = "nvenc")]
     println!("cargo:rustc-link-search=native=/usr/lib");
+
+    check_ffmpeg_headers();
+}
+
+/// ffmpeg-sys-next reports which ffmpeg releases the headers it was built against are at least
+/// as new as. Fail here with a readable message instead of with missing symbols further down.
+fn check_ffmpeg_headers() {
+    // Unset when ffmpeg-sys-next skipped its feature checks, e.g. for docs.rs builds
+    let Ok(ffmpeg_4_4) = env::var("DEP_FFMPEG_FFMPEG_4_4") else {
+        return;
+    };
+    if ffmpeg_4_4 != "true" {
+        panic!(
+            "waycap-rs needs the headers of ffmpeg 4.4 or newer, the ones found are older. \
+             Install a newer ffmpeg development package or point PKG_CONFIG_PATH at one."
+        );
+    }
 }
@@ -0,0 +1,18 @@
+/// Compares the quality of every available encoder backend on the same synthetic clip.
+/// Run with `cargo run --example backend_compare --features quality-harness [seconds]`
+use waycap_rs::{
+    quality::{compare_backends, CompareConfig},
+    types::error::Result,
+};
+
+fn main() -> Result<()> {
+    simple_logging::log_to_stderr(log::LevelFilter::Info);
+    let seconds = std::env::args()
+        .nth(1)
+        .and_then(|arg| arg.parse().ok())
+        .unwrap_or(5);
+
+    let report = compare_backends(&CompareConfig::default(), seconds)?;
+    print!("{report}");
+    Ok(())
+}
@@ -0,0 +1,52 @@
+/// Measures which resolutions and framerates every encoder sustains on this machine and prints
+/// them as a table, e.g. to attach to a bug report.
+/// Run with `cargo run --release --example benchmark --features benchmark [seconds]`
+use std::time::Duration;
+
+use waycap_rs::{
+    benchmark::{benchmark, BenchMatrix, BenchOutcome},
+    types::error::Result,
+};
+
+fn main() -> Result<()> {
+    simple_logging::log_to_stderr(log::LevelFilter::Warn);
+    let seconds = std::env::args()
+        .nth(1)
+        .and_then(|arg| arg.parse().ok())
+        .unwrap_or(5);
+    let matrix = BenchMatrix {
+        duration: Duration::from_secs(seconds),
+        ..Default::default()
+    };
+
+    let results = benchmark(&matrix)?;
+    println!(
+        "{:<14} {:>10} {:>4} {:>8} {:>8} {:>8} {:>8} {:>8} {:>5}  result",
+        "encoder", "size", "fps", "got", "p50", "p95", "p99", "cpu", "gpu"
+    );
+    for result in &results {
+        let ms = |duration: Duration| format!("{:.2}ms", duration.as_secs_f64() * 1000.0);
+        let outcome = match result.outcome {
+            BenchOutcome::KeptUp => "ok".to_string(),
+            BenchOutcome::FellBehind => "too slow".to_string(),
+            BenchOutcome::Failed(ref reason) => format!("failed: {reason}"),
+        };
+        let gpu = result
+            .gpu_busy
+            .map(|busy| format!("{busy:.0}%"))
+            .unwrap_or_else(|| "-".to_string());
+        println!(
+            "{:<14} {:>10} {:>4} {:>8.1} {:>8} {:>8} {:>8} {:>8} {:>5}  {outcome}",
+            result.encoder.display_name(),
+            format!("{}x{}", result.width, result.height),
+            result.framerate,
+            result.fps,
+            ms(result.latency.p50),
+            ms(result.latency.p95),
+            ms(result.latency.p99),
+            ms(result.cpu_time),
+            gpu,
+        );
+    }
+    Ok(())
+}
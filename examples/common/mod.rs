@@ -0,0 +1,415 @@
+//! Helpers shared by the examples: argument parsing, stopping on Ctrl+C, muxing and printing
+//! stats.
+#![allow(dead_code)]
+
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+
+use ffmpeg_next::{self as ffmpeg, Rational};
+use waycap_rs::{
+    pipeline::builder::CaptureBuilder,
+    types::{
+        audio_frame::EncodedAudioFrame,
+        config::{QualityPreset, VideoEncoder},
+        error::Result,
+        media_packet::TimedMetadata,
+        stats::{CaptureStats, FinishSummary},
+        time::{CaptureTime, StreamPts},
+        video_frame::EncodedVideoFrame,
+    },
+    Capture, DynamicEncoder,
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Args {
+    /// File path or URL to write to
+    pub output: String,
+    /// Stop after this long, runs until Ctrl+C when not set
+    pub duration: Option<Duration>,
+    pub quality: QualityPreset,
+    pub audio: bool,
+    /// Record a generated test pattern instead of asking the portal for a source, without audio
+    pub synthetic: bool,
+}
+
+pub const USAGE: &str = "[-o|--output <path>] [-d|--duration <seconds>] \
+     [-q|--quality low|medium|high|ultra] [--no-audio] [--synthetic]";
+
+/// Size of the test pattern recorded with `--synthetic`
+const SYNTHETIC_SIZE: (u32, u32) = (640, 360);
+
+/// Parse the command line arguments, without the program name
+pub fn parse_args(
+    args: impl IntoIterator<Item = String>,
+    default_output: Option<&str>,
+) -> std::result::Result<Args, String> {
+    let mut output = default_output.map(str::to_string);
+    let mut duration = None;
+    let mut quality = QualityPreset::Medium;
+    let mut audio = true;
+    let mut synthetic = false;
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{arg} needs a value"));
+        match arg.as_str() {
+            "-o" | "--output" => output = Some(value()?),
+            "-d" | "--duration" => {
+                let secs: f64 = value()?
+                    .parse()
+                    .map_err(|_| "duration must be a number of seconds".to_string())?;
+                if !secs.is_finite() || secs <= 0.0 {
+                    return Err("duration must be positive".to_string());
+                }
+                duration = Some(Duration::from_secs_f64(secs));
+            }
+            "-q" | "--quality" => {
+                let name = value()?;
+                quality = *QualityPreset::all()
+                    .iter()
+                    .find(|preset| preset.display_name().eq_ignore_ascii_case(&name))
+                    .ok_or(format!("unknown quality {name}"))?;
+            }
+            "--no-audio" => audio = false,
+            "--synthetic" => synthetic = true,
+            other => return Err(format!("unknown argument {other}")),
+        }
+    }
+
+    Ok(Args {
+        output: output.ok_or("--output is required")?,
+        duration,
+        quality,
+        audio: audio && !synthetic,
+        synthetic,
+    })
+}
+
+/// A builder for the quality and source of `args`. The test pattern of `--synthetic` is encoded
+/// with x264, which takes frames in memory.
+pub fn capture_builder(args: &Args) -> CaptureBuilder {
+    let builder = CaptureBuilder::new().with_quality_preset(args.quality);
+    if args.synthetic {
+        let (width, height) = SYNTHETIC_SIZE;
+        builder
+            .with_synthetic_source(width, height)
+            .with_video_encoder(VideoEncoder::H264Software)
+    } else {
+        builder
+    }
+}
+
+static STOP: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn request_stop(_signal: libc::c_int) {
+    STOP.store(true, Ordering::Release);
+}
+
+/// Make Ctrl+C and SIGTERM request a clean stop instead of killing the process, so the
+/// recording can be finished and the file is playable
+pub fn install_stop_handler() {
+    unsafe {
+        libc::signal(libc::SIGINT, request_stop as libc::sighandler_t);
+        libc::signal(libc::SIGTERM, request_stop as libc::sighandler_t);
+    }
+}
+
+/// Whether the recording should end, either because it was asked to or because it ran for
+/// `duration`
+pub fn should_stop(duration: Option<Duration>, started: Instant) -> bool {
+    STOP.load(Ordering::Acquire) || duration.is_some_and(|duration| started.elapsed() >= duration)
+}
+
+pub fn format_stats(stats: &CaptureStats) -> String {
+    let time_to_first_frame = stats
+        .time_to_first_frame
+        .map(|time| format!("{}ms", time.as_millis()))
+        .unwrap_or_else(|| "-".to_string());
+    format!(
+        "first frame after {time_to_first_frame}, video: {} captured, {} corrupted, {} encoded, \
+         {} duplicated, {} received, audio: {} received, {} dropped",
+        stats.frames_captured,
+        stats.frames_corrupted,
+        stats.frames_encoded,
+        stats.frames_duplicated,
+        stats.packets_consumed,
+        stats.audio_packets_consumed,
+        stats.audio_packets_dropped,
+    )
+}
+
+pub fn format_summary(summary: &FinishSummary) -> String {
+    format!(
+        "video {:.2}s, audio {:.2}s",
+        summary.video_duration.as_secs_f64(),
+        summary.audio_duration.as_secs_f64()
+    )
+}
+
+struct MuxedStream {
+    index: usize,
+    first_pts: Option<StreamPts>,
+}
+
+impl MuxedStream {
+    /// Timestamp relative to the first packet of the stream
+    fn relative(&mut self, pts: StreamPts) -> StreamPts {
+        pts - *self.first_pts.get_or_insert(pts)
+    }
+}
+
+/// Writes the packets of a capture into a container, e.g. an mp4 file or an flv RTMP stream
+pub struct Muxer {
+    output: ffmpeg::format::context::Output,
+    video: Option<MuxedStream>,
+    audio: Option<MuxedStream>,
+    /// Index of the data track for injected metadata
+    metadata: Option<usize>,
+}
+
+/// Containers which take a generic data track
+const DATA_TRACK_FORMATS: &[&str] = &["mpegts", "nut"];
+
+impl Muxer {
+    /// `format` is guessed from `path` when not given, `audio` must match whether the capture
+    /// was built with audio
+    pub fn new(
+        capture: &Capture<DynamicEncoder>,
+        path: &str,
+        format: Option<&str>,
+        audio: bool,
+    ) -> Result<Self> {
+        let mut output = match format {
+            Some(format) => ffmpeg::format::output_as(&path, format)?,
+            None => ffmpeg::format::output(&path)?,
+        };
+
+        let video = capture.with_video_encoder(|enc| {
+            let encoder = enc.as_ref()?;
+            let mut stream = output.add_stream(encoder.codec()?).ok()?;
+            stream.set_time_base(encoder.time_base());
+            stream.set_parameters(encoder);
+            Some(MuxedStream {
+                index: stream.index(),
+                first_pts: None,
+            })
+        });
+
+        let audio = if audio {
+            capture.with_audio_encoder(|enc| {
+                let encoder = enc.as_ref()?;
+                let mut stream = output.add_stream(encoder.codec()?).ok()?;
+                stream.set_time_base(encoder.time_base());
+                stream.set_parameters(encoder);
+                Some(MuxedStream {
+                    index: stream.index(),
+                    first_pts: None,
+                })
+            })
+        } else {
+            None
+        };
+
+        let metadata = if DATA_TRACK_FORMATS.contains(&output.format().name()) {
+            let mut stream = output.add_stream(None::<ffmpeg::Codec>)?;
+            let mut parameters = ffmpeg::codec::Parameters::new();
+            unsafe {
+                let parameters = parameters.as_mut_ptr();
+                (*parameters).codec_type = ffmpeg::ffi::AVMediaType::AVMEDIA_TYPE_DATA;
+                (*parameters).codec_id = ffmpeg::ffi::AVCodecID::AV_CODEC_ID_BIN_DATA;
+            }
+            stream.set_parameters(parameters);
+            stream.set_time_base(CaptureTime::TIME_BASE);
+            Some(stream.index())
+        } else {
+            None
+        };
+
+        output.write_header()?;
+
+        Ok(Self {
+            output,
+            video,
+            audio,
+            metadata,
+        })
+    }
+
+    pub fn write_video(&mut self, frame: &EncodedVideoFrame) -> Result<()> {
+        let Some(stream) = self.video.as_mut() else {
+            return Ok(());
+        };
+        let mut packet = ffmpeg::codec::packet::Packet::copy(&frame.data);
+        let pts = stream.relative(frame.pts);
+        let dts = frame.dts - stream.first_pts.unwrap_or(frame.dts);
+        packet.set_pts(Some(pts.value));
+        packet.set_dts(Some(dts.value));
+        if frame.is_keyframe {
+            packet.set_flags(ffmpeg::codec::packet::Flags::KEY);
+        }
+        Self::write(&mut self.output, packet, stream.index, pts.time_base)
+    }
+
+    pub fn write_audio(&mut self, frame: &EncodedAudioFrame) -> Result<()> {
+        let Some(stream) = self.audio.as_mut() else {
+            return Ok(());
+        };
+        let mut packet = ffmpeg::codec::packet::Packet::copy(&frame.data);
+        let pts = stream.relative(frame.pts);
+        packet.set_pts(Some(pts.value));
+        packet.set_dts(Some(pts.value));
+        packet.set_duration(frame.duration.rescale(pts.time_base).value);
+        Self::write(&mut self.output, packet, stream.index, pts.time_base)
+    }
+
+    /// Writes the key, a NUL byte and the data as one packet of the data track, timed relative
+    /// to the first video packet. Dropped when the container has no data track.
+    pub fn write_metadata(&mut self, metadata: &TimedMetadata) -> Result<()> {
+        let origin = self.video.as_ref().and_then(|stream| stream.first_pts);
+        let (Some(index), Some(origin)) = (self.metadata, origin) else {
+            return Ok(());
+        };
+        let origin = CaptureTime::from(origin);
+        if metadata.timestamp < origin {
+            return Ok(());
+        }
+        let mut payload = metadata.key.as_bytes().to_vec();
+        payload.push(0);
+        payload.extend_from_slice(&metadata.data);
+
+        let mut packet = ffmpeg::codec::packet::Packet::copy(&payload);
+        let pts = StreamPts::from_duration(metadata.timestamp - origin, CaptureTime::TIME_BASE);
+        packet.set_pts(Some(pts.value));
+        packet.set_dts(Some(pts.value));
+        Self::write(&mut self.output, packet, index, pts.time_base)
+    }
+
+    fn write(
+        output: &mut ffmpeg::format::context::Output,
+        mut packet: ffmpeg::codec::packet::Packet,
+        index: usize,
+        time_base: Rational,
+    ) -> Result<()> {
+        // The muxer may have picked a different time base for the stream in write_header
+        let stream_time_base = output.stream(index).unwrap().time_base();
+        packet.set_stream(index);
+        packet.rescale_ts(time_base, stream_time_base);
+        packet.write_interleaved(output)?;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<()> {
+        self.output.write_trailer()?;
+        Ok(())
+    }
+}
+
+/// Number of video packets in the file at `path`, to check what an example wrote
+pub fn count_video_packets(path: &str) -> Result<usize> {
+    let mut input = ffmpeg::format::input(&path)?;
+    let Some(index) = input
+        .streams()
+        .best(ffmpeg::media::Type::Video)
+        .map(|stream| stream.index())
+    else {
+        return Ok(0);
+    };
+    Ok(input
+        .packets()
+        .filter(|(stream, _)| stream.index() == index)
+        .count())
+}
+
+/// A path in the temporary directory for the output of an example's test, unique per process
+pub fn temp_output(name: &str) -> String {
+    std::env::temp_dir()
+        .join(format!("waycap-{}-{name}", std::process::id()))
+        .to_string_lossy()
+        .into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> std::result::Result<Args, String> {
+        parse_args(args.iter().map(|arg| arg.to_string()), Some("out.mp4"))
+    }
+
+    #[test]
+    fn parses_defaults() {
+        let parsed = args(&[]).unwrap();
+        assert_eq!(parsed.output, "out.mp4");
+        assert_eq!(parsed.duration, None);
+        assert!(matches!(parsed.quality, QualityPreset::Medium));
+        assert!(parsed.audio);
+        assert!(!parsed.synthetic);
+    }
+
+    #[test]
+    fn parses_all_options() {
+        let parsed = args(&[
+            "-o",
+            "a.mkv",
+            "--duration",
+            "2.5",
+            "-q",
+            "HIGH",
+            "--no-audio",
+        ])
+        .unwrap();
+        assert_eq!(parsed.output, "a.mkv");
+        assert_eq!(parsed.duration, Some(Duration::from_millis(2500)));
+        assert!(matches!(parsed.quality, QualityPreset::High));
+        assert!(!parsed.audio);
+    }
+
+    #[test]
+    fn synthetic_source_has_no_audio() {
+        let parsed = args(&["--synthetic"]).unwrap();
+        assert!(parsed.synthetic);
+        assert!(!parsed.audio);
+    }
+
+    #[test]
+    fn rejects_bad_arguments() {
+        assert!(args(&["--duration"]).is_err());
+        assert!(args(&["--duration", "-1"]).is_err());
+        assert!(args(&["--quality", "best"]).is_err());
+        assert!(args(&["--verbose"]).is_err());
+        assert!(parse_args(Vec::new(), None).is_err());
+    }
+
+    #[test]
+    fn signal_requests_stop() {
+        install_stop_handler();
+        let started = Instant::now();
+        assert!(!should_stop(None, started));
+        unsafe { libc::raise(libc::SIGTERM) };
+        assert!(should_stop(None, started));
+        STOP.store(false, Ordering::Release);
+    }
+
+    #[test]
+    fn stops_after_duration() {
+        let started = Instant::now() - Duration::from_secs(2);
+        assert!(should_stop(Some(Duration::from_secs(1)), started));
+    }
+
+    #[test]
+    fn formats_stats() {
+        let stats = CaptureStats {
+            time_to_first_frame: Some(Duration::from_millis(120)),
+            frames_captured: 10,
+            frames_encoded: 9,
+            packets_consumed: 8,
+            ..Default::default()
+        };
+        let text = format_stats(&stats);
+        assert!(text.starts_with("first frame after 120ms"));
+        assert!(text.contains("10 captured"));
+        assert!(text.contains("8 received"));
+    }
+}
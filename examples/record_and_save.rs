@@ -36,7 +36,7 @@ fn main() -> Result<()> {
         .with_cursor_shown()
         .build()?;
 
-    let video_recv = capture.get_video_receiver();
+    let video_recv = capture.get_video_receiver()?;
 
     // Use a BTree Map so it is sorted by DTS
     // needed like this for export time monotonic dts times
@@ -50,7 +50,7 @@ fn main() -> Result<()> {
                     capture_clone
                         .lock()
                         .unwrap()
-                        .insert(encoded_frame.dts, encoded_frame);
+                        .insert(encoded_frame.dts.value, encoded_frame);
                 }
                 Err(crossbeam::channel::RecvTimeoutError::Timeout) => {
                     continue;
@@ -143,14 +143,14 @@ fn save_buffer(
     let first_pts = video_buffer
         .values()
         .next()
-        .map(|frame| frame.pts)
+        .map(|frame| frame.pts.value)
         .unwrap_or(0);
 
     // Write video
     for frame in video_buffer.values() {
         let mut packet = ffmpeg_next::codec::packet::Packet::copy(&frame.data);
-        packet.set_pts(Some(frame.pts - first_pts));
-        packet.set_dts(Some(frame.dts - first_pts));
+        packet.set_pts(Some(frame.pts.value - first_pts));
+        packet.set_dts(Some(frame.dts.value - first_pts));
 
         // 0 = Video
         // 1 = Audio
@@ -160,12 +160,13 @@ fn save_buffer(
         packet.write_interleaved(&mut output)?;
     }
 
-    let first_pts = audio_buffer.first().map(|f| f.pts).unwrap_or(0);
+    let first_pts = audio_buffer.first().map(|f| f.pts.value).unwrap_or(0);
     // Write Audio
     for sample in audio_buffer {
         let mut packet = ffmpeg_next::codec::packet::Packet::copy(&sample.data);
-        packet.set_pts(Some(sample.pts - first_pts));
-        packet.set_dts(Some(sample.pts - first_pts));
+        packet.set_pts(Some(sample.pts.value - first_pts));
+        packet.set_dts(Some(sample.pts.value - first_pts));
+        packet.set_duration(sample.duration.value);
 
         packet.set_stream(1);
 
@@ -0,0 +1,82 @@
+//! Records the screen into an mp4 file until Ctrl+C or the given duration passed.
+//!
+//! `cargo run --example record_to_mp4 -- --output recording.mp4 --duration 10`
+mod common;
+
+use std::time::{Duration, Instant};
+
+use waycap_rs::types::error::Result;
+
+fn main() -> Result<()> {
+    simple_logging::log_to_stderr(log::LevelFilter::Info);
+    let args = match common::parse_args(std::env::args().skip(1), Some("recording.mp4")) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{e}\nUsage: record_to_mp4 {}", common::USAGE);
+            std::process::exit(2);
+        }
+    };
+    common::install_stop_handler();
+    run(&args)
+}
+
+fn run(args: &common::Args) -> Result<()> {
+    let mut builder = common::capture_builder(args).with_cursor_shown();
+    if args.audio {
+        builder = builder.with_audio().with_audio_trim();
+    }
+    let mut capture = builder.build()?;
+    let mut muxer = common::Muxer::new(&capture, &args.output, None, args.audio)?;
+
+    let video = capture.video_frames()?;
+    let audio = if args.audio {
+        Some(capture.audio_frames()?)
+    } else {
+        None
+    };
+
+    log::info!("Recording to {}, press Ctrl+C to stop", args.output);
+    capture.start()?;
+    let started = Instant::now();
+    while !common::should_stop(args.duration, started) {
+        if let Ok(frame) = video.recv_timeout(Duration::from_millis(50)) {
+            muxer.write_video(&frame)?;
+        }
+        for frame in audio.iter().flat_map(|audio| audio.try_iter()) {
+            muxer.write_audio(&frame)?;
+        }
+    }
+
+    let summary = capture.finish()?;
+    // Write what was still queued and what finishing flushed out of the encoders
+    for frame in video.try_iter() {
+        muxer.write_video(&frame)?;
+    }
+    for frame in audio.iter().flat_map(|audio| audio.try_iter()) {
+        muxer.write_audio(&frame)?;
+    }
+    muxer.finish()?;
+
+    log::info!("{}", common::format_stats(&capture.stats()));
+    log::info!(
+        "Saved {}: {}",
+        args.output,
+        common::format_summary(&summary)
+    );
+    capture.close()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_the_synthetic_source() {
+        let output = common::temp_output("recording.mp4");
+        let args = ["--synthetic", "-d", "1", "-o", &output].map(str::to_string);
+        run(&common::parse_args(args, None).unwrap()).unwrap();
+        let packets = common::count_video_packets(&output);
+        std::fs::remove_file(&output).unwrap();
+        assert!(packets.unwrap() > 0);
+    }
+}
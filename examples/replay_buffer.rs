@@ -0,0 +1,165 @@
+//! Keeps the last 30 seconds of the screen in memory and saves them whenever Enter is pressed,
+//! like the instant replay of game recorders.
+//!
+//! `cargo run --example replay_buffer -- --output replay`
+mod common;
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use crossbeam::channel::Receiver;
+use waycap_rs::{
+    types::{
+        audio_frame::EncodedAudioFrame, error::Result, time::CaptureTime,
+        video_frame::EncodedVideoFrame,
+    },
+    Capture, DynamicEncoder,
+};
+
+const REPLAY_LENGTH: Duration = Duration::from_secs(30);
+
+#[derive(Default)]
+struct ReplayBuffer {
+    video: VecDeque<EncodedVideoFrame>,
+    audio: VecDeque<EncodedAudioFrame>,
+}
+
+impl ReplayBuffer {
+    fn push_video(&mut self, frame: EncodedVideoFrame) {
+        self.video.push_back(frame);
+        self.trim();
+    }
+
+    fn push_audio(&mut self, frame: EncodedAudioFrame) {
+        self.audio.push_back(frame);
+    }
+
+    /// Drop whole GOPs from the front while the rest still covers the replay length, so the
+    /// buffer always starts with a keyframe
+    fn trim(&mut self) {
+        let Some(newest) = self.video.back().map(|frame| CaptureTime::from(frame.pts)) else {
+            return;
+        };
+        while let Some(next_keyframe) = self
+            .video
+            .iter()
+            .skip(1)
+            .position(|frame| frame.is_keyframe)
+            .map(|i| i + 1)
+        {
+            if newest - CaptureTime::from(self.video[next_keyframe].pts) < REPLAY_LENGTH {
+                break;
+            }
+            self.video.drain(..next_keyframe);
+        }
+
+        // Video pts convert to capture times, which audio packets carry as their timestamp
+        if let Some(start) = self.video.front().map(|frame| CaptureTime::from(frame.pts)) {
+            while self
+                .audio
+                .front()
+                .is_some_and(|frame| frame.timestamp < start)
+            {
+                self.audio.pop_front();
+            }
+        }
+    }
+
+    fn save(&self, capture: &Capture<DynamicEncoder>, path: &str, audio: bool) -> Result<()> {
+        let mut muxer = common::Muxer::new(capture, path, None, audio)?;
+        for frame in &self.video {
+            muxer.write_video(frame)?;
+        }
+        for frame in &self.audio {
+            muxer.write_audio(frame)?;
+        }
+        muxer.finish()
+    }
+}
+
+fn main() -> Result<()> {
+    simple_logging::log_to_stderr(log::LevelFilter::Info);
+    let args = match common::parse_args(std::env::args().skip(1), Some("replay")) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{e}\nUsage: replay_buffer {}", common::USAGE);
+            std::process::exit(2);
+        }
+    };
+    common::install_stop_handler();
+
+    let (save_tx, save_rx) = crossbeam::channel::unbounded();
+    std::thread::spawn(move || {
+        for _ in std::io::stdin().lines() {
+            if save_tx.send(()).is_err() {
+                break;
+            }
+        }
+    });
+    run(&args, save_rx).map(|_| ())
+}
+
+/// Saves the buffer for every message on `save_rx`, returns the number of saved replays
+fn run(args: &common::Args, save_rx: Receiver<()>) -> Result<usize> {
+    let mut builder = common::capture_builder(args).with_cursor_shown();
+    if args.audio {
+        builder = builder.with_audio();
+    }
+    let mut capture = builder.build()?;
+    let video = capture.video_frames()?;
+    let audio = if args.audio {
+        Some(capture.audio_frames()?)
+    } else {
+        None
+    };
+
+    log::info!("Press Enter to save the last {REPLAY_LENGTH:?}, Ctrl+C to quit");
+    capture.start()?;
+    let started = Instant::now();
+    let mut buffer = ReplayBuffer::default();
+    let mut saved = 0;
+    while !common::should_stop(args.duration, started) {
+        if let Ok(frame) = video.recv_timeout(Duration::from_millis(50)) {
+            buffer.push_video(frame);
+        }
+        for frame in audio.iter().flat_map(|audio| audio.try_iter()) {
+            buffer.push_audio(frame);
+        }
+
+        if save_rx.try_recv().is_ok() {
+            saved += 1;
+            let path = format!("{}-{saved}.mp4", args.output);
+            buffer.save(&capture, &path, args.audio)?;
+            log::info!("Saved {path}");
+        }
+    }
+
+    log::info!("{}", common::format_stats(&capture.stats()));
+    capture.close()?;
+    Ok(saved)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn saves_the_synthetic_source() {
+        let output = common::temp_output("replay");
+        let args = ["--synthetic", "-d", "2", "-o", &output].map(str::to_string);
+        let (save_tx, save_rx) = crossbeam::channel::unbounded();
+        // Once a few frames are buffered, as pressing Enter would
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(1200));
+            save_tx.send(()).unwrap();
+        });
+        let saved = run(&common::parse_args(args, None).unwrap(), save_rx).unwrap();
+        assert_eq!(saved, 1);
+        let path = format!("{output}-1.mp4");
+        let packets = common::count_video_packets(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(packets.unwrap() > 0);
+    }
+}
@@ -0,0 +1,43 @@
+//! Captures the screen for hours while sampling memory usage, failing when it keeps growing.
+//!
+//! `cargo run --release --example soak_test --features soak-test -- [minutes]`
+use std::{alloc::System, time::Duration};
+
+use waycap_rs::{
+    pipeline::builder::CaptureBuilder, run_soak, types::error::Result, CountingAllocator,
+    SoakConfig,
+};
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator::new(System);
+
+fn main() -> Result<()> {
+    simple_logging::log_to_stderr(log::LevelFilter::Info);
+    let minutes = std::env::args()
+        .nth(1)
+        .and_then(|arg| arg.parse().ok())
+        .unwrap_or(6 * 60);
+    let config = SoakConfig {
+        duration: Duration::from_secs(minutes * 60),
+        ..Default::default()
+    };
+
+    let mut capture = CaptureBuilder::new().with_audio().build()?;
+    // Consume the output like a muxer would, so nothing piles up in the receivers
+    let video = capture.video_frames()?;
+    let audio = capture.audio_frames()?;
+    let video_thread = std::thread::spawn(move || video.count());
+    let audio_thread = std::thread::spawn(move || audio.count());
+
+    capture.start()?;
+    let report = run_soak(&capture, &config)?;
+    capture.finish()?;
+    drop(capture);
+    let packets = video_thread.join().unwrap() + audio_thread.join().unwrap();
+
+    println!("{packets} packets over {minutes} minutes");
+    for growth in &report.growth {
+        println!("{:>24}: {:+.1}/h", growth.what, growth.per_hour);
+    }
+    report.check()
+}
@@ -0,0 +1,87 @@
+//! Streams the screen to an RTMP server, e.g. a local nginx-rtmp or a streaming service ingest.
+//!
+//! `cargo run --example stream_rtmp -- --output rtmp://localhost/live/stream`
+mod common;
+
+use std::time::{Duration, Instant};
+
+use crossbeam::channel::RecvTimeoutError;
+use waycap_rs::types::{error::Result, media_packet::MediaPacket};
+
+fn main() -> Result<()> {
+    simple_logging::log_to_stderr(log::LevelFilter::Info);
+    let args = match common::parse_args(std::env::args().skip(1), None) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{e}\nUsage: stream_rtmp {}", common::USAGE);
+            std::process::exit(2);
+        }
+    };
+    common::install_stop_handler();
+    run(&args)
+}
+
+fn run(args: &common::Args) -> Result<()> {
+    let mut builder = common::capture_builder(args)
+        .with_cursor_shown()
+        // Ingest servers expect a constant frame rate
+        .with_cfr(60)
+        .with_single_output();
+    if args.audio {
+        builder = builder.with_audio();
+    }
+    let mut capture = builder.build()?;
+    // RTMP carries FLV, which can't be guessed from the URL
+    let mut muxer = common::Muxer::new(&capture, &args.output, Some("flv"), args.audio)?;
+    let packets = capture.output()?;
+
+    log::info!("Streaming to {}, press Ctrl+C to stop", args.output);
+    capture.start()?;
+    let started = Instant::now();
+    let mut last_stats = Instant::now();
+    while !common::should_stop(args.duration, started) {
+        // Packets arrive ordered by capture time, so they can go out as they come
+        match packets.recv_timeout(Duration::from_millis(100)) {
+            Ok(MediaPacket::Video(frame)) => muxer.write_video(&frame)?,
+            Ok(MediaPacket::Audio(frame)) => muxer.write_audio(&frame)?,
+            Ok(MediaPacket::Metadata(metadata)) => muxer.write_metadata(&metadata)?,
+            Ok(MediaPacket::Event(event)) => log::info!("{:?}", event.event),
+            Ok(MediaPacket::Cursor(_)) => {}
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+
+        if last_stats.elapsed() >= Duration::from_secs(5) {
+            log::info!("{}", common::format_stats(&capture.stats()));
+            last_stats = Instant::now();
+        }
+    }
+
+    capture.finish()?;
+    for packet in packets.try_iter() {
+        match packet {
+            MediaPacket::Video(frame) => muxer.write_video(&frame)?,
+            MediaPacket::Audio(frame) => muxer.write_audio(&frame)?,
+            MediaPacket::Metadata(metadata) => muxer.write_metadata(&metadata)?,
+            MediaPacket::Cursor(_) | MediaPacket::Event(_) => {}
+        }
+    }
+    muxer.finish()?;
+    capture.close()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The FLV an ingest server would receive, written to a file instead
+    #[test]
+    fn streams_the_synthetic_source() {
+        let output = common::temp_output("stream.flv");
+        let args = ["--synthetic", "-d", "1", "-o", &output].map(str::to_string);
+        run(&common::parse_args(args, None).unwrap()).unwrap();
+        let packets = common::count_video_packets(&output);
+        std::fs::remove_file(&output).unwrap();
+        assert!(packets.unwrap() > 0);
+    }
+}
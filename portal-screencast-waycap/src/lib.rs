@@ -86,6 +86,50 @@ impl std::fmt::Display for PortalError {
 
 impl std::error::Error for PortalError {}
 
+/// Turn the response code of a portal request into an error: 1 means the user cancelled the
+/// dialog, any other non-zero code that the interaction was ended some other way.
+fn check_response(code: u32) -> Result<(), PortalError> {
+    match code {
+        0 => Ok(()),
+        1 => Err(PortalError::Cancelled),
+        code => Err(PortalError::Generic(format!(
+            "The portal ended the request with response {code}"
+        ))),
+    }
+}
+
+/// Identity and hints an application passes to the portal when opening a session.
+#[derive(Debug, Clone, Default)]
+pub struct SessionOptions {
+    /// Prefix of the request and session handle tokens, made of `[A-Za-z0-9_]`. Lets the
+    /// application recognise its sessions, e.g. in the portal's permission store.
+    pub handle_token: Option<String>,
+    /// App id to register the D-Bus connection under with the host portal registry
+    /// (`org.freedesktop.host.portal.Registry`), so unsandboxed applications show up with a
+    /// name instead of a blank entry. Ignored by portals older than 1.19 which lack it.
+    pub app_id: Option<String>,
+    /// Human readable purpose of the session, passed as the `purpose` option of
+    /// `SelectSources` for portals which show it in their dialog.
+    pub purpose: Option<String>,
+    /// Ask the compositor not to show its screen sharing indicator, passed as the
+    /// `disable_indicator` option of `SelectSources`. Only portals which trust the client honor
+    /// it, see `ActiveScreenCast::indicator_disabled`.
+    pub disable_indicator: bool,
+}
+
+/// How long the portal keeps the permission given in the dialog, see
+/// `ScreenCast::set_persist_mode`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PersistMode {
+    /// Ask again next time
+    #[default]
+    DoNot = 0,
+    /// Until the application exits
+    Application = 1,
+    /// Until the user revokes it
+    Persistent = 2,
+}
+
 /// An un-opened screencast session. This can be queried for the supported
 /// capture source types, and used to configure which source types to prompt
 /// for. Each `ScreenCast` can be mde active once by calling `start()`.
@@ -95,6 +139,10 @@ pub struct ScreenCast {
     multiple: bool,
     source_types: Option<SourceType>,
     cursor_mode: Option<CursorMode>,
+    purpose: Option<String>,
+    disable_indicator: bool,
+    restore_token: Option<String>,
+    persist_mode: PersistMode,
 }
 
 impl ScreenCast {
@@ -102,7 +150,23 @@ impl ScreenCast {
     ///
     /// Connects to D-Bus and initaialises a ScreenCast object.
     pub fn new() -> Result<Self, PortalError> {
-        let state = ConnectionState::open_new()?;
+        Self::with_options(SessionOptions::default())
+    }
+
+    /// Create a new ScreenCast Session carrying the identity and hints in `options`
+    pub fn with_options(options: SessionOptions) -> Result<Self, PortalError> {
+        let mut state = ConnectionState::open_new()?;
+        if let Some(token) = options.handle_token {
+            if token.is_empty() || !token.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                return Err(PortalError::Generic(format!(
+                    "Invalid handle token {token:?}, only [A-Za-z0-9_] is allowed"
+                )));
+            }
+            state.handle_prefix = token;
+        }
+        if let Some(app_id) = &options.app_id {
+            state.register_app_id(app_id)?;
+        }
 
         let session = {
             let request = Request::with_handler(&state, |a| {
@@ -133,6 +197,10 @@ impl ScreenCast {
             multiple: false,
             source_types: None,
             cursor_mode: None,
+            purpose: options.purpose,
+            disable_indicator: options.disable_indicator,
+            restore_token: None,
+            persist_mode: PersistMode::DoNot,
         })
     }
 
@@ -153,6 +221,19 @@ impl ScreenCast {
         self.cursor_mode = Some(mode);
     }
 
+    /// Restore the sources of an earlier session without showing the dialog, using a token from
+    /// `ActiveScreenCast::restore_token`. Portals which don't know the token, or are older than
+    /// version 4, show the dialog as usual. Tokens can only be used once.
+    pub fn set_restore_token(&mut self, token: &str) {
+        self.restore_token = Some(token.to_owned());
+    }
+
+    /// Ask the portal for a restore token for this session, see
+    /// `ActiveScreenCast::restore_token`.
+    pub fn set_persist_mode(&mut self, mode: PersistMode) {
+        self.persist_mode = mode;
+    }
+
     /// Enable multi-stream selection. This allows the user to choose more than
     /// one thing to share. Each will be a separate item in the
     /// `ActiveScreenCast::streams()` iterator.
@@ -188,17 +269,41 @@ impl ScreenCast {
                     None => CursorMode::HIDDEN.bits(),
                 })),
             );
+            if let Some(purpose) = &self.purpose {
+                select_args.insert("purpose".into(), Variant(Box::new(purpose.clone())));
+            }
+            if self.disable_indicator {
+                select_args.insert("disable_indicator".into(), Variant(Box::new(true)));
+            }
+            if let Some(token) = &self.restore_token {
+                select_args.insert("restore_token".into(), Variant(Box::new(token.clone())));
+            }
+            if self.persist_mode != PersistMode::DoNot {
+                select_args.insert(
+                    "persist_mode".into(),
+                    Variant(Box::new(self.persist_mode as u32)),
+                );
+            }
 
             desktop_proxy.select_sources(session, select_args)?;
             request.wait_response()?;
         }
 
-        let streams = {
+        let (streams, indicator_disabled, restore_token) = {
             let request = Request::with_handler(&self.state, |response| {
-                if response.response != 0 {
-                    return Err(PortalError::Cancelled);
-                }
-                match response.results.get("streams") {
+                check_response(response.response)?;
+                // Portals honoring the request confirm it, silence means the indicator is shown
+                let indicator_disabled = response
+                    .results
+                    .get("disable_indicator")
+                    .and_then(|value| value.as_u64())
+                    .is_some_and(|value| value != 0);
+                let restore_token = response
+                    .results
+                    .get("restore_token")
+                    .and_then(|value| value.as_str())
+                    .map(str::to_owned);
+                let streams = match response.results.get("streams") {
                     Some(streams) => match streams.as_iter() {
                         Some(streams) => streams
                             .flat_map(|s| {
@@ -210,7 +315,8 @@ impl ScreenCast {
                         None => Err(PortalError::Parse),
                     },
                     None => Err(PortalError::Parse),
-                }
+                };
+                streams.map(|streams| (streams, indicator_disabled, restore_token))
             })?;
             let session = dbus::Path::from(&self.session);
             let mut select_args = HashMap::<String, Variant<Box<dyn RefArg>>>::new();
@@ -230,6 +336,8 @@ impl ScreenCast {
             session_path: self.session,
             pipewire_fd,
             streams,
+            indicator_disabled,
+            restore_token,
         })
     }
 }
@@ -241,6 +349,8 @@ pub struct ActiveScreenCast {
     session_path: String,
     pipewire_fd: OwnedFd,
     streams: Vec<ScreenCastStream>,
+    indicator_disabled: bool,
+    restore_token: Option<String>,
 }
 
 impl ActiveScreenCast {
@@ -254,6 +364,19 @@ impl ActiveScreenCast {
         self.streams.iter()
     }
 
+    /// Whether the portal confirmed hiding the screen sharing indicator, see
+    /// `SessionOptions::disable_indicator`
+    pub fn indicator_disabled(&self) -> bool {
+        self.indicator_disabled
+    }
+
+    /// Token for restoring this session's sources later without the dialog, see
+    /// `ScreenCast::set_restore_token`. Only handed out when a persist mode was requested and the
+    /// portal supports it.
+    pub fn restore_token(&self) -> Option<&str> {
+        self.restore_token.as_deref()
+    }
+
     /// Close the ScreenCast session. This ends the cast.
     pub fn close(&self) -> Result<(), PortalError> {
         // Open a handle to the active session, and close it.
@@ -278,6 +401,7 @@ pub struct ScreenCastStream {
     pipewire_node: u32,
     width: u32,
     height: u32,
+    position: Option<(i32, i32)>,
 }
 
 impl ScreenCastStream {
@@ -297,6 +421,12 @@ impl ScreenCastStream {
     pub fn size(&self) -> (u32, u32) {
         (self.width(), self.height())
     }
+
+    /// Position of the stream in the compositor's global coordinate space. Only reported for
+    /// monitors, and not by every portal implementation.
+    pub fn position(&self) -> Option<(i32, i32)> {
+        self.position
+    }
 }
 
 impl std::convert::TryFrom<&dyn RefArg> for ScreenCastStream {
@@ -316,40 +446,43 @@ impl std::convert::TryFrom<&dyn RefArg> for ScreenCastStream {
 
         let mut width = 0;
         let mut height = 0;
+        let mut position = None;
 
         if let Some(mut dict_iter) = metadata.as_iter() {
             while let Some(key) = dict_iter.next() {
-                if key.as_str() == Some("size") {
-                    if let Some(values) = dict_iter.next().ok_or(PortalError::Parse)?.as_iter() {
-                        for v in values {
-                            let mut v_iter = v.as_iter().ok_or(PortalError::Parse)?;
-                            width = v_iter
-                                .next()
-                                .and_then(|w| w.as_i64())
-                                .map(|w| w as u32)
-                                .ok_or(PortalError::Parse)?;
-
-                            height = v_iter
-                                .next()
-                                .and_then(|h| h.as_i64())
-                                .map(|h| h as u32)
-                                .ok_or(PortalError::Parse)?;
-                        }
-                    } else {
-                        return Err(PortalError::Parse);
-                    }
+                let value = dict_iter.next().ok_or(PortalError::Parse)?;
+                match key.as_str() {
+                    Some("size") => (width, height) = parse_pair(value)?,
+                    Some("position") => position = Some(parse_pair(value)?),
+                    _ => {}
                 }
             }
         }
 
         Ok(ScreenCastStream {
             pipewire_node: node_id,
-            width,
-            height,
+            width: width as u32,
+            height: height as u32,
+            position,
         })
     }
 }
 
+/// Parse an `(ii)` struct, wrapped in a variant
+fn parse_pair(value: &dyn RefArg) -> Result<(i32, i32), PortalError> {
+    let mut values = value.as_iter().ok_or(PortalError::Parse)?;
+    let pair = values.next().ok_or(PortalError::Parse)?;
+    let mut pair_iter = pair.as_iter().ok_or(PortalError::Parse)?;
+    let mut next = || {
+        pair_iter
+            .next()
+            .and_then(|v| v.as_i64())
+            .map(|v| v as i32)
+            .ok_or(PortalError::Parse)
+    };
+    Ok((next()?, next()?))
+}
+
 bitflags! {
     /// Source Type Bitflags
     ///
@@ -380,6 +513,8 @@ bitflags! {
 struct ConnectionState {
     connection: Connection,
     sender_token: String,
+    /// Start of the handle tokens of requests and sessions
+    handle_prefix: String,
 }
 
 impl ConnectionState {
@@ -392,9 +527,31 @@ impl ConnectionState {
         Ok(ConnectionState {
             connection,
             sender_token,
+            handle_prefix: "screencap".to_string(),
         })
     }
 
+    /// Register the connection under `app_id`, has to happen before any other portal call
+    pub fn register_app_id(&self, app_id: &str) -> Result<(), dbus::Error> {
+        let result: Result<(), dbus::Error> = self.desktop_proxy().method_call(
+            "org.freedesktop.host.portal.Registry",
+            "Register",
+            (app_id, HashMap::<String, Variant<Box<dyn RefArg>>>::new()),
+        );
+        match result {
+            Err(e)
+                if matches!(
+                    e.name(),
+                    Some("org.freedesktop.DBus.Error.UnknownMethod")
+                        | Some("org.freedesktop.DBus.Error.UnknownInterface")
+                ) =>
+            {
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
     /// Create a proxy to the main desktop portal object
     pub fn desktop_proxy(&self) -> Proxy<&Connection> {
         self.connection.with_proxy(
@@ -438,7 +595,7 @@ impl<'a, Response> Request<'a, Response> {
         ResponseHandler: FnMut(OrgFreedesktopPortalRequestResponse) -> Response + Send + 'static,
         Response: Send + 'static,
     {
-        let handle = format!("screencap{0}", rand::random::<usize>());
+        let handle = format!("{0}{1}", state.handle_prefix, rand::random::<usize>());
         let resp_path = Path::new(format!(
             "/org/freedesktop/portal/desktop/request/{0}/{1}",
             state.sender_token, handle
@@ -507,7 +664,7 @@ impl<'a> Session<'a> {
 
 #[cfg(test)]
 mod tests {
-    use super::SourceType;
+    use super::{check_response, PortalError, SourceType};
 
     #[test]
     pub fn check_source_types() {
@@ -515,4 +672,11 @@ mod tests {
         assert_eq!(2, SourceType::WINDOW.bits());
         assert_eq!(3, (SourceType::WINDOW | SourceType::MONITOR).bits());
     }
+
+    #[test]
+    pub fn only_response_1_is_a_cancellation() {
+        assert!(check_response(0).is_ok());
+        assert!(matches!(check_response(1), Err(PortalError::Cancelled)));
+        assert!(matches!(check_response(2), Err(PortalError::Generic(_))));
+    }
 }
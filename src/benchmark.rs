@@ -0,0 +1,565 @@
+//! Measures what this machine can sustain, e.g. whether it can record 4K at 144fps.
+//!
+//! [`benchmark`] opens every encoder of a [`BenchMatrix`] the way a capture opens it, and feeds
+//! it frames of ffmpeg's `testsrc2` pattern at each resolution and framerate of the matrix,
+//! paced like a capture delivers them. The frames are uploaded to the GPU once up front, so
+//! only the encoding is measured, like the capture's zero-copy path. A combination stops early
+//! once the encoder falls clearly behind. The results are plain data, meant as a common
+//! baseline for users and bug reports, see the `benchmark` example. Needs the `benchmark`
+//! feature.
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use ffmpeg_next::{
+    self as ffmpeg,
+    ffi::{
+        av_buffer_ref, av_hwframe_ctx_init, av_hwframe_get_buffer, av_hwframe_transfer_data,
+        AVHWFramesContext, AVPixelFormat,
+    },
+};
+
+#[cfg(feature = "nvenc")]
+use crate::encoders::nvenc_encoder::{self, NvencCodec, NvencEncoder};
+use crate::{
+    encoders::{
+        dynamic_encoder::mjpeg_on_vaapi,
+        qsv_encoder::QsvEncoder,
+        software_encoder::{SoftwareCodec, SoftwareEncoder},
+        vaapi_encoder::{VaapiCodec, VaapiEncoder},
+        video::{create_hw_frame_ctx, HwBufferRef},
+    },
+    ffmpeg_compat,
+    runtime::Runtime,
+    types::{
+        config::{VideoConfig, VideoEncoder},
+        error::{Result, WaycapError},
+    },
+};
+
+/// Distinct frames cycled through, so the encoder doesn't see the same picture over and over
+const SOURCE_FRAMES: usize = 8;
+/// Time given to the encoder to settle before measuring, the first frames are slow everywhere
+const WARMUP: Duration = Duration::from_millis(500);
+/// How far the submissions may trail the schedule before the combination counts as failed
+const MAX_LAG: Duration = Duration::from_millis(500);
+/// amdgpu's utilization counter of the device the encoders open, other drivers have none
+const GPU_BUSY_PATH: &str = "/sys/class/drm/renderD128/device/gpu_busy_percent";
+const GPU_SAMPLE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Combinations [`benchmark`] runs, every encoder at every resolution and framerate
+#[derive(Debug, Clone, PartialEq)]
+pub struct BenchMatrix {
+    pub encoders: Vec<VideoEncoder>,
+    /// Width and height
+    pub resolutions: Vec<(u32, u32)>,
+    pub framerates: Vec<u32>,
+    /// How long each combination is measured when it keeps up
+    pub duration: Duration,
+    /// Settings the encoders are opened with
+    pub video_config: VideoConfig,
+}
+
+impl Default for BenchMatrix {
+    /// Every encoder compiled in at 1080p, 1440p and 4K, each at 60 and 144fps
+    fn default() -> Self {
+        Self {
+            encoders: VideoEncoder::all_supported()
+                .iter()
+                .copied()
+                .filter(|&encoder| encoder != VideoEncoder::H264Passthrough)
+                .collect(),
+            resolutions: vec![(1920, 1080), (2560, 1440), (3840, 2160)],
+            framerates: vec![60, 144],
+            duration: Duration::from_secs(5),
+            video_config: VideoConfig::default(),
+        }
+    }
+}
+
+/// How a combination went
+#[derive(Debug, Clone, PartialEq)]
+pub enum BenchOutcome {
+    /// Every frame was encoded in time
+    KeptUp,
+    /// The encoder fell behind and the combination was stopped early
+    FellBehind,
+    /// The encoder could not be opened or failed while encoding
+    Failed(String),
+}
+
+/// Time from handing a frame to the encoder to taking its packet out
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LatencyPercentiles {
+    pub p50: Duration,
+    pub p95: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+impl LatencyPercentiles {
+    /// Nearest rank percentiles of `samples`, all zero without samples
+    fn from_samples(samples: &mut [Duration]) -> Self {
+        samples.sort_unstable();
+        let Some(&max) = samples.last() else {
+            return Self::default();
+        };
+        let rank = |quantile: f64| {
+            let rank = (samples.len() as f64 * quantile).ceil() as usize;
+            samples[rank.clamp(1, samples.len()) - 1]
+        };
+        Self {
+            p50: rank(0.50),
+            p95: rank(0.95),
+            p99: rank(0.99),
+            max,
+        }
+    }
+}
+
+/// Result for one combination of a [`BenchMatrix`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct BenchResult {
+    pub encoder: VideoEncoder,
+    pub width: u32,
+    pub height: u32,
+    pub framerate: u32,
+    pub outcome: BenchOutcome,
+    /// Frames encoded after the warmup
+    pub frames: u64,
+    /// Frames per second the encoder sustained, `framerate` when it kept up and what it
+    /// managed at full speed when it fell behind
+    pub fps: f64,
+    pub latency: LatencyPercentiles,
+    /// CPU time of the whole process while measuring, user and system
+    pub cpu_time: Duration,
+    /// Average GPU utilization in percent while measuring, only reported by amdgpu
+    pub gpu_busy: Option<f64>,
+}
+
+impl BenchResult {
+    pub fn kept_up(&self) -> bool {
+        self.outcome == BenchOutcome::KeptUp
+    }
+}
+
+/// Run every combination of `matrix` and return the results in the order of its encoders,
+/// resolutions and framerates.
+///
+/// Encoders which can't be opened on this machine end up as [`BenchOutcome::Failed`], only an
+/// invalid matrix fails the whole benchmark.
+pub fn benchmark(matrix: &BenchMatrix) -> Result<Vec<BenchResult>> {
+    if matrix.encoders.is_empty() || matrix.resolutions.is_empty() || matrix.framerates.is_empty() {
+        return Err(WaycapError::Validation(
+            "The benchmark needs at least one encoder, resolution and framerate".to_string(),
+        ));
+    }
+    if let Some((width, height)) = matrix
+        .resolutions
+        .iter()
+        .find(|(width, height)| *width == 0 || *height == 0)
+    {
+        return Err(WaycapError::Validation(format!(
+            "Can't benchmark a resolution of {width}x{height}"
+        )));
+    }
+    if matrix.framerates.contains(&0) || matrix.duration.is_zero() {
+        return Err(WaycapError::Validation(
+            "Framerates and the duration have to be above zero".to_string(),
+        ));
+    }
+    let _runtime = Runtime::acquire()?;
+
+    let mut results = Vec::new();
+    for &encoder in &matrix.encoders {
+        for &(width, height) in &matrix.resolutions {
+            for &framerate in &matrix.framerates {
+                let mut result = BenchResult {
+                    encoder,
+                    width,
+                    height,
+                    framerate,
+                    outcome: BenchOutcome::KeptUp,
+                    frames: 0,
+                    fps: 0.0,
+                    latency: LatencyPercentiles::default(),
+                    cpu_time: Duration::ZERO,
+                    gpu_busy: None,
+                };
+                if let Err(e) = bench_combination(&mut result, matrix) {
+                    log::info!(
+                        "{} failed at {width}x{height}@{framerate}: {e}",
+                        encoder.display_name()
+                    );
+                    result.outcome = BenchOutcome::Failed(e.to_string());
+                }
+                results.push(result);
+            }
+        }
+    }
+    Ok(results)
+}
+
+/// Open the encoder of `result` and measure it, filling in `result`
+fn bench_combination(result: &mut BenchResult, matrix: &BenchMatrix) -> Result<()> {
+    let mut encoder = BenchEncoder::open(result, &matrix.video_config)?;
+    let mut frames = source_frames(&encoder.encoder, result.framerate)?;
+
+    let interval = Duration::from_secs(1) / result.framerate;
+    let started = Instant::now();
+    let mut schedule = Schedule::new(started, interval);
+    let mut measuring = None;
+    let mut cpu_start = cpu_time();
+    let mut gpu = GpuSamples::default();
+    let mut submitted: HashMap<i64, Instant> = HashMap::new();
+    let mut latencies = Vec::new();
+    let mut packet = ffmpeg::codec::packet::Packet::empty();
+    for index in 0u64.. {
+        let now = Instant::now();
+        match measuring {
+            None if now - started >= WARMUP => {
+                measuring = Some(now);
+                schedule = Schedule::new(now, interval);
+                cpu_start = cpu_time();
+                submitted.clear();
+            }
+            Some(measuring) if now - measuring >= matrix.duration => break,
+            _ => {}
+        }
+        if schedule.lag(now) > MAX_LAG {
+            result.outcome = BenchOutcome::FellBehind;
+            break;
+        }
+        if measuring.is_some() {
+            gpu.sample(now);
+        }
+        if let Some(wait) = schedule.due().checked_duration_since(now) {
+            std::thread::sleep(wait);
+        }
+
+        let frame = &mut frames[index as usize % SOURCE_FRAMES];
+        let pts = (interval * index as u32).as_nanos() as i64;
+        frame.set_pts(Some(pts));
+        submitted.insert(pts, Instant::now());
+        encoder.encoder.send_frame(frame)?;
+        schedule.submitted += 1;
+        while encoder.encoder.receive_packet(&mut packet).is_ok() {
+            if let Some(at) = packet.pts().and_then(|pts| submitted.remove(&pts)) {
+                latencies.push(at.elapsed());
+            }
+        }
+    }
+
+    let Some(measuring) = measuring else {
+        // Fell behind during the warmup already
+        return Ok(());
+    };
+    result.frames = schedule.submitted;
+    result.fps = schedule.submitted as f64 / measuring.elapsed().as_secs_f64();
+    result.latency = LatencyPercentiles::from_samples(&mut latencies);
+    result.cpu_time = cpu_time().saturating_sub(cpu_start);
+    result.gpu_busy = gpu.average();
+    Ok(())
+}
+
+/// An encoder opened the way the capture opens it
+struct BenchEncoder {
+    encoder: ffmpeg::codec::encoder::Video,
+    /// Has to outlive the encoder, which the field order takes care of
+    #[cfg(feature = "nvenc")]
+    cuda_ctx: Option<cust::prelude::Context>,
+}
+
+impl BenchEncoder {
+    fn open(result: &BenchResult, config: &VideoConfig) -> Result<Self> {
+        let vaapi = |codec| -> Result<Self> {
+            let (encoder, _) =
+                VaapiEncoder::create_encoder(result.width, result.height, codec, config)?;
+            Ok(Self {
+                encoder,
+                #[cfg(feature = "nvenc")]
+                cuda_ctx: None,
+            })
+        };
+        let software = |codec| -> Result<Self> {
+            let (encoder, _) =
+                SoftwareEncoder::create_encoder(result.width, result.height, codec, config)?;
+            Ok(Self {
+                encoder,
+                #[cfg(feature = "nvenc")]
+                cuda_ctx: None,
+            })
+        };
+        #[cfg(feature = "nvenc")]
+        let nvenc = |codec| -> Result<Self> {
+            let cuda_ctx = cust::quick_init()
+                .map_err(|e| WaycapError::Init(format!("Could not initialize CUDA: {e}")))?;
+            let (encoder, _) = NvencEncoder::create_encoder(
+                result.width,
+                result.height,
+                codec,
+                config,
+                &cuda_ctx,
+            )?;
+            Ok(Self {
+                encoder,
+                cuda_ctx: Some(cuda_ctx),
+            })
+        };
+        match result.encoder {
+            #[cfg(feature = "nvenc")]
+            VideoEncoder::H264Nvenc => nvenc(NvencCodec::H264),
+            #[cfg(feature = "nvenc")]
+            VideoEncoder::H265Nvenc => nvenc(NvencCodec::Hevc),
+            #[cfg(feature = "nvenc")]
+            VideoEncoder::Av1Nvenc => nvenc(NvencCodec::Av1),
+            VideoEncoder::H264Vaapi => vaapi(VaapiCodec::H264),
+            VideoEncoder::H265Vaapi => vaapi(VaapiCodec::Hevc),
+            VideoEncoder::Av1Vaapi => vaapi(VaapiCodec::Av1),
+            VideoEncoder::Vp9Vaapi => vaapi(VaapiCodec::Vp9),
+            VideoEncoder::H264Qsv => {
+                let (encoder, _) = QsvEncoder::create_encoder(result.width, result.height, config)?;
+                Ok(Self {
+                    encoder,
+                    #[cfg(feature = "nvenc")]
+                    cuda_ctx: None,
+                })
+            }
+            VideoEncoder::H264Software => software(SoftwareCodec::H264),
+            VideoEncoder::Av1Svt => software(SoftwareCodec::Av1),
+            VideoEncoder::Mjpeg if mjpeg_on_vaapi() => vaapi(VaapiCodec::Mjpeg),
+            VideoEncoder::Mjpeg => software(SoftwareCodec::Mjpeg),
+            VideoEncoder::Vp8 => software(SoftwareCodec::Vp8),
+            VideoEncoder::H264Passthrough => Err(WaycapError::Unsupported(
+                "Passthrough hands on what the compositor encoded, there is nothing to measure"
+                    .to_string(),
+            )),
+        }
+    }
+}
+
+#[cfg(feature = "nvenc")]
+impl Drop for BenchEncoder {
+    fn drop(&mut self) {
+        if self.cuda_ctx.is_some() {
+            nvenc_encoder::session_closed();
+        }
+    }
+}
+
+/// [`SOURCE_FRAMES`] frames of the test pattern, uploaded to the encoder's device in its input
+/// format
+fn source_frames(
+    encoder: &ffmpeg::codec::encoder::Video,
+    framerate: u32,
+) -> Result<Vec<ffmpeg::util::frame::Video>> {
+    let encoder_frames = unsafe { (*encoder.as_ptr()).hw_frames_ctx };
+    if encoder_frames.is_null() {
+        // Software encoders take the pattern as it is, the conversion from the captured RGB
+        // is not measured
+        let mut source = source_graph(
+            encoder.width() as i32,
+            encoder.height() as i32,
+            framerate,
+            encoder.format().into(),
+        )?;
+        let mut frames = Vec::with_capacity(SOURCE_FRAMES);
+        while frames.len() < SOURCE_FRAMES {
+            let mut frame = ffmpeg::util::frame::Video::empty();
+            source.get("out").unwrap().sink().frame(&mut frame)?;
+            frames.push(frame);
+        }
+        return Ok(frames);
+    }
+    let encoder_frames = unsafe { &*((*encoder_frames).data as *const AVHWFramesContext) };
+
+    // A pool of its own, the encoder's only holds the surfaces it keeps in flight
+    let device = unsafe { HwBufferRef::from_raw(av_buffer_ref(encoder_frames.device_ref)) }
+        .ok_or_else(|| WaycapError::Init("Could not reference hw device".to_string()))?;
+    let pool = create_hw_frame_ctx(&device)?;
+    unsafe {
+        let pool_frames = &mut *((*pool.as_ptr()).data as *mut AVHWFramesContext);
+        pool_frames.width = encoder_frames.width;
+        pool_frames.height = encoder_frames.height;
+        pool_frames.format = encoder_frames.format;
+        pool_frames.sw_format = encoder_frames.sw_format;
+        pool_frames.initial_pool_size = SOURCE_FRAMES as i32;
+        let err = av_hwframe_ctx_init(pool.as_ptr());
+        if err < 0 {
+            return Err(WaycapError::Init(format!(
+                "Error trying to initialize hw frame context: {err:?}",
+            )));
+        }
+    }
+
+    let mut source = source_graph(
+        encoder_frames.width,
+        encoder_frames.height,
+        framerate,
+        encoder_frames.sw_format,
+    )?;
+    let mut frame = ffmpeg::util::frame::Video::empty();
+    let mut frames = Vec::with_capacity(SOURCE_FRAMES);
+    while frames.len() < SOURCE_FRAMES {
+        source.get("out").unwrap().sink().frame(&mut frame)?;
+        let mut hw_frame = ffmpeg::util::frame::Video::empty();
+        unsafe {
+            let err = av_hwframe_get_buffer(pool.as_ptr(), hw_frame.as_mut_ptr(), 0);
+            if err < 0 {
+                return Err(ffmpeg::Error::from(err).into());
+            }
+            let err = av_hwframe_transfer_data(hw_frame.as_mut_ptr(), frame.as_ptr(), 0);
+            if err < 0 {
+                return Err(ffmpeg::Error::from(err).into());
+            }
+        }
+        frames.push(hw_frame);
+    }
+    Ok(frames)
+}
+
+/// Graph generating the test pattern in `format`, read from its `out` sink
+fn source_graph(
+    width: i32,
+    height: i32,
+    framerate: u32,
+    format: AVPixelFormat,
+) -> Result<ffmpeg::filter::Graph> {
+    let format = ffmpeg::format::Pixel::from(format)
+        .descriptor()
+        .map(|descriptor| descriptor.name())
+        .ok_or_else(|| WaycapError::Init("The encoder takes an unknown format".to_string()))?;
+    let mut graph = ffmpeg::filter::Graph::new();
+    graph.add(&ffmpeg_compat::find_filter("buffersink")?, "out", "")?;
+    ffmpeg_compat::find_filter("testsrc2")?;
+    let spec = format!("testsrc2=size={width}x{height}:rate={framerate},format={format}");
+    graph.input("out", 0)?.parse(&spec)?;
+    graph.validate()?;
+    Ok(graph)
+}
+
+/// When frames are due at the target framerate
+#[derive(Debug)]
+struct Schedule {
+    start: Instant,
+    interval: Duration,
+    submitted: u64,
+}
+
+impl Schedule {
+    fn new(start: Instant, interval: Duration) -> Self {
+        Self {
+            start,
+            interval,
+            submitted: 0,
+        }
+    }
+
+    /// When the next frame is due
+    fn due(&self) -> Instant {
+        self.start + self.interval * self.submitted as u32
+    }
+
+    /// How far the submissions trail the schedule at `now`
+    fn lag(&self, now: Instant) -> Duration {
+        now.saturating_duration_since(self.due())
+    }
+}
+
+#[derive(Debug, Default)]
+struct GpuSamples {
+    last: Option<Instant>,
+    total: f64,
+    count: u32,
+}
+
+impl GpuSamples {
+    fn sample(&mut self, now: Instant) {
+        if self
+            .last
+            .is_some_and(|last| now - last < GPU_SAMPLE_INTERVAL)
+        {
+            return;
+        }
+        self.last = Some(now);
+        let busy = std::fs::read_to_string(GPU_BUSY_PATH)
+            .ok()
+            .and_then(|busy| busy.trim().parse::<f64>().ok());
+        if let Some(busy) = busy {
+            self.total += busy;
+            self.count += 1;
+        }
+    }
+
+    fn average(&self) -> Option<f64> {
+        (self.count > 0).then(|| self.total / self.count as f64)
+    }
+}
+
+/// User and system CPU time of the process so far
+fn cpu_time() -> Duration {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    if unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) } != 0 {
+        return Duration::ZERO;
+    }
+    let time = |time: libc::timeval| {
+        Duration::from_secs(time.tv_sec as u64) + Duration::from_micros(time.tv_usec as u64)
+    };
+    time(usage.ru_utime) + time(usage.ru_stime)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn latency_percentiles() {
+        let mut samples: Vec<Duration> = (1..=100).rev().map(Duration::from_millis).collect();
+        let latency = LatencyPercentiles::from_samples(&mut samples);
+        assert_eq!(latency.p50, Duration::from_millis(50));
+        assert_eq!(latency.p95, Duration::from_millis(95));
+        assert_eq!(latency.p99, Duration::from_millis(99));
+        assert_eq!(latency.max, Duration::from_millis(100));
+
+        let single = LatencyPercentiles::from_samples(&mut [Duration::from_millis(7)]);
+        assert_eq!(single.p50, Duration::from_millis(7));
+        assert_eq!(
+            LatencyPercentiles::from_samples(&mut []),
+            LatencyPercentiles::default()
+        );
+    }
+
+    #[test]
+    fn lag_grows_once_behind_schedule() {
+        let start = Instant::now();
+        let mut schedule = Schedule::new(start, Duration::from_millis(10));
+        for frame in 0..100u64 {
+            // Every frame submitted when due
+            let now = start + Duration::from_millis(frame * 10);
+            assert_eq!(schedule.lag(now), Duration::ZERO);
+            schedule.submitted += 1;
+        }
+        // An encoder needing 15ms a frame falls 5ms further behind with every frame
+        let behind = start + Duration::from_millis(1000);
+        for frame in 0..100u64 {
+            let now = behind + Duration::from_millis(frame * 15);
+            assert_eq!(schedule.lag(now), Duration::from_millis(frame * 5));
+            schedule.submitted += 1;
+        }
+        assert!(schedule.lag(behind + Duration::from_millis(1600)) > MAX_LAG);
+    }
+
+    #[test]
+    fn rejects_empty_matrices() {
+        let matrix = BenchMatrix {
+            framerates: Vec::new(),
+            ..Default::default()
+        };
+        assert!(matches!(
+            benchmark(&matrix),
+            Err(WaycapError::Validation(_))
+        ));
+    }
+}
@@ -1,7 +1,13 @@
-use std::{process::Command, sync::Arc};
+use std::{
+    process::Command,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use crate::{types::audio_frame::RawAudioFrame, CaptureControls, ReadyState};
-use crossbeam::channel::Sender;
+use crate::{
+    failure_injection, pipeline::shutdown::StreamKind, types::stats::StatsCounters,
+    CaptureControls, ReadyState,
+};
 use pipewire::{
     self as pw,
     context::Context,
@@ -17,7 +23,10 @@ use pipewire::{
     sys::pw_stream_get_nsec,
 };
 
-use super::Terminate;
+use super::{
+    audio_ring::{split_period, Pushed, RingProducer},
+    Terminate,
+};
 
 #[derive(Clone, Copy, Default)]
 struct UserData {
@@ -34,11 +43,14 @@ impl AudioCapture {
         Self { ready_state }
     }
 
+    /// Capture the default sink into `ring`. The process callback runs on PipeWire's realtime
+    /// thread and only copies the samples into the ring, encoding happens on the other side.
     pub fn run(
         &self,
-        audio_sender: Sender<RawAudioFrame>,
+        ring: RingProducer,
         termination_recv: pw::channel::Receiver<Terminate>,
         controls: Arc<CaptureControls>,
+        stats: Arc<StatsCounters>,
     ) -> Result<(), pw::Error> {
         let pw_loop = MainLoop::new(None)?;
         let terminate_loop = pw_loop.clone();
@@ -114,7 +126,7 @@ impl AudioCapture {
                     udata.audio_format.format().as_raw()
                 );
             })
-            .process(move |stream, _| match stream.dequeue_buffer() {
+            .process(move |stream, udata| match stream.dequeue_buffer() {
                 None => log::debug!("Out of audio buffers"),
                 Some(mut buffer) => {
                     // Wait until video is streaming before we try to process
@@ -131,28 +143,36 @@ impl AudioCapture {
                     let n_samples = data.chunk().size() / (std::mem::size_of::<f32>()) as u32;
 
                     if let Some(samples) = data.data() {
+                        let started = Instant::now();
                         let samples_f32: &[f32] = bytemuck::cast_slice(samples);
                         let audio_samples = &samples_f32[..n_samples as usize];
-                        match audio_sender.try_send(RawAudioFrame {
-                            samples: audio_samples.to_vec(),
-                            timestamp: unsafe { pw_stream_get_nsec(stream.as_raw_ptr()) } as i64,
-                        }) {
-                            Ok(_) => {}
-                            Err(crossbeam::channel::TrySendError::Full(frame)) => {
-                                log::error!(
-                                    "channel is full when trying to send frame at: {}.",
-                                    frame.timestamp
-                                );
+                        let clock_ns = unsafe { pw_stream_get_nsec(stream.as_raw_ptr()) } as i64;
+                        let timestamp = failure_injection::capture_time(&controls, clock_ns);
+                        if !controls.cutoff().accepts(timestamp) {
+                            return;
+                        }
+                        let (channels, rate) =
+                            (udata.audio_format.channels(), udata.audio_format.rate());
+                        for (offset, chunk) in split_period(audio_samples, channels, rate) {
+                            if failure_injection::queue_full(&controls) {
+                                stats.mark_audio_period_dropped();
+                                continue;
                             }
-                            Err(crossbeam::channel::TrySendError::Disconnected(frame)) => {
-                                // TODO: If we disconnected, terminate the session instead of
-                                // throwing an error it means the receiver was dropped.
-                                log::error!(
-                                    "channel is disconnected when trying to send frame at: {}.",
-                                    frame.timestamp
-                                );
+                            controls.cutoff().frame_queued(StreamKind::Audio);
+                            match ring.push(timestamp + offset, chunk) {
+                                Pushed::Queued => {}
+                                // Logging here could block the realtime thread, the drops
+                                // show up in the stats
+                                Pushed::Dropped | Pushed::ReplacedOldest => {
+                                    controls.cutoff().frame_done(StreamKind::Audio);
+                                    stats.mark_audio_period_dropped();
+                                }
                             }
                         }
+                        stats.record_audio_callback(
+                            started.elapsed(),
+                            quantum_duration(audio_samples.len(), channels, rate),
+                        );
                     }
                 }
             })
@@ -205,6 +225,15 @@ impl AudioCapture {
     }
 }
 
+/// How long `samples` interleaved samples play, the time the callback has before the next quantum
+fn quantum_duration(samples: usize, channels: u32, rate: u32) -> Option<Duration> {
+    if channels == 0 || rate == 0 {
+        return None;
+    }
+    let frames = (samples / channels as usize) as u64;
+    Some(Duration::from_nanos(frames * 1_000_000_000 / rate as u64))
+}
+
 // Theres gotta be a less goofy way to do this
 fn get_default_sink_node_id() -> Option<u32> {
     let output = Command::new("sh")
@@ -0,0 +1,234 @@
+//! Hand-off of captured audio from the realtime PipeWire callback to the audio encoding thread.
+//!
+//! The callback must neither block nor allocate, so periods are copied into buffers allocated up
+//! front and passed through two lock-free queues: [`RingProducer::push`] takes a free buffer,
+//! fills it and queues it, [`RingConsumer::pop_timeout`] copies the oldest period out and hands
+//! the buffer back.
+
+use std::{
+    sync::{Arc, OnceLock},
+    thread::Thread,
+    time::Duration,
+};
+
+use crossbeam::queue::ArrayQueue;
+
+use crate::types::{
+    audio_frame::RawAudioFrame,
+    config::{AudioRingConfig, OverflowPolicy},
+    time::CaptureTime,
+};
+
+/// Samples one slot of the ring holds, a 1024 frame stereo quantum fits twice. Longer periods are
+/// split over several slots by [`split_period`].
+pub(crate) const SLOT_SAMPLES: usize = 4096;
+
+struct Period {
+    timestamp: CaptureTime,
+    samples: Vec<f32>,
+}
+
+struct Shared {
+    free: ArrayQueue<Vec<f32>>,
+    filled: ArrayQueue<Period>,
+    consumer: OnceLock<Thread>,
+}
+
+/// What happened to a pushed period
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Pushed {
+    Queued,
+    /// The ring was full and the period was dropped
+    Dropped,
+    /// The ring was full and the oldest queued period was dropped to make room
+    ReplacedOldest,
+}
+
+/// Realtime side of the ring, owned by the PipeWire process callback
+pub(crate) struct RingProducer {
+    shared: Arc<Shared>,
+    overflow: OverflowPolicy,
+}
+
+/// Encoder side of the ring
+pub(crate) struct RingConsumer {
+    shared: Arc<Shared>,
+}
+
+/// Allocate a ring of `config.depth` slots
+pub(crate) fn audio_ring(config: AudioRingConfig) -> (RingProducer, RingConsumer) {
+    let shared = Arc::new(Shared {
+        free: ArrayQueue::new(config.depth),
+        filled: ArrayQueue::new(config.depth),
+        consumer: OnceLock::new(),
+    });
+    for _ in 0..config.depth {
+        let _ = shared.free.push(Vec::with_capacity(SLOT_SAMPLES));
+    }
+    (
+        RingProducer {
+            shared: Arc::clone(&shared),
+            overflow: config.overflow,
+        },
+        RingConsumer { shared },
+    )
+}
+
+impl RingProducer {
+    /// Queue a period of at most [`SLOT_SAMPLES`] samples. Doesn't allocate or block, waking the
+    /// consumer is a single futex wake.
+    pub fn push(&self, timestamp: CaptureTime, samples: &[f32]) -> Pushed {
+        debug_assert!(samples.len() <= SLOT_SAMPLES);
+        let (mut buffer, pushed) = match self.shared.free.pop() {
+            Some(buffer) => (buffer, Pushed::Queued),
+            None => match self.overflow {
+                OverflowPolicy::DropOldest => match self.shared.filled.pop() {
+                    Some(oldest) => (oldest.samples, Pushed::ReplacedOldest),
+                    // The consumer took the last period in the meantime, its buffer is on the
+                    // way back but not free yet
+                    None => return Pushed::Dropped,
+                },
+                _ => return Pushed::Dropped,
+            },
+        };
+        buffer.clear();
+        buffer.extend_from_slice(&samples[..samples.len().min(SLOT_SAMPLES)]);
+        // Every buffer is either free, queued or with the consumer, so this can't overflow
+        let _ = self.shared.filled.push(Period {
+            timestamp,
+            samples: buffer,
+        });
+        if let Some(consumer) = self.shared.consumer.get() {
+            consumer.unpark();
+        }
+        pushed
+    }
+}
+
+impl RingConsumer {
+    /// Take the oldest queued period, waiting up to `timeout` for one. Has to be called from
+    /// the same thread every time, which is the one woken by the producer.
+    pub fn pop_timeout(&self, timeout: Duration) -> Option<RawAudioFrame> {
+        self.shared.consumer.get_or_init(std::thread::current);
+        let period = match self.shared.filled.pop() {
+            Some(period) => period,
+            None => {
+                std::thread::park_timeout(timeout);
+                self.shared.filled.pop()?
+            }
+        };
+        let frame = RawAudioFrame {
+            samples: period.samples.to_vec(),
+            timestamp: period.timestamp,
+        };
+        let _ = self.shared.free.push(period.samples);
+        Some(frame)
+    }
+
+    /// Whether the producer is gone and every queued period was taken
+    pub fn is_finished(&self) -> bool {
+        Arc::strong_count(&self.shared) == 1 && self.shared.filled.is_empty()
+    }
+}
+
+/// Split a period of interleaved samples into chunks fitting a slot, each with its offset from
+/// the start of the period. Chunks end on a frame boundary.
+pub(crate) fn split_period(
+    samples: &[f32],
+    channels: u32,
+    rate: u32,
+) -> impl Iterator<Item = (Duration, &[f32])> {
+    let channels = channels.max(1) as usize;
+    let chunk_len = (SLOT_SAMPLES / channels).max(1) * channels;
+    samples
+        .chunks(chunk_len)
+        .enumerate()
+        .map(move |(index, chunk)| {
+            let frames = (index * chunk_len / channels) as u64;
+            let offset = match rate {
+                0 => Duration::ZERO,
+                rate => Duration::from_nanos(frames * 1_000_000_000 / rate as u64),
+            };
+            (offset, chunk)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(millis: u64) -> CaptureTime {
+        CaptureTime::from_nanos(0) + Duration::from_millis(millis)
+    }
+
+    fn ring(depth: usize, overflow: OverflowPolicy) -> (RingProducer, RingConsumer) {
+        audio_ring(AudioRingConfig { depth, overflow })
+    }
+
+    fn pop_all(consumer: &RingConsumer) -> Vec<CaptureTime> {
+        std::iter::from_fn(|| consumer.pop_timeout(Duration::ZERO))
+            .map(|frame| frame.timestamp)
+            .collect()
+    }
+
+    #[test]
+    fn drop_newest_keeps_queued_periods() {
+        let (producer, consumer) = ring(2, OverflowPolicy::DropNewest);
+        assert_eq!(producer.push(at(0), &[0.5; 2048]), Pushed::Queued);
+        assert_eq!(producer.push(at(21), &[0.5; 2048]), Pushed::Queued);
+        assert_eq!(producer.push(at(42), &[0.5; 2048]), Pushed::Dropped);
+
+        let frame = consumer.pop_timeout(Duration::ZERO).unwrap();
+        assert_eq!(frame.timestamp, at(0));
+        assert_eq!(frame.samples, vec![0.5; 2048]);
+        // Popping freed a slot
+        assert_eq!(producer.push(at(63), &[0.5; 2048]), Pushed::Queued);
+        assert_eq!(pop_all(&consumer), vec![at(21), at(63)]);
+    }
+
+    #[test]
+    fn drop_oldest_replaces_queued_periods() {
+        let (producer, consumer) = ring(2, OverflowPolicy::DropOldest);
+        for millis in [0, 21, 42, 63] {
+            producer.push(at(millis), &[1.0; 8]);
+        }
+        assert_eq!(producer.push(at(84), &[1.0; 8]), Pushed::ReplacedOldest);
+        assert_eq!(pop_all(&consumer), vec![at(63), at(84)]);
+    }
+
+    #[test]
+    fn consumer_finishes_after_producer() {
+        let (producer, consumer) = ring(4, OverflowPolicy::DropNewest);
+        let thread = std::thread::spawn(move || {
+            for millis in 0..3 {
+                producer.push(at(millis), &[0.0; 2]);
+            }
+        });
+        let mut received = Vec::new();
+        while !consumer.is_finished() {
+            received.extend(consumer.pop_timeout(Duration::from_millis(10)));
+        }
+        thread.join().unwrap();
+        assert_eq!(
+            received.iter().map(|f| f.timestamp).collect::<Vec<_>>(),
+            vec![at(0), at(1), at(2)]
+        );
+    }
+
+    #[test]
+    fn long_periods_are_split_on_frames() {
+        // 5.1 at 48kHz, 1024 frames
+        let samples = vec![0.0; 6 * 1024];
+        let chunks: Vec<_> = split_period(&samples, 6, 48_000)
+            .map(|(offset, chunk)| (offset, chunk.len()))
+            .collect();
+        assert_eq!(
+            chunks,
+            vec![
+                (Duration::ZERO, 4092),
+                (Duration::from_nanos(682 * 1_000_000_000 / 48_000), 2052),
+            ]
+        );
+        assert_eq!(split_period(&[0.0; 2048], 2, 48_000).count(), 1);
+    }
+}
@@ -0,0 +1,245 @@
+//! Detection of the screen locking or blanking during a capture.
+//!
+//! Compositors handle DPMS and lock screens differently: some pause the PipeWire stream, others
+//! keep delivering frames which are entirely black. Both end up here and are turned into
+//! [`BlankTransition`]s, which [`apply_policy`] acts on according to the
+//! [`ScreenBlankPolicy`] of the capture.
+
+use std::time::Duration;
+
+use pipewire::spa::param::video::VideoFormat;
+
+use crate::{
+    types::{
+        config::ScreenBlankPolicy,
+        event::{BlankReason, CaptureEvent, EventSender},
+        time::CaptureTime,
+    },
+    CaptureControls,
+};
+
+/// How often a frame is sampled for the black frame heuristic
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(500);
+/// How long frames have to stay black before the screen counts as blanked, so a cut to black
+/// in a video doesn't pause the capture
+const BLACK_FOR: Duration = Duration::from_secs(3);
+/// Sampled points per axis
+const GRID: u32 = 8;
+/// Brightest channel value still counted as black, above limited range black (16)
+const BLACK_LEVEL: u8 = 24;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum BlankTransition {
+    Blanked(BlankReason),
+    Restored,
+}
+
+/// Tracks the stream state and sampled frames of one video stream
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct BlankDetector {
+    last_sample: Option<CaptureTime>,
+    black_since: Option<CaptureTime>,
+    blanked: Option<BlankReason>,
+}
+
+impl BlankDetector {
+    /// The compositor paused the stream after it was streaming
+    pub fn stream_paused(&mut self) -> Option<BlankTransition> {
+        if self.blanked.is_some() {
+            return None;
+        }
+        self.blanked = Some(BlankReason::StreamPaused);
+        Some(BlankTransition::Blanked(BlankReason::StreamPaused))
+    }
+
+    /// The stream is streaming again after being paused
+    pub fn stream_resumed(&mut self) -> Option<BlankTransition> {
+        if self.blanked != Some(BlankReason::StreamPaused) {
+            return None;
+        }
+        self.blanked = None;
+        self.black_since = None;
+        Some(BlankTransition::Restored)
+    }
+
+    /// Whether the frame captured at `now` should be checked with [`is_black`]
+    pub fn wants_sample(&self, now: CaptureTime) -> bool {
+        self.last_sample
+            .is_none_or(|last| now.abs_diff(last) >= SAMPLE_INTERVAL)
+    }
+
+    pub fn on_sample(&mut self, now: CaptureTime, black: bool) -> Option<BlankTransition> {
+        self.last_sample = Some(now);
+        if !black {
+            self.black_since = None;
+            return match self.blanked {
+                Some(BlankReason::BlackFrames) => {
+                    self.blanked = None;
+                    Some(BlankTransition::Restored)
+                }
+                _ => None,
+            };
+        }
+        let since = *self.black_since.get_or_insert(now);
+        if self.blanked.is_none() && now - since >= BLACK_FOR {
+            self.blanked = Some(BlankReason::BlackFrames);
+            return Some(BlankTransition::Blanked(BlankReason::BlackFrames));
+        }
+        None
+    }
+}
+
+/// Whether every sampled pixel of the frame in `data` is black. `None` for formats the
+/// heuristic doesn't understand or a buffer too small for the layout.
+pub(crate) fn is_black(
+    data: &[u8],
+    format: VideoFormat,
+    offset: usize,
+    stride: usize,
+    width: u32,
+    height: u32,
+) -> Option<bool> {
+    let bytes_per_pixel = match format {
+        VideoFormat::BGRx | VideoFormat::BGRA | VideoFormat::RGBx | VideoFormat::RGBA => 4,
+        // Only the luma plane is looked at
+        VideoFormat::NV12 | VideoFormat::I420 => 1,
+        _ => return None,
+    };
+    if width == 0 || height == 0 {
+        return None;
+    }
+    for row in 0..GRID {
+        let y = ((row * 2 + 1) * height / (GRID * 2)) as usize;
+        for column in 0..GRID {
+            let x = ((column * 2 + 1) * width / (GRID * 2)) as usize;
+            let start = offset + y * stride + x * bytes_per_pixel;
+            // The fourth byte of RGB formats is alpha or padding
+            let channels = bytes_per_pixel.min(3);
+            let pixel = data.get(start..start + channels)?;
+            if pixel.iter().any(|&value| value > BLACK_LEVEL) {
+                return Some(false);
+            }
+        }
+    }
+    Some(true)
+}
+
+/// Act on `transition`. Returns true when the current frame has to be queued as the placeholder
+/// before the capture pauses, see [`ScreenBlankPolicy::Placeholder`].
+pub(crate) fn apply_policy(
+    transition: BlankTransition,
+    policy: ScreenBlankPolicy,
+    controls: &CaptureControls,
+    events: &EventSender,
+) -> bool {
+    match transition {
+        BlankTransition::Blanked(reason) => {
+            log::info!("Screen blanked ({reason:?}), applying {policy:?}");
+            events.send(CaptureEvent::ScreenBlanked { reason, policy });
+            match policy {
+                ScreenBlankPolicy::KeepRecording => false,
+                ScreenBlankPolicy::AutoPause => {
+                    controls.auto_pause();
+                    false
+                }
+                // A paused stream has no frame to show
+                ScreenBlankPolicy::Placeholder if reason == BlankReason::StreamPaused => {
+                    controls.auto_pause();
+                    false
+                }
+                ScreenBlankPolicy::Placeholder => !controls.skip_processing(),
+            }
+        }
+        BlankTransition::Restored => {
+            log::info!("Screen restored");
+            events.send(CaptureEvent::ScreenRestored);
+            controls.auto_resume();
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(millis: u64) -> CaptureTime {
+        CaptureTime::from_nanos(0) + Duration::from_millis(millis)
+    }
+
+    /// Frames every 100ms from `start` to `end`, returning the transitions
+    fn feed(
+        detector: &mut BlankDetector,
+        start: u64,
+        end: u64,
+        black: bool,
+    ) -> Vec<BlankTransition> {
+        let mut transitions = Vec::new();
+        for millis in (start..end).step_by(100) {
+            if detector.wants_sample(at(millis)) {
+                transitions.extend(detector.on_sample(at(millis), black));
+            }
+        }
+        transitions
+    }
+
+    #[test]
+    fn black_frames_blank_after_a_while() {
+        let mut detector = BlankDetector::default();
+        assert!(feed(&mut detector, 0, 5_000, false).is_empty());
+        // A short fade to black is left alone
+        assert!(feed(&mut detector, 5_000, 7_000, true).is_empty());
+        assert!(feed(&mut detector, 7_000, 8_000, false).is_empty());
+
+        assert_eq!(
+            feed(&mut detector, 8_000, 20_000, true),
+            vec![BlankTransition::Blanked(BlankReason::BlackFrames)]
+        );
+        assert_eq!(
+            feed(&mut detector, 20_000, 21_000, false),
+            vec![BlankTransition::Restored]
+        );
+    }
+
+    #[test]
+    fn stream_pause_blanks_immediately() {
+        let mut detector = BlankDetector::default();
+        // Connecting goes through paused before streaming
+        assert_eq!(detector.stream_resumed(), None);
+        assert_eq!(
+            detector.stream_paused(),
+            Some(BlankTransition::Blanked(BlankReason::StreamPaused))
+        );
+        // Black frames while the stream is paused don't count twice
+        assert!(feed(&mut detector, 0, 10_000, true).is_empty());
+        assert_eq!(detector.stream_resumed(), Some(BlankTransition::Restored));
+    }
+
+    #[test]
+    fn samples_black_pixels() {
+        let (width, height, stride) = (64, 32, 64 * 4 + 16);
+        let mut frame = vec![0u8; 8 + stride * height as usize];
+        assert_eq!(
+            is_black(&frame, VideoFormat::BGRx, 8, stride, width, height),
+            Some(true)
+        );
+
+        // Padding bytes are ignored
+        frame[8 + 3] = 255;
+        assert_eq!(
+            is_black(&frame, VideoFormat::BGRA, 8, stride, width, height),
+            Some(true)
+        );
+
+        let center = 8 + 18 * stride + 36 * 4;
+        frame[center + 1] = 200;
+        assert_eq!(
+            is_black(&frame, VideoFormat::BGRx, 8, stride, width, height),
+            Some(false)
+        );
+        assert_eq!(
+            is_black(&frame[..100], VideoFormat::BGRx, 8, stride, width, height),
+            None
+        );
+    }
+}
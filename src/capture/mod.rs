@@ -1,4 +1,8 @@
 pub mod audio;
+pub mod audio_ring;
+mod blank;
+pub(crate) mod pip;
+pub(crate) mod synthetic;
 pub mod video;
 
 pub struct Terminate {}
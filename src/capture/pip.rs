@@ -0,0 +1,235 @@
+//! The second source of [`crate::types::config::VideoConfig::pip`], e.g. a webcam.
+//!
+//! The source's node is captured on a thread of its own through the user's PipeWire daemon.
+//! Its frames are scaled to the picture-in-picture size as they come in and kept in a short
+//! queue, from which the encoders take the one nearest to each captured frame. Nothing waits on
+//! the source, a stalled one just leaves its last frame in the queue.
+
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    thread::JoinHandle,
+};
+
+use pipewire::{
+    self as pw,
+    context::Context,
+    main_loop::MainLoop,
+    spa::{
+        param::video::{VideoFormat, VideoInfoRaw},
+        pod::Pod,
+        utils::{Direction, Fraction, Rectangle},
+    },
+    stream::{Stream, StreamFlags, StreamState},
+    sys::pw_stream_get_nsec,
+};
+use pw::properties::properties;
+
+use crate::{
+    encoders::{rgba_image_encoder::RgbaScaler, spa_format::VideoFormatOffer},
+    failure_injection,
+    types::{config::PipConfig, error::Result, time::CaptureTime},
+    CaptureControls,
+};
+
+use super::Terminate;
+
+/// Frames of the source kept to pick the nearest one from
+const KEPT_FRAMES: usize = 4;
+
+/// The latest frames of the source, scaled to the picture-in-picture size
+#[derive(Debug, Default)]
+pub(crate) struct PipFrames {
+    frames: Mutex<VecDeque<(CaptureTime, Arc<image::RgbaImage>)>>,
+}
+
+impl PipFrames {
+    /// Keep `image`, captured at `timestamp`, in place of the oldest frame
+    pub fn push(&self, timestamp: CaptureTime, image: image::RgbaImage) {
+        let mut frames = self.frames.lock().unwrap();
+        if frames.len() == KEPT_FRAMES {
+            frames.pop_front();
+        }
+        frames.push_back((timestamp, Arc::new(image)));
+    }
+
+    /// Frame captured nearest to `timestamp`, `None` until the first one arrived
+    pub fn nearest(&self, timestamp: CaptureTime) -> Option<Arc<image::RgbaImage>> {
+        let frames = self.frames.lock().unwrap();
+        frames
+            .iter()
+            .min_by_key(|(time, _)| time.abs_diff(timestamp))
+            .map(|(_, image)| Arc::clone(image))
+    }
+}
+
+#[derive(Default)]
+struct UserData {
+    video_format: VideoInfoRaw,
+    scaler: RgbaScaler,
+    /// Whether a frame failed to scale already, the next ones are not logged
+    warned: bool,
+}
+
+/// Capture the node of `config` into `frames` on a new thread, until the returned sender
+/// terminates it
+pub(crate) fn start(
+    config: PipConfig,
+    frames: Arc<PipFrames>,
+    controls: Arc<CaptureControls>,
+) -> (pw::channel::Sender<Terminate>, JoinHandle<Result<()>>) {
+    let (terminate_tx, terminate_rx) = pw::channel::channel();
+    let handle = std::thread::spawn(move || -> Result<()> {
+        let pw_loop = MainLoop::new(None)?;
+        let context = Context::new(&pw_loop)?;
+        let core = context.connect(None)?;
+        let _core_listener = core
+            .add_listener_local()
+            .error(|id, seq, res, message| {
+                log::error!("Picture-in-picture core error {id},{seq},{res}: {message}")
+            })
+            .register();
+        let stream = Stream::new(
+            &core,
+            "waycap-pip",
+            properties! {
+                *pw::keys::MEDIA_TYPE => "Video",
+                *pw::keys::MEDIA_CATEGORY => "Capture",
+                *pw::keys::MEDIA_ROLE => "Camera",
+            },
+        )?;
+        let _listener = stream
+            .add_local_listener_with_user_data(UserData::default())
+            .state_changed(move |_, _, old, new| {
+                log::debug!("Picture-in-picture stream state changed: {old:?} -> {new:?}");
+                if let StreamState::Error(e) = new {
+                    log::warn!("Lost the picture-in-picture source {}: {e}", config.node_id);
+                }
+            })
+            .param_changed(|_, user_data, id, param| {
+                let Some(param) = param else {
+                    return;
+                };
+                if id != pw::spa::param::ParamType::Format.as_raw() {
+                    return;
+                }
+                if let Err(e) = user_data.video_format.parse(param) {
+                    log::error!("Could not parse the picture-in-picture format: {e}");
+                    return;
+                }
+                let size = user_data.video_format.size();
+                log::debug!(
+                    "Picture-in-picture source negotiated {:?} at {}x{}",
+                    user_data.video_format.format(),
+                    size.width,
+                    size.height
+                );
+            })
+            .process(move |stream, user_data| {
+                let Some(mut buffer) = stream.dequeue_buffer() else {
+                    return;
+                };
+                if controls.skip_processing() {
+                    return;
+                }
+                let clock_ns = unsafe { pw_stream_get_nsec(stream.as_raw_ptr()) } as i64;
+                let timestamp = failure_injection::capture_time(&controls, clock_ns);
+                let Some(data) = buffer.datas_mut().first_mut() else {
+                    return;
+                };
+                let offset = data.chunk().offset() as usize;
+                let stride = data.chunk().stride() as usize;
+                let Some(bytes) = data.data() else {
+                    return;
+                };
+                let size = user_data.video_format.size();
+                let scaled = user_data.scaler.scale(
+                    bytes.get(offset..).unwrap_or_default(),
+                    stride,
+                    user_data.video_format.format(),
+                    (size.width, size.height),
+                    (config.width, config.height),
+                );
+                match scaled {
+                    Ok(image) => frames.push(timestamp, image),
+                    Err(e) if !user_data.warned => {
+                        log::warn!("Could not scale a picture-in-picture frame: {e}");
+                        user_data.warned = true;
+                    }
+                    Err(_) => {}
+                }
+            })
+            .register()?;
+        connect(&stream, config)?;
+
+        let quit_loop = pw_loop.clone();
+        let _terminate = terminate_rx.attach(pw_loop.loop_(), move |_| {
+            log::debug!("Terminating picture-in-picture capture loop");
+            quit_loop.quit();
+        });
+        pw_loop.run();
+        Ok(())
+    });
+    (terminate_tx, handle)
+}
+
+/// Connect `stream` to the node of `config`, asking for shared memory frames close to the
+/// picture-in-picture size, which the CPU scales
+fn connect(stream: &Stream, config: PipConfig) -> Result<()> {
+    let mut offer = VideoFormatOffer::new(vec![
+        VideoFormat::YUY2,
+        VideoFormat::BGRx,
+        VideoFormat::BGRA,
+        VideoFormat::RGBx,
+        VideoFormat::RGBA,
+    ]);
+    offer.size_range.default = Rectangle {
+        width: config.width,
+        height: config.height,
+    };
+    offer.framerate_range.default = Fraction { num: 30, denom: 1 };
+    let values = pw::spa::pod::serialize::PodSerializer::serialize(
+        std::io::Cursor::new(Vec::new()),
+        &pw::spa::pod::Value::Object(offer.to_object()),
+    )
+    .unwrap()
+    .0
+    .into_inner();
+    let mut params = [Pod::from_bytes(&values).unwrap()];
+    stream.connect(
+        Direction::Input,
+        Some(config.node_id),
+        StreamFlags::AUTOCONNECT | StreamFlags::MAP_BUFFERS,
+        &mut params,
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(shade: u8) -> image::RgbaImage {
+        image::RgbaImage::from_pixel(2, 2, image::Rgba([shade, shade, shade, 255]))
+    }
+
+    #[test]
+    fn the_nearest_frame_is_picked_without_waiting() {
+        let frames = PipFrames::default();
+        assert!(frames.nearest(CaptureTime::from_nanos(0)).is_none());
+
+        // A 30fps camera, the oldest of its frames are replaced
+        for shade in 0..6 {
+            frames.push(
+                CaptureTime::from_nanos(i64::from(shade) * 33_000_000),
+                frame(shade),
+            );
+        }
+        let shade = |nanos| frames.nearest(CaptureTime::from_nanos(nanos)).unwrap()[(0, 0)][0];
+        assert_eq!(shade(0), 2);
+        assert_eq!(shade(110_000_000), 3);
+        assert_eq!(shade(120_000_000), 4);
+        // A stalled camera keeps its last frame
+        assert_eq!(shade(10_000_000_000), 5);
+    }
+}
@@ -0,0 +1,130 @@
+//! A generated video source standing in for the portal, e.g. to run the examples headless.
+//!
+//! Frames are BGRx in memory, a gradient with a bar moving across it so consecutive frames
+//! differ, sent at the target framerate on the same clock as PipeWire's. They go through the
+//! same pausing, cutoff and queueing as frames of a PipeWire stream, and the failure injection
+//! hooks of the PipeWire process callback apply to them as well.
+
+use std::{sync::Arc, thread::JoinHandle, time::Duration};
+
+use crossbeam::channel::{Sender, TrySendError};
+use pipewire::spa::param::video::VideoFormat;
+
+use crate::{
+    failure_injection,
+    pipeline::shutdown::{capture_clock_ns, StreamKind},
+    types::{
+        error::Result,
+        event::PipelineStage,
+        stats::StatsCounters,
+        video_frame::{RawVideoFrame, VideoStreamInfo},
+    },
+    CaptureControls, ReadyState,
+};
+
+/// Width of the moving bar in pixels
+const BAR_WIDTH: u32 = 16;
+
+/// What the synthetic source negotiates for `width` x `height`
+pub(crate) fn stream_info(width: u32, height: u32, target_fps: u64) -> VideoStreamInfo {
+    VideoStreamInfo {
+        width,
+        height,
+        format: VideoFormat::BGRx,
+        framerate: (target_fps as u32, 1),
+    }
+}
+
+/// Send frames into `frame_tx` on a new thread until the capture is stopped, once `ready_state`
+/// reports audio as ready. The stream is reported ready right away.
+pub(crate) fn start(
+    info: VideoStreamInfo,
+    ready_state: Arc<ReadyState>,
+    controls: Arc<CaptureControls>,
+    stats: Arc<StatsCounters>,
+    frame_tx: Sender<RawVideoFrame>,
+) -> JoinHandle<Result<()>> {
+    ready_state.set_video_ready(true);
+    std::thread::spawn(move || {
+        let mut index = 0u32;
+        while !controls.is_stopped() {
+            std::thread::sleep(Duration::from_nanos(controls.frame_interval_ns()));
+            stats.mark_frame_received();
+
+            // Like a PipeWire stream in the error state, which delivers no more frames
+            if failure_injection::take_stream_error(&controls) {
+                log::error!("Injected stream error");
+                ready_state.set_video_ready(false);
+                break;
+            }
+
+            // Wait until audio is streaming before we try to process
+            if !ready_state.audio_ready() || controls.skip_processing() {
+                continue;
+            }
+
+            let timestamp = failure_injection::capture_time(&controls, capture_clock_ns());
+            if !controls.cutoff().accepts(timestamp) {
+                continue;
+            }
+            let frame = RawVideoFrame {
+                data: pattern(info.width, info.height, index),
+                timestamp,
+                ..RawVideoFrame::bgrx(info.width, info.height)
+            };
+            index = index.wrapping_add(1);
+
+            controls.cutoff().frame_queued(StreamKind::Video);
+            match failure_injection::try_send(&controls, &frame_tx, frame) {
+                Ok(_) => stats.mark_frame_queued(),
+                Err(TrySendError::Full(frame)) => {
+                    controls.cutoff().frame_done(StreamKind::Video);
+                    log::error!(
+                        "Could not send video frame at: {}. Channel full.",
+                        frame.timestamp
+                    );
+                    stats.mark_capture_dropped();
+                    stats.record_error(PipelineStage::Capture, "Raw frame channel full");
+                }
+                Err(TrySendError::Disconnected(_)) => {
+                    controls.cutoff().frame_done(StreamKind::Video);
+                    break;
+                }
+            }
+        }
+        Ok(())
+    })
+}
+
+/// The `index`th BGRx frame, a gradient with a white bar moving one bar width per frame
+fn pattern(width: u32, height: u32, index: u32) -> Vec<u8> {
+    let bar = index.wrapping_mul(BAR_WIDTH) % width.max(1);
+    let mut data = Vec::with_capacity((width * height * 4) as usize);
+    for y in 0..height {
+        for x in 0..width {
+            if (bar..bar + BAR_WIDTH).contains(&x) {
+                data.extend_from_slice(&[255, 255, 255, 255]);
+            } else {
+                let blue = (x * 255 / width.max(1)) as u8;
+                let green = (y * 255 / height.max(1)) as u8;
+                data.extend_from_slice(&[blue, green, 128, 255]);
+            }
+        }
+    }
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_fill_the_buffer_and_move() {
+        let first = pattern(64, 48, 0);
+        let second = pattern(64, 48, 1);
+        assert_eq!(first.len(), RawVideoFrame::bgrx(64, 48).size as usize);
+        assert_ne!(first, second);
+        // The bar wraps around instead of running off the frame
+        assert_eq!(pattern(64, 48, 4), first);
+    }
+}
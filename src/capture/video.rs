@@ -1,5 +1,6 @@
 use std::{
     os::fd::{FromRawFd, OwnedFd, RawFd},
+    ptr::NonNull,
     sync::{
         mpsc::{self},
         Arc,
@@ -13,10 +14,11 @@ use pipewire::{
     core::{Core, Listener},
     main_loop::MainLoop,
     spa::{
-        buffer::{Data, DataType},
+        buffer::{ChunkFlags, Data, DataType},
+        param::video::VideoFormat,
         utils::Direction,
     },
-    stream::{Stream, StreamFlags, StreamListener, StreamState},
+    stream::{Stream, StreamFlags, StreamListener, StreamRef, StreamState},
     sys::pw_stream_get_nsec,
 };
 use pw::{properties::properties, spa};
@@ -24,13 +26,25 @@ use pw::{properties::properties, spa};
 use spa::pod::Pod;
 
 use crate::{
+    encoders::spa_format,
+    failure_injection,
+    pipeline::{interleaver::InterleaverControl, shutdown::StreamKind},
+    portal::SourceTracker,
     types::{
+        config::{ScreenBlankPolicy, Transform},
         error::{Result, WaycapError},
-        video_frame::RawVideoFrame,
-    }, CaptureControls, ReadyState, Resolution
+        event::{CaptureEvent, EventSender, PipelineStage},
+        media_packet::{CursorUpdate, MediaPacket},
+        stats::StatsCounters,
+        time::CaptureTime,
+        video_frame::{DmaBufPlane, RawVideoFrame, VideoStreamInfo},
+    }, utils::{export_read_fence, own_dmabuf_fds}, CaptureControls, ReadyState
 };
 
-use super::Terminate;
+use super::{
+    blank::{self, BlankDetector},
+    Terminate,
+};
 
 
 
@@ -49,9 +63,140 @@ struct PipewireState {
     _stream_listener: StreamListener<UserData>,
 }
 
+/// Consecutive corrupted buffers after which a [`CaptureEvent::CorruptedBuffers`] is emitted
+const CORRUPTED_BUFFERS_WARNING: u32 = 30;
+
+/// Damaged regions room is asked for in the buffer metadata
+const DAMAGE_REGIONS: usize = 16;
+
+/// Largest cursor image room is asked for in the cursor metadata, the usual 64x64 ARGB
+const CURSOR_BITMAP_SIZE: usize = 64 * 64 * 4;
+
 #[derive(Clone, Copy, Default)]
 struct UserData {
     video_format: spa::param::video::VideoInfoRaw,
+    consecutive_corrupted: u32,
+    blank: BlankDetector,
+    /// Damage of buffers which were dropped, carried over to the next frame sent
+    dropped_damage: bool,
+    /// Position and hotspot of the last cursor update sent
+    cursor: Option<((i32, i32), (i32, i32))>,
+}
+
+/// Buffer dequeued through the raw API, which unlike [`pw::buffer::Buffer`] gives access to the
+/// metadata. Queued back to the stream on drop.
+struct RawBuffer<'s> {
+    buffer: NonNull<pw::sys::pw_buffer>,
+    stream: &'s StreamRef,
+}
+
+impl<'s> RawBuffer<'s> {
+    fn dequeue(stream: &'s StreamRef) -> Option<Self> {
+        let buffer = NonNull::new(unsafe { stream.dequeue_raw_buffer() })?;
+        Some(Self { buffer, stream })
+    }
+
+    fn spa_buffer(&self) -> *mut spa::sys::spa_buffer {
+        unsafe { self.buffer.as_ref().buffer }
+    }
+
+    fn datas_mut(&mut self) -> &mut [Data] {
+        let buffer = self.spa_buffer();
+        unsafe {
+            if buffer.is_null() || (*buffer).n_datas == 0 || (*buffer).datas.is_null() {
+                return &mut [];
+            }
+            // Data is a transparent wrapper around spa_data
+            std::slice::from_raw_parts_mut((*buffer).datas as *mut Data, (*buffer).n_datas as usize)
+        }
+    }
+
+    /// Metadata of `type_` the producer attached to the buffer, laid out as `T`
+    fn meta<T>(&self, type_: u32) -> Option<&T> {
+        let buffer = self.spa_buffer();
+        if buffer.is_null() {
+            return None;
+        }
+        unsafe {
+            let metas = (*buffer).metas;
+            (0..(*buffer).n_metas as usize)
+                .map(|i| &*metas.add(i))
+                .find(|meta| {
+                    meta.type_ == type_
+                        && !meta.data.is_null()
+                        && meta.size as usize >= std::mem::size_of::<T>()
+                })
+                .map(|meta| &*(meta.data as *const T))
+        }
+    }
+
+    /// Whether the producer flagged the buffer as corrupted, either in its header metadata or
+    /// on the chunk
+    fn is_corrupted(&mut self) -> bool {
+        let header_corrupted = self
+            .meta::<spa::sys::spa_meta_header>(spa::sys::SPA_META_Header)
+            .is_some_and(|header| header.flags & spa::sys::SPA_META_HEADER_FLAG_CORRUPTED != 0);
+
+        header_corrupted
+            || self
+                .datas_mut()
+                .first()
+                .is_some_and(|data| data.chunk().flags().contains(ChunkFlags::CORRUPTED))
+    }
+
+    /// Whether the buffer carries only metadata, like the cursor updates compositors send
+    /// between frames
+    fn is_metadata_only(&mut self) -> bool {
+        self.datas_mut()
+            .first()
+            .is_some_and(|data| data.chunk().size() == 0)
+    }
+
+    /// Cursor position and, when the cursor image came along, its hotspot. `None` when the
+    /// buffer carries no cursor update.
+    fn cursor(&self) -> Option<((i32, i32), Option<(i32, i32)>)> {
+        let cursor = self.meta::<spa::sys::spa_meta_cursor>(spa::sys::SPA_META_Cursor)?;
+        // An id of 0 marks the metadata as unset
+        if cursor.id == 0 {
+            return None;
+        }
+        let hotspot = (cursor.bitmap_offset != 0).then_some((cursor.hotspot.x, cursor.hotspot.y));
+        Some(((cursor.position.x, cursor.position.y), hotspot))
+    }
+
+    /// Whether the compositor's damage metadata marks a region of the buffer as changed, `None`
+    /// when it attaches none
+    fn damaged(&self) -> Option<bool> {
+        let damage = self.meta::<spa::sys::spa_meta_region>(spa::sys::SPA_META_VideoDamage)?;
+        // The regions end at the first empty one, an unchanged buffer starts with it
+        let size = damage.region.size;
+        Some(size.width != 0 && size.height != 0)
+    }
+
+    /// How the compositor says the buffer has to be turned, e.g. for a rotated monitor
+    fn transform(&self) -> Transform {
+        let Some(meta) =
+            self.meta::<spa::sys::spa_meta_videotransform>(spa::sys::SPA_META_VideoTransform)
+        else {
+            return Transform::Normal;
+        };
+        match meta.transform {
+            spa::sys::SPA_META_TRANSFORMATION_90 => Transform::Rot90,
+            spa::sys::SPA_META_TRANSFORMATION_180 => Transform::Rot180,
+            spa::sys::SPA_META_TRANSFORMATION_270 => Transform::Rot270,
+            spa::sys::SPA_META_TRANSFORMATION_Flipped => Transform::Flipped,
+            spa::sys::SPA_META_TRANSFORMATION_Flipped90 => Transform::Flipped90,
+            spa::sys::SPA_META_TRANSFORMATION_Flipped180 => Transform::Flipped180,
+            spa::sys::SPA_META_TRANSFORMATION_Flipped270 => Transform::Flipped270,
+            _ => Transform::Normal,
+        }
+    }
+}
+
+impl Drop for RawBuffer<'_> {
+    fn drop(&mut self) {
+        unsafe { self.stream.queue_raw_buffer(self.buffer.as_ptr()) };
+    }
 }
 
 impl VideoCapture {
@@ -61,10 +206,15 @@ impl VideoCapture {
         stream_node: u32,
         ready_state: Arc<ReadyState>,
         controls: Arc<CaptureControls>,
-        resolution_sender: mpsc::Sender<Resolution>,
+        stream_info_sender: mpsc::Sender<VideoStreamInfo>,
         frame_tx: Sender<RawVideoFrame>,
         termination_recv: pw::channel::Receiver<Terminate>,
-        pw_obj: spa::pod::Object,
+        pw_objs: Vec<spa::pod::Object>,
+        stats: Arc<StatsCounters>,
+        events: EventSender,
+        source: Arc<SourceTracker>,
+        blank_policy: ScreenBlankPolicy,
+        cursor_tx: Option<Sender<InterleaverControl>>,
     ) -> Result<Self> {
         let pw_loop = MainLoop::new(None)?;
         let context = Context::new(&pw_loop)?;
@@ -76,10 +226,15 @@ impl VideoCapture {
             UserData::default(),
             ready_state,
             &controls,
-            resolution_sender.clone(),
+            stream_info_sender.clone(),
             frame_tx.clone(),
+            stats,
+            events,
+            source,
+            blank_policy,
+            cursor_tx,
         )?;
-        Self::connect_stream(&mut stream, stream_node, pw_obj)?;
+        Self::connect_stream(&mut stream, stream_node, pw_objs)?;
 
         Ok(Self {
             termination_recv: Some(termination_recv),
@@ -124,22 +279,44 @@ impl VideoCapture {
         data: UserData,
         ready_state: Arc<ReadyState>,
         controls: &Arc<CaptureControls>,
-        resolution_sender: mpsc::Sender<Resolution>,
+        stream_info_sender: mpsc::Sender<VideoStreamInfo>,
         frame_tx: Sender<RawVideoFrame>,
+        stats: Arc<StatsCounters>,
+        events: EventSender,
+        source: Arc<SourceTracker>,
+        blank_policy: ScreenBlankPolicy,
+        cursor_tx: Option<Sender<InterleaverControl>>,
     ) -> Result<StreamListener<UserData>> {
+        let wants_cursor = cursor_tx.is_some();
         let ready_state_clone = Arc::clone(&ready_state);
         let controls_clone = Arc::clone(controls);
+        let blank_controls = Arc::clone(controls);
+        let blank_events = events.clone();
 
         let stream_listener = stream
             .add_local_listener_with_user_data(data)
-            .state_changed(move |_, _, old, new| {
+            .state_changed(move |_, user_data, old, new| {
                 log::info!("Video Stream State Changed: {old:?} -> {new:?}");
-                ready_state.video.store(
-                    new == StreamState::Streaming,
-                    std::sync::atomic::Ordering::Release,
-                );
+                ready_state.set_video_ready(new == StreamState::Streaming);
+
+                // Pausing the capture, e.g. in finish, pauses the stream too
+                let transition = match (&old, &new) {
+                    (StreamState::Streaming, StreamState::Paused)
+                        if !blank_controls.skip_processing() =>
+                    {
+                        user_data.blank.stream_paused()
+                    }
+                    (StreamState::Paused, StreamState::Streaming) => {
+                        user_data.blank.stream_resumed()
+                    }
+                    _ => None,
+                };
+                if let Some(transition) = transition {
+                    // A paused stream has no frame which could be the placeholder
+                    blank::apply_policy(transition, blank_policy, &blank_controls, &blank_events);
+                }
             })
-            .param_changed(move |_, user_data, id, param| {
+            .param_changed(move |stream, user_data, id, param| {
                 let Some(param) = param else {
                     return;
                 };
@@ -154,34 +331,88 @@ impl VideoCapture {
                         Err(_) => return,
                     };
 
-                if media_type != pw::spa::param::format::MediaType::Video
-                    || media_subtype != pw::spa::param::format::MediaSubtype::Raw
-                {   
+                if media_type != pw::spa::param::format::MediaType::Video {
                     return;
                 }
-
-                user_data
-                    .video_format
-                    .parse(param)
-                    .expect("Failed to parse param");
+                match media_subtype {
+                    pw::spa::param::format::MediaSubtype::Raw => {
+                        user_data
+                            .video_format
+                            .parse(param)
+                            .expect("Failed to parse param");
+                    }
+                    // Offered when passthrough was asked for, the frames are access units
+                    pw::spa::param::format::MediaSubtype::H264 => {
+                        match spa_format::encoded_video_info(param) {
+                            Some(info) => user_data.video_format = info,
+                            None => {
+                                log::error!("Negotiated an H.264 format without a size");
+                                return;
+                            }
+                        }
+                    }
+                    _ => return,
+                }
 
                 log::debug!(
                     "  format: {} ({:?})",
                     user_data.video_format.format().as_raw(),
                     user_data.video_format.format()
                 );
-
-                let (width, height) = (
-
-                    user_data.video_format.size().width,
-                    user_data.video_format.size().height,
-                    );
-                match resolution_sender.send(Resolution { width, height }) {
-                    Ok(_) => {}
-                    Err(e) => {
-                        log::error!("Tried to send resolution update {width}x{height} but ran into an error on the channel: {e}");
-                    }
+                log::debug!("  modifier: {:#x}", user_data.video_format.modifier());
+
+                let stream_info = VideoStreamInfo {
+                    width: user_data.video_format.size().width,
+                    height: user_data.video_format.size().height,
+                    format: user_data.video_format.format(),
+                    framerate: (
+                        user_data.video_format.framerate().num,
+                        user_data.video_format.framerate().denom,
+                    ),
                 };
+                source.negotiated(stream_info.width, stream_info.height);
+                // Only the first negotiation is waited for, the encoder follows later ones when
+                // the first frame of the new size or format reaches it
+                if stream_info_sender.send(stream_info).is_err() {
+                    log::debug!(
+                        "Stream renegotiated to {}x{}",
+                        stream_info.width,
+                        stream_info.height
+                    );
+                }
+
+                // Ask for the header metadata which flags corrupted buffers, the transform of
+                // rotated monitors and the damaged regions
+                let header_param = Self::meta_param(
+                    spa::sys::SPA_META_Header,
+                    std::mem::size_of::<spa::sys::spa_meta_header>(),
+                );
+                let transform_param = Self::meta_param(
+                    spa::sys::SPA_META_VideoTransform,
+                    std::mem::size_of::<spa::sys::spa_meta_videotransform>(),
+                );
+                let damage_param = Self::meta_param(
+                    spa::sys::SPA_META_VideoDamage,
+                    std::mem::size_of::<spa::sys::spa_meta_region>() * DAMAGE_REGIONS,
+                );
+                // And for the cursor when it is delivered as metadata
+                let cursor_param = Self::meta_param(
+                    spa::sys::SPA_META_Cursor,
+                    std::mem::size_of::<spa::sys::spa_meta_cursor>()
+                        + std::mem::size_of::<spa::sys::spa_meta_bitmap>()
+                        + CURSOR_BITMAP_SIZE,
+                );
+                let mut params = vec![
+                    Pod::from_bytes(&header_param).unwrap(),
+                    Pod::from_bytes(&transform_param).unwrap(),
+                    Pod::from_bytes(&damage_param).unwrap(),
+                ];
+                if wants_cursor {
+                    params.push(Pod::from_bytes(&cursor_param).unwrap());
+                }
+                if let Err(e) = stream.update_params(&mut params) {
+                    log::warn!("Could not request buffer metadata: {e}");
+                }
 
                 log::debug!(
                     "  size: {}x{}",
@@ -195,42 +426,161 @@ impl VideoCapture {
                 );
             })
             .process(move |stream, udata| {
-                match stream.dequeue_buffer() {
+                match RawBuffer::dequeue(stream) {
                     None => log::debug!("out of buffers"),
                     Some(mut buffer) => {
+                        stats.mark_frame_received();
+
+                        if failure_injection::take_stream_error(&controls_clone) {
+                            unsafe {
+                                pw::sys::pw_stream_set_error(
+                                    stream.as_raw_ptr(),
+                                    -libc::EIO,
+                                    c"Injected stream error".as_ptr(),
+                                );
+                            }
+                            return;
+                        }
+
+                        // Runs while paused too, an auto-paused capture resumes from here
+                        let mut placeholder = false;
+                        if ready_state_clone.audio_ready() && !controls_clone.is_stopped() {
+                            let clock_ns =
+                                unsafe { pw_stream_get_nsec(stream.as_raw_ptr()) } as i64;
+                            let now = CaptureTime::from_nanos(clock_ns);
+                            if udata.blank.wants_sample(now) && !buffer.is_corrupted() {
+                                let transition =
+                                    Self::sample_black(&mut buffer, &udata.video_format)
+                                        .and_then(|black| udata.blank.on_sample(now, black));
+                                if let Some(transition) = transition {
+                                    placeholder = blank::apply_policy(
+                                        transition,
+                                        blank_policy,
+                                        &controls_clone,
+                                        &events,
+                                    );
+                                }
+                            }
+                        }
+
                         // Wait until audio is streaming before we try to process
                         if !ready_state_clone.audio_ready() || controls_clone.skip_processing() {
                             return;
                         }
 
+                        if let Some(ref cursor_tx) = cursor_tx {
+                            let clock_ns =
+                                unsafe { pw_stream_get_nsec(stream.as_raw_ptr()) } as i64;
+                            let timestamp =
+                                failure_injection::capture_time(&controls_clone, clock_ns);
+                            if controls_clone.cutoff().accepts(timestamp) {
+                                Self::send_cursor(&buffer, &mut udata.cursor, timestamp, cursor_tx);
+                            }
+                            // Cursor only updates have no frame to encode
+                            if buffer.is_metadata_only() {
+                                return;
+                            }
+                        }
+
+                        // Corrupted buffers, e.g. during DPMS transitions, would show up as
+                        // garbage in the recording. Skipping them just leaves a gap.
+                        if buffer.is_corrupted() {
+                            stats.mark_frame_corrupted();
+                            udata.consecutive_corrupted += 1;
+                            if udata.consecutive_corrupted == CORRUPTED_BUFFERS_WARNING {
+                                log::warn!(
+                                    "Received {} corrupted buffers in a row",
+                                    udata.consecutive_corrupted
+                                );
+                                events.send(CaptureEvent::CorruptedBuffers {
+                                    consecutive: udata.consecutive_corrupted,
+                                });
+                            }
+                            return;
+                        }
+                        udata.consecutive_corrupted = 0;
+
+                        let transform = buffer.transform();
+                        let damaged = buffer
+                            .damaged()
+                            .map(|damaged| std::mem::take(&mut udata.dropped_damage) | damaged);
                         let datas = buffer.datas_mut();
                         if datas.is_empty() {
                             return;
                         }
+                        // Multi-planar formats such as NV12 come with a data per plane
+                        let mut planes: Vec<_> = datas
+                            .iter()
+                            .map_while(|data| {
+                                Some(DmaBufPlane {
+                                    fd: Self::get_dmabuf_fd(data)?,
+                                    offset: data.chunk().offset(),
+                                    stride: data.chunk().stride() as u32,
+                                })
+                            })
+                            .collect();
 
                         let data = &mut datas[0];
 
-                        let fd = Self::get_dmabuf_fd(data);
+                        let mut fd = Self::get_dmabuf_fd(data);
 
-                        match frame_tx.try_send(RawVideoFrame {
-                            data: data.data().unwrap_or_default().to_vec(),
-                            timestamp: unsafe { pw_stream_get_nsec(stream.as_raw_ptr())} as i64,
+                        let clock_ns = unsafe { pw_stream_get_nsec(stream.as_raw_ptr()) } as i64;
+                        let timestamp = failure_injection::capture_time(&controls_clone, clock_ns);
+                        if !controls_clone.cutoff().accepts(timestamp) {
+                            return;
+                        }
+                        // PipeWire reuses the buffer once this callback returns, the encoder may
+                        // still read it then
+                        let owned_fds = match own_dmabuf_fds(&mut fd, &mut planes) {
+                            Ok(owned_fds) => owned_fds,
+                            Err(e) => {
+                                log::error!("Could not duplicate the DMA-BUF of a frame: {e}");
+                                stats.mark_capture_dropped();
+                                stats.record_error(PipelineStage::Capture, &e);
+                                return;
+                            }
+                        };
+
+                        let filled = (data.chunk().offset(), data.chunk().size());
+                        let bytes = data.data().unwrap_or_default();
+                        let bytes = match udata.video_format.format() {
+                            VideoFormat::Encoded => Self::filled_bytes(bytes, filled),
+                            _ => bytes,
+                        }
+                        .to_vec();
+                        let frame = RawVideoFrame {
+                            data: bytes,
+                            timestamp,
                             dmabuf_fd: fd,
                             stride: data.chunk().stride(),
                             offset: data.chunk().offset(),
                             size: data.chunk().size(),
                             modifier: udata.video_format.modifier(),
                             format: udata.video_format.format(),
-                            dimensions: udata.video_format.size()
-                        }) {
-                            Ok(_) => {}
+                            dimensions: udata.video_format.size(),
+                            transform,
+                            force_keyframe: false,
+                            damaged,
+                            planes,
+                            fence: fd.and_then(export_read_fence),
+                            owned_fds,
+                        };
+                        controls_clone.cutoff().frame_queued(StreamKind::Video);
+                        match failure_injection::try_send(&controls_clone, &frame_tx, frame) {
+                            Ok(_) => stats.mark_frame_queued(),
                             Err(crossbeam::channel::TrySendError::Full(frame)) => {
+                                controls_clone.cutoff().frame_done(StreamKind::Video);
+                                udata.dropped_damage |= frame.damaged.unwrap_or(false);
                                 log::error!(
                                     "Could not send video frame at: {}. Channel full.",
                                     frame.timestamp
                                 );
+                                stats.mark_capture_dropped();
+                                stats
+                                    .record_error(PipelineStage::Capture, "Raw frame channel full");
                             }
                             Err(crossbeam::channel::TrySendError::Disconnected(frame)) => {
+                                controls_clone.cutoff().frame_done(StreamKind::Video);
                                 // TODO: If we disconnected, terminate the session instead of
                                 // throwing an error it means the receiver was dropped.
                                 log::error!(
@@ -239,6 +589,10 @@ impl VideoCapture {
                                 );
                             }
                         }
+                        // Sampled frames are never corrupted, only a cutoff can skip this
+                        if placeholder {
+                            controls_clone.auto_pause();
+                        }
                     }
                 }
             })
@@ -247,20 +601,113 @@ impl VideoCapture {
         Ok(stream_listener)
     }
 
-    fn connect_stream(
-        stream: &mut Stream,
-        stream_node: u32,
-        pw_obj: spa::pod::Object,
-    ) -> Result<()> {
-        let video_spa_values: Vec<u8> = pw::spa::pod::serialize::PodSerializer::serialize(
+    /// Send the cursor update of `buffer` to the single output, unless the cursor stayed where
+    /// it was in `last`
+    fn send_cursor(
+        buffer: &RawBuffer,
+        last: &mut Option<((i32, i32), (i32, i32))>,
+        timestamp: CaptureTime,
+        cursor_tx: &Sender<InterleaverControl>,
+    ) {
+        let Some((position, hotspot)) = buffer.cursor() else {
+            return;
+        };
+        // The hotspot only comes along with a new cursor image
+        let hotspot = hotspot
+            .or(last.map(|(_, hotspot)| hotspot))
+            .unwrap_or_default();
+        if *last == Some((position, hotspot)) {
+            return;
+        }
+        *last = Some((position, hotspot));
+        let update = CursorUpdate {
+            timestamp,
+            position,
+            hotspot,
+        };
+        if cursor_tx
+            .try_send(InterleaverControl::Sparse(MediaPacket::Cursor(update)))
+            .is_err()
+        {
+            log::debug!("Media receiver is not keeping up, dropping a cursor update");
+        }
+    }
+
+    /// The `(offset, size)` its chunk says was filled of a mapped buffer, e.g. one encoded
+    /// access unit
+    fn filled_bytes(bytes: &mut [u8], (offset, size): (u32, u32)) -> &mut [u8] {
+        let start = (offset as usize).min(bytes.len());
+        let end = start.saturating_add(size as usize).min(bytes.len());
+        &mut bytes[start..end]
+    }
+
+    /// Run the black frame heuristic on the mapped buffer, `None` when it can't be read
+    fn sample_black(
+        buffer: &mut RawBuffer,
+        video_format: &spa::param::video::VideoInfoRaw,
+    ) -> Option<bool> {
+        let data = buffer.datas_mut().first_mut()?;
+        let offset = data.chunk().offset() as usize;
+        let stride = data.chunk().stride() as usize;
+        let size = video_format.size();
+        blank::is_black(
+            data.data()?,
+            video_format.format(),
+            offset,
+            stride,
+            size.width,
+            size.height,
+        )
+    }
+
+    fn meta_param(meta_type: u32, size: usize) -> Vec<u8> {
+        let meta_obj = spa::pod::Object {
+            type_: spa::utils::SpaTypes::ObjectParamMeta.as_raw(),
+            id: spa::param::ParamType::Meta.as_raw(),
+            properties: vec![
+                spa::pod::Property::new(
+                    spa::sys::SPA_PARAM_META_type,
+                    spa::pod::Value::Id(spa::utils::Id(meta_type)),
+                ),
+                spa::pod::Property::new(
+                    spa::sys::SPA_PARAM_META_size,
+                    spa::pod::Value::Int(size as i32),
+                ),
+            ],
+        };
+
+        pw::spa::pod::serialize::PodSerializer::serialize(
             std::io::Cursor::new(Vec::new()),
-            &pw::spa::pod::Value::Object(pw_obj),
+            &pw::spa::pod::Value::Object(meta_obj),
         )
         .unwrap()
         .0
-        .into_inner();
+        .into_inner()
+    }
+
+    fn connect_stream(
+        stream: &mut Stream,
+        stream_node: u32,
+        pw_objs: Vec<spa::pod::Object>,
+    ) -> Result<()> {
+        // PipeWire tries the offered formats in order, so earlier objects take precedence
+        let video_spa_values: Vec<Vec<u8>> = pw_objs
+            .into_iter()
+            .map(|pw_obj| {
+                pw::spa::pod::serialize::PodSerializer::serialize(
+                    std::io::Cursor::new(Vec::new()),
+                    &pw::spa::pod::Value::Object(pw_obj),
+                )
+                .unwrap()
+                .0
+                .into_inner()
+            })
+            .collect();
 
-        let mut video_params = [Pod::from_bytes(&video_spa_values).unwrap()];
+        let mut video_params: Vec<&Pod> = video_spa_values
+            .iter()
+            .map(|values| Pod::from_bytes(values).unwrap())
+            .collect();
         stream.connect(
             Direction::Input,
             Some(stream_node),
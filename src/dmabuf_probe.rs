@@ -0,0 +1,285 @@
+//! What the GPU the hardware encoders open imports DMA-BUFs with.
+//!
+//! EGL is opened on the device platform for [`RENDER_NODE`] and asked through
+//! `EGL_EXT_image_dma_buf_import_modifiers`, so the modifiers offered to the compositor are those
+//! of the GPU encoding the frames even when the compositor renders on another one. Without the
+//! device platform or the extension each vendor gets modifiers its VAAPI driver is known to take.
+
+use std::{
+    ffi::{c_char, c_void, CStr},
+    fs, iter,
+    path::PathBuf,
+    ptr::null_mut,
+};
+
+use drm_fourcc::{DrmFourcc, DrmModifier};
+use khronos_egl::{self as egl, Dynamic, Instance};
+
+use crate::{types::error::Result, waycap_egl::GpuVendor};
+
+/// Render node the hardware encoders open
+pub(crate) const RENDER_NODE: &str = "/dev/dri/renderD128";
+
+const PLATFORM_DEVICE_EXT: egl::Enum = 0x313F;
+const DRM_DEVICE_FILE_EXT: egl::Int = 0x3233;
+const DRM_RENDER_NODE_FILE_EXT: egl::Int = 0x3377;
+
+pub(crate) type EglInstance = Instance<Dynamic<libloading::Library, egl::EGL1_5>>;
+
+type PFNEGLQUERYDEVICESEXTPROC = unsafe extern "C" fn(
+    max_devices: egl::Int,
+    devices: *mut *mut c_void,
+    num_devices: *mut egl::Int,
+) -> egl::Boolean;
+
+type PFNEGLQUERYDEVICESTRINGEXTPROC =
+    unsafe extern "C" fn(device: *mut c_void, name: egl::Int) -> *const c_char;
+
+type PFNEGLQUERYDMABUFMODIFIERSEXTPROC = unsafe extern "C" fn(
+    display: *mut c_void,
+    format: egl::Int,
+    max_modifiers: egl::Int,
+    modifiers: *mut u64,
+    external_only: *mut egl::Boolean,
+    num_modifiers: *mut egl::Int,
+) -> egl::Boolean;
+
+/// Whether `display` imports DMA-BUFs, and whether it takes and lists their modifiers. Fails
+/// when it can't import them at all.
+pub(crate) fn dmabuf_support(
+    instance: &EglInstance,
+    display: egl::Display,
+) -> Result<(bool, bool)> {
+    let extensions = instance.query_string(Some(display), egl::EXTENSIONS)?;
+    let extensions = extensions.to_string_lossy();
+
+    let dmabuf_import = extensions.contains("EGL_EXT_image_dma_buf_import");
+    let dmabuf_modifiers = extensions.contains("EGL_EXT_image_dma_buf_import_modifiers");
+
+    if !dmabuf_import {
+        return Err("EGL_EXT_image_dma_buf_import not supported".into());
+    }
+
+    Ok((dmabuf_import, dmabuf_modifiers))
+}
+
+/// Modifiers offered for DMA-BUFs of the DRM `format`: linear as the default, which the frames
+/// read on the CPU need, then the tiled and compressed ones the driver imports, which some
+/// compositors export only
+pub(crate) fn importable_modifiers(format: DrmFourcc) -> Vec<u64> {
+    let queried = render_node_modifiers(format);
+    if let Err(ref e) = queried {
+        log::warn!(
+            "Could not ask the driver of {RENDER_NODE} for the DMA-BUF modifiers of {format:?}, \
+             offering the usual ones of its vendor: {e}"
+        );
+    }
+    offered_modifiers(queried.ok(), render_node_vendor())
+}
+
+/// Modifiers to offer given the `queried` ones of the driver, or the fallback for `vendor`
+/// when it couldn't be asked. Mesa's and Intel's VAAPI drivers import buffers without an
+/// explicit modifier in the layout the kernel keeps for them, so those get the implicit one
+/// next to linear.
+fn offered_modifiers(queried: Option<Vec<u64>>, vendor: GpuVendor) -> Vec<u64> {
+    let (linear, invalid) = (
+        u64::from(DrmModifier::Linear),
+        u64::from(DrmModifier::Invalid),
+    );
+    match (queried, vendor) {
+        (Some(queried), _) => iter::once(linear)
+            .chain(
+                queried
+                    .into_iter()
+                    .filter(|&modifier| modifier != linear && modifier != invalid),
+            )
+            .collect(),
+        (None, GpuVendor::INTEL | GpuVendor::AMD) => vec![linear, invalid],
+        (None, GpuVendor::NVIDIA | GpuVendor::UNKNOWN) => vec![linear],
+    }
+}
+
+/// Modifiers the driver of [`RENDER_NODE`] imports DMA-BUFs of the DRM `format` with
+fn render_node_modifiers(format: DrmFourcc) -> Result<Vec<u64>> {
+    let lib = unsafe { libloading::Library::new("libEGL.so.1") }
+        .map_err(|e| format!("Unable to load libEGL.so.1: {e}"))?;
+    let instance = unsafe { egl::DynamicInstance::<egl::EGL1_5>::load_required_from(lib) }
+        .map_err(|e| format!("Unable to load EGL 1.5: {e}"))?;
+
+    let client_extensions = instance.query_string(None, egl::EXTENSIONS)?;
+    if !client_extensions
+        .to_string_lossy()
+        .contains("EGL_EXT_platform_device")
+    {
+        return Err("EGL_EXT_platform_device not supported".into());
+    }
+    let device = render_node_device(&instance)?;
+    let display =
+        unsafe { instance.get_platform_display(PLATFORM_DEVICE_EXT, device, &[egl::ATTRIB_NONE]) }?;
+    instance.initialize(display)?;
+
+    let modifiers = dmabuf_support(&instance, display).and_then(|(_, modifiers_supported)| {
+        if modifiers_supported {
+            query_modifiers(&instance, display, format as u32)
+        } else {
+            Err("EGL_EXT_image_dma_buf_import_modifiers not supported".into())
+        }
+    });
+    let _ = instance.terminate(display);
+    modifiers
+}
+
+/// The EGL device of the GPU behind [`RENDER_NODE`]
+fn render_node_device(instance: &EglInstance) -> Result<*mut c_void> {
+    let query_devices = unsafe {
+        std::mem::transmute::<extern "system" fn(), PFNEGLQUERYDEVICESEXTPROC>(proc_address(
+            instance,
+            "eglQueryDevicesEXT",
+        )?)
+    };
+    let query_device_string = unsafe {
+        std::mem::transmute::<extern "system" fn(), PFNEGLQUERYDEVICESTRINGEXTPROC>(proc_address(
+            instance,
+            "eglQueryDeviceStringEXT",
+        )?)
+    };
+
+    let mut count = 0;
+    if unsafe { query_devices(0, null_mut(), &mut count) } == egl::FALSE {
+        return Err("Could not count the EGL devices".into());
+    }
+    let mut devices = vec![null_mut(); count.max(0) as usize];
+    if unsafe { query_devices(count, devices.as_mut_ptr(), &mut count) } == egl::FALSE {
+        return Err("Could not list the EGL devices".into());
+    }
+    devices.truncate(count.max(0) as usize);
+
+    // Drivers without EGL_EXT_device_drm_render_node only name the primary node
+    devices
+        .into_iter()
+        .find(|&device| {
+            [DRM_RENDER_NODE_FILE_EXT, DRM_DEVICE_FILE_EXT]
+                .into_iter()
+                .any(|name| {
+                    let node = unsafe { query_device_string(device, name) };
+                    !node.is_null()
+                        && sysfs_device(&unsafe { CStr::from_ptr(node) }.to_string_lossy())
+                            .is_some_and(|gpu| sysfs_device(RENDER_NODE) == Some(gpu))
+                })
+        })
+        .ok_or_else(|| format!("No EGL device drives {RENDER_NODE}").into())
+}
+
+/// Modifiers `display` imports DMA-BUFs of the DRM `format` with as regular images, those only
+/// sampled as external images left out
+fn query_modifiers(instance: &EglInstance, display: egl::Display, format: u32) -> Result<Vec<u64>> {
+    let query = unsafe {
+        std::mem::transmute::<extern "system" fn(), PFNEGLQUERYDMABUFMODIFIERSEXTPROC>(
+            proc_address(instance, "eglQueryDmaBufModifiersEXT")?,
+        )
+    };
+
+    // Asked for the count first, then for the modifiers
+    let display = display.as_ptr();
+    let mut count = 0;
+    let counted = unsafe {
+        query(
+            display,
+            format as egl::Int,
+            0,
+            null_mut(),
+            null_mut(),
+            &mut count,
+        )
+    };
+    if counted == egl::FALSE {
+        return Err(format!("Could not query the DMA-BUF modifiers of {format:#x}").into());
+    }
+    let mut modifiers = vec![0; count.max(0) as usize];
+    let mut external_only = vec![egl::FALSE; modifiers.len()];
+    let queried = unsafe {
+        query(
+            display,
+            format as egl::Int,
+            count,
+            modifiers.as_mut_ptr(),
+            external_only.as_mut_ptr(),
+            &mut count,
+        )
+    };
+    if queried == egl::FALSE {
+        return Err(format!("Could not query the DMA-BUF modifiers of {format:#x}").into());
+    }
+    modifiers.truncate(count.max(0) as usize);
+    Ok(modifiers
+        .into_iter()
+        .zip(external_only)
+        .filter(|&(_, external_only)| external_only == egl::FALSE)
+        .map(|(modifier, _)| modifier)
+        .collect())
+}
+
+fn proc_address(instance: &EglInstance, name: &str) -> Result<extern "system" fn()> {
+    instance
+        .get_proc_address(name)
+        .ok_or_else(|| format!("{name} not available").into())
+}
+
+/// The GPU the DRM `node` belongs to, as its device directory in sysfs
+fn sysfs_device(node: &str) -> Option<PathBuf> {
+    let name = node.rsplit('/').next()?;
+    fs::canonicalize(format!("/sys/class/drm/{name}/device")).ok()
+}
+
+/// Vendor of the GPU behind [`RENDER_NODE`], by its PCI id
+fn render_node_vendor() -> GpuVendor {
+    sysfs_device(RENDER_NODE)
+        .and_then(|device| fs::read_to_string(device.join("vendor")).ok())
+        .map_or(GpuVendor::UNKNOWN, |id| pci_vendor(id.trim()))
+}
+
+fn pci_vendor(id: &str) -> GpuVendor {
+    match id {
+        "0x8086" => GpuVendor::INTEL,
+        "0x1002" => GpuVendor::AMD,
+        "0x10de" => GpuVendor::NVIDIA,
+        _ => GpuVendor::UNKNOWN,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LINEAR: u64 = 0;
+    const INVALID: u64 = 0x00ff_ffff_ffff_ffff;
+    /// I915_FORMAT_MOD_Y_TILED_CCS
+    const Y_TILED_CCS: u64 = 0x0100_0000_0000_0004;
+
+    #[test]
+    fn linear_comes_first() {
+        assert_eq!(
+            offered_modifiers(Some(vec![Y_TILED_CCS, LINEAR]), GpuVendor::INTEL),
+            [LINEAR, Y_TILED_CCS]
+        );
+        // Drivers listing no modifiers for a format still import it linear
+        assert_eq!(
+            offered_modifiers(Some(Vec::new()), GpuVendor::AMD),
+            [LINEAR]
+        );
+    }
+
+    #[test]
+    fn vendor_fallbacks() {
+        assert_eq!(
+            offered_modifiers(None, pci_vendor("0x8086")),
+            [LINEAR, INVALID]
+        );
+        assert_eq!(
+            offered_modifiers(None, pci_vendor("0x1002")),
+            [LINEAR, INVALID]
+        );
+        assert_eq!(offered_modifiers(None, pci_vendor("0x10de")), [LINEAR]);
+        assert_eq!(offered_modifiers(None, pci_vendor("0x1af4")), [LINEAR]);
+    }
+}
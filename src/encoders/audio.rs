@@ -1,18 +1,35 @@
+use std::sync::Arc;
+
 use crossbeam::channel::Receiver;
+use ffmpeg_next::Rational;
 
 use crate::types::{
     audio_frame::{EncodedAudioFrame, RawAudioFrame},
+    config::OverflowPolicy,
     error::Result,
+    event::EventSender,
+    stats::StatsCounters,
+    time::CaptureTime,
 };
 
 const MIN_RMS: f32 = 0.01;
 
+/// Sample rate of the encoded audio
+pub const SAMPLE_RATE: i64 = 48000;
+/// Time base of the encoded audio, one tick per sample
+pub const AUDIO_TIME_BASE: Rational = Rational(1, SAMPLE_RATE as i32);
+
 pub trait AudioEncoder: Send {
     fn new() -> Result<Self>
     where
         Self: Sized;
     fn process(&mut self, raw_frame: RawAudioFrame) -> Result<()>;
-    fn drain(&mut self) -> Result<()>;
+    /// Flush the encoder. The remaining packets are discarded, unless `end_timestamp` is set, then
+    /// they are delivered trimmed to end at that capture time.
+    fn drain(&mut self, end_timestamp: Option<CaptureTime>) -> Result<()>;
+    fn set_stats(&mut self, stats: Arc<StatsCounters>);
+    /// What to do with packets when the receiver is full, drops are reported through `events`
+    fn set_overflow_policy(&mut self, policy: OverflowPolicy, events: EventSender);
     fn reset(&mut self) -> Result<()>;
     fn get_encoder(&self) -> &Option<ffmpeg_next::codec::encoder::Audio>;
     fn get_encoded_recv(&mut self) -> Option<Receiver<EncodedAudioFrame>>;
@@ -0,0 +1,81 @@
+//! Just enough of the AV1 bitstream to tell key frames apart.
+
+const OBU_SEQUENCE_HEADER: u8 = 1;
+const OBU_FRAME_HEADER: u8 = 3;
+const OBU_FRAME: u8 = 6;
+const KEY_FRAME: u8 = 0;
+
+/// Whether the temporal unit in `data`, a sequence of OBUs with size fields, starts a key frame.
+/// Encoders repeat the sequence header in front of every key frame, a frame header of type
+/// `KEY_FRAME` counts too.
+pub(crate) fn is_keyframe(data: &[u8]) -> bool {
+    obus(data).any(|(obu_type, payload)| match obu_type {
+        OBU_SEQUENCE_HEADER => true,
+        // show_existing_frame and frame_type are the first bits of an uncompressed header
+        // without a reduced still picture header, which only still images use
+        OBU_FRAME_HEADER | OBU_FRAME => payload
+            .first()
+            .is_some_and(|&first| first & 0x80 == 0 && (first >> 5) & 0b11 == KEY_FRAME),
+        _ => false,
+    })
+}
+
+/// Type and payload of each OBU, up to the first malformed one
+fn obus(mut data: &[u8]) -> impl Iterator<Item = (u8, &[u8])> {
+    std::iter::from_fn(move || {
+        let (&header, rest) = data.split_first()?;
+        let obu_type = (header >> 3) & 0b1111;
+        let has_extension = header & 0b100 != 0;
+        let has_size = header & 0b10 != 0;
+        let rest = rest.get(usize::from(has_extension)..)?;
+        let (payload, rest) = if has_size {
+            let (size, consumed) = leb128(rest)?;
+            let rest = &rest[consumed..];
+            (rest.get(..size)?, &rest[size..])
+        } else {
+            // The last OBU of the unit may leave out its size
+            (rest, &[][..])
+        };
+        data = rest;
+        Some((obu_type, payload))
+    })
+}
+
+/// Decode a `leb128()` value, returning it with the number of bytes it took
+fn leb128(data: &[u8]) -> Option<(usize, usize)> {
+    let mut value = 0usize;
+    for (index, &byte) in data.iter().take(8).enumerate() {
+        value |= usize::from(byte & 0x7f) << (index * 7);
+        if byte & 0x80 == 0 {
+            return Some((value, index + 1));
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEMPORAL_DELIMITER: [u8; 2] = [0x12, 0x00];
+
+    #[test]
+    fn finds_key_frames() {
+        // Temporal delimiter, then a frame OBU with a KEY_FRAME header
+        let key_frame = [&TEMPORAL_DELIMITER[..], &[0x32, 0x03, 0x10, 0xab, 0xcd]].concat();
+        // Same with an INTER_FRAME header
+        let inter_frame = [&TEMPORAL_DELIMITER[..], &[0x32, 0x03, 0x30, 0xab, 0xcd]].concat();
+        // A sequence header in front, with a 2 byte size of 130
+        let mut with_sequence_header = TEMPORAL_DELIMITER.to_vec();
+        with_sequence_header.extend([0x0a, 0x82, 0x01]);
+        with_sequence_header.extend([0; 130]);
+        with_sequence_header.extend(&inter_frame[2..]);
+
+        assert!(is_keyframe(&key_frame));
+        assert!(!is_keyframe(&inter_frame));
+        assert!(is_keyframe(&with_sequence_header));
+        // Truncated sizes end the walk instead of reading past the packet
+        assert!(!is_keyframe(&[0x32, 0x7f, 0x10]));
+        assert!(!is_keyframe(&[]));
+    }
+}
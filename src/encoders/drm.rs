@@ -0,0 +1,516 @@
+//! Construction of the `AVDRMFrameDescriptor` handed to ffmpeg for DMA-BUF frames.
+//!
+//! All raw writes to the descriptor happen here, after the layout was checked against the array
+//! capacities of the struct and the plane count of the format. Descriptors handed to ffmpeg
+//! also have every fd checked to be open, drivers fault on closed ones, and keep the frame's
+//! duplicates of them open until ffmpeg frees the descriptor.
+
+use std::{
+    ffi::c_void,
+    io,
+    os::fd::{OwnedFd, RawFd},
+    sync::Arc,
+};
+
+use drm_fourcc::DrmFourcc;
+use ffmpeg_next::{
+    self as ffmpeg,
+    ffi::{av_buffer_create, av_free, av_mallocz, AVDRMFrameDescriptor},
+};
+
+use crate::{
+    soak::{self, ObjectKind},
+    types::{
+        error::{Result, WaycapError},
+        video_frame::DmaBufPlane,
+    },
+};
+
+/// Number of planes each supported layer format is made of
+fn plane_count(format: DrmFourcc) -> Option<usize> {
+    match format {
+        DrmFourcc::Argb8888 | DrmFourcc::Xrgb8888 | DrmFourcc::Abgr8888 | DrmFourcc::Xbgr8888 => {
+            Some(1)
+        }
+        DrmFourcc::Xrgb2101010
+        | DrmFourcc::Xbgr2101010
+        | DrmFourcc::Argb2101010
+        | DrmFourcc::Abgr2101010 => Some(1),
+        DrmFourcc::Nv12 => Some(2),
+        DrmFourcc::Yuv420 => Some(3),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct DrmObject {
+    pub fd: RawFd,
+    /// Size of the buffer, 0 if unknown
+    pub size: usize,
+    pub modifier: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct DrmPlane {
+    /// Index into the objects added to the builder
+    pub object_index: usize,
+    pub offset: isize,
+    pub pitch: isize,
+}
+
+/// Objects and layer planes of a DMA-BUF frame made of `planes`, an object per distinct fd.
+/// Compositors put the planes of NV12 and I420 into one buffer at their offsets, or export a
+/// buffer per plane.
+pub(crate) fn dmabuf_layout(
+    planes: &[DmaBufPlane],
+    modifier: u64,
+) -> (Vec<DrmObject>, Vec<DrmPlane>) {
+    let mut objects: Vec<DrmObject> = Vec::new();
+    let layer = planes
+        .iter()
+        .map(|plane| {
+            let object_index = match objects.iter().position(|object| object.fd == plane.fd) {
+                Some(index) => index,
+                None => {
+                    objects.push(DrmObject {
+                        fd: plane.fd,
+                        size: 0,
+                        modifier,
+                    });
+                    objects.len() - 1
+                }
+            };
+            DrmPlane {
+                object_index,
+                offset: plane.offset as isize,
+                pitch: plane.stride as isize,
+            }
+        })
+        .collect();
+    (objects, layer)
+}
+
+/// Fail with [`WaycapError::Validation`] unless each of `fds` is open
+pub(crate) fn check_fds(fds: impl IntoIterator<Item = RawFd>) -> Result<()> {
+    for fd in fds {
+        if unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+            return Err(WaycapError::Validation(format!(
+                "DMA-BUF fd {fd} is not open: {}",
+                io::Error::last_os_error()
+            )));
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct DrmLayer {
+    format: DrmFourcc,
+    planes: Vec<DrmPlane>,
+}
+
+/// Collects the objects and layers of a DMA-BUF frame and validates them before anything is
+/// written to a descriptor
+#[derive(Debug, Default)]
+pub(crate) struct DrmDescriptorBuilder {
+    objects: Vec<DrmObject>,
+    layers: Vec<DrmLayer>,
+    owned_fds: Vec<Arc<OwnedFd>>,
+}
+
+impl DrmDescriptorBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn object(mut self, object: DrmObject) -> Self {
+        self.objects.push(object);
+        self
+    }
+
+    pub fn layer(mut self, format: DrmFourcc, planes: &[DrmPlane]) -> Self {
+        self.layers.push(DrmLayer {
+            format,
+            planes: planes.to_vec(),
+        });
+        self
+    }
+
+    /// Keep `owned_fds`, which the objects refer to, open for as long as the built descriptor
+    pub fn keep_open(mut self, owned_fds: &[Arc<OwnedFd>]) -> Self {
+        self.owned_fds.extend_from_slice(owned_fds);
+        self
+    }
+
+    fn validate(&self, capacity: &AVDRMFrameDescriptor) -> Result<()> {
+        let invalid = |msg: String| -> Result<()> { Err(WaycapError::Validation(msg)) };
+        if self.objects.is_empty() || self.objects.len() > capacity.objects.len() {
+            return invalid(format!(
+                "DRM descriptor needs 1 to {} objects, got {}",
+                capacity.objects.len(),
+                self.objects.len()
+            ));
+        }
+        if let Some(object) = self.objects.iter().find(|object| object.fd < 0) {
+            return invalid(format!("Invalid DMA-BUF fd {}", object.fd));
+        }
+        if self.layers.is_empty() || self.layers.len() > capacity.layers.len() {
+            return invalid(format!(
+                "DRM descriptor needs 1 to {} layers, got {}",
+                capacity.layers.len(),
+                self.layers.len()
+            ));
+        }
+        for layer in &self.layers {
+            let Some(expected) = plane_count(layer.format) else {
+                return invalid(format!("Unsupported DRM format {:?}", layer.format));
+            };
+            if layer.planes.len() != expected {
+                return invalid(format!(
+                    "{:?} has {expected} planes, got {}",
+                    layer.format,
+                    layer.planes.len()
+                ));
+            }
+            if let Some(plane) = layer
+                .planes
+                .iter()
+                .find(|plane| plane.object_index >= self.objects.len())
+            {
+                return invalid(format!(
+                    "Plane refers to object {} of {}",
+                    plane.object_index,
+                    self.objects.len()
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Overwrite `desc` with the validated layout. Entries past the used ones are zeroed, so
+    /// nothing stale survives when a descriptor is reused.
+    pub fn write_into(&self, desc: &mut AVDRMFrameDescriptor) -> Result<()> {
+        self.validate(desc)?;
+        // SAFETY: the descriptor is plain old data for which all zeroes is the empty state
+        *desc = unsafe { std::mem::zeroed() };
+
+        desc.nb_objects = self.objects.len() as i32;
+        for (target, object) in desc.objects.iter_mut().zip(&self.objects) {
+            target.fd = object.fd;
+            target.size = object.size;
+            target.format_modifier = object.modifier;
+        }
+
+        desc.nb_layers = self.layers.len() as i32;
+        for (target, layer) in desc.layers.iter_mut().zip(&self.layers) {
+            target.format = layer.format as u32;
+            target.nb_planes = layer.planes.len() as i32;
+            for (target, plane) in target.planes.iter_mut().zip(&layer.planes) {
+                target.object_index = plane.object_index as i32;
+                target.offset = plane.offset;
+                target.pitch = plane.pitch;
+            }
+        }
+        Ok(())
+    }
+
+    /// Allocate a descriptor with ffmpeg's allocator, so it can be freed by the frame's buffer
+    pub fn build(&self) -> Result<DrmDescriptor> {
+        check_fds(self.objects.iter().map(|object| object.fd))?;
+        let size = std::mem::size_of::<AVDRMFrameDescriptor>();
+        let desc = unsafe { av_mallocz(size) } as *mut AVDRMFrameDescriptor;
+        if desc.is_null() {
+            return Err(WaycapError::Encoding(
+                "Could not allocate a DRM frame descriptor".to_string(),
+            ));
+        }
+        soak::object_created(ObjectKind::DrmDescriptor);
+        let desc = DrmDescriptor {
+            desc,
+            owned_fds: self.owned_fds.clone(),
+        };
+        // SAFETY: freshly allocated, zeroed and exclusively owned
+        self.write_into(unsafe { &mut *desc.desc })?;
+        Ok(desc)
+    }
+}
+
+/// A descriptor allocated by [`DrmDescriptorBuilder::build`], freed unless attached to a frame
+pub(crate) struct DrmDescriptor {
+    desc: *mut AVDRMFrameDescriptor,
+    owned_fds: Vec<Arc<OwnedFd>>,
+}
+
+impl DrmDescriptor {
+    /// Make the descriptor the data of a `DRM_PRIME` frame, which takes ownership of it and of
+    /// the fds it keeps open
+    pub fn attach(mut self, frame: &mut ffmpeg::util::frame::Video) -> Result<()> {
+        let size = std::mem::size_of::<AVDRMFrameDescriptor>();
+        // Handed back to `free_attached` along with the descriptor
+        let owned_fds = Box::into_raw(Box::new(std::mem::take(&mut self.owned_fds)));
+        unsafe {
+            let buf = av_buffer_create(
+                self.desc as *mut u8,
+                size,
+                Some(free_attached),
+                owned_fds as *mut c_void,
+                0,
+            );
+            if buf.is_null() {
+                drop(Box::from_raw(owned_fds));
+                return Err(WaycapError::Encoding(
+                    "Could not wrap the DRM frame descriptor in a buffer".to_string(),
+                ));
+            }
+            (*frame.as_mut_ptr()).data[0] = self.desc as *mut u8;
+            (*frame.as_mut_ptr()).buf[0] = buf;
+        }
+        std::mem::forget(self);
+        Ok(())
+    }
+}
+
+impl Drop for DrmDescriptor {
+    fn drop(&mut self) {
+        unsafe { av_free(self.desc as *mut c_void) };
+        soak::object_destroyed(ObjectKind::DrmDescriptor);
+    }
+}
+
+/// Free callback of the buffer an attached descriptor lives in, `opaque` holds the fds it kept
+/// open
+unsafe extern "C" fn free_attached(opaque: *mut c_void, data: *mut u8) {
+    av_free(data as *mut c_void);
+    drop(Box::from_raw(opaque as *mut Vec<Arc<OwnedFd>>));
+    soak::object_destroyed(ObjectKind::DrmDescriptor);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        os::fd::{AsRawFd, FromRawFd},
+    };
+
+    use super::*;
+    use crate::utils::own_dmabuf_fds;
+
+    /// Name of the memfd standing in for a DMA-BUF in the stress test
+    const STRESS_BUFFER: &str = "waycap-fd-stress";
+
+    fn object(fd: RawFd) -> DrmObject {
+        DrmObject {
+            fd,
+            size: 0,
+            modifier: 0,
+        }
+    }
+
+    fn plane(object_index: usize, offset: isize) -> DrmPlane {
+        DrmPlane {
+            object_index,
+            offset,
+            pitch: 256,
+        }
+    }
+
+    /// Descriptor full of garbage, like one coming back from a pool
+    fn dirty() -> AVDRMFrameDescriptor {
+        let mut desc: AVDRMFrameDescriptor = unsafe { std::mem::zeroed() };
+        desc.nb_objects = 4;
+        desc.nb_layers = 4;
+        for object in desc.objects.iter_mut() {
+            object.fd = 99;
+            object.size = 1234;
+        }
+        for layer in desc.layers.iter_mut() {
+            layer.nb_planes = 4;
+            for plane in layer.planes.iter_mut() {
+                plane.object_index = 3;
+                plane.offset = 77;
+            }
+        }
+        desc
+    }
+
+    #[test]
+    fn every_supported_layout() {
+        let layouts = [
+            (DrmFourcc::Argb8888, 1),
+            (DrmFourcc::Xrgb8888, 1),
+            (DrmFourcc::Abgr8888, 1),
+            (DrmFourcc::Xbgr8888, 1),
+            (DrmFourcc::Xrgb2101010, 1),
+            (DrmFourcc::Nv12, 2),
+            (DrmFourcc::Yuv420, 3),
+        ];
+        for (format, planes) in layouts {
+            let planes: Vec<_> = (0..planes).map(|i| plane(0, i * 4096)).collect();
+            let mut desc = dirty();
+            DrmDescriptorBuilder::new()
+                .object(object(5))
+                .layer(format, &planes)
+                .write_into(&mut desc)
+                .unwrap();
+
+            assert_eq!(desc.nb_objects, 1);
+            assert_eq!(desc.objects[0].fd, 5);
+            assert_eq!(desc.objects[1].fd, 0);
+            assert_eq!(desc.nb_layers, 1);
+            assert_eq!(desc.layers[0].format, format as u32);
+            assert_eq!(desc.layers[0].nb_planes, planes.len() as i32);
+            for (i, written) in desc.layers[0].planes.iter().enumerate() {
+                let expected = planes.get(i).map_or(0, |plane| plane.offset);
+                assert_eq!(written.offset, expected);
+            }
+            assert_eq!(desc.layers[1].nb_planes, 0);
+        }
+    }
+
+    #[test]
+    fn a_buffer_per_plane() {
+        let planes = [(7, 0), (9, 0), (7, 4096)].map(|(fd, offset)| DmaBufPlane {
+            fd,
+            offset,
+            stride: 256,
+        });
+        let (objects, layer) = dmabuf_layout(&planes, 0x0100_0000_0000_0004);
+        assert_eq!(objects.len(), 2);
+        assert_eq!((objects[0].fd, objects[1].fd), (7, 9));
+        assert!(objects
+            .iter()
+            .all(|object| object.modifier == 0x0100_0000_0000_0004));
+        assert_eq!(
+            layer
+                .iter()
+                .map(|plane| plane.object_index)
+                .collect::<Vec<_>>(),
+            [0, 1, 0]
+        );
+
+        let mut builder = DrmDescriptorBuilder::new();
+        for object in objects {
+            builder = builder.object(object);
+        }
+        let mut desc = dirty();
+        builder
+            .layer(DrmFourcc::Yuv420, &layer)
+            .write_into(&mut desc)
+            .unwrap();
+        assert_eq!(desc.nb_objects, 2);
+        assert_eq!(desc.objects[1].fd, 9);
+        assert_eq!(desc.objects[2].fd, 0);
+        let written: Vec<_> = desc.layers[0]
+            .planes
+            .iter()
+            .map(|plane| (plane.object_index, plane.offset))
+            .collect();
+        assert_eq!(written, [(0, 0), (1, 0), (0, 4096), (0, 0)]);
+    }
+
+    #[test]
+    fn rejects_closed_fds() {
+        let file = std::fs::File::open("/dev/null").unwrap();
+        check_fds([std::os::fd::AsRawFd::as_raw_fd(&file)]).unwrap();
+        // Past any fd limit, so never open. A closed fd could be reused by other tests.
+        let result = DrmDescriptorBuilder::new()
+            .object(object(RawFd::MAX))
+            .layer(DrmFourcc::Argb8888, &[plane(0, 0)])
+            .build();
+        assert!(matches!(result, Err(WaycapError::Validation(_))));
+    }
+
+    #[test]
+    fn rejects_wrong_plane_count() {
+        let result = DrmDescriptorBuilder::new()
+            .object(object(5))
+            .layer(DrmFourcc::Nv12, &[plane(0, 0)])
+            .write_into(&mut dirty());
+        assert!(matches!(result, Err(WaycapError::Validation(_))));
+    }
+
+    #[test]
+    fn rejects_planes_without_object() {
+        let result = DrmDescriptorBuilder::new()
+            .object(object(5))
+            .layer(DrmFourcc::Nv12, &[plane(0, 0), plane(1, 0)])
+            .write_into(&mut dirty());
+        assert!(matches!(result, Err(WaycapError::Validation(_))));
+    }
+
+    #[test]
+    fn rejects_more_objects_than_fit() {
+        let mut builder = DrmDescriptorBuilder::new();
+        for fd in 0..5 {
+            builder = builder.object(object(fd));
+        }
+        let result = builder
+            .layer(DrmFourcc::Argb8888, &[plane(0, 0)])
+            .write_into(&mut dirty());
+        assert!(matches!(result, Err(WaycapError::Validation(_))));
+    }
+
+    #[test]
+    fn rejects_missing_fd() {
+        let result = DrmDescriptorBuilder::new()
+            .object(object(-1))
+            .layer(DrmFourcc::Argb8888, &[plane(0, 0)])
+            .write_into(&mut dirty());
+        assert!(matches!(result, Err(WaycapError::Validation(_))));
+    }
+
+    /// Fds of the process open on the memfd `name`, other tests open and close fds concurrently
+    fn open_fds_of(name: &str) -> usize {
+        let target = format!("/memfd:{name} ");
+        fs::read_dir("/proc/self/fd")
+            .unwrap()
+            .filter_map(|entry| fs::read_link(entry.ok()?.path()).ok())
+            .filter(|link| link.to_string_lossy().starts_with(&target))
+            .count()
+    }
+
+    #[test]
+    fn imported_frames_close_their_fds_once() {
+        let name = std::ffi::CString::new(STRESS_BUFFER).unwrap();
+        let buffer = unsafe { libc::memfd_create(name.as_ptr(), libc::MFD_CLOEXEC) };
+        assert!(buffer >= 0);
+        let buffer = unsafe { OwnedFd::from_raw_fd(buffer) };
+        let before = open_fds_of(STRESS_BUFFER);
+        assert_eq!(before, 1);
+
+        for i in 0..10_000 {
+            // NV12 in one buffer, like PipeWire hands it over
+            let mut fd = Some(buffer.as_raw_fd());
+            let mut planes = [0, 4096].map(|offset| DmaBufPlane {
+                fd: buffer.as_raw_fd(),
+                offset,
+                stride: 64,
+            });
+            let owned_fds = own_dmabuf_fds(&mut fd, &mut planes).unwrap();
+            assert_eq!(owned_fds.len(), 1);
+            assert_ne!(fd, Some(buffer.as_raw_fd()));
+            assert!(planes.iter().all(|plane| Some(plane.fd) == fd));
+
+            let (objects, layer) = dmabuf_layout(&planes, 0);
+            let mut frame =
+                ffmpeg::util::frame::Video::new(ffmpeg::format::Pixel::DRM_PRIME, 64, 64);
+            objects
+                .into_iter()
+                .fold(DrmDescriptorBuilder::new(), DrmDescriptorBuilder::object)
+                .layer(DrmFourcc::Nv12, &layer)
+                .keep_open(&owned_fds)
+                .build()
+                .unwrap()
+                .attach(&mut frame)
+                .unwrap();
+            // The raw frame goes first, the driver may still read the buffer
+            drop(owned_fds);
+            if i % 1000 == 0 {
+                assert_eq!(open_fds_of(STRESS_BUFFER), before + 1);
+                assert!(check_fds(fd).is_ok());
+            }
+            drop(frame);
+        }
+        assert_eq!(open_fds_of(STRESS_BUFFER), before);
+    }
+}
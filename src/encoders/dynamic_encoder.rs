@@ -1,14 +1,29 @@
+use std::sync::{Arc, OnceLock};
+
 use crossbeam::channel::Receiver;
-use ffmpeg_next::codec::encoder;
+use ffmpeg_next::{codec::encoder, ffi::AVHWDeviceType};
+use pipewire::spa::param::video::VideoFormat;
 
 use crate::{
+    capture::pip::PipFrames,
     encoders::{
-        vaapi_encoder::VaapiEncoder,
-        video::{PipewireSPA, ProcessingThread},
+        passthrough_encoder::PassthroughEncoder,
+        qsv_encoder::QsvEncoder,
+        software_encoder::{SoftwareCodec, SoftwareEncoder},
+        vaapi_encoder::{VaapiCodec, VaapiEncoder},
+        video::{create_hw_device, PipewireSPA, ProcessingThread},
     },
+    ffmpeg_compat,
+    pipeline::fanout::FanOut,
     types::{
-        config::VideoEncoder as VideoEncoderType,
+        config::{
+            OverlayConfig, QualityPreset, Rect, VideoConfig, VideoEncoder as VideoEncoderType,
+        },
+        encoder_info::EncoderInfo,
         error::{Result, WaycapError},
+        event::EventSender,
+        stats::StatsCounters,
+        time::CaptureTime,
         video_frame::{EncodedVideoFrame, RawVideoFrame},
     },
     waycap_egl::{EglContext, GpuVendor},
@@ -16,21 +31,38 @@ use crate::{
 };
 
 #[cfg(feature = "nvenc")]
-use crate::encoders::nvenc_encoder::NvencEncoder;
+use crate::{
+    encoders::nvenc_encoder::{self, NvencCodec, NvencEncoder},
+    types::event::CaptureEvent,
+};
 
 pub enum DynamicEncoder {
     Vaapi(VaapiEncoder),
     #[cfg(feature = "nvenc")]
     Nvenc(NvencEncoder),
+    Qsv(QsvEncoder),
+    Software(SoftwareEncoder),
+    Passthrough(PassthroughEncoder),
 }
 
 impl DynamicEncoder {
+    #[cfg_attr(not(feature = "nvenc"), allow(unused_variables))]
     pub(crate) fn new(
         encoder_type: Option<VideoEncoderType>,
         width: u32,
         height: u32,
-        quality_preset: crate::types::config::QualityPreset,
+        config: VideoConfig,
+        events: &EventSender,
     ) -> crate::types::error::Result<DynamicEncoder> {
+        if config.quality == QualityPreset::Lossless && lossless_on_cpu(encoder_type) {
+            log::info!("VAAPI can't encode H.264 losslessly here, encoding with FFV1 on the CPU");
+            return Ok(DynamicEncoder::Software(SoftwareEncoder::new(
+                width,
+                height,
+                SoftwareCodec::Ffv1,
+                config,
+            )?));
+        }
         let encoder_type = match encoder_type {
             Some(typ) => typ,
             None => {
@@ -55,16 +87,177 @@ impl DynamicEncoder {
                 }
             }
         };
+        if config.hdr_metadata.is_some() && !encoder_type.writes_hdr_metadata() {
+            log::warn!(
+                "{} does not write HDR metadata, leaving it out",
+                encoder_type.display_name()
+            );
+        }
         Ok(match encoder_type {
             #[cfg(feature = "nvenc")]
             VideoEncoderType::H264Nvenc => {
-                DynamicEncoder::Nvenc(NvencEncoder::new(width, height, quality_preset)?)
+                Self::new_nvenc(width, height, NvencCodec::H264, config, events)?
+            }
+            #[cfg(feature = "nvenc")]
+            VideoEncoderType::H265Nvenc => {
+                Self::new_nvenc(width, height, NvencCodec::Hevc, config, events)?
+            }
+            #[cfg(feature = "nvenc")]
+            VideoEncoderType::Av1Nvenc => {
+                Self::new_nvenc(width, height, NvencCodec::Av1, config, events)?
             }
             VideoEncoderType::H264Vaapi => {
-                DynamicEncoder::Vaapi(VaapiEncoder::new(width, height, quality_preset)?)
+                DynamicEncoder::Vaapi(VaapiEncoder::new(width, height, VaapiCodec::H264, config)?)
+            }
+            VideoEncoderType::H265Vaapi => {
+                DynamicEncoder::Vaapi(VaapiEncoder::new(width, height, VaapiCodec::Hevc, config)?)
+            }
+            VideoEncoderType::Av1Vaapi => {
+                DynamicEncoder::Vaapi(VaapiEncoder::new(width, height, VaapiCodec::Av1, config)?)
+            }
+            VideoEncoderType::Vp9Vaapi => {
+                DynamicEncoder::Vaapi(VaapiEncoder::new(width, height, VaapiCodec::Vp9, config)?)
+            }
+            VideoEncoderType::H264Qsv => {
+                // QSV is Intel's, elsewhere its runtime fails in ways that are hard to tell apart
+                let dummy_context = EglContext::new(100, 100)?;
+                if !matches!(dummy_context.get_gpu_vendor(), GpuVendor::INTEL) {
+                    return Err(WaycapError::Unsupported(
+                        "QSV only runs on Intel GPUs, use VAAPI instead".to_string(),
+                    ));
+                }
+                DynamicEncoder::Qsv(QsvEncoder::new(width, height, config)?)
+            }
+            VideoEncoderType::H264Software => DynamicEncoder::Software(SoftwareEncoder::new(
+                width,
+                height,
+                SoftwareCodec::H264,
+                config,
+            )?),
+            VideoEncoderType::Av1Svt => DynamicEncoder::Software(SoftwareEncoder::new(
+                width,
+                height,
+                SoftwareCodec::Av1,
+                config,
+            )?),
+            VideoEncoderType::Mjpeg if mjpeg_on_vaapi() => {
+                DynamicEncoder::Vaapi(VaapiEncoder::new(width, height, VaapiCodec::Mjpeg, config)?)
+            }
+            VideoEncoderType::Mjpeg => DynamicEncoder::Software(SoftwareEncoder::new(
+                width,
+                height,
+                SoftwareCodec::Mjpeg,
+                config,
+            )?),
+            VideoEncoderType::Vp8 => DynamicEncoder::Software(SoftwareEncoder::new(
+                width,
+                height,
+                SoftwareCodec::Vp8,
+                config,
+            )?),
+            VideoEncoderType::H264Passthrough => {
+                DynamicEncoder::Passthrough(PassthroughEncoder::new(width, height)?)
             }
         })
     }
+
+    /// Open NVENC, waiting for a session to free up when the driver's session limit is reached
+    #[cfg(feature = "nvenc")]
+    fn new_nvenc(
+        width: u32,
+        height: u32,
+        codec: NvencCodec,
+        config: VideoConfig,
+        events: &EventSender,
+    ) -> Result<DynamicEncoder> {
+        let retry = config.nvenc_retry;
+        let mut delay = retry.initial_delay;
+        let mut attempt = 0;
+        loop {
+            match NvencEncoder::new(width, height, codec, config.clone()) {
+                Err(WaycapError::NvencSessionLimit) if attempt < retry.attempts => {
+                    attempt += 1;
+                    log::warn!(
+                        "NVENC session limit reached, retrying in {delay:?} ({attempt}/{})",
+                        retry.attempts
+                    );
+                    events.send(CaptureEvent::WaitingForEncoder {
+                        attempt,
+                        max_attempts: retry.attempts,
+                        retry_in: delay,
+                    });
+                    std::thread::sleep(delay);
+                    delay *= 2;
+                }
+                Err(WaycapError::NvencSessionLimit)
+                    if retry.fallback_to_vaapi && !config.reserve_nvenc_session =>
+                {
+                    // The same codec, a fallback must not change what the output is
+                    let (encoder, vaapi_codec) = vaapi_fallback(codec);
+                    if !probe(encoder) {
+                        log::warn!(
+                            "NVENC session limit reached and {} is unavailable",
+                            encoder.display_name()
+                        );
+                        return Err(WaycapError::NvencSessionLimit);
+                    }
+                    log::warn!(
+                        "NVENC session limit reached, falling back to {}",
+                        encoder.display_name()
+                    );
+                    return Ok(DynamicEncoder::Vaapi(VaapiEncoder::new(
+                        width,
+                        height,
+                        vaapi_codec,
+                        config,
+                    )?));
+                }
+                result => return result.map(DynamicEncoder::Nvenc),
+            }
+        }
+    }
+
+    pub(crate) fn set_stats(&mut self, stats: Arc<StatsCounters>) {
+        match self {
+            DynamicEncoder::Vaapi(enc) => enc.set_stats(stats),
+            #[cfg(feature = "nvenc")]
+            DynamicEncoder::Nvenc(enc) => enc.set_stats(stats),
+            DynamicEncoder::Qsv(enc) => enc.set_stats(stats),
+            DynamicEncoder::Software(enc) => enc.set_stats(stats),
+            DynamicEncoder::Passthrough(enc) => enc.set_stats(stats),
+        }
+    }
+
+    /// Hand the frames of the [`crate::types::config::VideoConfig::pip`] source to the
+    /// encoders which blend them in
+    pub(crate) fn set_pip_frames(&mut self, pip_frames: Arc<PipFrames>) {
+        match self {
+            DynamicEncoder::Vaapi(enc) => enc.set_pip_frames(pip_frames),
+            DynamicEncoder::Qsv(enc) => enc.set_pip_frames(pip_frames),
+            _ => {}
+        }
+    }
+
+    /// Build the filter graphs of the encoders which have one for frames in the negotiated
+    /// `format`
+    pub(crate) fn set_input_format(&mut self, format: VideoFormat) -> Result<()> {
+        match self {
+            DynamicEncoder::Vaapi(enc) => enc.set_input_format(format),
+            DynamicEncoder::Qsv(enc) => enc.set_input_format(format),
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn output_queues(&self) -> FanOut<EncodedVideoFrame> {
+        match self {
+            DynamicEncoder::Vaapi(enc) => enc.output_queues(),
+            #[cfg(feature = "nvenc")]
+            DynamicEncoder::Nvenc(enc) => enc.output_queues(),
+            DynamicEncoder::Qsv(enc) => enc.output_queues(),
+            DynamicEncoder::Software(enc) => enc.output_queues(),
+            DynamicEncoder::Passthrough(enc) => enc.output_queues(),
+        }
+    }
 }
 
 impl VideoEncoder for DynamicEncoder {
@@ -75,6 +268,9 @@ impl VideoEncoder for DynamicEncoder {
             DynamicEncoder::Vaapi(enc) => enc.reset(),
             #[cfg(feature = "nvenc")]
             DynamicEncoder::Nvenc(enc) => enc.reset(),
+            DynamicEncoder::Qsv(enc) => enc.reset(),
+            DynamicEncoder::Software(enc) => enc.reset(),
+            DynamicEncoder::Passthrough(enc) => enc.reset(),
         }
     }
 
@@ -83,6 +279,64 @@ impl VideoEncoder for DynamicEncoder {
             DynamicEncoder::Vaapi(enc) => enc.output(),
             #[cfg(feature = "nvenc")]
             DynamicEncoder::Nvenc(enc) => enc.output(),
+            DynamicEncoder::Qsv(enc) => enc.output(),
+            DynamicEncoder::Software(enc) => enc.output(),
+            DynamicEncoder::Passthrough(enc) => enc.output(),
+        }
+    }
+
+    fn source_changed(&mut self, width: u32, height: u32) -> Result<()> {
+        match self {
+            DynamicEncoder::Vaapi(enc) => enc.source_changed(width, height),
+            #[cfg(feature = "nvenc")]
+            DynamicEncoder::Nvenc(enc) => enc.source_changed(width, height),
+            DynamicEncoder::Qsv(enc) => enc.source_changed(width, height),
+            DynamicEncoder::Software(enc) => enc.source_changed(width, height),
+            DynamicEncoder::Passthrough(enc) => enc.source_changed(width, height),
+        }
+    }
+
+    fn set_bitrate(&mut self, bits_per_sec: u64) -> Result<()> {
+        match self {
+            DynamicEncoder::Vaapi(enc) => enc.set_bitrate(bits_per_sec),
+            #[cfg(feature = "nvenc")]
+            DynamicEncoder::Nvenc(enc) => enc.set_bitrate(bits_per_sec),
+            DynamicEncoder::Qsv(enc) => enc.set_bitrate(bits_per_sec),
+            DynamicEncoder::Software(enc) => enc.set_bitrate(bits_per_sec),
+            DynamicEncoder::Passthrough(enc) => enc.set_bitrate(bits_per_sec),
+        }
+    }
+
+    fn change_quality(&mut self, quality: QualityPreset) -> Result<()> {
+        match self {
+            DynamicEncoder::Vaapi(enc) => enc.change_quality(quality),
+            #[cfg(feature = "nvenc")]
+            DynamicEncoder::Nvenc(enc) => enc.change_quality(quality),
+            DynamicEncoder::Qsv(enc) => enc.change_quality(quality),
+            DynamicEncoder::Software(enc) => enc.change_quality(quality),
+            DynamicEncoder::Passthrough(enc) => enc.change_quality(quality),
+        }
+    }
+
+    fn set_crop(&mut self, crop: Option<Rect>) -> Result<()> {
+        match self {
+            DynamicEncoder::Vaapi(enc) => enc.set_crop(crop),
+            #[cfg(feature = "nvenc")]
+            DynamicEncoder::Nvenc(enc) => enc.set_crop(crop),
+            DynamicEncoder::Qsv(enc) => enc.set_crop(crop),
+            DynamicEncoder::Software(enc) => enc.set_crop(crop),
+            DynamicEncoder::Passthrough(enc) => enc.set_crop(crop),
+        }
+    }
+
+    fn set_overlay(&mut self, overlay: Option<OverlayConfig>) -> Result<()> {
+        match self {
+            DynamicEncoder::Vaapi(enc) => enc.set_overlay(overlay),
+            #[cfg(feature = "nvenc")]
+            DynamicEncoder::Nvenc(enc) => enc.set_overlay(overlay),
+            DynamicEncoder::Qsv(enc) => enc.set_overlay(overlay),
+            DynamicEncoder::Software(enc) => enc.set_overlay(overlay),
+            DynamicEncoder::Passthrough(enc) => enc.set_overlay(overlay),
         }
     }
 
@@ -91,6 +345,9 @@ impl VideoEncoder for DynamicEncoder {
             DynamicEncoder::Vaapi(enc) => enc.drop_processor(),
             #[cfg(feature = "nvenc")]
             DynamicEncoder::Nvenc(enc) => enc.drop_processor(),
+            DynamicEncoder::Qsv(enc) => enc.drop_processor(),
+            DynamicEncoder::Software(enc) => enc.drop_processor(),
+            DynamicEncoder::Passthrough(enc) => enc.drop_processor(),
         }
     }
 
@@ -99,6 +356,9 @@ impl VideoEncoder for DynamicEncoder {
             DynamicEncoder::Vaapi(enc) => enc.drain(),
             #[cfg(feature = "nvenc")]
             DynamicEncoder::Nvenc(enc) => enc.drain(),
+            DynamicEncoder::Qsv(enc) => enc.drain(),
+            DynamicEncoder::Software(enc) => enc.drain(),
+            DynamicEncoder::Passthrough(enc) => enc.drain(),
         }
     }
 
@@ -107,6 +367,31 @@ impl VideoEncoder for DynamicEncoder {
             DynamicEncoder::Vaapi(enc) => enc.get_encoder(),
             #[cfg(feature = "nvenc")]
             DynamicEncoder::Nvenc(enc) => enc.get_encoder(),
+            DynamicEncoder::Qsv(enc) => enc.get_encoder(),
+            DynamicEncoder::Software(enc) => enc.get_encoder(),
+            DynamicEncoder::Passthrough(enc) => enc.get_encoder(),
+        }
+    }
+
+    fn info(&self) -> Option<EncoderInfo> {
+        match self {
+            DynamicEncoder::Vaapi(enc) => enc.info(),
+            #[cfg(feature = "nvenc")]
+            DynamicEncoder::Nvenc(enc) => enc.info(),
+            DynamicEncoder::Qsv(enc) => enc.info(),
+            DynamicEncoder::Software(enc) => enc.info(),
+            DynamicEncoder::Passthrough(enc) => enc.info(),
+        }
+    }
+
+    fn has_consumers(&self) -> bool {
+        match self {
+            DynamicEncoder::Vaapi(enc) => enc.has_consumers(),
+            #[cfg(feature = "nvenc")]
+            DynamicEncoder::Nvenc(enc) => enc.has_consumers(),
+            DynamicEncoder::Qsv(enc) => enc.has_consumers(),
+            DynamicEncoder::Software(enc) => enc.has_consumers(),
+            DynamicEncoder::Passthrough(enc) => enc.has_consumers(),
         }
     }
 }
@@ -117,13 +402,31 @@ impl ProcessingThread for DynamicEncoder {
             DynamicEncoder::Vaapi(enc) => enc.process(frame),
             #[cfg(feature = "nvenc")]
             DynamicEncoder::Nvenc(enc) => enc.process(frame),
+            DynamicEncoder::Qsv(enc) => enc.process(frame),
+            DynamicEncoder::Software(enc) => enc.process(frame),
+            DynamicEncoder::Passthrough(enc) => enc.process(frame),
+        }
+    }
+
+    fn repeat_frame(&mut self, timestamp: CaptureTime, force_keyframe: bool) -> Result<bool> {
+        match self {
+            DynamicEncoder::Vaapi(enc) => enc.repeat_frame(timestamp, force_keyframe),
+            #[cfg(feature = "nvenc")]
+            DynamicEncoder::Nvenc(enc) => enc.repeat_frame(timestamp, force_keyframe),
+            DynamicEncoder::Qsv(enc) => enc.repeat_frame(timestamp, force_keyframe),
+            DynamicEncoder::Software(enc) => enc.repeat_frame(timestamp, force_keyframe),
+            DynamicEncoder::Passthrough(enc) => enc.repeat_frame(timestamp, force_keyframe),
         }
     }
+
     fn thread_setup(&mut self) -> Result<()> {
         match self {
             DynamicEncoder::Vaapi(enc) => enc.thread_setup(),
             #[cfg(feature = "nvenc")]
             DynamicEncoder::Nvenc(enc) => enc.thread_setup(),
+            DynamicEncoder::Qsv(enc) => enc.thread_setup(),
+            DynamicEncoder::Software(enc) => enc.thread_setup(),
+            DynamicEncoder::Passthrough(enc) => enc.thread_setup(),
         }
     }
 
@@ -132,6 +435,9 @@ impl ProcessingThread for DynamicEncoder {
             DynamicEncoder::Vaapi(enc) => enc.thread_teardown(),
             #[cfg(feature = "nvenc")]
             DynamicEncoder::Nvenc(enc) => enc.thread_teardown(),
+            DynamicEncoder::Qsv(enc) => enc.thread_teardown(),
+            DynamicEncoder::Software(enc) => enc.thread_teardown(),
+            DynamicEncoder::Passthrough(enc) => enc.thread_teardown(),
         }
     }
 }
@@ -156,3 +462,91 @@ impl PipewireSPA for DynamicEncoder {
         }
     }
 }
+
+/// Whether [`VideoEncoderType::Mjpeg`] is encoded through VAAPI, which decides the buffers
+/// asked for as well. Found out once, so the capture and the encoder agree.
+pub(crate) fn mjpeg_on_vaapi() -> bool {
+    static ON_VAAPI: OnceLock<bool> = OnceLock::new();
+    *ON_VAAPI.get_or_init(|| {
+        VaapiEncoder::create_encoder(256, 256, VaapiCodec::Mjpeg, &VideoConfig::default()).is_ok()
+    })
+}
+
+/// Whether [`QualityPreset::Lossless`] is encoded with FFV1 on the CPU, which it is for H.264
+/// (VAAPI) and without an encoder asked for when the driver can't encode it bit-exact. Decides
+/// the buffers asked for as well.
+pub(crate) fn lossless_on_cpu(encoder: Option<VideoEncoderType>) -> bool {
+    matches!(
+        encoder,
+        None | Some(VideoEncoderType::H264Vaapi | VideoEncoderType::H264Passthrough)
+    ) && !vaapi_lossless()
+}
+
+/// Whether the VAAPI driver encodes H.264 losslessly, see [`VaapiEncoder::probe_lossless`].
+/// Found out once, so the capture and the encoder agree.
+fn vaapi_lossless() -> bool {
+    static LOSSLESS: OnceLock<bool> = OnceLock::new();
+    *LOSSLESS.get_or_init(|| {
+        VaapiEncoder::probe_lossless().unwrap_or_else(|e| {
+            log::debug!("Could not probe lossless VAAPI encoding: {e}");
+            false
+        })
+    })
+}
+
+/// Whether `encoder` is built into the linked ffmpeg and its hardware device can be opened
+pub(crate) fn probe(encoder: VideoEncoderType) -> bool {
+    match encoder {
+        #[cfg(feature = "nvenc")]
+        VideoEncoderType::H264Nvenc => probe_nvenc(NvencCodec::H264),
+        #[cfg(feature = "nvenc")]
+        VideoEncoderType::H265Nvenc => probe_nvenc(NvencCodec::Hevc),
+        #[cfg(feature = "nvenc")]
+        VideoEncoderType::Av1Nvenc => probe_nvenc(NvencCodec::Av1),
+        VideoEncoderType::H264Vaapi => {
+            ffmpeg_compat::find_encoder("h264_vaapi").is_ok()
+                && create_hw_device(AVHWDeviceType::AV_HWDEVICE_TYPE_VAAPI).is_ok()
+        }
+        // Only opening an encoder shows whether the driver has an HEVC, AV1 or VP9 entrypoint
+        VideoEncoderType::H265Vaapi => {
+            VaapiEncoder::create_encoder(256, 256, VaapiCodec::Hevc, &VideoConfig::default())
+                .is_ok()
+        }
+        VideoEncoderType::Av1Vaapi => {
+            VaapiEncoder::create_encoder(256, 256, VaapiCodec::Av1, &VideoConfig::default()).is_ok()
+        }
+        VideoEncoderType::Vp9Vaapi => {
+            VaapiEncoder::create_encoder(256, 256, VaapiCodec::Vp9, &VideoConfig::default()).is_ok()
+        }
+        // Deriving the QSV device shows whether the oneVPL runtime can drive the GPU
+        VideoEncoderType::H264Qsv => {
+            QsvEncoder::create_encoder(256, 256, &VideoConfig::default()).is_ok()
+        }
+        VideoEncoderType::H264Software => ffmpeg_compat::find_encoder("libx264").is_ok(),
+        VideoEncoderType::Av1Svt => ffmpeg_compat::find_encoder("libsvtav1").is_ok(),
+        VideoEncoderType::Mjpeg => mjpeg_on_vaapi() || ffmpeg_compat::find_encoder("mjpeg").is_ok(),
+        VideoEncoderType::Vp8 => ffmpeg_compat::find_encoder("libvpx").is_ok(),
+        // Whether the compositor offers encoded streams is only known once one is negotiated
+        VideoEncoderType::H264Passthrough => {
+            ffmpeg_next::decoder::find(ffmpeg_next::codec::Id::H264).is_some()
+        }
+    }
+}
+
+/// The VAAPI encoder for the codec of `codec`, which NVENC falls back to
+#[cfg(feature = "nvenc")]
+fn vaapi_fallback(codec: NvencCodec) -> (VideoEncoderType, VaapiCodec) {
+    match codec {
+        NvencCodec::H264 => (VideoEncoderType::H264Vaapi, VaapiCodec::H264),
+        NvencCodec::Hevc => (VideoEncoderType::H265Vaapi, VaapiCodec::Hevc),
+        NvencCodec::Av1 => (VideoEncoderType::Av1Vaapi, VaapiCodec::Av1),
+    }
+}
+
+#[cfg(feature = "nvenc")]
+fn probe_nvenc(codec: NvencCodec) -> bool {
+    ffmpeg_compat::find_encoder(codec.encoder_name()).is_ok()
+        && cust::init(cust::CudaFlags::empty()).is_ok()
+        && cust::device::Device::num_devices().is_ok_and(|count| count > 0)
+        && nvenc_encoder::check_codec_support(codec).is_ok()
+}
@@ -0,0 +1,138 @@
+//! Just enough of the H.264 Annex B byte stream to find parameter sets and IDR slices.
+
+pub(crate) const NAL_IDR: u8 = 5;
+pub(crate) const NAL_SPS: u8 = 7;
+pub(crate) const NAL_PPS: u8 = 8;
+
+/// Type of a NAL unit, from its header byte
+pub(crate) fn nal_type(unit: &[u8]) -> u8 {
+    unit[0] & 0x1f
+}
+
+/// NAL units of an Annex B byte stream, without their start codes
+pub(crate) fn nal_units(data: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let mut starts = Vec::new();
+    let mut index = 0;
+    while index + 3 <= data.len() {
+        if data[index..index + 3] == [0, 0, 1] {
+            starts.push(index + 3);
+            index += 3;
+        } else {
+            index += 1;
+        }
+    }
+    let ends: Vec<usize> = starts
+        .iter()
+        .skip(1)
+        .map(|next| next - 3)
+        .chain([data.len()])
+        .collect();
+    starts.into_iter().zip(ends).filter_map(|(start, end)| {
+        // Zeros before a start code are the leading byte of a 4 byte one or trailing padding,
+        // a NAL unit never ends in a zero byte
+        let mut unit = &data[start..end];
+        while let [rest @ .., 0] = unit {
+            unit = rest;
+        }
+        (!unit.is_empty()).then_some(unit)
+    })
+}
+
+/// The SPS and PPS an encoder wrote last, put back in front of the keyframes it wrote without
+/// them, see [`crate::types::config::VideoConfig::repeat_headers`].
+///
+/// The encoders are opened without `AV_CODEC_FLAG_GLOBAL_HEADER`, so they write the parameter
+/// sets in band, but only on IDR frames and not always on each of them.
+#[derive(Debug, Default)]
+pub(crate) struct ParameterSets {
+    /// SPS and PPS in Annex B
+    units: Vec<u8>,
+}
+
+impl ParameterSets {
+    /// Remember the parameter sets of a packet, and copy the last ones in front of a keyframe
+    /// which has no SPS
+    pub(crate) fn repeat(&mut self, data: &[u8], is_keyframe: bool) -> Vec<u8> {
+        let (mut sps, mut pps) = (None, None);
+        for unit in nal_units(data) {
+            match nal_type(unit) {
+                NAL_SPS => sps = Some(unit),
+                NAL_PPS => pps = Some(unit),
+                _ => {}
+            }
+        }
+        if let (Some(sps), Some(pps)) = (sps, pps) {
+            self.units.clear();
+            for unit in [sps, pps] {
+                self.units.extend_from_slice(&[0, 0, 0, 1]);
+                self.units.extend_from_slice(unit);
+            }
+        }
+        if is_keyframe && sps.is_none() {
+            [&self.units[..], data].concat()
+        } else {
+            data.to_vec()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPS: [u8; 8] = [0, 0, 0, 1, 0x67, 0x64, 0x00, 0x28];
+    const PPS: [u8; 8] = [0, 0, 0, 1, 0x68, 0xee, 0x3c, 0x80];
+    const IDR: [u8; 7] = [0, 0, 1, 0x65, 0x88, 0x84, 0x10];
+    const P_SLICE: [u8; 7] = [0, 0, 1, 0x41, 0x9a, 0x22, 0x10];
+
+    fn nal_types(data: &[u8]) -> Vec<u8> {
+        nal_units(data).map(nal_type).collect()
+    }
+
+    #[test]
+    fn splits_annex_b_access_units() {
+        let access_unit = [
+            0, 0, 0, 1, 0x67, 0x64, 0x00, 0x28, // SPS, High profile level 4.0
+            0, 0, 0, 1, 0x68, 0xee, 0x3c, 0x80, // PPS
+            0, 0, 1, 0x65, 0x88, 0x84, 0x00, 0x10, 0, 0, // IDR slice with trailing zeros
+        ];
+        let units: Vec<&[u8]> = nal_units(&access_unit).collect();
+        assert_eq!(
+            units,
+            vec![
+                &[0x67, 0x64, 0x00, 0x28][..],
+                &[0x68, 0xee, 0x3c, 0x80][..],
+                &[0x65, 0x88, 0x84, 0x00, 0x10][..],
+            ]
+        );
+        assert_eq!(nal_types(&access_unit), vec![NAL_SPS, NAL_PPS, NAL_IDR]);
+        assert_eq!(nal_units(&[0x41, 0x9a, 0, 0]).count(), 0);
+    }
+
+    #[test]
+    fn keyframes_start_with_parameter_sets() {
+        let mut parameter_sets = ParameterSets::default();
+        let packets = [
+            ([&SPS[..], &PPS, &IDR].concat(), true),
+            (P_SLICE.to_vec(), false),
+            // Later IDR frames of the encoder come without them
+            (IDR.to_vec(), true),
+            (P_SLICE.to_vec(), false),
+        ];
+        let repeated: Vec<Vec<u8>> = packets
+            .iter()
+            .map(|(data, is_keyframe)| parameter_sets.repeat(data, *is_keyframe))
+            .collect();
+
+        for (index, (data, is_keyframe)) in packets.iter().enumerate() {
+            let types = nal_types(&repeated[index]);
+            if *is_keyframe {
+                assert_eq!(types, vec![NAL_SPS, NAL_PPS, NAL_IDR]);
+            } else {
+                assert_eq!(&repeated[index], data);
+            }
+        }
+        // Parameter sets the encoder wrote itself are not doubled
+        assert_eq!(repeated[0], packets[0].0);
+    }
+}
@@ -0,0 +1,147 @@
+use ffmpeg_next::{self as ffmpeg, ffi::AVRational, util::frame::side_data::Type};
+
+use crate::types::config::{ContentLightLevel, HdrMetadata, MasteringDisplay};
+
+// libavutil/mastering_display_metadata.h is not part of the generated ffmpeg bindings
+// so mirror the two structs here.
+#[repr(C)]
+struct AVMasteringDisplayMetadata {
+    display_primaries: [[AVRational; 2]; 3],
+    white_point: [AVRational; 2],
+    min_luminance: AVRational,
+    max_luminance: AVRational,
+    has_primaries: i32,
+    has_luminance: i32,
+}
+
+#[repr(C)]
+struct AVContentLightMetadata {
+    max_cll: u32,
+    max_fall: u32,
+}
+
+// Same denominators ffmpeg uses when parsing these values out of HEVC SEI
+const CHROMA_DEN: i32 = 50_000;
+const LUMA_DEN: i32 = 10_000;
+
+fn rational(value: f64, den: i32) -> AVRational {
+    AVRational {
+        num: (value * den as f64).round() as i32,
+        den,
+    }
+}
+
+impl From<&MasteringDisplay> for AVMasteringDisplayMetadata {
+    fn from(value: &MasteringDisplay) -> Self {
+        let primaries = value
+            .primaries
+            .map(|(x, y)| [rational(x, CHROMA_DEN), rational(y, CHROMA_DEN)]);
+        Self {
+            display_primaries: primaries,
+            white_point: [
+                rational(value.white_point.0, CHROMA_DEN),
+                rational(value.white_point.1, CHROMA_DEN),
+            ],
+            min_luminance: rational(value.min_luminance, LUMA_DEN),
+            max_luminance: rational(value.max_luminance, LUMA_DEN),
+            has_primaries: 1,
+            has_luminance: 1,
+        }
+    }
+}
+
+impl From<&ContentLightLevel> for AVContentLightMetadata {
+    fn from(value: &ContentLightLevel) -> Self {
+        Self {
+            max_cll: value.max_cll,
+            max_fall: value.max_fall,
+        }
+    }
+}
+
+/// Attach the HDR metadata as frame side data so the encoder writes it into the bitstream.
+///
+/// hevc_vaapi only emits the SEI on IDR frames, but attaching it to every frame means it is
+/// present on the first keyframe after an encoder reset as well.
+pub fn attach_hdr_side_data(frame: &mut ffmpeg::util::frame::Video, hdr: &HdrMetadata) {
+    if let Some(ref mastering) = hdr.mastering_display {
+        write_side_data(
+            frame,
+            Type::MasteringDisplayMetadata,
+            AVMasteringDisplayMetadata::from(mastering),
+        );
+    }
+    if let Some(ref cll) = hdr.content_light_level {
+        write_side_data(
+            frame,
+            Type::ContentLightLevel,
+            AVContentLightMetadata::from(cll),
+        );
+    }
+}
+
+fn write_side_data<T>(frame: &mut ffmpeg::util::frame::Video, kind: Type, value: T) {
+    // Frames coming out of the filter graph can carry side data from a previous frame
+    frame.remove_side_data(kind);
+    match frame.new_side_data(kind, std::mem::size_of::<T>()) {
+        Some(mut side_data) => unsafe {
+            std::ptr::write_unaligned((*side_data.as_mut_ptr()).data as *mut T, value);
+        },
+        None => log::error!("Could not allocate {kind:?} side data"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read<T>(frame: &ffmpeg::util::frame::Video, kind: Type) -> T {
+        let side_data = frame.side_data(kind).unwrap();
+        assert_eq!(side_data.data().len(), std::mem::size_of::<T>());
+        unsafe { std::ptr::read_unaligned(side_data.data().as_ptr() as *const T) }
+    }
+
+    #[test]
+    fn metadata_is_attached_in_the_units_of_ffmpeg() {
+        let hdr = HdrMetadata {
+            mastering_display: Some(MasteringDisplay::bt2020(0.005, 1000.0)),
+            content_light_level: Some(ContentLightLevel {
+                max_cll: 1000,
+                max_fall: 400,
+            }),
+        };
+        let mut frame = ffmpeg::util::frame::Video::new(ffmpeg::format::Pixel::NV12, 16, 16);
+        attach_hdr_side_data(&mut frame, &hdr);
+        // Attached again to a frame which carries it already, e.g. out of the filter graph
+        attach_hdr_side_data(&mut frame, &hdr);
+        assert_eq!(unsafe { (*frame.as_ptr()).nb_side_data }, 2);
+
+        let mastering: AVMasteringDisplayMetadata = read(&frame, Type::MasteringDisplayMetadata);
+        assert_eq!((mastering.has_primaries, mastering.has_luminance), (1, 1));
+        // Red, then green and blue
+        let red_x = mastering.display_primaries[0][0];
+        assert_eq!((red_x.num, red_x.den), (35_400, CHROMA_DEN));
+        let white_y = mastering.white_point[1];
+        assert_eq!((white_y.num, white_y.den), (16_450, CHROMA_DEN));
+        assert_eq!(mastering.min_luminance.num, 50);
+        assert_eq!(mastering.max_luminance.num, 10_000_000);
+
+        let light: AVContentLightMetadata = read(&frame, Type::ContentLightLevel);
+        assert_eq!((light.max_cll, light.max_fall), (1000, 400));
+    }
+
+    #[test]
+    fn missing_parts_are_left_out() {
+        let hdr = HdrMetadata {
+            mastering_display: None,
+            content_light_level: Some(ContentLightLevel {
+                max_cll: 1000,
+                max_fall: 400,
+            }),
+        };
+        let mut frame = ffmpeg::util::frame::Video::new(ffmpeg::format::Pixel::NV12, 16, 16);
+        attach_hdr_side_data(&mut frame, &hdr);
+        assert!(frame.side_data(Type::MasteringDisplayMetadata).is_none());
+        assert!(frame.side_data(Type::ContentLightLevel).is_some());
+    }
+}
@@ -1,8 +1,19 @@
 pub mod audio;
+mod av1;
 pub mod dma_buf_encoder;
+mod drm;
 pub mod dynamic_encoder;
+mod h264;
+mod hdr;
 pub mod opus_encoder;
+mod overlay;
+pub mod passthrough_encoder;
+mod pip;
+pub mod qsv_encoder;
 pub mod rgba_image_encoder;
+pub mod software_encoder;
+pub(crate) mod spa_format;
+mod va;
 pub mod vaapi_encoder;
 pub mod video;
 
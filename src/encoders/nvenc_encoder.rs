@@ -1,7 +1,14 @@
-use std::ptr::null_mut;
+use std::{
+    ptr::null_mut,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+};
 
-use crossbeam::channel::{bounded, Receiver, Sender};
+use crossbeam::channel::Receiver;
 use cust::{
+    device::{Device, DeviceAttribute},
     prelude::Context,
     sys::{
         cuCtxSetCurrent, cuGraphicsMapResources, cuGraphicsResourceSetMapFlags_v2,
@@ -13,35 +20,51 @@ use cust::{
 use ffmpeg_next::{
     self as ffmpeg,
     ffi::{
-        av_buffer_ref, av_buffer_unref, av_hwdevice_ctx_alloc, av_hwdevice_ctx_init,
-        av_hwframe_ctx_init, av_hwframe_get_buffer, AVHWDeviceContext, AVHWFramesContext,
-        AVPixelFormat,
+        av_hwdevice_ctx_alloc, av_hwdevice_ctx_init, av_hwframe_ctx_init, av_hwframe_get_buffer,
+        avcodec_flush_buffers, AVHWDeviceContext, AVHWFramesContext, AVPixelFormat,
+        AV_CODEC_CAP_ENCODER_FLUSH,
     },
-    Rational,
 };
-use pipewire as pw;
+use pipewire::{self as pw, spa::param::video::VideoFormat};
 
 use crate::{
-    encoders::video::{PipewireSPA, ProcessingThread, VideoEncoder},
+    encoders::video::{PipewireSPA, ProcessingThread, VideoEncoder, OUTPUT_CAPACITY},
+    failure_injection, ffmpeg_compat, ffmpeg_log,
+    pipeline::fanout::{Delivery, FanOut},
     types::{
-        config::QualityPreset,
+        config::{
+            H264Profile, LatencyMode, NvencPreset, NvencTune, OverlayConfig, QualityPreset,
+            RateControl, Rect, Scaling, VideoConfig,
+        },
+        encoder_info::{EncoderInfo, NvencSessionInfo},
         error::{Result, WaycapError},
+        event::PipelineStage,
+        stats::StatsCounters,
+        time::{CaptureTime, StreamPts},
         video_frame::{EncodedVideoFrame, RawVideoFrame},
     },
-    utils::{extract_dmabuf_planes, TIME_UNIT_NS},
+    utils::extract_dmabuf_planes,
     waycap_egl::EglContext,
 };
 use khronos_egl::Image;
 
 use super::{
     cuda::{cuGraphicsGLRegisterImage, AVCUDADeviceContext},
-    video::{create_hw_frame_ctx, GOP_SIZE},
+    h264::ParameterSets,
+    hdr::attach_hdr_side_data,
+    overlay::Overlay,
+    spa_format::VideoFormatOffer,
+    video::{
+        changed_frame_transform, create_hw_frame_ctx, ffmpeg_color_range, open_configured_encoder,
+        resend_frame, set_bitrate_options, set_colorimetry, HwBufferRef, GOP_SIZE,
+        SCHEDULED_GOP_SIZE,
+    },
 };
 
 // Literally stole these by looking at what OBS uses
 // just magic numbers to me no clue what these are
 // but they enable DMA Buf so it is what it is
-const NVIDIA_MODIFIERS: &[i64] = &[
+const NVIDIA_MODIFIERS: &[u64] = &[
     216172782120099856,
     216172782120099857,
     216172782120099858,
@@ -57,6 +80,130 @@ const NVIDIA_MODIFIERS: &[i64] = &[
     72057594037927935,
 ];
 
+/// NVENC sessions held by the encoders of this process
+static SESSIONS_IN_USE: AtomicU32 = AtomicU32::new(0);
+
+/// NVENC sessions this process holds, and how many the driver allows where that can be told
+pub fn nvenc_session_info() -> NvencSessionInfo {
+    NvencSessionInfo {
+        max_sessions: detect_session_limit(),
+        in_use_by_us: SESSIONS_IN_USE.load(Ordering::Relaxed),
+    }
+}
+
+/// Give back the session of an encoder opened with [`NvencEncoder::create_encoder`] once it is
+/// dropped
+pub(crate) fn session_closed() {
+    SESSIONS_IN_USE.fetch_sub(1, Ordering::Relaxed);
+}
+
+fn detect_session_limit() -> Option<u32> {
+    let driver_version = std::fs::read_to_string("/sys/module/nvidia/version").ok()?;
+    let models: Vec<String> = std::fs::read_dir("/proc/driver/nvidia/gpus")
+        .ok()?
+        .filter_map(|gpu| std::fs::read_to_string(gpu.ok()?.path().join("information")).ok())
+        .filter_map(|info| {
+            info.lines()
+                .find_map(|line| line.strip_prefix("Model:"))
+                .map(|model| model.trim().to_string())
+        })
+        .collect();
+    session_limit(&driver_version, &models)
+}
+
+/// Concurrent sessions the driver allows on consumer GPUs, `None` when only professional GPUs,
+/// which have no limit, are installed
+fn session_limit(driver_version: &str, models: &[String]) -> Option<u32> {
+    let major: u32 = driver_version.trim().split('.').next()?.parse().ok()?;
+    if !models
+        .iter()
+        .any(|model| model.contains("GeForce") || model.contains("TITAN"))
+    {
+        return None;
+    }
+    // Raised from 3 with the 530 drivers and again with the 550 drivers
+    Some(match major {
+        550.. => 8,
+        530.. => 5,
+        _ => 3,
+    })
+}
+
+/// Codecs encoded through NVENC
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum NvencCodec {
+    H264,
+    Hevc,
+    /// Needs an RTX 40 series GPU or newer and ffmpeg 6.0
+    Av1,
+}
+
+impl NvencCodec {
+    pub fn encoder_name(self) -> &'static str {
+        match self {
+            NvencCodec::H264 => "h264_nvenc",
+            NvencCodec::Hevc => "hevc_nvenc",
+            NvencCodec::Av1 => "av1_nvenc",
+        }
+    }
+
+    /// Highest qp of constant qp rate control
+    fn max_qp(self) -> u32 {
+        match self {
+            NvencCodec::H264 | NvencCodec::Hevc => 51,
+            NvencCodec::Av1 => 255,
+        }
+    }
+
+    /// Highest cq the quality driven VBR of the presets takes
+    fn max_cq(self) -> u32 {
+        match self {
+            NvencCodec::H264 | NvencCodec::Hevc => 51,
+            NvencCodec::Av1 => 63,
+        }
+    }
+}
+
+/// Fail with an explanation when the GPU has no NVENC engine for `codec`, instead of the
+/// generic error ffmpeg gives when opening the encoder
+pub(crate) fn check_codec_support(codec: NvencCodec) -> Result<()> {
+    if codec != NvencCodec::Av1 {
+        return Ok(());
+    }
+    match compute_capability() {
+        // Ada, compute capability 8.9, is the first generation encoding AV1
+        Some((major, minor)) if (major, minor) < (8, 9) => Err(WaycapError::Unsupported(format!(
+            "AV1 NVENC needs an RTX 40 series GPU or newer, this one has compute capability \
+             {major}.{minor}. Use H.264 or HEVC NVENC instead"
+        ))),
+        // Left to ffmpeg when the device can't be asked
+        _ => Ok(()),
+    }
+}
+
+/// Whether ffmpeg logged the driver refusing to open an encode session. It does so with
+/// NV_ENC_ERR_OUT_OF_MEMORY once its concurrent session limit is reached, which ffmpeg reports
+/// as ENOMEM like any failed allocation.
+fn refused_session(messages: &[String]) -> bool {
+    messages.iter().any(|message| {
+        message.contains("NV_ENC_ERR_OUT_OF_MEMORY")
+            || message.contains("OpenEncodeSessionEx failed: out of memory")
+    })
+}
+
+/// Compute capability of the first CUDA device, the one `cust::quick_init` opens
+fn compute_capability() -> Option<(i32, i32)> {
+    let device = Device::get_device(0).ok()?;
+    Some((
+        device
+            .get_attribute(DeviceAttribute::ComputeCapabilityMajor)
+            .ok()?,
+        device
+            .get_attribute(DeviceAttribute::ComputeCapabilityMinor)
+            .ok()?,
+    ))
+}
+
 /// Encoder which provides frames encoded using Nvenc
 ///
 /// Only available for Nvidia GPUs
@@ -64,15 +211,27 @@ pub struct NvencEncoder {
     encoder: Option<ffmpeg::codec::encoder::Video>,
     width: u32,
     height: u32,
-    encoder_name: String,
-    quality: QualityPreset,
-    encoded_frame_recv: Option<Receiver<EncodedVideoFrame>>,
-    encoded_frame_sender: Sender<EncodedVideoFrame>,
+    codec: NvencCodec,
+    config: VideoConfig,
+    rejected_options: Vec<String>,
+    output: FanOut<EncodedVideoFrame>,
+    stats: Arc<StatsCounters>,
 
     cuda_ctx: Context,
     graphics_resource: CUgraphicsResource,
     egl_context: Option<Box<EglContext>>, // boxed egl context because its huge
     egl_texture: u32,
+    /// The last frame copied out of the texture, kept with [`VideoConfig::cfr`] or
+    /// [`VideoConfig::timelapse`] to repeat it
+    last_frame: Option<ffmpeg::util::frame::Video>,
+    parameter_sets: ParameterSets,
+    /// [`VideoConfig::transform`] was left to the compositor, the config holds the one of the
+    /// last frame then
+    follows_compositor_transform: bool,
+    /// [`VideoConfig::overlay`], read once
+    overlay: Option<Overlay>,
+    /// The overlay is in a texture of the current EGL context
+    overlay_uploaded: bool,
 }
 
 unsafe impl Send for NvencEncoder {}
@@ -81,25 +240,41 @@ unsafe impl Sync for NvencEncoder {}
 impl VideoEncoder for NvencEncoder {
     type Output = EncodedVideoFrame;
     fn reset(&mut self) -> Result<()> {
+        if self.config.reserve_nvenc_session && self.reuse_encoder() {
+            return Ok(());
+        }
         self.drop_processor();
-        let new_encoder = Self::create_encoder(
-            self.width,
-            self.height,
-            &self.encoder_name,
-            &self.quality,
-            &self.cuda_ctx,
-        )?;
+        let (width, height) = self.config.encoded_size(self.width, self.height)?;
+        let (new_encoder, rejected_options) =
+            Self::create_encoder(width, height, self.codec, &self.config, &self.cuda_ctx)?;
 
         self.encoder = Some(new_encoder);
+        self.rejected_options = rejected_options;
         Ok(())
     }
 
+    fn source_changed(&mut self, width: u32, height: u32) -> Result<()> {
+        // Fails before draining when the crop lies outside the new source
+        self.config.encoded_size(width, height)?;
+        self.drain()?;
+        self.width = width;
+        self.height = height;
+        self.reset()
+    }
+
     fn drop_processor(&mut self) {
-        self.encoder.take();
+        self.last_frame.take();
+        if self.encoder.take().is_some() {
+            session_closed();
+        }
     }
 
     fn output(&mut self) -> Option<Receiver<EncodedVideoFrame>> {
-        self.encoded_frame_recv.clone()
+        Some(self.output.subscribe())
+    }
+
+    fn has_consumers(&self) -> bool {
+        self.output.has_subscribers()
     }
 
     fn drain(&mut self) -> Result<()> {
@@ -112,18 +287,86 @@ impl VideoEncoder for NvencEncoder {
         Ok(())
     }
 
+    /// A reserved session is given up too, as the cq and preset are only taken on opening
+    fn change_quality(&mut self, quality: QualityPreset) -> Result<()> {
+        self.config.set_quality(quality)?;
+        self.drain()?;
+        self.drop_processor();
+        self.reset()
+    }
+
+    /// ffmpeg reconfigures the open session when the bitrates on the context changed before
+    /// the next frame, which every NVENC generation supports. The stream goes on without a
+    /// keyframe.
+    fn set_bitrate(&mut self, bits_per_sec: u64) -> Result<()> {
+        let rate_control = self.config.set_bitrate(bits_per_sec)?;
+        if let (Some(ref mut encoder), Some((bitrate, max_bitrate, buffer_size))) =
+            (&mut self.encoder, rate_control.bitrate_limits())
+        {
+            unsafe {
+                let ctx = encoder.as_mut_ptr();
+                (*ctx).bit_rate = bitrate as i64;
+                (*ctx).rc_max_rate = max_bitrate as i64;
+                (*ctx).rc_buffer_size = buffer_size.min(i32::MAX as u64) as i32;
+            }
+        }
+        Ok(())
+    }
+
+    /// Moving the crop only changes where the frames are copied from, a new size reopens the
+    /// encoder
+    fn set_crop(&mut self, crop: Option<Rect>) -> Result<()> {
+        let mut config = self.config.clone();
+        config.set_crop(crop)?;
+        let size = config.encoded_size(self.width, self.height)?;
+        let same_size = self
+            .encoder
+            .as_ref()
+            .is_some_and(|encoder| (encoder.width(), encoder.height()) == size);
+        self.config = config;
+        if !same_size {
+            self.drain()?;
+            self.drop_processor();
+            self.reset()?;
+        }
+        Ok(())
+    }
+
+    /// The image is uploaded with the next frame, the encoder is kept
+    fn set_overlay(&mut self, overlay: Option<OverlayConfig>) -> Result<()> {
+        let mut config = self.config.clone();
+        config.set_overlay(overlay)?;
+        let overlay = config.overlay.as_ref().map(Overlay::load).transpose()?;
+        if let Some(ref overlay) = overlay {
+            overlay.placement(config.encoded_size(self.width, self.height)?)?;
+        }
+        self.overlay = overlay;
+        self.overlay_uploaded = false;
+        self.config = config;
+        Ok(())
+    }
+
     fn get_encoder(&self) -> &Option<ffmpeg::codec::encoder::Video> {
         &self.encoder
     }
+
+    fn info(&self) -> Option<EncoderInfo> {
+        Some(EncoderInfo {
+            name: self.codec.encoder_name().to_string(),
+            rejected_options: self.rejected_options.clone(),
+            ..Default::default()
+        })
+    }
 }
 impl ProcessingThread for NvencEncoder {
     fn thread_setup(&mut self) -> Result<()> {
-        self.egl_context = Some(Box::new(EglContext::new(
-            self.width as i32,
-            self.height as i32,
-        )?));
+        // The texture the frames are copied into is the size of the encoder, so they are cropped
+        // and scaled on the way
+        let (width, height) = self.config.encoded_size(self.width, self.height)?;
+        self.egl_context = Some(Box::new(EglContext::new(width as i32, height as i32)?));
         self.make_current()?;
         self.init_gl(None)?;
+        self.overlay_uploaded = false;
         Ok(())
     }
 
@@ -132,7 +375,28 @@ impl ProcessingThread for NvencEncoder {
     }
 
     fn process(&mut self, frame: RawVideoFrame) -> Result<()> {
-        match egl_img_from_dmabuf(self.egl_context.as_ref().unwrap(), &frame) {
+        if let Some(transform) =
+            changed_frame_transform(&self.config, self.follows_compositor_transform, &frame)
+        {
+            self.config.transform = Some(transform);
+            self.drain()?;
+            self.drop_processor();
+            self.reset()?;
+        }
+        self.fit_texture()?;
+        let scaling = self.config.scaling(self.width, self.height)?;
+        let egl_context = self.egl_context.as_ref().unwrap();
+        if !self.overlay_uploaded {
+            egl_context.set_overlay_image(self.overlay.as_ref().map(|overlay| &overlay.image))?;
+            self.overlay_uploaded = true;
+        }
+        match egl_img_from_dmabuf(
+            egl_context,
+            &frame,
+            &scaling,
+            self.config.pad_color,
+            self.overlay.as_ref(),
+        ) {
             Ok(img) => {
                 if let Some(ref mut encoder) = self.encoder {
                     let mut cuda_frame = ffmpeg::util::frame::Video::new(
@@ -221,32 +485,18 @@ impl ProcessingThread for NvencEncoder {
                         gl::BindTexture(gl::TEXTURE_2D, 0);
                     }
 
-                    cuda_frame.set_pts(Some(frame.timestamp));
-                    encoder.send_frame(&cuda_frame)?;
-
-                    let mut packet = ffmpeg::codec::packet::Packet::empty();
-                    if encoder.receive_packet(&mut packet).is_ok() {
-                        if let Some(data) = packet.data() {
-                            match self.encoded_frame_sender.try_send(EncodedVideoFrame {
-                                data: data.to_vec(),
-                                is_keyframe: packet.is_key(),
-                                pts: packet.pts().unwrap_or(0),
-                                dts: packet.dts().unwrap_or(0),
-                            }) {
-                                Ok(_) => {}
-                                Err(crossbeam::channel::TrySendError::Full(_)) => {
-                                    log::error!(
-                                        "Could not send encoded video frame. Receiver is full"
-                                    );
-                                }
-                                Err(crossbeam::channel::TrySendError::Disconnected(_)) => {
-                                    log::error!(
-                                        "Could not send encoded video frame. Receiver disconnected"
-                                    );
-                                }
-                            }
-                        };
+                    cuda_frame.set_pts(Some(frame.timestamp.as_nanos()));
+                    if let Some(ref hdr) = self.config.hdr_metadata {
+                        attach_hdr_side_data(&mut cuda_frame, hdr);
+                    }
+                    if frame.force_keyframe {
+                        cuda_frame.set_kind(ffmpeg::picture::Type::I);
+                    }
+                    failure_injection::send_frame(encoder, &cuda_frame)?;
+                    if self.config.repeats_frames() {
+                        self.last_frame = Some(cuda_frame);
                     }
+                    self.emit_packet();
                 }
                 self.egl_context.as_ref().unwrap().destroy_image(img)?;
             }
@@ -254,80 +504,38 @@ impl ProcessingThread for NvencEncoder {
         }
         Ok(())
     }
+
+    fn repeat_frame(&mut self, timestamp: CaptureTime, force_keyframe: bool) -> Result<bool> {
+        let (Some(encoder), Some(frame)) = (self.encoder.as_mut(), self.last_frame.as_mut()) else {
+            return Ok(false);
+        };
+        // The CUDA buffer the texture was copied into, nothing is drawn or copied again
+        resend_frame(encoder, frame, timestamp, force_keyframe)?;
+        self.emit_packet();
+        Ok(true)
+    }
 }
 
 impl PipewireSPA for NvencEncoder {
     fn get_spa_definition() -> Result<pw::spa::pod::Object> {
-        let nvidia_mod_property = pw::spa::pod::Property {
-            key: pw::spa::param::format::FormatProperties::VideoModifier.as_raw(),
-            flags: pw::spa::pod::PropertyFlags::empty(),
-            value: pw::spa::pod::Value::Choice(pw::spa::pod::ChoiceValue::Long(
-                pw::spa::utils::Choice::<i64>(
-                    pw::spa::utils::ChoiceFlags::empty(),
-                    pw::spa::utils::ChoiceEnum::<i64>::Enum {
-                        default: NVIDIA_MODIFIERS[0],
-                        alternatives: NVIDIA_MODIFIERS.to_vec(),
-                    },
-                ),
-            )),
-        };
-
-        Ok(pw::spa::pod::object!(
-            pw::spa::utils::SpaTypes::ObjectParamFormat,
-            pw::spa::param::ParamType::EnumFormat,
-            pw::spa::pod::property!(
-                pw::spa::param::format::FormatProperties::MediaType,
-                Id,
-                pw::spa::param::format::MediaType::Video
-            ),
-            pw::spa::pod::property!(
-                pw::spa::param::format::FormatProperties::MediaSubtype,
-                Id,
-                pw::spa::param::format::MediaSubtype::Raw
-            ),
-            nvidia_mod_property,
-            pw::spa::pod::property!(
-                pw::spa::param::format::FormatProperties::VideoFormat,
-                Choice,
-                Enum,
-                Id,
-                pw::spa::param::video::VideoFormat::NV12,
-                pw::spa::param::video::VideoFormat::I420,
-                pw::spa::param::video::VideoFormat::BGRA,
-                pw::spa::param::video::VideoFormat::BGRx
-            ),
-            pw::spa::pod::property!(
-                pw::spa::param::format::FormatProperties::VideoSize,
-                Choice,
-                Range,
-                Rectangle,
-                pw::spa::utils::Rectangle {
-                    width: 2560,
-                    height: 1440
-                }, // Default
-                pw::spa::utils::Rectangle {
-                    width: 1,
-                    height: 1
-                }, // Min
-                pw::spa::utils::Rectangle {
-                    width: 4096,
-                    height: 4096
-                } // Max
-            ),
-            pw::spa::pod::property!(
-                pw::spa::param::format::FormatProperties::VideoFramerate,
-                Choice,
-                Range,
-                Fraction,
-                pw::spa::utils::Fraction { num: 240, denom: 1 }, // Default
-                pw::spa::utils::Fraction { num: 0, denom: 1 },   // Min
-                pw::spa::utils::Fraction { num: 244, denom: 1 }  // Max
-            ),
-        ))
+        Ok(VideoFormatOffer::new(vec![
+            VideoFormat::NV12,
+            VideoFormat::I420,
+            VideoFormat::BGRA,
+            VideoFormat::BGRx,
+        ])
+        .with_modifiers(NVIDIA_MODIFIERS.to_vec())
+        .to_object())
     }
 }
 
-fn egl_img_from_dmabuf(egl_ctx: &EglContext, raw_frame: &RawVideoFrame) -> Result<Image> {
+fn egl_img_from_dmabuf(
+    egl_ctx: &EglContext,
+    raw_frame: &RawVideoFrame,
+    scaling: &Scaling,
+    pad_color: [u8; 3],
+    overlay: Option<&Overlay>,
+) -> Result<Image> {
     let dma_buf_planes = extract_dmabuf_planes(raw_frame)?;
 
     let format = drm_fourcc::DrmFourcc::Argb8888 as u32;
@@ -341,45 +549,116 @@ fn egl_img_from_dmabuf(egl_ctx: &EglContext, raw_frame: &RawVideoFrame) -> Resul
         modifier,
     )?;
 
-    egl_ctx.update_texture_from_image(egl_image)?;
+    egl_ctx.update_texture_from_image(
+        egl_image,
+        (raw_frame.dimensions.width, raw_frame.dimensions.height),
+        scaling,
+        pad_color,
+    )?;
+    if let Some(overlay) = overlay {
+        let (width, height) = egl_ctx.texture_size();
+        let placement = overlay.placement((width as u32, height as u32))?;
+        egl_ctx.draw_overlay(placement, overlay.opacity)?;
+    }
 
     Ok(egl_image)
 }
 
 impl NvencEncoder {
-    pub(crate) fn new(width: u32, height: u32, quality: QualityPreset) -> Result<Self> {
-        let encoder_name = "h264_nvenc";
+    pub(crate) fn set_stats(&mut self, stats: Arc<StatsCounters>) {
+        self.stats = stats;
+    }
 
-        let (frame_tx, frame_rx): (Sender<EncodedVideoFrame>, Receiver<EncodedVideoFrame>) =
-            bounded(10);
-        let cuda_ctx = cust::quick_init().unwrap();
+    /// Output queues, for watching their depth without subscribing
+    pub(crate) fn output_queues(&self) -> FanOut<EncodedVideoFrame> {
+        self.output.clone()
+    }
+
+    /// Send the packet of the last frame to the output, if the encoder has it ready
+    fn emit_packet(&mut self) {
+        let Some(ref mut encoder) = self.encoder else {
+            return;
+        };
+        let mut packet = ffmpeg::codec::packet::Packet::empty();
+        if encoder.receive_packet(&mut packet).is_ok() {
+            if let Some(data) = packet.data() {
+                let pts = StreamPts::new(packet.pts().unwrap_or(0), CaptureTime::TIME_BASE);
+                let data = if self.codec == NvencCodec::H264 && self.config.repeat_headers {
+                    self.parameter_sets.repeat(data, packet.is_key())
+                } else {
+                    data.to_vec()
+                };
+                match self.output.send(EncodedVideoFrame {
+                    data,
+                    is_keyframe: packet.is_key(),
+                    pts,
+                    dts: StreamPts::new(packet.dts().unwrap_or(0), CaptureTime::TIME_BASE),
+                }) {
+                    Delivery::Delivered => self.stats.mark_packet_emitted(pts.into()),
+                    Delivery::Full => {
+                        self.stats.mark_frame_dropped();
+                        log::error!("Could not send encoded video frame. Receiver is full");
+                        self.stats
+                            .record_error(PipelineStage::Consumer, "Encoded receiver full");
+                    }
+                    // Handled once by the processing loop
+                    Delivery::NoSubscribers => {}
+                }
+            };
+        }
+    }
 
-        let encoder = Self::create_encoder(width, height, encoder_name, &quality, &cuda_ctx)?;
+    pub(crate) fn new(
+        width: u32,
+        height: u32,
+        codec: NvencCodec,
+        config: VideoConfig,
+    ) -> Result<Self> {
+        let cuda_ctx = cust::quick_init()
+            .map_err(|e| WaycapError::Init(format!("Could not initialize CUDA: {e}")))?;
+
+        let (output_width, output_height) = config.encoded_size(width, height)?;
+        // Checked before opening a session, which would be counted until it's closed
+        let overlay = config.overlay.as_ref().map(Overlay::load).transpose()?;
+        if let Some(ref overlay) = overlay {
+            overlay.placement((output_width, output_height))?;
+        }
+        let (encoder, rejected_options) =
+            Self::create_encoder(output_width, output_height, codec, &config, &cuda_ctx)?;
 
+        let follows_compositor_transform = config.transform.is_none();
         Ok(Self {
             encoder: Some(encoder),
             width,
             height,
-            encoder_name: encoder_name.to_string(),
-            quality,
-            encoded_frame_recv: Some(frame_rx),
-            encoded_frame_sender: frame_tx,
+            codec,
+            config,
+            rejected_options,
+            output: FanOut::new(OUTPUT_CAPACITY),
+            stats: Arc::default(),
             cuda_ctx,
             graphics_resource: null_mut(),
             egl_context: None,
             egl_texture: 0,
+            last_frame: None,
+            parameter_sets: ParameterSets::default(),
+            follows_compositor_transform,
+            overlay,
+            overlay_uploaded: false,
         })
     }
 
-    fn create_encoder(
+    /// Open an NVENC encoder taking CUDA frames. It counts towards [`nvenc_session_info`] until
+    /// [`session_closed`] is called for it.
+    pub(crate) fn create_encoder(
         width: u32,
         height: u32,
-        encoder: &str,
-        quality: &QualityPreset,
+        codec: NvencCodec,
+        config: &VideoConfig,
         cuda_ctx: &Context,
-    ) -> Result<ffmpeg::codec::encoder::Video> {
-        let encoder_codec =
-            ffmpeg::codec::encoder::find_by_name(encoder).ok_or(ffmpeg::Error::EncoderNotFound)?;
+    ) -> Result<(ffmpeg::codec::encoder::Video, Vec<String>)> {
+        let encoder_codec = ffmpeg_compat::find_encoder(codec.encoder_name())?;
+        check_codec_support(codec)?;
 
         let mut encoder_ctx = ffmpeg::codec::context::Context::new_with_codec(encoder_codec)
             .encoder()
@@ -392,20 +671,16 @@ impl NvencEncoder {
 
         unsafe {
             // Set up the cuda context
-            let nvenc_device =
-                av_hwdevice_ctx_alloc(ffmpeg_next::ffi::AVHWDeviceType::AV_HWDEVICE_TYPE_CUDA);
-
-            if nvenc_device.is_null() {
-                return Err(WaycapError::Init(
-                    "Could not initialize nvenc device".into(),
-                ));
-            }
+            let nvenc_device = HwBufferRef::from_raw(av_hwdevice_ctx_alloc(
+                ffmpeg_next::ffi::AVHWDeviceType::AV_HWDEVICE_TYPE_CUDA,
+            ))
+            .ok_or_else(|| WaycapError::Init("Could not initialize nvenc device".into()))?;
 
-            let hw_device_ctx = (*nvenc_device).data as *mut AVHWDeviceContext;
+            let hw_device_ctx = (*nvenc_device.as_ptr()).data as *mut AVHWDeviceContext;
             let cuda_device_ctx = (*hw_device_ctx).hwctx as *mut AVCUDADeviceContext;
             (*cuda_device_ctx).cuda_ctx = cuda_ctx.as_raw();
 
-            let err = av_hwdevice_ctx_init(nvenc_device);
+            let err = av_hwdevice_ctx_init(nvenc_device.as_ptr());
 
             if err < 0 {
                 return Err(WaycapError::Init(format!(
@@ -413,19 +688,13 @@ impl NvencEncoder {
                 )));
             }
 
-            let hw_device_ctx = (*nvenc_device).data as *mut AVHWDeviceContext;
+            let hw_device_ctx = (*nvenc_device.as_ptr()).data as *mut AVHWDeviceContext;
             let cuda_device_ctx = (*hw_device_ctx).hwctx as *mut AVCUDADeviceContext;
             (*cuda_device_ctx).cuda_ctx = cuda_ctx.as_raw();
 
-            let mut frame_ctx = create_hw_frame_ctx(nvenc_device)?;
-
-            if frame_ctx.is_null() {
-                return Err(WaycapError::Init(
-                    "Could not initialize hw frame context".into(),
-                ));
-            }
+            let frame_ctx = create_hw_frame_ctx(&nvenc_device)?;
 
-            let hw_frame_context = &mut *((*frame_ctx).data as *mut AVHWFramesContext);
+            let hw_frame_context = &mut *((*frame_ctx.as_ptr()).data as *mut AVHWFramesContext);
 
             hw_frame_context.width = width as i32;
             hw_frame_context.height = height as i32;
@@ -433,63 +702,220 @@ impl NvencEncoder {
             hw_frame_context.format = encoder_ctx.format().into();
             hw_frame_context.device_ctx = hw_device_ctx;
             // Decides buffer size if we do not pop frame from the encoder we cannot
-            // keep pushing. Smaller better as we reserve less GPU memory
-            hw_frame_context.initial_pool_size = 2;
-
-            let err = av_hwframe_ctx_init(frame_ctx);
+            // keep pushing. Smaller better as we reserve less GPU memory, but NVENC holds on to
+            // the frames it looks ahead at or keeps back for B-frames, and a constant frame rate
+            // keeps the last one to repeat
+            hw_frame_context.initial_pool_size =
+                (2 + config.b_frames() + config.nvenc_tuning.lookahead + config.kept_frames())
+                    as i32;
+
+            let err = av_hwframe_ctx_init(frame_ctx.as_ptr());
             if err < 0 {
                 return Err(WaycapError::Init(format!(
                     "Error trying to initialize hw frame context: {err:?}",
                 )));
             }
 
-            (*encoder_ctx.as_mut_ptr()).hw_device_ctx = av_buffer_ref(nvenc_device);
-            (*encoder_ctx.as_mut_ptr()).hw_frames_ctx = av_buffer_ref(frame_ctx);
-
-            av_buffer_unref(&mut frame_ctx);
+            // The context frees these with itself, the guards release ours
+            (*encoder_ctx.as_mut_ptr()).hw_device_ctx = nvenc_device.new_ref()?;
+            (*encoder_ctx.as_mut_ptr()).hw_frames_ctx = frame_ctx.new_ref()?;
         }
 
-        encoder_ctx.set_time_base(Rational::new(1, TIME_UNIT_NS as i32));
-        encoder_ctx.set_gop(GOP_SIZE);
+        encoder_ctx.set_time_base(CaptureTime::TIME_BASE);
+        if config
+            .rate_control
+            .is_some_and(RateControl::targets_bitrate)
+        {
+            // NVENC budgets each frame with the frame rate, or the time base without one
+            encoder_ctx.set_frame_rate(Some(ffmpeg::Rational::new(config.framerate as i32, 1)));
+        }
+        encoder_ctx.set_gop(if config.schedules_keyframes() {
+            SCHEDULED_GOP_SIZE
+        } else {
+            GOP_SIZE
+        });
+        encoder_ctx.set_max_b_frames(config.b_frames() as usize);
+        // NVENC converts the RGB frames itself, with the matrix and range of the stream's VUI
+        encoder_ctx.set_color_range(ffmpeg_color_range(config.color_range));
+        set_colorimetry(&mut encoder_ctx, config.colorimetry);
 
         let encoder_params = ffmpeg::codec::Parameters::new();
 
-        let opts = Self::get_encoder_params(quality);
+        let mut opts = Self::get_encoder_params(codec, config)?;
+        // Frames forced to I are otherwise plain I frames, not ones a segment can start at
+        opts.set("forced-idr", "1");
 
         encoder_ctx.set_parameters(encoder_params)?;
-        let encoder = encoder_ctx.open_with(opts)?;
+        let (opened, messages) =
+            ffmpeg_log::collect_messages(|| open_configured_encoder(encoder_ctx, opts, config));
+        let opened = opened.map_err(|e| match e {
+            WaycapError::FFmpeg(ffmpeg::Error::Other {
+                errno: libc::ENOMEM,
+            }) if refused_session(&messages) => WaycapError::NvencSessionLimit,
+            e => e,
+        })?;
+        SESSIONS_IN_USE.fetch_add(1, Ordering::Relaxed);
+        Ok(opened)
+    }
 
-        Ok(encoder)
+    /// Make the drained encoder take frames again without closing its session. False when it
+    /// has to be reopened, because the size changed or the encoder can't be flushed.
+    fn reuse_encoder(&mut self) -> bool {
+        let Some(ref mut encoder) = self.encoder else {
+            return false;
+        };
+        let size = self.config.encoded_size(self.width, self.height).ok();
+        if size != Some((encoder.width(), encoder.height())) {
+            return false;
+        }
+        unsafe {
+            let codec = (*encoder.as_ptr()).codec;
+            if codec.is_null() || (*codec).capabilities & AV_CODEC_CAP_ENCODER_FLUSH as i32 == 0 {
+                return false;
+            }
+            avcodec_flush_buffers(encoder.as_mut_ptr());
+        }
+        true
+    }
+
+    /// NVENC preset, cq and bitrate cap of `quality`
+    fn preset_params(
+        codec: NvencCodec,
+        quality: &QualityPreset,
+    ) -> (&'static str, u32, &'static str) {
+        match (codec, quality) {
+            (NvencCodec::H264, QualityPreset::Low) => ("p2", 30, "20M"),
+            (NvencCodec::H264, QualityPreset::Medium) => ("p4", 25, "40M"),
+            (NvencCodec::H264, QualityPreset::High) => ("p7", 20, "80M"),
+            (NvencCodec::H264, QualityPreset::Ultra) => ("p7", 15, "120M"),
+            // HEVC looks the same at a higher cq and about half the bitrate. p7 is too slow
+            // for 4K at high framerates on older GPUs, p6 is nearly as good
+            (NvencCodec::Hevc, QualityPreset::Low) => ("p2", 32, "10M"),
+            (NvencCodec::Hevc, QualityPreset::Medium) => ("p4", 28, "20M"),
+            (NvencCodec::Hevc, QualityPreset::High) => ("p6", 24, "40M"),
+            (NvencCodec::Hevc, QualityPreset::Ultra) => ("p6", 19, "60M"),
+            // AV1's cq goes up to 63, screen content needs even less bitrate than with HEVC
+            (NvencCodec::Av1, QualityPreset::Low) => ("p2", 40, "8M"),
+            (NvencCodec::Av1, QualityPreset::Medium) => ("p4", 34, "16M"),
+            (NvencCodec::Av1, QualityPreset::High) => ("p6", 28, "32M"),
+            (NvencCodec::Av1, QualityPreset::Ultra) => ("p7", 22, "50M"),
+            // Lossless takes the lossless tune, which sets the quantizer, at Ultra's speed
+            (_, QualityPreset::Lossless) => Self::preset_params(codec, &QualityPreset::Ultra),
+            // The speed and bitrate cap of Medium around the pinned cq
+            (_, QualityPreset::Custom(custom)) => {
+                let (preset, _, bitrate) = Self::preset_params(codec, &QualityPreset::Medium);
+                (preset, custom.nvenc_cq, bitrate)
+            }
+        }
     }
 
-    fn get_encoder_params(quality: &QualityPreset) -> ffmpeg::Dictionary<'_> {
+    fn get_encoder_params(
+        codec: NvencCodec,
+        config: &VideoConfig,
+    ) -> Result<ffmpeg::Dictionary<'static>> {
         let mut opts = ffmpeg::Dictionary::new();
-        opts.set("vsync", "vfr");
-        opts.set("rc", "vbr");
-        opts.set("tune", "hq");
-        match quality {
-            QualityPreset::Low => {
-                opts.set("preset", "p2");
-                opts.set("cq", "30");
-                opts.set("b:v", "20M");
+        let tune = match config.latency_mode {
+            LatencyMode::Quality => NvencTune::HighQuality,
+            LatencyMode::LowLatency => NvencTune::LowLatency,
+            LatencyMode::UltraLowLatency => NvencTune::UltraLowLatency,
+        };
+        let tune = match config.quality {
+            QualityPreset::Lossless => NvencTune::Lossless,
+            _ => config.nvenc_tune.unwrap_or(tune),
+        };
+        opts.set("tune", tune.name());
+        if config.latency_mode != LatencyMode::Quality {
+            // Hand out every packet as soon as it is encoded
+            opts.set("delay", "0");
+        }
+        if codec == NvencCodec::H264 {
+            if let Some(profile) = config.h264_profile {
+                // NVENC's baseline is constrained
+                opts.set(
+                    "profile",
+                    match profile {
+                        H264Profile::ConstrainedBaseline => "baseline",
+                        H264Profile::Main => "main",
+                        H264Profile::High => "high",
+                    },
+                );
             }
-            QualityPreset::Medium => {
-                opts.set("preset", "p4");
-                opts.set("cq", "25");
-                opts.set("b:v", "40M");
+            if let Some(level) = config.h264_level {
+                opts.set("level", &level.to_string());
             }
-            QualityPreset::High => {
-                opts.set("preset", "p7");
-                opts.set("cq", "20");
-                opts.set("b:v", "80M");
+        }
+        let tuning = config.nvenc_tuning;
+        if tuning.lookahead > 0 {
+            opts.set("rc-lookahead", &tuning.lookahead.to_string());
+        }
+        if tuning.spatial_aq {
+            opts.set("spatial-aq", "1");
+            if let Some(strength) = tuning.aq_strength {
+                opts.set("aq-strength", &strength.to_string());
             }
-            QualityPreset::Ultra => {
-                opts.set("preset", "p7");
-                opts.set("cq", "15");
-                opts.set("b:v", "120M");
+        }
+        if tuning.temporal_aq {
+            opts.set("temporal-aq", "1");
+        }
+        if config.intra_refresh {
+            // The GOP becomes the refresh period, without IDR frames after the first
+            opts.set("intra-refresh", "1");
+        }
+        let (preset, cq, bitrate) = Self::preset_params(codec, &config.quality);
+        opts.set(
+            "preset",
+            config.nvenc_preset.map_or(preset, NvencPreset::name),
+        );
+        if tune == NvencTune::Lossless {
+            // Constant qp 0, set up by the tune
+            return Ok(opts);
+        }
+        let check_cq = |cq: u32| {
+            if cq > codec.max_cq() {
+                return Err(WaycapError::Init(format!(
+                    "{} takes a cq up to {}, not {cq}",
+                    codec.encoder_name(),
+                    codec.max_cq()
+                )));
+            }
+            Ok(cq.to_string())
+        };
+        match config.rate_control {
+            // The presets aim at a quality and only cap the bitrate
+            None => {
+                opts.set("cq", &check_cq(cq)?);
+                opts.set("rc", "vbr");
+                opts.set("b", bitrate);
+            }
+            // NVENC's VBR aiming at a cq is its constant quality mode, uncapped without a
+            // bitrate
+            Some(RateControl::Icq(quality)) => {
+                opts.set("cq", &check_cq(quality)?);
+                opts.set("rc", "vbr");
+                opts.set("b", "0");
+            }
+            Some(RateControl::Qvbr { quality, .. }) => {
+                opts.set("cq", &check_cq(quality)?);
+                opts.set("rc", "vbr");
             }
+            Some(RateControl::ConstantQp(qp)) => {
+                if qp > codec.max_qp() {
+                    return Err(WaycapError::Init(format!(
+                        "{} takes a qp up to {}, not {qp}",
+                        codec.encoder_name(),
+                        codec.max_qp()
+                    )));
+                }
+                opts.set("rc", "constqp");
+                opts.set("qp", &qp.to_string());
+            }
+            Some(RateControl::Vbr { .. }) => opts.set("rc", "vbr"),
+            Some(RateControl::Cbr { .. }) => opts.set("rc", "cbr"),
+        }
+        if let Some(rate_control) = config.rate_control {
+            set_bitrate_options(&mut opts, rate_control);
         }
-        opts
+        Ok(opts)
     }
 
     fn init_gl(&mut self, texture_id: Option<u32>) -> Result<()> {
@@ -533,6 +959,22 @@ impl NvencEncoder {
         Ok(())
     }
 
+    /// Recreate the texture the frames are copied into when the encoder was reopened at another
+    /// size, after a source switch or a new crop
+    fn fit_texture(&mut self) -> Result<()> {
+        let Some(ref encoder) = self.encoder else {
+            return Ok(());
+        };
+        let size = (encoder.width() as i32, encoder.height() as i32);
+        let egl_context = self.egl_context.as_mut().unwrap();
+        if egl_context.texture_size() == size {
+            return Ok(());
+        }
+        unsafe { cuGraphicsUnregisterResource(self.graphics_resource) };
+        egl_context.resize_texture(size.0, size.1);
+        self.init_gl(None)
+    }
+
     /// Set cuda  context to current thread
     fn make_current(&self) -> Result<()> {
         unsafe { cuCtxSetCurrent(self.cuda_ctx.as_raw()) };
@@ -564,3 +1006,124 @@ impl Drop for NvencEncoder {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::types::config::NvencTuning;
+
+    #[test]
+    fn only_a_refused_session_is_the_session_limit() {
+        assert!(refused_session(&[
+            "[h264_nvenc @ 0x5581] OpenEncodeSessionEx failed: out of memory (10): (no details)"
+                .to_string()
+        ]));
+        assert!(!refused_session(&[
+            "[h264_nvenc @ 0x5581] Failed locking bitstream buffer: out of memory (10)".to_string()
+        ]));
+        assert!(!refused_session(&[]));
+    }
+
+    #[test]
+    fn session_limit_follows_driver_and_gpu() {
+        let geforce = ["NVIDIA GeForce RTX 3080".to_string()];
+        assert_eq!(session_limit("550.54.14\n", &geforce), Some(8));
+        assert_eq!(session_limit("535.183.01", &geforce), Some(5));
+        assert_eq!(session_limit("525.147.05", &geforce), Some(3));
+        assert_eq!(
+            session_limit("550.54.14", &["NVIDIA RTX A4000".to_string()]),
+            None
+        );
+        assert_eq!(session_limit("", &geforce), None);
+    }
+
+    #[test]
+    fn tuning_becomes_options() {
+        let config = VideoConfig {
+            nvenc_tuning: NvencTuning {
+                lookahead: 20,
+                spatial_aq: true,
+                temporal_aq: true,
+                aq_strength: Some(12),
+            },
+            ..Default::default()
+        };
+        let opts = NvencEncoder::get_encoder_params(NvencCodec::Hevc, &config).unwrap();
+        assert_eq!(opts.get("rc-lookahead"), Some("20"));
+        assert_eq!(opts.get("spatial-aq"), Some("1"));
+        assert_eq!(opts.get("temporal-aq"), Some("1"));
+        assert_eq!(opts.get("aq-strength"), Some("12"));
+
+        let opts = NvencEncoder::get_encoder_params(NvencCodec::Hevc, &VideoConfig::default());
+        assert_eq!(opts.unwrap().get("rc-lookahead"), None);
+    }
+
+    #[test]
+    fn preset_and_tune_override_the_derived_ones() {
+        let streaming = VideoConfig {
+            nvenc_preset: Some(NvencPreset::P1),
+            nvenc_tune: Some(NvencTune::UltraLowLatency),
+            ..Default::default()
+        };
+        let opts = NvencEncoder::get_encoder_params(NvencCodec::H264, &streaming).unwrap();
+        assert_eq!(opts.get("preset"), Some("p1"));
+        assert_eq!(opts.get("tune"), Some("ull"));
+        // The quality still picks the cq
+        assert_eq!(opts.get("cq"), Some("25"));
+
+        let lossless = VideoConfig {
+            nvenc_tune: Some(NvencTune::Lossless),
+            ..Default::default()
+        };
+        lossless.validate().unwrap();
+        let opts = NvencEncoder::get_encoder_params(NvencCodec::Hevc, &lossless).unwrap();
+        assert_eq!(opts.get("tune"), Some("lossless"));
+        assert_eq!(opts.get("cq"), None);
+        let with_cq = VideoConfig {
+            rate_control: Some(RateControl::Icq(20)),
+            ..lossless
+        };
+        assert!(matches!(
+            with_cq.validate(),
+            Err(WaycapError::Validation(_))
+        ));
+        // The Lossless preset takes the tune as well
+        let preset = VideoConfig {
+            quality: QualityPreset::Lossless,
+            ..Default::default()
+        };
+        let opts = NvencEncoder::get_encoder_params(NvencCodec::Av1, &preset).unwrap();
+        assert_eq!(opts.get("tune"), Some("lossless"));
+        assert_eq!(opts.get("cq"), None);
+    }
+
+    #[test]
+    fn intra_refresh_excludes_keyframe_features() {
+        let config = VideoConfig {
+            intra_refresh: true,
+            ..Default::default()
+        };
+        config.validate().unwrap();
+        let opts = NvencEncoder::get_encoder_params(NvencCodec::H264, &config).unwrap();
+        assert_eq!(opts.get("intra-refresh"), Some("1"));
+
+        let scheduled = VideoConfig {
+            keyframe_interval: Some(Duration::from_secs(2)),
+            ..config.clone()
+        };
+        assert!(matches!(
+            scheduled.validate(),
+            Err(WaycapError::Validation(_))
+        ));
+        let repeated = VideoConfig {
+            repeat_headers: true,
+            ..config
+        };
+        assert!(matches!(
+            repeated.validate(),
+            Err(WaycapError::Validation(_))
+        ));
+    }
+}
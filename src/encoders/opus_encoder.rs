@@ -1,18 +1,93 @@
 use crossbeam::channel::{bounded, Receiver, Sender};
 use ffmpeg_next::{self as ffmpeg, Rational};
-use std::collections::VecDeque;
+use std::{collections::VecDeque, sync::Arc, time::Duration};
 
-use crate::types::audio_frame::EncodedAudioFrame;
+use crate::{
+    pipeline::overflow::{self, SendOutcome},
+    types::{
+        audio_frame::EncodedAudioFrame,
+        config::OverflowPolicy,
+        event::{CaptureEvent, EventSender},
+        stats::StatsCounters,
+        time::{CaptureTime, StreamPts},
+    },
+};
 
-use super::audio::{boost_with_rms, AudioEncoder};
+use super::audio::{boost_with_rms, AudioEncoder, AUDIO_TIME_BASE, SAMPLE_RATE};
+
+/// Two seconds of 20ms Opus packets, audio is small enough to buffer generously so packets are
+/// only dropped when the consumer is stuck
+const OUTPUT_CAPACITY: usize = 100;
 
 pub struct OpusEncoder {
     encoder: Option<ffmpeg::codec::encoder::Audio>,
     next_pts: i64,
     leftover_data: VecDeque<f32>,
-    encoded_samples_recv: Option<Receiver<EncodedAudioFrame>>,
-    encoded_samples_sender: Sender<EncodedAudioFrame>,
-    capture_timestamps: VecDeque<i64>,
+    output: PacketOutput,
+    capture_timestamps: VecDeque<CaptureTime>,
+    last_capture_timestamp: CaptureTime,
+}
+
+/// Where encoded packets go
+struct PacketOutput {
+    sender: Sender<EncodedAudioFrame>,
+    receiver: Receiver<EncodedAudioFrame>,
+    stats: Arc<StatsCounters>,
+    policy: OverflowPolicy,
+    events: Option<EventSender>,
+}
+
+impl PacketOutput {
+    /// Hand an encoded packet to the receiver. With `end_timestamp` set, packets captured after
+    /// it are dropped and the one crossing it is shortened to end there.
+    fn send(
+        &self,
+        packet: &ffmpeg::codec::packet::Packet,
+        timestamp: CaptureTime,
+        end_timestamp: Option<CaptureTime>,
+    ) {
+        let Some(data) = packet.data() else {
+            return;
+        };
+
+        let mut duration = StreamPts::new(packet.duration(), AUDIO_TIME_BASE);
+        if let Some(end) = end_timestamp {
+            if timestamp >= end {
+                return;
+            }
+            let remaining = StreamPts::from_duration(end - timestamp, AUDIO_TIME_BASE);
+            duration.value = duration.value.min(remaining.value);
+        }
+
+        let frame = EncodedAudioFrame {
+            data: data.to_vec(),
+            pts: StreamPts::new(packet.pts().unwrap_or(0), AUDIO_TIME_BASE),
+            duration,
+            timestamp,
+        };
+        let dropped = match overflow::send(self.policy, &self.sender, &self.receiver, frame) {
+            SendOutcome::Sent => {
+                self.stats.mark_audio_emitted(duration);
+                return;
+            }
+            SendOutcome::Replaced(oldest) => {
+                self.stats.mark_audio_emitted(duration);
+                oldest
+            }
+            SendOutcome::Dropped(frame) => frame,
+            SendOutcome::Disconnected => {
+                log::error!("Could not send encoded audio frame. Receiver disconnected");
+                return;
+            }
+        };
+
+        let gap = Duration::try_from(dropped.duration).unwrap_or_default();
+        log::warn!("Audio receiver is full, dropped {gap:?} of audio");
+        self.stats.mark_audio_dropped();
+        if let Some(events) = &self.events {
+            events.send(CaptureEvent::AudioFrameDropped { gap });
+        }
+    }
 }
 
 impl OpusEncoder {
@@ -24,13 +99,13 @@ impl OpusEncoder {
             .encoder()
             .audio()?;
 
-        encoder_ctx.set_rate(48000);
+        encoder_ctx.set_rate(SAMPLE_RATE as i32);
         encoder_ctx.set_bit_rate(70_000);
         encoder_ctx.set_format(ffmpeg::format::Sample::F32(
             ffmpeg_next::format::sample::Type::Packed,
         ));
-        encoder_ctx.set_time_base(Rational::new(1, 48000));
-        encoder_ctx.set_frame_rate(Some(Rational::new(1, 48000)));
+        encoder_ctx.set_time_base(AUDIO_TIME_BASE);
+        encoder_ctx.set_frame_rate(Some(Rational::new(1, SAMPLE_RATE as i32)));
         encoder_ctx.set_channel_layout(ffmpeg::channel_layout::ChannelLayout::STEREO);
 
         let mut encoder = encoder_ctx.open()?;
@@ -52,14 +127,20 @@ impl AudioEncoder for OpusEncoder {
     {
         let encoder = Self::create_encoder()?;
         let (frame_tx, frame_rx): (Sender<EncodedAudioFrame>, Receiver<EncodedAudioFrame>) =
-            bounded(10);
+            bounded(OUTPUT_CAPACITY);
         Ok(Self {
             encoder: Some(encoder),
             next_pts: 0,
             leftover_data: VecDeque::with_capacity(10),
-            encoded_samples_recv: Some(frame_rx),
-            encoded_samples_sender: frame_tx,
+            output: PacketOutput {
+                sender: frame_tx,
+                receiver: frame_rx,
+                stats: Arc::default(),
+                policy: OverflowPolicy::default(),
+                events: None,
+            },
             capture_timestamps: VecDeque::with_capacity(10),
+            last_capture_timestamp: CaptureTime::default(),
         })
     }
 
@@ -83,6 +164,7 @@ impl AudioEncoder for OpusEncoder {
             // it's still audible in playback
             boost_with_rms(&mut raw_frame.samples)?;
             self.leftover_data.extend(raw_frame.samples);
+            self.last_capture_timestamp = raw_frame.timestamp;
 
             // Send chunked frames to encoder
             while self.leftover_data.len() >= frame_size {
@@ -104,24 +186,11 @@ impl AudioEncoder for OpusEncoder {
                 // Try and get a frame back from encoder
                 let mut packet = ffmpeg::codec::packet::Packet::empty();
                 if encoder.receive_packet(&mut packet).is_ok() {
-                    if let Some(data) = packet.data() {
-                        let pts = packet.pts().unwrap_or(0);
-                        match self.encoded_samples_sender.try_send(EncodedAudioFrame {
-                            data: data.to_vec(),
-                            pts,
-                            timestamp: self.capture_timestamps.pop_front().unwrap_or(0),
-                        }) {
-                            Ok(_) => {}
-                            Err(crossbeam::channel::TrySendError::Full(_)) => {
-                                log::error!("Could not send encoded audio frame. Receiver is full");
-                            }
-                            Err(crossbeam::channel::TrySendError::Disconnected(_)) => {
-                                log::error!(
-                                    "Could not send encoded audio frame. Receiver disconnected"
-                                );
-                            }
-                        }
-                    }
+                    self.output.send(
+                        &packet,
+                        self.capture_timestamps.pop_front().unwrap_or_default(),
+                        None,
+                    );
                 }
 
                 self.next_pts += frame_size as i64;
@@ -135,16 +204,61 @@ impl AudioEncoder for OpusEncoder {
         &self.encoder
     }
 
-    fn drain(&mut self) -> crate::types::error::Result<()> {
+    fn drain(&mut self, end_timestamp: Option<CaptureTime>) -> crate::types::error::Result<()> {
+        let Some(end_timestamp) = end_timestamp else {
+            // Flushed packets would overflow receivers nobody reads anymore
+            if let Some(ref mut encoder) = self.encoder {
+                encoder.send_eof()?;
+                let mut packet = ffmpeg::codec::packet::Packet::empty();
+                while encoder.receive_packet(&mut packet).is_ok() {} // Discard frames
+            }
+            self.leftover_data.clear();
+            self.capture_timestamps.clear();
+            return Ok(());
+        };
+
         if let Some(ref mut encoder) = self.encoder {
+            // Encode the samples short of a full frame too so the tail isn't lost
+            if !self.leftover_data.is_empty() {
+                let frame_samples: Vec<f32> = self.leftover_data.drain(..).collect();
+                let mut frame = ffmpeg::frame::Audio::new(
+                    encoder.format(),
+                    frame_samples.len(),
+                    encoder.channel_layout(),
+                );
+                frame.plane_mut(0).copy_from_slice(&frame_samples);
+                frame.set_pts(Some(self.next_pts));
+                frame.set_rate(encoder.rate());
+
+                self.capture_timestamps
+                    .push_back(self.last_capture_timestamp);
+                encoder.send_frame(&frame)?;
+                self.next_pts += frame_samples.len() as i64;
+            }
+
             encoder.send_eof()?;
             let mut packet = ffmpeg::codec::packet::Packet::empty();
-            while encoder.receive_packet(&mut packet).is_ok() {} // Discard frames
+            while encoder.receive_packet(&mut packet).is_ok() {
+                self.output.send(
+                    &packet,
+                    self.capture_timestamps.pop_front().unwrap_or_default(),
+                    Some(end_timestamp),
+                );
+            }
         }
 
         Ok(())
     }
 
+    fn set_stats(&mut self, stats: Arc<StatsCounters>) {
+        self.output.stats = stats;
+    }
+
+    fn set_overflow_policy(&mut self, policy: OverflowPolicy, events: EventSender) {
+        self.output.policy = policy;
+        self.output.events = Some(events);
+    }
+
     fn drop_encoder(&mut self) {
         self.encoder.take();
     }
@@ -152,12 +266,55 @@ impl AudioEncoder for OpusEncoder {
     fn reset(&mut self) -> crate::types::error::Result<()> {
         self.drop_encoder();
         self.capture_timestamps.clear();
+        self.leftover_data.clear();
         self.encoder = Some(Self::create_encoder()?);
 
         Ok(())
     }
 
     fn get_encoded_recv(&mut self) -> Option<Receiver<EncodedAudioFrame>> {
-        self.encoded_samples_recv.clone()
+        Some(self.output.receiver.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::types::audio_frame::RawAudioFrame;
+
+    use super::*;
+
+    /// An encoder fed 50ms of stereo audio, a few packets of which are still buffered
+    fn fed_encoder() -> OpusEncoder {
+        let mut encoder = OpusEncoder::new().unwrap();
+        let samples = (0..4800).map(|i| (i as f32 / 20.0).sin() * 0.5).collect();
+        encoder
+            .process(RawAudioFrame {
+                samples,
+                timestamp: CaptureTime::from_nanos(1_000_000_000),
+            })
+            .unwrap();
+        encoder
+    }
+
+    #[test]
+    fn drain_discards_the_flushed_packets_without_an_end() {
+        let mut encoder = fed_encoder();
+        let receiver = encoder.get_encoded_recv().unwrap();
+        let delivered = receiver.len();
+
+        encoder.drain(None).unwrap();
+        assert_eq!(receiver.len(), delivered);
+    }
+
+    #[test]
+    fn drain_delivers_the_flushed_packets_up_to_the_end() {
+        let mut encoder = fed_encoder();
+        let receiver = encoder.get_encoded_recv().unwrap();
+        let delivered = receiver.len();
+
+        encoder
+            .drain(Some(CaptureTime::from_nanos(2_000_000_000)))
+            .unwrap();
+        assert!(receiver.len() > delivered);
     }
 }
@@ -0,0 +1,235 @@
+//! Images blended onto the encoded frames, see [`crate::types::config::VideoConfig::overlay`].
+//!
+//! The image is read once when the encoder is created or the overlay changes. The VAAPI and QSV
+//! filter graphs upload it to a surface of their own through a buffer source which is ended right
+//! after its single frame, `overlay_vaapi` then repeats that frame over every captured one.
+
+use ffmpeg::ffi::av_buffer_ref;
+use ffmpeg_next::{self as ffmpeg};
+
+use crate::ffmpeg_compat;
+use crate::types::config::{Corner, OverlayConfig, OverlayImage, OverlayPosition, Rect};
+use crate::types::error::{Result, WaycapError};
+
+/// Name of the blend filter, the image enters the filter graphs through `overlay_in`
+const NAME: &str = "overlay";
+
+#[derive(Debug)]
+pub(crate) struct Overlay {
+    pub image: image::RgbaImage,
+    pub position: OverlayPosition,
+    pub opacity: f32,
+}
+
+impl Overlay {
+    /// Read the image of `config`
+    pub fn load(config: &OverlayConfig) -> Result<Self> {
+        let image = match config.image {
+            OverlayImage::Path(ref path) => image::open(path)
+                .map_err(|e| {
+                    WaycapError::Config(format!(
+                        "Could not read the overlay {}: {e}",
+                        path.display()
+                    ))
+                })?
+                .into_rgba8(),
+            OverlayImage::Rgba {
+                width,
+                height,
+                ref data,
+            } => image::RgbaImage::from_raw(width, height, data.clone()).ok_or_else(|| {
+                WaycapError::Validation(format!("The overlay is no {width}x{height} RGBA image"))
+            })?,
+        };
+        Ok(Self {
+            image,
+            position: config.position,
+            opacity: config.opacity,
+        })
+    }
+
+    /// Region of the `output` sized encoded frames the image covers, see [`placement`]
+    pub fn placement(&self, output: (u32, u32)) -> Result<Rect> {
+        placement(self.position, self.image.dimensions(), output)
+    }
+
+    /// The image in an RGBA frame, see [`rgba_frame`]
+    pub fn frame(&self, premultiplied: bool) -> ffmpeg::util::frame::Video {
+        rgba_frame(&self.image, self.opacity, premultiplied)
+    }
+
+    /// `overlay_vaapi` filter blending the image onto the frames of a VAAPI filter graph at
+    /// `placement`, see [`add_vaapi_blend`]
+    pub fn add_vaapi_filter(
+        &self,
+        graph: &mut ffmpeg::filter::Graph,
+        encoder: &ffmpeg::codec::encoder::Video,
+        placement: Rect,
+    ) -> Result<ffmpeg::filter::Context> {
+        // The opacity is in the uploaded alpha already
+        add_vaapi_blend(graph, encoder, NAME, self.image.dimensions(), placement)
+    }
+
+    /// Feed the image to the validated graph of [`Self::add_vaapi_filter`] and end its input
+    pub fn push(&self, graph: &mut ffmpeg::filter::Graph) -> Result<()> {
+        let mut source = graph
+            .get(&source_name(NAME))
+            .ok_or_else(|| WaycapError::Init("The filter graph has no overlay".to_string()))?;
+        source.source().add(&self.frame(true))?;
+        source.source().flush()?;
+        Ok(())
+    }
+}
+
+/// Region of `width`x`height` encoded frames an `image_width`x`image_height` image at
+/// `position` covers. It starts on even pixels so it lines up with the chroma of 4:2:0 frames.
+pub(crate) fn placement(
+    position: OverlayPosition,
+    (image_width, image_height): (u32, u32),
+    (width, height): (u32, u32),
+) -> Result<Rect> {
+    let (x, y) = match position {
+        OverlayPosition::Pixels { x, y } => (x, y),
+        OverlayPosition::Anchored { corner, margin } => {
+            let right = width.saturating_sub(image_width.saturating_add(margin));
+            let bottom = height.saturating_sub(image_height.saturating_add(margin));
+            match corner {
+                Corner::TopLeft => (margin, margin),
+                Corner::TopRight => (right, margin),
+                Corner::BottomLeft => (margin, bottom),
+                Corner::BottomRight => (right, bottom),
+            }
+        }
+    };
+    let placement = Rect {
+        x: x & !1,
+        y: y & !1,
+        width: image_width,
+        height: image_height,
+    };
+    if u64::from(placement.x) + u64::from(image_width) > u64::from(width)
+        || u64::from(placement.y) + u64::from(image_height) > u64::from(height)
+    {
+        return Err(WaycapError::Validation(format!(
+            "The {image_width}x{image_height} overlay at {},{} doesn't fit into the \
+             {width}x{height} frames",
+            placement.x, placement.y
+        )));
+    }
+    Ok(placement)
+}
+
+/// `image` in an RGBA frame, its alpha times `opacity`. `overlay_vaapi` blends `premultiplied`
+/// colors, swscale converts straight ones.
+pub(crate) fn rgba_frame(
+    image: &image::RgbaImage,
+    opacity: f32,
+    premultiplied: bool,
+) -> ffmpeg::util::frame::Video {
+    let (width, height) = image.dimensions();
+    let mut frame = ffmpeg::util::frame::Video::new(ffmpeg::format::Pixel::RGBA, width, height);
+    let stride = frame.stride(0);
+    let row = width as usize * 4;
+    for (y, pixels) in image.as_raw().chunks_exact(row).enumerate() {
+        let target = &mut frame.data_mut(0)[y * stride..][..row];
+        for (target, pixel) in target.chunks_exact_mut(4).zip(pixels.chunks_exact(4)) {
+            let alpha = f32::from(pixel[3]) * opacity;
+            for (target, &channel) in target[..3].iter_mut().zip(&pixel[..3]) {
+                *target = if premultiplied {
+                    (f32::from(channel) * alpha / 255.0).round() as u8
+                } else {
+                    channel
+                };
+            }
+            target[3] = alpha.round() as u8;
+        }
+    }
+    frame.set_pts(Some(0));
+    frame
+}
+
+/// `overlay_vaapi` filter called `name` blending `width`x`height` RGBA frames onto the frames of
+/// a VAAPI filter graph at `placement`. The RGBA frames enter through the buffer source
+/// `{name}_in` and are uploaded into its second input, the frames go into its first input.
+pub(crate) fn add_vaapi_blend(
+    graph: &mut ffmpeg::filter::Graph,
+    encoder: &ffmpeg::codec::encoder::Video,
+    name: &str,
+    (width, height): (u32, u32),
+    placement: Rect,
+) -> Result<ffmpeg::filter::Context> {
+    let mut source = graph.add(
+        &ffmpeg_compat::find_filter("buffer")?,
+        &source_name(name),
+        &format!("video_size={width}x{height}:pix_fmt=rgba:time_base=1/1000000"),
+    )?;
+    let mut upload = graph.add(
+        &ffmpeg_compat::find_filter("hwupload")?,
+        &format!("{name}_upload"),
+        "derive_device=vaapi",
+    )?;
+    unsafe {
+        (*upload.as_mut_ptr()).hw_device_ctx = av_buffer_ref((*encoder.as_ptr()).hw_device_ctx);
+    }
+    let mut overlay = graph.add(
+        &ffmpeg_compat::find_filter("overlay_vaapi")?,
+        name,
+        &format!("x={}:y={}", placement.x, placement.y),
+    )?;
+    source.link(0, &mut upload, 0);
+    upload.link(0, &mut overlay, 1);
+    Ok(overlay)
+}
+
+/// Buffer source of the [`add_vaapi_blend`] filter called `name`
+pub(crate) fn source_name(name: &str) -> String {
+    format!("{name}_in")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn overlay(position: OverlayPosition) -> Overlay {
+        Overlay::load(&OverlayConfig {
+            image: OverlayImage::Rgba {
+                width: 33,
+                height: 20,
+                data: vec![255; 33 * 20 * 4],
+            },
+            position,
+            opacity: 1.0,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn anchored_overlays_keep_their_margin() {
+        let corners = [
+            (Corner::TopLeft, (10, 10)),
+            (Corner::TopRight, (1236, 10)),
+            (Corner::BottomLeft, (10, 688)),
+            (Corner::BottomRight, (1236, 688)),
+        ];
+        for (corner, (x, y)) in corners {
+            let overlay = overlay(OverlayPosition::Anchored { corner, margin: 11 });
+            let placement = overlay.placement((1280, 720)).unwrap();
+            // The margin of 11 and 720 - 20 - 11 = 689 are put on even pixels
+            assert_eq!((placement.x, placement.y), (x, y), "{corner:?}");
+            assert_eq!((placement.width, placement.height), (33, 20));
+        }
+    }
+
+    #[test]
+    fn overlays_have_to_fit() {
+        let overlay = overlay(OverlayPosition::Pixels { x: 1248, y: 0 });
+        assert!(overlay.placement((1280, 720)).is_err());
+        assert_eq!(overlay.placement((1282, 720)).unwrap().x, 1248);
+        // Anchored ones too, even when the frames are smaller than the image
+        let overlay = overlay(OverlayPosition::Anchored {
+            corner: Corner::BottomRight,
+            margin: 0,
+        });
+        assert!(overlay.placement((32, 720)).is_err());
+    }
+}
@@ -0,0 +1,197 @@
+use std::sync::Arc;
+
+use crossbeam::channel::Receiver;
+use ffmpeg_next::{
+    self as ffmpeg,
+    ffi::{av_freep, av_mallocz},
+};
+
+use crate::{
+    encoders::{
+        h264::{nal_type, nal_units, NAL_IDR, NAL_PPS, NAL_SPS},
+        video::{ProcessingThread, VideoEncoder, OUTPUT_CAPACITY},
+    },
+    pipeline::fanout::{Delivery, FanOut},
+    types::{
+        encoder_info::EncoderInfo,
+        error::{Result, WaycapError},
+        event::PipelineStage,
+        stats::StatsCounters,
+        time::{CaptureTime, StreamPts},
+        video_frame::{EncodedVideoFrame, RawVideoFrame},
+    },
+};
+
+/// `AV_INPUT_BUFFER_PADDING_SIZE`, zeroed bytes ffmpeg's bitstream readers may read past the end
+const EXTRADATA_PADDING: usize = 64;
+
+/// "Encoder" for streams the compositor already encoded to H.264, see
+/// [`crate::types::config::VideoEncoder::H264Passthrough`].
+///
+/// The access units are handed on as they arrive, only the keyframe flags are read from the
+/// bitstream. The codec parameters for muxers, see [`crate::Capture::with_video_encoder`], are
+/// made up from the negotiated size and the stream's SPS and PPS, which are only known once the
+/// first keyframe arrived.
+pub struct PassthroughEncoder {
+    /// Never opened, only describes the stream
+    context: Option<ffmpeg::codec::encoder::Video>,
+    /// SPS and PPS of the stream in Annex B, as written to the context's extradata
+    parameter_sets: Vec<u8>,
+    /// Whether a keyframe was passed on since the start or the last source change. Frames
+    /// before it reference pictures the receivers never got.
+    synced: bool,
+    output: FanOut<EncodedVideoFrame>,
+    stats: Arc<StatsCounters>,
+}
+
+impl PassthroughEncoder {
+    pub(crate) fn new(width: u32, height: u32) -> Result<Self> {
+        let codec = ffmpeg::decoder::find(ffmpeg::codec::Id::H264).ok_or(WaycapError::Init(
+            "The linked ffmpeg does not know H.264, which passthrough describes streams with"
+                .to_string(),
+        ))?;
+        let mut context = ffmpeg::codec::Context::new_with_codec(codec)
+            .encoder()
+            .video()?;
+        context.set_width(width);
+        context.set_height(height);
+        context.set_time_base(CaptureTime::TIME_BASE);
+        Ok(Self {
+            context: Some(context),
+            parameter_sets: Vec::new(),
+            synced: false,
+            output: FanOut::new(OUTPUT_CAPACITY),
+            stats: Arc::default(),
+        })
+    }
+
+    pub(crate) fn set_stats(&mut self, stats: Arc<StatsCounters>) {
+        self.stats = stats;
+    }
+
+    /// Output queues, for watching their depth without subscribing
+    pub(crate) fn output_queues(&self) -> FanOut<EncodedVideoFrame> {
+        self.output.clone()
+    }
+
+    /// Describe the stream with new parameter sets
+    fn set_parameter_sets(&mut self, sps: &[u8], pps: &[u8]) {
+        let mut parameter_sets = Vec::with_capacity(sps.len() + pps.len() + 8);
+        for unit in [sps, pps] {
+            parameter_sets.extend_from_slice(&[0, 0, 0, 1]);
+            parameter_sets.extend_from_slice(unit);
+        }
+        if parameter_sets == self.parameter_sets {
+            return;
+        }
+        let Some(ref mut context) = self.context else {
+            return;
+        };
+        unsafe {
+            let context = context.as_mut_ptr();
+            av_freep(&mut (*context).extradata as *mut *mut u8 as *mut _);
+            let extradata = av_mallocz(parameter_sets.len() + EXTRADATA_PADDING) as *mut u8;
+            if extradata.is_null() {
+                (*context).extradata_size = 0;
+                return;
+            }
+            std::ptr::copy_nonoverlapping(parameter_sets.as_ptr(), extradata, parameter_sets.len());
+            (*context).extradata = extradata;
+            (*context).extradata_size = parameter_sets.len() as i32;
+            // profile_idc and level_idc are plain bytes at the start of the SPS
+            if let [_, profile, _, level, ..] = sps {
+                (*context).profile = *profile as i32;
+                (*context).level = *level as i32;
+            }
+        }
+        self.parameter_sets = parameter_sets;
+    }
+}
+
+impl ProcessingThread for PassthroughEncoder {
+    fn process(&mut self, frame: RawVideoFrame) -> Result<()> {
+        let mut is_keyframe = false;
+        let (mut sps, mut pps) = (None, None);
+        for unit in nal_units(&frame.data) {
+            match nal_type(unit) {
+                NAL_IDR => is_keyframe = true,
+                NAL_SPS => sps = Some(unit),
+                NAL_PPS => pps = Some(unit),
+                _ => {}
+            }
+        }
+        if let (Some(sps), Some(pps)) = (sps, pps) {
+            self.set_parameter_sets(sps, pps);
+        }
+        self.synced |= is_keyframe;
+        if !self.synced {
+            self.stats.mark_encoder_dropped();
+            return Ok(());
+        }
+
+        // Compositor encoders don't reorder frames, so decode order is presentation order
+        let pts = StreamPts::new(frame.timestamp.as_nanos(), CaptureTime::TIME_BASE);
+        match self.output.send(EncodedVideoFrame {
+            data: frame.data,
+            is_keyframe,
+            pts,
+            dts: pts,
+        }) {
+            Delivery::Delivered => self.stats.mark_packet_emitted(pts.into()),
+            Delivery::Full => {
+                self.stats.mark_frame_dropped();
+                log::error!("Could not send encoded video frame. Receiver is full");
+                self.stats
+                    .record_error(PipelineStage::Consumer, "Encoded receiver full");
+            }
+            // Handled once by the processing loop
+            Delivery::NoSubscribers => {}
+        }
+        Ok(())
+    }
+}
+
+impl VideoEncoder for PassthroughEncoder {
+    type Output = EncodedVideoFrame;
+
+    /// There is no encoder to restart, the output waits for the compositor's next keyframe
+    fn reset(&mut self) -> Result<()> {
+        self.synced = false;
+        Ok(())
+    }
+
+    fn source_changed(&mut self, width: u32, height: u32) -> Result<()> {
+        if let Some(ref mut context) = self.context {
+            context.set_width(width);
+            context.set_height(height);
+        }
+        self.reset()
+    }
+
+    fn output(&mut self) -> Option<Receiver<EncodedVideoFrame>> {
+        Some(self.output.subscribe())
+    }
+
+    fn has_consumers(&self) -> bool {
+        self.output.has_subscribers()
+    }
+
+    fn drop_processor(&mut self) {}
+
+    /// Nothing is held back, every access unit is passed on as it arrives
+    fn drain(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn get_encoder(&self) -> &Option<ffmpeg::codec::encoder::Video> {
+        &self.context
+    }
+
+    fn info(&self) -> Option<EncoderInfo> {
+        Some(EncoderInfo {
+            name: "h264_passthrough".to_string(),
+            rejected_options: Vec::new(),
+            ..Default::default()
+        })
+    }
+}
@@ -0,0 +1,59 @@
+//! The second source blended onto the encoded frames, see
+//! [`crate::types::config::VideoConfig::pip`].
+//!
+//! Unlike the image of an overlay, the source's frames keep coming: each captured frame is
+//! preceded by the source frame nearest to it, at the same pts, so `overlay_vaapi` pairs the two
+//! right away instead of waiting for the source.
+
+use ffmpeg_next::{self as ffmpeg};
+
+use super::overlay;
+use crate::{
+    capture::pip::PipFrames,
+    types::{
+        config::PipConfig,
+        error::{Result, WaycapError},
+        time::CaptureTime,
+    },
+};
+
+/// Name of the blend filter, the source enters the filter graphs through `pip_in`
+const NAME: &str = "pip";
+
+/// `overlay_vaapi` filter blending the source of `config` onto the `output` sized frames of a
+/// VAAPI filter graph. The frames go into its first input.
+pub(crate) fn add_vaapi_filter(
+    config: &PipConfig,
+    graph: &mut ffmpeg::filter::Graph,
+    encoder: &ffmpeg::codec::encoder::Video,
+    output: (u32, u32),
+) -> Result<ffmpeg::filter::Context> {
+    let size = (config.width, config.height);
+    let placement = overlay::placement(config.position, size, output)?;
+    overlay::add_vaapi_blend(graph, encoder, NAME, size, placement)
+}
+
+/// Feed the frame of `frames` nearest to `timestamp` to the graph of [`add_vaapi_filter`], ahead
+/// of the captured frame of `timestamp`. Transparent until the source sent its first frame, or
+/// without `frames`.
+pub(crate) fn push(
+    graph: &mut ffmpeg::filter::Graph,
+    config: &PipConfig,
+    frames: Option<&PipFrames>,
+    timestamp: CaptureTime,
+) -> Result<()> {
+    let mut frame = match frames.and_then(|frames| frames.nearest(timestamp)) {
+        Some(image) => overlay::rgba_frame(&image, 1.0, true),
+        None => overlay::rgba_frame(
+            &image::RgbaImage::new(config.width, config.height),
+            1.0,
+            true,
+        ),
+    };
+    frame.set_pts(Some(timestamp.as_nanos()));
+    let mut source = graph.get(&overlay::source_name(NAME)).ok_or_else(|| {
+        WaycapError::Init("The filter graph has no picture-in-picture".to_string())
+    })?;
+    source.source().add(&frame)?;
+    Ok(())
+}
@@ -0,0 +1,585 @@
+use std::sync::Arc;
+
+use crate::{
+    capture::pip::PipFrames,
+    encoders::video::{PipewireSPA, ProcessingThread, VideoEncoder, OUTPUT_CAPACITY},
+    failure_injection, ffmpeg_compat,
+    pipeline::fanout::{Delivery, FanOut},
+    types::{
+        config::{DenoiseStrength, OverlayConfig, QualityPreset, Rect, VideoConfig},
+        encoder_info::EncoderInfo,
+        error::{Result, WaycapError},
+        event::PipelineStage,
+        stats::StatsCounters,
+        time::{CaptureTime, StreamPts},
+        video_frame::{EncodedVideoFrame, RawVideoFrame},
+    },
+};
+use crossbeam::channel::Receiver;
+use ffmpeg_next::{
+    self as ffmpeg,
+    ffi::{
+        av_buffer_ref, av_hwframe_ctx_init, AVHWDeviceType, AVHWFramesContext, AVPixelFormat,
+        FF_QP2LAMBDA,
+    },
+};
+use pipewire::{self as pw, spa::param::video::VideoFormat};
+
+use super::{
+    hdr::attach_hdr_side_data,
+    overlay::Overlay,
+    pip,
+    vaapi_encoder::{
+        default_input_format, drm_prime_frame, graph_input_format, has_closed_dmabufs,
+        scale_format, VaapiEncoder,
+    },
+    video::{
+        add_crop_filter, add_denoise_filter, add_pad_filter, add_transpose_filter,
+        changed_frame_transform, create_hw_device, create_hw_frame_ctx, derive_hw_device,
+        ffmpeg_color_range, link_filters_with_custom, lossless_unsupported,
+        open_configured_encoder, resend_frame, scale_color_args, set_colorimetry, validate_graph,
+        with_denoise_fallback, GOP_SIZE, SCHEDULED_GOP_SIZE,
+    },
+};
+
+/// Encoder which encodes frames using Intel Quick Sync Video.
+///
+/// The QSV device is derived from a VAAPI one, so the DMA-BUFs are imported and converted
+/// through VAAPI like in [`VaapiEncoder`] and only then mapped to QSV surfaces for the encoder.
+pub struct QsvEncoder {
+    encoder: Option<ffmpeg::codec::encoder::Video>,
+    width: u32,
+    height: u32,
+    config: VideoConfig,
+    rejected_options: Vec<String>,
+    output: FanOut<EncodedVideoFrame>,
+    stats: Arc<StatsCounters>,
+    filter_graph: Option<ffmpeg::filter::Graph>,
+    /// The last filtered frame, kept with [`VideoConfig::cfr`] or
+    /// [`VideoConfig::timelapse`] to repeat it
+    last_frame: Option<ffmpeg::util::frame::Video>,
+    /// Frames of the [`VideoConfig::pip`] source, a transparent frame is blended in without
+    pip_frames: Option<Arc<PipFrames>>,
+    /// Format of the frames the filter graph was built for
+    input_format: VideoFormat,
+    /// [`VideoConfig::transform`] was left to the compositor, the config holds the one of the
+    /// last frame then
+    follows_compositor_transform: bool,
+}
+
+impl ProcessingThread for QsvEncoder {
+    fn process(&mut self, frame: RawVideoFrame) -> Result<()> {
+        if let Some(transform) =
+            changed_frame_transform(&self.config, self.follows_compositor_transform, &frame)
+        {
+            self.config.transform = Some(transform);
+            self.drain()?;
+            self.reset()?;
+        }
+        if frame.dmabuf_fd.is_some() {
+            self.set_input_format(frame.format)?;
+        }
+        if let Some(ref mut encoder) = self.encoder {
+            if frame.dmabuf_fd.is_some() && !has_closed_dmabufs(&frame, &self.stats) {
+                let mut drm_frame = drm_prime_frame(&frame, (self.width, self.height), encoder)?;
+                drm_frame.set_pts(Some(frame.timestamp.as_nanos()));
+                if let Some(ref pip) = self.config.pip {
+                    pip::push(
+                        self.filter_graph.as_mut().unwrap(),
+                        pip,
+                        self.pip_frames.as_deref(),
+                        frame.timestamp,
+                    )?;
+                }
+                self.filter_graph
+                    .as_mut()
+                    .unwrap()
+                    .get("in")
+                    .unwrap()
+                    .source()
+                    .add(&drm_frame)
+                    .unwrap();
+
+                let mut filtered = ffmpeg::util::frame::Video::empty();
+                if self
+                    .filter_graph
+                    .as_mut()
+                    .unwrap()
+                    .get("out")
+                    .unwrap()
+                    .sink()
+                    .frame(&mut filtered)
+                    .is_ok()
+                {
+                    if let Some(ref hdr) = self.config.hdr_metadata {
+                        attach_hdr_side_data(&mut filtered, hdr);
+                    }
+                    if frame.force_keyframe {
+                        filtered.set_kind(ffmpeg::picture::Type::I);
+                    }
+                    failure_injection::send_frame(encoder, &filtered)?;
+                    if self.config.repeats_frames() {
+                        self.last_frame = Some(filtered);
+                    }
+                }
+            }
+        }
+        self.emit_packets();
+        Ok(())
+    }
+
+    fn repeat_frame(&mut self, timestamp: CaptureTime, force_keyframe: bool) -> Result<bool> {
+        let (Some(encoder), Some(frame)) = (self.encoder.as_mut(), self.last_frame.as_mut()) else {
+            return Ok(false);
+        };
+        resend_frame(encoder, frame, timestamp, force_keyframe)?;
+        self.emit_packets();
+        Ok(true)
+    }
+}
+
+impl VideoEncoder for QsvEncoder {
+    type Output = EncodedVideoFrame;
+    fn reset(&mut self) -> Result<()> {
+        self.drop_processor();
+        let (width, height) = self.config.encoded_size(self.width, self.height)?;
+        let (new_encoder, rejected_options) = Self::create_encoder(width, height, &self.config)?;
+
+        let new_filter_graph = Self::create_filter_graph(
+            &new_encoder,
+            self.width,
+            self.height,
+            &self.config,
+            self.input_format,
+        )?;
+
+        self.encoder = Some(new_encoder);
+        self.rejected_options = rejected_options;
+        self.filter_graph = Some(new_filter_graph);
+        Ok(())
+    }
+
+    fn source_changed(&mut self, width: u32, height: u32) -> Result<()> {
+        // Fails before draining when the crop lies outside the new source
+        self.config.encoded_size(width, height)?;
+        self.drain()?;
+        self.width = width;
+        self.height = height;
+        self.reset()
+    }
+
+    fn drop_processor(&mut self) {
+        self.encoder.take();
+        self.filter_graph.take();
+        self.last_frame.take();
+    }
+
+    fn output(&mut self) -> Option<Receiver<EncodedVideoFrame>> {
+        Some(self.output.subscribe())
+    }
+
+    fn has_consumers(&self) -> bool {
+        self.output.has_subscribers()
+    }
+
+    fn change_quality(&mut self, quality: QualityPreset) -> Result<()> {
+        self.config.set_quality(quality)?;
+        self.drain()?;
+        self.reset()
+    }
+
+    /// Drain the filter graph and encoder of any remaining frames it is processing
+    fn drain(&mut self) -> Result<()> {
+        if let Some(ref mut encoder) = self.encoder {
+            let mut filtered = ffmpeg::util::frame::Video::empty();
+            while self
+                .filter_graph
+                .as_mut()
+                .unwrap()
+                .get("out")
+                .unwrap()
+                .sink()
+                .frame(&mut filtered)
+                .is_ok()
+            {
+                failure_injection::send_frame(encoder, &filtered)?;
+            }
+
+            encoder.send_eof()?;
+            let mut packet = ffmpeg::codec::packet::Packet::empty();
+            while encoder.receive_packet(&mut packet).is_ok() {} // Discard these frames
+        }
+        Ok(())
+    }
+
+    /// Moving the crop only rebuilds the filter graph, a new size reopens the encoder
+    fn set_crop(&mut self, crop: Option<Rect>) -> Result<()> {
+        let mut config = self.config.clone();
+        config.set_crop(crop)?;
+        let size = config.encoded_size(self.width, self.height)?;
+        match self.encoder {
+            Some(ref encoder) if (encoder.width(), encoder.height()) == size => {
+                self.filter_graph = Some(Self::create_filter_graph(
+                    encoder,
+                    self.width,
+                    self.height,
+                    &config,
+                    self.input_format,
+                )?);
+                self.config = config;
+                Ok(())
+            }
+            _ => {
+                self.config = config;
+                self.drain()?;
+                self.reset()
+            }
+        }
+    }
+
+    /// A new overlay only rebuilds the filter graph
+    fn set_overlay(&mut self, overlay: Option<OverlayConfig>) -> Result<()> {
+        let mut config = self.config.clone();
+        config.set_overlay(overlay)?;
+        if let Some(ref encoder) = self.encoder {
+            self.filter_graph = Some(Self::create_filter_graph(
+                encoder,
+                self.width,
+                self.height,
+                &config,
+                self.input_format,
+            )?);
+        }
+        self.config = config;
+        Ok(())
+    }
+
+    fn get_encoder(&self) -> &Option<ffmpeg::codec::encoder::Video> {
+        &self.encoder
+    }
+
+    fn info(&self) -> Option<EncoderInfo> {
+        Some(EncoderInfo {
+            name: "h264_qsv".to_string(),
+            rejected_options: self.rejected_options.clone(),
+            ..Default::default()
+        })
+    }
+}
+
+impl PipewireSPA for QsvEncoder {
+    /// The frames are imported through VAAPI, so it takes the same buffers
+    fn get_spa_definition() -> Result<pw::spa::pod::Object> {
+        VaapiEncoder::get_spa_definition()
+    }
+}
+
+impl QsvEncoder {
+    pub(crate) fn set_stats(&mut self, stats: Arc<StatsCounters>) {
+        self.stats = stats;
+    }
+
+    /// Blend the frames of `pip_frames` in for [`VideoConfig::pip`]
+    pub(crate) fn set_pip_frames(&mut self, pip_frames: Arc<PipFrames>) {
+        self.pip_frames = Some(pip_frames);
+    }
+
+    /// Output queues, for watching their depth without subscribing
+    pub(crate) fn output_queues(&self) -> FanOut<EncodedVideoFrame> {
+        self.output.clone()
+    }
+
+    /// Take frames in `format`, which the compositor negotiated, rebuilding the filter graph if
+    /// it was built for another one
+    pub(crate) fn set_input_format(&mut self, format: VideoFormat) -> Result<()> {
+        if format == self.input_format {
+            return Ok(());
+        }
+        log::debug!(
+            "Taking {format:?} frames instead of {:?}",
+            self.input_format
+        );
+        if let Some(ref encoder) = self.encoder {
+            self.filter_graph = Some(Self::create_filter_graph(
+                encoder,
+                self.width,
+                self.height,
+                &self.config,
+                format,
+            )?);
+        }
+        self.input_format = format;
+        Ok(())
+    }
+
+    /// Send the packets the encoder has ready to the output. QSV keeps async_depth frames in
+    /// flight, packets come out that many frames later.
+    fn emit_packets(&mut self) {
+        if let Some(ref mut encoder) = self.encoder {
+            let mut packet = ffmpeg::codec::packet::Packet::empty();
+            while encoder.receive_packet(&mut packet).is_ok() {
+                if let Some(data) = packet.data() {
+                    let pts = StreamPts::new(packet.pts().unwrap_or(0), CaptureTime::TIME_BASE);
+                    match self.output.send(EncodedVideoFrame {
+                        data: data.to_vec(),
+                        is_keyframe: packet.is_key(),
+                        pts,
+                        dts: StreamPts::new(packet.dts().unwrap_or(0), CaptureTime::TIME_BASE),
+                    }) {
+                        Delivery::Delivered => self.stats.mark_packet_emitted(pts.into()),
+                        Delivery::Full => {
+                            self.stats.mark_frame_dropped();
+                            log::error!("Could not send encoded video frame. Receiver is full");
+                            self.stats
+                                .record_error(PipelineStage::Consumer, "Encoded receiver full");
+                        }
+                        // Handled once by the processing loop
+                        Delivery::NoSubscribers => {}
+                    }
+                };
+            }
+        }
+    }
+
+    pub(crate) fn new(width: u32, height: u32, config: VideoConfig) -> Result<Self> {
+        let (output_width, output_height) = config.encoded_size(width, height)?;
+        let (encoder, rejected_options) =
+            Self::create_encoder(output_width, output_height, &config)?;
+
+        let input_format = default_input_format(&config);
+        let filter_graph = Some(Self::create_filter_graph(
+            &encoder,
+            width,
+            height,
+            &config,
+            input_format,
+        )?);
+
+        let follows_compositor_transform = config.transform.is_none();
+        Ok(Self {
+            encoder: Some(encoder),
+            width,
+            height,
+            config,
+            rejected_options,
+            output: FanOut::new(OUTPUT_CAPACITY),
+            stats: Arc::default(),
+            filter_graph,
+            last_frame: None,
+            pip_frames: None,
+            input_format,
+            follows_compositor_transform,
+        })
+    }
+
+    pub(crate) fn create_encoder(
+        width: u32,
+        height: u32,
+        config: &VideoConfig,
+    ) -> Result<(ffmpeg::codec::encoder::Video, Vec<String>)> {
+        let encoder_codec = match ffmpeg_compat::find_encoder("h264_qsv") {
+            Err(WaycapError::FFmpeg(ffmpeg::Error::EncoderNotFound)) => {
+                return Err(WaycapError::Init(
+                    "The linked ffmpeg was built without h264_qsv, use VAAPI instead".to_string(),
+                ))
+            }
+            result => result?,
+        };
+
+        let mut encoder_ctx = ffmpeg::codec::context::Context::new_with_codec(encoder_codec)
+            .encoder()
+            .video()?;
+
+        encoder_ctx.set_width(width);
+        encoder_ctx.set_height(height);
+        encoder_ctx.set_format(ffmpeg::format::Pixel::QSV);
+        let vaapi_device = create_hw_device(AVHWDeviceType::AV_HWDEVICE_TYPE_VAAPI)?;
+        // Fails where the oneVPL runtime is missing or the GPU isn't Intel's
+        let qsv_device = derive_hw_device(&vaapi_device, AVHWDeviceType::AV_HWDEVICE_TYPE_QSV)
+            .map_err(|e| {
+                WaycapError::Init(format!(
+                    "QSV needs an Intel GPU and the oneVPL runtime: {e}"
+                ))
+            })?;
+        let frame_ctx = create_hw_frame_ctx(&qsv_device)?;
+
+        unsafe {
+            let hw_frame_context = &mut *((*frame_ctx.as_ptr()).data as *mut AVHWFramesContext);
+            hw_frame_context.width = width as i32;
+            hw_frame_context.height = height as i32;
+            hw_frame_context.sw_format = AVPixelFormat::AV_PIX_FMT_NV12;
+            hw_frame_context.format = encoder_ctx.format().into();
+            // QSV pools can't grow, every frame the runtime keeps in flight or holds back for
+            // B-frames needs a surface, as does the last one when it is kept to repeat
+            hw_frame_context.initial_pool_size =
+                (config.frames_in_flight() + 1 + config.b_frames() + config.kept_frames()) as i32;
+
+            let err = av_hwframe_ctx_init(frame_ctx.as_ptr());
+            if err < 0 {
+                return Err(WaycapError::Init(format!(
+                    "Error trying to initialize hw frame context: {err:?}",
+                )));
+            }
+
+            // The context frees these with itself, the guards release ours. The QSV device
+            // holds on to the VAAPI one it was derived from.
+            (*encoder_ctx.as_mut_ptr()).hw_device_ctx = qsv_device.new_ref()?;
+            (*encoder_ctx.as_mut_ptr()).hw_frames_ctx = frame_ctx.new_ref()?;
+        }
+
+        encoder_ctx.set_time_base(CaptureTime::TIME_BASE);
+        encoder_ctx.set_gop(if config.schedules_keyframes() {
+            SCHEDULED_GOP_SIZE
+        } else {
+            GOP_SIZE
+        });
+        encoder_ctx.set_max_b_frames(config.b_frames() as usize);
+        // The filter graph converts to the range and matrix the stream is tagged with
+        encoder_ctx.set_color_range(ffmpeg_color_range(config.color_range));
+        set_colorimetry(&mut encoder_ctx, config.colorimetry);
+
+        let encoder_params = ffmpeg::codec::Parameters::new();
+
+        let mut opts = Self::get_encoder_params(config)?;
+        // Frames forced to I are otherwise plain I frames, not ones a segment can start at
+        opts.set("forced_idr", "1");
+
+        encoder_ctx.set_parameters(encoder_params)?;
+        open_configured_encoder(encoder_ctx, opts, config)
+    }
+
+    fn get_encoder_params(config: &VideoConfig) -> Result<ffmpeg::Dictionary<'_>> {
+        let mut opts = ffmpeg::Dictionary::new();
+        opts.set("async_depth", &config.frames_in_flight().to_string());
+        // Fixed qps like VAAPI. With qscale set QSV picks CQP and reads the qp from
+        // global_quality, counted in lambda units.
+        opts.set("flags", "+qscale");
+        let (preset, qp) = match config.quality {
+            QualityPreset::Low => ("veryfast", 30),
+            QualityPreset::Medium | QualityPreset::Custom(_) => ("veryfast", 25),
+            QualityPreset::High => ("faster", 20),
+            QualityPreset::Ultra => ("medium", 15),
+            QualityPreset::Lossless => return Err(lossless_unsupported("h264_qsv")),
+        };
+        opts.set("preset", preset);
+        opts.set("global_quality", &(qp * FF_QP2LAMBDA).to_string());
+        Ok(opts)
+    }
+
+    /// Graph taking `width`x`height` DMA-BUFs in `input`
+    fn create_filter_graph(
+        encoder: &ffmpeg::codec::encoder::Video,
+        width: u32,
+        height: u32,
+        config: &VideoConfig,
+        input: VideoFormat,
+    ) -> Result<ffmpeg::filter::Graph> {
+        with_denoise_fallback(config, |denoise| {
+            Self::build_filter_graph(encoder, width, height, config, input, denoise)
+        })
+    }
+
+    /// [`Self::create_filter_graph`], denoising with `denoise` instead of the config's
+    fn build_filter_graph(
+        encoder: &ffmpeg::codec::encoder::Video,
+        width: u32,
+        height: u32,
+        config: &VideoConfig,
+        input: VideoFormat,
+        denoise: Option<DenoiseStrength>,
+    ) -> Result<ffmpeg::filter::Graph> {
+        let mut graph = ffmpeg::filter::Graph::new();
+
+        let input_format = graph_input_format(input)?;
+        let output_format = scale_format(input, false)
+            .map_or_else(String::new, |format| format!("format={format}:"));
+        let args =
+            format!("video_size={width}x{height}:pix_fmt={input_format}:time_base=1/1000000");
+
+        let input = graph.add(&ffmpeg_compat::find_filter("buffer")?, "in", &args)?;
+
+        // Deriving VAAPI from the QSV device gives back the device it was derived from
+        let mut hwmap = graph.add(
+            &ffmpeg_compat::find_filter("hwmap")?,
+            "hwmap",
+            "mode=read+write:derive_device=vaapi",
+        )?;
+
+        let scaling = config.scaling(width, height)?;
+        let crop = add_crop_filter(&mut graph, scaling.source, width, height)?;
+        let transpose = add_transpose_filter(&mut graph, scaling.transform)?;
+
+        let scale_args = format!(
+            "w={}:h={}:{output_format}{}",
+            scaling.picture.width,
+            scaling.picture.height,
+            scale_color_args(encoder)
+        );
+        let scale = graph.add(
+            &ffmpeg_compat::find_filter("scale_vaapi")?,
+            "scale",
+            &scale_args,
+        )?;
+        let denoise = add_denoise_filter(&mut graph, denoise)?;
+        // Padded while still a VAAPI surface, QSV has no filter for it
+        let pad = add_pad_filter(&mut graph, &scaling, config.pad_color)?;
+        let overlay = config.overlay.as_ref().map(Overlay::load).transpose()?;
+        let blend = match overlay {
+            Some(ref overlay) => {
+                let placement = overlay.placement(scaling.output)?;
+                Some(overlay.add_vaapi_filter(&mut graph, encoder, placement)?)
+            }
+            None => None,
+        };
+        let pip = config
+            .pip
+            .as_ref()
+            .map(|pip| pip::add_vaapi_filter(pip, &mut graph, encoder, scaling.output))
+            .transpose()?;
+
+        // The converted VAAPI surfaces are mapped, not copied, onto the encoder's QSV device
+        let mut qsv_map = graph.add(&ffmpeg_compat::find_filter("hwmap")?, "qsv_map", "")?;
+        // Otherwise the mapping may be negotiated back to system memory
+        let qsv_format = graph.add(
+            &ffmpeg_compat::find_filter("format")?,
+            "qsv_format",
+            "pix_fmts=qsv",
+        )?;
+
+        let out = graph.add(&ffmpeg_compat::find_filter("buffersink")?, "out", "")?;
+        unsafe {
+            let dev = (*encoder.as_ptr()).hw_device_ctx;
+
+            (*hwmap.as_mut_ptr()).hw_device_ctx = av_buffer_ref(dev);
+            (*qsv_map.as_mut_ptr()).hw_device_ctx = av_buffer_ref(dev);
+        }
+
+        let mut filters = vec![input, hwmap];
+        filters.extend(crop);
+        filters.extend(transpose);
+        filters.push(scale);
+        filters.extend(denoise);
+        filters.extend(pad);
+        filters.extend(blend);
+        filters.extend(pip);
+        filters.extend([qsv_map, qsv_format, out]);
+        link_filters_with_custom(&mut graph, &mut filters, 2, encoder, config)?;
+
+        validate_graph(&mut graph, config)?;
+        if let Some(overlay) = overlay {
+            overlay.push(&mut graph)?;
+        }
+        log::trace!("QSV Graph\n{}", graph.dump());
+
+        Ok(graph)
+    }
+}
+
+impl Drop for QsvEncoder {
+    fn drop(&mut self) {
+        if let Err(e) = self.drain() {
+            log::error!("Error while draining qsv encoder during drop: {e:?}");
+        }
+        self.drop_processor();
+    }
+}
@@ -1,12 +1,24 @@
+use std::ptr::{null, null_mut};
+
 use crate::{
-    encoders::video::{PipewireSPA, ProcessingThread},
+    encoders::{
+        software_encoder::input_pixel,
+        spa_format::VideoFormatOffer,
+        video::{PipewireSPA, ProcessingThread},
+    },
     types::video_frame::RawVideoFrame,
     VideoEncoder,
 };
 use crossbeam::channel::{Receiver, Sender};
+use ffmpeg_next::{
+    self as ffmpeg,
+    ffi::sws_scale,
+    format::Pixel,
+    software::scaling::{self, Flags},
+};
 
-use crate::types::error::Result;
-use pipewire as pw;
+use crate::types::error::{Result, WaycapError};
+use pipewire::spa::param::video::VideoFormat;
 
 /// "Encoder" which outputs image::RgbaImage
 ///
@@ -72,55 +84,7 @@ impl VideoEncoder for RgbaImageEncoder {
 
 impl PipewireSPA for RgbaImageEncoder {
     fn get_spa_definition() -> Result<pipewire::spa::pod::Object> {
-        Ok(pw::spa::pod::object!(
-            pw::spa::utils::SpaTypes::ObjectParamFormat,
-            pw::spa::param::ParamType::EnumFormat,
-            pw::spa::pod::property!(
-                pw::spa::param::format::FormatProperties::MediaType,
-                Id,
-                pw::spa::param::format::MediaType::Video
-            ),
-            pw::spa::pod::property!(
-                pw::spa::param::format::FormatProperties::MediaSubtype,
-                Id,
-                pw::spa::param::format::MediaSubtype::Raw
-            ),
-            pw::spa::pod::property!(
-                pw::spa::param::format::FormatProperties::VideoFormat,
-                Choice,
-                Enum,
-                Id,
-                pw::spa::param::video::VideoFormat::BGRA,
-                pw::spa::param::video::VideoFormat::BGRx
-            ),
-            pw::spa::pod::property!(
-                pw::spa::param::format::FormatProperties::VideoSize,
-                Choice,
-                Range,
-                Rectangle,
-                pw::spa::utils::Rectangle {
-                    width: 2560,
-                    height: 1440
-                }, // Default
-                pw::spa::utils::Rectangle {
-                    width: 1,
-                    height: 1
-                }, // Min
-                pw::spa::utils::Rectangle {
-                    width: 4096,
-                    height: 4096
-                } // Max
-            ),
-            pw::spa::pod::property!(
-                pw::spa::param::format::FormatProperties::VideoFramerate,
-                Choice,
-                Range,
-                Fraction,
-                pw::spa::utils::Fraction { num: 240, denom: 1 }, // Default
-                pw::spa::utils::Fraction { num: 0, denom: 1 },   // Min
-                pw::spa::utils::Fraction { num: 244, denom: 1 }  // Max
-            ),
-        ))
+        Ok(VideoFormatOffer::new(vec![VideoFormat::BGRA, VideoFormat::BGRx]).to_object())
     }
 }
 
@@ -139,3 +103,104 @@ pub fn bgra_to_rgba_inplace(buf: &mut [u8]) {
         *p = rgba.to_be_bytes();
     }
 }
+
+/// Scales 8 bit RGB and YUY2 frames into RGBA images on the CPU, e.g. for
+/// [`crate::Capture::preview_frames`] and the webcams of [`crate::types::config::PipConfig`].
+/// The swscale context is kept for the next frame of the same size.
+#[derive(Default)]
+pub(crate) struct RgbaScaler {
+    context: Option<scaling::Context>,
+}
+
+impl RgbaScaler {
+    /// Scale the `width`x`height` frame of `format` at the start of `bytes`, its rows `stride`
+    /// bytes apart, to a `target_width`x`target_height` image
+    pub fn scale(
+        &mut self,
+        bytes: &[u8],
+        stride: usize,
+        format: VideoFormat,
+        (width, height): (u32, u32),
+        (target_width, target_height): (u32, u32),
+    ) -> Result<image::RgbaImage> {
+        let (input, pixel_size) = match format {
+            VideoFormat::YUY2 => (Pixel::YUYV422, 2),
+            format => match input_pixel(format) {
+                Some(input) => (input, 4),
+                None => {
+                    return Err(WaycapError::Encoding(format!(
+                        "Can't scale {format:?} frames on the CPU"
+                    )))
+                }
+            },
+        };
+        let row = width as usize * pixel_size;
+        let needed = stride * height.saturating_sub(1) as usize + row;
+        if stride < row || bytes.len() < needed {
+            return Err(WaycapError::Encoding(format!(
+                "Frame of {} bytes is too small for {width}x{height} with a stride of {stride}",
+                bytes.len()
+            )));
+        }
+        let context_matches = self.context.as_ref().is_some_and(|context| {
+            let (input_size, output) = (context.input(), context.output());
+            (input_size.format, input_size.width, input_size.height) == (input, width, height)
+                && (output.width, output.height) == (target_width, target_height)
+        });
+        if !context_matches {
+            // Area averaging keeps text readable when shrinking a lot
+            self.context = Some(scaling::Context::get(
+                input,
+                width,
+                height,
+                Pixel::RGBA,
+                target_width,
+                target_height,
+                Flags::AREA,
+            )?);
+        }
+        let context = self.context.as_mut().unwrap();
+
+        let mut image = image::RgbaImage::new(target_width, target_height);
+        let scaled = unsafe {
+            let source = [bytes.as_ptr(), null(), null(), null()];
+            let source_stride = [stride as i32, 0, 0, 0];
+            let destination = [image.as_mut_ptr(), null_mut(), null_mut(), null_mut()];
+            let destination_stride = [target_width as i32 * 4, 0, 0, 0];
+            sws_scale(
+                context.as_mut_ptr(),
+                source.as_ptr(),
+                source_stride.as_ptr(),
+                0,
+                height as i32,
+                destination.as_ptr(),
+                destination_stride.as_ptr(),
+            )
+        };
+        if scaled < 0 {
+            return Err(ffmpeg::Error::from(scaled).into());
+        }
+        Ok(image)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scaled_frames_come_out_as_rgba() {
+        // 64x32 BGRx in rows padded to 320 bytes
+        let row: Vec<u8> = [10, 20, 30, 0]
+            .repeat(64)
+            .into_iter()
+            .chain([0; 64])
+            .collect();
+        let frame = row.repeat(32);
+        let image = RgbaScaler::default()
+            .scale(&frame, 320, VideoFormat::BGRx, (64, 32), (16, 8))
+            .unwrap();
+        assert_eq!(image.dimensions(), (16, 8));
+        assert!(image.pixels().all(|pixel| pixel.0 == [30, 20, 10, 255]));
+    }
+}
@@ -0,0 +1,1435 @@
+use std::{ptr::null, sync::Arc};
+
+use crossbeam::channel::Receiver;
+use ffmpeg_next::{
+    self as ffmpeg,
+    ffi::{
+        sws_getCoefficients, sws_scale, sws_setColorspaceDetails, AVColorSpace, AVPixelFormat,
+        FF_QP2LAMBDA,
+    },
+    format::Pixel,
+    software::scaling::{self, Flags},
+};
+use pipewire::{self as pw, spa::param::video::VideoFormat};
+
+use crate::{
+    encoders::video::{PipewireSPA, ProcessingThread, VideoEncoder, OUTPUT_CAPACITY},
+    failure_injection, ffmpeg_compat,
+    pipeline::fanout::{Delivery, FanOut},
+    types::{
+        config::{DenoiseStrength, OverlayConfig, QualityPreset, Rect, Transform, VideoConfig},
+        encoder_info::EncoderInfo,
+        error::{Result, WaycapError},
+        event::PipelineStage,
+        stats::StatsCounters,
+        time::{CaptureTime, StreamPts},
+        video_frame::{EncodedVideoFrame, RawVideoFrame},
+    },
+    utils::DmaBufMapping,
+};
+
+use super::{
+    hdr::attach_hdr_side_data,
+    overlay::Overlay,
+    spa_format::VideoFormatOffer,
+    video::{
+        changed_frame_transform, ffmpeg_color_range, link_filters, lossless_unsupported,
+        open_configured_encoder, resend_frame, set_colorimetry, GOP_SIZE, SCHEDULED_GOP_SIZE,
+    },
+};
+
+/// Codecs encoded on the CPU
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SoftwareCodec {
+    /// libx264, fast enough for real time on most machines
+    H264,
+    /// SVT-AV1 in 10 bit, for recordings where the hardware encoders lose too much
+    Av1,
+    /// FFV1 on the captured RGB, bit-exact, for [`QualityPreset::Lossless`]
+    Ffv1,
+    /// ffmpeg's own JPEG encoder, where VAAPI can't encode JPEGs
+    Mjpeg,
+    /// libvpx at a constant bitrate, for WebRTC
+    Vp8,
+}
+
+impl SoftwareCodec {
+    pub fn encoder_name(self) -> &'static str {
+        match self {
+            SoftwareCodec::H264 => "libx264",
+            SoftwareCodec::Av1 => "libsvtav1",
+            SoftwareCodec::Ffv1 => "ffv1",
+            SoftwareCodec::Mjpeg => "mjpeg",
+            SoftwareCodec::Vp8 => "libvpx",
+        }
+    }
+
+    /// Every frame is a key frame, there is no GOP to set up
+    fn intra_only(self) -> bool {
+        matches!(self, SoftwareCodec::Ffv1 | SoftwareCodec::Mjpeg)
+    }
+
+    /// The encoder's input, the captured frames are converted to it
+    fn pixel_format(self) -> Pixel {
+        match self {
+            SoftwareCodec::H264 | SoftwareCodec::Mjpeg | SoftwareCodec::Vp8 => Pixel::YUV420P,
+            // 10 bit avoids banding in dark gradients at the same bitrate
+            SoftwareCodec::Av1 => Pixel::YUV420P10LE,
+            // Converting to YUV would round, BGRx and RGBx only get their channels reordered
+            SoftwareCodec::Ffv1 => Pixel::BGRZ,
+        }
+    }
+}
+
+/// Encoder which encodes frames on the CPU, with libx264, SVT-AV1, FFV1, ffmpeg's JPEG encoder
+/// or libvpx, see [`crate::types::config::VideoEncoder::H264Software`],
+/// [`crate::types::config::VideoEncoder::Av1Svt`], [`QualityPreset::Lossless`],
+/// [`crate::types::config::VideoEncoder::Mjpeg`] and [`crate::types::config::VideoEncoder::Vp8`].
+///
+/// Works without any GPU encoder, for broken drivers, virtual machines and old hardware. The
+/// frames are taken from shared memory, or mapped when the compositor sends linear DMA-BUFs
+/// anyway, and converted to YUV with swscale. Costs a lot more CPU time than the hardware
+/// encoders.
+pub struct SoftwareEncoder {
+    encoder: Option<ffmpeg::codec::encoder::Video>,
+    codec: SoftwareCodec,
+    /// Converts the captured frames to the encoder's YUV, recreated when their size or format
+    /// changes
+    scaler: Option<scaling::Context>,
+    /// An encoder sized frame of [`VideoConfig::pad_color`] the picture is scaled into when it
+    /// is letterboxed, made along with the scaler
+    background: Option<ffmpeg::util::frame::Video>,
+    /// [`VideoConfig::overlay`], read once
+    overlay: Option<Overlay>,
+    /// The overlay converted for the opened encoder
+    blend: Option<Blend>,
+    /// See [`denoiser`], made with the encoder
+    denoiser: Option<ffmpeg::filter::Graph>,
+    /// The last converted frame, kept with [`VideoConfig::cfr`] or
+    /// [`VideoConfig::timelapse`] to repeat it
+    last_frame: Option<ffmpeg::util::frame::Video>,
+    width: u32,
+    height: u32,
+    config: VideoConfig,
+    rejected_options: Vec<String>,
+    output: FanOut<EncodedVideoFrame>,
+    stats: Arc<StatsCounters>,
+    /// [`VideoConfig::transform`] was left to the compositor, the config holds the one of the
+    /// last frame then
+    follows_compositor_transform: bool,
+}
+
+// The scaler holds a raw pointer, it is only used by whoever holds the encoder's lock
+unsafe impl Send for SoftwareEncoder {}
+
+impl ProcessingThread for SoftwareEncoder {
+    fn process(&mut self, frame: RawVideoFrame) -> Result<()> {
+        if let Some(transform) =
+            changed_frame_transform(&self.config, self.follows_compositor_transform, &frame)
+        {
+            self.config.transform = Some(transform);
+            self.drain()?;
+            self.reset()?;
+        }
+        let Some(ref mut encoder) = self.encoder else {
+            return Ok(());
+        };
+        let Some(input_format) = input_pixel(frame.format) else {
+            return Err(WaycapError::Encoding(format!(
+                "Software encoding can't take {:?} frames",
+                frame.format
+            )));
+        };
+        let (width, height) = (frame.dimensions.width, frame.dimensions.height);
+        let (offset, stride) = (frame.offset as usize, frame.stride as usize);
+        let needed = offset + stride * height.saturating_sub(1) as usize + width as usize * 4;
+
+        // PipeWire maps shared memory buffers, DMA-BUFs are mapped here
+        let mapping;
+        let bytes = match frame.dmabuf_fd {
+            _ if !frame.data.is_empty() => frame.data.as_slice(),
+            Some(fd) => {
+                mapping = DmaBufMapping::new(fd, frame.modifier, needed)?;
+                mapping.bytes()
+            }
+            None => return Ok(()),
+        };
+        if stride < width as usize * 4 || bytes.len() < needed {
+            return Err(WaycapError::Encoding(format!(
+                "Frame of {} bytes is too small for {width}x{height} with a stride of {stride}",
+                bytes.len()
+            )));
+        }
+        let scaling = self.config.scaling(width, height)?;
+        let region = scaling.source;
+        let offset = offset + region.y as usize * stride + region.x as usize * 4;
+        // swscale only scales, rotated and mirrored frames are rearranged before
+        let turned;
+        let (source, source_stride, width, height) = match scaling.transform {
+            Transform::Normal => (&bytes[offset..], stride, region.width, region.height),
+            transform => {
+                turned = turn(&bytes[offset..], stride, region, transform);
+                let (width, height) = if transform.swaps_sides() {
+                    (region.height, region.width)
+                } else {
+                    (region.width, region.height)
+                };
+                (turned.as_slice(), width as usize * 4, width, height)
+            }
+        };
+        // Without bars the picture is the encoder's size, which rounds odd sizes down for 4:2:0
+        let picture = if scaling.is_padded() {
+            scaling.picture
+        } else {
+            Rect {
+                x: 0,
+                y: 0,
+                width: encoder.width(),
+                height: encoder.height(),
+            }
+        };
+
+        let scaler_matches = self.scaler.as_ref().is_some_and(|scaler| {
+            let (input, output) = (scaler.input(), scaler.output());
+            (input.format, input.width, input.height) == (input_format, width, height)
+                && (output.width, output.height) == (picture.width, picture.height)
+        });
+        if !scaler_matches {
+            self.scaler = Some(converter(
+                input_format,
+                (width, height),
+                encoder,
+                (picture.width, picture.height),
+            )?);
+            self.background = if scaling.is_padded() {
+                Some(background(encoder, self.config.pad_color)?)
+            } else {
+                None
+            };
+        }
+        let scaler = self.scaler.as_mut().unwrap();
+
+        let mut yuv_frame = match self.background {
+            Some(ref background) => background.clone(),
+            None => {
+                ffmpeg::util::frame::Video::new(encoder.format(), encoder.width(), encoder.height())
+            }
+        };
+        let scaled = unsafe {
+            let source = [source.as_ptr(), null(), null(), null()];
+            let source_stride = [source_stride as i32, 0, 0, 0];
+            let destination = plane_pointers(&mut yuv_frame, picture.x, picture.y);
+            sws_scale(
+                scaler.as_mut_ptr(),
+                source.as_ptr(),
+                source_stride.as_ptr(),
+                0,
+                height as i32,
+                destination.as_ptr(),
+                (*yuv_frame.as_mut_ptr()).linesize.as_ptr(),
+            )
+        };
+        if scaled < 0 {
+            return Err(ffmpeg::Error::from(scaled).into());
+        }
+
+        yuv_frame.set_pts(Some(frame.timestamp.as_nanos()));
+        if let Some(ref mut denoiser) = self.denoiser {
+            yuv_frame = denoise(denoiser, &yuv_frame)?;
+        }
+        // Drawn after denoising, which would smear it
+        if let Some(ref blend) = self.blend {
+            blend.blend_onto(&mut yuv_frame);
+        }
+        if let Some(ref hdr) = self.config.hdr_metadata {
+            attach_hdr_side_data(&mut yuv_frame, hdr);
+        }
+        if frame.force_keyframe {
+            yuv_frame.set_kind(ffmpeg::picture::Type::I);
+        }
+        failure_injection::send_frame(encoder, &yuv_frame)?;
+        if self.config.repeats_frames() {
+            self.last_frame = Some(yuv_frame);
+        }
+        self.emit_packets();
+        Ok(())
+    }
+
+    fn repeat_frame(&mut self, timestamp: CaptureTime, force_keyframe: bool) -> Result<bool> {
+        let (Some(encoder), Some(frame)) = (self.encoder.as_mut(), self.last_frame.as_mut()) else {
+            return Ok(false);
+        };
+        resend_frame(encoder, frame, timestamp, force_keyframe)?;
+        self.emit_packets();
+        Ok(true)
+    }
+}
+
+impl VideoEncoder for SoftwareEncoder {
+    type Output = EncodedVideoFrame;
+
+    fn reset(&mut self) -> Result<()> {
+        self.drop_processor();
+        let (encoder, rejected_options) =
+            Self::create_encoder(self.width, self.height, self.codec, &self.config)?;
+        self.blend = self
+            .overlay
+            .as_ref()
+            .map(|overlay| Blend::new(overlay, &encoder))
+            .transpose()?;
+        self.denoiser = denoiser(&encoder, self.codec, self.config.denoise)?;
+        self.encoder = Some(encoder);
+        self.rejected_options = rejected_options;
+        Ok(())
+    }
+
+    fn source_changed(&mut self, width: u32, height: u32) -> Result<()> {
+        // Fails before draining when the crop lies outside the new source
+        self.config.encoded_size(width, height)?;
+        self.drain()?;
+        self.width = width;
+        self.height = height;
+        self.reset()
+    }
+
+    fn drop_processor(&mut self) {
+        self.encoder.take();
+        self.scaler.take();
+        self.background.take();
+        self.blend.take();
+        self.denoiser.take();
+        self.last_frame.take();
+    }
+
+    fn output(&mut self) -> Option<Receiver<EncodedVideoFrame>> {
+        Some(self.output.subscribe())
+    }
+
+    fn has_consumers(&self) -> bool {
+        self.output.has_subscribers()
+    }
+
+    fn change_quality(&mut self, quality: QualityPreset) -> Result<()> {
+        self.config.set_quality(quality)?;
+        self.drain()?;
+        self.reset()
+    }
+
+    fn drain(&mut self) -> Result<()> {
+        if let Some(ref mut encoder) = self.encoder {
+            encoder.send_eof()?;
+            let mut packet = ffmpeg::codec::packet::Packet::empty();
+            while encoder.receive_packet(&mut packet).is_ok() {} // Discard these frames
+        }
+        Ok(())
+    }
+
+    /// Moving the crop only changes where the frames are read from, a new size reopens the
+    /// encoder
+    fn set_crop(&mut self, crop: Option<Rect>) -> Result<()> {
+        let mut config = self.config.clone();
+        config.set_crop(crop)?;
+        let size = config.encoded_size(self.width, self.height)?;
+        let same_size = self
+            .encoder
+            .as_ref()
+            .is_some_and(|encoder| (encoder.width(), encoder.height()) == size);
+        self.config = config;
+        if !same_size {
+            self.drain()?;
+            self.reset()?;
+        }
+        Ok(())
+    }
+
+    /// Only the converted image is replaced, the encoder is kept
+    fn set_overlay(&mut self, overlay: Option<OverlayConfig>) -> Result<()> {
+        let mut config = self.config.clone();
+        config.set_overlay(overlay)?;
+        let overlay = config.overlay.as_ref().map(Overlay::load).transpose()?;
+        if let Some(ref encoder) = self.encoder {
+            self.blend = overlay
+                .as_ref()
+                .map(|overlay| Blend::new(overlay, encoder))
+                .transpose()?;
+        }
+        self.overlay = overlay;
+        self.config = config;
+        Ok(())
+    }
+
+    fn get_encoder(&self) -> &Option<ffmpeg::codec::encoder::Video> {
+        &self.encoder
+    }
+
+    fn info(&self) -> Option<EncoderInfo> {
+        Some(EncoderInfo {
+            name: self.codec.encoder_name().to_string(),
+            rejected_options: self.rejected_options.clone(),
+            ..Default::default()
+        })
+    }
+}
+
+impl PipewireSPA for SoftwareEncoder {
+    /// Shared memory, no modifiers, so the compositor doesn't hand out tiled GPU buffers
+    fn get_spa_definition() -> Result<pw::spa::pod::Object> {
+        Ok(VideoFormatOffer::new(vec![
+            VideoFormat::BGRx,
+            VideoFormat::BGRA,
+            VideoFormat::RGBx,
+            VideoFormat::RGBA,
+        ])
+        .to_object())
+    }
+}
+
+impl SoftwareEncoder {
+    pub(crate) fn set_stats(&mut self, stats: Arc<StatsCounters>) {
+        self.stats = stats;
+    }
+
+    /// Output queues, for watching their depth without subscribing
+    pub(crate) fn output_queues(&self) -> FanOut<EncodedVideoFrame> {
+        self.output.clone()
+    }
+
+    /// Send the packets the encoder has ready to the output
+    fn emit_packets(&mut self) {
+        let Some(ref mut encoder) = self.encoder else {
+            return;
+        };
+        let mut packet = ffmpeg::codec::packet::Packet::empty();
+        while encoder.receive_packet(&mut packet).is_ok() {
+            if let Some(data) = packet.data() {
+                let pts = StreamPts::new(packet.pts().unwrap_or(0), CaptureTime::TIME_BASE);
+                match self.output.send(EncodedVideoFrame {
+                    data: data.to_vec(),
+                    is_keyframe: packet.is_key() || self.codec.intra_only(),
+                    pts,
+                    dts: StreamPts::new(packet.dts().unwrap_or(0), CaptureTime::TIME_BASE),
+                }) {
+                    Delivery::Delivered => self.stats.mark_packet_emitted(pts.into()),
+                    Delivery::Full => {
+                        self.stats.mark_frame_dropped();
+                        log::error!("Could not send encoded video frame. Receiver is full");
+                        self.stats
+                            .record_error(PipelineStage::Consumer, "Encoded receiver full");
+                    }
+                    // Handled once by the processing loop
+                    Delivery::NoSubscribers => {}
+                }
+            }
+        }
+    }
+
+    pub(crate) fn new(
+        width: u32,
+        height: u32,
+        codec: SoftwareCodec,
+        config: VideoConfig,
+    ) -> Result<Self> {
+        let (encoder, rejected_options) = Self::create_encoder(width, height, codec, &config)?;
+        let follows_compositor_transform = config.transform.is_none();
+        let overlay = config.overlay.as_ref().map(Overlay::load).transpose()?;
+        let blend = overlay
+            .as_ref()
+            .map(|overlay| Blend::new(overlay, &encoder))
+            .transpose()?;
+        let denoiser = denoiser(&encoder, codec, config.denoise)?;
+        Ok(Self {
+            encoder: Some(encoder),
+            codec,
+            scaler: None,
+            background: None,
+            overlay,
+            blend,
+            denoiser,
+            last_frame: None,
+            width,
+            height,
+            config,
+            rejected_options,
+            output: FanOut::new(OUTPUT_CAPACITY),
+            stats: Arc::default(),
+            follows_compositor_transform,
+        })
+    }
+
+    pub(crate) fn create_encoder(
+        width: u32,
+        height: u32,
+        codec: SoftwareCodec,
+        config: &VideoConfig,
+    ) -> Result<(ffmpeg::codec::encoder::Video, Vec<String>)> {
+        let encoder_codec = match ffmpeg_compat::find_encoder(codec.encoder_name()) {
+            Err(WaycapError::FFmpeg(ffmpeg::Error::EncoderNotFound)) => {
+                return Err(WaycapError::Init(format!(
+                    "The linked ffmpeg was built without {}",
+                    codec.encoder_name()
+                )))
+            }
+            result => result?,
+        };
+        let mut encoder_ctx = ffmpeg::codec::context::Context::new_with_codec(encoder_codec)
+            .encoder()
+            .video()?;
+
+        // The scaler takes the cropped frames down to the output size along with converting them
+        let (width, height) = config.encoded_size(width, height)?;
+        if codec == SoftwareCodec::Ffv1 {
+            encoder_ctx.set_width(width);
+            encoder_ctx.set_height(height);
+        } else {
+            // 4:2:0 needs an even size, odd windows lose their last row or column to the scaler
+            encoder_ctx.set_width((width & !1).max(2));
+            encoder_ctx.set_height((height & !1).max(2));
+        }
+        encoder_ctx.set_format(codec.pixel_format());
+        encoder_ctx.set_time_base(CaptureTime::TIME_BASE);
+        encoder_ctx.set_gop(match (codec, config.schedules_keyframes()) {
+            // Every frame stands on its own, so editors can cut anywhere
+            _ if codec.intra_only() => 1,
+            (_, true) => SCHEDULED_GOP_SIZE,
+            (_, false) => GOP_SIZE,
+        });
+        if codec == SoftwareCodec::H264 {
+            // Takes precedence over the none of zerolatency. The other codecs have no B-frames,
+            // or pick their own like SVT-AV1.
+            encoder_ctx.set_max_b_frames(config.b_frames() as usize);
+        }
+        match codec {
+            // Baseline JPEG is full range BT.601, limited range is only taken in unofficial mode
+            SoftwareCodec::Mjpeg => {
+                set_colorimetry(&mut encoder_ctx, config.colorimetry);
+                encoder_ctx.set_color_range(ffmpeg::color::Range::JPEG);
+                encoder_ctx.set_colorspace(ffmpeg::color::Space::SMPTE170M);
+            }
+            // RGB has no range or matrix
+            SoftwareCodec::Ffv1 => {}
+            // swscale converts to the range and matrix the stream is tagged with, see
+            // converter()
+            _ => {
+                set_colorimetry(&mut encoder_ctx, config.colorimetry);
+                encoder_ctx.set_color_range(ffmpeg_color_range(config.color_range));
+            }
+        }
+
+        let mut opts = match codec {
+            SoftwareCodec::H264 => Self::get_x264_params(&config.quality)?,
+            SoftwareCodec::Av1 => Self::get_svt_params(&config.quality)?,
+            SoftwareCodec::Ffv1 => Self::get_ffv1_params(),
+            SoftwareCodec::Mjpeg => Self::get_mjpeg_params(&config.quality)?,
+            SoftwareCodec::Vp8 => {
+                Self::get_vp8_params(config.quality, encoder_ctx.width(), encoder_ctx.height())?
+            }
+        };
+        if codec == SoftwareCodec::H264 {
+            // Frames forced to I are otherwise plain I frames, not ones a segment can start at.
+            // SVT-AV1 makes them key frames already.
+            opts.set("forced-idr", "1");
+        }
+        open_configured_encoder(encoder_ctx, opts, config)
+    }
+
+    /// Only the fastest x264 presets keep up with a desktop in real time
+    fn get_x264_params(quality: &QualityPreset) -> Result<ffmpeg::Dictionary<'_>> {
+        let mut opts = ffmpeg::Dictionary::new();
+        // No B-frames and no lookahead, every frame comes out before the next one goes in
+        opts.set("tune", "zerolatency");
+        let (preset, crf) = match quality {
+            QualityPreset::Low => ("ultrafast", "28"),
+            QualityPreset::Medium | QualityPreset::Custom(_) => ("superfast", "24"),
+            QualityPreset::High => ("veryfast", "21"),
+            QualityPreset::Ultra => ("veryfast", "18"),
+            QualityPreset::Lossless => return Err(lossless_unsupported("libx264")),
+        };
+        opts.set("preset", preset);
+        opts.set("crf", crf);
+        Ok(opts)
+    }
+
+    /// Slower presets than x264's, the output is meant to be kept. SVT-AV1's presets run from
+    /// 0, the slowest, to 13.
+    fn get_svt_params(quality: &QualityPreset) -> Result<ffmpeg::Dictionary<'_>> {
+        let mut opts = ffmpeg::Dictionary::new();
+        let (preset, crf) = match quality {
+            QualityPreset::Low => ("10", "40"),
+            QualityPreset::Medium | QualityPreset::Custom(_) => ("9", "34"),
+            QualityPreset::High => ("8", "28"),
+            QualityPreset::Ultra => ("6", "24"),
+            QualityPreset::Lossless => return Err(lossless_unsupported("libsvtav1")),
+        };
+        opts.set("preset", preset);
+        opts.set("crf", crf);
+        Ok(opts)
+    }
+
+    /// FFV1 is always lossless, the options only trade speed for size
+    fn get_ffv1_params() -> ffmpeg::Dictionary<'static> {
+        let mut opts = ffmpeg::Dictionary::new();
+        // Version 3 splits frames into slices which are encoded in parallel, and checksums
+        // them so damaged files can be told apart
+        opts.set("level", "3");
+        opts.set("slicecrc", "1");
+        opts.set("threads", "auto");
+        opts
+    }
+
+    /// The JPEG quantizer, 2 to 31 with lower the better, is taken from global_quality in
+    /// lambda units once qscale is set
+    fn get_mjpeg_params(quality: &QualityPreset) -> Result<ffmpeg::Dictionary<'_>> {
+        let mut opts = ffmpeg::Dictionary::new();
+        let q = match quality {
+            QualityPreset::Low => 12,
+            QualityPreset::Medium | QualityPreset::Custom(_) => 7,
+            QualityPreset::High => 4,
+            QualityPreset::Ultra => 2,
+            QualityPreset::Lossless => return Err(lossless_unsupported("mjpeg")),
+        };
+        opts.set("flags", "+qscale");
+        opts.set("global_quality", &(q * FF_QP2LAMBDA).to_string());
+        Ok(opts)
+    }
+
+    /// Real time VP8 the way WebRTC peers expect it: a constant bitrate, no frames held back
+    /// and streams which survive lost packets
+    fn get_vp8_params(
+        quality: QualityPreset,
+        width: u32,
+        height: u32,
+    ) -> Result<ffmpeg::Dictionary<'static>> {
+        if quality == QualityPreset::Lossless {
+            return Err(lossless_unsupported("libvpx"));
+        }
+        let bitrate = quality.target_bitrate(width, height);
+        let mut opts = ffmpeg::Dictionary::new();
+        opts.set("deadline", "realtime");
+        opts.set("cpu-used", "8");
+        opts.set("lag-in-frames", "0");
+        opts.set("error-resilient", "default");
+        // libvpx picks CBR when the bitrate, minrate and maxrate are the same
+        let target = bitrate.to_string();
+        opts.set("b", &target);
+        opts.set("minrate", &target);
+        opts.set("maxrate", &target);
+        // Half a second of buffer, short enough that a burst doesn't stall the receiver
+        opts.set("bufsize", &(bitrate / 2).to_string());
+        Ok(opts)
+    }
+}
+
+/// Scaler from `input_size` frames of `input_format` to `output_size` ones in the encoder's
+/// format, range and matrix
+fn converter(
+    input_format: Pixel,
+    (input_width, input_height): (u32, u32),
+    encoder: &ffmpeg::codec::encoder::Video,
+    (output_width, output_height): (u32, u32),
+) -> Result<scaling::Context> {
+    let mut scaler = scaling::Context::get(
+        input_format,
+        input_width,
+        input_height,
+        encoder.format(),
+        output_width,
+        output_height,
+        Flags::BILINEAR,
+    )?;
+    // swscale converts to limited range BT.601 unless told otherwise. Its tables are indexed by
+    // ffmpeg's color spaces, an unset one falls back to BT.601.
+    let full_range = encoder.color_range() == ffmpeg::color::Range::JPEG;
+    unsafe {
+        let coefficients = sws_getCoefficients(AVColorSpace::from(encoder.colorspace()) as i32);
+        sws_setColorspaceDetails(
+            scaler.as_mut_ptr(),
+            coefficients,
+            1,
+            coefficients,
+            i32::from(full_range),
+            0,
+            1 << 16,
+            1 << 16,
+        );
+    }
+    Ok(scaler)
+}
+
+/// An encoder sized frame of `color`, converted by swscale like the picture so the bars match
+/// whatever it makes of the same RGB
+fn background(
+    encoder: &ffmpeg::codec::encoder::Video,
+    color: [u8; 3],
+) -> Result<ffmpeg::util::frame::Video> {
+    let mut source = ffmpeg::util::frame::Video::new(Pixel::RGB24, 2, 2);
+    let stride = source.stride(0);
+    for row in 0..2 {
+        source.data_mut(0)[row * stride..][..6].copy_from_slice(&color.repeat(2));
+    }
+    let mut frame = ffmpeg::util::frame::Video::empty();
+    converter(
+        Pixel::RGB24,
+        (2, 2),
+        encoder,
+        (encoder.width(), encoder.height()),
+    )?
+    .run(&source, &mut frame)?;
+    Ok(frame)
+}
+
+/// `hqdn3d` graph taking noise out of the encoder's frames, see [`VideoConfig::denoise`].
+/// Nothing for FFV1, which is meant to keep the captured pixels as they are.
+fn denoiser(
+    encoder: &ffmpeg::codec::encoder::Video,
+    codec: SoftwareCodec,
+    denoise: Option<DenoiseStrength>,
+) -> Result<Option<ffmpeg::filter::Graph>> {
+    // Spatial luma and chroma, then temporal luma and chroma strength. Medium is hqdn3d's
+    // default.
+    let strength = match denoise {
+        None => return Ok(None),
+        Some(_) if codec == SoftwareCodec::Ffv1 => return Ok(None),
+        Some(DenoiseStrength::Light) => "2:1.5:3:2.25",
+        Some(DenoiseStrength::Medium) => "4:3:6:4.5",
+        Some(DenoiseStrength::Strong) => "8:6:12:9",
+    };
+    let args = format!(
+        "video_size={}x{}:pix_fmt={}:time_base={}/{}",
+        encoder.width(),
+        encoder.height(),
+        AVPixelFormat::from(encoder.format()) as i32,
+        CaptureTime::TIME_BASE.numerator(),
+        CaptureTime::TIME_BASE.denominator()
+    );
+    let mut graph = ffmpeg::filter::Graph::new();
+    let mut filters = [
+        graph.add(&ffmpeg_compat::find_filter("buffer")?, "in", &args)?,
+        graph.add(&ffmpeg_compat::find_filter("hqdn3d")?, "denoise", strength)?,
+        graph.add(&ffmpeg_compat::find_filter("buffersink")?, "out", "")?,
+    ];
+    link_filters(&mut filters);
+    graph.validate()?;
+    Ok(Some(graph))
+}
+
+/// Run `frame` through a [`denoiser`], which hands each frame on right away
+fn denoise(
+    graph: &mut ffmpeg::filter::Graph,
+    frame: &ffmpeg::util::frame::Video,
+) -> Result<ffmpeg::util::frame::Video> {
+    graph.get("in").unwrap().source().add(frame)?;
+    let mut denoised = ffmpeg::util::frame::Video::empty();
+    graph.get("out").unwrap().sink().frame(&mut denoised)?;
+    Ok(denoised)
+}
+
+/// An overlay converted by swscale like the picture, blended onto it on the CPU
+struct Blend {
+    image: ffmpeg::util::frame::Video,
+    /// Alpha of each pixel of the image, times the opacity
+    alpha: Vec<u8>,
+    placement: Rect,
+}
+
+impl Blend {
+    fn new(overlay: &Overlay, encoder: &ffmpeg::codec::encoder::Video) -> Result<Self> {
+        let placement = overlay.placement((encoder.width(), encoder.height()))?;
+        let size = (placement.width, placement.height);
+        let source = overlay.frame(false);
+        let mut image = ffmpeg::util::frame::Video::empty();
+        converter(Pixel::RGBA, size, encoder, size)?.run(&source, &mut image)?;
+        let stride = source.stride(0);
+        let alpha = (0..placement.height as usize)
+            .flat_map(|y| {
+                source.data(0)[y * stride..][..placement.width as usize * 4]
+                    .chunks_exact(4)
+                    .map(|pixel| pixel[3])
+            })
+            .collect();
+        Ok(Self {
+            image,
+            alpha,
+            placement,
+        })
+    }
+
+    /// Blend the image onto `frame` component by component, which works for the planar YUV
+    /// and the packed RGB formats alike
+    fn blend_onto(&self, frame: &mut ffmpeg::util::frame::Video) {
+        let Some(descriptor) = frame.format().descriptor() else {
+            return;
+        };
+        let descriptor = unsafe { &*descriptor.as_ptr() };
+        let (image, target) = unsafe { (&*self.image.as_ptr(), &mut *frame.as_mut_ptr()) };
+        let Rect {
+            x,
+            y,
+            width,
+            height,
+        } = self.placement;
+        for component in &descriptor.comp[..usize::from(descriptor.nb_components)] {
+            let plane = component.plane as usize;
+            let (shift_x, shift_y) = match plane {
+                1 | 2 => (descriptor.log2_chroma_w, descriptor.log2_chroma_h),
+                _ => (0, 0),
+            };
+            let (step, offset) = (component.step as usize, component.offset as usize);
+            let (left, top) = ((x >> shift_x) as usize, (y >> shift_y) as usize);
+            for row in 0..(height as usize).div_ceil(1 << shift_y) {
+                for column in 0..(width as usize).div_ceil(1 << shift_x) {
+                    // The top left pixel of those sharing a chroma sample stands for them
+                    let alpha = u32::from(
+                        self.alpha[(row << shift_y) * width as usize + (column << shift_x)],
+                    );
+                    if alpha == 0 {
+                        continue;
+                    }
+                    unsafe {
+                        let source = image.data[plane]
+                            .offset(row as isize * image.linesize[plane] as isize)
+                            .add(column * step + offset);
+                        let destination = target.data[plane]
+                            .offset((top + row) as isize * target.linesize[plane] as isize)
+                            .add((left + column) * step + offset);
+                        if component.depth > 8 {
+                            let (source, destination) =
+                                (source as *const u16, destination as *mut u16);
+                            let value = mix(
+                                u32::from(source.read_unaligned()),
+                                u32::from(destination.read_unaligned()),
+                                alpha,
+                            );
+                            destination.write_unaligned(value as u16);
+                        } else {
+                            *destination =
+                                mix(u32::from(*source), u32::from(*destination), alpha) as u8;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// `source` over `target` at an 8 bit `alpha`
+fn mix(source: u32, target: u32, alpha: u32) -> u32 {
+    (source * alpha + target * (255 - alpha) + 127) / 255
+}
+
+/// The `region` of 4 byte pixels in `source`, whose rows are `stride` apart, packed into rows
+/// of their own with each pixel where `transform` puts it
+fn turn(source: &[u8], stride: usize, region: Rect, transform: Transform) -> Vec<u8> {
+    let (width, height) = (region.width, region.height);
+    let turned_width = if transform.swaps_sides() {
+        height
+    } else {
+        width
+    } as usize;
+    let mut turned = vec![0; width as usize * height as usize * 4];
+    for y in 0..height {
+        let row = &source[y as usize * stride..][..width as usize * 4];
+        for (x, pixel) in row.chunks_exact(4).enumerate() {
+            let (x, y) = transform.map(x as u32, y, width, height);
+            let index = (y as usize * turned_width + x as usize) * 4;
+            turned[index..index + 4].copy_from_slice(pixel);
+        }
+    }
+    turned
+}
+
+/// The planes of `frame` starting at pixel `x`,`y` instead of the top left corner, which have
+/// to be even for 4:2:0
+unsafe fn plane_pointers(frame: &mut ffmpeg::util::frame::Video, x: u32, y: u32) -> [*mut u8; 8] {
+    let descriptor = frame.format().descriptor();
+    let frame = &mut *frame.as_mut_ptr();
+    let mut data = frame.data;
+    let Some(descriptor) = descriptor.map(|descriptor| &*descriptor.as_ptr()) else {
+        return data;
+    };
+    for component in &descriptor.comp[..usize::from(descriptor.nb_components)] {
+        let plane = component.plane as usize;
+        // As in av_image_fill_pointers, planes 1 and 2 are the subsampled chroma
+        let (x, y) = match plane {
+            1 | 2 => (x >> descriptor.log2_chroma_w, y >> descriptor.log2_chroma_h),
+            _ => (x, y),
+        };
+        let offset =
+            y as isize * frame.linesize[plane] as isize + (x * component.step as u32) as isize;
+        data[plane] = frame.data[plane].offset(offset);
+    }
+    data
+}
+
+/// swscale's name for the layouts offered in [`SoftwareEncoder::get_spa_definition`]
+pub(crate) fn input_pixel(format: VideoFormat) -> Option<Pixel> {
+    match format {
+        VideoFormat::BGRx => Some(Pixel::BGRZ),
+        VideoFormat::BGRA => Some(Pixel::BGRA),
+        VideoFormat::RGBx => Some(Pixel::RGBZ),
+        VideoFormat::RGBA => Some(Pixel::RGBA),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        runtime::Runtime,
+        types::config::{
+            ColorMatrix, ColorRange, Colorimetry, ContentLightLevel, Corner, Fps, HdrMetadata,
+            MasteringDisplay, OverlayImage, OverlayPosition,
+        },
+    };
+    use ffmpeg::util::frame::side_data::Type;
+
+    #[test]
+    fn encodes_padded_shared_memory_frames() {
+        let _runtime = Runtime::acquire().unwrap();
+        let mut encoder =
+            SoftwareEncoder::new(64, 48, SoftwareCodec::H264, VideoConfig::default()).unwrap();
+        let packets = encoder.output().unwrap();
+        // Rows padded to 320 bytes, behind a 16 byte header
+        let (stride, offset) = (320, 16);
+        for frame in 0..3i64 {
+            let mut data = vec![0u8; offset + stride * 48];
+            for (index, byte) in data[offset..].iter_mut().enumerate() {
+                *byte = (index as i64 * 7 + frame * 31) as u8;
+            }
+            encoder
+                .process(RawVideoFrame {
+                    data,
+                    timestamp: CaptureTime::from_nanos(frame * 16_666_667),
+                    stride: stride as i32,
+                    offset: offset as u32,
+                    size: (stride * 48) as u32,
+                    ..RawVideoFrame::bgrx(64, 48)
+                })
+                .unwrap();
+        }
+
+        let packets: Vec<EncodedVideoFrame> = packets.try_iter().collect();
+        assert_eq!(packets.len(), 3, "zerolatency holds no frames back");
+        assert!(packets[0].is_keyframe);
+        assert_eq!(
+            packets[2].pts,
+            StreamPts::new(2 * 16_666_667, CaptureTime::TIME_BASE)
+        );
+    }
+
+    #[test]
+    fn the_stream_is_tagged_with_what_it_was_converted_to() {
+        use ffmpeg::color::{Primaries, Range, Space, TransferCharacteristic as Trc};
+
+        let _runtime = Runtime::acquire().unwrap();
+        let (width, height) = (64u32, 48u32);
+        let bt601 = Colorimetry {
+            matrix: ColorMatrix::Bt601,
+            ..Colorimetry::BT709
+        };
+        // Luma of pure red is the red weight of the matrix, scaled to the range
+        let cases = [
+            (ColorRange::Limited, Colorimetry::BT709, Space::BT709, 63),
+            (ColorRange::Full, Colorimetry::BT709, Space::BT709, 54),
+            (ColorRange::Limited, bt601, Space::SMPTE170M, 81),
+            (ColorRange::Full, Colorimetry::HDR10, Space::BT2020NCL, 67),
+        ];
+        for (color_range, colorimetry, matrix_tag, red) in cases {
+            let config = VideoConfig {
+                color_range,
+                colorimetry,
+                ..Default::default()
+            };
+            let mut encoder =
+                SoftwareEncoder::new(width, height, SoftwareCodec::H264, config).unwrap();
+            let packets = encoder.output().unwrap();
+            let data = [0, 0, 255, 0].repeat((width * height) as usize);
+            encoder
+                .process(RawVideoFrame {
+                    size: data.len() as u32,
+                    data,
+                    ..RawVideoFrame::bgrx(width, height)
+                })
+                .unwrap();
+            let packet = packets.try_recv().unwrap();
+
+            // A decoder which knows nothing of the encoder, so the tags are read from the SPS
+            let codec = ffmpeg::decoder::find(ffmpeg::codec::Id::H264).unwrap();
+            let mut decoder = ffmpeg::codec::Context::new_with_codec(codec)
+                .decoder()
+                .video()
+                .unwrap();
+            decoder
+                .send_packet(&ffmpeg::Packet::copy(&packet.data))
+                .unwrap();
+            decoder.send_eof().unwrap();
+            let mut decoded = ffmpeg::util::frame::Video::empty();
+            decoder.receive_frame(&mut decoded).unwrap();
+
+            let range_tag = match color_range {
+                ColorRange::Limited => Range::MPEG,
+                ColorRange::Full => Range::JPEG,
+            };
+            assert_eq!(decoded.color_range(), range_tag);
+            assert_eq!(decoded.color_space(), matrix_tag);
+            if colorimetry == Colorimetry::HDR10 {
+                assert_eq!(decoded.color_primaries(), Primaries::BT2020);
+                assert_eq!(decoded.color_transfer_characteristic(), Trc::SMPTE2084);
+            } else {
+                assert_eq!(decoded.color_primaries(), Primaries::BT709);
+                assert_eq!(decoded.color_transfer_characteristic(), Trc::BT709);
+            }
+            // The conversion agrees with the tags
+            let luma = decoded.data(0)[0];
+            assert!(
+                luma.abs_diff(red) <= 2,
+                "{color_range:?} {colorimetry:?}: {luma}"
+            );
+        }
+    }
+
+    #[test]
+    fn b_frames_come_out_in_decode_order() {
+        let _runtime = Runtime::acquire().unwrap();
+        let config = VideoConfig {
+            max_b_frames: 2,
+            ..Default::default()
+        };
+        let mut encoder = SoftwareEncoder::new(64, 48, SoftwareCodec::H264, config).unwrap();
+        let packets = encoder.output().unwrap();
+        let stride = 64 * 4;
+        for frame in 0..30i64 {
+            // A gradient moving a pixel per frame, which x264 likes to predict from both sides
+            let mut data = vec![0u8; stride * 48];
+            for (index, byte) in data.iter_mut().enumerate() {
+                *byte = ((index % stride) as i64 / 4 + frame) as u8;
+            }
+            encoder
+                .process(RawVideoFrame {
+                    data,
+                    timestamp: CaptureTime::from_nanos(frame * 16_666_667),
+                    stride: stride as i32,
+                    size: (stride * 48) as u32,
+                    ..RawVideoFrame::bgrx(64, 48)
+                })
+                .unwrap();
+        }
+
+        let packets: Vec<EncodedVideoFrame> = packets.try_iter().collect();
+        assert!(packets.len() > 2);
+        let dts: Vec<i64> = packets.iter().map(|packet| packet.dts.value).collect();
+        assert!(dts.windows(2).all(|pair| pair[0] < pair[1]), "{dts:?}");
+        assert!(packets
+            .iter()
+            .all(|packet| packet.dts.value <= packet.pts.value));
+        // The held back frames shift dts away from pts
+        assert!(packets.iter().any(|packet| packet.dts != packet.pts));
+    }
+
+    #[test]
+    fn quality_switch_keeps_the_output() {
+        let _runtime = Runtime::acquire().unwrap();
+        let mut encoder =
+            SoftwareEncoder::new(64, 48, SoftwareCodec::H264, VideoConfig::default()).unwrap();
+        let packets = encoder.output().unwrap();
+        let stride = 64 * 4;
+        for frame in 0..6i64 {
+            if frame == 3 {
+                encoder.change_quality(QualityPreset::High).unwrap();
+            }
+            encoder
+                .process(RawVideoFrame {
+                    data: vec![frame as u8; stride * 48],
+                    timestamp: CaptureTime::from_nanos(frame * 16_666_667),
+                    stride: stride as i32,
+                    size: (stride * 48) as u32,
+                    ..RawVideoFrame::bgrx(64, 48)
+                })
+                .unwrap();
+        }
+
+        let packets: Vec<EncodedVideoFrame> = packets.try_iter().collect();
+        assert_eq!(packets.len(), 6);
+        let keyframes: Vec<bool> = packets.iter().map(|packet| packet.is_keyframe).collect();
+        assert_eq!(keyframes, [true, false, false, true, false, false]);
+        assert_eq!(
+            packets[3].pts,
+            StreamPts::new(3 * 16_666_667, CaptureTime::TIME_BASE)
+        );
+        assert!(matches!(
+            encoder.change_quality(QualityPreset::Lossless),
+            Err(WaycapError::Validation(_))
+        ));
+    }
+
+    #[test]
+    fn lossless_frames_decode_bit_exact() {
+        let _runtime = Runtime::acquire().unwrap();
+        // Odd sizes are kept, only 4:2:0 needs them even
+        let (width, height, stride) = (63u32, 47u32, 256usize);
+        let config = VideoConfig {
+            quality: QualityPreset::Lossless,
+            ..Default::default()
+        };
+        let mut encoder = SoftwareEncoder::new(width, height, SoftwareCodec::Ffv1, config).unwrap();
+        let packets = encoder.output().unwrap();
+        let mut pattern = vec![0u8; stride * height as usize];
+        for (index, byte) in pattern.iter_mut().enumerate() {
+            *byte = (index * 31 % 251) as u8;
+        }
+        encoder
+            .process(RawVideoFrame {
+                data: pattern.clone(),
+                stride: stride as i32,
+                size: pattern.len() as u32,
+                ..RawVideoFrame::bgrx(width, height)
+            })
+            .unwrap();
+        let packet = packets.try_recv().unwrap();
+        assert!(packet.is_keyframe);
+
+        let parameters = ffmpeg::codec::Parameters::from(encoder.get_encoder().as_ref().unwrap());
+        let mut decoder = ffmpeg::codec::Context::from_parameters(parameters)
+            .unwrap()
+            .decoder()
+            .video()
+            .unwrap();
+        decoder
+            .send_packet(&ffmpeg::Packet::copy(&packet.data))
+            .unwrap();
+        let mut decoded = ffmpeg::util::frame::Video::empty();
+        decoder.receive_frame(&mut decoded).unwrap();
+
+        assert_eq!((decoded.width(), decoded.height()), (width, height));
+        let decoded_stride = decoded.stride(0);
+        for row in 0..height as usize {
+            let input = &pattern[row * stride..][..width as usize * 4];
+            let output = &decoded.data(0)[row * decoded_stride..][..width as usize * 4];
+            // The x byte of BGRx carries nothing
+            for (pixel, (input, output)) in input.chunks(4).zip(output.chunks(4)).enumerate() {
+                assert_eq!(input[..3], output[..3], "row {row}, pixel {pixel}");
+            }
+        }
+    }
+
+    #[test]
+    fn lossy_codecs_reject_lossless() {
+        let lossless = QualityPreset::Lossless;
+        assert!(matches!(
+            SoftwareEncoder::get_x264_params(&lossless),
+            Err(WaycapError::Unsupported(_))
+        ));
+        assert!(matches!(
+            SoftwareEncoder::get_svt_params(&lossless),
+            Err(WaycapError::Unsupported(_))
+        ));
+        assert!(matches!(
+            SoftwareEncoder::get_mjpeg_params(&lossless),
+            Err(WaycapError::Unsupported(_))
+        ));
+        assert!(matches!(
+            SoftwareEncoder::get_vp8_params(lossless, 64, 48),
+            Err(WaycapError::Unsupported(_))
+        ));
+        assert!(SoftwareEncoder::get_x264_params(&QualityPreset::Ultra).is_ok());
+    }
+
+    #[test]
+    fn letterbox_bars_decode_as_the_pad_color() {
+        let _runtime = Runtime::acquire().unwrap();
+        let (width, height) = (64u32, 32u32);
+        let config = VideoConfig {
+            quality: QualityPreset::Lossless,
+            output_width: Some(64),
+            output_height: Some(64),
+            pad_color: [255, 0, 0],
+            ..Default::default()
+        };
+        let mut encoder = SoftwareEncoder::new(width, height, SoftwareCodec::Ffv1, config).unwrap();
+        let packets = encoder.output().unwrap();
+        let data = vec![200u8; (width * height * 4) as usize];
+        encoder
+            .process(RawVideoFrame {
+                size: data.len() as u32,
+                data,
+                ..RawVideoFrame::bgrx(width, height)
+            })
+            .unwrap();
+        let packet = packets.try_recv().unwrap();
+
+        let parameters = ffmpeg::codec::Parameters::from(encoder.get_encoder().as_ref().unwrap());
+        let mut decoder = ffmpeg::codec::Context::from_parameters(parameters)
+            .unwrap()
+            .decoder()
+            .video()
+            .unwrap();
+        decoder
+            .send_packet(&ffmpeg::Packet::copy(&packet.data))
+            .unwrap();
+        let mut decoded = ffmpeg::util::frame::Video::empty();
+        decoder.receive_frame(&mut decoded).unwrap();
+
+        assert_eq!((decoded.width(), decoded.height()), (64, 64));
+        let decoded_stride = decoded.stride(0);
+        for row in 0..64 {
+            let pixels = &decoded.data(0)[row * decoded_stride..][..64 * 4];
+            // 16 rows of red above and below the picture, in BGRx
+            let expected = if (16..48).contains(&row) {
+                [200, 200, 200]
+            } else {
+                [0, 0, 255]
+            };
+            for (pixel, output) in pixels.chunks(4).enumerate() {
+                assert_eq!(output[..3], expected, "row {row}, pixel {pixel}");
+            }
+        }
+    }
+
+    #[test]
+    fn transforms_move_the_corner_marker() {
+        let _runtime = Runtime::acquire().unwrap();
+        let (width, height) = (64u32, 32u32);
+        // Gray, with a white 8x8 marker in the top left corner
+        let data: Vec<u8> = (0..width * height)
+            .flat_map(|index| {
+                let value = if index % width < 8 && index / width < 8 {
+                    255
+                } else {
+                    60
+                };
+                [value, value, value, 0]
+            })
+            .collect();
+        // The top left corner of the marker in the decoded frame
+        let marker = |config_transform: Option<Transform>, frame_transform: Transform| {
+            let config = VideoConfig {
+                quality: QualityPreset::Lossless,
+                transform: config_transform,
+                ..Default::default()
+            };
+            let mut encoder =
+                SoftwareEncoder::new(width, height, SoftwareCodec::Ffv1, config).unwrap();
+            let packets = encoder.output().unwrap();
+            encoder
+                .process(RawVideoFrame {
+                    size: data.len() as u32,
+                    data: data.clone(),
+                    transform: frame_transform,
+                    ..RawVideoFrame::bgrx(width, height)
+                })
+                .unwrap();
+            let packet = packets.try_recv().unwrap();
+
+            let parameters =
+                ffmpeg::codec::Parameters::from(encoder.get_encoder().as_ref().unwrap());
+            let mut decoder = ffmpeg::codec::Context::from_parameters(parameters)
+                .unwrap()
+                .decoder()
+                .video()
+                .unwrap();
+            decoder
+                .send_packet(&ffmpeg::Packet::copy(&packet.data))
+                .unwrap();
+            let mut decoded = ffmpeg::util::frame::Video::empty();
+            decoder.receive_frame(&mut decoded).unwrap();
+
+            let stride = decoded.stride(0);
+            let white: Vec<(u32, u32)> = (0..decoded.height())
+                .flat_map(|y| (0..decoded.width()).map(move |x| (x, y)))
+                .filter(|&(x, y)| decoded.data(0)[y as usize * stride + x as usize * 4] == 255)
+                .collect();
+            assert_eq!(white.len(), 64);
+            ((decoded.width(), decoded.height()), white[0])
+        };
+
+        assert_eq!(marker(None, Transform::Normal), ((64, 32), (0, 0)));
+        // Turned counter-clockwise, the top left corner goes to the bottom left
+        assert_eq!(
+            marker(Some(Transform::Rot90), Transform::Normal),
+            ((32, 64), (0, 56))
+        );
+        assert_eq!(
+            marker(Some(Transform::Flipped), Transform::Normal),
+            ((64, 32), (56, 0))
+        );
+        // The compositor's transform is followed unless the config has one
+        assert_eq!(marker(None, Transform::Rot180), ((64, 32), (56, 24)));
+        assert_eq!(
+            marker(Some(Transform::Normal), Transform::Rot180),
+            ((64, 32), (0, 0))
+        );
+    }
+
+    #[test]
+    fn overlays_are_blended_until_removed() {
+        let _runtime = Runtime::acquire().unwrap();
+        let (width, height) = (64u32, 32u32);
+        let config = VideoConfig {
+            quality: QualityPreset::Lossless,
+            // Red at half the opacity, in the bottom right corner
+            overlay: Some(OverlayConfig {
+                image: OverlayImage::Rgba {
+                    width: 8,
+                    height: 8,
+                    data: [255, 0, 0, 255].repeat(64),
+                },
+                position: OverlayPosition::Anchored {
+                    corner: Corner::BottomRight,
+                    margin: 4,
+                },
+                opacity: 0.5,
+            }),
+            ..Default::default()
+        };
+        let mut encoder = SoftwareEncoder::new(width, height, SoftwareCodec::Ffv1, config).unwrap();
+        let packets = encoder.output().unwrap();
+        let parameters = ffmpeg::codec::Parameters::from(encoder.get_encoder().as_ref().unwrap());
+        let mut decoder = ffmpeg::codec::Context::from_parameters(parameters)
+            .unwrap()
+            .decoder()
+            .video()
+            .unwrap();
+        // BGR of the decoded pixels at `points` of a gray frame
+        let mut encode = |encoder: &mut SoftwareEncoder, points: &[(usize, usize)]| {
+            let data = vec![60; (width * height * 4) as usize];
+            encoder
+                .process(RawVideoFrame {
+                    size: data.len() as u32,
+                    data,
+                    ..RawVideoFrame::bgrx(width, height)
+                })
+                .unwrap();
+            let packet = packets.try_recv().unwrap();
+            decoder
+                .send_packet(&ffmpeg::Packet::copy(&packet.data))
+                .unwrap();
+            let mut decoded = ffmpeg::util::frame::Video::empty();
+            decoder.receive_frame(&mut decoded).unwrap();
+            let stride = decoded.stride(0);
+            points
+                .iter()
+                .map(|&(x, y)| {
+                    let pixel = &decoded.data(0)[y * stride + x * 4..];
+                    [pixel[0], pixel[1], pixel[2]]
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // The image covers 52,20 to 59,27, 64 - 8 - 4 and 32 - 8 - 4 being its top left corner
+        let points = [(52, 20), (59, 27), (51, 20), (60, 27), (10, 10)];
+        let (inside, gray) = ([30, 30, 158], [60, 60, 60]);
+        assert_eq!(
+            encode(&mut encoder, &points),
+            [inside, inside, gray, gray, gray]
+        );
+        encoder.set_overlay(None).unwrap();
+        assert_eq!(encode(&mut encoder, &points), [gray; 5]);
+    }
+
+    #[test]
+    fn denoising_lowers_the_bitrate_of_noisy_frames() {
+        let _runtime = Runtime::acquire().unwrap();
+        let (width, height) = (64u32, 48u32);
+        let encoded_bytes = |denoise| {
+            let config = VideoConfig {
+                denoise,
+                ..Default::default()
+            };
+            let mut encoder =
+                SoftwareEncoder::new(width, height, SoftwareCodec::H264, config).unwrap();
+            let packets = encoder.output().unwrap();
+            let mut seed = 1u32;
+            for frame in 0..10i64 {
+                // Gray with a few levels of grain, different in every frame
+                let data: Vec<u8> = (0..width * height * 4)
+                    .map(|_| {
+                        seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                        120 + (seed >> 28) as u8
+                    })
+                    .collect();
+                encoder
+                    .process(RawVideoFrame {
+                        size: data.len() as u32,
+                        data,
+                        timestamp: CaptureTime::from_nanos(frame * 16_666_667),
+                        ..RawVideoFrame::bgrx(width, height)
+                    })
+                    .unwrap();
+            }
+            let packets: Vec<EncodedVideoFrame> = packets.try_iter().collect();
+            assert_eq!(packets.len(), 10);
+            packets
+                .iter()
+                .map(|packet| packet.data.len())
+                .sum::<usize>()
+        };
+        let noisy = encoded_bytes(None);
+        let denoised = encoded_bytes(Some(DenoiseStrength::Strong));
+        assert!(
+            denoised < noisy,
+            "{denoised} bytes denoised, {noisy} without"
+        );
+    }
+
+    #[test]
+    fn constant_rate_repeats_the_last_frame() {
+        let _runtime = Runtime::acquire().unwrap();
+        let config = VideoConfig {
+            cfr: Some(Fps::from(30)),
+            ..Default::default()
+        };
+        let mut encoder = SoftwareEncoder::new(64, 48, SoftwareCodec::H264, config).unwrap();
+        let packets = encoder.output().unwrap();
+        // Nothing to repeat before the first frame
+        assert!(!encoder.repeat_frame(CaptureTime::default(), false).unwrap());
+        encoder
+            .process(RawVideoFrame {
+                data: vec![128; 64 * 48 * 4],
+                ..RawVideoFrame::bgrx(64, 48)
+            })
+            .unwrap();
+        for slot in 1..3 {
+            let time = CaptureTime::from_nanos(slot * 33_333_333);
+            assert!(encoder.repeat_frame(time, slot == 2).unwrap());
+        }
+
+        let packets: Vec<EncodedVideoFrame> = packets.try_iter().collect();
+        let keyframes: Vec<bool> = packets.iter().map(|packet| packet.is_keyframe).collect();
+        assert_eq!(keyframes, [true, false, true]);
+        assert_eq!(
+            packets[2].pts,
+            StreamPts::new(2 * 33_333_333, CaptureTime::TIME_BASE)
+        );
+    }
+
+    #[test]
+    fn frames_carry_the_hdr_metadata_after_a_reset() {
+        let _runtime = Runtime::acquire().unwrap();
+        let config = VideoConfig {
+            // Keeps the frame last handed to the encoder around to look at
+            cfr: Some(Fps::from(30)),
+            hdr_metadata: Some(HdrMetadata {
+                mastering_display: Some(MasteringDisplay::bt2020(0.005, 1000.0)),
+                content_light_level: Some(ContentLightLevel {
+                    max_cll: 1000,
+                    max_fall: 400,
+                }),
+            }),
+            ..Default::default()
+        };
+        let mut encoder = SoftwareEncoder::new(64, 48, SoftwareCodec::H264, config).unwrap();
+        for _ in 0..2 {
+            encoder
+                .process(RawVideoFrame {
+                    data: vec![128; 64 * 48 * 4],
+                    ..RawVideoFrame::bgrx(64, 48)
+                })
+                .unwrap();
+            let frame = encoder.last_frame.as_ref().unwrap();
+            assert!(frame.side_data(Type::MasteringDisplayMetadata).is_some());
+            assert!(frame.side_data(Type::ContentLightLevel).is_some());
+            encoder.reset().unwrap();
+            assert!(encoder.last_frame.is_none());
+        }
+    }
+}
@@ -0,0 +1,365 @@
+//! Typed description of the video streams an encoder accepts, turned into the `EnumFormat` pod
+//! offered to PipeWire by [`super::video::PipewireSPA::get_spa_definition`].
+
+use pipewire::spa::{
+    self,
+    param::{
+        format::{FormatProperties, MediaSubtype, MediaType},
+        video::{VideoFormat, VideoInfoRaw},
+        ParamType,
+    },
+    pod::{deserialize::PodDeserializer, ChoiceValue, Object, Pod, Property, PropertyFlags, Value},
+    utils::{Choice, ChoiceEnum, ChoiceFlags, Fraction, Id, Rectangle, SpaTypes},
+};
+
+/// A range of values with the one the encoder prefers
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct SpaRange<T> {
+    pub default: T,
+    pub min: T,
+    pub max: T,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct VideoFormatOffer {
+    /// Pixel formats, the preferred one first
+    pub formats: Vec<VideoFormat>,
+    /// DRM modifiers of the DMA-BUFs, the preferred one first. Empty for shared memory.
+    pub modifiers: Vec<u64>,
+    pub size_range: SpaRange<Rectangle>,
+    pub framerate_range: SpaRange<Fraction>,
+}
+
+impl VideoFormatOffer {
+    /// Offer `formats` in shared memory for any size up to 4096x4096 and up to 244 fps
+    pub fn new(formats: Vec<VideoFormat>) -> Self {
+        Self {
+            formats,
+            modifiers: Vec::new(),
+            size_range: SpaRange {
+                default: Rectangle {
+                    width: 2560,
+                    height: 1440,
+                },
+                min: Rectangle {
+                    width: 1,
+                    height: 1,
+                },
+                max: Rectangle {
+                    width: 4096,
+                    height: 4096,
+                },
+            },
+            framerate_range: SpaRange {
+                default: Fraction { num: 240, denom: 1 },
+                min: Fraction { num: 0, denom: 1 },
+                max: Fraction { num: 244, denom: 1 },
+            },
+        }
+    }
+
+    pub fn with_modifiers(mut self, modifiers: Vec<u64>) -> Self {
+        self.modifiers = modifiers;
+        self
+    }
+
+    pub fn to_object(&self) -> Object {
+        let mut properties = vec![
+            property(
+                FormatProperties::MediaType,
+                Value::Id(Id(MediaType::Video.as_raw())),
+            ),
+            property(
+                FormatProperties::MediaSubtype,
+                Value::Id(Id(MediaSubtype::Raw.as_raw())),
+            ),
+        ];
+        // A single modifier is offered as a plain value, which is what e.g. the VAAPI path
+        // negotiated before modifier lists
+        match self.modifiers.as_slice() {
+            [] => {}
+            [modifier] => properties.push(property(
+                FormatProperties::VideoModifier,
+                Value::Long(*modifier as i64),
+            )),
+            modifiers => properties.push(property(
+                FormatProperties::VideoModifier,
+                Value::Choice(ChoiceValue::Long(Choice(
+                    ChoiceFlags::empty(),
+                    ChoiceEnum::Enum {
+                        default: modifiers[0] as i64,
+                        alternatives: modifiers.iter().map(|&m| m as i64).collect(),
+                    },
+                ))),
+            )),
+        }
+        if let Some(&preferred) = self.formats.first() {
+            properties.push(property(
+                FormatProperties::VideoFormat,
+                Value::Choice(ChoiceValue::Id(Choice(
+                    ChoiceFlags::empty(),
+                    ChoiceEnum::Enum {
+                        default: Id(preferred.as_raw()),
+                        alternatives: self
+                            .formats
+                            .iter()
+                            .map(|format| Id(format.as_raw()))
+                            .collect(),
+                    },
+                ))),
+            ));
+        }
+        properties.push(size_property(self.size_range));
+        properties.push(framerate_property(self.framerate_range));
+
+        Object {
+            type_: SpaTypes::ObjectParamFormat.as_raw(),
+            id: ParamType::EnumFormat.as_raw(),
+            properties,
+        }
+    }
+}
+
+/// H.264 byte stream with a whole access unit per buffer, for producers which encode on their
+/// side. Offered before the raw formats when passthrough was asked for, with the same size and
+/// framerate ranges.
+pub(crate) fn h264_offer() -> Object {
+    let ranges = VideoFormatOffer::new(Vec::new());
+    Object {
+        type_: SpaTypes::ObjectParamFormat.as_raw(),
+        id: ParamType::EnumFormat.as_raw(),
+        properties: vec![
+            property(
+                FormatProperties::MediaType,
+                Value::Id(Id(MediaType::Video.as_raw())),
+            ),
+            property(
+                FormatProperties::MediaSubtype,
+                Value::Id(Id(MediaSubtype::H264.as_raw())),
+            ),
+            size_property(ranges.size_range),
+            framerate_property(ranges.framerate_range),
+            property(
+                FormatProperties::VideoH264StreamFormat,
+                Value::Id(Id(spa::sys::SPA_H264_STREAM_FORMAT_BYTESTREAM)),
+            ),
+            property(
+                FormatProperties::VideoH264Alignment,
+                Value::Id(Id(spa::sys::SPA_H264_ALIGNMENT_AU)),
+            ),
+        ],
+    }
+}
+
+/// Size and framerate of a negotiated encoded format. Returned as a [`VideoInfoRaw`] with the
+/// format [`VideoFormat::Encoded`], so the capture can track it like a raw one.
+pub(crate) fn encoded_video_info(param: &Pod) -> Option<VideoInfoRaw> {
+    let Ok((_, Value::Object(object))) = PodDeserializer::deserialize_any_from(param.as_bytes())
+    else {
+        return None;
+    };
+    let mut info = VideoInfoRaw::new();
+    info.set_format(VideoFormat::Encoded);
+    for prop in object.properties {
+        match (FormatProperties::from_raw(prop.key), prop.value) {
+            (FormatProperties::VideoSize, Value::Rectangle(size)) => info.set_size(size),
+            (FormatProperties::VideoFramerate, Value::Fraction(framerate)) => {
+                info.set_framerate(framerate)
+            }
+            _ => {}
+        }
+    }
+    (info.size().width > 0 && info.size().height > 0).then_some(info)
+}
+
+fn size_property(size: SpaRange<Rectangle>) -> Property {
+    property(
+        FormatProperties::VideoSize,
+        Value::Choice(ChoiceValue::Rectangle(Choice(
+            ChoiceFlags::empty(),
+            ChoiceEnum::Range {
+                default: size.default,
+                min: size.min,
+                max: size.max,
+            },
+        ))),
+    )
+}
+
+fn framerate_property(framerate: SpaRange<Fraction>) -> Property {
+    property(
+        FormatProperties::VideoFramerate,
+        Value::Choice(ChoiceValue::Fraction(Choice(
+            ChoiceFlags::empty(),
+            ChoiceEnum::Range {
+                default: framerate.default,
+                min: framerate.min,
+                max: framerate.max,
+            },
+        ))),
+    )
+}
+
+fn property(key: FormatProperties, value: Value) -> Property {
+    Property {
+        key: key.as_raw(),
+        flags: PropertyFlags::empty(),
+        value,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use pipewire::spa::pod::serialize::PodSerializer;
+
+    use super::*;
+
+    fn serialize(object: Object) -> Vec<u8> {
+        PodSerializer::serialize(Cursor::new(Vec::new()), &Value::Object(object))
+            .unwrap()
+            .0
+            .into_inner()
+    }
+
+    /// Serialize `offer` into pod bytes like the stream does, and read the offer back from them
+    fn round_trip(offer: &VideoFormatOffer) -> VideoFormatOffer {
+        let bytes = serialize(offer.to_object());
+        let (rest, value) = PodDeserializer::deserialize_any_from(&bytes).unwrap();
+        assert!(rest.is_empty());
+        let Value::Object(object) = value else {
+            panic!("Not an object: {value:?}");
+        };
+        assert_eq!(object.type_, SpaTypes::ObjectParamFormat.as_raw());
+        assert_eq!(object.id, ParamType::EnumFormat.as_raw());
+        parse(&object)
+    }
+
+    fn parse(object: &Object) -> VideoFormatOffer {
+        let mut offer = VideoFormatOffer::new(Vec::new());
+        for prop in &object.properties {
+            match (FormatProperties::from_raw(prop.key), &prop.value) {
+                (FormatProperties::MediaType, Value::Id(id)) => {
+                    assert_eq!(*id, Id(MediaType::Video.as_raw()))
+                }
+                (FormatProperties::MediaSubtype, Value::Id(id)) => {
+                    assert_eq!(*id, Id(MediaSubtype::Raw.as_raw()))
+                }
+                (FormatProperties::VideoModifier, Value::Long(modifier)) => {
+                    offer.modifiers = vec![*modifier as u64]
+                }
+                (
+                    FormatProperties::VideoModifier,
+                    Value::Choice(ChoiceValue::Long(Choice(
+                        _,
+                        ChoiceEnum::Enum {
+                            default,
+                            alternatives,
+                        },
+                    ))),
+                ) => {
+                    assert_eq!(Some(default), alternatives.first());
+                    offer.modifiers = alternatives.iter().map(|&m| m as u64).collect();
+                }
+                (
+                    FormatProperties::VideoFormat,
+                    Value::Choice(ChoiceValue::Id(Choice(
+                        _,
+                        ChoiceEnum::Enum {
+                            default,
+                            alternatives,
+                        },
+                    ))),
+                ) => {
+                    assert_eq!(Some(default), alternatives.first());
+                    offer.formats = alternatives
+                        .iter()
+                        .map(|id| VideoFormat::from_raw(id.0))
+                        .collect();
+                }
+                (
+                    FormatProperties::VideoSize,
+                    Value::Choice(ChoiceValue::Rectangle(Choice(
+                        _,
+                        ChoiceEnum::Range { default, min, max },
+                    ))),
+                ) => {
+                    offer.size_range = SpaRange {
+                        default: *default,
+                        min: *min,
+                        max: *max,
+                    }
+                }
+                (
+                    FormatProperties::VideoFramerate,
+                    Value::Choice(ChoiceValue::Fraction(Choice(
+                        _,
+                        ChoiceEnum::Range { default, min, max },
+                    ))),
+                ) => {
+                    offer.framerate_range = SpaRange {
+                        default: *default,
+                        min: *min,
+                        max: *max,
+                    }
+                }
+                (key, value) => panic!("Unexpected property {key:?}: {value:?}"),
+            }
+        }
+        offer
+    }
+
+    #[test]
+    fn shared_memory_offer_round_trips() {
+        let offer = VideoFormatOffer::new(vec![VideoFormat::BGRA, VideoFormat::BGRx]);
+        assert_eq!(round_trip(&offer), offer);
+    }
+
+    #[test]
+    fn modifier_offers_round_trip() {
+        let single = VideoFormatOffer::new(vec![VideoFormat::NV12, VideoFormat::BGRx])
+            .with_modifiers(vec![0]);
+        assert_eq!(round_trip(&single), single);
+
+        let mut list = VideoFormatOffer::new(vec![VideoFormat::BGRx])
+            .with_modifiers(vec![216172782120099856, 72057594037927935]);
+        list.size_range.max = Rectangle {
+            width: 7680,
+            height: 4320,
+        };
+        list.framerate_range.default = Fraction { num: 60, denom: 1 };
+        assert_eq!(round_trip(&list), list);
+    }
+
+    #[test]
+    fn reads_negotiated_h264_format() {
+        // What the producer fixates the H.264 offer to
+        let mut format = h264_offer();
+        format.id = ParamType::Format.as_raw();
+        for prop in &mut format.properties {
+            match FormatProperties::from_raw(prop.key) {
+                FormatProperties::VideoSize => {
+                    prop.value = Value::Rectangle(Rectangle {
+                        width: 1920,
+                        height: 1080,
+                    })
+                }
+                FormatProperties::VideoFramerate => {
+                    prop.value = Value::Fraction(Fraction { num: 60, denom: 1 })
+                }
+                _ => {}
+            }
+        }
+        let bytes = serialize(format);
+
+        let info = encoded_video_info(Pod::from_bytes(&bytes).unwrap()).unwrap();
+        assert_eq!(info.format(), VideoFormat::Encoded);
+        assert_eq!((info.size().width, info.size().height), (1920, 1080));
+        assert_eq!(info.framerate(), Fraction { num: 60, denom: 1 });
+
+        // The unfixated offer has no size yet
+        let bytes = serialize(h264_offer());
+        assert!(encoded_video_info(Pod::from_bytes(&bytes).unwrap()).is_none());
+    }
+}
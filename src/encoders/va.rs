@@ -0,0 +1,121 @@
+//! Asks the VAAPI driver behind an ffmpeg device through libva what it encodes with, which
+//! ffmpeg only finds out when an encoder fails to open.
+
+use std::ffi::{c_char, c_int, c_uint, c_void, CStr};
+
+use ffmpeg_next::ffi::AVHWDeviceContext;
+
+use crate::{types::config::RateControlMode, waycap_egl::GpuVendor};
+
+use super::video::HwBufferRef;
+
+pub(crate) type VaProfile = c_int;
+pub(crate) const PROFILE_H264_MAIN: VaProfile = 6;
+pub(crate) const PROFILE_H264_HIGH: VaProfile = 7;
+pub(crate) const PROFILE_JPEG_BASELINE: VaProfile = 12;
+pub(crate) const PROFILE_H264_CONSTRAINED_BASELINE: VaProfile = 13;
+pub(crate) const PROFILE_HEVC_MAIN: VaProfile = 17;
+pub(crate) const PROFILE_HEVC_MAIN10: VaProfile = 18;
+pub(crate) const PROFILE_VP9_PROFILE0: VaProfile = 19;
+pub(crate) const PROFILE_AV1_PROFILE0: VaProfile = 32;
+
+pub(crate) type VaEntrypoint = c_int;
+pub(crate) const ENTRYPOINT_ENC_SLICE: VaEntrypoint = 6;
+pub(crate) const ENTRYPOINT_ENC_PICTURE: VaEntrypoint = 7;
+pub(crate) const ENTRYPOINT_ENC_SLICE_LP: VaEntrypoint = 8;
+
+const VA_STATUS_SUCCESS: c_int = 0;
+const VA_CONFIG_ATTRIB_RATE_CONTROL: c_int = 5;
+const VA_ATTRIB_NOT_SUPPORTED: c_uint = 0x8000_0000;
+
+/// `VA_RC_*` bits of the modes ffmpeg's `rc_mode` selects
+const RATE_CONTROL_BITS: [(c_uint, RateControlMode); 5] = [
+    (0x10, RateControlMode::Cqp),
+    (0x04, RateControlMode::Vbr),
+    (0x02, RateControlMode::Cbr),
+    (0x40, RateControlMode::Icq),
+    (0x400, RateControlMode::Qvbr),
+];
+
+/// The `hwctx` of a VAAPI device, up to the display
+#[repr(C)]
+struct AVVAAPIDeviceContext {
+    display: *mut c_void,
+}
+
+#[repr(C)]
+struct VAConfigAttrib {
+    // Only read by libva
+    #[allow(dead_code)]
+    kind: c_int,
+    value: c_uint,
+}
+
+type GetConfigAttributes = unsafe extern "C" fn(
+    display: *mut c_void,
+    profile: VaProfile,
+    entrypoint: VaEntrypoint,
+    attribs: *mut VAConfigAttrib,
+    count: c_int,
+) -> c_int;
+type QueryVendorString = unsafe extern "C" fn(display: *mut c_void) -> *const c_char;
+
+/// Rate control modes a driver advertises for an encoder
+pub(crate) struct DriverRateControl {
+    /// e.g. `Intel iHD driver for Intel(R) Gen Graphics - 24.1.0`
+    pub vendor: String,
+    /// GPU vendor the driver names
+    pub gpu: GpuVendor,
+    pub modes: Vec<RateControlMode>,
+}
+
+/// Rate control modes the driver of `device` has for `profile`, at the first of `entrypoints`
+/// it encodes it with. None when libva can't be loaded or none of them encodes the profile,
+/// opening the encoder tells then.
+pub(crate) fn rate_control_modes(
+    device: &HwBufferRef,
+    profile: VaProfile,
+    entrypoints: &[VaEntrypoint],
+) -> Option<DriverRateControl> {
+    // ffmpeg loaded it for the device, this only takes another reference
+    let libva = unsafe { libloading::Library::new("libva.so.2") }.ok()?;
+    unsafe {
+        let get_config_attributes = libva
+            .get::<GetConfigAttributes>(b"vaGetConfigAttributes\0")
+            .ok()?;
+        let query_vendor_string = libva
+            .get::<QueryVendorString>(b"vaQueryVendorString\0")
+            .ok()?;
+        let device_ctx = &*((*device.as_ptr()).data as *const AVHWDeviceContext);
+        let display = (*(device_ctx.hwctx as *const AVVAAPIDeviceContext)).display;
+
+        let supported = entrypoints.iter().find_map(|&entrypoint| {
+            let mut attrib = VAConfigAttrib {
+                kind: VA_CONFIG_ATTRIB_RATE_CONTROL,
+                value: 0,
+            };
+            let status = get_config_attributes(display, profile, entrypoint, &mut attrib, 1);
+            (status == VA_STATUS_SUCCESS && attrib.value != VA_ATTRIB_NOT_SUPPORTED)
+                .then_some(attrib.value)
+        })?;
+        let vendor = query_vendor_string(display);
+        let (vendor, gpu) = if vendor.is_null() {
+            ("unknown".to_string(), GpuVendor::UNKNOWN)
+        } else {
+            let vendor = CStr::from_ptr(vendor);
+            (
+                vendor.to_string_lossy().into_owned(),
+                GpuVendor::from(vendor),
+            )
+        };
+        Some(DriverRateControl {
+            vendor,
+            gpu,
+            modes: RATE_CONTROL_BITS
+                .iter()
+                .filter(|(bit, _)| supported & bit != 0)
+                .map(|&(_, mode)| mode)
+                .collect(),
+        })
+    }
+}
@@ -1,159 +1,630 @@
-use std::ptr::null_mut;
+use std::{ops::RangeInclusive, ptr::null_mut, sync::Arc};
 
 use crate::{
-    encoders::video::{PipewireSPA, ProcessingThread, VideoEncoder},
+    capture::pip::PipFrames,
+    dmabuf_probe::importable_modifiers,
+    encoders::video::{PipewireSPA, ProcessingThread, VideoEncoder, OUTPUT_CAPACITY},
+    failure_injection, ffmpeg_compat,
+    pipeline::fanout::{Delivery, FanOut},
     types::{
-        config::QualityPreset,
+        config::{
+            DenoiseStrength, H264Profile, OverlayConfig, QualityPreset, RateControl,
+            RateControlMode, Rect, VaapiImport, VideoConfig,
+        },
+        encoder_info::EncoderInfo,
         error::{Result, WaycapError},
+        event::PipelineStage,
+        stats::StatsCounters,
+        time::{CaptureTime, StreamPts},
         video_frame::{EncodedVideoFrame, RawVideoFrame},
     },
-    utils::TIME_UNIT_NS,
+    utils::{extract_dmabuf_planes, DmaBufMapping},
+    waycap_egl::GpuVendor,
 };
-use crossbeam::channel::{bounded, Receiver, Sender};
+use crossbeam::channel::Receiver;
 use drm_fourcc::DrmFourcc;
 use ffmpeg_next::{
     self as ffmpeg,
     ffi::{
-        av_buffer_create, av_buffer_default_free, av_buffer_ref, av_buffer_unref,
-        av_hwframe_ctx_init, AVDRMFrameDescriptor, AVHWDeviceContext, AVHWFramesContext,
-        AVPixelFormat,
+        av_buffer_ref, av_hwframe_ctx_init, av_hwframe_get_buffer, av_hwframe_transfer_data,
+        AVHWFramesContext, AVPixelFormat,
     },
-    Rational,
+    format::Pixel,
 };
-use pipewire as pw;
+use pipewire::{self as pw, spa::param::video::VideoFormat};
 
-use super::video::{create_hw_device, create_hw_frame_ctx, GOP_SIZE};
+use super::{
+    av1,
+    drm::{check_fds, dmabuf_layout, DrmDescriptorBuilder},
+    h264::ParameterSets,
+    hdr::attach_hdr_side_data,
+    overlay::Overlay,
+    pip,
+    spa_format::VideoFormatOffer,
+    va::{self, DriverRateControl, VaEntrypoint, VaProfile},
+    video::{
+        add_crop_filter, add_denoise_filter, add_pad_filter, add_transpose_filter,
+        changed_frame_transform, create_hw_device, create_hw_frame_ctx, ffmpeg_color_range,
+        link_filters_with_custom, lossless_unsupported, open_configured_encoder, resend_frame,
+        scale_color_args, set_bitrate_options, set_colorimetry, validate_graph,
+        with_denoise_fallback, GOP_SIZE, SCHEDULED_GOP_SIZE,
+    },
+};
+
+/// Codecs encoded through VAAPI
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum VaapiCodec {
+    H264,
+    /// Needs an AMD GPU from Polaris or an Intel GPU from Skylake on
+    Hevc,
+    /// Needs an AMD RDNA3 or Intel Arc GPU and ffmpeg 6.1
+    Av1,
+    /// Needs an Intel GPU from Kaby Lake on, AMD's VCN only decodes VP9
+    Vp9,
+    /// Every frame a JPEG, on Intel GPUs
+    Mjpeg,
+}
+
+impl VaapiCodec {
+    pub fn encoder_name(self) -> &'static str {
+        match self {
+            VaapiCodec::H264 => "h264_vaapi",
+            VaapiCodec::Hevc => "hevc_vaapi",
+            VaapiCodec::Av1 => "av1_vaapi",
+            VaapiCodec::Vp9 => "vp9_vaapi",
+            VaapiCodec::Mjpeg => "mjpeg_vaapi",
+        }
+    }
+
+    /// The GPUs able to encode the codec, for errors where the driver can't
+    fn hardware(self) -> &'static str {
+        match self {
+            VaapiCodec::H264 => "any VAAPI capable GPU",
+            VaapiCodec::Hevc => "an AMD GPU from Polaris or an Intel GPU from Skylake on",
+            VaapiCodec::Av1 => "an AMD RDNA3 or Intel Arc GPU",
+            VaapiCodec::Vp9 => "an Intel GPU from Kaby Lake on",
+            VaapiCodec::Mjpeg => "an Intel GPU",
+        }
+    }
+
+    /// The VA profile ffmpeg encodes with and the entrypoints it tries for it
+    fn va_profile(self, config: &VideoConfig) -> (VaProfile, &'static [VaEntrypoint]) {
+        const SLICE: &[VaEntrypoint] = &[va::ENTRYPOINT_ENC_SLICE, va::ENTRYPOINT_ENC_SLICE_LP];
+        match self {
+            VaapiCodec::H264 => (
+                match config.h264_profile {
+                    Some(H264Profile::ConstrainedBaseline) => va::PROFILE_H264_CONSTRAINED_BASELINE,
+                    Some(H264Profile::Main) => va::PROFILE_H264_MAIN,
+                    Some(H264Profile::High) | None => va::PROFILE_H264_HIGH,
+                },
+                SLICE,
+            ),
+            // ffmpeg picks the profile from the bit depth of the frames
+            VaapiCodec::Hevc if config.ten_bit => (va::PROFILE_HEVC_MAIN10, SLICE),
+            VaapiCodec::Hevc => (va::PROFILE_HEVC_MAIN, SLICE),
+            // Main profile, 10 bit included
+            VaapiCodec::Av1 => (va::PROFILE_AV1_PROFILE0, SLICE),
+            VaapiCodec::Vp9 => (va::PROFILE_VP9_PROFILE0, SLICE),
+            VaapiCodec::Mjpeg => (va::PROFILE_JPEG_BASELINE, &[va::ENTRYPOINT_ENC_PICTURE]),
+        }
+    }
+
+    /// Quantizers the encoder takes, the JPEG quality for MJPEG
+    fn qp_range(self) -> RangeInclusive<u32> {
+        match self {
+            VaapiCodec::H264 | VaapiCodec::Hevc => 0..=51,
+            VaapiCodec::Av1 | VaapiCodec::Vp9 => 0..=255,
+            VaapiCodec::Mjpeg => 1..=100,
+        }
+    }
+}
+
+/// RGB layouts compositors send, with the DRM fourcc their DMA-BUFs are imported as and the
+/// format the filter graph takes them in. DRM names the channels of a little endian word from
+/// the top, SPA and ffmpeg in memory order, so BGRA is ARGB8888. ffmpeg has no 10 bit formats
+/// with alpha, the alpha of the frames isn't encoded anyway.
+const RGB_FORMATS: [(VideoFormat, DrmFourcc, Pixel); 8] = [
+    (VideoFormat::BGRA, DrmFourcc::Argb8888, Pixel::BGRA),
+    (VideoFormat::BGRx, DrmFourcc::Xrgb8888, Pixel::BGRZ),
+    (VideoFormat::RGBA, DrmFourcc::Abgr8888, Pixel::RGBA),
+    (VideoFormat::RGBx, DrmFourcc::Xbgr8888, Pixel::RGBZ),
+    (
+        VideoFormat::xRGB_210LE,
+        DrmFourcc::Xrgb2101010,
+        Pixel::X2RGB10LE,
+    ),
+    (
+        VideoFormat::xBGR_210LE,
+        DrmFourcc::Xbgr2101010,
+        Pixel::X2BGR10LE,
+    ),
+    (
+        VideoFormat::ARGB_210LE,
+        DrmFourcc::Argb2101010,
+        Pixel::X2RGB10LE,
+    ),
+    (
+        VideoFormat::ABGR_210LE,
+        DrmFourcc::Abgr2101010,
+        Pixel::X2BGR10LE,
+    ),
+];
+
+/// DRM fourcc and filter graph format of the RGB `format`
+fn rgb_format(format: VideoFormat) -> Result<(DrmFourcc, Pixel)> {
+    RGB_FORMATS
+        .iter()
+        .find(|&&(rgb, ..)| rgb == format)
+        .map(|&(_, fourcc, pixel)| (fourcc, pixel))
+        .ok_or_else(|| {
+            WaycapError::Unsupported(format!(
+                "The VAAPI filter graph can't take {format:?} frames"
+            ))
+        })
+}
+
+/// DRM fourcc the DMA-BUFs of `format` are imported as
+fn drm_fourcc(format: VideoFormat) -> Result<DrmFourcc> {
+    match format {
+        VideoFormat::NV12 => Ok(DrmFourcc::Nv12),
+        VideoFormat::I420 => Ok(DrmFourcc::Yuv420),
+        format => rgb_format(format).map(|(fourcc, _)| fourcc),
+    }
+}
+
+/// Format the filter graph of a VAAPI or QSV encoder takes frames of `format` in
+fn graph_pixel(format: VideoFormat) -> Result<Pixel> {
+    match format {
+        VideoFormat::NV12 => Ok(Pixel::NV12),
+        VideoFormat::I420 => Ok(Pixel::YUV420P),
+        format => rgb_format(format).map(|(_, pixel)| pixel),
+    }
+}
+
+/// `format` as the filter graph of a VAAPI or QSV encoder takes it, for the `pix_fmt` of its
+/// buffer source
+pub(crate) fn graph_input_format(format: VideoFormat) -> Result<&'static str> {
+    let pixel = graph_pixel(format)?;
+    pixel
+        .descriptor()
+        .map(|descriptor| descriptor.name())
+        .ok_or_else(|| WaycapError::Init(format!("ffmpeg doesn't know {pixel:?}")))
+}
+
+/// Format `scale_vaapi` converts frames of `input` to for the encoder, `None` for NV12 frames,
+/// which the 8 bit encoders take as they are
+pub(crate) fn scale_format(input: VideoFormat, ten_bit: bool) -> Option<&'static str> {
+    match input {
+        _ if ten_bit => Some("p010"),
+        VideoFormat::NV12 => None,
+        _ => Some("nv12"),
+    }
+}
+
+/// Format the filter graph of an encoder for `config` expects before the first frame tells
+pub(crate) fn default_input_format(config: &VideoConfig) -> VideoFormat {
+    if config.ten_bit {
+        VideoFormat::xRGB_210LE
+    } else {
+        VideoFormat::BGRA
+    }
+}
+
+/// `frame` as a `width`x`height` DRM PRIME frame for `hwmap` to map onto the surfaces of
+/// `encoder`, with a plane in its layer for each plane of the frame and an object for each of
+/// its buffers. The DMA-BUFs stay open until the driver is done with the DRM PRIME frame.
+pub(crate) fn drm_prime_frame(
+    frame: &RawVideoFrame,
+    (width, height): (u32, u32),
+    encoder: &ffmpeg::codec::encoder::Video,
+) -> Result<ffmpeg::util::frame::Video> {
+    let (objects, layer) = dmabuf_layout(&extract_dmabuf_planes(frame)?, frame.modifier);
+    let mut drm_frame =
+        ffmpeg::util::frame::Video::new(ffmpeg::format::Pixel::DRM_PRIME, width, height);
+    objects
+        .into_iter()
+        .fold(DrmDescriptorBuilder::new(), DrmDescriptorBuilder::object)
+        .layer(drm_fourcc(frame.format)?, &layer)
+        .keep_open(&frame.owned_fds)
+        .build()?
+        .attach(&mut drm_frame)?;
+    unsafe {
+        (*drm_frame.as_mut_ptr()).hw_frames_ctx = av_buffer_ref((*encoder.as_ptr()).hw_frames_ctx);
+    }
+    Ok(drm_frame)
+}
+
+/// Whether `frame` has to be dropped because one of its DMA-BUF fds is no longer open, which the
+/// driver would fault on. The next frames come in other buffers, so the capture goes on.
+pub(crate) fn has_closed_dmabufs(frame: &RawVideoFrame, stats: &StatsCounters) -> bool {
+    let checked = extract_dmabuf_planes(frame)
+        .and_then(|planes| check_fds(planes.iter().map(|plane| plane.fd)));
+    match checked {
+        Ok(()) => false,
+        Err(e) => {
+            log::warn!("Dropping a frame: {e}");
+            stats.mark_encoder_dropped();
+            stats.record_error(PipelineStage::EncoderInput, &e);
+            true
+        }
+    }
+}
+
+/// Whether `format` keeps the 10 bits [`VideoConfig::ten_bit`] asks for
+pub(crate) fn is_ten_bit(format: VideoFormat) -> bool {
+    rgb_format(format).is_ok_and(|(_, pixel)| matches!(pixel, Pixel::X2RGB10LE | Pixel::X2BGR10LE))
+}
 
 /// Encoder which encodes frames using Vaapi
 pub struct VaapiEncoder {
     encoder: Option<ffmpeg::codec::encoder::Video>,
     width: u32,
     height: u32,
-    encoder_name: String,
-    quality: QualityPreset,
-    encoded_frame_recv: Option<Receiver<EncodedVideoFrame>>,
-    encoded_frame_sender: Sender<EncodedVideoFrame>,
+    codec: VaapiCodec,
+    config: VideoConfig,
+    rejected_options: Vec<String>,
+    output: FanOut<EncodedVideoFrame>,
+    stats: Arc<StatsCounters>,
     filter_graph: Option<ffmpeg::filter::Graph>,
+    /// The last filtered frame, kept with [`VideoConfig::cfr`] or
+    /// [`VideoConfig::timelapse`] to repeat it
+    last_frame: Option<ffmpeg::util::frame::Video>,
+    /// Frames of the [`VideoConfig::pip`] source, a transparent frame is blended in without
+    pip_frames: Option<Arc<PipFrames>>,
+    parameter_sets: ParameterSets,
+    /// The frames are copied through the CPU instead of mapped, see [`VideoConfig::vaapi_import`]
+    uploads_frames: bool,
+    /// Format of the frames the filter graph was built for
+    input_format: VideoFormat,
+    /// [`VideoConfig::transform`] was left to the compositor, the config holds the one of the
+    /// last frame then
+    follows_compositor_transform: bool,
 }
 
 impl ProcessingThread for VaapiEncoder {
     fn process(&mut self, frame: RawVideoFrame) -> Result<()> {
-        if let Some(ref mut encoder) = self.encoder {
-            if let Some(fd) = frame.dmabuf_fd {
-                let mut drm_frame = ffmpeg::util::frame::Video::new(
-                    ffmpeg_next::format::Pixel::DRM_PRIME,
-                    encoder.width(),
-                    encoder.height(),
-                );
-                unsafe {
-                    // Create DRM descriptor that points to the DMA buffer
-                    let drm_desc =
-                        Box::into_raw(Box::new(std::mem::zeroed::<AVDRMFrameDescriptor>()));
-
-                    (*drm_desc).nb_objects = 1;
-                    (*drm_desc).objects[0].fd = fd;
-                    (*drm_desc).objects[0].size = 0;
-                    (*drm_desc).objects[0].format_modifier = 0;
-
-                    (*drm_desc).nb_layers = 1;
-                    (*drm_desc).layers[0].format = DrmFourcc::Argb8888 as u32;
-                    (*drm_desc).layers[0].nb_planes = 1;
-                    (*drm_desc).layers[0].planes[0].object_index = 0;
-                    (*drm_desc).layers[0].planes[0].offset = frame.offset as isize;
-                    (*drm_desc).layers[0].planes[0].pitch = frame.stride as isize;
-
-                    // Attach descriptor to frame
-                    (*drm_frame.as_mut_ptr()).data[0] = drm_desc as *mut u8;
-                    (*drm_frame.as_mut_ptr()).buf[0] = av_buffer_create(
-                        drm_desc as *mut u8,
-                        std::mem::size_of::<AVDRMFrameDescriptor>(),
-                        Some(av_buffer_default_free),
-                        null_mut(),
-                        0,
+        if let Some(transform) =
+            changed_frame_transform(&self.config, self.follows_compositor_transform, &frame)
+        {
+            self.reopen(VideoConfig {
+                transform: Some(transform),
+                ..self.config.clone()
+            })?;
+        }
+        if self.encoder.is_some() && frame.dmabuf_fd.is_some() {
+            if has_closed_dmabufs(&frame, &self.stats) {
+                self.emit_packets();
+                return Ok(());
+            }
+            self.set_input_format(frame.format)?;
+            let filtered = match self.filter(&frame) {
+                Err(e) if !self.uploads_frames && self.config.vaapi_import == VaapiImport::Auto => {
+                    log::warn!(
+                        "The VAAPI driver could not map a frame ({e}), copying the frames through \
+                         the CPU from now on, which is slower"
                     );
-
-                    (*drm_frame.as_mut_ptr()).hw_frames_ctx =
-                        av_buffer_ref((*encoder.as_ptr()).hw_frames_ctx);
+                    self.uploads_frames = true;
+                    self.stats.mark_cpu_upload();
+                    self.filter_graph = Some(Self::create_filter_graph(
+                        self.encoder.as_ref().unwrap(),
+                        self.width,
+                        self.height,
+                        &self.config,
+                        self.input_format,
+                        true,
+                    )?);
+                    self.filter(&frame)?
                 }
-
-                drm_frame.set_pts(Some(frame.timestamp));
-                self.filter_graph
-                    .as_mut()
-                    .unwrap()
-                    .get("in")
-                    .unwrap()
-                    .source()
-                    .add(&drm_frame)
-                    .unwrap();
-
-                let mut filtered = ffmpeg::util::frame::Video::empty();
-                if self
-                    .filter_graph
-                    .as_mut()
-                    .unwrap()
-                    .get("out")
-                    .unwrap()
-                    .sink()
-                    .frame(&mut filtered)
-                    .is_ok()
-                {
-                    encoder.send_frame(&filtered)?;
+                result => result?,
+            };
+            if let (Some(encoder), Some(mut filtered)) = (self.encoder.as_mut(), filtered) {
+                if let (VaapiCodec::Hevc, Some(hdr)) = (self.codec, &self.config.hdr_metadata) {
+                    attach_hdr_side_data(&mut filtered, hdr);
+                }
+                if frame.force_keyframe {
+                    filtered.set_kind(ffmpeg::picture::Type::I);
+                }
+                failure_injection::send_frame(encoder, &filtered)?;
+                if self.config.repeats_frames() {
+                    self.last_frame = Some(filtered);
                 }
-            }
-
-            let mut packet = ffmpeg::codec::packet::Packet::empty();
-            if encoder.receive_packet(&mut packet).is_ok() {
-                if let Some(data) = packet.data() {
-                    match self.encoded_frame_sender.try_send(EncodedVideoFrame {
-                        data: data.to_vec(),
-                        is_keyframe: packet.is_key(),
-                        pts: packet.pts().unwrap_or(0),
-                        dts: packet.dts().unwrap_or(0),
-                    }) {
-                        Ok(_) => {}
-                        Err(crossbeam::channel::TrySendError::Full(_)) => {
-                            log::error!("Could not send encoded video frame. Receiver is full");
-                        }
-                        Err(crossbeam::channel::TrySendError::Disconnected(_)) => {
-                            log::error!(
-                                "Could not send encoded video frame. Receiver disconnected"
-                            );
-                        }
-                    }
-                };
             }
         }
+        self.emit_packets();
         Ok(())
     }
+
+    fn repeat_frame(&mut self, timestamp: CaptureTime, force_keyframe: bool) -> Result<bool> {
+        let (Some(encoder), Some(frame)) = (self.encoder.as_mut(), self.last_frame.as_mut()) else {
+            return Ok(false);
+        };
+        // Still a reference to the surface the filters wrote
+        resend_frame(encoder, frame, timestamp, force_keyframe)?;
+        self.emit_packets();
+        Ok(true)
+    }
 }
 
 impl VideoEncoder for VaapiEncoder {
     type Output = EncodedVideoFrame;
     fn reset(&mut self) -> Result<()> {
         self.drop_processor();
-        let new_encoder =
-            Self::create_encoder(self.width, self.height, &self.encoder_name, &self.quality)?;
+        let (width, height) = self.config.encoded_size(self.width, self.height)?;
+        let (new_encoder, rejected_options) =
+            Self::create_encoder(width, height, self.codec, &self.config)?;
 
-        let new_filter_graph = Self::create_filter_graph(&new_encoder, self.width, self.height)?;
+        let new_filter_graph = Self::create_filter_graph(
+            &new_encoder,
+            self.width,
+            self.height,
+            &self.config,
+            self.input_format,
+            self.uploads_frames,
+        )?;
 
         self.encoder = Some(new_encoder);
+        self.rejected_options = rejected_options;
         self.filter_graph = Some(new_filter_graph);
         Ok(())
     }
 
+    fn source_changed(&mut self, width: u32, height: u32) -> Result<()> {
+        // Fails before draining when the crop lies outside the new source
+        self.config.encoded_size(width, height)?;
+        self.drain()?;
+        self.width = width;
+        self.height = height;
+        self.reset()
+    }
+
     fn drop_processor(&mut self) {
         self.encoder.take();
         self.filter_graph.take();
+        self.last_frame.take();
     }
 
     fn output(&mut self) -> Option<Receiver<EncodedVideoFrame>> {
-        self.encoded_frame_recv.clone()
+        Some(self.output.subscribe())
+    }
+
+    fn has_consumers(&self) -> bool {
+        self.output.has_subscribers()
     }
 
     /// Drain the filter graph and encoder of any remaining frames it is processing
     fn drain(&mut self) -> Result<()> {
+        self.flush()?;
+        if let Some(ref mut encoder) = self.encoder {
+            let mut packet = ffmpeg::codec::packet::Packet::empty();
+            while encoder.receive_packet(&mut packet).is_ok() {} // Discard these frames
+        }
+        Ok(())
+    }
+
+    /// The drivers take the rate control once, when the encoder opens, so a new bitrate needs
+    /// a new encoder
+    fn set_bitrate(&mut self, bits_per_sec: u64) -> Result<()> {
+        let mut config = self.config.clone();
+        config.set_bitrate(bits_per_sec)?;
+        self.reopen(config)
+    }
+
+    fn change_quality(&mut self, quality: QualityPreset) -> Result<()> {
+        let mut config = self.config.clone();
+        config.set_quality(quality)?;
+        self.reopen(config)
+    }
+
+    /// Moving the crop only rebuilds the filter graph, a new size reopens the encoder after
+    /// encoding the frames in flight
+    fn set_crop(&mut self, crop: Option<Rect>) -> Result<()> {
+        let mut config = self.config.clone();
+        config.set_crop(crop)?;
+        let size = config.encoded_size(self.width, self.height)?;
+        match self.encoder {
+            Some(ref encoder) if (encoder.width(), encoder.height()) == size => {
+                self.filter_graph = Some(Self::create_filter_graph(
+                    encoder,
+                    self.width,
+                    self.height,
+                    &config,
+                    self.input_format,
+                    self.uploads_frames,
+                )?);
+                self.config = config;
+                Ok(())
+            }
+            _ => self.reopen(config),
+        }
+    }
+
+    /// A new overlay only rebuilds the filter graph
+    fn set_overlay(&mut self, overlay: Option<OverlayConfig>) -> Result<()> {
+        let mut config = self.config.clone();
+        config.set_overlay(overlay)?;
+        if let Some(ref encoder) = self.encoder {
+            self.filter_graph = Some(Self::create_filter_graph(
+                encoder,
+                self.width,
+                self.height,
+                &config,
+                self.input_format,
+                self.uploads_frames,
+            )?);
+        }
+        self.config = config;
+        Ok(())
+    }
+
+    fn get_encoder(&self) -> &Option<ffmpeg::codec::encoder::Video> {
+        &self.encoder
+    }
+
+    fn info(&self) -> Option<EncoderInfo> {
+        Some(EncoderInfo {
+            name: self.codec.encoder_name().to_string(),
+            rejected_options: self.rejected_options.clone(),
+            ..Default::default()
+        })
+    }
+}
+
+impl PipewireSPA for VaapiEncoder {
+    fn get_spa_definition() -> Result<pw::spa::pod::Object> {
+        Ok(VideoFormatOffer::new(vec![
+            VideoFormat::NV12,
+            VideoFormat::I420,
+            VideoFormat::BGRA,
+            VideoFormat::BGRx,
+            VideoFormat::RGBA,
+            VideoFormat::RGBx,
+        ])
+        .with_modifiers(importable_modifiers(DrmFourcc::Argb8888))
+        .to_object())
+    }
+}
+
+impl VaapiEncoder {
+    /// Offer for [`VideoConfig::ten_bit`] captures. The 8 bit formats stay as fallback so a
+    /// compositor without 10 bit buffers still negotiates, and the capture can tell why it
+    /// fails instead of timing out.
+    pub(crate) fn ten_bit_spa_definition() -> pw::spa::pod::Object {
+        let formats = RGB_FORMATS
+            .iter()
+            .map(|&(format, ..)| format)
+            .filter(|&format| is_ten_bit(format))
+            .chain([VideoFormat::BGRA, VideoFormat::BGRx])
+            .collect();
+        VideoFormatOffer::new(formats)
+            .with_modifiers(importable_modifiers(DrmFourcc::Xrgb2101010))
+            .to_object()
+    }
+
+    pub(crate) fn set_stats(&mut self, stats: Arc<StatsCounters>) {
+        if self.uploads_frames {
+            stats.mark_cpu_upload();
+        }
+        self.stats = stats;
+    }
+
+    /// Blend the frames of `pip_frames` in for [`VideoConfig::pip`]
+    pub(crate) fn set_pip_frames(&mut self, pip_frames: Arc<PipFrames>) {
+        self.pip_frames = Some(pip_frames);
+    }
+
+    /// Output queues, for watching their depth without subscribing
+    pub(crate) fn output_queues(&self) -> FanOut<EncodedVideoFrame> {
+        self.output.clone()
+    }
+
+    /// Take frames in `format`, which the compositor negotiated, rebuilding the filter graph if
+    /// it was built for another one
+    pub(crate) fn set_input_format(&mut self, format: VideoFormat) -> Result<()> {
+        if format == self.input_format {
+            return Ok(());
+        }
+        log::debug!(
+            "Taking {format:?} frames instead of {:?}",
+            self.input_format
+        );
+        let Some(ref encoder) = self.encoder else {
+            // Built for it when the encoder is reset
+            self.input_format = format;
+            return Ok(());
+        };
+        self.filter_graph = Some(Self::create_filter_graph(
+            encoder,
+            self.width,
+            self.height,
+            &self.config,
+            format,
+            self.uploads_frames,
+        )?);
+        self.input_format = format;
+        Ok(())
+    }
+
+    /// Run the DMA-BUF `frame` through the filter graph. `None` while the graph holds the
+    /// filtered frame back.
+    fn filter(&mut self, frame: &RawVideoFrame) -> Result<Option<ffmpeg::util::frame::Video>> {
+        let mut input = if self.uploads_frames {
+            read_dmabuf(frame, self.width, self.height)?
+        } else {
+            drm_prime_frame(
+                frame,
+                (self.width, self.height),
+                self.encoder.as_ref().unwrap(),
+            )?
+        };
+        input.set_pts(Some(frame.timestamp.as_nanos()));
+        let graph = self.filter_graph.as_mut().unwrap();
+        if let Some(ref pip) = self.config.pip {
+            pip::push(graph, pip, self.pip_frames.as_deref(), frame.timestamp)?;
+        }
+        graph.get("in").unwrap().source().add(&input)?;
+
+        let mut filtered = ffmpeg::util::frame::Video::empty();
+        match graph.get("out").unwrap().sink().frame(&mut filtered) {
+            Ok(()) => Ok(Some(filtered)),
+            Err(ffmpeg::Error::Other {
+                errno: libc::EAGAIN,
+            })
+            | Err(ffmpeg::Error::Eof) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Send the packets the encoder has ready to the output. With async_depth > 1 packets come
+    /// out a few frames after their frame went in, and several can become ready at once.
+    fn emit_packets(&mut self) {
+        if let Some(ref mut encoder) = self.encoder {
+            let mut packet = ffmpeg::codec::packet::Packet::empty();
+            while encoder.receive_packet(&mut packet).is_ok() {
+                if let Some(data) = packet.data() {
+                    let pts = StreamPts::new(packet.pts().unwrap_or(0), CaptureTime::TIME_BASE);
+                    // Some drivers leave AV1 key frames unflagged, their headers tell. JPEGs
+                    // are all key frames.
+                    let is_keyframe = packet.is_key()
+                        || (self.codec == VaapiCodec::Av1 && av1::is_keyframe(data))
+                        || self.codec == VaapiCodec::Mjpeg;
+                    let data = if self.codec == VaapiCodec::H264 && self.config.repeat_headers {
+                        self.parameter_sets.repeat(data, is_keyframe)
+                    } else {
+                        data.to_vec()
+                    };
+                    match self.output.send(EncodedVideoFrame {
+                        data,
+                        is_keyframe,
+                        pts,
+                        dts: StreamPts::new(packet.dts().unwrap_or(0), CaptureTime::TIME_BASE),
+                    }) {
+                        Delivery::Delivered => self.stats.mark_packet_emitted(pts.into()),
+                        Delivery::Full => {
+                            self.stats.mark_frame_dropped();
+                            log::error!("Could not send encoded video frame. Receiver is full");
+                            self.stats
+                                .record_error(PipelineStage::Consumer, "Encoded receiver full");
+                        }
+                        // Handled once by the processing loop
+                        Delivery::NoSubscribers => {}
+                    }
+                };
+            }
+        }
+    }
+
+    /// Encode the frames in flight with the current settings, then open a new encoder with
+    /// `config`. It starts with a keyframe, the pts carry on from the capture clock.
+    fn reopen(&mut self, config: VideoConfig) -> Result<()> {
+        self.flush()?;
+        self.emit_packets();
+        self.config = config;
+        self.reset()
+    }
+
+    /// Feed the encoder what the filter graph holds, then the end of the stream
+    fn flush(&mut self) -> Result<()> {
         if let Some(ref mut encoder) = self.encoder {
-            // Drain the filter graph
             let mut filtered = ffmpeg::util::frame::Video::empty();
             while self
                 .filter_graph
@@ -165,111 +636,83 @@ impl VideoEncoder for VaapiEncoder {
                 .frame(&mut filtered)
                 .is_ok()
             {
-                encoder.send_frame(&filtered)?;
+                failure_injection::send_frame(encoder, &filtered)?;
             }
-
-            // Drain encoder
             encoder.send_eof()?;
-            let mut packet = ffmpeg::codec::packet::Packet::empty();
-            while encoder.receive_packet(&mut packet).is_ok() {} // Discard these frames
         }
         Ok(())
     }
-    fn get_encoder(&self) -> &Option<ffmpeg::codec::encoder::Video> {
-        &self.encoder
-    }
-}
 
-impl PipewireSPA for VaapiEncoder {
-    fn get_spa_definition() -> Result<pw::spa::pod::Object> {
-        Ok(pw::spa::pod::object!(
-            pw::spa::utils::SpaTypes::ObjectParamFormat,
-            pw::spa::param::ParamType::EnumFormat,
-            pw::spa::pod::property!(
-                pw::spa::param::format::FormatProperties::MediaType,
-                Id,
-                pw::spa::param::format::MediaType::Video
-            ),
-            pw::spa::pod::property!(
-                pw::spa::param::format::FormatProperties::MediaSubtype,
-                Id,
-                pw::spa::param::format::MediaSubtype::Raw
-            ),
-            pw::spa::pod::property!(
-                pw::spa::param::format::FormatProperties::VideoModifier,
-                Long,
-                0
-            ),
-            pw::spa::pod::property!(
-                pw::spa::param::format::FormatProperties::VideoFormat,
-                Choice,
-                Enum,
-                Id,
-                pw::spa::param::video::VideoFormat::NV12,
-                pw::spa::param::video::VideoFormat::I420,
-                pw::spa::param::video::VideoFormat::BGRA,
-                pw::spa::param::video::VideoFormat::BGRx,
-            ),
-            pw::spa::pod::property!(
-                pw::spa::param::format::FormatProperties::VideoSize,
-                Choice,
-                Range,
-                Rectangle,
-                pw::spa::utils::Rectangle {
-                    width: 2560,
-                    height: 1440
-                }, // Default
-                pw::spa::utils::Rectangle {
-                    width: 1,
-                    height: 1
-                }, // Min
-                pw::spa::utils::Rectangle {
-                    width: 4096,
-                    height: 4096
-                } // Max
-            ),
-            pw::spa::pod::property!(
-                pw::spa::param::format::FormatProperties::VideoFramerate,
-                Choice,
-                Range,
-                Fraction,
-                pw::spa::utils::Fraction { num: 240, denom: 1 }, // Default
-                pw::spa::utils::Fraction { num: 0, denom: 1 },   // Min
-                pw::spa::utils::Fraction { num: 244, denom: 1 }  // Max
-            ),
-        ))
-    }
-}
-
-impl VaapiEncoder {
-    pub(crate) fn new(width: u32, height: u32, quality: QualityPreset) -> Result<Self> {
-        let encoder_name = "h264_vaapi";
-        let encoder = Self::create_encoder(width, height, encoder_name, &quality)?;
+    pub(crate) fn new(
+        width: u32,
+        height: u32,
+        codec: VaapiCodec,
+        config: VideoConfig,
+    ) -> Result<Self> {
+        let (output_width, output_height) = config.encoded_size(width, height)?;
+        let (encoder, rejected_options) =
+            Self::create_encoder(output_width, output_height, codec, &config)?;
 
-        let (frame_tx, frame_rx): (Sender<EncodedVideoFrame>, Receiver<EncodedVideoFrame>) =
-            bounded(10);
-        let filter_graph = Some(Self::create_filter_graph(&encoder, width, height)?);
+        let uploads_frames = config.vaapi_import == VaapiImport::Upload;
+        let input_format = default_input_format(&config);
+        let filter_graph = Some(Self::create_filter_graph(
+            &encoder,
+            width,
+            height,
+            &config,
+            input_format,
+            uploads_frames,
+        )?);
 
+        let follows_compositor_transform = config.transform.is_none();
         Ok(Self {
             encoder: Some(encoder),
             width,
             height,
-            encoder_name: encoder_name.to_string(),
-            quality,
-            encoded_frame_recv: Some(frame_rx),
-            encoded_frame_sender: frame_tx,
+            codec,
+            config,
+            rejected_options,
+            output: FanOut::new(OUTPUT_CAPACITY),
+            stats: Arc::default(),
             filter_graph,
+            last_frame: None,
+            pip_frames: None,
+            parameter_sets: ParameterSets::default(),
+            uploads_frames,
+            input_format,
+            follows_compositor_transform,
         })
     }
 
-    fn create_encoder(
+    pub(crate) fn create_encoder(
         width: u32,
         height: u32,
-        encoder: &str,
-        quality: &QualityPreset,
-    ) -> Result<ffmpeg::codec::encoder::Video> {
-        let encoder_codec =
-            ffmpeg::codec::encoder::find_by_name(encoder).ok_or(ffmpeg::Error::EncoderNotFound)?;
+        codec: VaapiCodec,
+        config: &VideoConfig,
+    ) -> Result<(ffmpeg::codec::encoder::Video, Vec<String>)> {
+        if config.ten_bit && !matches!(codec, VaapiCodec::Hevc | VaapiCodec::Av1) {
+            return Err(WaycapError::Unsupported(format!(
+                "{} can't encode 10 bit frames, use HEVC or AV1 instead",
+                codec.encoder_name()
+            )));
+        }
+        if config.intra_refresh {
+            return Err(WaycapError::Unsupported(format!(
+                "{} has no intra refresh",
+                codec.encoder_name()
+            )));
+        }
+        let encoder_codec = match ffmpeg_compat::find_encoder(codec.encoder_name()) {
+            Err(WaycapError::FFmpeg(ffmpeg::Error::EncoderNotFound))
+                if codec != VaapiCodec::H264 =>
+            {
+                return Err(WaycapError::Init(format!(
+                    "The linked ffmpeg was built without {}, use H.264 instead",
+                    codec.encoder_name()
+                )))
+            }
+            result => result?,
+        };
 
         let mut encoder_ctx = ffmpeg::codec::context::Context::new_with_codec(encoder_codec)
             .encoder()
@@ -280,115 +723,506 @@ impl VaapiEncoder {
         encoder_ctx.set_format(ffmpeg::format::Pixel::VAAPI);
         // Configuration inspiration from
         // https://git.dec05eba.com/gpu-screen-recorder/tree/src/capture/xcomposite_drm.c?id=8cbdb596ebf79587a432ed40583630b6cd39ed88
-        let mut vaapi_device =
+        let vaapi_device =
             create_hw_device(ffmpeg_next::ffi::AVHWDeviceType::AV_HWDEVICE_TYPE_VAAPI)?;
-        let mut frame_ctx = create_hw_frame_ctx(vaapi_device)?;
+        // ffmpeg only says that no mode fits when the driver lacks the one asked for
+        let rate_control = Self::rate_control(codec, config)?;
+        let (profile, entrypoints) = codec.va_profile(config);
+        // Lossless stays on the regular entrypoint, the one Self::probe_lossless tried
+        let wants_low_power = config.low_power && config.quality != QualityPreset::Lossless;
+        let mut driver = None;
+        if wants_low_power && entrypoints.contains(&va::ENTRYPOINT_ENC_SLICE_LP) {
+            driver = va::rate_control_modes(&vaapi_device, profile, &[va::ENTRYPOINT_ENC_SLICE_LP]);
+        }
+        let low_power = driver.is_some();
+        let driver = driver.or_else(|| va::rate_control_modes(&vaapi_device, profile, entrypoints));
+        if wants_low_power && !low_power {
+            match driver {
+                // AMD has no low power entrypoint to begin with
+                Some(ref driver) if matches!(driver.gpu, GpuVendor::AMD) => {}
+                _ => log::warn!(
+                    "The VAAPI driver can't encode {} at low power, using the regular encoder",
+                    codec.encoder_name()
+                ),
+            }
+        }
+        if let Some(ref driver) = driver {
+            if !driver.modes.contains(&rate_control.mode()) {
+                let supported: Vec<&str> = driver.modes.iter().map(|mode| mode.name()).collect();
+                return Err(WaycapError::Init(format!(
+                    "The VAAPI driver '{}' has no {} rate control for {}, only {}",
+                    driver.vendor,
+                    rate_control.mode().name(),
+                    codec.encoder_name(),
+                    supported.join(", ")
+                )));
+            }
+        }
+        let icq_config;
+        let config = match Self::preset_icq(codec, config, driver.as_ref()) {
+            Some(icq) => {
+                icq_config = VideoConfig {
+                    rate_control: Some(icq),
+                    ..config.clone()
+                };
+                &icq_config
+            }
+            None => config,
+        };
+        let frame_ctx = create_hw_frame_ctx(&vaapi_device)?;
 
         unsafe {
-            let hw_frame_context = &mut *((*frame_ctx).data as *mut AVHWFramesContext);
+            // av_hwframe_ctx_alloc already set device_ref and device_ctx with its own reference
+            let hw_frame_context = &mut *((*frame_ctx.as_ptr()).data as *mut AVHWFramesContext);
             hw_frame_context.width = width as i32;
             hw_frame_context.height = height as i32;
-            hw_frame_context.sw_format = AVPixelFormat::AV_PIX_FMT_NV12;
+            hw_frame_context.sw_format = if config.ten_bit {
+                AVPixelFormat::AV_PIX_FMT_P010LE
+            } else {
+                AVPixelFormat::AV_PIX_FMT_NV12
+            };
             hw_frame_context.format = encoder_ctx.format().into();
-            hw_frame_context.device_ref = av_buffer_ref(vaapi_device);
-            hw_frame_context.device_ctx = (*vaapi_device).data as *mut AVHWDeviceContext;
             // Decides buffer size if we do not pop frame from the encoder we cannot
-            // keep pushing. Smaller better as we reserve less GPU memory
-            hw_frame_context.initial_pool_size = 2;
+            // keep pushing. Smaller better as we reserve less GPU memory, but every frame the
+            // driver keeps in flight or holds back for B-frames needs its own surface, as does
+            // the last one when it is kept to repeat
+            hw_frame_context.initial_pool_size =
+                (config.frames_in_flight() + 1 + config.b_frames() + config.kept_frames()) as i32;
 
-            let err = av_hwframe_ctx_init(frame_ctx);
+            let err = av_hwframe_ctx_init(frame_ctx.as_ptr());
             if err < 0 {
                 return Err(WaycapError::Init(format!(
                     "Error trying to initialize hw frame context: {err:?}",
                 )));
             }
 
-            (*encoder_ctx.as_mut_ptr()).hw_device_ctx = av_buffer_ref(vaapi_device);
-            (*encoder_ctx.as_mut_ptr()).hw_frames_ctx = av_buffer_ref(frame_ctx);
-
-            av_buffer_unref(&mut vaapi_device);
-            av_buffer_unref(&mut frame_ctx);
+            // The context frees these with itself, the guards release ours
+            (*encoder_ctx.as_mut_ptr()).hw_device_ctx = vaapi_device.new_ref()?;
+            (*encoder_ctx.as_mut_ptr()).hw_frames_ctx = frame_ctx.new_ref()?;
         }
 
         // These should be part of a config file
-        encoder_ctx.set_time_base(Rational::new(1, TIME_UNIT_NS as i32));
+        encoder_ctx.set_time_base(CaptureTime::TIME_BASE);
+        if let Some((bitrate, max_bitrate, buffer_size)) =
+            config.rate_control.and_then(RateControl::bitrate_limits)
+        {
+            // Without a frame rate the driver takes the microsecond time base for one
+            encoder_ctx.set_frame_rate(Some(ffmpeg::Rational::new(config.framerate as i32, 1)));
+            // Set on the context as well, some drivers ignore the limits passed as options
+            encoder_ctx.set_bit_rate(bitrate as usize);
+            encoder_ctx.set_max_bit_rate(max_bitrate as usize);
+            unsafe {
+                (*encoder_ctx.as_mut_ptr()).rc_buffer_size =
+                    buffer_size.min(i32::MAX as u64) as i32;
+            }
+        }
 
-        // Needed to insert I-Frames more frequently so we don't lose full seconds
-        // when popping frames from the front
-        encoder_ctx.set_gop(GOP_SIZE);
+        set_colorimetry(&mut encoder_ctx, config.colorimetry);
+        if codec == VaapiCodec::Mjpeg {
+            // No GOP to set up. JFIF is full range BT.601, the filter graph converts to it.
+            encoder_ctx.set_color_range(ffmpeg::color::Range::JPEG);
+            encoder_ctx.set_colorspace(ffmpeg::color::Space::SMPTE170M);
+        } else {
+            // The filter graph converts to the range the stream is tagged with
+            encoder_ctx.set_color_range(ffmpeg_color_range(config.color_range));
+            // Needed to insert I-Frames more frequently so we don't lose full seconds
+            // when popping frames from the front
+            encoder_ctx.set_gop(if config.schedules_keyframes() {
+                SCHEDULED_GOP_SIZE
+            } else {
+                GOP_SIZE
+            });
+        }
+        encoder_ctx.set_max_b_frames(match codec {
+            // Without hidden alt-ref frames every packet is one shown frame, which WebM takes as
+            // is. Golden frames are then refreshed on key frames only.
+            VaapiCodec::Vp9 => 0,
+            // Every JPEG stands on its own
+            VaapiCodec::Mjpeg => 0,
+            VaapiCodec::H264 | VaapiCodec::Hevc | VaapiCodec::Av1 => config.b_frames() as usize,
+        });
 
         let encoder_params = ffmpeg::codec::Parameters::new();
 
-        let opts = Self::get_encoder_params(quality);
+        let mut opts = Self::get_encoder_params(codec, config, driver.as_ref())?;
+        if low_power {
+            opts.set("low_power", "1");
+        }
 
         encoder_ctx.set_parameters(encoder_params)?;
-        let encoder = encoder_ctx.open_with(opts)?;
-        Ok(encoder)
+        if let (VaapiCodec::H264, Some(profile)) = (codec, config.h264_profile) {
+            let profile = ffmpeg::codec::Profile::H264(match profile {
+                H264Profile::ConstrainedBaseline => {
+                    ffmpeg::codec::profile::H264::ConstrainedBaseline
+                }
+                H264Profile::Main => ffmpeg::codec::profile::H264::Main,
+                H264Profile::High => ffmpeg::codec::profile::H264::High,
+            });
+            // After the parameters, which would reset it
+            unsafe { (*encoder_ctx.as_mut_ptr()).profile = profile.into() };
+        }
+        let opened = open_configured_encoder(encoder_ctx, opts, config);
+        match (codec, opened) {
+            // Drivers without an encoding entrypoint for the codec fail here, after the device
+            // opened
+            (codec, Err(WaycapError::FFmpeg(e))) if codec != VaapiCodec::H264 => {
+                Err(WaycapError::Init(format!(
+                    "The VAAPI driver can't encode with {} ({e}), which needs {}. Use H.264 \
+                     instead",
+                    codec.encoder_name(),
+                    codec.hardware()
+                )))
+            }
+            (_, opened) => opened,
+        }
     }
 
-    fn get_encoder_params(quality: &QualityPreset) -> ffmpeg::Dictionary<'_> {
+    /// ffmpeg options of `codec`, with those `driver` needs for itself
+    fn get_encoder_params(
+        codec: VaapiCodec,
+        config: &VideoConfig,
+        driver: Option<&DriverRateControl>,
+    ) -> Result<ffmpeg::Dictionary<'static>> {
         let mut opts = ffmpeg::Dictionary::new();
-        opts.set("vsync", "vfr");
-        opts.set("rc", "VBR");
-        match quality {
-            QualityPreset::Low => {
-                opts.set("qp", "30");
+        if ffmpeg_compat::vaapi_has_async_depth() {
+            opts.set("async_depth", &config.frames_in_flight().to_string());
+        }
+        if let (VaapiCodec::H264, Some(level)) = (codec, config.h264_level) {
+            opts.set("level", &level.to_string());
+        }
+        let rate_control = Self::rate_control(codec, config)?;
+        if let (VaapiCodec::Vp9, None) = (codec, config.rate_control) {
+            let (_, loop_filter_level) = Self::vp9_preset(config.quality)?;
+            opts.set("loop_filter_level", loop_filter_level);
+            opts.set("loop_filter_sharpness", "4");
+        }
+        // iHD defaults to a balanced target usage, its best one spends fewer bits on qp 0.
+        // Other drivers keep their defaults.
+        if config.quality == QualityPreset::Lossless
+            && driver.is_some_and(|driver| matches!(driver.gpu, GpuVendor::INTEL))
+        {
+            opts.set("quality", "1");
+        }
+        if let Some(quality) = rate_control.quality() {
+            let range = codec.qp_range();
+            if !range.contains(&quality) {
+                return Err(WaycapError::Init(format!(
+                    "{} takes a {} quality from {} to {}, not {quality}",
+                    codec.encoder_name(),
+                    rate_control.mode().name(),
+                    range.start(),
+                    range.end()
+                )));
+            }
+            // Only the H.264 and HEVC encoders have a qp option, the others read
+            // global_quality. ICQ and QVBR aim at global_quality with every codec.
+            match (codec, rate_control) {
+                (VaapiCodec::H264 | VaapiCodec::Hevc, RateControl::ConstantQp(_)) => {
+                    opts.set("qp", &quality.to_string())
+                }
+                _ => opts.set("global_quality", &quality.to_string()),
+            }
+        }
+        opts.set("rc_mode", rate_control.mode().name());
+        set_bitrate_options(&mut opts, rate_control);
+        Ok(opts)
+    }
+
+    /// What `config` asks for, a fixed quantizer for the preset without a rate control
+    /// ICQ at the quantizer of the quality preset, which gives Intel's H.264 encoder better
+    /// quality per bit on screen content than CQP. None keeps [`Self::rate_control`], for other
+    /// drivers and codecs, a rate control set by hand or [`VideoConfig::vaapi_force_cqp`].
+    fn preset_icq(
+        codec: VaapiCodec,
+        config: &VideoConfig,
+        driver: Option<&DriverRateControl>,
+    ) -> Option<RateControl> {
+        let driver = driver?;
+        if codec != VaapiCodec::H264
+            || config.rate_control.is_some()
+            || config.vaapi_force_cqp
+            || config.quality == QualityPreset::Lossless
+            || !matches!(driver.gpu, GpuVendor::INTEL)
+            || !driver.modes.contains(&RateControlMode::Icq)
+        {
+            return None;
+        }
+        let RateControl::ConstantQp(qp) = config.quality.rate_control() else {
+            return None;
+        };
+        // The quality factor starts at 1
+        Some(RateControl::Icq(qp.max(1)))
+    }
+
+    fn rate_control(codec: VaapiCodec, config: &VideoConfig) -> Result<RateControl> {
+        Ok(match (codec, config.rate_control) {
+            (_, Some(rate_control)) => rate_control,
+            // Lossless is qp 0, which only H.264 is probed for, see Self::probe_lossless
+            (VaapiCodec::Hevc, None) if config.quality == QualityPreset::Lossless => {
+                return Err(lossless_unsupported(codec.encoder_name()))
+            }
+            // HEVC spends fewer bits than H.264 at the same qp
+            (VaapiCodec::H264 | VaapiCodec::Hevc, None) => config.quality.rate_control(),
+            // av1_vaapi takes the base_q_idx, 0 to 255, through global_quality. These land
+            // around the H.264 qps in size and quality.
+            (VaapiCodec::Av1, None) => RateControl::ConstantQp(match config.quality {
+                QualityPreset::Low => 150,
+                QualityPreset::Medium => 120,
+                QualityPreset::High => 95,
+                QualityPreset::Ultra => 70,
+                QualityPreset::Lossless => return Err(lossless_unsupported(codec.encoder_name())),
+                QualityPreset::Custom(custom) => custom.vaapi_qp,
+            }),
+            // vp9_vaapi takes the base_q_idx as well
+            (VaapiCodec::Vp9, None) => RateControl::ConstantQp(Self::vp9_preset(config.quality)?.0),
+            // The JPEG quality, 1 to 100
+            (VaapiCodec::Mjpeg, None) => RateControl::ConstantQp(match config.quality {
+                QualityPreset::Low => 60,
+                QualityPreset::Medium => 75,
+                QualityPreset::High => 85,
+                QualityPreset::Ultra => 95,
+                QualityPreset::Lossless => return Err(lossless_unsupported(codec.encoder_name())),
+                QualityPreset::Custom(custom) => custom.vaapi_qp,
+            }),
+        })
+    }
+
+    /// base_q_idx and loop filter level of the VP9 presets. The loop filter is turned up along
+    /// with the base_q_idx to smooth the blocking of the lower presets.
+    fn vp9_preset(quality: QualityPreset) -> Result<(u32, &'static str)> {
+        Ok(match quality {
+            QualityPreset::Low => (140, "32"),
+            QualityPreset::Medium => (110, "24"),
+            QualityPreset::High => (85, "16"),
+            QualityPreset::Ultra => (60, "10"),
+            QualityPreset::Lossless => {
+                return Err(lossless_unsupported(VaapiCodec::Vp9.encoder_name()))
             }
-            QualityPreset::Medium => {
-                opts.set("qp", "25");
+            QualityPreset::Custom(custom) => (custom.vaapi_qp, "24"),
+        })
+    }
+
+    /// Whether the driver encodes H.264 at [`QualityPreset::Lossless`] bit-exact. Drivers take
+    /// qp 0 whether or not they keep every sample, so a test pattern is encoded the way
+    /// captures are and decoded again on the CPU.
+    pub(crate) fn probe_lossless() -> Result<bool> {
+        const SIZE: usize = 256;
+        let config = VideoConfig {
+            quality: QualityPreset::Lossless,
+            ..Default::default()
+        };
+        let (mut encoder, _) =
+            Self::create_encoder(SIZE as u32, SIZE as u32, VaapiCodec::H264, &config)?;
+
+        let mut pattern = ffmpeg::util::frame::Video::new(Pixel::NV12, SIZE as u32, SIZE as u32);
+        for plane in 0..2 {
+            let stride = pattern.stride(plane);
+            for (i, sample) in pattern.data_mut(plane).iter_mut().enumerate() {
+                let (x, y) = (i % stride, i / stride);
+                *sample = ((x * 7 + y * 13) ^ (x * y)) as u8;
             }
-            QualityPreset::High => {
-                opts.set("qp", "20");
+        }
+        let mut surface = ffmpeg::util::frame::Video::empty();
+        unsafe {
+            let frames_ctx = (*encoder.as_ptr()).hw_frames_ctx;
+            let err = av_hwframe_get_buffer(frames_ctx, surface.as_mut_ptr(), 0);
+            if err < 0 {
+                return Err(ffmpeg::Error::from(err).into());
             }
-            QualityPreset::Ultra => {
-                opts.set("qp", "15");
+            let err = av_hwframe_transfer_data(surface.as_mut_ptr(), pattern.as_ptr(), 0);
+            if err < 0 {
+                return Err(ffmpeg::Error::from(err).into());
             }
         }
-        opts
+        surface.set_pts(Some(0));
+        encoder.send_frame(&surface)?;
+        encoder.send_eof()?;
+
+        let h264 =
+            ffmpeg::decoder::find(ffmpeg::codec::Id::H264).ok_or(ffmpeg::Error::DecoderNotFound)?;
+        let mut decoder = ffmpeg::codec::context::Context::new_with_codec(h264)
+            .decoder()
+            .video()?;
+        let mut packet = ffmpeg::codec::packet::Packet::empty();
+        while encoder.receive_packet(&mut packet).is_ok() {
+            decoder.send_packet(&packet)?;
+        }
+        decoder.send_eof()?;
+        let mut decoded = ffmpeg::util::frame::Video::empty();
+        decoder.receive_frame(&mut decoded)?;
+        if !matches!(decoded.format(), Pixel::YUV420P | Pixel::YUVJ420P) {
+            return Ok(false);
+        }
+
+        // The decoder puts the chroma of the NV12 into planes of their own
+        let sample = |frame: &ffmpeg::util::frame::Video, plane: usize, x: usize, y: usize| {
+            frame.data(plane)[y * frame.stride(plane) + x]
+        };
+        let luma_kept = (0..SIZE)
+            .all(|y| (0..SIZE).all(|x| sample(&decoded, 0, x, y) == sample(&pattern, 0, x, y)));
+        let chroma_kept = (0..SIZE / 2).all(|y| {
+            (0..SIZE / 2).all(|x| {
+                sample(&decoded, 1, x, y) == sample(&pattern, 1, 2 * x, y)
+                    && sample(&decoded, 2, x, y) == sample(&pattern, 1, 2 * x + 1, y)
+            })
+        });
+        Ok(luma_kept && chroma_kept)
     }
 
+    /// Graph taking `width`x`height` DMA-BUFs in `input`, which it crops and scales to the size
+    /// of the encoder. They are mapped, or with `upload` taken as frames [`read_dmabuf`] copied.
     fn create_filter_graph(
         encoder: &ffmpeg::codec::encoder::Video,
         width: u32,
         height: u32,
+        config: &VideoConfig,
+        input: VideoFormat,
+        upload: bool,
+    ) -> Result<ffmpeg::filter::Graph> {
+        with_denoise_fallback(config, |denoise| {
+            Self::build_filter_graph(encoder, width, height, config, input, upload, denoise)
+        })
+    }
+
+    /// [`Self::create_filter_graph`], denoising with `denoise` instead of the config's
+    fn build_filter_graph(
+        encoder: &ffmpeg::codec::encoder::Video,
+        width: u32,
+        height: u32,
+        config: &VideoConfig,
+        input: VideoFormat,
+        upload: bool,
+        denoise: Option<DenoiseStrength>,
     ) -> Result<ffmpeg::filter::Graph> {
         let mut graph = ffmpeg::filter::Graph::new();
 
-        let args = format!("video_size={width}x{height}:pix_fmt=bgra:time_base=1/1000000",);
+        let input_format = graph_input_format(input)?;
+        let output_format = scale_format(input, config.ten_bit)
+            .map_or_else(String::new, |format| format!("format={format}:"));
+        let args =
+            format!("video_size={width}x{height}:pix_fmt={input_format}:time_base=1/1000000");
 
-        let mut input = graph.add(&ffmpeg::filter::find("buffer").unwrap(), "in", &args)?;
+        let input = graph.add(&ffmpeg_compat::find_filter("buffer")?, "in", &args)?;
 
-        let mut hwmap = graph.add(
-            &ffmpeg::filter::find("hwmap").unwrap(),
-            "hwmap",
-            "mode=read+write:derive_device=vaapi",
+        let (import_filter, import_args) = if upload {
+            ("hwupload", "derive_device=vaapi")
+        } else {
+            ("hwmap", "mode=read+write:derive_device=vaapi")
+        };
+        let mut import = graph.add(
+            &ffmpeg_compat::find_filter(import_filter)?,
+            import_filter,
+            import_args,
         )?;
 
-        let scale_args = format!("w={width}:h={height}:format=nv12:out_range=tv");
-        let mut scale = graph.add(
-            &ffmpeg::filter::find("scale_vaapi").unwrap(),
+        let scaling = config.scaling(width, height)?;
+        let crop = add_crop_filter(&mut graph, scaling.source, width, height)?;
+        let transpose = add_transpose_filter(&mut graph, scaling.transform)?;
+
+        let scale_args = format!(
+            "w={}:h={}:{output_format}{}",
+            scaling.picture.width,
+            scaling.picture.height,
+            scale_color_args(encoder)
+        );
+        let scale = graph.add(
+            &ffmpeg_compat::find_filter("scale_vaapi")?,
             "scale",
             &scale_args,
         )?;
+        let denoise = add_denoise_filter(&mut graph, denoise)?;
+        let pad = add_pad_filter(&mut graph, &scaling, config.pad_color)?;
+        let overlay = config.overlay.as_ref().map(Overlay::load).transpose()?;
+        let blend = match overlay {
+            Some(ref overlay) => {
+                let placement = overlay.placement(scaling.output)?;
+                Some(overlay.add_vaapi_filter(&mut graph, encoder, placement)?)
+            }
+            None => None,
+        };
+        let pip = config
+            .pip
+            .as_ref()
+            .map(|pip| pip::add_vaapi_filter(pip, &mut graph, encoder, scaling.output))
+            .transpose()?;
 
-        let mut out = graph.add(&ffmpeg::filter::find("buffersink").unwrap(), "out", "")?;
+        let out = graph.add(&ffmpeg_compat::find_filter("buffersink")?, "out", "")?;
         unsafe {
             let dev = (*encoder.as_ptr()).hw_device_ctx;
 
-            (*hwmap.as_mut_ptr()).hw_device_ctx = av_buffer_ref(dev);
+            (*import.as_mut_ptr()).hw_device_ctx = av_buffer_ref(dev);
         }
 
-        input.link(0, &mut hwmap, 0);
-        hwmap.link(0, &mut scale, 0);
-        scale.link(0, &mut out, 0);
+        let mut filters = vec![input, import];
+        filters.extend(crop);
+        filters.extend(transpose);
+        filters.push(scale);
+        filters.extend(denoise);
+        filters.extend(pad);
+        filters.extend(blend);
+        filters.extend(pip);
+        filters.push(out);
+        link_filters_with_custom(&mut graph, &mut filters, 2, encoder, config)?;
 
-        graph.validate()?;
+        validate_graph(&mut graph, config)?;
+        if let Some(overlay) = overlay {
+            overlay.push(&mut graph)?;
+        }
         log::trace!("VAAPI Graph\n{}", graph.dump());
 
         Ok(graph)
     }
 }
 
+/// Copy the linear DMA-BUFs of the `width`x`height` `frame` into a frame in memory, plane by
+/// plane, for the graphs uploading the frames
+fn read_dmabuf(
+    frame: &RawVideoFrame,
+    width: u32,
+    height: u32,
+) -> Result<ffmpeg::util::frame::Video> {
+    let planes = extract_dmabuf_planes(frame)?;
+    let mut copy = ffmpeg::util::frame::Video::new(graph_pixel(frame.format)?, width, height);
+    if planes.len() < copy.planes() {
+        return Err(WaycapError::Encoding(format!(
+            "The {:?} frame has {} of its {} planes",
+            frame.format,
+            planes.len(),
+            copy.planes()
+        )));
+    }
+    for (index, plane) in planes.iter().enumerate().take(copy.planes()) {
+        let row = copy.plane_width(index) as usize * sample_size(frame.format, index);
+        let rows = copy.plane_height(index) as usize;
+        let (offset, stride) = (plane.offset as usize, plane.stride as usize);
+        if rows == 0 || stride < row {
+            return Err(WaycapError::Encoding(format!(
+                "Can't copy frames with a stride of {stride} through the CPU"
+            )));
+        }
+        let mapping =
+            DmaBufMapping::new(plane.fd, frame.modifier, offset + stride * (rows - 1) + row)?;
+        let source = &mapping.bytes()[offset..];
+
+        let copy_stride = copy.stride(index);
+        let data = copy.data_mut(index);
+        for y in 0..rows {
+            data[y * copy_stride..][..row].copy_from_slice(&source[y * stride..][..row]);
+        }
+    }
+    Ok(copy)
+}
+
+/// Bytes a sample takes in the plane `index` of `format` frames: the RGB formats have 4 byte
+/// pixels, NV12 interleaves U and V in its second plane
+fn sample_size(format: VideoFormat, index: usize) -> usize {
+    match (format, index) {
+        (VideoFormat::NV12, 1) => 2,
+        (VideoFormat::NV12 | VideoFormat::I420, _) => 1,
+        _ => 4,
+    }
+}
+
 impl Drop for VaapiEncoder {
     fn drop(&mut self) {
         if let Err(e) = self.drain() {
@@ -397,3 +1231,537 @@ impl Drop for VaapiEncoder {
         self.drop_processor();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        io::Write,
+        os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    };
+
+    use super::*;
+    use crate::{
+        encoders::h264::nal_units,
+        runtime::Runtime,
+        types::config::{
+            ContentLightLevel, CustomQuality, HdrMetadata, MasteringDisplay, ScaleMode, Transform,
+        },
+    };
+
+    const HEVC_PREFIX_SEI: u8 = 39;
+    const SEI_MASTERING_DISPLAY: u32 = 137;
+    const SEI_CONTENT_LIGHT_LEVEL: u32 = 144;
+
+    /// Payload type and payload of the SEI messages in an HEVC access unit
+    fn hevc_sei_messages(data: &[u8]) -> Vec<(u32, Vec<u8>)> {
+        let mut messages = Vec::new();
+        for unit in nal_units(data).filter(|unit| (unit[0] >> 1) & 0x3f == HEVC_PREFIX_SEI) {
+            // Without the emulation prevention bytes, a 3 after two zeros
+            let mut rbsp = Vec::new();
+            let mut zeros = 0;
+            for &byte in &unit[2..] {
+                if zeros == 2 && byte == 3 {
+                    zeros = 0;
+                    continue;
+                }
+                zeros = if byte == 0 { (zeros + 1).min(2) } else { 0 };
+                rbsp.push(byte);
+            }
+            // Messages up to the trailing bits
+            let mut rest = rbsp.as_slice();
+            while rest.len() > 1 {
+                let payload_type = sei_value(&mut rest);
+                let size = sei_value(&mut rest) as usize;
+                messages.push((payload_type, rest[..size].to_vec()));
+                rest = &rest[size..];
+            }
+        }
+        messages
+    }
+
+    /// SEI payload type or size, which counts 255 for each 0xff byte in front of the last one
+    fn sei_value(rest: &mut &[u8]) -> u32 {
+        let mut value = 0;
+        while let [byte, tail @ ..] = *rest {
+            *rest = tail;
+            value += *byte as u32;
+            if *byte != 0xff {
+                break;
+            }
+        }
+        value
+    }
+
+    /// A buffer of `len` gray bytes in a memfd, which maps like a linear DMA-BUF
+    fn memfd_buffer(len: usize) -> OwnedFd {
+        let fd = unsafe { libc::memfd_create(c"frame".as_ptr(), libc::MFD_CLOEXEC) };
+        assert!(fd >= 0);
+        let mut file = fs::File::from(unsafe { OwnedFd::from_raw_fd(fd) });
+        file.write_all(&vec![0x80; len]).unwrap();
+        file.into()
+    }
+
+    #[test]
+    #[ignore = "needs a VAAPI device"]
+    fn failed_initializations_release_the_device() {
+        let _runtime = Runtime::acquire().unwrap();
+        let config = VideoConfig::default();
+        // The driver may keep process wide fds around after its first use
+        drop(VaapiEncoder::create_encoder(64, 64, VaapiCodec::H264, &config).unwrap());
+        let before = dri_fds();
+
+        for _ in 0..1000 {
+            // 0x0 frames are rejected by av_hwframe_ctx_init, after the device was created
+            assert!(VaapiEncoder::create_encoder(0, 0, VaapiCodec::H264, &config).is_err());
+        }
+        for _ in 0..100 {
+            drop(VaapiEncoder::create_encoder(64, 64, VaapiCodec::H264, &config).unwrap());
+        }
+
+        assert_eq!(dri_fds(), before);
+    }
+
+    #[test]
+    #[ignore = "needs a VAAPI device which encodes HEVC"]
+    fn hevc_keyframes_carry_the_hdr_metadata_after_every_reset() {
+        let _runtime = Runtime::acquire().unwrap();
+        let config = VideoConfig {
+            hdr_metadata: Some(HdrMetadata {
+                mastering_display: Some(MasteringDisplay::bt2020(0.005, 1000.0)),
+                content_light_level: Some(ContentLightLevel {
+                    max_cll: 1000,
+                    max_fall: 400,
+                }),
+            }),
+            vaapi_import: VaapiImport::Upload,
+            ..Default::default()
+        };
+        let mut encoder = VaapiEncoder::new(64, 64, VaapiCodec::Hevc, config).unwrap();
+        let packets = encoder.output().unwrap();
+        let buffer = memfd_buffer(64 * 64 * 4);
+
+        let mut timestamp = 0;
+        for round in 0..3 {
+            if round > 0 {
+                encoder.reset().unwrap();
+            }
+            for _ in 0..5 {
+                timestamp += 16_666_667;
+                encoder
+                    .process(RawVideoFrame {
+                        dmabuf_fd: Some(buffer.as_raw_fd()),
+                        timestamp: CaptureTime::from_nanos(timestamp),
+                        ..RawVideoFrame::bgrx(64, 64)
+                    })
+                    .unwrap();
+            }
+            encoder.flush().unwrap();
+            encoder.emit_packets();
+
+            let packets: Vec<EncodedVideoFrame> = packets.try_iter().collect();
+            assert_eq!(packets.len(), 5);
+            assert!(packets[0].is_keyframe, "round {round}");
+            for packet in packets.iter().filter(|packet| packet.is_keyframe) {
+                let messages = hevc_sei_messages(&packet.data);
+                let payload = |payload_type| {
+                    messages
+                        .iter()
+                        .find(|(found, _)| *found == payload_type)
+                        .map(|(_, payload)| payload.as_slice())
+                };
+                // Primaries in green, blue, red order, then the white point, then the
+                // luminance in units of 0.0001 cd/m²
+                let mastering = payload(SEI_MASTERING_DISPLAY).expect("no mastering display SEI");
+                assert_eq!(mastering.len(), 24);
+                assert_eq!(mastering[12..16], [0x3d, 0x13, 0x40, 0x42]);
+                assert_eq!(mastering[16..20], 10_000_000u32.to_be_bytes());
+                assert_eq!(mastering[20..24], 50u32.to_be_bytes());
+                let light = payload(SEI_CONTENT_LIGHT_LEVEL).expect("no content light level SEI");
+                assert_eq!(light, [0x03, 0xe8, 0x01, 0x90]);
+            }
+        }
+    }
+
+    #[test]
+    fn custom_quantizers_are_range_checked() {
+        let custom = |vaapi_qp| VideoConfig {
+            quality: QualityPreset::Custom(CustomQuality {
+                vaapi_qp,
+                nvenc_cq: 23,
+            }),
+            ..Default::default()
+        };
+        let opts = VaapiEncoder::get_encoder_params(VaapiCodec::H264, &custom(18), None).unwrap();
+        assert_eq!(opts.get("qp"), Some("18"));
+        let opts = VaapiEncoder::get_encoder_params(VaapiCodec::Av1, &custom(200), None).unwrap();
+        assert_eq!(opts.get("global_quality"), Some("200"));
+
+        assert!(matches!(
+            VaapiEncoder::get_encoder_params(VaapiCodec::H264, &custom(52), None),
+            Err(WaycapError::Init(_))
+        ));
+        // The JPEG quality starts at 1
+        assert!(matches!(
+            VaapiEncoder::get_encoder_params(VaapiCodec::Mjpeg, &custom(0), None),
+            Err(WaycapError::Init(_))
+        ));
+    }
+
+    #[test]
+    fn vbv_limits_become_options() {
+        let config = VideoConfig {
+            rate_control: Some(RateControl::Vbr {
+                bitrate: 6_000_000,
+                max_bitrate: 8_000_000,
+                buffer_size: Some(4_000_000),
+            }),
+            ..Default::default()
+        };
+        let opts = VaapiEncoder::get_encoder_params(VaapiCodec::H264, &config, None).unwrap();
+        assert_eq!(opts.get("rc_mode"), Some("VBR"));
+        assert_eq!(opts.get("b"), Some("6000000"));
+        assert_eq!(opts.get("maxrate"), Some("8000000"));
+        assert_eq!(opts.get("bufsize"), Some("4000000"));
+    }
+
+    #[test]
+    fn quality_modes_aim_at_global_quality() {
+        let config = VideoConfig {
+            rate_control: Some(RateControl::Icq(24)),
+            ..Default::default()
+        };
+        let opts = VaapiEncoder::get_encoder_params(VaapiCodec::H264, &config, None).unwrap();
+        assert_eq!(opts.get("rc_mode"), Some("ICQ"));
+        assert_eq!(opts.get("global_quality"), Some("24"));
+        assert_eq!(opts.get("qp"), None);
+
+        let config = VideoConfig {
+            rate_control: Some(RateControl::Qvbr {
+                bitrate: 6_000_000,
+                max_bitrate: 8_000_000,
+                quality: 90,
+                buffer_size: None,
+            }),
+            ..Default::default()
+        };
+        let opts = VaapiEncoder::get_encoder_params(VaapiCodec::Av1, &config, None).unwrap();
+        assert_eq!(opts.get("rc_mode"), Some("QVBR"));
+        assert_eq!(opts.get("global_quality"), Some("90"));
+        assert_eq!(opts.get("maxrate"), Some("8000000"));
+    }
+
+    #[test]
+    fn intel_presets_use_icq() {
+        let driver = |gpu, modes: &[RateControlMode]| DriverRateControl {
+            vendor: String::new(),
+            gpu,
+            modes: modes.to_vec(),
+        };
+        let intel = driver(
+            GpuVendor::INTEL,
+            &[RateControlMode::Cqp, RateControlMode::Icq],
+        );
+        let config = VideoConfig::default();
+        assert_eq!(
+            VaapiEncoder::preset_icq(VaapiCodec::H264, &config, Some(&intel)),
+            Some(RateControl::Icq(25))
+        );
+        let icq = VideoConfig {
+            rate_control: Some(RateControl::Icq(25)),
+            ..Default::default()
+        };
+        let opts = VaapiEncoder::get_encoder_params(VaapiCodec::H264, &icq, None).unwrap();
+        assert_eq!(opts.get("rc_mode"), Some("ICQ"));
+        assert_eq!(opts.get("global_quality"), Some("25"));
+
+        // CQP everywhere else
+        let amd = driver(
+            GpuVendor::AMD,
+            &[RateControlMode::Cqp, RateControlMode::Icq],
+        );
+        let without_icq = driver(GpuVendor::INTEL, &[RateControlMode::Cqp]);
+        let forced = VideoConfig {
+            vaapi_force_cqp: true,
+            ..Default::default()
+        };
+        for (codec, config, driver) in [
+            (VaapiCodec::H264, &config, Some(&amd)),
+            (VaapiCodec::H264, &config, Some(&without_icq)),
+            (VaapiCodec::H264, &config, None),
+            (VaapiCodec::H264, &forced, Some(&intel)),
+            (VaapiCodec::Av1, &config, Some(&intel)),
+        ] {
+            assert_eq!(VaapiEncoder::preset_icq(codec, config, driver), None);
+        }
+    }
+
+    #[test]
+    fn lossless_is_qp_0_on_h264_only() {
+        let config = VideoConfig {
+            quality: QualityPreset::Lossless,
+            ..Default::default()
+        };
+        config.validate().unwrap();
+        let intel = DriverRateControl {
+            vendor: String::new(),
+            gpu: GpuVendor::INTEL,
+            modes: vec![RateControlMode::Cqp, RateControlMode::Icq],
+        };
+        assert_eq!(
+            VaapiEncoder::preset_icq(VaapiCodec::H264, &config, Some(&intel)),
+            None
+        );
+        let opts =
+            VaapiEncoder::get_encoder_params(VaapiCodec::H264, &config, Some(&intel)).unwrap();
+        assert_eq!(opts.get("rc_mode"), Some("CQP"));
+        assert_eq!(opts.get("qp"), Some("0"));
+        assert_eq!(opts.get("quality"), Some("1"));
+        let amd = DriverRateControl {
+            gpu: GpuVendor::AMD,
+            ..intel
+        };
+        let opts = VaapiEncoder::get_encoder_params(VaapiCodec::H264, &config, Some(&amd)).unwrap();
+        assert_eq!(opts.get("qp"), Some("0"));
+        assert_eq!(opts.get("quality"), None);
+
+        for codec in [
+            VaapiCodec::Hevc,
+            VaapiCodec::Av1,
+            VaapiCodec::Vp9,
+            VaapiCodec::Mjpeg,
+        ] {
+            assert!(matches!(
+                VaapiEncoder::get_encoder_params(codec, &config, None),
+                Err(WaycapError::Unsupported(_))
+            ));
+        }
+        // The quantizer is pinned
+        let with_rate_control = VideoConfig {
+            rate_control: Some(RateControl::Icq(1)),
+            ..config
+        };
+        assert!(matches!(
+            with_rate_control.validate(),
+            Err(WaycapError::Validation(_))
+        ));
+    }
+
+    #[test]
+    fn h264_profile_and_level() {
+        let config = VideoConfig {
+            h264_profile: Some(H264Profile::ConstrainedBaseline),
+            h264_level: Some(31),
+            max_b_frames: 0,
+            ..Default::default()
+        };
+        config.validate().unwrap();
+        let opts = VaapiEncoder::get_encoder_params(VaapiCodec::H264, &config, None).unwrap();
+        assert_eq!(opts.get("level"), Some("31"));
+        assert_eq!(
+            VaapiCodec::H264.va_profile(&config).0,
+            va::PROFILE_H264_CONSTRAINED_BASELINE
+        );
+        // Only H.264 has the option
+        let opts = VaapiEncoder::get_encoder_params(VaapiCodec::Av1, &config, None).unwrap();
+        assert_eq!(opts.get("level"), None);
+
+        let with_b_frames = VideoConfig {
+            max_b_frames: 2,
+            ..config.clone()
+        };
+        assert!(matches!(
+            with_b_frames.validate(),
+            Err(WaycapError::Validation(_))
+        ));
+        let unknown_level = VideoConfig {
+            h264_level: Some(45),
+            ..config
+        };
+        assert!(matches!(
+            unknown_level.validate(),
+            Err(WaycapError::Validation(_))
+        ));
+    }
+
+    #[test]
+    fn scale_modes_fill_the_output() {
+        let config = |output_width, output_height, scale_mode| VideoConfig {
+            output_width,
+            output_height,
+            scale_mode,
+            ..Default::default()
+        };
+        let rect = |x, y, width, height| Rect {
+            x,
+            y,
+            width,
+            height,
+        };
+        let fit = config(Some(1920), Some(1080), ScaleMode::Fit);
+        let scaling = fit.scaling(3840, 2160).unwrap();
+        assert_eq!(scaling.picture, rect(0, 0, 1920, 1080));
+        assert!(!scaling.is_padded());
+        // Ultrawide monitors are letterboxed instead of being squeezed
+        let scaling = fit.scaling(3440, 1440).unwrap();
+        assert_eq!(scaling.output, (1920, 1080));
+        assert_eq!(scaling.source, rect(0, 0, 3440, 1440));
+        assert_eq!(scaling.picture, rect(0, 138, 1920, 804));
+        assert!(scaling.is_padded());
+        let scaling = fit.scaling(1080, 1920).unwrap();
+        assert_eq!(scaling.picture, rect(656, 0, 608, 1080));
+        // Smaller captures are scaled up
+        assert_eq!(
+            fit.scaling(1280, 720).unwrap().picture,
+            rect(0, 0, 1920, 1080)
+        );
+
+        let stretch = config(Some(1920), Some(1080), ScaleMode::Stretch).scaling(3440, 1440);
+        assert_eq!(stretch.unwrap().picture, rect(0, 0, 1920, 1080));
+        // The middle of the ultrawide at 16:9
+        let fill = config(Some(1920), Some(1080), ScaleMode::Fill)
+            .scaling(3440, 1440)
+            .unwrap();
+        assert_eq!(fill.source, rect(440, 0, 2560, 1440));
+        assert_eq!(fill.picture, rect(0, 0, 1920, 1080));
+
+        // One side alone follows the aspect ratio, odd targets are rounded to even
+        let width_only = config(Some(1281), None, ScaleMode::Fill);
+        assert_eq!(width_only.encoded_size(2560, 1440).unwrap(), (1280, 720));
+        let height_only = config(None, Some(767), ScaleMode::Fit);
+        assert_eq!(height_only.encoded_size(1366, 768).unwrap(), (1364, 766));
+        let default = VideoConfig::default();
+        assert_eq!(default.encoded_size(1365, 767).unwrap(), (1365, 767));
+        assert!(config(Some(0), None, ScaleMode::Fit).validate().is_err());
+    }
+
+    #[test]
+    fn quarter_turns_swap_the_sides() {
+        let transforms = [
+            Transform::Normal,
+            Transform::Rot90,
+            Transform::Rot180,
+            Transform::Rot270,
+            Transform::Flipped,
+            Transform::Flipped90,
+            Transform::Flipped180,
+            Transform::Flipped270,
+        ];
+        for transform in transforms {
+            // Every pixel lands on its own one of the turned frame
+            let (width, height) = if transform.swaps_sides() {
+                (3, 5)
+            } else {
+                (5, 3)
+            };
+            let mut covered = [[false; 5]; 5];
+            for y in 0..3 {
+                for x in 0..5 {
+                    let (x, y) = transform.map(x, y, 5, 3);
+                    assert!(x < width && y < height, "{transform:?}");
+                    covered[y as usize][x as usize] = true;
+                }
+            }
+            assert_eq!(covered.iter().flatten().filter(|&&c| c).count(), 15);
+        }
+        // Counter-clockwise, the top right corner goes to the top left
+        assert_eq!(Transform::Rot90.map(4, 0, 5, 3), (0, 0));
+        assert_eq!(Transform::Rot270.map(0, 0, 5, 3), (2, 0));
+        assert_eq!(Transform::Flipped.map(0, 0, 5, 3), (4, 0));
+        assert_eq!(Transform::Flipped90.map(4, 2, 5, 3), (2, 4));
+
+        let config = |output, scale_mode, transform| VideoConfig {
+            output_width: output,
+            output_height: output.map(|width| width * 9 / 16),
+            scale_mode,
+            transform: Some(transform),
+            ..Default::default()
+        };
+        let portrait = config(None, ScaleMode::Fit, Transform::Rot90);
+        assert_eq!(portrait.encoded_size(1920, 1080).unwrap(), (1080, 1920));
+        let mirrored = config(None, ScaleMode::Fit, Transform::Flipped180);
+        assert_eq!(mirrored.encoded_size(1920, 1080).unwrap(), (1920, 1080));
+        // A turned landscape monitor is pillarboxed in a landscape output
+        let fit = config(Some(1920), ScaleMode::Fit, Transform::Rot270)
+            .scaling(1920, 1080)
+            .unwrap();
+        assert_eq!(fit.source.width, 1920);
+        assert_eq!((fit.picture.x, fit.picture.width), (656, 608));
+        // The cut of a filled output is taken in the captured frame
+        let fill = config(Some(1080), ScaleMode::Fill, Transform::Rot90)
+            .scaling(1920, 1080)
+            .unwrap();
+        assert_eq!(fill.output, (1080, 606));
+        assert_eq!((fill.source.width, fill.source.height), (606, 1080));
+    }
+
+    #[test]
+    fn crops_lie_within_the_stream() {
+        let crop = Rect {
+            x: 2560,
+            y: 720,
+            width: 1280,
+            height: 720,
+        };
+        let mut config = VideoConfig {
+            crop: Some(crop),
+            ..Default::default()
+        };
+        assert_eq!(config.encoded_size(3840, 2160).unwrap(), (1280, 720));
+        // A smaller source the crop doesn't fit in anymore
+        assert!(matches!(
+            config.encoded_size(2560, 1440),
+            Err(WaycapError::Validation(_))
+        ));
+        // Scaled after cropping
+        config.output_width = Some(640);
+        assert_eq!(config.encoded_size(3840, 2160).unwrap(), (640, 360));
+
+        assert!(config.set_crop(Some(Rect { width: 0, ..crop })).is_err());
+        assert_eq!(config.crop, Some(crop));
+        config.set_crop(None).unwrap();
+        assert_eq!(config.encoded_size(2560, 1440).unwrap(), (640, 360));
+    }
+
+    #[test]
+    fn rgb_formats_keep_their_channel_order() {
+        let formats = [
+            (VideoFormat::BGRA, DrmFourcc::Argb8888, "bgra"),
+            (VideoFormat::BGRx, DrmFourcc::Xrgb8888, "bgr0"),
+            (VideoFormat::RGBA, DrmFourcc::Abgr8888, "rgba"),
+            (VideoFormat::RGBx, DrmFourcc::Xbgr8888, "rgb0"),
+        ];
+        for (format, fourcc, pix_fmt) in formats {
+            assert_eq!(drm_fourcc(format).unwrap(), fourcc);
+            assert_eq!(graph_input_format(format).unwrap(), pix_fmt);
+            assert!(!is_ten_bit(format));
+        }
+        assert_eq!(
+            graph_input_format(VideoFormat::xBGR_210LE).unwrap(),
+            "x2bgr10le"
+        );
+        assert!(is_ten_bit(VideoFormat::ABGR_210LE));
+
+        for format in [VideoFormat::RGB, VideoFormat::YUY2, VideoFormat::ARGB] {
+            assert!(matches!(
+                drm_fourcc(format),
+                Err(WaycapError::Unsupported(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn yuv_frames_skip_the_conversion() {
+        assert_eq!(drm_fourcc(VideoFormat::NV12).unwrap(), DrmFourcc::Nv12);
+        assert_eq!(graph_input_format(VideoFormat::NV12).unwrap(), "nv12");
+        assert_eq!(scale_format(VideoFormat::NV12, false), None);
+
+        assert_eq!(drm_fourcc(VideoFormat::I420).unwrap(), DrmFourcc::Yuv420);
+        assert_eq!(graph_input_format(VideoFormat::I420).unwrap(), "yuv420p");
+        assert_eq!(scale_format(VideoFormat::I420, false), Some("nv12"));
+
+        assert_eq!(scale_format(VideoFormat::BGRx, false), Some("nv12"));
+        assert_eq!(scale_format(VideoFormat::xRGB_210LE, true), Some("p010"));
+        assert!(!is_ten_bit(VideoFormat::NV12));
+    }
+}
@@ -1,19 +1,51 @@
-use std::ffi::CString;
+use std::ffi::{CStr, CString};
 use std::ptr::null_mut;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
+use crate::dmabuf_probe::RENDER_NODE;
+use crate::pipeline::cfr::{CfrGrid, Placement};
+use crate::pipeline::dedup::Deduplicator;
+use crate::pipeline::external_copy;
+use crate::pipeline::fence::{self, FENCE_TIMEOUT};
+use crate::pipeline::frame_limiter::FrameLimiter;
+use crate::pipeline::latency::LatencyCheck;
+use crate::pipeline::preview;
+use crate::pipeline::shutdown::StreamKind;
+use crate::soak::{self, ObjectKind};
+use crate::types::config::{
+    ColorMatrix, ColorPrimaries, ColorRange, Colorimetry, DenoiseStrength, DisconnectPolicy,
+    OverlayConfig, QualityPreset, RateControl, Rect, Scaling, TransferCharacteristic, Transform,
+    VideoConfig,
+};
+use crate::types::encoder_info::{EncoderDelay, EncoderInfo};
 use crate::types::error::{Result, WaycapError};
-use crate::types::video_frame::RawVideoFrame;
-use crate::CaptureControls;
+use crate::types::event::{CaptureEvent, EventSender, PipelineStage};
+use crate::types::stats::StatsCounters;
+use crate::types::time::CaptureTime;
+use crate::types::video_frame::{RawVideoFrame, VideoStreamInfo};
+use crate::{failure_injection, ffmpeg_compat, ffmpeg_log, raw_recording, CaptureControls};
 use crossbeam::channel::Receiver;
 use crossbeam::select;
-use ffmpeg::ffi::{av_hwdevice_ctx_create, av_hwframe_ctx_alloc, AVBufferRef};
+use ffmpeg::ffi::{
+    av_buffer_ref, av_buffer_unref, av_hwdevice_ctx_create, av_hwdevice_ctx_create_derived,
+    av_hwframe_ctx_alloc, av_opt_get_int, AVBufferRef, AV_OPT_SEARCH_CHILDREN,
+};
 use ffmpeg_next::{self as ffmpeg};
 use pipewire::spa;
+use pipewire::spa::param::format::FormatProperties;
+use pipewire::spa::param::video::VideoFormat;
+use pipewire::spa::pod::Value;
+use pipewire::spa::utils::{Fraction, Id, Rectangle};
 use std::sync::Mutex;
 
 pub const GOP_SIZE: u32 = 30;
+/// GOP of encoders whose keyframes are placed by
+/// [`crate::types::config::VideoConfig::keyframe_interval`] or a timelapse, long enough to never
+/// insert one on its own
+pub(crate) const SCHEDULED_GOP_SIZE: u32 = 1 << 14;
+/// Encoded frames each video receiver can hold before frames are dropped
+pub(crate) const OUTPUT_CAPACITY: usize = 10;
 
 /// Base trait for video encoders. defines the output type of an encoder.
 ///
@@ -27,6 +59,100 @@ pub trait VideoEncoder: Send + 'static {
     fn drop_processor(&mut self);
     fn drain(&mut self) -> Result<()>;
     fn get_encoder(&self) -> &Option<ffmpeg::codec::encoder::Video>;
+
+    /// Called by [`crate::Capture::switch_source`] before the first frame of a source of
+    /// `width`x`height`. The next output must start with a keyframe. The default drains and
+    /// resets the encoder, which keeps its size.
+    fn source_changed(&mut self, width: u32, height: u32) -> Result<()> {
+        let _ = (width, height);
+        self.drain()?;
+        self.reset()
+    }
+
+    /// Target `bits_per_sec` from the next frame on, see [`crate::Capture::set_bitrate`].
+    /// Encoders which don't follow [`crate::types::config::VideoConfig::rate_control`] can't.
+    fn set_bitrate(&mut self, bits_per_sec: u64) -> Result<()> {
+        let _ = bits_per_sec;
+        Err(WaycapError::Unsupported(
+            "Only the VAAPI and NVENC encoders can change their bitrate".to_string(),
+        ))
+    }
+
+    /// Encode with `quality` from the next frame on, see [`crate::Capture::change_quality`]. The
+    /// encoder is reopened, the next output must start with a keyframe.
+    fn change_quality(&mut self, quality: QualityPreset) -> Result<()> {
+        let _ = quality;
+        Err(WaycapError::Unsupported(
+            "The compositor encodes passed through H.264, its quality can't be changed".to_string(),
+        ))
+    }
+
+    /// Encode `crop` of the frames from the next one on, see [`crate::Capture::set_crop`]. When
+    /// the size the encoder encodes at changes it is reset, and the next output must start with
+    /// a keyframe.
+    fn set_crop(&mut self, crop: Option<Rect>) -> Result<()> {
+        let _ = crop;
+        Err(WaycapError::Unsupported(
+            "Only the VAAPI, QSV, NVENC and software encoders can crop".to_string(),
+        ))
+    }
+
+    /// Blend `overlay` onto the frames from the next one on, see [`crate::Capture::set_overlay`].
+    /// The encoder is kept.
+    fn set_overlay(&mut self, overlay: Option<OverlayConfig>) -> Result<()> {
+        let _ = overlay;
+        Err(WaycapError::Unsupported(
+            "Only the VAAPI, QSV, NVENC and software encoders can draw overlays".to_string(),
+        ))
+    }
+
+    /// Whether anyone still receives the output. Encoders which can't tell always return true.
+    fn has_consumers(&self) -> bool {
+        true
+    }
+
+    /// Details about the opened encoder, `None` for encoders which don't use ffmpeg
+    fn info(&self) -> Option<EncoderInfo> {
+        let codec = self.get_encoder().as_ref()?.codec()?;
+        Some(EncoderInfo {
+            name: codec.name().to_string(),
+            rejected_options: Vec::new(),
+            ..Default::default()
+        })
+    }
+
+    /// Delay the opened encoder's configuration implies when fed a frame every
+    /// `frame_interval`: its B-frames, its lookahead (`rc-lookahead`) and the frames its driver
+    /// keeps in flight (`async_depth`). Zero without an opened encoder.
+    fn encoder_delay(&self, frame_interval: Duration) -> EncoderDelay {
+        let Some(encoder) = self.get_encoder() else {
+            return EncoderDelay::default();
+        };
+        let b_frames = unsafe { (*encoder.as_ptr()).max_b_frames }.max(0) as u32;
+        let lookahead = encoder_option(encoder, "rc-lookahead").unwrap_or(0);
+        // The first frame in flight is the one being encoded, it holds nothing back
+        let in_flight = encoder_option(encoder, "async_depth").map_or(0, |depth| depth.max(1) - 1);
+        let frames = b_frames + lookahead + in_flight;
+        EncoderDelay {
+            frames,
+            time: frame_interval * frames,
+        }
+    }
+}
+
+/// Integer option of the encoder or its private context, `None` if it has no such option
+fn encoder_option(encoder: &ffmpeg::codec::encoder::Video, name: &str) -> Option<u32> {
+    let name = CString::new(name).ok()?;
+    let mut value = 0;
+    let err = unsafe {
+        av_opt_get_int(
+            encoder.as_ptr() as *mut _,
+            name.as_ptr(),
+            AV_OPT_SEARCH_CHILDREN,
+            &mut value,
+        )
+    };
+    (err >= 0).then(|| value.clamp(0, u32::MAX as i64) as u32)
 }
 
 /// Specifies how processing is started for a encoder
@@ -45,6 +171,15 @@ pub trait ProcessingThread: StartVideoEncoder {
     /// Process a single raw frame
     /// this is called from inside the thread started by self.start
     fn process(&mut self, frame: RawVideoFrame) -> Result<()>;
+    /// Encode the frame last handed to the encoder again with `timestamp` as pts, to fill the
+    /// grid of [`crate::types::config::VideoConfig::cfr`] or
+    /// [`crate::types::config::VideoConfig::timelapse`]. The frame is kept as it was sent, so
+    /// nothing is converted or uploaded again. False when there is none, e.g. right after a
+    /// reset, and for encoders which can't repeat frames.
+    fn repeat_frame(&mut self, timestamp: CaptureTime, force_keyframe: bool) -> Result<bool> {
+        let _ = (timestamp, force_keyframe);
+        Ok(false)
+    }
     fn thread_setup(&mut self) -> Result<()> {
         Ok(())
     }
@@ -69,11 +204,29 @@ where
                 .expect("start_processing should be called after Capture.video_encoder is set"),
         );
         let controls = Arc::clone(&capture.controls);
+        let stats = Arc::clone(&capture.stats);
+        let events = capture.event_tx.clone();
+        let disconnect_policy = capture.disconnect_policy;
+        let grid = capture
+            .timelapse
+            .map(CfrGrid::timelapse)
+            .or_else(|| capture.cfr.map(CfrGrid::new));
+        let dedup = capture.dedup.map(Deduplicator::new);
 
         let handle = std::thread::spawn(move || -> Result<()> {
+            let _log_session = ffmpeg_log::SessionScope::enter(controls.session_id());
             encoder.as_ref().lock().unwrap().thread_setup()?;
 
-            let ret = default_processing_loop(input, controls, Arc::clone(&encoder));
+            let ret = default_processing_loop(
+                input,
+                controls,
+                stats,
+                events,
+                disconnect_policy,
+                grid,
+                dedup,
+                Arc::clone(&encoder),
+            );
 
             encoder.as_ref().lock().unwrap().thread_teardown()?;
             ret
@@ -83,28 +236,170 @@ where
     }
 }
 
-/// Default processing loop function. Handles stop/pause and frame interval changes
+/// Default processing loop function. Handles stop/pause, frame interval changes, retiming the
+/// frames onto a `grid`, waiting for the GPU to finish them, skipping the ones `dedup` finds
+/// unchanged and the consumer disconnecting
+#[allow(clippy::too_many_arguments)]
 pub fn default_processing_loop<V: ProcessingThread>(
     input: Receiver<RawVideoFrame>,
     controls: Arc<CaptureControls>,
+    stats: Arc<StatsCounters>,
+    events: EventSender,
+    disconnect_policy: DisconnectPolicy,
+    mut grid: Option<CfrGrid>,
+    mut dedup: Option<Deduplicator>,
     thread_self: Arc<Mutex<V>>,
 ) -> Result<()> {
-    let mut last_timestamp: u64 = 0;
-    let mut frame_interval = controls.frame_interval_ns();
+    let _injection = failure_injection::EncoderScope::enter(&controls);
+    let mut frame_limiter = FrameLimiter::default();
+    // Still screens are filled a frame at a time on a grid
+    let idle_timeout = grid.as_ref().map_or(Duration::from_millis(100), |grid| {
+        grid.interval().min(Duration::from_millis(100))
+    });
+    let mut disconnected = false;
+    let mut latency_check = LatencyCheck::default();
+    controls.cutoff().track(StreamKind::Video);
 
     while !controls.is_stopped() {
         if controls.is_paused() {
+            if disconnected
+                && disconnect_policy == DisconnectPolicy::PauseOnDisconnect
+                && thread_self.lock().unwrap().has_consumers()
+            {
+                log::info!("New video receiver attached, resuming");
+                disconnected = false;
+                controls.resume();
+            }
+            if let Some(ref mut grid) = grid {
+                grid.restart();
+            }
+            if let Some(ref mut dedup) = dedup {
+                dedup.restart();
+            }
             std::thread::sleep(Duration::from_millis(100));
             continue;
         }
         select! {
             recv(input) -> raw_frame => {
                 match raw_frame {
-                    Ok(raw_frame) => {
-                        let current_time = raw_frame.timestamp as u64;
-                        if current_time >= last_timestamp + frame_interval {
-                            thread_self.lock().unwrap().process(raw_frame)?;
-                            last_timestamp = current_time;
+                    Ok(mut raw_frame) => {
+                        let captured_at = raw_frame.timestamp;
+                        let frame_interval = Duration::from_nanos(controls.frame_interval_ns());
+                        // Encoded frames reference earlier ones, none of them can be skipped
+                        let placement = if raw_frame.format == VideoFormat::Encoded {
+                            Some(Placement::unchanged(captured_at))
+                        } else if let Some(ref mut grid) = grid {
+                            grid.place(captured_at, Instant::now())
+                        } else {
+                            frame_limiter
+                                .admit(captured_at, frame_interval)
+                                .then(|| Placement::unchanged(captured_at))
+                        };
+                        // Frames still being rendered would come out torn, only those to be
+                        // encoded are waited for
+                        let unrendered = placement.is_some()
+                            && !fence::wait_until_rendered(&raw_frame, FENCE_TIMEOUT);
+                        // A requested keyframe is encoded even if nothing changed
+                        let duplicate = placement.is_some()
+                            && !unrendered
+                            && raw_frame.format != VideoFormat::Encoded
+                            && dedup
+                                .as_mut()
+                                .is_some_and(|dedup| dedup.is_duplicate(&raw_frame))
+                            && !controls.keyframes().lock().unwrap().is_requested();
+                        if unrendered {
+                            log::debug!(
+                                "Dropping the frame at {captured_at}, the GPU did not finish it in \
+                                 time"
+                            );
+                            if let Some(ref mut dedup) = dedup {
+                                dedup.observe(&raw_frame);
+                            }
+                            stats.mark_fence_timeout();
+                            controls.cutoff().frame_done(StreamKind::Video);
+                        } else if duplicate {
+                            stats.mark_frame_deduplicated();
+                            controls.cutoff().frame_done(StreamKind::Video);
+                        } else if let Some(Placement {
+                            repeats,
+                            time: current_time,
+                            keyframe: grid_keyframe,
+                        }) = placement
+                        {
+                            raw_frame.timestamp = current_time;
+                            stats.mark_frame_encoded();
+                            let encode_start = Instant::now();
+                            let result = (|| -> Result<bool> {
+                                let mut encoder = thread_self.lock().unwrap();
+                                // Checked with the encoder held, which a switch holds too
+                                if controls.is_current_source(captured_at) {
+                                    if let Some(ref grid) = grid {
+                                        let points = grid.points(repeats);
+                                        repeat_frames(
+                                            &mut *encoder,
+                                            &controls,
+                                            &stats,
+                                            points,
+                                        )?;
+                                    }
+                                    if let Some(info) = resized_stream(&controls, &raw_frame) {
+                                        encoder.source_changed(info.width, info.height)?;
+                                        controls.set_stream_info(info);
+                                        log::info!(
+                                            "Video stream resized to {}x{}",
+                                            info.width,
+                                            info.height
+                                        );
+                                        events.send(CaptureEvent::ResolutionChanged {
+                                            info,
+                                            at: current_time,
+                                        });
+                                    }
+                                    let mut keyframes = controls.keyframes().lock().unwrap();
+                                    let scene_change = keyframes.is_scene_change(&raw_frame);
+                                    let scheduled = keyframes.is_keyframe(current_time);
+                                    raw_frame.force_keyframe =
+                                        scheduled || scene_change || grid_keyframe;
+                                    drop(keyframes);
+                                    raw_recording::record(&controls, &raw_frame);
+                                    external_copy::offer(&controls, &stats, &raw_frame);
+                                    preview::offer(&controls, &stats, &raw_frame);
+                                    stats.mark_frame_submitted(current_time);
+                                    encoder.process(raw_frame)?;
+                                }
+                                Ok(encoder.has_consumers())
+                            })();
+                            stats.record_encode_time(encode_start.elapsed());
+                            controls.cutoff().frame_done(StreamKind::Video);
+                            match result {
+                                Ok(true) => disconnected = false,
+                                Ok(false) if !disconnected => {
+                                    disconnected = true;
+                                    on_consumer_disconnect(disconnect_policy, &controls, &events);
+                                }
+                                Ok(false) => {}
+                                Err(e) => {
+                                    stats.record_error(PipelineStage::EncoderOutput, &e);
+                                    return Err(e);
+                                }
+                            }
+                            if let Some(interval) = latency_check.frame(current_time) {
+                                let expected = thread_self
+                                    .lock()
+                                    .unwrap()
+                                    .encoder_delay(interval.max(frame_interval));
+                                if let Some(event) =
+                                    latency_check.compare(expected, stats.encoder_latency())
+                                {
+                                    events.send(event);
+                                }
+                            }
+                        } else {
+                            if let Some(ref mut dedup) = dedup {
+                                dedup.observe(&raw_frame);
+                            }
+                            stats.mark_frame_rate_limited();
+                            controls.cutoff().frame_done(StreamKind::Video);
                         }
                     }
                     Err(_) => {
@@ -113,37 +408,519 @@ pub fn default_processing_loop<V: ProcessingThread>(
                     }
                 }
             }
-            default(Duration::from_millis(100)) => {
-                // Timeout to change fps if needed and check stop/pause flags periodically
-                frame_interval = controls.frame_interval_ns();
+            default(idle_timeout) => {
+                // Timeout to check stop/pause flags periodically, and to repeat the last frame
+                // while the compositor sends none
+                if let Some(ref mut grid) = grid {
+                    let idle = grid.fill_idle(Instant::now());
+                    if !idle.is_empty() {
+                        let points = grid.points(idle);
+                        let mut encoder = thread_self.lock().unwrap();
+                        if let Err(e) = repeat_frames(&mut *encoder, &controls, &stats, points) {
+                            stats.record_error(PipelineStage::EncoderOutput, &e);
+                            return Err(e);
+                        }
+                    }
+                }
             }
         }
     }
     Ok(())
 }
 
+/// Encode the last frame again at each of the grid `points`, a time and whether the grid makes
+/// it a keyframe
+fn repeat_frames<V: ProcessingThread>(
+    encoder: &mut V,
+    controls: &CaptureControls,
+    stats: &StatsCounters,
+    points: impl Iterator<Item = (CaptureTime, bool)>,
+) -> Result<()> {
+    for (time, grid_keyframe) in points {
+        let keyframe = controls.keyframes().lock().unwrap().is_keyframe(time) || grid_keyframe;
+        stats.mark_frame_submitted(time);
+        if encoder.repeat_frame(time, keyframe)? {
+            stats.mark_frame_duplicated();
+        }
+    }
+    Ok(())
+}
+
+/// Parameters of the stream when `frame` is the first one the compositor sent at a new size
+fn resized_stream(controls: &CaptureControls, frame: &RawVideoFrame) -> Option<VideoStreamInfo> {
+    let info = controls.stream_info()?;
+    let Rectangle { width, height } = frame.dimensions;
+    if (width, height) == (info.width, info.height) || width == 0 || height == 0 {
+        return None;
+    }
+    Some(VideoStreamInfo {
+        width,
+        height,
+        ..info
+    })
+}
+
+fn on_consumer_disconnect(
+    policy: DisconnectPolicy,
+    controls: &CaptureControls,
+    events: &EventSender,
+) {
+    events.send(CaptureEvent::ConsumerDisconnected { policy });
+    match policy {
+        DisconnectPolicy::StopOnDisconnect => {
+            log::warn!("Video receiver disconnected, stopping the capture");
+            controls.stop();
+        }
+        DisconnectPolicy::PauseOnDisconnect => {
+            log::warn!("Video receiver disconnected, pausing until a new one is attached");
+            controls.pause();
+        }
+        DisconnectPolicy::Ignore => {
+            log::warn!("Video receiver disconnected, encoding without a receiver");
+        }
+    }
+}
+
 pub trait PipewireSPA {
     fn get_spa_definition() -> Result<spa::pod::Object>;
 }
 
-pub fn create_hw_frame_ctx(device: *mut AVBufferRef) -> Result<*mut AVBufferRef> {
-    unsafe {
-        let frame = av_hwframe_ctx_alloc(device);
+/// Narrow an `EnumFormat` object from [`PipewireSPA::get_spa_definition`] down to exactly the
+/// format, size and framerate of a previously negotiated stream.
+pub fn fixate_spa_definition(
+    mut pw_obj: spa::pod::Object,
+    info: &VideoStreamInfo,
+) -> spa::pod::Object {
+    for prop in pw_obj.properties.iter_mut() {
+        if prop.key == FormatProperties::VideoFormat.as_raw() {
+            prop.value = Value::Id(Id(info.format.as_raw()));
+        } else if prop.key == FormatProperties::VideoSize.as_raw() {
+            prop.value = Value::Rectangle(Rectangle {
+                width: info.width,
+                height: info.height,
+            });
+        } else if prop.key == FormatProperties::VideoFramerate.as_raw() {
+            prop.value = Value::Fraction(Fraction {
+                num: info.framerate.0,
+                denom: info.framerate.1,
+            });
+        }
+    }
+    pw_obj
+}
+
+/// Owned reference to an ffmpeg hardware device or frames context, released when dropped so
+/// every early return gives back exactly the references it took.
+pub(crate) struct HwBufferRef(*mut AVBufferRef);
+
+impl HwBufferRef {
+    /// Take ownership of `buffer`, `None` when it is null
+    ///
+    /// # Safety
+    /// `buffer` must be a reference the caller owns and doesn't unref itself.
+    pub unsafe fn from_raw(buffer: *mut AVBufferRef) -> Option<Self> {
+        if buffer.is_null() {
+            return None;
+        }
+        soak::object_created(ObjectKind::HwContext);
+        Some(Self(buffer))
+    }
 
-        if frame.is_null() {
+    pub fn as_ptr(&self) -> *mut AVBufferRef {
+        self.0
+    }
+
+    /// A new reference for ffmpeg to own, e.g. to store in `hw_device_ctx`
+    pub fn new_ref(&self) -> Result<*mut AVBufferRef> {
+        let buffer = unsafe { av_buffer_ref(self.0) };
+        if buffer.is_null() {
             return Err(WaycapError::Init(
-                "Could not create hw frame context".to_string(),
+                "Could not reference hw context".to_string(),
             ));
         }
+        Ok(buffer)
+    }
+}
+
+impl Drop for HwBufferRef {
+    fn drop(&mut self) {
+        unsafe { av_buffer_unref(&mut self.0) };
+        soak::object_destroyed(ObjectKind::HwContext);
+    }
+}
+
+pub(crate) fn create_hw_frame_ctx(device: &HwBufferRef) -> Result<HwBufferRef> {
+    unsafe { HwBufferRef::from_raw(av_hwframe_ctx_alloc(device.as_ptr())) }
+        .ok_or_else(|| WaycapError::Init("Could not create hw frame context".to_string()))
+}
+
+/// `crop` filter for the VAAPI filter graphs, taking the [`Scaling::source`] of
+/// `width`x`height` frames. On VAAPI surfaces it only marks the region, which `scale_vaapi`
+/// reads from. Nothing when the source is the whole frame.
+pub(crate) fn add_crop_filter(
+    graph: &mut ffmpeg::filter::Graph,
+    source: Rect,
+    width: u32,
+    height: u32,
+) -> Result<Option<ffmpeg::filter::Context>> {
+    if (source.x, source.y, source.width, source.height) == (0, 0, width, height) {
+        return Ok(None);
+    }
+    let args = format!(
+        "w={}:h={}:x={}:y={}:exact=1",
+        source.width, source.height, source.x, source.y
+    );
+    Ok(Some(graph.add(
+        &ffmpeg_compat::find_filter("crop")?,
+        "crop",
+        &args,
+    )?))
+}
+
+/// `transpose_vaapi` filter turning the cropped frames for [`Scaling::transform`], nothing for
+/// [`Transform::Normal`]
+pub(crate) fn add_transpose_filter(
+    graph: &mut ffmpeg::filter::Graph,
+    transform: Transform,
+) -> Result<Option<ffmpeg::filter::Context>> {
+    let direction = match transform {
+        Transform::Normal => return Ok(None),
+        Transform::Rot90 => "cclock",
+        Transform::Rot180 => "reversal",
+        Transform::Rot270 => "clock",
+        Transform::Flipped => "hflip",
+        Transform::Flipped90 => "cclock_flip",
+        Transform::Flipped180 => "vflip",
+        Transform::Flipped270 => "clock_flip",
+    };
+    Ok(Some(graph.add(
+        &ffmpeg_compat::find_filter("transpose_vaapi")?,
+        "transpose",
+        &format!("dir={direction}"),
+    )?))
+}
+
+/// `pad_vaapi` filter letterboxing the scaled picture for
+/// [`crate::types::config::ScaleMode::Fit`], nothing when it covers the encoded frames
+pub(crate) fn add_pad_filter(
+    graph: &mut ffmpeg::filter::Graph,
+    scaling: &Scaling,
+    [red, green, blue]: [u8; 3],
+) -> Result<Option<ffmpeg::filter::Context>> {
+    if !scaling.is_padded() {
+        return Ok(None);
+    }
+    let args = format!(
+        "w={}:h={}:x={}:y={}:color=0x{red:02x}{green:02x}{blue:02x}",
+        scaling.output.0, scaling.output.1, scaling.picture.x, scaling.picture.y
+    );
+    Ok(Some(graph.add(
+        &ffmpeg_compat::find_filter("pad_vaapi")?,
+        "pad",
+        &args,
+    )?))
+}
+
+/// `denoise_vaapi` filter for [`VideoConfig::denoise`], nothing without it
+pub(crate) fn add_denoise_filter(
+    graph: &mut ffmpeg::filter::Graph,
+    denoise: Option<DenoiseStrength>,
+) -> Result<Option<ffmpeg::filter::Context>> {
+    // Out of 64
+    let level = match denoise {
+        None => return Ok(None),
+        Some(DenoiseStrength::Light) => 12,
+        Some(DenoiseStrength::Medium) => 28,
+        Some(DenoiseStrength::Strong) => 48,
+    };
+    Ok(Some(graph.add(
+        &ffmpeg_compat::find_filter("denoise_vaapi")?,
+        "denoise",
+        &format!("denoise={level}"),
+    )?))
+}
+
+/// The VAAPI filter graph `build` makes with the [`VideoConfig::denoise`] it is given. Not every
+/// driver has a denoise filter, which ffmpeg only finds out on validating the graph, it is built
+/// again without one then.
+pub(crate) fn with_denoise_fallback(
+    config: &VideoConfig,
+    build: impl Fn(Option<DenoiseStrength>) -> Result<ffmpeg::filter::Graph>,
+) -> Result<ffmpeg::filter::Graph> {
+    match build(config.denoise) {
+        Err(e) if config.denoise.is_some() => {
+            let graph = build(None)?;
+            log::warn!("Encoding without denoising, the driver can't: {e}");
+            Ok(graph)
+        }
+        result => result,
+    }
+}
 
-        Ok(frame)
+/// Send `frame`, which went to `encoder` before, once more as the frame at `timestamp`, see
+/// [`ProcessingThread::repeat_frame`]. The encoder takes another reference to its buffers.
+pub(crate) fn resend_frame(
+    encoder: &mut ffmpeg::codec::encoder::Video,
+    frame: &mut ffmpeg::util::frame::Video,
+    timestamp: CaptureTime,
+    force_keyframe: bool,
+) -> Result<()> {
+    frame.set_pts(Some(timestamp.as_nanos()));
+    frame.set_kind(if force_keyframe {
+        ffmpeg::picture::Type::I
+    } else {
+        ffmpeg::picture::Type::None
+    });
+    failure_injection::send_frame(encoder, frame)
+}
+
+/// The transform the compositor put on `frame` when the encoder follows it, see
+/// [`VideoConfig::transform`], and was built for another one
+pub(crate) fn changed_frame_transform(
+    config: &VideoConfig,
+    follows_compositor: bool,
+    frame: &RawVideoFrame,
+) -> Option<Transform> {
+    if !follows_compositor || config.transform.unwrap_or_default() == frame.transform {
+        return None;
     }
+    log::info!(
+        "Following the {:?} transform of the compositor",
+        frame.transform
+    );
+    Some(frame.transform)
 }
 
-pub fn create_hw_device(device_type: ffmpeg_next::ffi::AVHWDeviceType) -> Result<*mut AVBufferRef> {
+/// ffmpeg's name for `range`, set on the encoder contexts so the streams are tagged with it
+pub(crate) fn ffmpeg_color_range(range: ColorRange) -> ffmpeg::color::Range {
+    match range {
+        ColorRange::Limited => ffmpeg::color::Range::MPEG,
+        ColorRange::Full => ffmpeg::color::Range::JPEG,
+    }
+}
+
+/// Tag the streams of `encoder` with `colorimetry`. The filter graphs and swscale read the
+/// matrix back from the opened encoder to convert with.
+pub(crate) fn set_colorimetry(
+    encoder: &mut ffmpeg::codec::encoder::video::Video,
+    colorimetry: Colorimetry,
+) {
+    encoder.set_colorspace(match colorimetry.matrix {
+        ColorMatrix::Bt709 => ffmpeg::color::Space::BT709,
+        ColorMatrix::Bt601 => ffmpeg::color::Space::SMPTE170M,
+        ColorMatrix::Bt2020 => ffmpeg::color::Space::BT2020NCL,
+    });
+    let primaries = match colorimetry.primaries {
+        ColorPrimaries::Bt709 => ffmpeg::color::Primaries::BT709,
+        ColorPrimaries::Bt2020 => ffmpeg::color::Primaries::BT2020,
+    };
+    let transfer = match colorimetry.transfer {
+        TransferCharacteristic::Bt709 => ffmpeg::color::TransferCharacteristic::BT709,
+        TransferCharacteristic::Srgb => ffmpeg::color::TransferCharacteristic::IEC61966_2_1,
+        TransferCharacteristic::Pq => ffmpeg::color::TransferCharacteristic::SMPTE2084,
+        TransferCharacteristic::Hlg => ffmpeg::color::TransferCharacteristic::ARIB_STD_B67,
+    };
+    // The encoder context has no setters for these
+    unsafe {
+        let ctx = encoder.as_mut_ptr();
+        (*ctx).color_primaries = primaries.into();
+        (*ctx).color_trc = transfer.into();
+    }
+}
+
+/// `scale_vaapi` options converting to the range and colorimetry `encoder` tags its stream with
+pub(crate) fn scale_color_args(encoder: &ffmpeg::codec::encoder::Video) -> String {
+    let range = match encoder.color_range() {
+        ffmpeg::color::Range::JPEG => "pc",
+        _ => "tv",
+    };
+    let (primaries, transfer) = unsafe {
+        let ctx = encoder.as_ptr();
+        (
+            ffmpeg::color::Primaries::from((*ctx).color_primaries),
+            ffmpeg::color::TransferCharacteristic::from((*ctx).color_trc),
+        )
+    };
+    let mut args = format!("out_range={range}");
+    for (option, name) in [
+        ("out_color_matrix", encoder.colorspace().name()),
+        ("out_color_primaries", primaries.name()),
+        ("out_color_transfer", transfer.name()),
+    ] {
+        if let Some(name) = name {
+            args.push_str(&format!(":{option}={name}"));
+        }
+    }
+    args
+}
+
+/// Link each filter's first output to the first input of the next one
+pub(crate) fn link_filters(filters: &mut [ffmpeg::filter::Context]) {
+    for index in 1..filters.len() {
+        let (linked, rest) = filters.split_at_mut(index);
+        linked[index - 1].link(0, &mut rest[0], 0);
+    }
+}
+
+/// Link `filters` like [`link_filters`], with the chain of [`VideoConfig::custom_filter`] in
+/// between the first `split` of them and the rest. Its filters get the device of `encoder`.
+pub(crate) fn link_filters_with_custom(
+    graph: &mut ffmpeg::filter::Graph,
+    filters: &mut [ffmpeg::filter::Context],
+    split: usize,
+    encoder: &ffmpeg::codec::encoder::Video,
+    config: &VideoConfig,
+) -> Result<()> {
+    let Some(ref custom) = config.custom_filter else {
+        link_filters(filters);
+        return Ok(());
+    };
+    let (captured, processed) = filters.split_at_mut(split);
+    link_filters(captured);
+    link_filters(processed);
+    // The chain is labelled with the names of the filters around it, which is how the parser
+    // finds their pads
+    let [from, to] = [&captured[split - 1], &processed[0]].map(|filter| {
+        unsafe { CStr::from_ptr((*filter.as_ptr()).name) }
+            .to_string_lossy()
+            .into_owned()
+    });
+    let existing = unsafe { (*graph.as_ptr()).nb_filters };
+    let (parsed, messages) = ffmpeg_log::collect_messages(|| {
+        graph
+            .output(&from, 0)?
+            .input(&to, 0)?
+            .parse(&format!("[{from}]{custom}[{to}]"))
+    });
+    parsed.map_err(|e| custom_filter_error(custom, e, messages))?;
+    unsafe {
+        let device = (*encoder.as_ptr()).hw_device_ctx;
+        let graph = &*graph.as_ptr();
+        for index in existing..graph.nb_filters {
+            let filter = *graph.filters.add(index as usize);
+            if !device.is_null() && (*filter).hw_device_ctx.is_null() {
+                (*filter).hw_device_ctx = av_buffer_ref(device);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Validate `graph`, with ffmpeg's explanation in the error when it has a
+/// [`VideoConfig::custom_filter`]. Most chains which don't fit only fail here, on formats
+/// filters can't agree on.
+pub(crate) fn validate_graph(
+    graph: &mut ffmpeg::filter::Graph,
+    config: &VideoConfig,
+) -> Result<()> {
+    let Some(ref custom) = config.custom_filter else {
+        return Ok(graph.validate()?);
+    };
+    let (validated, messages) = ffmpeg_log::collect_messages(|| graph.validate());
+    validated.map_err(|e| custom_filter_error(custom, e, messages))
+}
+
+fn custom_filter_error(custom: &str, error: ffmpeg::Error, messages: Vec<String>) -> WaycapError {
+    WaycapError::Init(format!(
+        "The custom filter {custom:?} failed with {error}: {}",
+        messages.join("; ")
+    ))
+}
+
+/// Error of encoders which can't encode [`crate::types::config::QualityPreset::Lossless`]
+pub(crate) fn lossless_unsupported(encoder: &str) -> WaycapError {
+    WaycapError::Unsupported(format!(
+        "{encoder} can't encode losslessly, use H.264 (VAAPI) or NVENC, or leave the video \
+         encoder unset"
+    ))
+}
+
+/// The `b`, `maxrate` and `bufsize` options of rate control targeting a bitrate, see
+/// [`RateControl::bitrate_limits`]. Nothing for a constant qp.
+pub(crate) fn set_bitrate_options(opts: &mut ffmpeg::Dictionary, rate_control: RateControl) {
+    if let Some((bitrate, max_bitrate, buffer_size)) = rate_control.bitrate_limits() {
+        opts.set("b", &bitrate.to_string());
+        opts.set("maxrate", &max_bitrate.to_string());
+        opts.set("bufsize", &buffer_size.to_string());
+    }
+}
+
+/// Open the encoder and return the options it did not recognize along with it.
+///
+/// ffmpeg silently ignores these, so they are logged, or fail the opening when `strict` is set.
+pub(crate) fn open_encoder(
+    mut encoder_ctx: ffmpeg::codec::encoder::video::Video,
+    options: ffmpeg::Dictionary,
+    strict: bool,
+) -> Result<(ffmpeg::codec::encoder::Video, Vec<String>)> {
+    let name = encoder_ctx
+        .codec()
+        .map(|codec| codec.name().to_string())
+        .unwrap_or_default();
+
+    // Same as `open_with`, but keeping the dictionary ffmpeg hands back with the unused entries
+    let remaining = unsafe {
+        let mut opts = options.disown();
+        let res = ffmpeg::ffi::avcodec_open2(encoder_ctx.as_mut_ptr(), null_mut(), &mut opts);
+        let remaining = ffmpeg::Dictionary::own(opts);
+        if res < 0 {
+            return Err(ffmpeg::Error::from(res).into());
+        }
+        remaining
+    };
+
+    let rejected: Vec<String> = remaining.iter().map(|(key, _)| key.to_string()).collect();
+    if !rejected.is_empty() {
+        let rejected = rejected.join(", ");
+        if strict {
+            return Err(WaycapError::Config(format!(
+                "{name} does not recognize the options: {rejected}"
+            )));
+        }
+        log::warn!("{name} ignored unrecognized options: {rejected}");
+    }
+
+    let encoder = ffmpeg::codec::encoder::video::Encoder(encoder_ctx);
+    Ok((encoder, rejected))
+}
+
+/// [`open_encoder`] with the [`VideoConfig::extra_encoder_options`] of `config` set over
+/// `options`. The extra options were asked for by name, so one the encoder doesn't recognize
+/// fails the opening even without [`VideoConfig::strict_options`].
+pub(crate) fn open_configured_encoder(
+    encoder_ctx: ffmpeg::codec::encoder::video::Video,
+    mut options: ffmpeg::Dictionary,
+    config: &VideoConfig,
+) -> Result<(ffmpeg::codec::encoder::Video, Vec<String>)> {
+    for (key, value) in &config.extra_encoder_options {
+        options.set(key, value);
+    }
+    let (encoder, rejected) = open_encoder(encoder_ctx, options, config.strict_options)?;
+    let unknown: Vec<&str> = config
+        .extra_encoder_options
+        .iter()
+        .map(|(key, _)| key.as_str())
+        .filter(|key| rejected.iter().any(|rejected| rejected == key))
+        .collect();
+    if !unknown.is_empty() {
+        let name = encoder
+            .codec()
+            .map(|codec| codec.name().to_string())
+            .unwrap_or_default();
+        return Err(WaycapError::Config(format!(
+            "{name} does not recognize the extra options: {}",
+            unknown.join(", ")
+        )));
+    }
+    Ok((encoder, rejected))
+}
+
+pub(crate) fn create_hw_device(
+    device_type: ffmpeg_next::ffi::AVHWDeviceType,
+) -> Result<HwBufferRef> {
     unsafe {
         let mut device: *mut AVBufferRef = null_mut();
-        let device_path = CString::new("/dev/dri/renderD128").unwrap();
+        let device_path = CString::new(RENDER_NODE).unwrap();
         let ret = av_hwdevice_ctx_create(
             &mut device,
             device_type,
@@ -157,6 +934,142 @@ pub fn create_hw_device(device_type: ffmpeg_next::ffi::AVHWDeviceType) -> Result
             )));
         }
 
-        Ok(device)
+        HwBufferRef::from_raw(device)
+            .ok_or_else(|| WaycapError::Init("Failed to create hardware device".to_string()))
+    }
+}
+
+/// Create a device of `device_type` on top of `source`, sharing its underlying handle
+pub(crate) fn derive_hw_device(
+    source: &HwBufferRef,
+    device_type: ffmpeg_next::ffi::AVHWDeviceType,
+) -> Result<HwBufferRef> {
+    unsafe {
+        let mut device: *mut AVBufferRef = null_mut();
+        let ret = av_hwdevice_ctx_create_derived(&mut device, device_type, source.as_ptr(), 0);
+        if ret < 0 {
+            return Err(WaycapError::Init(format!(
+                "Failed to derive {device_type:?} device: Error code {ret:?}",
+            )));
+        }
+
+        HwBufferRef::from_raw(device)
+            .ok_or_else(|| WaycapError::Init("Failed to derive hardware device".to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::Runtime;
+
+    /// rawvideo ships with every ffmpeg build, so this doesn't need a GPU
+    fn rawvideo_ctx() -> ffmpeg::codec::encoder::video::Video {
+        let codec = ffmpeg::codec::encoder::find(ffmpeg::codec::Id::RAWVIDEO).unwrap();
+        let mut encoder_ctx = ffmpeg::codec::context::Context::new_with_codec(codec)
+            .encoder()
+            .video()
+            .unwrap();
+        encoder_ctx.set_width(64);
+        encoder_ctx.set_height(64);
+        encoder_ctx.set_format(ffmpeg::format::Pixel::YUV420P);
+        encoder_ctx.set_time_base(ffmpeg::Rational::new(1, 60));
+        encoder_ctx
+    }
+
+    fn bogus_options() -> ffmpeg::Dictionary<'static> {
+        let mut opts = ffmpeg::Dictionary::new();
+        opts.set("threads", "1");
+        opts.set("definitely_not_an_option", "1");
+        opts
+    }
+
+    #[test]
+    fn reports_rejected_options() {
+        let (_, rejected) = open_encoder(rawvideo_ctx(), bogus_options(), false).unwrap();
+        assert_eq!(rejected, vec!["definitely_not_an_option".to_string()]);
+    }
+
+    #[test]
+    fn strict_mode_fails_on_rejected_options() {
+        let result = open_encoder(rawvideo_ctx(), bogus_options(), true);
+        assert!(matches!(result, Err(WaycapError::Config(_))));
+    }
+
+    #[test]
+    fn extra_options_win_and_must_be_recognized() {
+        let mut opts = ffmpeg::Dictionary::new();
+        opts.set("threads", "1");
+        let config = VideoConfig {
+            extra_encoder_options: vec![("threads".to_string(), "2".to_string())],
+            ..Default::default()
+        };
+        let (encoder, rejected) = open_configured_encoder(rawvideo_ctx(), opts, &config).unwrap();
+        assert!(rejected.is_empty());
+        assert_eq!(unsafe { (*encoder.as_ptr()).thread_count }, 2);
+
+        let config = VideoConfig {
+            extra_encoder_options: vec![("definitely_not_an_option".to_string(), "1".to_string())],
+            ..Default::default()
+        };
+        let result = open_configured_encoder(rawvideo_ctx(), ffmpeg::Dictionary::new(), &config);
+        assert!(matches!(result, Err(WaycapError::Config(_))));
+    }
+
+    #[test]
+    fn notices_resized_streams() {
+        let controls = CaptureControls::from_fps(60);
+        let frame = |width, height| RawVideoFrame::bgrx(width, height);
+        // Nothing to compare to before the stream was negotiated
+        assert_eq!(resized_stream(&controls, &frame(1920, 1080)), None);
+
+        let info = VideoStreamInfo {
+            width: 1920,
+            height: 1080,
+            format: VideoFormat::BGRx,
+            framerate: (60, 1),
+        };
+        controls.set_stream_info(info);
+        assert_eq!(resized_stream(&controls, &frame(1920, 1080)), None);
+        assert_eq!(
+            resized_stream(&controls, &frame(1080, 1920)),
+            Some(VideoStreamInfo {
+                width: 1080,
+                height: 1920,
+                ..info
+            })
+        );
+    }
+
+    #[test]
+    fn custom_filters_are_spliced_in() {
+        let _runtime = Runtime::acquire().unwrap();
+        let (encoder, _) = open_encoder(rawvideo_ctx(), ffmpeg::Dictionary::new(), false).unwrap();
+        let build = |custom: Option<&str>| {
+            let mut graph = ffmpeg::filter::Graph::new();
+            let args = "video_size=64x64:pix_fmt=bgra:time_base=1/1000000";
+            let mut filters = vec![
+                graph.add(&ffmpeg_compat::find_filter("buffer")?, "in", args)?,
+                graph.add(&ffmpeg_compat::find_filter("buffersink")?, "out", "")?,
+            ];
+            let config = VideoConfig {
+                custom_filter: custom.map(str::to_string),
+                ..Default::default()
+            };
+            link_filters_with_custom(&mut graph, &mut filters, 1, &encoder, &config)?;
+            validate_graph(&mut graph, &config)?;
+            Ok::<_, WaycapError>(graph.dump())
+        };
+
+        assert!(!build(None).unwrap().contains("Parsed_"));
+        let dump = build(Some("hflip,vflip")).unwrap();
+        assert!(dump.contains("Parsed_hflip_0") && dump.contains("Parsed_vflip_1"));
+        // ffmpeg's complaint ends up in the error
+        match build(Some("hflip,no_such_filter")) {
+            Err(WaycapError::Init(message)) => {
+                assert!(message.contains("No such filter"), "{message}")
+            }
+            other => panic!("{other:?}"),
+        }
     }
 }
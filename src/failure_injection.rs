@@ -0,0 +1,394 @@
+//! Hooks to simulate capture failures for testing how an application handles them.
+//!
+//! The [`FailureInjector`] handle is only available with the `failure-injection` feature. The
+//! crate internal hooks below compile down to no-ops without it.
+
+use std::sync::Arc;
+#[cfg(feature = "failure-injection")]
+use std::{
+    cell::RefCell,
+    sync::atomic::{AtomicBool, AtomicI64, AtomicU32, Ordering},
+};
+
+use crossbeam::channel::{Sender, TrySendError};
+use ffmpeg_next as ffmpeg;
+
+use crate::{
+    types::{error::Result, time::CaptureTime},
+    CaptureControls,
+};
+
+#[cfg(feature = "failure-injection")]
+thread_local! {
+    /// Controls of the capture whose encoder runs on this thread, see [`EncoderScope`]
+    static ENCODER_CONTROLS: RefCell<Option<Arc<CaptureControls>>> = const { RefCell::new(None) };
+}
+
+/// Handle to simulate failures in a running capture.
+///
+/// Every injected failure is raised where the real failure would surface, so it takes the same
+/// code path afterwards. Get one with [`crate::CaptureControls::failure_injector`].
+#[cfg(feature = "failure-injection")]
+#[derive(Debug, Default)]
+pub struct FailureInjector {
+    channel_full: AtomicBool,
+    encoder_errors: AtomicU32,
+    stream_error: AtomicBool,
+    device_lost: AtomicBool,
+    clock_offset_ns: AtomicI64,
+}
+
+#[cfg(feature = "failure-injection")]
+impl FailureInjector {
+    /// Report the raw video frame channel and the audio ring as full until cleared
+    pub fn set_channel_full(&self, full: bool) {
+        self.channel_full.store(full, Ordering::Release);
+    }
+
+    /// Fail the next `count` video frames as if the encoder rejected them in `send_frame`
+    pub fn fail_encoder_frames(&self, count: u32) {
+        self.encoder_errors.fetch_add(count, Ordering::AcqRel);
+    }
+
+    /// Put the PipeWire video stream into the error state on its next process callback
+    pub fn trigger_stream_error(&self) {
+        self.stream_error.store(true, Ordering::Release);
+    }
+
+    /// Fail every video frame as if the GPU went away until cleared
+    pub fn set_device_lost(&self, lost: bool) {
+        self.device_lost.store(lost, Ordering::Release);
+    }
+
+    /// Shift all following capture timestamps by `offset_ns`, negative values jump backwards.
+    /// Jumps accumulate.
+    pub fn jump_clock(&self, offset_ns: i64) {
+        self.clock_offset_ns.fetch_add(offset_ns, Ordering::AcqRel);
+    }
+}
+
+/// `try_send` on a raw frame channel which reports injected channel-full failures
+#[cfg_attr(not(feature = "failure-injection"), allow(unused_variables))]
+pub(crate) fn try_send<T>(
+    controls: &CaptureControls,
+    sender: &Sender<T>,
+    value: T,
+) -> std::result::Result<(), TrySendError<T>> {
+    if queue_full(controls) {
+        return Err(TrySendError::Full(value));
+    }
+    sender.try_send(value)
+}
+
+/// Whether raw frame queues should report being full, for queues which aren't channels
+#[cfg_attr(not(feature = "failure-injection"), allow(unused_variables))]
+pub(crate) fn queue_full(controls: &CaptureControls) -> bool {
+    #[cfg(feature = "failure-injection")]
+    return controls
+        .failure_injector()
+        .channel_full
+        .load(Ordering::Acquire);
+    #[cfg(not(feature = "failure-injection"))]
+    false
+}
+
+/// Capture time of a clock reading in nanoseconds, shifted by the injected clock jumps
+pub(crate) fn capture_time(controls: &CaptureControls, clock_ns: i64) -> CaptureTime {
+    CaptureTime::from_nanos(clock_ns + clock_offset_ns(controls))
+}
+
+/// Offset to add to capture timestamps
+#[cfg_attr(not(feature = "failure-injection"), allow(unused_variables))]
+fn clock_offset_ns(controls: &CaptureControls) -> i64 {
+    #[cfg(feature = "failure-injection")]
+    return controls
+        .failure_injector()
+        .clock_offset_ns
+        .load(Ordering::Acquire);
+    #[cfg(not(feature = "failure-injection"))]
+    0
+}
+
+/// Makes the frames sent to a video encoder on the current thread fail as injected into a
+/// capture until dropped
+pub(crate) struct EncoderScope {
+    #[cfg(feature = "failure-injection")]
+    previous: Option<Arc<CaptureControls>>,
+}
+
+impl EncoderScope {
+    #[cfg_attr(not(feature = "failure-injection"), allow(unused_variables))]
+    pub fn enter(controls: &Arc<CaptureControls>) -> Self {
+        Self {
+            #[cfg(feature = "failure-injection")]
+            previous: ENCODER_CONTROLS.replace(Some(Arc::clone(controls))),
+        }
+    }
+}
+
+#[cfg(feature = "failure-injection")]
+impl Drop for EncoderScope {
+    fn drop(&mut self) {
+        ENCODER_CONTROLS.set(self.previous.take());
+    }
+}
+
+/// `send_frame` on a video encoder which fails with the encoder errors injected into the capture
+/// of the current [`EncoderScope`]
+pub(crate) fn send_frame(
+    encoder: &mut ffmpeg::codec::encoder::Video,
+    frame: &ffmpeg::util::frame::Video,
+) -> Result<()> {
+    #[cfg(feature = "failure-injection")]
+    ENCODER_CONTROLS.with_borrow(|controls| controls.as_deref().map_or(Ok(()), encoder_error))?;
+    encoder.send_frame(frame)?;
+    Ok(())
+}
+
+/// Error the encoder should fail the current frame with
+#[cfg(feature = "failure-injection")]
+fn encoder_error(controls: &CaptureControls) -> Result<()> {
+    let injector = controls.failure_injector();
+    if injector.device_lost.load(Ordering::Acquire) {
+        return Err(ffmpeg::Error::Other { errno: libc::EIO }.into());
+    }
+    let fail = injector
+        .encoder_errors
+        .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| n.checked_sub(1))
+        .is_ok();
+    if fail {
+        return Err(ffmpeg::Error::Other {
+            errno: libc::EINVAL,
+        }
+        .into());
+    }
+    Ok(())
+}
+
+/// Whether the video stream should be put into the error state now
+#[cfg_attr(not(feature = "failure-injection"), allow(unused_variables))]
+pub(crate) fn take_stream_error(controls: &CaptureControls) -> bool {
+    #[cfg(feature = "failure-injection")]
+    return controls
+        .failure_injector()
+        .stream_error
+        .swap(false, Ordering::AcqRel);
+    #[cfg(not(feature = "failure-injection"))]
+    false
+}
+
+#[cfg(all(test, feature = "failure-injection"))]
+mod tests {
+    use std::{
+        sync::Mutex,
+        thread::JoinHandle,
+        time::{Duration, Instant},
+    };
+
+    use crossbeam::channel::{bounded, Receiver};
+
+    use super::*;
+    use crate::{
+        capture::synthetic,
+        encoders::{
+            software_encoder::{SoftwareCodec, SoftwareEncoder},
+            video::{default_processing_loop, VideoEncoder},
+        },
+        pipeline::watchdog::watchdog_loop,
+        runtime::Runtime,
+        types::{
+            config::{DisconnectPolicy, VideoConfig, WatchdogConfig},
+            error::WaycapError,
+            event::{CaptureEvent, EventSender, PipelineStage},
+            stats::StatsCounters,
+            video_frame::EncodedVideoFrame,
+        },
+        ReadyState, RAW_FRAME_CAPACITY,
+    };
+
+    /// Frame rate of the synthetic source
+    const FPS: u64 = 50;
+    /// How often the synthetic source delivers a frame
+    const FRAME_PERIOD: Duration = Duration::from_millis(1000 / FPS);
+
+    /// A capture of synthetic frames through the processing loop and the software encoder, with
+    /// the watchdog looking on
+    struct Pipeline {
+        controls: Arc<CaptureControls>,
+        stats: Arc<StatsCounters>,
+        packets: Receiver<EncodedVideoFrame>,
+        events: Receiver<CaptureEvent>,
+        encoding: JoinHandle<Result<()>>,
+        _runtime: Runtime,
+    }
+
+    impl Pipeline {
+        fn start() -> Self {
+            let runtime = Runtime::acquire().unwrap();
+            let controls = Arc::new(CaptureControls::from_fps(FPS));
+            let stats = Arc::new(StatsCounters::default());
+            let (event_tx, events) = bounded(16);
+            let events_tx = EventSender::new(event_tx);
+
+            let mut encoder =
+                SoftwareEncoder::new(64, 48, SoftwareCodec::H264, VideoConfig::default()).unwrap();
+            encoder.set_stats(Arc::clone(&stats));
+            let packets = encoder.output().unwrap();
+            let encoded_queue = encoder.output_queues();
+            let encoder = Arc::new(Mutex::new(encoder));
+
+            let (frame_tx, frame_rx) = bounded(RAW_FRAME_CAPACITY);
+            watchdog_loop(
+                WatchdogConfig {
+                    interval: Duration::from_millis(10),
+                    stall_timeout: Duration::from_millis(50),
+                    auto_recover: false,
+                },
+                Arc::clone(&stats),
+                frame_rx.clone(),
+                encoded_queue,
+                Arc::clone(&encoder),
+                events_tx.clone(),
+                Arc::clone(&controls),
+            );
+            let encoding = {
+                let (controls, stats) = (Arc::clone(&controls), Arc::clone(&stats));
+                std::thread::spawn(move || {
+                    default_processing_loop(
+                        frame_rx,
+                        controls,
+                        stats,
+                        events_tx,
+                        DisconnectPolicy::default(),
+                        None,
+                        None,
+                        encoder,
+                    )
+                })
+            };
+
+            // Without audio, which the source would wait for
+            let ready_state = Arc::new(ReadyState::default());
+            ready_state.audio.store(true, Ordering::Release);
+            synthetic::start(
+                synthetic::stream_info(64, 48, FPS),
+                ready_state,
+                Arc::clone(&controls),
+                Arc::clone(&stats),
+                frame_tx,
+            );
+
+            controls.resume();
+            Self {
+                controls,
+                stats,
+                packets,
+                events,
+                encoding,
+                _runtime: runtime,
+            }
+        }
+
+        fn injector(&self) -> &FailureInjector {
+            self.controls.failure_injector()
+        }
+
+        /// Timestamp of the next encoded packet in nanoseconds
+        fn next_pts(&self) -> i64 {
+            let packet = self.packets.recv_timeout(Duration::from_secs(2)).unwrap();
+            packet.pts.rescale(CaptureTime::TIME_BASE).value
+        }
+
+        /// The next stall the watchdog reports, skipping other events
+        fn next_stall(&self) -> Option<(PipelineStage, Vec<(PipelineStage, String)>)> {
+            let deadline = Instant::now() + Duration::from_secs(2);
+            while let Ok(event) = self.events.recv_deadline(deadline) {
+                if let CaptureEvent::PipelineStalled {
+                    stage, last_errors, ..
+                } = event
+                {
+                    return Some((stage, last_errors));
+                }
+            }
+            None
+        }
+
+        /// Asserts the processing loop gave up with `errno` from the encoder, which the watchdog
+        /// then reports
+        fn assert_encoder_failed(self, errno: i32) {
+            let expected = ffmpeg::Error::Other { errno };
+            let result = self.encoding.join().unwrap();
+            assert!(
+                matches!(result, Err(WaycapError::FFmpeg(e)) if e == expected),
+                "{result:?}"
+            );
+
+            let error = WaycapError::from(expected).to_string();
+            let recorded = (PipelineStage::EncoderOutput, error);
+            assert!(self.stats.last_errors().contains(&recorded));
+            // Frames keep coming in while none are encoded anymore
+            let (stage, last_errors) = self.next_stall().expect("no stall reported");
+            assert_eq!(stage, PipelineStage::EncoderInput);
+            assert!(last_errors.contains(&recorded));
+            self.controls.stop();
+        }
+    }
+
+    #[test]
+    fn an_encoder_error_ends_the_video_and_is_reported_as_a_stall() {
+        let pipeline = Pipeline::start();
+        pipeline.next_pts();
+        pipeline.injector().fail_encoder_frames(1);
+        pipeline.assert_encoder_failed(libc::EINVAL);
+    }
+
+    #[test]
+    fn a_lost_device_ends_the_video_and_is_reported_as_a_stall() {
+        let pipeline = Pipeline::start();
+        pipeline.next_pts();
+        pipeline.injector().set_device_lost(true);
+        pipeline.assert_encoder_failed(libc::EIO);
+    }
+
+    #[test]
+    fn a_full_channel_drops_frames_at_capture_then_recovers() {
+        let pipeline = Pipeline::start();
+        pipeline.next_pts();
+
+        pipeline.injector().set_channel_full(true);
+        std::thread::sleep(FRAME_PERIOD * 10);
+        // Anything queued before is still encoded
+        while pipeline.packets.recv_timeout(FRAME_PERIOD * 3).is_ok() {}
+        let dropped = pipeline.stats.snapshot().frames_dropped_at_capture;
+        assert!(dropped > 0);
+        assert!(pipeline
+            .stats
+            .last_errors()
+            .contains(&(PipelineStage::Capture, "Raw frame channel full".to_string())));
+        // Dropping at capture is expected, not a stall
+        assert!(pipeline.next_stall().is_none());
+
+        pipeline.injector().set_channel_full(false);
+        pipeline.next_pts();
+        pipeline.controls.stop();
+        assert!(pipeline.encoding.join().unwrap().is_ok());
+    }
+
+    #[test]
+    fn a_clock_jump_shifts_the_packet_timestamps() {
+        const JUMP_NS: i64 = 10_000_000_000;
+
+        let pipeline = Pipeline::start();
+        let before = pipeline.next_pts();
+        pipeline.injector().jump_clock(JUMP_NS);
+        // Frames queued before the jump come out first
+        let after = (0..=RAW_FRAME_CAPACITY)
+            .map(|_| pipeline.next_pts())
+            .find(|&pts| pts >= before + JUMP_NS);
+        assert!(after.is_some());
+        assert!(pipeline.next_stall().is_none());
+
+        pipeline.controls.stop();
+        assert!(pipeline.encoding.join().unwrap().is_ok());
+    }
+}
@@ -0,0 +1,136 @@
+//! Differences between the ffmpeg releases the crate runs against.
+//!
+//! Distributions ship anything from ffmpeg 4.4 to 8.x, and the library found at runtime can be
+//! older than the headers the crate was built with. The versions of the linked libraries are
+//! read once, encoders, filters and options which only exist in newer releases are looked up
+//! here and fail with [`WaycapError::Unsupported`] naming the release they need.
+
+use std::{fmt, sync::OnceLock};
+
+use ffmpeg_next as ffmpeg;
+
+use crate::types::error::{Result, WaycapError};
+
+/// Oldest libavcodec the crate works with, the one of ffmpeg 4.4
+pub const MIN_AVCODEC: ComponentVersion = ComponentVersion::new(58, 134, 100);
+
+/// Version of one ffmpeg library, e.g. `60.31.102` for the libavcodec of ffmpeg 6.1
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ComponentVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub micro: u32,
+}
+
+impl ComponentVersion {
+    pub const fn new(major: u32, minor: u32, micro: u32) -> Self {
+        Self {
+            major,
+            minor,
+            micro,
+        }
+    }
+
+    /// Decode the `AV_VERSION_INT` packing returned by the `*_version()` functions
+    pub const fn from_packed(version: u32) -> Self {
+        Self::new(version >> 16, (version >> 8) & 0xff, version & 0xff)
+    }
+}
+
+impl fmt::Display for ComponentVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.micro)
+    }
+}
+
+/// Versions of the ffmpeg libraries linked at runtime
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FfmpegVersions {
+    pub avutil: ComponentVersion,
+    pub avcodec: ComponentVersion,
+    pub avformat: ComponentVersion,
+    pub avfilter: ComponentVersion,
+}
+
+/// Versions of the linked ffmpeg libraries, to include in bug reports
+pub fn versions() -> FfmpegVersions {
+    static VERSIONS: OnceLock<FfmpegVersions> = OnceLock::new();
+    *VERSIONS.get_or_init(|| FfmpegVersions {
+        avutil: ComponentVersion::from_packed(ffmpeg::util::version()),
+        avcodec: ComponentVersion::from_packed(ffmpeg::codec::version()),
+        avformat: ComponentVersion::from_packed(ffmpeg::format::version()),
+        avfilter: ComponentVersion::from_packed(ffmpeg::filter::version()),
+    })
+}
+
+/// Encoders which only exist in newer releases: name, first libavcodec and ffmpeg release
+const ENCODER_MIN_VERSIONS: &[(&str, ComponentVersion, &str)] = &[
+    ("av1_nvenc", ComponentVersion::new(60, 3, 100), "6.0"),
+    ("av1_vaapi", ComponentVersion::new(60, 31, 102), "6.1"),
+];
+
+/// Fail when the linked libavcodec is older than [`MIN_AVCODEC`]
+pub(crate) fn check_runtime() -> Result<()> {
+    let avcodec = versions().avcodec;
+    if avcodec < MIN_AVCODEC {
+        return Err(WaycapError::Unsupported(format!(
+            "ffmpeg 4.4 or newer is required, the linked libavcodec is {avcodec}"
+        )));
+    }
+    Ok(())
+}
+
+/// Look up an encoder by name, explaining which release is needed when it is too new for the
+/// linked ffmpeg
+pub(crate) fn find_encoder(name: &str) -> Result<ffmpeg::Codec> {
+    if let Some(codec) = ffmpeg::codec::encoder::find_by_name(name) {
+        return Ok(codec);
+    }
+    let avcodec = versions().avcodec;
+    match ENCODER_MIN_VERSIONS
+        .iter()
+        .find(|(encoder, min, _)| *encoder == name && avcodec < *min)
+    {
+        Some((_, _, release)) => Err(WaycapError::Unsupported(format!(
+            "{name} needs ffmpeg {release} or newer, the linked libavcodec is {avcodec}"
+        ))),
+        // Present in this release, so the build left it out
+        None => Err(ffmpeg::Error::EncoderNotFound.into()),
+    }
+}
+
+/// Look up a filter, failing with the libavfilter version when it is missing
+pub(crate) fn find_filter(name: &str) -> Result<ffmpeg::filter::Filter> {
+    ffmpeg::filter::find(name).ok_or_else(|| {
+        WaycapError::Unsupported(format!(
+            "The {name} filter is not available in the linked libavfilter {}",
+            versions().avfilter
+        ))
+    })
+}
+
+/// Whether the VAAPI encoders take `async_depth`, which came with ffmpeg 5.0
+pub(crate) fn vaapi_has_async_depth() -> bool {
+    versions().avcodec >= ComponentVersion::new(59, 18, 100)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unpacks_av_version_int() {
+        let packed = (60 << 16) | (31 << 8) | 102;
+        assert_eq!(
+            ComponentVersion::from_packed(packed),
+            ComponentVersion::new(60, 31, 102)
+        );
+        assert_eq!(ComponentVersion::new(60, 31, 102).to_string(), "60.31.102");
+    }
+
+    #[test]
+    fn linked_ffmpeg_is_supported() {
+        check_runtime().unwrap();
+        assert!(versions().avfilter.major > 0);
+    }
+}
@@ -0,0 +1,152 @@
+//! Routes ffmpeg's own log output through the [`log`] facade instead of stderr.
+//!
+//! The callback is installed process-wide when the first capture is created. Messages are
+//! logged with the `ffmpeg` target and, when they come from one of the threads of a capture,
+//! prefixed with its [`crate::CaptureControls::session_id`].
+//!
+//! Applications which install their own callback with `av_log_set_callback` should call
+//! [`remove_callback`] before creating a capture.
+
+use std::{
+    cell::{Cell, RefCell},
+    ffi::{c_char, c_int, c_void, CStr},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Once,
+    },
+};
+
+use ffmpeg_next::ffi;
+use log::{Level, LevelFilter};
+
+static INSTALL: Once = Once::new();
+static REMOVED: AtomicBool = AtomicBool::new(false);
+static MIN_LEVEL: AtomicUsize = AtomicUsize::new(LevelFilter::Warn as usize);
+
+thread_local! {
+    static SESSION: Cell<Option<u64>> = const { Cell::new(None) };
+    /// ffmpeg builds some lines from multiple calls, only log once the line is complete
+    static PARTIAL_LINE: RefCell<String> = const { RefCell::new(String::new()) };
+    static PRINT_PREFIX: Cell<c_int> = const { Cell::new(1) };
+    /// Warnings and errors kept for [`collect_messages`]
+    static COLLECTED: RefCell<Option<Vec<String>>> = const { RefCell::new(None) };
+}
+
+/// Set the most verbose level of ffmpeg messages to log.
+/// Default: [`LevelFilter::Warn`]
+pub fn set_min_level(level: LevelFilter) {
+    MIN_LEVEL.store(level as usize, Ordering::Relaxed);
+}
+
+/// Restore ffmpeg's default callback which prints to stderr. The crate won't install its
+/// callback again afterwards, leaving room for one installed by the application.
+pub fn remove_callback() {
+    REMOVED.store(true, Ordering::Release);
+    if INSTALL.is_completed() {
+        unsafe { ffi::av_log_set_callback(Some(ffi::av_log_default_callback)) };
+    }
+}
+
+/// Install the callback unless it was removed with [`remove_callback`]
+pub(crate) fn install() {
+    if REMOVED.load(Ordering::Acquire) {
+        return;
+    }
+    INSTALL.call_once(|| unsafe { ffi::av_log_set_callback(Some(log_callback)) });
+}
+
+/// Tags ffmpeg messages logged on the current thread with a capture session until dropped
+pub(crate) struct SessionScope(Option<u64>);
+
+impl SessionScope {
+    pub fn enter(session_id: u64) -> Self {
+        Self(SESSION.replace(Some(session_id)))
+    }
+}
+
+impl Drop for SessionScope {
+    fn drop(&mut self) {
+        SESSION.set(self.0);
+    }
+}
+
+/// Run `f` and collect the warnings and errors ffmpeg logs on this thread meanwhile, whatever
+/// [`set_min_level`] says, e.g. to explain why it failed. They are still logged as usual. Nothing
+/// is collected once [`remove_callback`] was called.
+pub(crate) fn collect_messages<T>(f: impl FnOnce() -> T) -> (T, Vec<String>) {
+    let outer = COLLECTED.replace(Some(Vec::new()));
+    let result = f();
+    let messages = COLLECTED.replace(outer).unwrap_or_default();
+    (result, messages)
+}
+
+fn map_level(level: c_int) -> Option<Level> {
+    if level < 0 {
+        // AV_LOG_QUIET
+        return None;
+    }
+    match level & 0xff {
+        l if l <= ffi::AV_LOG_ERROR => Some(Level::Error),
+        l if l <= ffi::AV_LOG_WARNING => Some(Level::Warn),
+        l if l <= ffi::AV_LOG_INFO => Some(Level::Info),
+        l if l <= ffi::AV_LOG_DEBUG => Some(Level::Debug),
+        _ => Some(Level::Trace),
+    }
+}
+
+// ffmpeg calls this from whichever thread logs, so it only touches atomics and thread locals
+unsafe extern "C" fn log_callback(
+    avcl: *mut c_void,
+    level: c_int,
+    fmt: *const c_char,
+    #[cfg(all(target_arch = "x86_64", target_family = "unix"))] vl: *mut ffi::__va_list_tag,
+    #[cfg(not(all(target_arch = "x86_64", target_family = "unix")))] vl: ffi::va_list,
+) {
+    let Some(log_level) = map_level(level) else {
+        return;
+    };
+    let collected = log_level <= Level::Warn && COLLECTED.with_borrow(Option::is_some);
+    let logged = log_level as usize <= MIN_LEVEL.load(Ordering::Relaxed);
+    if !collected && !logged {
+        return;
+    }
+
+    let mut buf = [0 as c_char; 1024];
+    let mut print_prefix = PRINT_PREFIX.get();
+    let len = ffi::av_log_format_line2(
+        avcl,
+        level,
+        fmt,
+        vl,
+        buf.as_mut_ptr(),
+        buf.len() as c_int,
+        &mut print_prefix,
+    );
+    PRINT_PREFIX.set(print_prefix);
+    if len < 0 {
+        return;
+    }
+    let text = CStr::from_ptr(buf.as_ptr()).to_string_lossy();
+
+    PARTIAL_LINE.with_borrow_mut(|line| {
+        line.push_str(&text);
+        if !line.ends_with('\n') {
+            return;
+        }
+        let message = line.trim_end();
+        if collected && !message.is_empty() {
+            COLLECTED.with_borrow_mut(|messages| {
+                if let Some(messages) = messages {
+                    messages.push(message.to_string());
+                }
+            });
+        }
+        if logged && !message.is_empty() {
+            match SESSION.get() {
+                Some(id) => log::log!(target: "ffmpeg", log_level, "[session {id}] {message}"),
+                None => log::log!(target: "ffmpeg", log_level, "{message}"),
+            }
+        }
+        line.clear();
+    });
+}
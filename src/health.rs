@@ -0,0 +1,144 @@
+//! The one line status shown by [`crate::Capture::health_summary`].
+
+use std::{fmt::Write, time::Duration};
+
+use crate::types::{stats::CaptureStats, video_frame::VideoStreamInfo};
+
+/// Everything the summary line is built from, gathered by the capture
+pub(crate) struct HealthInputs {
+    pub stats: CaptureStats,
+    pub uptime: Option<Duration>,
+    /// ffmpeg name of the video encoder, `None` for encoders handing out raw frames
+    pub video_encoder: Option<String>,
+    pub stream_info: Option<VideoStreamInfo>,
+    /// ffmpeg name and bitrate of the audio encoder
+    pub audio_encoder: Option<(String, i64)>,
+    pub queued: u64,
+    pub queue_capacity: usize,
+    /// Track length once the capture was finished
+    pub finished: Option<Duration>,
+}
+
+pub(crate) fn summary_line(inputs: &HealthInputs) -> String {
+    let stats = &inputs.stats;
+    if let Some(length) = inputs.finished {
+        return format!(
+            "waycap: finished, {} frames, {}",
+            stats.packets_emitted,
+            format_hms(length)
+        );
+    }
+    let Some(info) = inputs.stream_info else {
+        return "waycap: negotiating…".to_string();
+    };
+
+    let mut line = format!(
+        "waycap: {} {}x{}",
+        backend_label(inputs.video_encoder.as_deref()),
+        info.width,
+        info.height
+    );
+    match info.framerate {
+        (0, _) | (_, 0) => {
+            let measured = match inputs.uptime {
+                Some(uptime) if !uptime.is_zero() => {
+                    stats.frames_captured as f64 / uptime.as_secs_f64()
+                }
+                _ => 0.0,
+            };
+            let _ = write!(line, "@{measured:.0} vfr");
+        }
+        (num, denom) => {
+            let _ = write!(line, "@{}", num / denom);
+        }
+    }
+
+    match stats.avg_encode_time {
+        Some(avg) => {
+            let _ = write!(line, ", enc {:.1}ms avg", avg.as_secs_f64() * 1000.0);
+        }
+        None => line.push_str(", enc -"),
+    }
+    let _ = write!(
+        line,
+        ", drops {}/{}",
+        stats.frames_dropped, stats.frames_captured
+    );
+    match &inputs.audio_encoder {
+        Some((name, bit_rate)) => {
+            let name = name.strip_prefix("lib").unwrap_or(name);
+            let _ = write!(line, ", aud {name} {}k", bit_rate / 1000);
+        }
+        None => line.push_str(", aud off"),
+    }
+    let _ = write!(line, ", q {}/{}", inputs.queued, inputs.queue_capacity);
+    line
+}
+
+/// `h264_vaapi` becomes `vaapi(h264)`
+fn backend_label(encoder: Option<&str>) -> String {
+    match encoder {
+        Some(name) => match name.split_once('_') {
+            Some((codec, backend)) => format!("{backend}({codec})"),
+            None => name.to_string(),
+        },
+        None => "raw".to_string(),
+    }
+}
+
+fn format_hms(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    format!("{:02}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use pipewire::spa::param::video::VideoFormat;
+
+    use super::*;
+
+    fn running() -> HealthInputs {
+        HealthInputs {
+            stats: CaptureStats {
+                frames_captured: 12431,
+                avg_encode_time: Some(Duration::from_micros(4100)),
+                ..Default::default()
+            },
+            uptime: Some(Duration::from_secs_f64(12431.0 / 143.0)),
+            video_encoder: Some("h264_vaapi".to_string()),
+            stream_info: Some(VideoStreamInfo {
+                width: 2560,
+                height: 1440,
+                format: VideoFormat::BGRx,
+                framerate: (0, 1),
+            }),
+            audio_encoder: Some(("libopus".to_string(), 128_000)),
+            queued: 3,
+            queue_capacity: 10,
+            finished: None,
+        }
+    }
+
+    #[test]
+    fn running_capture() {
+        assert_eq!(
+            summary_line(&running()),
+            "waycap: vaapi(h264) 2560x1440@143 vfr, enc 4.1ms avg, drops 0/12431, \
+             aud opus 128k, q 3/10"
+        );
+    }
+
+    #[test]
+    fn before_negotiation_and_after_finish() {
+        let mut inputs = running();
+        inputs.stream_info = None;
+        assert_eq!(summary_line(&inputs), "waycap: negotiating…");
+
+        inputs.stats.packets_emitted = 12431;
+        inputs.finished = Some(Duration::from_secs(192));
+        assert_eq!(
+            summary_line(&inputs),
+            "waycap: finished, 12431 frames, 00:03:12"
+        );
+    }
+}
@@ -39,7 +39,7 @@
 //!     capture.start()?;
 //!     
 //!     // Get receivers for encoded frames
-//!     let video_receiver = capture.get_video_receiver();
+//!     let video_receiver = capture.get_video_receiver()?;
 //!     let audio_receiver = capture.get_audio_receiver()?;
 //!     
 //!     // Process frames as needed...
@@ -55,31 +55,70 @@
 #![warn(clippy::all)]
 use std::{
     sync::{
-        atomic::{AtomicBool, AtomicU64, Ordering},
+        atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering},
         mpsc::{self},
-        Arc,
+        Arc, RwLock,
     },
-    time::{Duration, Instant},
+    time::Duration,
 };
 
-use capture::{audio::AudioCapture, video::VideoCapture, Terminate};
-use crossbeam::{
-    channel::{bounded, Receiver, Sender},
-    select,
+use capture::{
+    audio::AudioCapture,
+    audio_ring::{audio_ring, RingConsumer},
+    pip::PipFrames,
+    video::VideoCapture,
+    Terminate,
 };
-use encoders::{audio::AudioEncoder, opus_encoder::OpusEncoder};
-use portal_screencast_waycap::{CursorMode, ScreenCast, SourceType};
+use crossbeam::channel::{bounded, Receiver, Sender, TrySendError};
+use encoders::{audio::AudioEncoder, opus_encoder::OpusEncoder, spa_format};
+use pipeline::{
+    external_copy::{self, ExternalTap},
+    interleaver::{interleaving_loop, InterleaverControl},
+    keyframes::KeyframeScheduler,
+    preview::{self, PreviewTap},
+    shutdown::{capture_clock_ns, StreamCutoff, StreamKind, SHUTDOWN_TIMEOUT},
+    watchdog::watchdog_loop,
+};
+use pipewire::spa::param::video::VideoFormat;
+use portal::{DbusPortal, SessionMetadata, SourceInfo, SourceSelection, SourceTracker};
+use portal_screencast_waycap::CursorMode;
 use std::sync::Mutex;
 use types::{
-    audio_frame::{EncodedAudioFrame, RawAudioFrame},
-    config::{AudioEncoder as AudioEncoderType, QualityPreset, VideoEncoder as VideoEncoderType},
+    audio_frame::EncodedAudioFrame,
+    config::{
+        AudioEncoder as AudioEncoderType, AudioRingConfig, CaptureSource, DisconnectPolicy, Fps,
+        LatencyMode, OverflowPolicy, OverlayConfig, QualityPreset, Rect, ScreenBlankPolicy,
+        TimelapseConfig, Transform, VideoConfig, VideoEncoder as VideoEncoderType,
+    },
+    encoder_info::EncoderInfo,
     error::{Result, WaycapError},
-    video_frame::{EncodedVideoFrame, RawVideoFrame},
+    event::{CaptureEvent, EventSender},
+    external_buffer::{ExternalBuffer, ExternalBufferPool, ExternalFrame},
+    media_packet::{MediaPacket, TimedMetadata, MAX_METADATA_KEY_LEN, MAX_METADATA_SIZE},
+    preview::{PreviewConfig, PreviewFrame},
+    receiver::{AudioFrames, VideoFrames},
+    session::SessionSnapshot,
+    stats::{CaptureStats, FinishSummary, StatsCounters},
+    time::CaptureTime,
+    video_frame::{EncodedVideoFrame, RawVideoFrame, VideoStreamInfo},
 };
 
+#[cfg(feature = "benchmark")]
+pub mod benchmark;
 mod capture;
+mod dmabuf_probe;
 mod encoders;
+mod failure_injection;
+pub mod ffmpeg_compat;
+pub mod ffmpeg_log;
+mod health;
 pub mod pipeline;
+pub mod portal;
+#[cfg(feature = "quality-harness")]
+pub mod quality;
+mod raw_recording;
+mod runtime;
+mod soak;
 pub mod types;
 mod utils;
 mod waycap_egl;
@@ -87,20 +126,44 @@ mod waycap_egl;
 pub use crate::encoders::dma_buf_encoder::DmaBufEncoder;
 pub use crate::encoders::dynamic_encoder::DynamicEncoder;
 #[cfg(feature = "nvenc")]
-pub use crate::encoders::nvenc_encoder::NvencEncoder;
+pub use crate::encoders::nvenc_encoder::{nvenc_session_info, NvencEncoder};
+pub use crate::encoders::passthrough_encoder::PassthroughEncoder;
+pub use crate::encoders::qsv_encoder::QsvEncoder;
 pub use crate::encoders::rgba_image_encoder::RgbaImageEncoder;
+pub use crate::encoders::software_encoder::SoftwareEncoder;
 pub use crate::encoders::vaapi_encoder::VaapiEncoder;
+#[cfg(feature = "failure-injection")]
+pub use crate::failure_injection::FailureInjector;
+#[cfg(feature = "raw-recording")]
+pub use crate::raw_recording::{
+    replay_raw_capture, RawRecorder, RawRecording, RecordedFrame, MAGIC as RAW_RECORDING_MAGIC,
+};
+#[cfg(feature = "soak-test")]
+pub use crate::soak::{
+    heap_usage, live_objects, run_soak, CountingAllocator, Growth, HeapUsage, ObjectKind,
+    SoakConfig, SoakReport, SoakSnapshot,
+};
 pub use encoders::video::VideoEncoder;
 pub use utils::TIME_UNIT_NS;
 
-use crate::encoders::video::{PipewireSPA, StartVideoEncoder};
+use crate::encoders::video::{fixate_spa_definition, PipewireSPA, StartVideoEncoder};
+use crate::health::HealthInputs;
+use crate::runtime::Runtime;
 
-/// Target Screen Resolution
-pub struct Resolution {
-    width: u32,
-    height: u32,
-}
+/// Raw frames each capture stream can queue for its encoder
+const RAW_FRAME_CAPACITY: usize = 10;
+/// Injected metadata blobs waiting for the interleaver
+const METADATA_CAPACITY: usize = 64;
 
+/// Raw frames each capture stream queues with `latency_mode`. A frame behind others in the
+/// queue is encoded late, dropping it instead keeps the latency down.
+fn raw_frame_capacity(latency_mode: LatencyMode) -> usize {
+    match latency_mode {
+        LatencyMode::Quality => RAW_FRAME_CAPACITY,
+        LatencyMode::LowLatency => 2,
+        LatencyMode::UltraLowLatency => 1,
+    }
+}
 /// Main capture instance for recording screen content and audio.
 ///
 /// `Capture` provides methods to control the recording process, retrieve
@@ -124,7 +187,7 @@ pub struct Resolution {
 /// capture.start().expect("Failed to start capture");
 ///
 /// // Get video receiver
-/// let video_receiver = capture.get_video_receiver();
+/// let video_receiver = capture.get_video_receiver().expect("Failed to get video receiver");
 ///
 /// // Process Frames
 /// loop {
@@ -139,9 +202,66 @@ pub struct Capture<V: VideoEncoder + Send> {
 
     video_encoder: Option<Arc<Mutex<V>>>,
     pw_video_terminate_tx: Option<pipewire::channel::Sender<Terminate>>,
+    /// Name of the video encoder, kept when it is opened so [`Self::health_summary`] doesn't
+    /// wait on the processing thread for it
+    video_encoder_name: Option<String>,
 
     audio_encoder: Option<Arc<Mutex<dyn AudioEncoder + Send>>>,
+    /// Name and bitrate of the audio encoder, see [`Self::video_encoder_name`]
+    audio_encoder_name: Option<(String, i64)>,
     pw_audio_terminate_tx: Option<pipewire::channel::Sender<Terminate>>,
+    /// Ends the capture of the [`VideoConfig::pip`] source
+    pw_pip_terminate_tx: Option<pipewire::channel::Sender<Terminate>>,
+
+    media_rx: Option<Receiver<MediaPacket>>,
+    interleaver_tx: Option<Sender<InterleaverControl>>,
+
+    stats: Arc<StatsCounters>,
+    source: Option<Arc<SourceTracker>>,
+    /// Feeds the video processing thread, kept to attach a new stream in [`Self::switch_source`]
+    raw_video_tx: Option<Sender<RawVideoFrame>>,
+    /// Ask for the cursor as metadata, see
+    /// [`crate::pipeline::builder::CaptureBuilder::with_cursor_metadata`]
+    cursor_metadata: bool,
+    /// Readiness of the current video stream, handed over to the next in [`Self::switch_source`]
+    stream_ready: Option<Arc<ReadyState>>,
+    include_cursor: bool,
+    /// Offer the compositor to send H.264 it encoded itself, see
+    /// [`VideoEncoderType::H264Passthrough`]
+    passthrough: bool,
+    /// Ask for shared memory instead of DMA-BUFs, see [`VideoEncoderType::H264Software`],
+    /// [`VideoEncoderType::Av1Svt`], [`VideoEncoderType::Vp8`], MJPEG without VAAPI and
+    /// [`QualityPreset::Lossless`] encoded with FFV1
+    software: bool,
+    /// Offer 10 bit DMA-BUFs, see [`VideoConfig::ten_bit`]
+    ten_bit: bool,
+    /// Raw frames queued for the video encoder, see [`VideoConfig::latency_mode`]
+    raw_frame_capacity: usize,
+    trim_audio: bool,
+    disconnect_policy: DisconnectPolicy,
+    /// Frame rate the video processing thread fills, see [`VideoConfig::cfr`]
+    cfr: Option<Fps>,
+    /// Frames the video processing thread keeps, see [`VideoConfig::timelapse`]
+    timelapse: Option<TimelapseConfig>,
+    /// Heartbeat of the frames skipped as unchanged, see [`VideoConfig::dedup`]
+    dedup: Option<Duration>,
+    screen_blank_policy: ScreenBlankPolicy,
+    portal_metadata: SessionMetadata,
+    /// Whether the portal confirmed hiding its screen sharing indicator for the current source
+    recording_indicator_hidden: bool,
+    /// Token to restore the current source with, passed to the next portal session
+    restore_token: Option<String>,
+    /// Settings the capture was built with, `None` unless built by
+    /// [`pipeline::builder::CaptureBuilder`]
+    settings: Option<SessionSnapshot>,
+    /// Video length once [`Self::finish`] ran, until the next [`Self::reset`]
+    finished: Option<Duration>,
+
+    event_tx: EventSender,
+    event_rx: Receiver<CaptureEvent>,
+
+    // Dropped last, after the threads using ffmpeg and PipeWire were joined
+    _runtime: Runtime,
 }
 
 /// Controls for the capture, allows you to pause/resume processing
@@ -150,14 +270,45 @@ pub struct CaptureControls {
     stop_flag: AtomicBool,
     pause_flag: AtomicBool,
     target_fps: AtomicU64,
+    session_id: u64,
+    cutoff: StreamCutoff,
+    /// Capture time in ns of the last [`Capture::switch_source`]
+    source_switched_at: AtomicI64,
+    /// Paused by the [`ScreenBlankPolicy`] rather than by hand
+    auto_paused: AtomicBool,
+    /// Pool registered with [`Capture::register_external_buffers`]
+    external_tap: Mutex<Option<ExternalTap>>,
+    /// Preview thread started by [`Capture::preview_frames`]
+    preview_tap: Mutex<Option<PreviewTap>>,
+    /// Keyframe decisions shared by every video encoder of the capture
+    keyframes: Mutex<KeyframeScheduler>,
+    /// Parameters of the video stream the encoder is set up for
+    stream_info: Mutex<Option<VideoStreamInfo>>,
+    #[cfg(feature = "failure-injection")]
+    failure_injector: FailureInjector,
+    #[cfg(feature = "raw-recording")]
+    raw_recorder: RawRecorder,
 }
 
 impl CaptureControls {
     fn from_fps(target_fps: u64) -> Self {
+        static NEXT_SESSION_ID: AtomicU64 = AtomicU64::new(1);
         Self {
             stop_flag: AtomicBool::new(false),
             pause_flag: AtomicBool::new(true),
             target_fps: AtomicU64::new(target_fps),
+            session_id: NEXT_SESSION_ID.fetch_add(1, Ordering::Relaxed),
+            cutoff: StreamCutoff::default(),
+            source_switched_at: AtomicI64::new(i64::MIN),
+            auto_paused: AtomicBool::new(false),
+            external_tap: Mutex::new(None),
+            preview_tap: Mutex::new(None),
+            keyframes: Mutex::new(KeyframeScheduler::default()),
+            stream_info: Mutex::new(None),
+            #[cfg(feature = "failure-injection")]
+            failure_injector: Default::default(),
+            #[cfg(feature = "raw-recording")]
+            raw_recorder: Default::default(),
         }
     }
     /// True when stopped or paused
@@ -181,18 +332,94 @@ impl CaptureControls {
 
     /// Pause processing
     pub fn pause(&self) {
+        self.auto_paused.store(false, Ordering::Release);
         self.pause_flag.store(true, Ordering::Release);
     }
 
     /// Resume processing
     pub fn resume(&self) {
+        self.auto_paused.store(false, Ordering::Release);
+        self.cutoff.clear();
         self.pause_flag.store(false, Ordering::Release);
     }
 
+    /// Make the next encoded video frame an IDR frame, e.g. to start a segment or let a viewer
+    /// join a stream. Safe to call from any thread, calls before that frame is encoded give a
+    /// single keyframe.
+    pub fn force_keyframe(&self) {
+        self.keyframes.lock().unwrap().request();
+    }
+
+    /// Pause for a blanked screen, unless already paused
+    pub(crate) fn auto_pause(&self) {
+        if !self.pause_flag.swap(true, Ordering::AcqRel) {
+            self.auto_paused.store(true, Ordering::Release);
+        }
+    }
+
+    /// Undo [`Self::auto_pause`], unless the capture was paused or resumed by hand since
+    pub(crate) fn auto_resume(&self) {
+        if self.auto_paused.swap(false, Ordering::AcqRel) {
+            self.resume();
+        }
+    }
+
     /// Frame interval in nanoseconds
     pub fn frame_interval_ns(&self) -> u64 {
         TIME_UNIT_NS / self.target_fps.load(Ordering::Acquire)
     }
+
+    /// Id of this capture, used to tell apart the messages of multiple captures in the logs
+    pub fn session_id(&self) -> u64 {
+        self.session_id
+    }
+
+    pub(crate) fn switch_source_at(&self, time: CaptureTime) {
+        self.source_switched_at
+            .store(time.as_nanos(), Ordering::Release);
+    }
+
+    /// False for frames captured before the last source switch, their stream is gone
+    pub(crate) fn is_current_source(&self, timestamp: CaptureTime) -> bool {
+        timestamp.as_nanos() >= self.source_switched_at.load(Ordering::Acquire)
+    }
+
+    pub(crate) fn cutoff(&self) -> &StreamCutoff {
+        &self.cutoff
+    }
+
+    pub(crate) fn external_tap(&self) -> &Mutex<Option<ExternalTap>> {
+        &self.external_tap
+    }
+
+    pub(crate) fn preview_tap(&self) -> &Mutex<Option<PreviewTap>> {
+        &self.preview_tap
+    }
+
+    pub(crate) fn keyframes(&self) -> &Mutex<KeyframeScheduler> {
+        &self.keyframes
+    }
+
+    pub(crate) fn stream_info(&self) -> Option<VideoStreamInfo> {
+        *self.stream_info.lock().unwrap()
+    }
+
+    /// Set when the stream was negotiated, and again each time the encoder follows a new size
+    pub(crate) fn set_stream_info(&self, info: VideoStreamInfo) {
+        *self.stream_info.lock().unwrap() = Some(info);
+    }
+
+    /// Handle to simulate failures in this capture
+    #[cfg(feature = "failure-injection")]
+    pub fn failure_injector(&self) -> &FailureInjector {
+        &self.failure_injector
+    }
+
+    /// Handle to record the raw frames this capture hands to its encoder
+    #[cfg(feature = "raw-recording")]
+    pub fn raw_recorder(&self) -> &RawRecorder {
+        &self.raw_recorder
+    }
 }
 
 /// State of audio/video readiness, used internally
@@ -200,14 +427,41 @@ impl CaptureControls {
 pub struct ReadyState {
     audio: AtomicBool,
     video: AtomicBool,
+    /// The state of the whole capture, which a video stream reports its readiness to and takes
+    /// the audio readiness from. None while the stream isn't the current one.
+    capture: RwLock<Option<Arc<ReadyState>>>,
 }
 
 impl ReadyState {
+    /// State of a video stream reporting to `capture`
+    fn video_stream(capture: &Arc<ReadyState>) -> Arc<Self> {
+        Arc::new(Self {
+            capture: RwLock::new(Some(Arc::clone(capture))),
+            ..Default::default()
+        })
+    }
     pub fn video_ready(&self) -> bool {
         self.video.load(Ordering::Acquire)
     }
     pub fn audio_ready(&self) -> bool {
-        self.audio.load(Ordering::Acquire)
+        match &*self.capture.read().unwrap() {
+            Some(capture) => capture.audio_ready(),
+            None => self.audio.load(Ordering::Acquire),
+        }
+    }
+    fn set_video_ready(&self, ready: bool) {
+        self.video.store(ready, Ordering::Release);
+        if let Some(capture) = &*self.capture.read().unwrap() {
+            capture.video.store(ready, Ordering::Release);
+        }
+    }
+    /// Makes `next` the stream reporting to the capture, this one is held back from then on
+    fn hand_over(&self, next: &ReadyState) {
+        let capture = self.capture.write().unwrap().take();
+        if let Some(capture) = &capture {
+            capture.video.store(next.video_ready(), Ordering::Release);
+        }
+        *next.capture.write().unwrap() = capture;
     }
     fn wait_for_both(&self) {
         while !self.audio.load(Ordering::Acquire) || !self.video.load(Ordering::Acquire) {
@@ -221,16 +475,51 @@ impl<V: VideoEncoder + PipewireSPA + StartVideoEncoder> Capture<V> {
     where
         V: 'static,
     {
+        let runtime = Runtime::acquire()?;
+        let controls = Arc::new(CaptureControls::from_fps(target_fps));
+        let _log_session = ffmpeg_log::SessionScope::enter(controls.session_id());
+        let (event_tx, event_rx) = bounded(64);
         let mut _self = Self {
-            controls: Arc::new(CaptureControls::from_fps(target_fps)),
+            controls,
             worker_handles: Vec::new(),
+            video_encoder_name: video_encoder.info().map(|info| info.name),
             video_encoder: Some(Arc::new(Mutex::new(video_encoder))),
             audio_encoder: None,
+            audio_encoder_name: None,
             pw_video_terminate_tx: None,
             pw_audio_terminate_tx: None,
+            pw_pip_terminate_tx: None,
+            media_rx: None,
+            interleaver_tx: None,
+            stats: Arc::new(StatsCounters::default()),
+            source: None,
+            raw_video_tx: None,
+            cursor_metadata: false,
+            stream_ready: None,
+            include_cursor: false,
+            passthrough: false,
+            software: false,
+            ten_bit: false,
+            raw_frame_capacity: RAW_FRAME_CAPACITY,
+            trim_audio: false,
+            disconnect_policy: DisconnectPolicy::default(),
+            cfr: None,
+            timelapse: None,
+            dedup: None,
+            screen_blank_policy: ScreenBlankPolicy::default(),
+            portal_metadata: SessionMetadata::default(),
+            recording_indicator_hidden: false,
+            restore_token: None,
+            settings: None,
+            finished: None,
+            event_tx: EventSender::new(event_tx),
+            event_rx,
+            _runtime: runtime,
         };
 
-        let (frame_rx, ready_state, _) = _self.start_pipewire_video(include_cursor)?;
+        let (frame_rx, ready_state, stream_info) =
+            _self.start_pipewire_video(include_cursor, None)?;
+        _self.controls.set_stream_info(stream_info);
 
         std::thread::sleep(Duration::from_millis(100));
         ready_state.audio.store(true, Ordering::Release);
@@ -246,40 +535,133 @@ impl<V: VideoEncoder + PipewireSPA + StartVideoEncoder> Capture<V> {
     fn start_pipewire_video(
         &mut self,
         include_cursor: bool,
-    ) -> Result<(Receiver<RawVideoFrame>, Arc<ReadyState>, Resolution)> {
-        let (frame_tx, frame_rx): (Sender<RawVideoFrame>, Receiver<RawVideoFrame>) = bounded(10);
+        fast_start: Option<VideoStreamInfo>,
+    ) -> Result<(Receiver<RawVideoFrame>, Arc<ReadyState>, VideoStreamInfo)> {
+        let (frame_tx, frame_rx): (Sender<RawVideoFrame>, Receiver<RawVideoFrame>) =
+            bounded(self.raw_frame_capacity);
+        self.raw_video_tx = Some(frame_tx.clone());
+        self.include_cursor = include_cursor;
 
         let ready_state = Arc::new(ReadyState::default());
-        let ready_state_pw = Arc::clone(&ready_state);
-
-        let (pw_sender, pw_recv) = pipewire::channel::channel();
+        let stream_ready = ReadyState::video_stream(&ready_state);
+        // Only the first source can be restored, a switch asks for another one
+        let selection = SourceSelection {
+            restore_token: self.restore_token.clone(),
+            ..self.source_selection(CaptureSource::Any)
+        };
+        let (pw_sender, stream_info) =
+            self.spawn_video_stream(&selection, frame_tx, Arc::clone(&stream_ready), fast_start)?;
         self.pw_video_terminate_tx = Some(pw_sender);
+        self.stream_ready = Some(stream_ready);
 
-        let (reso_sender, reso_recv) = mpsc::channel::<Resolution>();
+        Ok((frame_rx, ready_state, stream_info))
+    }
 
-        let mut screen_cast = ScreenCast::new()?;
-        screen_cast.set_source_types(SourceType::all());
-        screen_cast.set_cursor_mode(if include_cursor {
-            CursorMode::EMBEDDED
-        } else {
-            CursorMode::HIDDEN
-        });
-        let active_cast = screen_cast.start(None)?;
-        let fd = active_cast.pipewire_fd();
-        let stream = active_cast.streams().next().unwrap();
-        let stream_node = stream.pipewire_node();
+    /// Like [`Self::start_pipewire_video`] with generated `width` x `height` frames at
+    /// `target_fps` instead of a portal session, the source can't be switched
+    fn start_synthetic_video(
+        &mut self,
+        (width, height): (u32, u32),
+        target_fps: u64,
+    ) -> (Receiver<RawVideoFrame>, Arc<ReadyState>, VideoStreamInfo) {
+        let (frame_tx, frame_rx) = bounded(self.raw_frame_capacity);
+        let ready_state = Arc::new(ReadyState::default());
+        let stream_ready = ReadyState::video_stream(&ready_state);
+        let stream_info = capture::synthetic::stream_info(width, height, target_fps);
+        self.stats.mark_stream_started();
+        self.worker_handles.push(capture::synthetic::start(
+            stream_info,
+            Arc::clone(&stream_ready),
+            Arc::clone(&self.controls),
+            Arc::clone(&self.stats),
+            frame_tx,
+        ));
+        self.stream_ready = Some(stream_ready);
+
+        (frame_rx, ready_state, stream_info)
+    }
+
+    fn source_selection(&self, source: CaptureSource) -> SourceSelection {
+        SourceSelection {
+            source_types: source.source_types(),
+            cursor_mode: if self.include_cursor {
+                CursorMode::EMBEDDED
+            } else if self.cursor_metadata {
+                CursorMode::METADATA
+            } else {
+                CursorMode::HIDDEN
+            },
+            multiple: false,
+            restore_token: None,
+            metadata: self.portal_metadata.clone(),
+        }
+    }
+
+    /// Open a portal session and run its video stream on a new thread, sending frames into
+    /// `frame_tx` once `ready_state` reports audio as ready. Returns once the stream negotiated
+    /// its format.
+    fn spawn_video_stream(
+        &mut self,
+        selection: &SourceSelection,
+        frame_tx: Sender<RawVideoFrame>,
+        ready_state: Arc<ReadyState>,
+        fast_start: Option<VideoStreamInfo>,
+    ) -> Result<(pipewire::channel::Sender<Terminate>, VideoStreamInfo)> {
+        let (pw_sender, pw_recv) = pipewire::channel::channel();
+        let (info_sender, info_recv) = mpsc::channel::<VideoStreamInfo>();
+
+        let session = portal::QUEUE.request(
+            || portal::start_session(Box::new(DbusPortal::default()), selection),
+            portal::is_transient,
+        )?;
+        self.stats.mark_stream_started();
+        self.recording_indicator_hidden = session.indicator_hidden;
+        self.restore_token = session.restore_token.clone();
+        let fd = session.pipewire_fd;
+        let stream_node = session.stream.node_id;
+        let source = Arc::new(SourceTracker::new(&session.stream));
+        self.source = Some(Arc::clone(&source));
         let controls = Arc::clone(&self.controls);
+        let stats = Arc::clone(&self.stats);
+        let events = self.event_tx.clone();
+        let screen_blank_policy = self.screen_blank_policy;
+        let passthrough = self.passthrough;
+        let software = self.software;
+        let ten_bit = self.ten_bit;
+        let cursor_tx = self.interleaver_tx.clone().filter(|_| self.cursor_metadata);
         self.worker_handles
             .push(std::thread::spawn(move || -> Result<()> {
+                // Offering exactly the known parameters first lets PipeWire skip the
+                // renegotiation, the wide offer is kept as fallback if the narrow one is rejected
+                let pw_obj = if software {
+                    SoftwareEncoder::get_spa_definition()?
+                } else if ten_bit {
+                    VaapiEncoder::ten_bit_spa_definition()
+                } else {
+                    V::get_spa_definition()?
+                };
+                let mut pw_objs = match fast_start {
+                    Some(info) => vec![fixate_spa_definition(pw_obj.clone(), &info), pw_obj],
+                    None => vec![pw_obj],
+                };
+                // Raw formats stay as fallback for compositors which don't encode themselves
+                if passthrough {
+                    pw_objs.insert(0, spa_format::h264_offer());
+                }
                 let mut video_cap = match VideoCapture::new(
                     fd,
                     stream_node,
-                    ready_state_pw,
+                    ready_state,
                     controls,
-                    reso_sender,
+                    info_sender,
                     frame_tx,
                     pw_recv,
-                    V::get_spa_definition()?,
+                    pw_objs,
+                    stats,
+                    events,
+                    source,
+                    screen_blank_policy,
+                    cursor_tx,
                 ) {
                     Ok(pw_capture) => pw_capture,
                     Err(e) => {
@@ -290,44 +672,121 @@ impl<V: VideoEncoder + PipewireSPA + StartVideoEncoder> Capture<V> {
 
                 video_cap.run()?;
 
-                let _ = active_cast.close(); // Keep this alive until the thread ends
+                session.close(); // Keep this alive until the thread ends
                 Ok(())
             }));
 
-        // Wait to get back a negotiated resolution from pipewire
-        let timeout = Duration::from_secs(5);
-        let start = Instant::now();
-        let resolution = loop {
-            if let Ok(reso) = reso_recv.recv() {
-                break reso;
-            }
-
-            if start.elapsed() > timeout {
+        // Wait to get back the negotiated stream parameters from pipewire
+        let stream_info = match info_recv.recv_timeout(Duration::from_secs(5)) {
+            Ok(info) => info,
+            Err(_) => {
                 log::error!("Timeout waiting for PipeWire negotiated resolution.");
+                let _ = pw_sender.send(Terminate {});
                 return Err(WaycapError::Init(
                     "Timed out waiting for pipewire to negotiate video resolution".into(),
                 ));
             }
-
-            std::thread::sleep(Duration::from_millis(100));
         };
 
-        Ok((frame_rx, ready_state, resolution))
+        Ok((pw_sender, stream_info))
+    }
+
+    /// Switch the video to a different source mid-session, e.g. to follow the focus to another
+    /// monitor, without starting a new recording.
+    ///
+    /// Asks the user for the new source through the portal and keeps capturing the old one
+    /// until the new stream is negotiated. The video encoder is then drained and restarted for
+    /// the size of the new source, so the next packet is a keyframe, and the old stream and its
+    /// portal session are released. Timestamps stay on the same clock, audio is not affected.
+    /// Emits [`CaptureEvent::SourceSwitched`].
+    pub fn switch_source(&mut self, source: CaptureSource) -> Result<VideoStreamInfo> {
+        let frame_tx = self.raw_video_tx.clone().ok_or(WaycapError::Validation(
+            "The capture has no running video stream to switch".to_string(),
+        ))?;
+        let encoder = Arc::clone(self.video_encoder.as_ref().ok_or(WaycapError::Validation(
+            "The capture has no video encoder".to_string(),
+        ))?);
+        let previous_source = self.source.clone();
+        let previous_indicator_hidden = self.recording_indicator_hidden;
+        let previous_restore_token = self.restore_token.clone();
+
+        // Frames of the new stream are held back until the switch is done
+        let ready_state = Arc::new(ReadyState::default());
+        let selection = self.source_selection(source);
+        let (pw_sender, stream_info) =
+            match self.spawn_video_stream(&selection, frame_tx, Arc::clone(&ready_state), None) {
+                Ok(stream) => stream,
+                Err(e) => {
+                    self.source = previous_source;
+                    self.recording_indicator_hidden = previous_indicator_hidden;
+                    self.restore_token = previous_restore_token;
+                    return Err(e);
+                }
+            };
+        // A passthrough encoder can't encode raw frames, nor the other way round
+        let was_encoded = self
+            .controls
+            .stream_info()
+            .is_some_and(|info| info.format == VideoFormat::Encoded);
+        if (stream_info.format == VideoFormat::Encoded) != was_encoded {
+            let _ = pw_sender.send(Terminate {});
+            self.source = previous_source;
+            self.recording_indicator_hidden = previous_indicator_hidden;
+            self.restore_token = previous_restore_token;
+            return Err(WaycapError::Validation(
+                "The new source is not encoded by the same side as the current one".to_string(),
+            ));
+        }
+
+        {
+            // Holding the encoder keeps the processing thread from encoding in between
+            let mut encoder = encoder.lock().unwrap();
+            // The old stream keeps running if the encoder can't take the new source
+            if let Err(e) = encoder.source_changed(stream_info.width, stream_info.height) {
+                let _ = pw_sender.send(Terminate {});
+                self.source = previous_source;
+                self.recording_indicator_hidden = previous_indicator_hidden;
+                self.restore_token = previous_restore_token;
+                return Err(e);
+            }
+            if let Some(previous) = self.pw_video_terminate_tx.replace(pw_sender) {
+                let _ = previous.send(Terminate {});
+            }
+            // Queued frames of the old stream point into buffers which are released now
+            self.controls.switch_source_at(self.now());
+            self.controls.set_stream_info(stream_info);
+            // The new stream follows the audio of the capture from here on
+            if let Some(previous) = self.stream_ready.replace(Arc::clone(&ready_state)) {
+                previous.hand_over(&ready_state);
+            }
+        }
+
+        log::info!(
+            "Switched to a {}x{} source",
+            stream_info.width,
+            stream_info.height
+        );
+        self.event_tx
+            .send(CaptureEvent::SourceSwitched { info: stream_info });
+        Ok(stream_info)
     }
 
     fn start_pipewire_audio(
         &mut self,
         audio_encoder_type: AudioEncoderType,
+        audio_overflow: OverflowPolicy,
+        audio_ring_config: AudioRingConfig,
         ready_state: Arc<ReadyState>,
-    ) -> Result<Receiver<RawAudioFrame>> {
+    ) -> Result<RingConsumer> {
         let (pw_audio_sender, pw_audio_recv) = pipewire::channel::channel();
         self.pw_audio_terminate_tx = Some(pw_audio_sender);
-        let (audio_tx, audio_rx): (Sender<RawAudioFrame>, Receiver<RawAudioFrame>) = bounded(10);
+        let (ring_producer, ring_consumer) = audio_ring(audio_ring_config);
         let controls = Arc::clone(&self.controls);
+        let stats = Arc::clone(&self.stats);
         let pw_audio_worker = std::thread::spawn(move || -> Result<()> {
             log::debug!("Starting audio stream");
             let audio_cap = AudioCapture::new(ready_state);
-            audio_cap.run(audio_tx, pw_audio_recv, controls)?;
+            audio_cap.run(ring_producer, pw_audio_recv, controls, stats)?;
             Ok(())
         });
 
@@ -336,10 +795,19 @@ impl<V: VideoEncoder + PipewireSPA + StartVideoEncoder> Capture<V> {
         let enc: Arc<Mutex<dyn AudioEncoder + Send>> = match audio_encoder_type {
             AudioEncoderType::Opus => Arc::new(Mutex::new(OpusEncoder::new()?)),
         };
+        {
+            let mut enc = enc.lock().unwrap();
+            enc.set_stats(Arc::clone(&self.stats));
+            enc.set_overflow_policy(audio_overflow, self.event_tx.clone());
+            self.audio_encoder_name = enc.get_encoder().as_ref().and_then(|encoder| {
+                let name = encoder.codec()?.name().to_string();
+                Some((name, unsafe { (*encoder.as_ptr()).bit_rate }))
+            });
+        }
 
         self.audio_encoder = Some(enc);
 
-        Ok(audio_rx)
+        Ok(ring_consumer)
     }
 }
 impl<V: VideoEncoder> Capture<V> {
@@ -355,16 +823,44 @@ impl<V: VideoEncoder> Capture<V> {
     }
 
     /// Stop recording and drain the encoders of any last frames they have in their internal
-    /// buffers. These frames are discarded.
-    pub fn finish(&mut self) -> Result<()> {
+    /// buffers.
+    ///
+    /// Both streams stop taking new buffers at the same capture time, everything captured up to
+    /// then is encoded and delivered before the encoders are drained. When enabled with
+    /// [`crate::pipeline::builder::CaptureBuilder::with_audio_trim`] the audio flushed from the
+    /// encoder is delivered too, cut to end with the last video frame, otherwise it is discarded.
+    ///
+    /// Returns the final length of the tracks.
+    pub fn finish(&mut self) -> Result<FinishSummary> {
+        if !self.controls.is_paused() {
+            let cutoff = failure_injection::capture_time(&self.controls, capture_clock_ns());
+            self.controls.cutoff().set(cutoff);
+            if !self.controls.cutoff().wait_idle(SHUTDOWN_TIMEOUT) {
+                log::warn!("Timed out waiting for the encoders to catch up, the tail may be torn");
+            }
+        }
         self.controls.pause();
         if let Some(ref mut enc) = self.video_encoder {
             enc.lock().unwrap().drain()?;
         }
+        // Without a video frame there is nothing to trim to, all of the audio is kept
+        let end_timestamp = self
+            .trim_audio
+            .then(|| self.stats.last_video_pts().unwrap_or_else(|| self.now()));
         if let Some(ref mut enc) = self.audio_encoder {
-            enc.lock().unwrap().drain()?;
+            enc.lock().unwrap().drain(end_timestamp)?;
         }
-        Ok(())
+        if let Some(ref interleaver) = self.interleaver_tx {
+            let (done_tx, done_rx) = bounded(1);
+            if interleaver.send(InterleaverControl::Flush(done_tx)).is_ok()
+                && done_rx.recv_timeout(SHUTDOWN_TIMEOUT).is_err()
+            {
+                log::warn!("Timed out waiting for the media packets to be flushed");
+            }
+        }
+        let summary = self.stats.finish_summary();
+        self.finished = Some(summary.video_duration);
+        Ok(summary)
     }
 
     /// Resets the encoder states so we can resume encoding from within this same session
@@ -375,6 +871,7 @@ impl<V: VideoEncoder> Capture<V> {
         if let Some(ref mut enc) = self.audio_encoder {
             enc.lock().unwrap().reset()?;
         }
+        self.finished = None;
 
         Ok(())
     }
@@ -391,6 +888,13 @@ impl<V: VideoEncoder> Capture<V> {
         if let Some(pw_aud) = &self.pw_audio_terminate_tx {
             let _ = pw_aud.send(Terminate {});
         }
+        if let Some(pw_pip) = &self.pw_pip_terminate_tx {
+            let _ = pw_pip.send(Terminate {});
+        }
+        self.raw_video_tx = None;
+        // Ends the copy thread of the external buffers and the preview thread
+        self.controls.external_tap().lock().unwrap().take();
+        self.controls.preview_tap().lock().unwrap().take();
 
         for handle in self.worker_handles.drain(..) {
             let _ = handle.join();
@@ -402,6 +906,223 @@ impl<V: VideoEncoder> Capture<V> {
         Ok(())
     }
 
+    /// Copy every frame handed to the video encoder into `buffers` as well, e.g. DMA-BUFs
+    /// exported from a Vulkan image or GL texture pool, to process the frames on the GPU in
+    /// parallel with encoding.
+    ///
+    /// Each copied frame is announced through the returned receiver with the index of the buffer
+    /// it was copied into. That buffer is not written again until it is handed back with
+    /// [`ExternalBufferPool::release`]. When every buffer is held by the application the frame is
+    /// still encoded but not copied, counted in [`CaptureStats::external_copies_skipped`].
+    /// Frames larger than a buffer are cropped to it. Replaces a previously registered pool.
+    ///
+    /// Only DMA-BUF streams of 8 bit RGB formats are copied.
+    pub fn register_external_buffers(
+        &self,
+        buffers: Vec<ExternalBuffer>,
+    ) -> Result<(ExternalBufferPool, Receiver<ExternalFrame>)> {
+        let (tap, pool, frames) = external_copy::start(buffers, Arc::clone(&self.stats))?;
+        *self.controls.external_tap().lock().unwrap() = Some(tap);
+        Ok((pool, frames))
+    }
+
+    /// Small RGBA copies of the frames handed to the video encoder, e.g. for a live preview in
+    /// a GUI without decoding the encoded stream. Frames are scaled into the size of `config`
+    /// on a thread of their own, shared memory ones on the CPU and DMA-BUFs on the GPU, at most
+    /// [`PreviewConfig::max_fps`] a second.
+    ///
+    /// Encoding never waits for the preview: frames which find the preview thread busy or the
+    /// receiver full are dropped, counted in [`CaptureStats::preview_frames_dropped`]. They show
+    /// the frames as captured, before the encoder crops, scales or turns them. Replaces the
+    /// receiver of an earlier call, which is disconnected.
+    ///
+    /// Only 8 bit RGB streams are previewed.
+    pub fn preview_frames(&self, config: PreviewConfig) -> Result<Receiver<PreviewFrame>> {
+        let (tap, frames) = preview::start(config, Arc::clone(&self.stats))?;
+        *self.controls.preview_tap().lock().unwrap() = Some(tap);
+        Ok(frames)
+    }
+
+    /// Make the next encoded video frame an IDR frame, its [`EncodedVideoFrame::is_keyframe`]
+    /// is set. See [`CaptureControls::force_keyframe`] to request them from another thread.
+    /// Passed through H.264 is encoded by the compositor and ignores it.
+    pub fn force_keyframe(&self) {
+        self.controls.force_keyframe();
+    }
+
+    /// Target `bits_per_sec` from the next video frame on without a [`Self::reset`], e.g. to
+    /// follow the network of a stream. Needs a VBR, CBR or QVBR
+    /// [`VideoConfig::rate_control`], whose max bitrate and VBV buffer are scaled along. NVENC
+    /// changes it in the open session. The VAAPI drivers can't, their encoder is reopened
+    /// after encoding the frames in flight and starts with a keyframe, the pts carry on.
+    pub fn set_bitrate(&mut self, bits_per_sec: u64) -> Result<()> {
+        if let Some(ref enc) = self.video_encoder {
+            enc.lock().unwrap().set_bitrate(bits_per_sec)?;
+        }
+        if let Some(ref mut settings) = self.settings {
+            settings.video_config.set_bitrate(bits_per_sec)?;
+        }
+        Ok(())
+    }
+
+    /// Switch the video encoder to `quality` while capturing, e.g. between a performance and a
+    /// quality mode. The encoder is reopened, so the next frame is a keyframe, while the pts
+    /// carry on and the receivers handed out stay subscribed. Frames still in flight in the
+    /// VAAPI encoder are encoded first, the other encoders drop them. Switching to or from
+    /// [`QualityPreset::Lossless`] takes a new capture.
+    pub fn change_quality(&mut self, quality: QualityPreset) -> Result<()> {
+        if let Some(ref enc) = self.video_encoder {
+            enc.lock().unwrap().change_quality(quality)?;
+        }
+        if let Some(ref mut settings) = self.settings {
+            settings.video_config.set_quality(quality)?;
+        }
+        Ok(())
+    }
+
+    /// Encode only `crop` of the captured frames from the next video frame on, or the whole
+    /// frames again for None, see [`VideoConfig::crop`]. Moving the crop keeps the encoder. A
+    /// crop of another size reopens it, so the next frame is a keyframe, while the pts carry on
+    /// and the receivers handed out stay subscribed. Fails when the crop doesn't lie within the
+    /// current source.
+    pub fn set_crop(&mut self, crop: Option<Rect>) -> Result<()> {
+        if let Some(ref enc) = self.video_encoder {
+            enc.lock().unwrap().set_crop(crop)?;
+        }
+        if let Some(ref mut settings) = self.settings {
+            settings.video_config.set_crop(crop)?;
+        }
+        Ok(())
+    }
+
+    /// Blend `overlay` onto the frames from the next video frame on, or stop blending one for
+    /// None, see [`VideoConfig::overlay`]. Only the filter graph is rebuilt, the encoder is kept
+    /// and the next frame needn't be a keyframe. Fails when the image can't be read or doesn't
+    /// fit into the encoded frames.
+    pub fn set_overlay(&mut self, overlay: Option<OverlayConfig>) -> Result<()> {
+        if let Some(ref enc) = self.video_encoder {
+            enc.lock().unwrap().set_overlay(overlay.clone())?;
+        }
+        if let Some(ref mut settings) = self.settings {
+            settings.video_config.set_overlay(overlay)?;
+        }
+        Ok(())
+    }
+
+    /// The current time on the clock frames are stamped with, e.g. for
+    /// [`Capture::inject_metadata`]
+    pub fn now(&self) -> CaptureTime {
+        failure_injection::capture_time(&self.controls, capture_clock_ns())
+    }
+
+    /// Add an application defined blob, e.g. input events or game telemetry, to the single
+    /// output stream. It is delivered as [`MediaPacket::Metadata`] ordered by `timestamp`
+    /// between the audio and video packets, and held back no longer than them. Blobs still
+    /// queued are delivered by [`Self::finish`].
+    ///
+    /// Blobs are limited to [`MAX_METADATA_SIZE`] bytes and keys to [`MAX_METADATA_KEY_LEN`].
+    /// Only available when the capture was built with
+    /// [`crate::pipeline::builder::CaptureBuilder::with_single_output`].
+    pub fn inject_metadata(&self, timestamp: CaptureTime, key: &str, data: &[u8]) -> Result<()> {
+        let interleaver = self.interleaver_tx.as_ref().ok_or(WaycapError::Validation(
+            "Capture was not built in single output mode".to_string(),
+        ))?;
+        if data.len() > MAX_METADATA_SIZE || key.len() > MAX_METADATA_KEY_LEN {
+            return Err(WaycapError::Validation(format!(
+                "Metadata {key:.32} of {} bytes exceeds the size limits",
+                data.len()
+            )));
+        }
+        let metadata = TimedMetadata {
+            timestamp,
+            key: key.to_string(),
+            data: data.to_vec(),
+        };
+        match interleaver.try_send(InterleaverControl::Sparse(MediaPacket::Metadata(metadata))) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => Err(WaycapError::Stream(
+                "Metadata queue is full, the media receiver is not keeping up".to_string(),
+            )),
+            Err(TrySendError::Disconnected(_)) => {
+                Err(WaycapError::Stream("Capture was closed".to_string()))
+            }
+        }
+    }
+
+    /// Snapshot of the statistics of this capture session
+    pub fn stats(&self) -> CaptureStats {
+        self.stats.snapshot()
+    }
+
+    /// One line status for bug reports and status bars, e.g.
+    /// `waycap: vaapi(h264) 2560x1440@143 vfr, enc 4.1ms avg, drops 0/12431, aud opus 128k, q 3/10`
+    ///
+    /// Shows `negotiating…` until the video stream was negotiated and the frame count and length
+    /// after [`Self::finish`]. Cheap enough to call every second.
+    pub fn health_summary(&self) -> String {
+        health::summary_line(&HealthInputs {
+            stats: self.stats.snapshot(),
+            uptime: self.stats.stream_uptime(),
+            video_encoder: self.video_encoder_name.clone(),
+            stream_info: self.controls.stream_info(),
+            audio_encoder: self.audio_encoder_name.clone(),
+            queued: self.controls.cutoff().pending(StreamKind::Video),
+            queue_capacity: self.raw_frame_capacity,
+            finished: self.finished,
+        })
+    }
+
+    /// Get a channel for which to receive [`CaptureEvent`]s about the capture.
+    ///
+    /// Events are dropped when nobody receives them and the channel fills up.
+    pub fn get_event_receiver(&self) -> Receiver<CaptureEvent> {
+        self.event_rx.clone()
+    }
+
+    /// Details about the video encoder, including the options it did not recognize and the
+    /// delay its configuration implies at the target frame rate
+    pub fn video_encoder_info(&self) -> Option<EncoderInfo> {
+        let encoder = self.video_encoder.as_ref()?.lock().unwrap();
+        let mut info = encoder.info()?;
+        info.delay = encoder.encoder_delay(Duration::from_nanos(self.controls.frame_interval_ns()));
+        Some(info)
+    }
+
+    /// Stream parameters PipeWire negotiated for the video stream. The size follows the
+    /// compositor when it resizes the stream, see [`CaptureEvent::ResolutionChanged`].
+    ///
+    /// Save these and pass them to [`crate::pipeline::builder::CaptureBuilder::with_fast_start`]
+    /// to shorten the time to the first frame on the next run.
+    pub fn video_stream_info(&self) -> Option<VideoStreamInfo> {
+        self.controls.stream_info()
+    }
+
+    /// Node, position and size of the captured source in the compositor's global space, as
+    /// reported by the portal. The size follows resolution changes of the source.
+    pub fn source_info(&self) -> Option<SourceInfo> {
+        self.source.as_ref().map(|source| source.info())
+    }
+
+    /// Whether the portal confirmed hiding the compositor's screen sharing indicator, as asked
+    /// for with [`SessionMetadata::hide_indicator`]. False when it was not asked for, or the
+    /// portal doesn't trust the application or doesn't support it.
+    pub fn recording_indicator_hidden(&self) -> bool {
+        self.recording_indicator_hidden
+    }
+
+    /// Settings, negotiated stream parameters and portal restore token of this capture, to start
+    /// it again with [`Capture::resume_from`], e.g. after the application crashed. Take a new one
+    /// after [`Self::switch_source`], the token restores the current source.
+    ///
+    /// `None` for captures not made by [`pipeline::builder::CaptureBuilder`], whose settings
+    /// aren't known.
+    pub fn session_snapshot(&self) -> Option<SessionSnapshot> {
+        let mut snapshot = self.settings.clone()?;
+        snapshot.stream_info = self.controls.stream_info().or(snapshot.stream_info);
+        snapshot.portal_metadata.restore_token = self.restore_token.clone();
+        Some(snapshot)
+    }
+
     pub fn get_output(&mut self) -> Receiver<V::Output> {
         self.video_encoder
             .as_mut()
@@ -414,41 +1135,277 @@ impl<V: VideoEncoder> Capture<V> {
 }
 
 impl Capture<DynamicEncoder> {
-    pub fn new(
-        video_encoder_type: Option<VideoEncoderType>,
-        audio_encoder_type: AudioEncoderType,
-        quality: QualityPreset,
-        include_cursor: bool,
-        include_audio: bool,
-        target_fps: u64,
+    /// Start a capture with `settings`, see [`pipeline::builder::CaptureBuilder::build`]. The
+    /// video comes from a generated `synthetic_source` of that size if given, from the portal
+    /// otherwise.
+    pub(crate) fn new(
+        settings: SessionSnapshot,
+        synthetic_source: Option<(u32, u32)>,
     ) -> Result<Self> {
+        let SessionSnapshot {
+            portal_metadata,
+            stream_info: fast_start,
+            video_encoder: video_encoder_type,
+            video_config,
+            audio_encoder,
+            include_cursor,
+            cursor_metadata,
+            trim_audio,
+            audio_overflow,
+            audio_ring: audio_ring_config,
+            disconnect_policy,
+            screen_blank_policy,
+            single_output,
+            watchdog,
+            target_fps,
+        } = settings.clone();
+        let include_audio = audio_encoder.is_some();
+        let audio_encoder_type = audio_encoder.unwrap_or(AudioEncoderType::Opus);
+        portal_metadata.validate()?;
+        audio_ring_config.validate()?;
+        video_config.validate()?;
+        if cursor_metadata && (include_cursor || !single_output) {
+            return Err(WaycapError::Validation(
+                "Cursor metadata is only delivered in single output mode without a drawn cursor"
+                    .to_string(),
+            ));
+        }
+        if let Some((width, height)) = synthetic_source {
+            if include_audio {
+                return Err(WaycapError::Validation(
+                    "The synthetic source has no audio".to_string(),
+                ));
+            }
+            if width == 0 || height == 0 {
+                return Err(WaycapError::Validation(format!(
+                    "The synthetic source can't be {width}x{height}"
+                )));
+            }
+        }
+        // A timelapse has nothing the audio could play along to
+        let include_audio = if include_audio && video_config.timelapse.is_some() {
+            log::warn!("Leaving the audio out of the timelapse");
+            false
+        } else {
+            include_audio
+        };
+        if video_config.ten_bit
+            && !video_encoder_type.is_some_and(VideoEncoderType::supports_ten_bit)
+        {
+            return Err(WaycapError::Unsupported(match video_encoder_type {
+                Some(encoder) => format!("{} can't encode 10 bit frames", encoder.display_name()),
+                None => "10 bit needs a video encoder which supports it, e.g. AV1 (VAAPI)".into(),
+            }));
+        }
+        // Players tone map AV1 with the metadata, silently leaving it out would look washed out
+        if video_config.hdr_metadata.is_some()
+            && video_encoder_type == Some(VideoEncoderType::Av1Vaapi)
+        {
+            return Err(WaycapError::Validation(
+                "AV1 (VAAPI) can't write HDR metadata, use AV1 (SVT-AV1) or AV1 (NVENC)".into(),
+            ));
+        }
+        if let Some(encoder) = video_encoder_type.filter(|encoder| {
+            video_config.quality == QualityPreset::Lossless && !encoder.supports_lossless()
+        }) {
+            return Err(encoders::video::lossless_unsupported(
+                encoder.display_name(),
+            ));
+        }
+        if video_config.intra_refresh
+            && !video_encoder_type.is_some_and(VideoEncoderType::supports_intra_refresh)
+        {
+            return Err(WaycapError::Unsupported(match video_encoder_type {
+                Some(encoder) => format!("{} has no intra refresh", encoder.display_name()),
+                None => "Intra refresh needs an NVENC video encoder".into(),
+            }));
+        }
+        // Before the lossless probe, which opens an encoder
+        let runtime = Runtime::acquire()?;
+        let lossless_on_cpu = video_config.quality == QualityPreset::Lossless
+            && encoders::dynamic_encoder::lossless_on_cpu(video_encoder_type);
+        if video_config.pip.is_some()
+            && (!video_encoder_type.is_some_and(VideoEncoderType::supports_pip) || lossless_on_cpu)
+        {
+            return Err(WaycapError::Unsupported(match video_encoder_type {
+                _ if lossless_on_cpu => {
+                    "Lossless is encoded with FFV1 here, which has no picture-in-picture".into()
+                }
+                Some(encoder) => format!("{} has no picture-in-picture", encoder.display_name()),
+                None => "Picture-in-picture needs a VAAPI or QSV video encoder".into(),
+            }));
+        }
+        let pip = video_config.pip;
+        let controls = Arc::new(CaptureControls::from_fps(target_fps));
+        *controls.keyframes().lock().unwrap() =
+            KeyframeScheduler::new(video_config.keyframe_interval)
+                .with_scene_changes(video_config.scene_change_threshold);
+        let _log_session = ffmpeg_log::SessionScope::enter(controls.session_id());
+        let (event_tx, event_rx) = bounded(64);
+        // Created first so events and cursor updates reach it from the start
+        let interleaver = single_output.then(|| bounded(METADATA_CAPACITY));
         let mut _self = Self {
-            controls: Arc::new(CaptureControls::from_fps(target_fps)),
+            controls,
             worker_handles: Vec::new(),
             video_encoder: None,
+            video_encoder_name: None,
             audio_encoder: None,
+            audio_encoder_name: None,
             pw_video_terminate_tx: None,
             pw_audio_terminate_tx: None,
+            pw_pip_terminate_tx: None,
+            media_rx: None,
+            interleaver_tx: interleaver
+                .as_ref()
+                .map(|(control_tx, _)| control_tx.clone()),
+            stats: Arc::new(StatsCounters::default()),
+            source: None,
+            raw_video_tx: None,
+            cursor_metadata,
+            stream_ready: None,
+            include_cursor: false,
+            // Cropped, scaled, turned, overlaid, picture-in-picture, constant rate or timelapse
+            // captures encode the stream themselves
+            passthrough: video_encoder_type == Some(VideoEncoderType::H264Passthrough)
+                && video_config.quality != QualityPreset::Lossless
+                && video_config.output_width.is_none()
+                && video_config.output_height.is_none()
+                && video_config.crop.is_none()
+                && video_config.overlay.is_none()
+                && video_config.pip.is_none()
+                && video_config.cfr.is_none()
+                && video_config.timelapse.is_none()
+                && matches!(video_config.transform, None | Some(Transform::Normal)),
+            software: matches!(
+                video_encoder_type,
+                Some(
+                    VideoEncoderType::H264Software
+                        | VideoEncoderType::Av1Svt
+                        | VideoEncoderType::Vp8
+                )
+            ) || (video_encoder_type == Some(VideoEncoderType::Mjpeg)
+                && !encoders::dynamic_encoder::mjpeg_on_vaapi())
+                || lossless_on_cpu,
+            ten_bit: video_config.ten_bit,
+            raw_frame_capacity: raw_frame_capacity(video_config.latency_mode),
+            trim_audio,
+            disconnect_policy,
+            cfr: video_config.cfr,
+            timelapse: video_config.timelapse,
+            dedup: video_config.dedup,
+            screen_blank_policy,
+            restore_token: portal_metadata.restore_token.clone(),
+            portal_metadata,
+            recording_indicator_hidden: false,
+            settings: Some(settings),
+            finished: None,
+            event_tx: match &interleaver {
+                Some((control_tx, _)) => EventSender::new(event_tx).with_media(control_tx.clone()),
+                None => EventSender::new(event_tx),
+            },
+            event_rx,
+            _runtime: runtime,
         };
+        // Its frames are in memory, which only the encoders on the CPU take
+        if synthetic_source.is_some() && !_self.software {
+            return Err(WaycapError::Unsupported(
+                "The synthetic source needs a video encoder on the CPU, e.g. H.264 (software)"
+                    .into(),
+            ));
+        }
 
-        let (frame_rx, ready_state, resolution) = _self.start_pipewire_video(include_cursor)?;
+        // Create the encoder for the expected parameters while the portal dialog is open
+        let pre_created_encoder = fast_start.map(|info| {
+            let video_config = video_config.clone();
+            let events = _self.event_tx.clone();
+            let session_id = _self.controls.session_id();
+            std::thread::spawn(move || {
+                let _log_session = ffmpeg_log::SessionScope::enter(session_id);
+                DynamicEncoder::new(
+                    video_encoder_type,
+                    info.width,
+                    info.height,
+                    video_config,
+                    &events,
+                )
+            })
+        });
 
-        _self.video_encoder = Some(Arc::new(Mutex::new(DynamicEncoder::new(
-            video_encoder_type,
-            resolution.width,
-            resolution.height,
-            quality,
-        )?)));
+        let (frame_rx, ready_state, stream_info) = match synthetic_source {
+            Some(size) => _self.start_synthetic_video(size, target_fps),
+            None => _self.start_pipewire_video(include_cursor, fast_start)?,
+        };
+        _self.controls.set_stream_info(stream_info);
+        if video_config.ten_bit && !encoders::vaapi_encoder::is_ten_bit(stream_info.format) {
+            return Err(WaycapError::Unsupported(format!(
+                "The compositor negotiated {:?}, it doesn't send 10 bit frames",
+                stream_info.format
+            )));
+        }
+
+        let encoded = stream_info.format == VideoFormat::Encoded;
+        let video_encoder_type = match video_encoder_type {
+            Some(VideoEncoderType::H264Passthrough) if !encoded => {
+                log::info!("The compositor only offered raw video, encoding it ourselves");
+                None
+            }
+            encoder_type => encoder_type,
+        };
+
+        let pre_created_encoder = match (pre_created_encoder, fast_start) {
+            (Some(handle), Some(info))
+                if info.width == stream_info.width
+                    && info.height == stream_info.height
+                    && (info.format == VideoFormat::Encoded) == encoded =>
+            {
+                match handle.join() {
+                    Ok(Ok(encoder)) => Some(encoder),
+                    Ok(Err(e)) => {
+                        log::warn!("Could not pre-create video encoder: {e}");
+                        None
+                    }
+                    Err(_) => None,
+                }
+            }
+            _ => None,
+        };
+
+        let mut video_encoder = match pre_created_encoder {
+            Some(encoder) => encoder,
+            None => DynamicEncoder::new(
+                video_encoder_type,
+                stream_info.width,
+                stream_info.height,
+                video_config,
+                &_self.event_tx,
+            )?,
+        };
+        video_encoder.set_stats(Arc::clone(&_self.stats));
+        video_encoder.set_input_format(stream_info.format)?;
+        if let Some(pip) = pip {
+            let frames = Arc::new(PipFrames::default());
+            video_encoder.set_pip_frames(Arc::clone(&frames));
+            let (pip_sender, pip_worker) =
+                capture::pip::start(pip, frames, Arc::clone(&_self.controls));
+            _self.pw_pip_terminate_tx = Some(pip_sender);
+            _self.worker_handles.push(pip_worker);
+        }
+        _self.video_encoder_name = video_encoder.info().map(|info| info.name);
+        _self.video_encoder = Some(Arc::new(Mutex::new(video_encoder)));
 
         if include_audio {
             println!("including audio");
-            let audio_rx =
-                _self.start_pipewire_audio(audio_encoder_type, Arc::clone(&ready_state))?;
+            let audio_ring = _self.start_pipewire_audio(
+                audio_encoder_type,
+                audio_overflow,
+                audio_ring_config,
+                Arc::clone(&ready_state),
+            )?;
             // Wait until both either threads are ready
             ready_state.wait_for_both();
             let audio_loop = audio_encoding_loop(
                 Arc::clone(_self.audio_encoder.as_ref().unwrap()),
-                audio_rx,
+                audio_ring,
                 Arc::clone(&_self.controls),
             );
 
@@ -459,24 +1416,73 @@ impl Capture<DynamicEncoder> {
             ready_state.wait_for_both();
         }
 
+        let raw_queue = frame_rx.clone();
         DynamicEncoder::start_processing(&mut _self, frame_rx)?;
 
+        if let Some(watchdog) = watchdog {
+            let video_encoder = Arc::clone(_self.video_encoder.as_ref().unwrap());
+            let encoded_queue = video_encoder.lock().unwrap().output_queues();
+            _self.worker_handles.push(watchdog_loop(
+                watchdog,
+                Arc::clone(&_self.stats),
+                raw_queue,
+                encoded_queue,
+                video_encoder,
+                _self.event_tx.clone(),
+                Arc::clone(&_self.controls),
+            ));
+        }
+
+        if let Some((_, control_rx)) = interleaver {
+            let (media_tx, media_rx) = bounded(20);
+            let video_rx = _self.get_video_receiver()?;
+            let audio_rx = _self.get_audio_receiver().ok();
+            _self.worker_handles.push(interleaving_loop(
+                video_rx,
+                audio_rx,
+                control_rx,
+                media_tx,
+                Arc::clone(&_self.controls),
+            ));
+            _self.media_rx = Some(media_rx);
+        }
+
         log::info!("Capture started successfully.");
         Ok(_self)
     }
 
+    /// Start a capture with the settings of [`Self::session_snapshot`], e.g. into a new segment
+    /// after the application crashed. The portal restores the captured source without the
+    /// dialog while the snapshot's restore token is valid, and asks the user otherwise. The
+    /// negotiated stream parameters are offered first, so the encoder produces the same output.
+    pub fn resume_from(snapshot: SessionSnapshot) -> Result<Self> {
+        pipeline::builder::CaptureBuilder::from_snapshot(snapshot).build()
+    }
+
     /// Get a channel for which to receive encoded video frames.
     ///
     /// Returns a [`crossbeam::channel::Receiver`] which allows multiple consumers.
     /// Each call creates a new consumer that will receive all future frames.
-    pub fn get_video_receiver(&mut self) -> Receiver<EncodedVideoFrame> {
-        self.video_encoder
-            .as_mut()
-            .expect("Cannot access a video encoder which was never started.")
+    ///
+    /// Unavailable when the capture was built with
+    /// [`crate::pipeline::builder::CaptureBuilder::with_single_output`], use [`Self::output`]
+    /// instead.
+    pub fn get_video_receiver(&mut self) -> Result<Receiver<EncodedVideoFrame>> {
+        if self.media_rx.is_some() {
+            return Err(WaycapError::Validation(
+                "Video receiver is unavailable in single output mode".to_string(),
+            ));
+        }
+        let encoder = self.video_encoder.as_mut().ok_or(WaycapError::Validation(
+            "Video encoder does not exist".to_string(),
+        ))?;
+        encoder
             .lock()
             .unwrap()
             .output()
-            .unwrap()
+            .ok_or(WaycapError::Validation(
+                "Video encoder has no output".to_string(),
+            ))
     }
 
     /// Get a channel for which to receive encoded audio frames.
@@ -484,6 +1490,11 @@ impl Capture<DynamicEncoder> {
     /// Returns a [`crossbeam::channel::Receiver`] which allows multiple consumers.
     /// Each call creates a new consumer that will receive all future frames.
     pub fn get_audio_receiver(&mut self) -> Result<Receiver<EncodedAudioFrame>> {
+        if self.media_rx.is_some() {
+            return Err(WaycapError::Validation(
+                "Audio receiver is unavailable in single output mode".to_string(),
+            ));
+        }
         if let Some(ref mut audio_enc) = self.audio_encoder {
             return Ok(audio_enc.lock().unwrap().get_encoded_recv().unwrap());
         } else {
@@ -493,6 +1504,38 @@ impl Capture<DynamicEncoder> {
         }
     }
 
+    /// Encoded video frames as an iterator which ends once the capture is closed.
+    ///
+    /// Frames received through it are counted in [`CaptureStats::packets_consumed`].
+    /// Unavailable like [`Self::get_video_receiver`].
+    pub fn video_frames(&mut self) -> Result<VideoFrames> {
+        Ok(VideoFrames::new(
+            self.get_video_receiver()?,
+            Arc::clone(&self.stats),
+        ))
+    }
+
+    /// Encoded audio frames as an iterator which ends once the capture is closed.
+    ///
+    /// Frames received through it are counted in [`CaptureStats::audio_packets_consumed`].
+    pub fn audio_frames(&mut self) -> Result<AudioFrames> {
+        Ok(AudioFrames::new(
+            self.get_audio_receiver()?,
+            Arc::clone(&self.stats),
+        ))
+    }
+
+    /// Get a channel for which to receive the encoded video and audio packets together with the
+    /// injected metadata, cursor updates and events, ordered by their capture timestamp.
+    ///
+    /// Only available when the capture was built with
+    /// [`crate::pipeline::builder::CaptureBuilder::with_single_output`].
+    pub fn output(&self) -> Result<Receiver<MediaPacket>> {
+        self.media_rx.clone().ok_or(WaycapError::Validation(
+            "Capture was not built in single output mode".to_string(),
+        ))
+    }
+
     /// Perform an action with the video encoder
     /// # Examples
     ///
@@ -572,11 +1615,12 @@ impl<V: VideoEncoder> Drop for Capture<V> {
 #[allow(clippy::too_many_arguments)]
 fn audio_encoding_loop(
     audio_encoder: Arc<Mutex<dyn AudioEncoder + Send>>,
-    audio_recv: Receiver<RawAudioFrame>,
+    audio_ring: RingConsumer,
     controls: Arc<CaptureControls>,
 ) -> std::thread::JoinHandle<Result<()>> {
+    controls.cutoff().track(StreamKind::Audio);
     std::thread::spawn(move || -> Result<()> {
-        // CUDA contexts are thread local so set ours to this thread
+        let _log_session = ffmpeg_log::SessionScope::enter(controls.session_id());
 
         while !controls.is_stopped() {
             if controls.is_paused() {
@@ -584,23 +1628,18 @@ fn audio_encoding_loop(
                 continue;
             }
 
-            select! {
-                recv(audio_recv) -> raw_samples => {
-                    match raw_samples {
-                        Ok(raw_samples) => {
-                            // If we are getting samples then we know this must be set or we
-                            // wouldn't be in here
-                            audio_encoder.as_ref().lock().unwrap().process(raw_samples)?;
-                        }
-                        Err(_) => {
-                            log::info!("Audio channel disconnected");
-                            break;
-                        }
-                    }
+            // Times out to check the stop/pause flags periodically
+            match audio_ring.pop_timeout(Duration::from_millis(100)) {
+                Some(raw_samples) => {
+                    let result = audio_encoder.as_ref().lock().unwrap().process(raw_samples);
+                    controls.cutoff().frame_done(StreamKind::Audio);
+                    result?;
                 }
-                default(Duration::from_millis(100)) => {
-                    // Timeout to check stop/pause flags periodically
+                None if audio_ring.is_finished() => {
+                    log::info!("Audio capture stopped");
+                    break;
                 }
+                None => {}
             }
         }
         Ok(())
@@ -1,8 +1,19 @@
+use std::time::Duration;
+
 use crate::{
     encoders::dynamic_encoder::DynamicEncoder,
+    portal::SessionMetadata,
     types::{
-        config::{AudioEncoder, QualityPreset, VideoEncoder},
+        config::{
+            AudioEncoder, AudioRingConfig, ColorRange, Colorimetry, DenoiseStrength,
+            DisconnectPolicy, Fps, H264Profile, HdrMetadata, LatencyMode, NvencPreset,
+            NvencRetryConfig, NvencTune, NvencTuning, OverflowPolicy, OverlayConfig, PipConfig,
+            QualityPreset, RateControl, Rect, ScaleMode, ScreenBlankPolicy, TimelapseConfig,
+            Transform, VaapiImport, VideoConfig, VideoEncoder, WatchdogConfig,
+        },
         error::Result,
+        session::SessionSnapshot,
+        video_frame::VideoStreamInfo,
     },
     Capture,
 };
@@ -11,9 +22,57 @@ pub struct CaptureBuilder {
     video_encoder: Option<VideoEncoder>,
     audio_encoder: Option<AudioEncoder>,
     quality_preset: Option<QualityPreset>,
+    hdr_metadata: Option<HdrMetadata>,
+    async_depth: Option<u32>,
+    nvenc_retry: Option<NvencRetryConfig>,
+    strict_options: bool,
+    reserve_nvenc_session: bool,
+    keyframe_interval: Option<Duration>,
+    ten_bit: bool,
+    rate_control: Option<RateControl>,
+    max_b_frames: u32,
+    extra_encoder_options: Vec<(String, String)>,
+    latency_mode: LatencyMode,
+    nvenc_tuning: NvencTuning,
+    nvenc_preset: Option<NvencPreset>,
+    nvenc_tune: Option<NvencTune>,
+    low_power: bool,
+    vaapi_force_cqp: bool,
+    vaapi_import: VaapiImport,
+    h264_profile: Option<H264Profile>,
+    h264_level: Option<u32>,
+    repeat_headers: bool,
+    intra_refresh: bool,
+    scene_change_threshold: Option<f32>,
+    cfr: Option<Fps>,
+    timelapse: Option<TimelapseConfig>,
+    dedup: Option<Duration>,
+    output_width: Option<u32>,
+    output_height: Option<u32>,
+    scale_mode: ScaleMode,
+    pad_color: [u8; 3],
+    transform: Option<Transform>,
+    color_range: ColorRange,
+    colorimetry: Colorimetry,
+    overlay: Option<OverlayConfig>,
+    pip: Option<PipConfig>,
+    custom_filter: Option<String>,
+    denoise: Option<DenoiseStrength>,
+    crop: Option<Rect>,
     include_cursor: bool,
+    cursor_metadata: bool,
     include_audio: bool,
+    trim_audio: bool,
+    audio_overflow: OverflowPolicy,
+    audio_ring: AudioRingConfig,
+    disconnect_policy: DisconnectPolicy,
+    screen_blank_policy: ScreenBlankPolicy,
+    portal_metadata: SessionMetadata,
+    single_output: bool,
+    fast_start: Option<VideoStreamInfo>,
+    watchdog: Option<WatchdogConfig>,
     target_fps: u64,
+    synthetic_source: Option<(u32, u32)>,
 }
 
 impl Default for CaptureBuilder {
@@ -28,9 +87,118 @@ impl CaptureBuilder {
             video_encoder: None,
             audio_encoder: None,
             quality_preset: None,
+            hdr_metadata: None,
+            async_depth: None,
+            nvenc_retry: None,
+            strict_options: false,
+            reserve_nvenc_session: false,
+            keyframe_interval: None,
+            ten_bit: false,
+            rate_control: None,
+            max_b_frames: 0,
+            extra_encoder_options: Vec::new(),
+            latency_mode: LatencyMode::Quality,
+            nvenc_tuning: NvencTuning::default(),
+            nvenc_preset: None,
+            nvenc_tune: None,
+            low_power: false,
+            vaapi_force_cqp: false,
+            vaapi_import: VaapiImport::Auto,
+            h264_profile: None,
+            h264_level: None,
+            repeat_headers: false,
+            intra_refresh: false,
+            scene_change_threshold: None,
+            cfr: None,
+            timelapse: None,
+            dedup: None,
+            output_width: None,
+            output_height: None,
+            scale_mode: ScaleMode::Fit,
+            pad_color: [0, 0, 0],
+            transform: None,
+            color_range: ColorRange::Limited,
+            colorimetry: Colorimetry::BT709,
+            overlay: None,
+            pip: None,
+            custom_filter: None,
+            denoise: None,
+            crop: None,
             include_cursor: false,
+            cursor_metadata: false,
             include_audio: false,
+            trim_audio: false,
+            audio_overflow: OverflowPolicy::default(),
+            audio_ring: AudioRingConfig::default(),
+            disconnect_policy: DisconnectPolicy::default(),
+            screen_blank_policy: ScreenBlankPolicy::default(),
+            portal_metadata: SessionMetadata::default(),
+            single_output: false,
+            fast_start: None,
+            watchdog: None,
             target_fps: 60,
+            synthetic_source: None,
+        }
+    }
+
+    /// Builder with the settings of an earlier capture, see [`Capture::resume_from`]
+    pub fn from_snapshot(snapshot: SessionSnapshot) -> Self {
+        let video_config = snapshot.video_config;
+        Self {
+            video_encoder: snapshot.video_encoder,
+            audio_encoder: snapshot.audio_encoder,
+            quality_preset: Some(video_config.quality),
+            hdr_metadata: video_config.hdr_metadata,
+            async_depth: Some(video_config.async_depth),
+            nvenc_retry: Some(video_config.nvenc_retry),
+            strict_options: video_config.strict_options,
+            reserve_nvenc_session: video_config.reserve_nvenc_session,
+            keyframe_interval: video_config.keyframe_interval,
+            ten_bit: video_config.ten_bit,
+            rate_control: video_config.rate_control,
+            max_b_frames: video_config.max_b_frames,
+            extra_encoder_options: video_config.extra_encoder_options,
+            latency_mode: video_config.latency_mode,
+            nvenc_tuning: video_config.nvenc_tuning,
+            nvenc_preset: video_config.nvenc_preset,
+            nvenc_tune: video_config.nvenc_tune,
+            low_power: video_config.low_power,
+            vaapi_force_cqp: video_config.vaapi_force_cqp,
+            vaapi_import: video_config.vaapi_import,
+            h264_profile: video_config.h264_profile,
+            h264_level: video_config.h264_level,
+            repeat_headers: video_config.repeat_headers,
+            intra_refresh: video_config.intra_refresh,
+            scene_change_threshold: video_config.scene_change_threshold,
+            cfr: video_config.cfr,
+            timelapse: video_config.timelapse,
+            dedup: video_config.dedup,
+            output_width: video_config.output_width,
+            output_height: video_config.output_height,
+            scale_mode: video_config.scale_mode,
+            pad_color: video_config.pad_color,
+            transform: video_config.transform,
+            color_range: video_config.color_range,
+            colorimetry: video_config.colorimetry,
+            overlay: video_config.overlay,
+            pip: video_config.pip,
+            custom_filter: video_config.custom_filter,
+            denoise: video_config.denoise,
+            crop: video_config.crop,
+            include_cursor: snapshot.include_cursor,
+            cursor_metadata: snapshot.cursor_metadata,
+            include_audio: snapshot.audio_encoder.is_some(),
+            trim_audio: snapshot.trim_audio,
+            audio_overflow: snapshot.audio_overflow,
+            audio_ring: snapshot.audio_ring,
+            disconnect_policy: snapshot.disconnect_policy,
+            screen_blank_policy: snapshot.screen_blank_policy,
+            portal_metadata: snapshot.portal_metadata,
+            single_output: snapshot.single_output,
+            fast_start: snapshot.stream_info,
+            watchdog: snapshot.watchdog,
+            target_fps: snapshot.target_fps,
+            synthetic_source: None,
         }
     }
 
@@ -53,45 +221,606 @@ impl CaptureBuilder {
         self
     }
 
+    /// Optional: Leave the cursor out of the video and deliver where it is as
+    /// [`crate::types::media_packet::MediaPacket::Cursor`] instead, e.g. to draw it during
+    /// playback. Needs [`Self::with_single_output`], and can't be combined with
+    /// [`Self::with_cursor_shown`]. Building fails when the portal doesn't offer the cursor as
+    /// metadata.
+    /// Default: the cursor is hidden
+    pub fn with_cursor_metadata(mut self) -> Self {
+        self.cursor_metadata = true;
+        self
+    }
+
     pub fn with_audio(mut self) -> Self {
         self.include_audio = true;
         self
     }
 
+    /// Optional: Deliver the audio flushed by [`Capture::finish`], trimmed so the audio track ends
+    /// with the last video frame instead of running past it.
+    /// Default: the flushed audio is discarded
+    pub fn with_audio_trim(mut self) -> Self {
+        self.trim_audio = true;
+        self
+    }
+
+    /// Optional: What to do with encoded audio packets when the audio receiver is full. Dropped
+    /// packets are counted in [`crate::types::stats::CaptureStats::audio_packets_dropped`] and
+    /// reported as [`crate::types::event::CaptureEvent::AudioFrameDropped`]. The channel holds
+    /// about two seconds of audio.
+    /// Default: [`OverflowPolicy::DropNewest`]
+    pub fn with_audio_overflow_policy(mut self, policy: OverflowPolicy) -> Self {
+        self.audio_overflow = policy;
+        self
+    }
+
+    /// Optional: Size of the ring handing captured audio from the realtime capture callback to
+    /// the audio encoder, and which period to drop when the encoder falls behind. Dropped
+    /// periods are counted in [`crate::types::stats::CaptureStats::audio_periods_dropped`].
+    /// Building fails with a depth of 0 or [`OverflowPolicy::Block`].
+    /// Default: [`AudioRingConfig::default`], 64 slots dropping the newest period
+    pub fn with_audio_ring(mut self, config: AudioRingConfig) -> Self {
+        self.audio_ring = config;
+        self
+    }
+
+    /// Optional: What to do when every receiver of the encoded video was dropped. Reported once
+    /// as [`crate::types::event::CaptureEvent::ConsumerDisconnected`].
+    /// Default: [`DisconnectPolicy::StopOnDisconnect`]
+    pub fn with_disconnect_policy(mut self, policy: DisconnectPolicy) -> Self {
+        self.disconnect_policy = policy;
+        self
+    }
+
+    /// Optional: What to do when the screen locks or blanks. Reported as
+    /// [`crate::types::event::CaptureEvent::ScreenBlanked`] and
+    /// [`crate::types::event::CaptureEvent::ScreenRestored`] whatever the policy.
+    /// Default: [`ScreenBlankPolicy::KeepRecording`]
+    pub fn with_screen_blank_policy(mut self, policy: ScreenBlankPolicy) -> Self {
+        self.screen_blank_policy = policy;
+        self
+    }
+
+    /// Optional: Identify the application to the portal with an app id, the purpose of the
+    /// session and a parent window for the dialog, and optionally ask to hide the screen
+    /// sharing indicator. Building fails on a malformed handle token or parent window.
+    /// Default: [`SessionMetadata::default`], an anonymous session
+    pub fn with_portal_metadata(mut self, metadata: SessionMetadata) -> Self {
+        self.portal_metadata = metadata;
+        self
+    }
+
+    /// Optional: Deliver video, audio, cursor updates and events through a single channel of
+    /// [`crate::types::media_packet::MediaPacket`]s ordered by timestamp, see
+    /// [`Capture::output`]. The per-type receivers are unavailable in this mode.
+    pub fn with_single_output(mut self) -> Self {
+        self.single_output = true;
+        self
+    }
+
     pub fn with_quality_preset(mut self, quality: QualityPreset) -> Self {
         self.quality_preset = Some(quality);
         self
     }
 
-    /// Optional: Set a target FPS for the recording.
+    /// Optional: Target a bitrate or pin the quantizer instead of the one of the quality
+    /// preset, e.g. [`RateControl::Cbr`] for a streaming sink. See
+    /// [`VideoConfig::rate_control`].
+    /// Default: the quantizer of the quality preset
+    pub fn with_rate_control(mut self, rate_control: RateControl) -> Self {
+        self.rate_control = Some(rate_control);
+        self
+    }
+
+    /// Optional: Static HDR metadata to write into the bitstream of encoders that support it, see
+    /// [`VideoEncoder::writes_hdr_metadata`].
+    /// Default: None
+    pub fn with_hdr_metadata(mut self, hdr_metadata: HdrMetadata) -> Self {
+        self.hdr_metadata = Some(hdr_metadata);
+        self
+    }
+
+    /// Optional: Number of frames the VAAPI encoder may keep in flight, clamped to at least 1.
+    /// Default: 2
+    pub fn with_async_depth(mut self, async_depth: u32) -> Self {
+        self.async_depth = Some(async_depth);
+        self
+    }
+
+    /// Optional: How to retry opening NVENC when the driver's session limit is reached.
+    /// Default: [`NvencRetryConfig::default`], 5 retries over about 10 seconds without fallback
+    pub fn with_nvenc_retry(mut self, retry: NvencRetryConfig) -> Self {
+        self.nvenc_retry = Some(retry);
+        self
+    }
+
+    /// Optional: Open the NVENC session while building and keep it until the capture is closed
+    /// or dropped, so [`Capture::start`] and [`Capture::reset`] can't lose it to another process.
+    /// Only [`Capture::switch_source`] to a source of a different size reopens the encoder. See
+    /// [`VideoConfig::reserve_nvenc_session`].
+    /// Default: not reserved
+    pub fn with_reserved_nvenc_session(mut self) -> Self {
+        self.reserve_nvenc_session = true;
+        self
+    }
+
+    /// Optional: Have the capture place a keyframe every `interval`, at the same pts in every
+    /// encoder it feeds, instead of every 30 frames. See [`VideoConfig::keyframe_interval`].
+    /// Default: None
+    pub fn with_keyframe_interval(mut self, interval: Duration) -> Self {
+        self.keyframe_interval = Some(interval);
+        self
+    }
+
+    /// Optional: Capture and encode 10 bit frames, see [`VideoConfig::ten_bit`]. Building fails
+    /// when the compositor only sends 8 bit frames or the video encoder can't encode 10 bit.
+    /// Default: 8 bit
+    pub fn with_ten_bit(mut self) -> Self {
+        self.ten_bit = true;
+        self
+    }
+
+    /// Optional: Let the video encoder use up to `max_b_frames` B-frames, which saves bitrate
+    /// at the cost of latency and packets in decode order. See [`VideoConfig::max_b_frames`].
+    /// Default: 0
+    pub fn with_max_b_frames(mut self, max_b_frames: u32) -> Self {
+        self.max_b_frames = max_b_frames;
+        self
+    }
+
+    /// Optional: Trade quality for end-to-end latency, e.g. for game streaming: no B-frames,
+    /// one frame in flight and a short queue of raw frames. See [`VideoConfig::latency_mode`].
+    /// Default: [`LatencyMode::Quality`]
+    pub fn with_latency_mode(mut self, latency_mode: LatencyMode) -> Self {
+        self.latency_mode = latency_mode;
+        self
+    }
+
+    /// Optional: Pass an ffmpeg option to the video encoder as is, e.g. `idr_interval`, over
+    /// the ones the quality preset sets. Can be called repeatedly, building fails when the
+    /// encoder doesn't recognize one. See [`VideoConfig::extra_encoder_options`].
+    /// Default: None
+    pub fn with_encoder_option(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.extra_encoder_options.push((key.into(), value.into()));
+        self
+    }
+
+    /// Optional: Turn on NVENC's lookahead and adaptive quantization, see [`NvencTuning`].
+    /// Default: all off
+    pub fn with_nvenc_tuning(mut self, tuning: NvencTuning) -> Self {
+        self.nvenc_tuning = tuning;
+        self
+    }
+
+    /// Optional: Pin the NVENC preset and tune instead of deriving them from the quality and
+    /// latency mode, e.g. P5 with [`NvencTune::HighQuality`] for recordings or P1 with
+    /// [`NvencTune::UltraLowLatency`] for streaming. Either can be left to the default.
+    /// Default: None for both
+    pub fn with_nvenc_preset(
+        mut self,
+        preset: Option<NvencPreset>,
+        tune: Option<NvencTune>,
+    ) -> Self {
+        self.nvenc_preset = preset;
+        self.nvenc_tune = tune;
+        self
+    }
+
+    /// Optional: Encode with the low power entrypoint on Intel GPUs, which frees their shader
+    /// cores. See [`VideoConfig::low_power`].
+    /// Default: the regular entrypoint
+    pub fn with_low_power_encoding(mut self) -> Self {
+        self.low_power = true;
+        self
+    }
+
+    /// Optional: Keep the fixed quantizer of the quality presets on Intel GPUs instead of ICQ.
+    /// See [`VideoConfig::vaapi_force_cqp`].
+    /// Default: ICQ where the driver has it
+    pub fn with_vaapi_cqp(mut self) -> Self {
+        self.vaapi_force_cqp = true;
+        self
+    }
+
+    /// Optional: Pin how the VAAPI encoders import the captured DMA-BUFs, to debug drivers
+    /// which fail to map them. See [`VideoConfig::vaapi_import`].
+    /// Default: [`VaapiImport::Auto`], mapped and copied through the CPU if that fails
+    pub fn with_vaapi_import(mut self, import: VaapiImport) -> Self {
+        self.vaapi_import = import;
+        self
+    }
+
+    /// Optional: Pin the H.264 profile of the VAAPI and NVENC encoders, e.g. Constrained
+    /// Baseline for old decoders, which needs `max_b_frames` at 0.
+    /// Default: None, the driver's choice
+    pub fn with_h264_profile(mut self, profile: H264Profile) -> Self {
+        self.h264_profile = Some(profile);
+        self
+    }
+
+    /// Optional: Pin the H.264 level of the VAAPI and NVENC encoders, as the level times ten,
+    /// e.g. 41 for 4.1.
+    /// Default: None, picked by the encoder
+    pub fn with_h264_level(mut self, level: u32) -> Self {
+        self.h264_level = Some(level);
+        self
+    }
+
+    /// Optional: Put the SPS and PPS in front of every H.264 keyframe, for streams receivers
+    /// join mid-session. See [`VideoConfig::repeat_headers`].
+    /// Default: only where the encoder writes them
+    pub fn with_repeated_headers(mut self) -> Self {
+        self.repeat_headers = true;
+        self
+    }
+
+    /// Optional: Refresh the picture gradually instead of with periodic IDR frames, avoiding
+    /// their bitrate spikes. NVENC only, and only the first packet is a keyframe, see
+    /// [`VideoConfig::intra_refresh`].
+    /// Default: periodic keyframes
+    pub fn with_intra_refresh(mut self) -> Self {
+        self.intra_refresh = true;
+        self
+    }
+
+    /// Optional: Force a keyframe where more than `threshold` of the picture changes between
+    /// two frames, e.g. 0.4. See [`VideoConfig::scene_change_threshold`].
+    /// Default: off
+    pub fn with_scene_change_keyframes(mut self, threshold: f32) -> Self {
+        self.scene_change_threshold = Some(threshold);
+        self
+    }
+
+    /// Optional: Encode exactly `fps` frames per second, repeating and dropping frames to fill
+    /// a grid of the frame interval, for consumers which choke on a variable frame rate. The
+    /// counts are in [`crate::types::stats::CaptureStats::frames_duplicated`] and
+    /// [`crate::types::stats::CaptureStats::frames_rate_limited`]. See [`VideoConfig::cfr`].
+    /// Default: variable, the capture timestamps are kept
+    pub fn with_cfr(mut self, fps: impl Into<Fps>) -> Self {
+        self.cfr = Some(fps.into());
+        self
+    }
+
+    /// Optional: Record a timelapse, keeping a frame per capture interval and playing the kept
+    /// frames back at a rate of their own, e.g. `TimelapseConfig::new(Duration::from_secs(1),
+    /// 30)`. The audio is left out even with [`Self::with_audio`]. See
+    /// [`VideoConfig::timelapse`].
+    /// Default: real time
+    pub fn with_timelapse(mut self, timelapse: TimelapseConfig) -> Self {
+        self.timelapse = Some(timelapse);
+        self
+    }
+
+    /// Optional: Skip frames identical to the last encoded one, still encoding one every
+    /// `heartbeat`, for still screens which would otherwise be encoded at the full frame rate.
+    /// The count is in [`crate::types::stats::CaptureStats::frames_deduplicated`]. Building
+    /// fails together with [`Self::with_cfr`] or [`Self::with_timelapse`]. See
+    /// [`VideoConfig::dedup`].
+    /// Default: None, every frame is encoded
+    pub fn with_dedup(mut self, heartbeat: Duration) -> Self {
+        self.dedup = Some(heartbeat);
+        self
+    }
+
+    /// Optional: Scale the frames to `width`x`height` before encoding them, e.g. 1920x1080 for
+    /// a 4K monitor. Pictures of another aspect ratio are letterboxed unless
+    /// [`Self::with_scale_mode`] says otherwise. See [`VideoConfig::output_width`].
+    /// Default: the captured size
+    pub fn with_output_size(mut self, width: u32, height: u32) -> Self {
+        self.output_width = Some(width);
+        self.output_height = Some(height);
+        self
+    }
+
+    /// Optional: Stretch, letterbox or crop pictures whose aspect ratio differs from the
+    /// [`Self::with_output_size`]. See [`VideoConfig::scale_mode`].
+    /// Default: [`ScaleMode::Fit`]
+    pub fn with_scale_mode(mut self, scale_mode: ScaleMode) -> Self {
+        self.scale_mode = scale_mode;
+        self
+    }
+
+    /// Optional: RGB of the bars around letterboxed pictures. See [`VideoConfig::pad_color`].
+    /// Default: black
+    pub fn with_pad_color(mut self, pad_color: [u8; 3]) -> Self {
+        self.pad_color = pad_color;
+        self
+    }
+
+    /// Optional: Rotate or mirror the frames before encoding them, over the transform the
+    /// compositor puts in the buffer metadata. [`Transform::Normal`] encodes the buffers as they
+    /// are. See [`VideoConfig::transform`].
+    /// Default: the compositor's transform
+    pub fn with_transform(mut self, transform: Transform) -> Self {
+        self.transform = Some(transform);
+        self
+    }
+
+    /// Optional: Encode YUV of the full 0 to 255 range instead of the limited 16 to 235 one, for
+    /// players which know to read the tag. See [`VideoConfig::color_range`].
+    /// Default: [`ColorRange::Limited`]
+    pub fn with_color_range(mut self, color_range: ColorRange) -> Self {
+        self.color_range = color_range;
+        self
+    }
+
+    /// Optional: Primaries, transfer and matrix to tag the stream with, e.g.
+    /// [`Colorimetry::HDR10`] with [`Self::with_ten_bit`] on an HDR compositor. See
+    /// [`VideoConfig::colorimetry`].
+    /// Default: [`Colorimetry::BT709`]
+    pub fn with_colorimetry(mut self, colorimetry: Colorimetry) -> Self {
+        self.colorimetry = colorimetry;
+        self
+    }
+
+    /// Optional: Blend an image, e.g. a logo, onto every frame. Building fails when it can't be
+    /// read or doesn't fit into the encoded frames. Can be changed while capturing with
+    /// [`Capture::set_overlay`]. See [`VideoConfig::overlay`].
+    /// Default: None
+    pub fn with_overlay(mut self, overlay: OverlayConfig) -> Self {
+        self.overlay = Some(overlay);
+        self
+    }
+
+    /// Optional: Composite a second PipeWire source, e.g. a webcam, into the frames. Its frames
+    /// are matched to the captured ones by timestamp without ever holding the capture up.
+    /// Building fails with encoders other than VAAPI and QSV. See [`VideoConfig::pip`].
+    /// Default: None
+    pub fn with_pip(mut self, pip: PipConfig) -> Self {
+        self.pip = Some(pip);
+        self
+    }
+
+    /// Optional: Run a chain of ffmpeg filters, e.g. `deinterlace_vaapi`, on the captured
+    /// frames of the VAAPI and QSV encoders. Building fails with ffmpeg's explanation when it
+    /// doesn't fit, see [`VideoConfig::custom_filter`] for what it gets and has to hand on.
+    /// Default: None
+    pub fn with_custom_filter(mut self, filter: impl Into<String>) -> Self {
+        self.custom_filter = Some(filter.into());
+        self
+    }
+
+    /// Optional: Filter noise out of the frames, for camera previews and video playback which
+    /// waste bitrate on it. See [`VideoConfig::denoise`].
+    /// Default: None
+    pub fn with_denoise(mut self, strength: DenoiseStrength) -> Self {
+        self.denoise = Some(strength);
+        self
+    }
+
+    /// Optional: Encode only `crop` of the captured frames, e.g. a window sized region of the
+    /// monitor. Building fails when it doesn't lie within the stream. Can be moved while
+    /// capturing with [`Capture::set_crop`]. See [`VideoConfig::crop`].
+    /// Default: the whole frames
+    pub fn with_crop(mut self, crop: Rect) -> Self {
+        self.crop = Some(crop);
+        self
+    }
+
+    /// Optional: Fail to build when the video encoder does not recognize one of the options
+    /// passed to it, see [`Capture::video_encoder_info`].
+    /// Default: unrecognized options are logged and ignored
+    pub fn with_strict_encoder_options(mut self) -> Self {
+        self.strict_options = true;
+        self
+    }
+
+    /// Optional: Offer exactly the stream parameters of a previous run (see
+    /// [`Capture::video_stream_info`]) to PipeWire and create the encoder for them while the
+    /// portal dialog is open. Falls back to the regular negotiation if they are rejected.
+    /// Default: None
+    pub fn with_fast_start(mut self, stream_info: VideoStreamInfo) -> Self {
+        self.fast_start = Some(stream_info);
+        self
+    }
+
+    /// Optional: Run a watchdog which emits a
+    /// [`crate::types::event::CaptureEvent::PipelineStalled`] event when a stage of the video
+    /// pipeline stops making progress, see [`Capture::get_event_receiver`].
+    /// Default: No watchdog
+    pub fn with_watchdog(mut self, config: WatchdogConfig) -> Self {
+        self.watchdog = Some(config);
+        self
+    }
+
+    /// Optional: Set a target FPS for the recording. Frames the compositor delivers beyond it
+    /// are skipped before the encoder, by their timestamps so jitter doesn't lower the rate,
+    /// and counted in [`crate::types::stats::CaptureStats::frames_rate_limited`]. Replaced by
+    /// [`Self::with_cfr`] and [`Self::with_timelapse`] when they are set.
     /// Default: 60fps
     pub fn with_target_fps(mut self, fps: u64) -> Self {
         self.target_fps = fps;
         self
     }
 
+    /// Optional: Capture a generated `width` x `height` test pattern at the target FPS instead
+    /// of asking the portal for a source, e.g. to run without a compositor in tests. Needs a
+    /// video encoder on the CPU such as [`VideoEncoder::H264Software`] and no audio.
+    /// Default: the source the user picks in the portal dialog
+    pub fn with_synthetic_source(mut self, width: u32, height: u32) -> Self {
+        self.synthetic_source = Some((width, height));
+        self
+    }
+
     pub fn build(self) -> Result<Capture<DynamicEncoder>> {
+        Capture::new(self.session_settings(), self.synthetic_source)
+    }
+
+    /// What [`Capture::session_snapshot`] reports for the capture built from this, before
+    /// anything was negotiated
+    fn session_settings(&self) -> SessionSnapshot {
+        let audio_encoder = self
+            .include_audio
+            .then(|| self.audio_encoder.unwrap_or(AudioEncoder::Opus));
+        SessionSnapshot {
+            portal_metadata: self.portal_metadata.clone(),
+            stream_info: self.fast_start,
+            video_encoder: self.video_encoder,
+            video_config: self.video_config(),
+            audio_encoder,
+            include_cursor: self.include_cursor,
+            cursor_metadata: self.cursor_metadata,
+            trim_audio: self.trim_audio,
+            audio_overflow: self.audio_overflow,
+            audio_ring: self.audio_ring,
+            disconnect_policy: self.disconnect_policy,
+            screen_blank_policy: self.screen_blank_policy,
+            single_output: self.single_output,
+            watchdog: self.watchdog,
+            target_fps: self.target_fps,
+        }
+    }
+
+    fn video_config(&self) -> VideoConfig {
         let quality = match self.quality_preset {
             Some(qual) => qual,
             None => QualityPreset::Medium,
         };
 
-        let audio_encoder = if self.include_audio {
-            match self.audio_encoder {
-                Some(enc) => enc,
-                None => AudioEncoder::Opus,
-            }
-        } else {
-            AudioEncoder::Opus
+        let mut video_config = VideoConfig {
+            quality,
+            hdr_metadata: self.hdr_metadata,
+            strict_options: self.strict_options,
+            reserve_nvenc_session: self.reserve_nvenc_session,
+            keyframe_interval: self.keyframe_interval,
+            ten_bit: self.ten_bit,
+            rate_control: self.rate_control,
+            framerate: self.target_fps as u32,
+            max_b_frames: self.max_b_frames,
+            extra_encoder_options: self.extra_encoder_options.clone(),
+            latency_mode: self.latency_mode,
+            nvenc_tuning: self.nvenc_tuning,
+            nvenc_preset: self.nvenc_preset,
+            nvenc_tune: self.nvenc_tune,
+            low_power: self.low_power,
+            vaapi_force_cqp: self.vaapi_force_cqp,
+            vaapi_import: self.vaapi_import,
+            h264_profile: self.h264_profile,
+            h264_level: self.h264_level,
+            repeat_headers: self.repeat_headers,
+            intra_refresh: self.intra_refresh,
+            scene_change_threshold: self.scene_change_threshold,
+            cfr: self.cfr,
+            timelapse: self.timelapse,
+            dedup: self.dedup,
+            output_width: self.output_width,
+            output_height: self.output_height,
+            scale_mode: self.scale_mode,
+            pad_color: self.pad_color,
+            transform: self.transform,
+            color_range: self.color_range,
+            colorimetry: self.colorimetry,
+            overlay: self.overlay.clone(),
+            pip: self.pip,
+            custom_filter: self.custom_filter.clone(),
+            denoise: self.denoise,
+            crop: self.crop,
+            ..Default::default()
         };
+        if let Some(async_depth) = self.async_depth {
+            video_config.async_depth = async_depth;
+        }
+        if let Some(nvenc_retry) = self.nvenc_retry {
+            video_config.nvenc_retry = nvenc_retry;
+        }
+        video_config
+    }
+}
 
-        Capture::new(
-            self.video_encoder,
-            audio_encoder,
-            quality,
-            self.include_cursor,
-            self.include_audio,
-            self.target_fps,
-        )
+#[cfg(test)]
+mod tests {
+    use pipewire::spa::param::video::VideoFormat;
+
+    use super::*;
+    use crate::types::config::{Corner, OverlayImage, OverlayPosition};
+
+    #[test]
+    fn snapshot_round_trip() {
+        let original = CaptureBuilder::new()
+            .with_video_encoder(VideoEncoder::H264Vaapi)
+            .with_quality_preset(QualityPreset::High)
+            .with_rate_control(RateControl::Cbr {
+                bitrate: 8_000_000,
+                buffer_size: Some(4_000_000),
+            })
+            .with_encoder_option("idr_interval", "1")
+            .with_latency_mode(LatencyMode::LowLatency)
+            .with_h264_profile(H264Profile::Main)
+            .with_nvenc_preset(Some(NvencPreset::P5), Some(NvencTune::HighQuality))
+            .with_h264_level(41)
+            .with_repeated_headers()
+            .with_scene_change_keyframes(0.4)
+            .with_output_size(1920, 1080)
+            .with_scale_mode(ScaleMode::Fill)
+            .with_pad_color([16, 16, 16])
+            .with_transform(Transform::Rot270)
+            .with_color_range(ColorRange::Full)
+            .with_colorimetry(Colorimetry::HDR10)
+            .with_overlay(OverlayConfig {
+                image: OverlayImage::Path("/usr/share/pixmaps/logo.png".into()),
+                position: OverlayPosition::Anchored {
+                    corner: Corner::TopRight,
+                    margin: 16,
+                },
+                opacity: 0.8,
+            })
+            .with_pip(PipConfig {
+                node_id: 57,
+                position: OverlayPosition::Anchored {
+                    corner: Corner::BottomRight,
+                    margin: 24,
+                },
+                width: 320,
+                height: 180,
+            })
+            .with_custom_filter("deinterlace_vaapi")
+            .with_denoise(DenoiseStrength::Light)
+            .with_crop(Rect {
+                x: 100,
+                y: 50,
+                width: 1280,
+                height: 720,
+            })
+            .with_async_depth(4)
+            .with_low_power_encoding()
+            .with_vaapi_cqp()
+            .with_vaapi_import(VaapiImport::Upload)
+            .with_keyframe_interval(Duration::from_secs(2))
+            .with_cfr(Fps::new(30000, 1001))
+            .with_timelapse(TimelapseConfig::new(Duration::from_secs(5), 24))
+            .with_dedup(Duration::from_secs(1))
+            .with_audio()
+            .with_cursor_shown()
+            .with_screen_blank_policy(ScreenBlankPolicy::AutoPause)
+            .with_portal_metadata(SessionMetadata {
+                app_id: Some("org.example.Recorder".to_string()),
+                persist_session: true,
+                ..Default::default()
+            })
+            .with_target_fps(30);
+        let mut snapshot = original.session_settings();
+        // As filled in by the running capture
+        snapshot.stream_info = Some(VideoStreamInfo {
+            width: 2560,
+            height: 1440,
+            format: VideoFormat::BGRx,
+            framerate: (0, 1),
+        });
+        snapshot.portal_metadata.restore_token = Some("7c1e2f".to_string());
+
+        let resumed = CaptureBuilder::from_snapshot(snapshot.clone());
+        assert_eq!(resumed.session_settings(), snapshot);
+        // The resumed encoder is opened with the same parameters for the same stream
+        assert_eq!(resumed.video_config(), original.video_config());
+        assert_eq!(resumed.fast_start, snapshot.stream_info);
+        assert_eq!(
+            resumed.portal_metadata.restore_token.as_deref(),
+            Some("7c1e2f")
+        );
+
+        let without_audio = CaptureBuilder::new().session_settings();
+        assert_eq!(without_audio.audio_encoder, None);
+        assert!(!CaptureBuilder::from_snapshot(without_audio).include_audio);
     }
 }
@@ -0,0 +1,339 @@
+//! Frames retimed onto a grid, for a constant frame rate, see
+//! [`crate::types::config::VideoConfig::cfr`], and for timelapses, see
+//! [`crate::types::config::VideoConfig::timelapse`].
+//!
+//! Frames are encoded on the points of a grid of the frame interval starting at the first frame,
+//! one frame per point, with the point's time as pts. A frame goes to the point nearest to its
+//! timestamp; when that point has a frame already it is dropped, points skipped on the way to it
+//! repeat the previous frame. Compositors send nothing while the screen stands still, so the
+//! points are also filled as time passes without frames, estimated from when the last frame
+//! arrived. A point is left open for a frame still on its way, so a new picture isn't dropped
+//! for a repeat taken just before it. The interval is kept as a fraction, 30000/1001 fps puts
+//! frame 30000 at exactly 1001 seconds.
+//!
+//! A timelapse places the frames on a grid of its capture interval the same way, but encodes
+//! them a playback frame interval apart. Its points go on counting after a pause, so the
+//! timelapse continues where it stopped, and every few of them are keyframes.
+
+use std::{
+    ops::Range,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    types::{
+        config::{Fps, TimelapseConfig},
+        time::CaptureTime,
+    },
+    utils::TIME_UNIT_NS,
+};
+
+#[derive(Debug)]
+pub(crate) struct CfrGrid {
+    /// Capture time between two points
+    capture: Interval,
+    /// Encoded time between two points
+    output: Interval,
+    /// Every this many points are keyframes
+    keyframe_every: Option<u32>,
+    /// Whether the points go on counting after a restart, instead of starting over
+    continuous: bool,
+    /// Capture time of grid point `first_slot`
+    origin: Option<CaptureTime>,
+    first_slot: u64,
+    /// Encoded time of grid point 0
+    start: Option<CaptureTime>,
+    /// First grid point without a frame
+    next_slot: u64,
+    /// Timestamp of the last placed frame and when it arrived
+    last_frame: Option<(CaptureTime, Instant)>,
+}
+
+/// Where [`CfrGrid::place`] put a frame
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Placement {
+    /// Grid points before the frame, each repeats the previous frame
+    pub repeats: Range<u64>,
+    /// Time the frame is encoded at
+    pub time: CaptureTime,
+    /// Whether the grid makes the frame a keyframe
+    pub keyframe: bool,
+}
+
+impl Placement {
+    /// A frame encoded at its own `timestamp`, for streams without a constant frame rate
+    pub fn unchanged(timestamp: CaptureTime) -> Self {
+        Self {
+            repeats: 0..0,
+            time: timestamp,
+            keyframe: false,
+        }
+    }
+}
+
+impl CfrGrid {
+    /// A grid of `fps`, the frames are encoded at their grid point
+    pub fn new(fps: Fps) -> Self {
+        let interval = Interval::of(fps);
+        Self {
+            capture: interval,
+            output: interval,
+            keyframe_every: None,
+            continuous: false,
+            origin: None,
+            first_slot: 0,
+            start: None,
+            next_slot: 0,
+            last_frame: None,
+        }
+    }
+
+    /// A grid of the capture interval of `timelapse`, encoded at its playback rate
+    pub fn timelapse(timelapse: TimelapseConfig) -> Self {
+        Self {
+            capture: Interval::from(timelapse.capture_interval),
+            output: Interval::of(timelapse.playback_fps),
+            keyframe_every: Some(timelapse.keyframe_every),
+            continuous: true,
+            ..Self::new(timelapse.playback_fps)
+        }
+    }
+
+    /// Start a new grid at the next frame, nothing is repeated up to it. A timelapse encodes
+    /// that frame right after the last one.
+    pub fn restart(&mut self) {
+        self.origin = None;
+        self.last_frame = None;
+        if self.continuous {
+            self.first_slot = self.next_slot;
+        } else {
+            self.start = None;
+            self.first_slot = 0;
+            self.next_slot = 0;
+        }
+    }
+
+    /// Place the frame captured at `timestamp`, which arrived at `now`. `None` when its grid
+    /// point has a frame already, it is dropped then.
+    pub fn place(&mut self, timestamp: CaptureTime, now: Instant) -> Option<Placement> {
+        // Timestamps of a new source which started over start a new grid
+        if self.origin.is_some_and(|origin| timestamp < origin) {
+            self.restart();
+        }
+        self.origin.get_or_insert(timestamp);
+        self.start.get_or_insert(timestamp);
+        let slot = self.slot_at(timestamp);
+        if slot < self.next_slot {
+            return None;
+        }
+        let repeats = self.next_slot..slot;
+        self.next_slot = slot + 1;
+        self.last_frame = Some((timestamp, now));
+        Some(Placement {
+            repeats,
+            time: self.time(slot),
+            keyframe: self.is_keyframe(slot),
+        })
+    }
+
+    /// Grid points which passed by `now` without a frame, the last frame is repeated on them.
+    /// Empty before the first frame.
+    pub fn fill_idle(&mut self, now: Instant) -> Range<u64> {
+        let Some((timestamp, arrived)) = self.last_frame else {
+            return 0..0;
+        };
+        let estimated = timestamp + now.saturating_duration_since(arrived);
+        // The point nearest to now and the one before it are left to frames on their way
+        let end = self
+            .slot_at(estimated)
+            .saturating_sub(1)
+            .max(self.next_slot);
+        let idle = self.next_slot..end;
+        self.next_slot = end;
+        idle
+    }
+
+    /// Capture time between two grid points
+    pub fn interval(&self) -> Duration {
+        self.capture.times(1)
+    }
+
+    /// Time and keyframe flag of each of `slots`
+    pub fn points(&self, slots: Range<u64>) -> impl Iterator<Item = (CaptureTime, bool)> + '_ {
+        slots.map(|slot| (self.time(slot), self.is_keyframe(slot)))
+    }
+
+    /// Time grid point `slot` is encoded at
+    pub fn time(&self, slot: u64) -> CaptureTime {
+        self.start.unwrap_or_default() + self.output.times(slot)
+    }
+
+    /// Whether the frame on grid point `slot` has to be a keyframe
+    pub fn is_keyframe(&self, slot: u64) -> bool {
+        self.keyframe_every
+            .is_some_and(|every| slot % u64::from(every.max(1)) == 0)
+    }
+
+    /// Grid point nearest to `timestamp`
+    fn slot_at(&self, timestamp: CaptureTime) -> u64 {
+        let elapsed = timestamp - self.origin.unwrap_or_default();
+        self.first_slot + self.capture.nearest(elapsed)
+    }
+}
+
+/// Time between two grid points as a fraction of nanoseconds, so 30000/1001 fps doesn't drift
+#[derive(Debug, Clone, Copy)]
+struct Interval {
+    nanos: u128,
+    per: u128,
+}
+
+impl Interval {
+    fn of(fps: Fps) -> Self {
+        Self {
+            nanos: u128::from(fps.den) * u128::from(TIME_UNIT_NS),
+            per: u128::from(fps.num),
+        }
+    }
+
+    /// `count` intervals, rounded down to whole nanoseconds
+    fn times(self, count: u64) -> Duration {
+        Duration::from_nanos((u128::from(count) * self.nanos / self.per) as u64)
+    }
+
+    /// Intervals in `elapsed`, rounded to the nearest
+    fn nearest(self, elapsed: Duration) -> u64 {
+        ((elapsed.as_nanos() * self.per + self.nanos / 2) / self.nanos) as u64
+    }
+}
+
+impl From<Duration> for Interval {
+    fn from(interval: Duration) -> Self {
+        Self {
+            nanos: interval.as_nanos(),
+            per: 1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const START: CaptureTime = CaptureTime::from_nanos(5_000_000_000);
+
+    /// Timestamps of `frames` frames of a `fps` stream with up to `jitter` added
+    fn stream(fps: u64, frames: u64, jitter: Duration) -> impl Iterator<Item = CaptureTime> {
+        (0..frames).map(move |frame| {
+            let jitter = Duration::from_nanos(frame * 7919 % jitter.as_nanos().max(1) as u64);
+            START + Duration::from_nanos(frame * TIME_UNIT_NS / fps) + jitter
+        })
+    }
+
+    /// Times encoded for `timestamps`, repeats included, and the number of dropped frames
+    fn encoded(
+        grid: &mut CfrGrid,
+        timestamps: impl Iterator<Item = CaptureTime>,
+    ) -> (Vec<i64>, usize) {
+        let (mut times, mut dropped) = (Vec::new(), 0);
+        let now = Instant::now();
+        for timestamp in timestamps {
+            match grid.place(timestamp, now) {
+                Some(placement) => {
+                    times.extend(placement.repeats.map(|slot| grid.time(slot).as_nanos()));
+                    times.push(placement.time.as_nanos());
+                }
+                None => dropped += 1,
+            }
+        }
+        (times, dropped)
+    }
+
+    #[test]
+    fn fast_streams_drop_and_slow_ones_repeat_onto_the_grid() {
+        let on_grid = |times: &[i64]| {
+            times.iter().enumerate().all(|(slot, &time)| {
+                time == START.as_nanos() + (slot as u64 * TIME_UNIT_NS / 30) as i64
+            })
+        };
+
+        // 60fps with up to 4ms of jitter, every other frame is dropped. The last one is nearest
+        // to point 300.
+        let mut grid = CfrGrid::new(Fps::from(30));
+        let (times, dropped) = encoded(&mut grid, stream(60, 600, Duration::from_millis(4)));
+        assert_eq!((times.len(), dropped), (301, 299));
+        assert!(on_grid(&times), "{times:?}");
+
+        // 24fps repeats every fourth frame to make 30
+        let mut grid = CfrGrid::new(Fps::from(30));
+        let (times, dropped) = encoded(&mut grid, stream(24, 240, Duration::ZERO));
+        assert_eq!((times.len(), dropped), (300, 0));
+        assert!(on_grid(&times), "{times:?}");
+    }
+
+    #[test]
+    fn fractional_rates_dont_drift() {
+        let grid = {
+            let mut grid = CfrGrid::new(Fps::new(30000, 1001));
+            grid.place(START, Instant::now());
+            grid
+        };
+        assert_eq!(grid.time(30000), START + Duration::from_secs(1001));
+        assert_eq!(grid.time(1).as_nanos() - START.as_nanos(), 33_366_666);
+    }
+
+    #[test]
+    fn still_screens_are_filled_while_waiting() {
+        let mut grid = CfrGrid::new(Fps::from(60));
+        let arrived = Instant::now();
+        assert_eq!(grid.fill_idle(arrived), 0..0);
+        grid.place(START, arrived);
+
+        // 100ms is nearest to point 6, it and the one before are kept for frames on their way
+        let after = Duration::from_millis(100);
+        assert_eq!(grid.fill_idle(arrived + after), 1..5);
+        assert_eq!(grid.fill_idle(arrived + after), 5..5);
+        // The frame which ends the still screen lands behind the repeats
+        let placement = grid.place(START + after, arrived + after).unwrap();
+        assert_eq!(placement.repeats, 5..6);
+        assert_eq!(placement.time, grid.time(6));
+
+        // A pause starts over, the next frame repeats nothing
+        grid.restart();
+        let placement = grid
+            .place(START + Duration::from_secs(10), arrived)
+            .unwrap();
+        assert_eq!(placement.repeats, 0..0);
+        assert_eq!(placement.time, START + Duration::from_secs(10));
+    }
+
+    #[test]
+    fn timelapses_keep_a_frame_per_interval() {
+        let timelapse = TimelapseConfig {
+            capture_interval: Duration::from_secs(1),
+            playback_fps: Fps::from(30),
+            keyframe_every: 10,
+        };
+        let mut grid = CfrGrid::timelapse(timelapse);
+        // 20 seconds of 60fps, the last frame is nearest to second 20
+        let (times, dropped) = encoded(&mut grid, stream(60, 1200, Duration::from_millis(4)));
+        assert_eq!((times.len(), dropped), (21, 1179));
+        for (kept, &time) in times.iter().enumerate() {
+            assert_eq!(
+                time,
+                START.as_nanos() + (kept as u64 * TIME_UNIT_NS / 30) as i64
+            );
+        }
+        let keyframes: Vec<u64> = (0..21).filter(|&slot| grid.is_keyframe(slot)).collect();
+        assert_eq!(keyframes, [0, 10, 20]);
+
+        // After a pause the timelapse goes on without a gap
+        grid.restart();
+        let placement = grid
+            .place(START + Duration::from_secs(600), Instant::now())
+            .unwrap();
+        assert_eq!(placement.repeats, 21..21);
+        assert_eq!(placement.time, grid.time(21));
+        assert_eq!(placement.time.as_nanos() - START.as_nanos(), 700_000_000);
+    }
+}
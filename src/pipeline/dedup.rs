@@ -0,0 +1,200 @@
+//! Frames identical to the last encoded one are skipped, see
+//! [`crate::types::config::VideoConfig::dedup`].
+//!
+//! Compositors which attach damage metadata say outright whether a frame changed. Without it,
+//! every eighth row of the frame is folded into a checksum, so a 1440p frame costs 180 row reads
+//! instead of a full comparison. A change confined to the rows in between goes unnoticed until
+//! the heartbeat encodes a frame anyway.
+
+use std::time::Duration;
+
+use pipewire::spa::param::video::VideoFormat;
+
+use crate::{
+    types::{time::CaptureTime, video_frame::RawVideoFrame},
+    utils::DmaBufMapping,
+};
+
+/// Rows skipped between the ones checksummed
+const ROW_STEP: usize = 8;
+
+#[derive(Debug)]
+pub(crate) struct Deduplicator {
+    heartbeat: Duration,
+    /// Whether a frame which was not encoded carried damage, the next frame has to be
+    damage: bool,
+    /// Checksum of the last encoded frame, `None` if it had none
+    kept_checksum: Option<u64>,
+    last_kept: Option<CaptureTime>,
+}
+
+impl Deduplicator {
+    pub fn new(heartbeat: Duration) -> Self {
+        Self {
+            heartbeat,
+            damage: false,
+            kept_checksum: None,
+            last_kept: None,
+        }
+    }
+
+    /// Note the damage of `frame`, which is not encoded for another reason, e.g. the target fps
+    pub fn observe(&mut self, frame: &RawVideoFrame) {
+        self.damage |= frame.damaged.unwrap_or(false);
+    }
+
+    /// Whether `frame` shows the same as the last encoded frame and the heartbeat is not due
+    /// yet. Otherwise the frame is taken as encoded and the next ones are compared to it.
+    pub fn is_duplicate(&mut self, frame: &RawVideoFrame) -> bool {
+        let (unchanged, checksum) = match frame.damaged {
+            Some(damaged) => (!damaged && !self.damage, None),
+            None => {
+                let checksum = checksum(frame);
+                (
+                    checksum.is_some() && checksum == self.kept_checksum,
+                    checksum,
+                )
+            }
+        };
+        let due = self
+            .last_kept
+            .is_none_or(|last| frame.timestamp.duration_since(last) >= self.heartbeat);
+        if unchanged && !due {
+            return true;
+        }
+        self.damage = false;
+        self.kept_checksum = checksum;
+        self.last_kept = Some(frame.timestamp);
+        false
+    }
+
+    /// Forget the last encoded frame, e.g. after a pause, so the next frame is encoded
+    pub fn restart(&mut self) {
+        *self = Self::new(self.heartbeat);
+    }
+}
+
+/// Checksum of every [`ROW_STEP`]th row of `frame`, `None` for frames which can't be read on
+/// the CPU: anything but 4 byte pixels in shared memory or linear DMA-BUFs
+fn checksum(frame: &RawVideoFrame) -> Option<u64> {
+    match frame.format {
+        VideoFormat::BGRx
+        | VideoFormat::BGRA
+        | VideoFormat::RGBx
+        | VideoFormat::RGBA
+        | VideoFormat::xRGB
+        | VideoFormat::ARGB
+        | VideoFormat::xBGR
+        | VideoFormat::ABGR
+        | VideoFormat::xRGB_210LE
+        | VideoFormat::xBGR_210LE
+        | VideoFormat::ARGB_210LE
+        | VideoFormat::ABGR_210LE => {}
+        _ => return None,
+    }
+    let (width, height) = (
+        frame.dimensions.width as usize,
+        frame.dimensions.height as usize,
+    );
+    let (offset, stride) = (frame.offset as usize, usize::try_from(frame.stride).ok()?);
+    if width == 0 || height == 0 || stride < width * 4 {
+        return None;
+    }
+    let needed = offset + stride * (height - 1) + width * 4;
+
+    let mapping;
+    let bytes = match frame.dmabuf_fd {
+        _ if !frame.data.is_empty() => frame.data.as_slice(),
+        Some(fd) => {
+            mapping = DmaBufMapping::new(fd, frame.modifier, needed).ok()?;
+            mapping.bytes()
+        }
+        None => return None,
+    };
+    let bytes = bytes.get(offset..needed)?;
+
+    let mut hash = ((width as u64) << 32) | height as u64;
+    for y in (0..height).step_by(ROW_STEP) {
+        let row = &bytes[y * stride..][..width * 4];
+        let (words, rest) = row.as_chunks::<8>();
+        // Odd widths leave half a word, 4 byte pixels never leave less
+        let rest = rest
+            .first_chunk::<4>()
+            .map(|rest| u32::from_le_bytes(*rest));
+        for word in words
+            .iter()
+            .map(|word| u64::from_le_bytes(*word))
+            .chain(rest.map(u64::from))
+        {
+            hash = (hash.rotate_left(5) ^ word).wrapping_mul(0x51_7c_c1_b7_27_22_0a_95);
+        }
+    }
+    Some(hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WIDTH: u32 = 33;
+    const HEIGHT: u32 = 20;
+
+    /// A gray BGRx frame in shared memory captured at `millis`
+    fn frame(millis: i64) -> RawVideoFrame {
+        RawVideoFrame {
+            data: vec![128; (WIDTH * HEIGHT * 4) as usize],
+            timestamp: CaptureTime::from_nanos(millis * 1_000_000),
+            ..RawVideoFrame::bgrx(WIDTH, HEIGHT)
+        }
+    }
+
+    #[test]
+    fn identical_frames_are_skipped_until_the_heartbeat() {
+        let mut dedup = Deduplicator::new(Duration::from_secs(1));
+        assert!(!dedup.is_duplicate(&frame(0)));
+        assert!(dedup.is_duplicate(&frame(16)));
+        assert!(dedup.is_duplicate(&frame(983)));
+        assert!(!dedup.is_duplicate(&frame(1000)));
+        assert!(dedup.is_duplicate(&frame(1016)));
+
+        // The last pixel of a sampled row, past the full words of the odd width
+        let mut changed = frame(1033);
+        changed.data[(8 * WIDTH * 4 + (WIDTH - 1) * 4) as usize] = 0;
+        assert!(!dedup.is_duplicate(&changed));
+        // Compared to the changed frame from now on
+        let same = RawVideoFrame {
+            data: changed.data.clone(),
+            ..frame(1050)
+        };
+        assert!(dedup.is_duplicate(&same));
+        assert!(!dedup.is_duplicate(&frame(1066)));
+
+        // Unreadable frames are all encoded
+        let mut nv12 = frame(1083);
+        nv12.format = VideoFormat::NV12;
+        assert!(!dedup.is_duplicate(&nv12));
+        assert!(!dedup.is_duplicate(&nv12));
+
+        dedup.restart();
+        assert!(!dedup.is_duplicate(&frame(1100)));
+    }
+
+    #[test]
+    fn damage_metadata_decides_when_present() {
+        let damaged = |millis, damaged| RawVideoFrame {
+            damaged: Some(damaged),
+            ..frame(millis)
+        };
+        let mut dedup = Deduplicator::new(Duration::from_secs(1));
+        assert!(!dedup.is_duplicate(&damaged(0, false)));
+        assert!(dedup.is_duplicate(&damaged(16, false)));
+        assert!(!dedup.is_duplicate(&damaged(33, true)));
+        assert!(dedup.is_duplicate(&damaged(50, false)));
+
+        // Damage of a frame the target fps dropped carries over to the next one
+        dedup.observe(&damaged(66, true));
+        assert!(!dedup.is_duplicate(&damaged(83, false)));
+        assert!(dedup.is_duplicate(&damaged(100, false)));
+        assert!(!dedup.is_duplicate(&damaged(1083, false)));
+    }
+}
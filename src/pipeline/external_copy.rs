@@ -0,0 +1,222 @@
+//! Copies of the frames handed to the video encoder into DMA-BUFs owned by the application, see
+//! [`crate::Capture::register_external_buffers`].
+//!
+//! The encoding thread only reserves a buffer and hands the frame to a copy thread with its own
+//! EGL context, so a slow copy or an exhausted pool never holds up encoding.
+
+use std::{
+    os::fd::{AsRawFd, BorrowedFd, OwnedFd},
+    sync::Arc,
+};
+
+use crossbeam::channel::{bounded, Receiver, Sender};
+use drm_fourcc::DrmFourcc;
+use khronos_egl::Image;
+use pipewire::spa::param::video::VideoFormat;
+
+use crate::{
+    types::{
+        error::{Result, WaycapError},
+        external_buffer::{ExternalBuffer, ExternalBufferPool, ExternalFrame},
+        stats::StatsCounters,
+        time::CaptureTime,
+        video_frame::{DmaBufPlane, RawVideoFrame},
+    },
+    waycap_egl::EglContext,
+    CaptureControls,
+};
+
+/// Entry point of the encoding thread into a registered pool
+pub(crate) struct ExternalTap {
+    pool: ExternalBufferPool,
+    jobs: Sender<CopyJob>,
+}
+
+struct CopyJob {
+    index: usize,
+    fd: OwnedFd,
+    offset: u32,
+    stride: u32,
+    modifier: u64,
+    fourcc: DrmFourcc,
+    width: u32,
+    height: u32,
+    timestamp: CaptureTime,
+}
+
+/// Start the copy thread for `buffers`
+pub(crate) fn start(
+    buffers: Vec<ExternalBuffer>,
+    stats: Arc<StatsCounters>,
+) -> Result<(ExternalTap, ExternalBufferPool, Receiver<ExternalFrame>)> {
+    if buffers.is_empty() {
+        return Err(WaycapError::Validation(
+            "At least one external buffer is needed".to_string(),
+        ));
+    }
+    for buffer in &buffers {
+        buffer.validate()?;
+    }
+    let pool = ExternalBufferPool::new(buffers.len());
+    let (jobs_tx, jobs_rx) = bounded(buffers.len());
+    let (frames_tx, frames_rx) = bounded(buffers.len());
+    let thread_pool = pool.clone();
+    std::thread::spawn(move || copy_loop(buffers, thread_pool, jobs_rx, frames_tx, stats));
+    let tap = ExternalTap {
+        pool: pool.clone(),
+        jobs: jobs_tx,
+    };
+    Ok((tap, pool, frames_rx))
+}
+
+/// Hand `frame` to the copy thread of the registered pool, if any, and a buffer is free.
+/// Never blocks.
+pub(crate) fn offer(controls: &CaptureControls, stats: &StatsCounters, frame: &RawVideoFrame) {
+    let tap = controls.external_tap().lock().unwrap();
+    let Some(tap) = tap.as_ref() else {
+        return;
+    };
+    // Shared memory frames would need an upload, only DMA-BUF streams are copied
+    let (Some(fd), Some(fourcc)) = (frame.dmabuf_fd, drm_fourcc(frame.format)) else {
+        return;
+    };
+    let Some(index) = tap.pool.acquire() else {
+        stats.mark_external_copy_skipped();
+        return;
+    };
+    // The copy thread may still read the buffer after PipeWire got it back
+    let fd = match unsafe { BorrowedFd::borrow_raw(fd) }.try_clone_to_owned() {
+        Ok(fd) => fd,
+        Err(e) => {
+            log::warn!("Could not duplicate the frame's DMA-BUF: {e}");
+            tap.pool.abandon(index);
+            return;
+        }
+    };
+    let job = CopyJob {
+        index,
+        fd,
+        offset: frame.offset,
+        stride: frame.stride as u32,
+        modifier: frame.modifier,
+        fourcc,
+        width: frame.dimensions.width,
+        height: frame.dimensions.height,
+        timestamp: frame.timestamp,
+    };
+    if tap.jobs.try_send(job).is_err() {
+        tap.pool.abandon(index);
+        stats.mark_external_copy_skipped();
+    }
+}
+
+pub(crate) fn drm_fourcc(format: VideoFormat) -> Option<DrmFourcc> {
+    match format {
+        VideoFormat::BGRA => Some(DrmFourcc::Argb8888),
+        VideoFormat::BGRx => Some(DrmFourcc::Xrgb8888),
+        VideoFormat::RGBA => Some(DrmFourcc::Abgr8888),
+        VideoFormat::RGBx => Some(DrmFourcc::Xbgr8888),
+        _ => None,
+    }
+}
+
+fn copy_loop(
+    buffers: Vec<ExternalBuffer>,
+    pool: ExternalBufferPool,
+    jobs: Receiver<CopyJob>,
+    frames: Sender<ExternalFrame>,
+    stats: Arc<StatsCounters>,
+) {
+    let egl = match EglContext::new(1, 1) {
+        Ok(egl) => egl,
+        Err(e) => {
+            log::error!("Could not create the EGL context for external buffer copies: {e}");
+            return;
+        }
+    };
+    // Imported on first use and kept until the pool goes away
+    let mut targets: Vec<Option<Image>> = buffers.iter().map(|_| None).collect();
+
+    for job in jobs {
+        let result = target_image(&egl, &buffers, &mut targets, job.index)
+            .and_then(|target| copy_frame(&egl, &job, &buffers[job.index], target));
+        match result {
+            Ok(()) => {
+                pool.delivered(job.index);
+                let frame = ExternalFrame {
+                    index: job.index,
+                    timestamp: job.timestamp,
+                };
+                if frames.try_send(frame).is_ok() {
+                    stats.mark_external_copied();
+                } else {
+                    pool.abandon(job.index);
+                }
+            }
+            Err(e) => {
+                log::warn!(
+                    "Could not copy a frame into external buffer {}: {e}",
+                    job.index
+                );
+                pool.abandon(job.index);
+            }
+        }
+    }
+
+    for image in targets.into_iter().flatten() {
+        let _ = egl.destroy_image(image);
+    }
+}
+
+fn target_image(
+    egl: &EglContext,
+    buffers: &[ExternalBuffer],
+    targets: &mut [Option<Image>],
+    index: usize,
+) -> Result<Image> {
+    if let Some(image) = targets[index] {
+        return Ok(image);
+    }
+    let buffer = &buffers[index];
+    let image = egl.create_image_from_dmabuf(
+        &[DmaBufPlane {
+            fd: buffer.fd.as_raw_fd(),
+            offset: buffer.offset,
+            stride: buffer.stride,
+        }],
+        buffer.fourcc as u32,
+        buffer.width,
+        buffer.height,
+        buffer.modifier,
+    )?;
+    targets[index] = Some(image);
+    Ok(image)
+}
+
+/// Copy the frame into `target`, cropping it to the buffer's size
+fn copy_frame(
+    egl: &EglContext,
+    job: &CopyJob,
+    buffer: &ExternalBuffer,
+    target: Image,
+) -> Result<()> {
+    let source = egl.create_image_from_dmabuf(
+        &[DmaBufPlane {
+            fd: job.fd.as_raw_fd(),
+            offset: job.offset,
+            stride: job.stride,
+        }],
+        job.fourcc as u32,
+        job.width,
+        job.height,
+        job.modifier,
+    )?;
+    let result = egl.copy_image(
+        source,
+        target,
+        job.width.min(buffer.width) as i32,
+        job.height.min(buffer.height) as i32,
+    );
+    egl.destroy_image(source)?;
+    result
+}
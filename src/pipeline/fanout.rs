@@ -0,0 +1,148 @@
+use std::sync::{Arc, Mutex};
+
+use crossbeam::channel::{bounded, Receiver, Sender, TrySendError};
+
+/// Result of [`FanOut::send`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Delivery {
+    Delivered,
+    /// At least one subscriber's channel was full and missed the item
+    Full,
+    /// Every subscriber dropped its receiver
+    NoSubscribers,
+}
+
+/// Hands every item to each subscriber through a channel of its own.
+///
+/// Only the senders are kept, so a subscriber dropping its receiver is noticed on the next
+/// send. Items sent before the first subscriber attaches wait in a channel which is handed to
+/// it.
+pub(crate) struct FanOut<T>(Arc<Inner<T>>);
+
+struct Inner<T> {
+    subscribers: Mutex<Subscribers<T>>,
+    capacity: usize,
+}
+
+struct Subscribers<T> {
+    senders: Vec<Sender<T>>,
+    first: Option<Receiver<T>>,
+}
+
+impl<T> Clone for FanOut<T> {
+    fn clone(&self) -> Self {
+        Self(Arc::clone(&self.0))
+    }
+}
+
+impl<T: Clone> FanOut<T> {
+    pub fn new(capacity: usize) -> Self {
+        let (sender, receiver) = bounded(capacity);
+        Self(Arc::new(Inner {
+            subscribers: Mutex::new(Subscribers {
+                senders: vec![sender],
+                first: Some(receiver),
+            }),
+            capacity,
+        }))
+    }
+
+    /// Receive every item sent from now on
+    pub fn subscribe(&self) -> Receiver<T> {
+        let mut subscribers = self.0.subscribers.lock().unwrap();
+        if let Some(first) = subscribers.first.take() {
+            return first;
+        }
+        let (sender, receiver) = bounded(self.0.capacity);
+        subscribers.senders.push(sender);
+        receiver
+    }
+
+    pub fn send(&self, item: T) -> Delivery {
+        let mut subscribers = self.0.subscribers.lock().unwrap();
+        let mut full = false;
+        subscribers
+            .senders
+            .retain(|sender| match sender.try_send(item.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    full = true;
+                    true
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            });
+
+        if subscribers.senders.is_empty() {
+            Delivery::NoSubscribers
+        } else if full {
+            Delivery::Full
+        } else {
+            Delivery::Delivered
+        }
+    }
+
+    /// Whether anyone still receives the items, as of the last send. True until the first
+    /// subscriber attached.
+    pub fn has_subscribers(&self) -> bool {
+        !self.0.subscribers.lock().unwrap().senders.is_empty()
+    }
+
+    /// Items waiting for the slowest subscriber
+    pub fn len(&self) -> usize {
+        let subscribers = self.0.subscribers.lock().unwrap();
+        subscribers
+            .senders
+            .iter()
+            .map(Sender::len)
+            .max()
+            .unwrap_or(0)
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len() >= self.0.capacity
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_subscriber_gets_every_item() {
+        let fan_out = FanOut::new(4);
+        fan_out.send(0);
+        let first = fan_out.subscribe();
+        let second = fan_out.subscribe();
+        assert_eq!(fan_out.send(1), Delivery::Delivered);
+
+        assert_eq!(first.try_iter().collect::<Vec<_>>(), [0, 1]);
+        assert_eq!(second.try_iter().collect::<Vec<_>>(), [1]);
+    }
+
+    #[test]
+    fn detects_disconnect_and_resubscribe() {
+        let fan_out = FanOut::new(4);
+        drop(fan_out.subscribe());
+        assert!(fan_out.has_subscribers());
+        assert_eq!(fan_out.send(0), Delivery::NoSubscribers);
+        assert!(!fan_out.has_subscribers());
+
+        let receiver = fan_out.subscribe();
+        assert!(fan_out.has_subscribers());
+        assert_eq!(fan_out.send(1), Delivery::Delivered);
+        assert_eq!(receiver.try_recv(), Ok(1));
+    }
+
+    #[test]
+    fn slow_subscriber_misses_items() {
+        let fan_out = FanOut::new(1);
+        let slow = fan_out.subscribe();
+        let fast = fan_out.subscribe();
+        assert_eq!(fan_out.send(0), Delivery::Delivered);
+        assert_eq!(fast.try_recv(), Ok(0));
+        assert_eq!(fan_out.send(1), Delivery::Full);
+        assert!(fan_out.is_full());
+        assert_eq!(fast.try_recv(), Ok(1));
+        assert_eq!(slow.try_iter().collect::<Vec<_>>(), [0]);
+    }
+}
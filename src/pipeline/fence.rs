@@ -0,0 +1,106 @@
+//! Waiting for the GPU to finish rendering a frame before it is encoded.
+//!
+//! Compositors hand a buffer over as soon as its rendering is submitted, not once it is done, so
+//! reading it right away can show a torn or half drawn frame. The fence exported from the
+//! DMA-BUF at capture is waited on, or the DMA-BUF itself on kernels which can't export it. A
+//! frame the GPU doesn't finish within [`FENCE_TIMEOUT`] is dropped instead of stalling the
+//! encoder.
+
+use std::{
+    io,
+    os::fd::{AsRawFd, RawFd},
+    time::{Duration, Instant},
+};
+
+use crate::types::video_frame::RawVideoFrame;
+
+/// Longest wait for a frame to be rendered, three frames at 60fps
+pub(crate) const FENCE_TIMEOUT: Duration = Duration::from_millis(50);
+
+/// Whether the GPU finished rendering `frame` within `timeout`. Frames in memory are always
+/// ready.
+pub(crate) fn wait_until_rendered(frame: &RawVideoFrame, timeout: Duration) -> bool {
+    match frame
+        .fence
+        .as_ref()
+        .map(AsRawFd::as_raw_fd)
+        .or(frame.dmabuf_fd)
+    {
+        Some(fd) => wait_readable(fd, timeout),
+        None => true,
+    }
+}
+
+/// Polls `fd` until it is readable, which sync files and DMA-BUFs are once their fences signal
+fn wait_readable(fd: RawFd, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        let millis = left.as_micros().div_ceil(1000).min(i32::MAX as u128) as i32;
+        let mut poll_fd = libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        match unsafe { libc::poll(&mut poll_fd, 1, millis) } {
+            0 => return false,
+            ret if ret > 0 => return true,
+            _ if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted => continue,
+            // A descriptor which can't be polled isn't waited on, the encoder reports it if bad
+            _ => return true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::fd::{FromRawFd, OwnedFd};
+
+    use super::*;
+
+    /// A pipe standing in for a fence, which signals once a byte is written
+    fn pipe() -> (OwnedFd, OwnedFd) {
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) }
+    }
+
+    fn signal(fence: &OwnedFd) {
+        assert_eq!(
+            unsafe { libc::write(fence.as_raw_fd(), [1u8].as_ptr().cast(), 1) },
+            1
+        );
+    }
+
+    #[test]
+    fn waits_for_the_fence() {
+        let (read, write) = pipe();
+        let frame = RawVideoFrame {
+            fence: Some(read),
+            ..RawVideoFrame::bgrx(0, 0)
+        };
+        assert!(!wait_until_rendered(&frame, Duration::from_millis(5)));
+        signal(&write);
+        assert!(wait_until_rendered(&frame, Duration::from_millis(5)));
+    }
+
+    #[test]
+    fn waits_for_the_dmabuf_without_a_fence() {
+        let (read, write) = pipe();
+        let frame = RawVideoFrame {
+            dmabuf_fd: Some(read.as_raw_fd()),
+            ..RawVideoFrame::bgrx(0, 0)
+        };
+        assert!(!wait_until_rendered(&frame, Duration::from_millis(5)));
+        signal(&write);
+        assert!(wait_until_rendered(&frame, Duration::from_millis(5)));
+    }
+
+    #[test]
+    fn frames_in_memory_are_ready() {
+        assert!(wait_until_rendered(
+            &RawVideoFrame::bgrx(0, 0),
+            Duration::ZERO
+        ));
+    }
+}
@@ -0,0 +1,97 @@
+//! Frames passed to the video encoder when the compositor delivers more than the target fps, see
+//! [`crate::pipeline::builder::CaptureBuilder::with_target_fps`].
+//!
+//! Frame timestamps are placed on a grid of the frame interval starting at the first frame, and
+//! the first frame in each cell is kept. Measured from the last kept frame instead, every late
+//! frame would push the next one back and jitter would lower the rate. A 240fps stream limited
+//! to 60 keeps every fourth frame, a 60fps stream limited to 60 keeps all of them. Kept frames
+//! keep their timestamps, so variable framerate muxing stays correct.
+
+use std::time::Duration;
+
+use crate::types::time::CaptureTime;
+
+#[derive(Debug, Default)]
+pub(crate) struct FrameLimiter {
+    interval: Duration,
+    origin: Option<CaptureTime>,
+    /// Grid cell of the last kept frame
+    last_slot: Option<u128>,
+}
+
+impl FrameLimiter {
+    /// Whether the frame captured at `timestamp` is kept at one frame per `interval`
+    pub fn admit(&mut self, timestamp: CaptureTime, interval: Duration) -> bool {
+        // A new rate, or timestamps of a new source which started over, start a new grid
+        if interval != self.interval || self.origin.is_some_and(|origin| timestamp < origin) {
+            *self = Self {
+                interval,
+                ..Default::default()
+            };
+        }
+        let origin = *self.origin.get_or_insert(timestamp);
+        // Cells are centered on the frames of an exact rate, so jitter doesn't move them across
+        let slot = (timestamp - origin + interval / 2).as_nanos() / interval.as_nanos().max(1);
+        if self.last_slot.is_some_and(|last| slot <= last) {
+            return false;
+        }
+        self.last_slot = Some(slot);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Timestamps of `seconds` of a `fps` stream with up to `jitter` added
+    fn stream(fps: u64, seconds: u64, jitter: Duration) -> impl Iterator<Item = CaptureTime> {
+        let start = CaptureTime::from_nanos(3_000_000_000);
+        (0..fps * seconds).map(move |frame| {
+            let jitter = Duration::from_nanos(frame * 7919 % jitter.as_nanos().max(1) as u64);
+            start + Duration::from_nanos(frame * 1_000_000_000 / fps) + jitter
+        })
+    }
+
+    #[test]
+    fn keeps_the_target_rate_through_jitter() {
+        let interval = Duration::from_nanos(1_000_000_000 / 60);
+        let mut limiter = FrameLimiter::default();
+        let kept: Vec<CaptureTime> = stream(240, 10, Duration::from_micros(1500))
+            .filter(|&timestamp| limiter.admit(timestamp, interval))
+            .collect();
+        // The first frame's cell reaches back half an interval, so the cell of the last frames
+        // is cut short
+        assert_eq!(kept.len(), 601);
+        // Evenly spaced after the first cell, the timestamps are those of the captured frames
+        for pair in kept[1..].windows(2) {
+            let spacing = pair[1] - pair[0];
+            assert!(
+                spacing > interval / 2 && spacing < interval * 3 / 2,
+                "{spacing:?}"
+            );
+        }
+
+        // A stream at the target rate loses nothing to its jitter
+        let mut limiter = FrameLimiter::default();
+        assert!(stream(60, 10, Duration::from_millis(4)).all(|t| limiter.admit(t, interval)));
+    }
+
+    #[test]
+    fn rate_changes_start_a_new_grid() {
+        let mut limiter = FrameLimiter::default();
+        let thirty = Duration::from_nanos(1_000_000_000 / 30);
+        let sixty = Duration::from_nanos(1_000_000_000 / 60);
+        let timestamps: Vec<CaptureTime> = stream(120, 2, Duration::ZERO).collect();
+        let (first, second) = timestamps.split_at(120);
+        // One more each for the half cells at either end, as above
+        assert_eq!(
+            first.iter().filter(|&&t| limiter.admit(t, thirty)).count(),
+            31
+        );
+        assert_eq!(
+            second.iter().filter(|&&t| limiter.admit(t, sixty)).count(),
+            61
+        );
+    }
+}
@@ -0,0 +1,340 @@
+use std::{collections::VecDeque, sync::Arc, time::Duration};
+
+use crossbeam::{
+    channel::{never, Receiver, Sender, TrySendError},
+    select,
+};
+
+use crate::{
+    types::{
+        audio_frame::EncodedAudioFrame, error::Result, media_packet::MediaPacket,
+        time::CaptureTime, video_frame::EncodedVideoFrame,
+    },
+    CaptureControls,
+};
+
+/// How far behind the newest packet a packet may be held back while waiting for the other
+/// stream. Past this it is sent out even if that stream has nothing queued.
+pub const REORDER_WINDOW: Duration = Duration::from_millis(200);
+
+/// Messages from the capture to the interleaving thread
+pub(crate) enum InterleaverControl {
+    /// Injected metadata, a cursor update or an event
+    Sparse(MediaPacket),
+    /// Release everything queued, then acknowledge
+    Flush(Sender<()>),
+}
+
+/// Merges the per-stream packet queues by timestamp.
+///
+/// Each encoder already outputs its packets in order, so a packet can be released as soon as
+/// every other live stream has a packet queued to compare it against. Metadata blobs, cursor
+/// updates and events are sparse and may arrive out of order, they are sorted into their queue
+/// and never hold back audio or video.
+#[derive(Default)]
+struct Interleaver {
+    video: VecDeque<EncodedVideoFrame>,
+    audio: VecDeque<EncodedAudioFrame>,
+    sparse: VecDeque<MediaPacket>,
+    video_open: bool,
+    audio_open: bool,
+    newest: CaptureTime,
+}
+
+impl Interleaver {
+    fn push_video(&mut self, frame: EncodedVideoFrame) {
+        self.newest = self.newest.max(frame.dts.into());
+        self.video.push_back(frame);
+    }
+
+    fn push_audio(&mut self, frame: EncodedAudioFrame) {
+        self.newest = self.newest.max(frame.timestamp);
+        self.audio.push_back(frame);
+    }
+
+    fn push_sparse(&mut self, packet: MediaPacket) {
+        let index = self
+            .sparse
+            .partition_point(|queued| queued.timestamp() <= packet.timestamp());
+        self.sparse.insert(index, packet);
+    }
+
+    fn pop_ready(&mut self) -> Option<MediaPacket> {
+        let next = self.next_av();
+        if let Some(sparse) = self.sparse.front() {
+            let ready = match next {
+                Some((_, timestamp)) => sparse.timestamp() <= timestamp,
+                None => (!self.video_open && !self.audio_open) || self.expired(sparse.timestamp()),
+            };
+            if ready {
+                return self.sparse.pop_front();
+            }
+        }
+
+        let (take_video, _) = next?;
+        if take_video {
+            self.video.pop_front().map(MediaPacket::Video)
+        } else {
+            self.audio.pop_front().map(MediaPacket::Audio)
+        }
+    }
+
+    /// Whether the next audio or video packet to release is a video one, and its timestamp
+    fn next_av(&self) -> Option<(bool, CaptureTime)> {
+        match (self.video.front(), self.audio.front()) {
+            (Some(video), Some(audio)) => {
+                let video = CaptureTime::from(video.dts);
+                Some(if video <= audio.timestamp {
+                    (true, video)
+                } else {
+                    (false, audio.timestamp)
+                })
+            }
+            (Some(video), None) if !self.audio_open || self.expired(video.dts.into()) => {
+                Some((true, video.dts.into()))
+            }
+            (None, Some(audio)) if !self.video_open || self.expired(audio.timestamp) => {
+                Some((false, audio.timestamp))
+            }
+            _ => None,
+        }
+    }
+
+    /// Everything queued, in timestamp order
+    fn flush(&mut self) -> Vec<MediaPacket> {
+        let open = (self.video_open, self.audio_open);
+        (self.video_open, self.audio_open) = (false, false);
+        let packets = std::iter::from_fn(|| self.pop_ready()).collect();
+        (self.video_open, self.audio_open) = open;
+        packets
+    }
+
+    fn expired(&self, timestamp: CaptureTime) -> bool {
+        self.newest - timestamp > REORDER_WINDOW
+    }
+
+    fn is_finished(&self) -> bool {
+        !self.video_open
+            && !self.audio_open
+            && self.video.is_empty()
+            && self.audio.is_empty()
+            && self.sparse.is_empty()
+    }
+}
+
+/// Send a packet on, false once the receiver is gone
+fn forward(output: &Sender<MediaPacket>, packet: MediaPacket) -> bool {
+    match output.try_send(packet) {
+        Ok(_) => true,
+        Err(TrySendError::Full(packet)) => {
+            log::error!(
+                "Could not send media packet at: {}. Receiver is full",
+                packet.timestamp()
+            );
+            true
+        }
+        Err(TrySendError::Disconnected(_)) => {
+            log::info!("Media packet receiver disconnected");
+            false
+        }
+    }
+}
+
+/// Forward the encoded video and audio packets, the injected metadata, the cursor updates and
+/// the events into a single channel ordered by timestamp.
+pub(crate) fn interleaving_loop(
+    video_recv: Receiver<EncodedVideoFrame>,
+    audio_recv: Option<Receiver<EncodedAudioFrame>>,
+    control_recv: Receiver<InterleaverControl>,
+    output: Sender<MediaPacket>,
+    controls: Arc<CaptureControls>,
+) -> std::thread::JoinHandle<Result<()>> {
+    std::thread::spawn(move || -> Result<()> {
+        let mut interleaver = Interleaver {
+            video_open: true,
+            audio_open: audio_recv.is_some(),
+            ..Default::default()
+        };
+        let mut video_recv = video_recv;
+        let mut audio_recv = audio_recv.unwrap_or_else(never);
+        let mut control_recv = control_recv;
+
+        while !controls.is_stopped() && !interleaver.is_finished() {
+            select! {
+                recv(video_recv) -> frame => match frame {
+                    Ok(frame) => interleaver.push_video(frame),
+                    Err(_) => interleaver.video_open = false,
+                },
+                recv(audio_recv) -> frame => match frame {
+                    Ok(frame) => interleaver.push_audio(frame),
+                    Err(_) => interleaver.audio_open = false,
+                },
+                recv(control_recv) -> control => match control {
+                    Ok(InterleaverControl::Sparse(packet)) => interleaver.push_sparse(packet),
+                    Ok(InterleaverControl::Flush(done)) => {
+                        // The drained packets were sent before the flush was requested
+                        video_recv.try_iter().for_each(|frame| interleaver.push_video(frame));
+                        audio_recv.try_iter().for_each(|frame| interleaver.push_audio(frame));
+                        for packet in interleaver.flush() {
+                            if !forward(&output, packet) {
+                                return Ok(());
+                            }
+                        }
+                        let _ = done.send(());
+                    }
+                    Err(_) => control_recv = never(),
+                },
+                default(Duration::from_millis(100)) => {
+                    // Timeout to check the stop flag periodically
+                }
+            }
+
+            // Disconnected channels are always ready, swap them out so select doesn't spin
+            if !interleaver.video_open {
+                video_recv = never();
+            }
+            if !interleaver.audio_open {
+                audio_recv = never();
+            }
+
+            while let Some(packet) = interleaver.pop_ready() {
+                if !forward(&output, packet) {
+                    return Ok(());
+                }
+            }
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{
+        event::CaptureEvent,
+        media_packet::{CursorUpdate, TimedEvent, TimedMetadata},
+        time::StreamPts,
+    };
+
+    fn video(ms: i64) -> EncodedVideoFrame {
+        let pts = CaptureTime::from_nanos(ms * 1_000_000).to_pts(CaptureTime::TIME_BASE);
+        EncodedVideoFrame {
+            data: Vec::new(),
+            is_keyframe: false,
+            pts,
+            dts: pts,
+        }
+    }
+
+    fn audio(ms: i64) -> EncodedAudioFrame {
+        let time_base = ffmpeg_next::Rational(1, 48_000);
+        EncodedAudioFrame {
+            data: Vec::new(),
+            pts: StreamPts::new(ms * 48, time_base),
+            duration: StreamPts::new(960, time_base),
+            timestamp: CaptureTime::from_nanos(ms * 1_000_000),
+        }
+    }
+
+    fn metadata(ms: i64) -> MediaPacket {
+        MediaPacket::Metadata(TimedMetadata {
+            timestamp: CaptureTime::from_nanos(ms * 1_000_000),
+            key: "input".to_string(),
+            data: vec![1, 2, 3],
+        })
+    }
+
+    fn open() -> Interleaver {
+        Interleaver {
+            video_open: true,
+            audio_open: true,
+            ..Default::default()
+        }
+    }
+
+    fn drain(interleaver: &mut Interleaver) -> Vec<(char, i64)> {
+        std::iter::from_fn(|| interleaver.pop_ready())
+            .map(|packet| {
+                let kind = match packet {
+                    MediaPacket::Video(_) => 'v',
+                    MediaPacket::Audio(_) => 'a',
+                    MediaPacket::Metadata(_) => 'm',
+                    MediaPacket::Cursor(_) => 'c',
+                    MediaPacket::Event(_) => 'e',
+                };
+                (kind, packet.timestamp().as_nanos() / 1_000_000)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn metadata_is_sorted_between_audio_and_video() {
+        let mut interleaver = open();
+        interleaver.push_sparse(metadata(25));
+        interleaver.push_sparse(metadata(5));
+        interleaver.push_video(video(0));
+        interleaver.push_video(video(16));
+        interleaver.push_audio(audio(10));
+        interleaver.push_audio(audio(30));
+
+        assert_eq!(
+            drain(&mut interleaver),
+            [('v', 0), ('m', 5), ('a', 10), ('v', 16)]
+        );
+        // Waits for the next video packet, the metadata behind it waits too
+        interleaver.push_video(video(33));
+        assert_eq!(drain(&mut interleaver), [('m', 25), ('a', 30)]);
+    }
+
+    #[test]
+    fn metadata_does_not_hold_back_audio_and_video() {
+        let mut interleaver = open();
+        interleaver.push_sparse(metadata(500));
+        interleaver.push_video(video(0));
+        interleaver.push_audio(audio(10));
+        assert_eq!(drain(&mut interleaver), [('v', 0)]);
+
+        interleaver.push_video(video(800));
+        assert_eq!(drain(&mut interleaver), [('a', 10), ('m', 500)]);
+    }
+
+    #[test]
+    fn flush_releases_everything_in_order() {
+        let mut interleaver = open();
+        interleaver.push_video(video(16));
+        interleaver.push_sparse(metadata(40));
+        interleaver.push_sparse(metadata(20));
+        assert!(drain(&mut interleaver).is_empty());
+
+        let flushed: Vec<_> = interleaver
+            .flush()
+            .iter()
+            .map(|packet| packet.timestamp().as_nanos() / 1_000_000)
+            .collect();
+        assert_eq!(flushed, [16, 20, 40]);
+        assert!(interleaver.video_open && interleaver.audio_open);
+    }
+
+    #[test]
+    fn cursor_updates_and_events_are_sorted_like_metadata() {
+        let mut interleaver = open();
+        interleaver.push_sparse(MediaPacket::Event(TimedEvent {
+            timestamp: CaptureTime::from_nanos(12_000_000),
+            event: CaptureEvent::ScreenRestored,
+        }));
+        interleaver.push_sparse(MediaPacket::Cursor(CursorUpdate {
+            timestamp: CaptureTime::from_nanos(8_000_000),
+            position: (100, 200),
+            hotspot: (0, 0),
+        }));
+        interleaver.push_sparse(metadata(10));
+        interleaver.push_video(video(0));
+        interleaver.push_video(video(16));
+        interleaver.push_audio(audio(20));
+
+        assert_eq!(
+            drain(&mut interleaver),
+            [('v', 0), ('c', 8), ('m', 10), ('e', 12), ('v', 16)]
+        );
+    }
+}
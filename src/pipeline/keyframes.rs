@@ -0,0 +1,138 @@
+//! Keyframe decisions shared by every encoder fed from one capture.
+//!
+//! Encoders counting their own GOPs drift apart as soon as one of them drops or delays a frame.
+//! With [`crate::types::config::VideoConfig::keyframe_interval`] set the capture decides instead:
+//! keyframes lie on a fixed grid starting at the first frame, and the frame crossing a grid point
+//! is flagged with [`crate::types::video_frame::RawVideoFrame::force_keyframe`], so every encoder
+//! handed that frame starts a GOP at the same pts. Keyframes requested through
+//! [`crate::CaptureControls::force_keyframe`] are flagged the same way, as are scene changes.
+
+use std::time::Duration;
+
+use crate::{
+    pipeline::scene_change::SceneDetector,
+    types::{time::CaptureTime, video_frame::RawVideoFrame},
+};
+
+#[derive(Debug, Default)]
+pub(crate) struct KeyframeScheduler {
+    interval: Option<Duration>,
+    origin: Option<CaptureTime>,
+    /// Grid point of the last keyframe
+    last_slot: Option<u128>,
+    /// A keyframe was requested for the next frame
+    requested: bool,
+    scenes: Option<SceneDetector>,
+}
+
+impl KeyframeScheduler {
+    /// Keyframes every `interval`, without one the encoders keep their own GOP
+    pub fn new(interval: Option<Duration>) -> Self {
+        Self {
+            interval,
+            ..Default::default()
+        }
+    }
+
+    /// Also make frames keyframes which differ from the previous one by more than `threshold`,
+    /// see [`crate::types::config::VideoConfig::scene_change_threshold`]
+    pub fn with_scene_changes(mut self, threshold: Option<f32>) -> Self {
+        self.scenes = threshold.map(SceneDetector::new);
+        self
+    }
+
+    /// Make the next frame a keyframe. Requests before it comes in make it only one.
+    pub fn request(&mut self) {
+        self.requested = true;
+    }
+
+    /// Whether a keyframe was requested for the next frame
+    pub fn is_requested(&self) -> bool {
+        self.requested
+    }
+
+    /// Whether the frame captured at `timestamp` has to be a keyframe
+    pub fn is_keyframe(&mut self, timestamp: CaptureTime) -> bool {
+        let scheduled = self.is_scheduled(timestamp);
+        std::mem::take(&mut self.requested) || scheduled
+    }
+
+    /// Whether `frame` starts a new scene. Every frame has to be passed, it is compared to the
+    /// one before.
+    pub fn is_scene_change(&mut self, frame: &RawVideoFrame) -> bool {
+        self.scenes
+            .as_mut()
+            .is_some_and(|scenes| scenes.is_scene_change(frame))
+    }
+
+    fn is_scheduled(&mut self, timestamp: CaptureTime) -> bool {
+        let Some(interval) = self.interval else {
+            return false;
+        };
+        let origin = *self.origin.get_or_insert(timestamp);
+        let slot = (timestamp - origin).as_nanos() / interval.as_nanos().max(1);
+        if self.last_slot.is_some_and(|last| slot <= last) {
+            return false;
+        }
+        self.last_slot = Some(slot);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Records the pts of the frames it was told to make keyframes
+    #[derive(Default)]
+    struct MockEncoder {
+        keyframes: Vec<CaptureTime>,
+    }
+
+    impl MockEncoder {
+        fn encode(&mut self, timestamp: CaptureTime, force_keyframe: bool) {
+            if force_keyframe {
+                self.keyframes.push(timestamp);
+            }
+        }
+    }
+
+    #[test]
+    fn encoders_share_keyframe_pts() {
+        let mut scheduler = KeyframeScheduler::new(Some(Duration::from_secs(2)));
+        let (mut file, mut stream) = (MockEncoder::default(), MockEncoder::default());
+        let start = CaptureTime::from_nanos(5_000_000_000);
+
+        // A minute of 60fps with a few ms of jitter, and a frame missing now and then
+        for frame in 0..3600u64 {
+            if frame % 97 == 13 {
+                continue;
+            }
+            let jitter = Duration::from_micros(frame * 7919 % 3000);
+            let timestamp = start + Duration::from_nanos(frame * 1_000_000_000 / 60) + jitter;
+            let force_keyframe = scheduler.is_keyframe(timestamp);
+            file.encode(timestamp, force_keyframe);
+            stream.encode(timestamp, force_keyframe);
+        }
+
+        assert_eq!(file.keyframes, stream.keyframes);
+        assert_eq!(file.keyframes.len(), 30);
+        for (slot, keyframe) in file.keyframes.iter().enumerate() {
+            let since_start = *keyframe - start;
+            assert_eq!(since_start.as_secs() / 2, slot as u64, "{since_start:?}");
+        }
+    }
+
+    #[test]
+    fn requests_coalesce_into_the_next_frame() {
+        let mut scheduler = KeyframeScheduler::new(None);
+        let start = CaptureTime::from_nanos(0);
+        assert!(!scheduler.is_keyframe(start));
+
+        scheduler.request();
+        scheduler.request();
+        scheduler.request();
+        assert!(scheduler.is_keyframe(start + Duration::from_millis(16)));
+        assert!(!scheduler.is_keyframe(start + Duration::from_millis(33)));
+    }
+}
@@ -0,0 +1,104 @@
+//! Cross-check of the video encoder's measured latency against the delay its configuration
+//! implies, see [`crate::types::event::CaptureEvent::EncoderLatencyMismatch`].
+
+use std::time::Duration;
+
+use crate::types::{encoder_info::EncoderDelay, event::CaptureEvent, time::CaptureTime};
+
+/// How often the latency is compared, in capture time
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// Latency on top of twice the expected delay which still counts as normal, covering the time
+/// to encode a frame and scheduling hiccups
+const TOLERANCE: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Default)]
+pub(crate) struct LatencyCheck {
+    last_check: Option<CaptureTime>,
+    frames: u32,
+    reported: bool,
+}
+
+impl LatencyCheck {
+    /// Count a frame submitted to the encoder. Returns the average interval of the frames since
+    /// the last check when the next one is due, an encoder holding back frames waits that long
+    /// for each of them.
+    pub fn frame(&mut self, now: CaptureTime) -> Option<Duration> {
+        self.frames += 1;
+        let Some(last_check) = self.last_check else {
+            self.last_check = Some(now);
+            self.frames = 0;
+            return None;
+        };
+        let elapsed = now - last_check;
+        if elapsed < CHECK_INTERVAL {
+            return None;
+        }
+        let interval = elapsed / self.frames;
+        self.last_check = Some(now);
+        self.frames = 0;
+        Some(interval)
+    }
+
+    /// The event to report the first time `measured` exceeds `expected` by a wide margin
+    pub fn compare(
+        &mut self,
+        expected: EncoderDelay,
+        measured: Option<Duration>,
+    ) -> Option<CaptureEvent> {
+        let measured = measured?;
+        if measured <= expected.time * 2 + TOLERANCE {
+            if self.reported {
+                log::info!("Video encoder latency is back to {measured:?}");
+                self.reported = false;
+            }
+            return None;
+        }
+        if self.reported {
+            return None;
+        }
+        self.reported = true;
+        log::warn!(
+            "Video encoder latency is {measured:?}, its configuration explains {:?}",
+            expected.time
+        );
+        Some(CaptureEvent::EncoderLatencyMismatch { expected, measured })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mismatch_is_reported_once() {
+        let mut check = LatencyCheck::default();
+        let start = CaptureTime::from_nanos(1_000_000_000);
+        let intervals: Vec<Duration> = (0..=180u64)
+            .filter_map(|frame| check.frame(start + Duration::from_millis(frame * 50 / 3)))
+            .collect();
+        // 60fps for three seconds
+        assert_eq!(intervals, vec![Duration::from_secs(1) / 60; 3]);
+
+        // Two B-frames and an async depth of 2 at 60fps
+        let expected = EncoderDelay {
+            frames: 3,
+            time: Duration::from_millis(50),
+        };
+        assert!(check.compare(expected, None).is_none());
+        assert!(check
+            .compare(expected, Some(Duration::from_millis(70)))
+            .is_none());
+        let stalled = Some(Duration::from_millis(900));
+        let Some(CaptureEvent::EncoderLatencyMismatch { measured, .. }) =
+            check.compare(expected, stalled)
+        else {
+            panic!("a stalled encoder should be reported");
+        };
+        assert_eq!(Some(measured), stalled);
+        assert!(check.compare(expected, stalled).is_none());
+        assert!(check
+            .compare(expected, Some(Duration::from_millis(60)))
+            .is_none());
+        assert!(check.compare(expected, stalled).is_some());
+    }
+}
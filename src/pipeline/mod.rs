@@ -1 +1,15 @@
 pub mod builder;
+pub(crate) mod cfr;
+pub(crate) mod dedup;
+pub(crate) mod external_copy;
+pub(crate) mod fanout;
+pub(crate) mod fence;
+pub(crate) mod frame_limiter;
+pub(crate) mod interleaver;
+pub(crate) mod keyframes;
+pub(crate) mod latency;
+pub(crate) mod overflow;
+pub(crate) mod preview;
+pub(crate) mod scene_change;
+pub(crate) mod shutdown;
+pub(crate) mod watchdog;
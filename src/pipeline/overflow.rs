@@ -0,0 +1,120 @@
+use crossbeam::channel::{Receiver, SendTimeoutError, Sender, TrySendError};
+
+use crate::types::config::OverflowPolicy;
+
+/// Result of [`send`]
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum SendOutcome<T> {
+    Sent,
+    /// The channel was full, the item was sent after evicting this older one
+    Replaced(T),
+    /// The channel was full and the item was not sent
+    Dropped(T),
+    Disconnected,
+}
+
+/// Hand `item` to an output channel, applying `policy` when it is full.
+///
+/// `receiver` must belong to the same channel, it is used to evict the oldest item.
+pub(crate) fn send<T>(
+    policy: OverflowPolicy,
+    sender: &Sender<T>,
+    receiver: &Receiver<T>,
+    item: T,
+) -> SendOutcome<T> {
+    let result = match policy {
+        OverflowPolicy::DropNewest => sender.try_send(item),
+        OverflowPolicy::DropOldest => match sender.try_send(item) {
+            Err(TrySendError::Full(item)) => {
+                let Ok(oldest) = receiver.try_recv() else {
+                    // The consumer emptied the channel in the meantime
+                    return match sender.try_send(item) {
+                        Ok(()) => SendOutcome::Sent,
+                        Err(TrySendError::Full(item)) => SendOutcome::Dropped(item),
+                        Err(TrySendError::Disconnected(_)) => SendOutcome::Disconnected,
+                    };
+                };
+                return match sender.try_send(item) {
+                    Ok(()) => SendOutcome::Replaced(oldest),
+                    Err(TrySendError::Full(item)) => SendOutcome::Dropped(item),
+                    Err(TrySendError::Disconnected(_)) => SendOutcome::Disconnected,
+                };
+            }
+            result => result,
+        },
+        OverflowPolicy::Block { timeout } => match sender.send_timeout(item, timeout) {
+            Ok(()) => Ok(()),
+            Err(SendTimeoutError::Timeout(item)) => Err(TrySendError::Full(item)),
+            Err(SendTimeoutError::Disconnected(item)) => Err(TrySendError::Disconnected(item)),
+        },
+    };
+
+    match result {
+        Ok(()) => SendOutcome::Sent,
+        Err(TrySendError::Full(item)) => SendOutcome::Dropped(item),
+        Err(TrySendError::Disconnected(_)) => SendOutcome::Disconnected,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crossbeam::channel::bounded;
+
+    use super::*;
+
+    #[test]
+    fn drop_newest_keeps_queue() {
+        let (tx, rx) = bounded(2);
+        for i in 0..2 {
+            assert_eq!(
+                send(OverflowPolicy::DropNewest, &tx, &rx, i),
+                SendOutcome::Sent
+            );
+        }
+        assert_eq!(
+            send(OverflowPolicy::DropNewest, &tx, &rx, 2),
+            SendOutcome::Dropped(2)
+        );
+        assert_eq!(rx.try_iter().collect::<Vec<_>>(), [0, 1]);
+    }
+
+    #[test]
+    fn drop_oldest_makes_room() {
+        let (tx, rx) = bounded(2);
+        for i in 0..2 {
+            assert_eq!(
+                send(OverflowPolicy::DropOldest, &tx, &rx, i),
+                SendOutcome::Sent
+            );
+        }
+        assert_eq!(
+            send(OverflowPolicy::DropOldest, &tx, &rx, 2),
+            SendOutcome::Replaced(0)
+        );
+        assert_eq!(rx.try_iter().collect::<Vec<_>>(), [1, 2]);
+    }
+
+    #[test]
+    fn block_waits_for_consumer() {
+        let policy = OverflowPolicy::Block {
+            timeout: Duration::from_millis(500),
+        };
+        let (tx, rx) = bounded(1);
+        assert_eq!(send(policy, &tx, &rx, 0), SendOutcome::Sent);
+
+        let consumer = rx.clone();
+        let handle = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            consumer.recv().unwrap()
+        });
+        assert_eq!(send(policy, &tx, &rx, 1), SendOutcome::Sent);
+        assert_eq!(handle.join().unwrap(), 0);
+
+        let policy = OverflowPolicy::Block {
+            timeout: Duration::from_millis(10),
+        };
+        assert_eq!(send(policy, &tx, &rx, 2), SendOutcome::Dropped(2));
+    }
+}
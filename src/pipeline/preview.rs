@@ -0,0 +1,197 @@
+//! Downscaled RGBA copies of the frames handed to the video encoder, see
+//! [`crate::Capture::preview_frames`].
+//!
+//! The encoding thread only rate limits the frames and hands them to a preview thread, which
+//! scales shared memory frames with swscale and DMA-BUFs on the GPU through its own EGL context.
+//! Frames are dropped whenever the preview thread or the receiver is still busy, so a slow GUI
+//! never holds up encoding.
+
+use std::{
+    os::fd::{AsRawFd, BorrowedFd, OwnedFd},
+    sync::Arc,
+    time::Duration,
+};
+
+use crossbeam::channel::{bounded, Receiver, Sender};
+use pipewire::spa::param::video::VideoFormat;
+
+use crate::{
+    encoders::rgba_image_encoder::RgbaScaler,
+    pipeline::{external_copy::drm_fourcc, frame_limiter::FrameLimiter},
+    types::{
+        error::{Result, WaycapError},
+        preview::{PreviewConfig, PreviewFrame},
+        stats::StatsCounters,
+        time::CaptureTime,
+        video_frame::{DmaBufPlane, RawVideoFrame},
+    },
+    waycap_egl::EglContext,
+    CaptureControls,
+};
+
+/// Entry point of the encoding thread into the preview thread
+pub(crate) struct PreviewTap {
+    interval: Duration,
+    limiter: FrameLimiter,
+    jobs: Sender<PreviewJob>,
+}
+
+struct PreviewJob {
+    pixels: Pixels,
+    format: VideoFormat,
+    offset: u32,
+    stride: u32,
+    width: u32,
+    height: u32,
+    timestamp: CaptureTime,
+}
+
+enum Pixels {
+    /// Copy of a shared memory frame, PipeWire reuses the buffer once the frame is encoded
+    Memory(Vec<u8>),
+    DmaBuf {
+        fd: OwnedFd,
+        modifier: u64,
+    },
+}
+
+/// Start the preview thread
+pub(crate) fn start(
+    config: PreviewConfig,
+    stats: Arc<StatsCounters>,
+) -> Result<(PreviewTap, Receiver<PreviewFrame>)> {
+    config.validate()?;
+    // One frame in flight, the next one waits for the preview thread to be done with it
+    let (jobs_tx, jobs_rx) = bounded(1);
+    let (frames_tx, frames_rx) = bounded(config.capacity);
+    std::thread::spawn(move || preview_loop(config, jobs_rx, frames_tx, stats));
+    let tap = PreviewTap {
+        interval: Duration::from_secs(1) / config.max_fps,
+        limiter: FrameLimiter::default(),
+        jobs: jobs_tx,
+    };
+    Ok((tap, frames_rx))
+}
+
+/// Hand `frame` to the preview thread, if a preview was requested, the frame is due and the
+/// thread is idle. Never blocks.
+pub(crate) fn offer(controls: &CaptureControls, stats: &StatsCounters, frame: &RawVideoFrame) {
+    let mut tap = controls.preview_tap().lock().unwrap();
+    let Some(tap) = tap.as_mut() else {
+        return;
+    };
+    // Like the external copies, only 8 bit RGB is previewed
+    if drm_fourcc(frame.format).is_none() {
+        return;
+    }
+    if !tap.limiter.admit(frame.timestamp, tap.interval) {
+        return;
+    }
+    let pixels = if !frame.data.is_empty() {
+        Pixels::Memory(frame.data.clone())
+    } else if let Some(fd) = frame.dmabuf_fd {
+        // The preview thread may still read the buffer after PipeWire got it back
+        match unsafe { BorrowedFd::borrow_raw(fd) }.try_clone_to_owned() {
+            Ok(fd) => Pixels::DmaBuf {
+                fd,
+                modifier: frame.modifier,
+            },
+            Err(e) => {
+                log::warn!("Could not duplicate the frame's DMA-BUF for the preview: {e}");
+                return;
+            }
+        }
+    } else {
+        return;
+    };
+    let job = PreviewJob {
+        pixels,
+        format: frame.format,
+        offset: frame.offset,
+        stride: frame.stride as u32,
+        width: frame.dimensions.width,
+        height: frame.dimensions.height,
+        timestamp: frame.timestamp,
+    };
+    if tap.jobs.try_send(job).is_err() {
+        stats.mark_preview_dropped();
+    }
+}
+
+fn preview_loop(
+    config: PreviewConfig,
+    jobs: Receiver<PreviewJob>,
+    frames: Sender<PreviewFrame>,
+    stats: Arc<StatsCounters>,
+) {
+    let mut scaler = RgbaScaler::default();
+    // Created with the first DMA-BUF, shared memory streams never need it
+    let mut egl: Option<Result<EglContext>> = None;
+
+    for job in jobs {
+        let size = config.scaled_size(job.width, job.height);
+        let image = match job.pixels {
+            Pixels::Memory(ref data) => scaler.scale(
+                data.get(job.offset as usize..).unwrap_or_default(),
+                job.stride as usize,
+                job.format,
+                (job.width, job.height),
+                size,
+            ),
+            Pixels::DmaBuf { ref fd, modifier } => {
+                let egl = egl.get_or_insert_with(|| {
+                    EglContext::new(1, 1).inspect_err(|e| {
+                        log::error!("Could not create the EGL context for preview frames: {e}");
+                    })
+                });
+                match egl {
+                    Ok(egl) => read_dmabuf(egl, &job, fd, modifier, size),
+                    // Logged once above
+                    Err(_) => continue,
+                }
+            }
+        };
+        let image = match image {
+            Ok(image) => image,
+            Err(e) => {
+                log::warn!("Could not scale a preview frame: {e}");
+                continue;
+            }
+        };
+        let frame = PreviewFrame {
+            image,
+            timestamp: job.timestamp,
+        };
+        if frames.try_send(frame).is_ok() {
+            stats.mark_preview_sent();
+        } else {
+            stats.mark_preview_dropped();
+        }
+    }
+}
+
+/// Scale the DMA-BUF of `job` to `size` on the GPU
+fn read_dmabuf(
+    egl: &EglContext,
+    job: &PreviewJob,
+    fd: &OwnedFd,
+    modifier: u64,
+    (width, height): (u32, u32),
+) -> Result<image::RgbaImage> {
+    let fourcc = drm_fourcc(job.format)
+        .ok_or_else(|| WaycapError::Encoding(format!("Can't preview {:?} DMA-BUFs", job.format)))?;
+    let source = egl.create_image_from_dmabuf(
+        &[DmaBufPlane {
+            fd: fd.as_raw_fd(),
+            offset: job.offset,
+            stride: job.stride,
+        }],
+        fourcc as u32,
+        job.width,
+        job.height,
+        modifier,
+    )?;
+    let pixels = egl.read_scaled(source, (job.width, job.height), (width, height));
+    egl.destroy_image(source)?;
+    Ok(image::RgbaImage::from_raw(width, height, pixels?).expect("read back at the scaled size"))
+}
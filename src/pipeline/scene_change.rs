@@ -0,0 +1,164 @@
+//! Keyframes at scene changes, see [`crate::types::config::VideoConfig::scene_change_threshold`].
+//!
+//! Window switches and slide transitions replace most of the picture at once, the encoder spends
+//! about as many bits on that frame as on a keyframe anyway. Making it one lets seeks land right
+//! on the new scene. Frames are compared by the luma histogram of a sparse grid of pixels, a few
+//! thousand reads per frame whatever its size, so it stays well below a millisecond at 1440p.
+
+use pipewire::spa::param::video::VideoFormat;
+
+use crate::{types::video_frame::RawVideoFrame, utils::DmaBufMapping};
+
+const GRID_COLUMNS: usize = 64;
+const GRID_ROWS: usize = 36;
+const SAMPLES: usize = GRID_COLUMNS * GRID_ROWS;
+const BINS: usize = 32;
+
+type Histogram = [u32; BINS];
+
+#[derive(Debug)]
+pub(crate) struct SceneDetector {
+    threshold: f32,
+    previous: Option<Histogram>,
+}
+
+impl SceneDetector {
+    pub fn new(threshold: f32) -> Self {
+        Self {
+            threshold,
+            previous: None,
+        }
+    }
+
+    /// Whether `frame` differs from the previous frame by more than the threshold. Frames which
+    /// can't be read on the CPU, anything but 8 bit RGB in shared memory or linear DMA-BUFs,
+    /// never are, and the next frame is not compared to them either.
+    pub fn is_scene_change(&mut self, frame: &RawVideoFrame) -> bool {
+        let histogram = luma_histogram(frame);
+        let changed = match (self.previous, histogram) {
+            (Some(previous), Some(histogram)) => difference(&previous, &histogram) > self.threshold,
+            _ => false,
+        };
+        self.previous = histogram;
+        changed
+    }
+}
+
+/// Share of the samples which landed in another bin, from 0 for the same histogram to 1 when
+/// none overlap
+fn difference(previous: &Histogram, current: &Histogram) -> f32 {
+    let moved: u32 = previous
+        .iter()
+        .zip(current)
+        .map(|(previous, current)| previous.abs_diff(*current))
+        .sum();
+    moved as f32 / (2 * SAMPLES) as f32
+}
+
+fn luma_histogram(frame: &RawVideoFrame) -> Option<Histogram> {
+    // Byte offsets of red, green and blue in a pixel
+    let (red, green, blue) = match frame.format {
+        VideoFormat::BGRx | VideoFormat::BGRA => (2, 1, 0),
+        VideoFormat::RGBx | VideoFormat::RGBA => (0, 1, 2),
+        VideoFormat::xRGB | VideoFormat::ARGB => (1, 2, 3),
+        VideoFormat::xBGR | VideoFormat::ABGR => (3, 2, 1),
+        _ => return None,
+    };
+    let (width, height) = (
+        frame.dimensions.width as usize,
+        frame.dimensions.height as usize,
+    );
+    let (offset, stride) = (frame.offset as usize, usize::try_from(frame.stride).ok()?);
+    if width == 0 || height == 0 || stride < width * 4 {
+        return None;
+    }
+    let needed = offset + stride * (height - 1) + width * 4;
+
+    let mapping;
+    let bytes = match frame.dmabuf_fd {
+        _ if !frame.data.is_empty() => frame.data.as_slice(),
+        Some(fd) => {
+            mapping = DmaBufMapping::new(fd, frame.modifier, needed).ok()?;
+            mapping.bytes()
+        }
+        None => return None,
+    };
+    let bytes = bytes.get(offset..needed)?;
+
+    let mut histogram = [0; BINS];
+    for row in 0..GRID_ROWS {
+        let y = (2 * row + 1) * height / (2 * GRID_ROWS);
+        for column in 0..GRID_COLUMNS {
+            let x = (2 * column + 1) * width / (2 * GRID_COLUMNS);
+            let pixel = &bytes[y * stride + x * 4..][..4];
+            // BT.709 weights in 8 bit fixed point
+            let luma = (54 * u32::from(pixel[red])
+                + 183 * u32::from(pixel[green])
+                + 19 * u32::from(pixel[blue]))
+                >> 8;
+            histogram[luma as usize * BINS / 256] += 1;
+        }
+    }
+    Some(histogram)
+}
+
+#[cfg(test)]
+mod tests {
+    use pipewire::spa::utils::Rectangle;
+
+    use super::*;
+
+    const WIDTH: u32 = 320;
+    const HEIGHT: u32 = 180;
+
+    /// A BGRx frame in shared memory, dark on the left and `right` on the right
+    fn frame(right: u8) -> RawVideoFrame {
+        let row: Vec<u8> = (0..WIDTH)
+            .flat_map(|x| {
+                let value = if x < WIDTH / 2 { 20 } else { right };
+                [value, value, value, 0]
+            })
+            .collect();
+        RawVideoFrame {
+            data: row.repeat(HEIGHT as usize),
+            ..RawVideoFrame::bgrx(WIDTH, HEIGHT)
+        }
+    }
+
+    #[test]
+    fn flags_frames_which_replace_the_picture() {
+        let mut detector = SceneDetector::new(0.3);
+        // The first frame has nothing to be compared to
+        assert!(!detector.is_scene_change(&frame(30)));
+        assert!(!detector.is_scene_change(&frame(30)));
+        // A slightly darker right half stays in the same bins
+        assert!(!detector.is_scene_change(&frame(24)));
+        // Half the picture turns white
+        assert!(detector.is_scene_change(&frame(250)));
+        assert!(!detector.is_scene_change(&frame(250)));
+
+        // Unreadable frames are skipped without counting as a change
+        let mut nv12 = frame(30);
+        nv12.format = VideoFormat::NV12;
+        assert!(!detector.is_scene_change(&nv12));
+        assert!(!detector.is_scene_change(&frame(30)));
+    }
+
+    #[test]
+    fn histograms_are_cheap_at_1440p() {
+        let mut frame = frame(30);
+        frame.dimensions = Rectangle {
+            width: 2560,
+            height: 1440,
+        };
+        frame.stride = 2560 * 4;
+        frame.data = vec![128; 2560 * 1440 * 4];
+        let mut detector = SceneDetector::new(0.3);
+        let start = std::time::Instant::now();
+        for _ in 0..100 {
+            detector.is_scene_change(&frame);
+        }
+        // Generous for debug builds on loaded CI machines, release builds take a few µs
+        assert!(start.elapsed() < std::time::Duration::from_millis(100));
+    }
+}
@@ -0,0 +1,190 @@
+use std::{
+    sync::atomic::{AtomicBool, AtomicI64, Ordering},
+    time::{Duration, Instant},
+};
+
+use crate::types::time::CaptureTime;
+
+/// How long [`crate::Capture::finish`] waits for the encoders to catch up with the cutoff
+pub(crate) const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Stream a frame belongs to
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum StreamKind {
+    Video = 0,
+    Audio = 1,
+}
+
+/// Ends video and audio at the same capture time.
+///
+/// Once a cutoff is set the capture callbacks stop queueing buffers captured after it, while the
+/// frames already queued keep being encoded. [`Self::wait_idle`] returns when both streams
+/// handled everything up to the cutoff, after which the encoders can be drained.
+#[derive(Debug)]
+pub(crate) struct StreamCutoff {
+    cutoff_ns: AtomicI64,
+    pending: [AtomicI64; 2],
+    /// Streams with an encoding thread reporting handled frames, raw streams handed straight
+    /// to the user are never waited for
+    tracked: [AtomicBool; 2],
+}
+
+impl Default for StreamCutoff {
+    fn default() -> Self {
+        Self {
+            cutoff_ns: AtomicI64::new(i64::MAX),
+            pending: [AtomicI64::new(0), AtomicI64::new(0)],
+            tracked: [AtomicBool::new(false), AtomicBool::new(false)],
+        }
+    }
+}
+
+impl StreamCutoff {
+    pub fn set(&self, cutoff: CaptureTime) {
+        self.cutoff_ns.store(cutoff.as_nanos(), Ordering::Release);
+    }
+
+    pub fn clear(&self) {
+        self.cutoff_ns.store(i64::MAX, Ordering::Release);
+    }
+
+    /// Whether a frame captured at `timestamp` is still part of the recording
+    pub fn accepts(&self, timestamp: CaptureTime) -> bool {
+        timestamp.as_nanos() <= self.cutoff_ns.load(Ordering::Acquire)
+    }
+
+    /// Called by the encoding thread of `stream` before it starts receiving frames
+    pub fn track(&self, stream: StreamKind) {
+        self.tracked[stream as usize].store(true, Ordering::Release);
+    }
+
+    /// Call before queueing a frame for the encoder, and [`Self::frame_done`] if it couldn't be
+    /// queued after all
+    pub fn frame_queued(&self, stream: StreamKind) {
+        self.pending[stream as usize].fetch_add(1, Ordering::AcqRel);
+    }
+
+    /// Call once a queued frame was encoded or dropped
+    pub fn frame_done(&self, stream: StreamKind) {
+        self.pending[stream as usize].fetch_sub(1, Ordering::AcqRel);
+    }
+
+    /// Frames of `stream` queued for or being handled by the encoder
+    pub fn pending(&self, stream: StreamKind) -> u64 {
+        self.pending[stream as usize].load(Ordering::Acquire).max(0) as u64
+    }
+
+    /// Wait until every queued frame was handled. Returns false on timeout, e.g. when an
+    /// encoding thread died.
+    pub fn wait_idle(&self, timeout: Duration) -> bool {
+        let start = Instant::now();
+        while self
+            .pending
+            .iter()
+            .zip(&self.tracked)
+            .any(|(pending, tracked)| {
+                tracked.load(Ordering::Acquire) && pending.load(Ordering::Acquire) > 0
+            })
+        {
+            if start.elapsed() >= timeout {
+                return false;
+            }
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        true
+    }
+}
+
+/// Current time on the clock PipeWire stamps buffers with (`pw_stream_get_nsec`)
+pub(crate) fn capture_clock_ns() -> i64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    ts.tv_sec * 1_000_000_000 + ts.tv_nsec
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{atomic::AtomicBool, Arc};
+
+    use crossbeam::channel::bounded;
+
+    use super::*;
+
+    /// One Opus frame at 48kHz
+    const AUDIO_FRAME: Duration = Duration::from_millis(20);
+
+    /// Runs a synthetic source and encoder for one stream, returns the timestamp of the last
+    /// frame which made it through the encoder
+    fn spawn_stream(
+        cutoff: Arc<StreamCutoff>,
+        stream: StreamKind,
+        period: Duration,
+        encode_time: Duration,
+        stop: Arc<AtomicBool>,
+    ) -> std::thread::JoinHandle<CaptureTime> {
+        let (tx, rx) = bounded::<CaptureTime>(10);
+        let source_cutoff = Arc::clone(&cutoff);
+        cutoff.track(stream);
+        std::thread::spawn(move || {
+            while !stop.load(Ordering::Acquire) {
+                std::thread::sleep(period);
+                let timestamp = CaptureTime::from_nanos(capture_clock_ns());
+                if !source_cutoff.accepts(timestamp) {
+                    continue;
+                }
+                source_cutoff.frame_queued(stream);
+                if tx.try_send(timestamp).is_err() {
+                    source_cutoff.frame_done(stream);
+                }
+            }
+        });
+
+        std::thread::spawn(move || {
+            let mut last = CaptureTime::default();
+            while let Ok(timestamp) = rx.recv() {
+                std::thread::sleep(encode_time);
+                last = timestamp;
+                cutoff.frame_done(stream);
+            }
+            last
+        })
+    }
+
+    #[test]
+    fn streams_end_at_the_same_capture_time() {
+        let cutoff = Arc::new(StreamCutoff::default());
+        let stop = Arc::new(AtomicBool::new(false));
+        let video = spawn_stream(
+            Arc::clone(&cutoff),
+            StreamKind::Video,
+            Duration::from_millis(16),
+            Duration::from_millis(12),
+            Arc::clone(&stop),
+        );
+        let audio = spawn_stream(
+            Arc::clone(&cutoff),
+            StreamKind::Audio,
+            Duration::from_millis(10),
+            Duration::from_millis(1),
+            Arc::clone(&stop),
+        );
+
+        std::thread::sleep(Duration::from_millis(500));
+        let cutoff_time = CaptureTime::from_nanos(capture_clock_ns());
+        cutoff.set(cutoff_time);
+        assert!(cutoff.wait_idle(Duration::from_secs(1)));
+
+        // Keep the sources running past the cutoff to check nothing else gets through
+        std::thread::sleep(Duration::from_millis(100));
+        stop.store(true, Ordering::Release);
+        let last_video = video.join().unwrap();
+        let last_audio = audio.join().unwrap();
+
+        assert!(last_video <= cutoff_time);
+        assert!(last_audio <= cutoff_time);
+        assert!(last_video.abs_diff(last_audio) < AUDIO_FRAME);
+    }
+}
@@ -0,0 +1,230 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use crossbeam::channel::Receiver;
+
+use crate::{
+    encoders::video::VideoEncoder,
+    pipeline::fanout::FanOut,
+    types::{
+        config::WatchdogConfig,
+        error::Result,
+        event::{CaptureEvent, EventSender, PipelineStage},
+        stats::{CaptureStats, StatsCounters},
+        video_frame::{EncodedVideoFrame, RawVideoFrame},
+    },
+    CaptureControls,
+};
+
+/// Progress of one stage relative to the stage feeding it
+struct StageProgress {
+    stage: PipelineStage,
+    input: u64,
+    output: u64,
+    last_progress: Instant,
+    reported: bool,
+}
+
+impl StageProgress {
+    fn new(stage: PipelineStage) -> Self {
+        Self {
+            stage,
+            input: 0,
+            output: 0,
+            last_progress: Instant::now(),
+            reported: false,
+        }
+    }
+
+    /// Returns true the first time the stage is found stalled
+    fn update(&mut self, input: u64, output: u64, config: &WatchdogConfig) -> bool {
+        let input_moved = input != self.input;
+        let output_moved = output != self.output;
+        self.input = input;
+        self.output = output;
+
+        if output_moved || !input_moved {
+            // Either fine or idle because nothing is coming in
+            if output_moved {
+                self.reported = false;
+            }
+            self.last_progress = Instant::now();
+            return false;
+        }
+
+        if self.reported || self.last_progress.elapsed() < config.stall_timeout {
+            return false;
+        }
+        self.reported = true;
+        true
+    }
+}
+
+fn stage_counters(stage: PipelineStage, stats: &CaptureStats) -> (u64, u64) {
+    match stage {
+        // Skipped corrupted buffers and frames the callback dropped are expected not to come out
+        // the other end. The snapshot may count a buffer as corrupted before it counts it as
+        // captured.
+        PipelineStage::Capture => (
+            stats
+                .frames_captured
+                .saturating_sub(stats.frames_corrupted + stats.frames_dropped_at_capture),
+            stats.frames_queued,
+        ),
+        // Frames the processing thread skips are handled too, a timelapse keeps only one frame
+        // per capture interval
+        PipelineStage::EncoderInput => (
+            stats.frames_queued,
+            stats.frames_encoded
+                + stats.frames_rate_limited
+                + stats.frames_deduplicated
+                + stats.frames_fence_timed_out,
+        ),
+        // Frames the encoder dropped without encoding them never get a packet
+        PipelineStage::EncoderOutput | PipelineStage::Consumer => (
+            stats
+                .frames_encoded
+                .saturating_sub(stats.frames_dropped_by_encoder),
+            stats.packets_emitted,
+        ),
+    }
+}
+
+/// Watch the progress counters of the video pipeline and report stages which stall.
+pub(crate) fn watchdog_loop<V: VideoEncoder>(
+    config: WatchdogConfig,
+    stats: Arc<StatsCounters>,
+    raw_queue: Receiver<RawVideoFrame>,
+    encoded_queue: FanOut<EncodedVideoFrame>,
+    video_encoder: Arc<Mutex<V>>,
+    events: EventSender,
+    controls: Arc<CaptureControls>,
+) -> std::thread::JoinHandle<Result<()>> {
+    std::thread::spawn(move || -> Result<()> {
+        let new_stages = || {
+            [
+                PipelineStage::Capture,
+                PipelineStage::EncoderInput,
+                PipelineStage::EncoderOutput,
+            ]
+            .map(StageProgress::new)
+        };
+        let mut stages = new_stages();
+
+        while !controls.is_stopped() {
+            std::thread::sleep(config.interval);
+
+            if controls.is_paused() {
+                // Nothing is expected to move while paused
+                stages = new_stages();
+                continue;
+            }
+
+            let snapshot = stats.snapshot();
+            for progress in stages.iter_mut() {
+                let (input, output) = stage_counters(progress.stage, &snapshot);
+                if !progress.update(input, output, &config) {
+                    continue;
+                }
+
+                // A stage can't hand anything on while the queue after it is full, in that case
+                // the next stage is the one holding things up
+                let stage = match progress.stage {
+                    PipelineStage::Capture if raw_queue.is_full() => PipelineStage::EncoderInput,
+                    PipelineStage::EncoderOutput if encoded_queue.is_full() => {
+                        PipelineStage::Consumer
+                    }
+                    stage => stage,
+                };
+
+                log::warn!("Video pipeline stalled at {stage:?}");
+                events.send(CaptureEvent::PipelineStalled {
+                    stage,
+                    raw_queue_depth: raw_queue.len(),
+                    encoded_queue_depth: encoded_queue.len(),
+                    last_errors: stats.last_errors(),
+                });
+
+                if config.auto_recover && stage == PipelineStage::EncoderOutput {
+                    // The encoding thread may be stuck holding the lock, don't get stuck with it
+                    match video_encoder.try_lock() {
+                        Ok(mut encoder) => match encoder.reset() {
+                            Ok(_) => log::info!("Reset stalled video encoder"),
+                            Err(e) => log::error!("Could not reset stalled video encoder: {e}"),
+                        },
+                        Err(_) => log::error!("Could not reset stalled video encoder: in use"),
+                    }
+                }
+            }
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn capture_input_leaves_out_skipped_buffers() {
+        // Every buffer corrupted, the last one counted as corrupted but not yet as captured
+        let stats = CaptureStats {
+            frames_captured: 10,
+            frames_corrupted: 11,
+            ..Default::default()
+        };
+        assert_eq!(stage_counters(PipelineStage::Capture, &stats), (0, 0));
+
+        let stats = CaptureStats {
+            frames_captured: 10,
+            frames_corrupted: 2,
+            frames_dropped_at_capture: 3,
+            frames_dropped: 4,
+            frames_queued: 5,
+            ..Default::default()
+        };
+        assert_eq!(stage_counters(PipelineStage::Capture, &stats), (5, 5));
+    }
+
+    #[test]
+    fn encoder_output_leaves_out_frames_the_encoder_dropped() {
+        // Passed through frames before the first keyframe
+        let stats = CaptureStats {
+            frames_encoded: 10,
+            frames_dropped_by_encoder: 4,
+            frames_dropped: 4,
+            packets_emitted: 6,
+            ..Default::default()
+        };
+        assert_eq!(stage_counters(PipelineStage::EncoderOutput, &stats), (6, 6));
+    }
+
+    #[test]
+    fn skipped_frames_count_as_handled() {
+        let config = WatchdogConfig {
+            stall_timeout: Duration::ZERO,
+            ..Default::default()
+        };
+        let mut progress = StageProgress::new(PipelineStage::EncoderInput);
+        let mut stats = CaptureStats {
+            frames_queued: 1,
+            frames_encoded: 1,
+            ..Default::default()
+        };
+        // A timelapse skipping every frame between the kept ones
+        for _ in 0..10 {
+            stats.frames_queued += 1;
+            stats.frames_rate_limited += 1;
+            let (input, output) = stage_counters(PipelineStage::EncoderInput, &stats);
+            assert!(!progress.update(input, output, &config));
+        }
+
+        stats.frames_queued += 1;
+        let (input, output) = stage_counters(PipelineStage::EncoderInput, &stats);
+        assert!(progress.update(input, output, &config));
+    }
+}
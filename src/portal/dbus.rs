@@ -0,0 +1,92 @@
+use std::os::fd::RawFd;
+
+use portal_screencast_waycap::{
+    ActiveScreenCast, PersistMode, PortalError, ScreenCast, SessionOptions,
+};
+
+use super::{PortalStream, ScreenCastPortal, SessionMetadata, SourceSelection};
+
+/// The desktop portal on the session bus.
+///
+/// `portal-screencast-waycap` sends `SelectSources`, `Start` and `OpenPipeWireRemote` from a
+/// single call, so [`ScreenCastPortal::select_sources`] only records the selection and
+/// [`ScreenCastPortal::start`] sends all three.
+#[derive(Default)]
+pub(crate) struct DbusPortal {
+    screen_cast: Option<ScreenCast>,
+    active: Option<ActiveScreenCast>,
+    parent_window: Option<String>,
+}
+
+fn no_session() -> PortalError {
+    PortalError::Generic("Portal calls made out of order".to_string())
+}
+
+impl ScreenCastPortal for DbusPortal {
+    fn create_session(&mut self, metadata: &SessionMetadata) -> Result<(), PortalError> {
+        self.screen_cast = Some(ScreenCast::with_options(SessionOptions {
+            handle_token: metadata.handle_token.clone(),
+            app_id: metadata.app_id.clone(),
+            purpose: metadata.purpose.clone(),
+            disable_indicator: metadata.hide_indicator,
+        })?);
+        self.parent_window = metadata.parent_window.clone();
+        Ok(())
+    }
+
+    fn select_sources(&mut self, selection: &SourceSelection) -> Result<(), PortalError> {
+        let screen_cast = self.screen_cast.as_mut().ok_or_else(no_session)?;
+        screen_cast.set_source_types(selection.source_types);
+        screen_cast.set_cursor_mode(selection.cursor_mode);
+        if selection.multiple {
+            screen_cast.enable_multiple();
+        }
+        if let Some(token) = &selection.restore_token {
+            screen_cast.set_restore_token(token);
+        }
+        if selection.metadata.persist_session {
+            screen_cast.set_persist_mode(PersistMode::Persistent);
+        }
+        Ok(())
+    }
+
+    fn start(&mut self) -> Result<Vec<PortalStream>, PortalError> {
+        let screen_cast = self.screen_cast.take().ok_or_else(no_session)?;
+        let active = screen_cast.start(self.parent_window.as_deref())?;
+        let streams = active
+            .streams()
+            .map(|stream| PortalStream {
+                node_id: stream.pipewire_node(),
+                size: stream.size(),
+                position: stream.position(),
+            })
+            .collect();
+        self.active = Some(active);
+        Ok(streams)
+    }
+
+    fn indicator_hidden(&self) -> bool {
+        self.active
+            .as_ref()
+            .is_some_and(|active| active.indicator_disabled())
+    }
+
+    fn restore_token(&self) -> Option<String> {
+        self.active
+            .as_ref()
+            .and_then(|active| active.restore_token())
+            .map(str::to_owned)
+    }
+
+    fn open_pipewire_remote(&mut self) -> Result<RawFd, PortalError> {
+        let active = self.active.as_ref().ok_or_else(no_session)?;
+        Ok(active.pipewire_fd())
+    }
+
+    fn close(&mut self) -> Result<(), PortalError> {
+        match self.active.take() {
+            Some(active) => active.close(),
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,140 @@
+//! Scriptable [`ScreenCastPortal`] for testing the session setup without a desktop
+
+use std::{
+    os::fd::RawFd,
+    sync::{Arc, Mutex},
+};
+
+use portal_screencast_waycap::PortalError;
+
+use super::{PortalStream, ScreenCastPortal, SessionMetadata, SourceSelection};
+
+/// How the mocked portal and user behave
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Script {
+    /// The user cancels the dialog
+    pub cancel: bool,
+    /// Streams the user picks
+    pub streams: Vec<PortalStream>,
+    /// `SelectSources` fails when given a restore token
+    pub reject_restore_token: bool,
+    /// The portal closes the session right after starting it
+    pub close_after_start: bool,
+    /// The portal confirms hiding the screen sharing indicator
+    pub hide_indicator: bool,
+    /// Restore token handed out to sessions asking to be persisted
+    pub restore_token: Option<String>,
+}
+
+/// Calls the session setup made, shared with the test. The string is the restore token of
+/// `SelectSources` and the app id of `CreateSession`.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Calls(Arc<Mutex<Vec<(&'static str, Option<String>)>>>);
+
+impl Calls {
+    fn push(&self, call: &'static str, restore_token: Option<String>) {
+        self.0.lock().unwrap().push((call, restore_token));
+    }
+
+    pub fn get(&self) -> Vec<&'static str> {
+        self.0
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(call, _)| *call)
+            .collect()
+    }
+
+    /// Restore tokens passed to each `SelectSources` call
+    pub fn restore_tokens(&self) -> Vec<Option<String>> {
+        self.arguments("select_sources")
+    }
+
+    /// App ids passed to each `CreateSession` call
+    pub fn app_ids(&self) -> Vec<Option<String>> {
+        self.arguments("create_session")
+    }
+
+    fn arguments(&self, of: &str) -> Vec<Option<String>> {
+        self.0
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(call, _)| *call == of)
+            .map(|(_, argument)| argument.clone())
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct MockPortal {
+    script: Script,
+    calls: Calls,
+    closed: bool,
+    persist_session: bool,
+}
+
+impl MockPortal {
+    pub fn new(script: Script) -> (Self, Calls) {
+        let calls = Calls::default();
+        let portal = Self {
+            script,
+            calls: calls.clone(),
+            closed: false,
+            persist_session: false,
+        };
+        (portal, calls)
+    }
+}
+
+impl ScreenCastPortal for MockPortal {
+    fn create_session(&mut self, metadata: &SessionMetadata) -> Result<(), PortalError> {
+        self.calls.push("create_session", metadata.app_id.clone());
+        Ok(())
+    }
+
+    fn select_sources(&mut self, selection: &SourceSelection) -> Result<(), PortalError> {
+        self.calls
+            .push("select_sources", selection.restore_token.clone());
+        if self.script.reject_restore_token && selection.restore_token.is_some() {
+            return Err(PortalError::Generic("Invalid restore token".to_string()));
+        }
+        self.persist_session = selection.metadata.persist_session;
+        Ok(())
+    }
+
+    fn start(&mut self) -> Result<Vec<PortalStream>, PortalError> {
+        self.calls.push("start", None);
+        if self.script.cancel {
+            return Err(PortalError::Cancelled);
+        }
+        self.closed = self.script.close_after_start;
+        Ok(self.script.streams.clone())
+    }
+
+    fn open_pipewire_remote(&mut self) -> Result<RawFd, PortalError> {
+        self.calls.push("open_pipewire_remote", None);
+        Ok(-1)
+    }
+
+    fn indicator_hidden(&self) -> bool {
+        self.script.hide_indicator
+    }
+
+    fn restore_token(&self) -> Option<String> {
+        self.script
+            .restore_token
+            .clone()
+            .filter(|_| self.persist_session)
+    }
+
+    fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    fn close(&mut self) -> Result<(), PortalError> {
+        self.calls.push("close", None);
+        self.closed = true;
+        Ok(())
+    }
+}
@@ -0,0 +1,455 @@
+//! Talks to the ScreenCast desktop portal to let the user pick what to capture.
+//!
+//! Session setup goes through the [`ScreenCastPortal`] trait, one method per portal call, so it
+//! can be tested without a desktop.
+
+use std::{os::fd::RawFd, sync::Mutex};
+
+use portal_screencast_waycap::{CursorMode, PortalError, SourceType};
+
+use crate::types::error::{self, WaycapError};
+
+mod dbus;
+#[cfg(test)]
+mod mock;
+mod queue;
+
+pub(crate) use dbus::DbusPortal;
+pub(crate) use queue::{is_transient, PortalSession, QUEUE};
+pub use queue::{state, PortalState};
+
+/// What the user gets asked to share
+#[derive(Debug, Clone)]
+pub(crate) struct SourceSelection {
+    pub source_types: SourceType,
+    pub cursor_mode: CursorMode,
+    pub multiple: bool,
+    /// Token of an earlier session to restore without showing the dialog
+    pub restore_token: Option<String>,
+    pub metadata: SessionMetadata,
+}
+
+/// How a capture introduces itself to the portal, so permission dialogs and the screen sharing
+/// indicator name the application instead of showing a blank entry. See
+/// [`crate::pipeline::builder::CaptureBuilder::with_portal_metadata`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SessionMetadata {
+    /// Prefix of the portal's request and session handle tokens, made of `[A-Za-z0-9_]`
+    pub handle_token: Option<String>,
+    /// Window to parent the dialog to, `wayland:<exported xdg-foreign handle>` or
+    /// `x11:<window id>`
+    pub parent_window: Option<String>,
+    /// App id to register with the portal, for applications running outside a sandbox. Needs
+    /// xdg-desktop-portal 1.19 or newer, older versions ignore it.
+    pub app_id: Option<String>,
+    /// Why the screen is shared, shown by portals which support it
+    pub purpose: Option<String>,
+    /// Ask the compositor to hide its screen sharing indicator. Only honored for trusted
+    /// clients, see [`crate::Capture::recording_indicator_hidden`].
+    pub hide_indicator: bool,
+    /// Ask the portal for a token which restores the picked source on the next run without
+    /// the dialog, see [`crate::Capture::session_snapshot`]. The permission lasts until the
+    /// user revokes it.
+    pub persist_session: bool,
+    /// Token of an earlier session, from [`crate::types::session::SessionSnapshot`], to restore
+    /// its source without the dialog. Portals which don't know it show the dialog instead.
+    pub restore_token: Option<String>,
+}
+
+impl SessionMetadata {
+    pub(crate) fn validate(&self) -> error::Result<()> {
+        if let Some(token) = &self.handle_token {
+            if token.is_empty() || !token.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                return Err(WaycapError::Validation(format!(
+                    "Portal handle token {token:?} may only contain [A-Za-z0-9_]"
+                )));
+            }
+        }
+        if let Some(parent) = &self.parent_window {
+            if !parent.starts_with("wayland:") && !parent.starts_with("x11:") {
+                return Err(WaycapError::Validation(format!(
+                    "Parent window {parent:?} is neither a wayland: nor an x11: handle"
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A stream the user picked in the dialog
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct PortalStream {
+    pub node_id: u32,
+    pub size: (u32, u32),
+    pub position: Option<(i32, i32)>,
+}
+
+/// Where a captured source sits in the compositor's global coordinate space, as reported by
+/// the portal. Captures of several outputs can be laid out on one canvas from these.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SourceInfo {
+    /// PipeWire node of the stream
+    pub node_id: u32,
+    /// Top left corner, `None` for windows or when the portal doesn't report it
+    pub position: Option<(i32, i32)>,
+    /// Size in compositor coordinates, which differs from the stream size on scaled outputs
+    pub size: (u32, u32),
+}
+
+/// The [`SourceInfo`] of a running capture, kept up to date by the video stream.
+///
+/// The portal has no signal for changed stream properties, a reconfigured output shows up as a
+/// renegotiated stream size instead. The compositor size follows it with the scale seen at the
+/// first negotiation.
+#[derive(Debug)]
+pub(crate) struct SourceTracker(Mutex<TrackedSource>);
+
+#[derive(Debug)]
+struct TrackedSource {
+    info: SourceInfo,
+    negotiated: Option<(u32, u32)>,
+}
+
+impl SourceTracker {
+    pub fn new(stream: &PortalStream) -> Self {
+        Self(Mutex::new(TrackedSource {
+            info: SourceInfo {
+                node_id: stream.node_id,
+                position: stream.position,
+                size: stream.size,
+            },
+            negotiated: None,
+        }))
+    }
+
+    pub fn info(&self) -> SourceInfo {
+        self.0.lock().unwrap().info
+    }
+
+    /// Called with the stream size each time PipeWire negotiates the format
+    pub fn negotiated(&self, width: u32, height: u32) {
+        let mut source = self.0.lock().unwrap();
+        let Some((old_width, old_height)) = source.negotiated.replace((width, height)) else {
+            return;
+        };
+        if (old_width, old_height) == (width, height) || old_width == 0 || old_height == 0 {
+            return;
+        }
+        let (logical_width, logical_height) = source.info.size;
+        source.info.size = (
+            (u64::from(logical_width) * u64::from(width) / u64::from(old_width)) as u32,
+            (u64::from(logical_height) * u64::from(height) / u64::from(old_height)) as u32,
+        );
+        log::info!(
+            "Source {} resized to {}x{}",
+            source.info.node_id,
+            source.info.size.0,
+            source.info.size.1
+        );
+    }
+}
+
+/// The calls of the ScreenCast portal, made in order by [`start_session`]
+pub(crate) trait ScreenCastPortal: Send {
+    /// `CreateSession`
+    fn create_session(&mut self, metadata: &SessionMetadata) -> Result<(), PortalError>;
+    /// `SelectSources`
+    fn select_sources(&mut self, selection: &SourceSelection) -> Result<(), PortalError>;
+    /// `Start`, shows the dialog and returns the streams the user picked
+    fn start(&mut self) -> Result<Vec<PortalStream>, PortalError>;
+    /// `OpenPipeWireRemote`
+    fn open_pipewire_remote(&mut self) -> Result<RawFd, PortalError>;
+    /// Whether the portal confirmed hiding the screen sharing indicator after `Start`
+    fn indicator_hidden(&self) -> bool {
+        false
+    }
+    /// Token to restore the session's source with later, handed out after `Start` when
+    /// persisting was asked for
+    fn restore_token(&self) -> Option<String> {
+        None
+    }
+    /// Whether the portal closed the session on its own, e.g. from the compositor's sharing
+    /// indicator
+    fn is_closed(&self) -> bool {
+        false
+    }
+    /// `Session.Close`
+    fn close(&mut self) -> Result<(), PortalError>;
+}
+
+/// A started session and the stream to capture from it
+pub(crate) struct StartedSession {
+    portal: Box<dyn ScreenCastPortal>,
+    pub pipewire_fd: RawFd,
+    pub stream: PortalStream,
+    pub indicator_hidden: bool,
+    pub restore_token: Option<String>,
+}
+
+impl PortalSession for StartedSession {
+    fn close(&mut self) {
+        if self.portal.is_closed() {
+            log::debug!("Portal session was already closed by the portal");
+            return;
+        }
+        if let Err(e) = self.portal.close() {
+            log::warn!("Failed to close the portal session: {e}");
+        }
+    }
+}
+
+/// Run the portal calls to start a session, and pick the stream to capture.
+///
+/// A rejected restore token is dropped and the user is asked again.
+pub(crate) fn start_session(
+    mut portal: Box<dyn ScreenCastPortal>,
+    selection: &SourceSelection,
+) -> Result<StartedSession, PortalError> {
+    portal.create_session(&selection.metadata)?;
+    if let Err(e) = portal.select_sources(selection) {
+        if selection.restore_token.is_none() {
+            return Err(e);
+        }
+        log::warn!("Portal rejected the restore token, showing the dialog: {e}");
+        portal.select_sources(&SourceSelection {
+            restore_token: None,
+            ..selection.clone()
+        })?;
+    }
+
+    let streams = portal.start()?;
+    if streams.len() > 1 {
+        log::warn!(
+            "Portal returned {} streams, capturing the first one",
+            streams.len()
+        );
+    }
+    let stream = *streams
+        .first()
+        .ok_or_else(|| PortalError::Generic("The portal returned no streams".to_string()))?;
+
+    log::debug!(
+        "Capturing portal stream {} of size {:?} at {:?}",
+        stream.node_id,
+        stream.size,
+        stream.position
+    );
+
+    let indicator_hidden = portal.indicator_hidden();
+    if selection.metadata.hide_indicator && !indicator_hidden {
+        log::info!("Portal did not confirm hiding the screen sharing indicator");
+    }
+
+    let restore_token = portal.restore_token();
+    if selection.metadata.persist_session && restore_token.is_none() {
+        log::info!("Portal handed out no restore token, the next run shows the dialog again");
+    }
+
+    let pipewire_fd = portal.open_pipewire_remote()?;
+    Ok(StartedSession {
+        portal,
+        pipewire_fd,
+        stream,
+        indicator_hidden,
+        restore_token,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{mock::*, *};
+    use crate::types::error::{codes, WaycapError};
+
+    fn selection(restore_token: Option<&str>) -> SourceSelection {
+        SourceSelection {
+            source_types: SourceType::all(),
+            cursor_mode: CursorMode::HIDDEN,
+            multiple: false,
+            restore_token: restore_token.map(str::to_string),
+            metadata: SessionMetadata::default(),
+        }
+    }
+
+    fn stream(node_id: u32) -> PortalStream {
+        PortalStream {
+            node_id,
+            size: (1920, 1080),
+            position: Some((1920, 0)),
+        }
+    }
+
+    #[test]
+    fn user_cancels_dialog() {
+        let (portal, calls) = MockPortal::new(Script {
+            cancel: true,
+            ..Default::default()
+        });
+
+        let Err(err) = start_session(Box::new(portal), &selection(None)) else {
+            panic!("the session should not start");
+        };
+        assert_eq!(WaycapError::from(err).error_code(), codes::PORTAL_CANCELLED);
+        assert_eq!(calls.get(), ["create_session", "select_sources", "start"]);
+    }
+
+    #[test]
+    fn zero_streams() {
+        let (portal, calls) = MockPortal::new(Script::default());
+
+        let Err(err) = start_session(Box::new(portal), &selection(None)) else {
+            panic!("the session should not start");
+        };
+        assert!(matches!(err, PortalError::Generic(_)));
+        assert!(!calls.get().contains(&"open_pipewire_remote"));
+    }
+
+    #[test]
+    fn multiple_streams_captures_the_first() {
+        let (portal, calls) = MockPortal::new(Script {
+            streams: vec![stream(42), stream(43)],
+            ..Default::default()
+        });
+
+        let session = start_session(Box::new(portal), &selection(None)).unwrap();
+        assert_eq!(session.stream, stream(42));
+        assert_eq!(
+            calls.get(),
+            [
+                "create_session",
+                "select_sources",
+                "start",
+                "open_pipewire_remote"
+            ]
+        );
+    }
+
+    #[test]
+    fn expired_restore_token_shows_dialog() {
+        let (portal, calls) = MockPortal::new(Script {
+            streams: vec![stream(42)],
+            reject_restore_token: true,
+            ..Default::default()
+        });
+
+        start_session(Box::new(portal), &selection(Some("expired"))).unwrap();
+        assert_eq!(
+            calls.get(),
+            [
+                "create_session",
+                "select_sources",
+                "select_sources",
+                "start",
+                "open_pipewire_remote"
+            ]
+        );
+        assert_eq!(calls.restore_tokens(), [Some("expired".to_string()), None]);
+    }
+
+    #[test]
+    fn restore_token_only_for_persisted_sessions() {
+        let mut selection = selection(Some("previous"));
+        for persist_session in [false, true] {
+            let (portal, calls) = MockPortal::new(Script {
+                streams: vec![stream(42)],
+                restore_token: Some("next".to_string()),
+                ..Default::default()
+            });
+            selection.metadata.persist_session = persist_session;
+            let session = start_session(Box::new(portal), &selection).unwrap();
+            assert_eq!(
+                session.restore_token.as_deref(),
+                persist_session.then_some("next")
+            );
+            assert_eq!(calls.restore_tokens(), [Some("previous".to_string())]);
+        }
+    }
+
+    #[test]
+    fn indicator_hiding_is_reported() {
+        let mut selection = selection(None);
+        selection.metadata = SessionMetadata {
+            app_id: Some("org.example.Kiosk".to_string()),
+            hide_indicator: true,
+            ..Default::default()
+        };
+        for honored in [false, true] {
+            let (portal, calls) = MockPortal::new(Script {
+                streams: vec![stream(42)],
+                hide_indicator: honored,
+                ..Default::default()
+            });
+            let session = start_session(Box::new(portal), &selection).unwrap();
+            assert_eq!(session.indicator_hidden, honored);
+            assert_eq!(calls.app_ids(), [Some("org.example.Kiosk".to_string())]);
+        }
+    }
+
+    #[test]
+    fn session_metadata_is_validated() {
+        assert!(SessionMetadata::default().validate().is_ok());
+        let valid = SessionMetadata {
+            handle_token: Some("kiosk_1".to_string()),
+            parent_window: Some("wayland:1a2b".to_string()),
+            ..Default::default()
+        };
+        assert!(valid.validate().is_ok());
+        for invalid in [
+            SessionMetadata {
+                handle_token: Some("kiosk-1".to_string()),
+                ..Default::default()
+            },
+            SessionMetadata {
+                parent_window: Some("1a2b".to_string()),
+                ..Default::default()
+            },
+        ] {
+            let err = invalid.validate().unwrap_err();
+            assert!(matches!(err, WaycapError::Validation(_)), "{err}");
+        }
+    }
+
+    #[test]
+    fn session_closed_mid_capture() {
+        static QUEUE: queue::PortalQueue = queue::PortalQueue::new();
+        let (portal, calls) = MockPortal::new(Script {
+            streams: vec![stream(42)],
+            close_after_start: true,
+            ..Default::default()
+        });
+
+        let session = QUEUE
+            .request(
+                || start_session(Box::new(portal.clone()), &selection(None)),
+                is_transient,
+            )
+            .unwrap();
+        assert_eq!(QUEUE.state(), PortalState::Active);
+
+        session.close();
+        assert!(!calls.get().contains(&"close"));
+        assert_eq!(QUEUE.state(), PortalState::Idle);
+    }
+
+    #[test]
+    fn source_follows_renegotiated_size() {
+        let tracker = SourceTracker::new(&PortalStream {
+            node_id: 42,
+            size: (1920, 1080),
+            position: Some((2560, 0)),
+        });
+        // Output scaled by 1.5
+        tracker.negotiated(2880, 1620);
+        assert_eq!(tracker.info().size, (1920, 1080));
+
+        tracker.negotiated(3840, 2160);
+        assert_eq!(
+            tracker.info(),
+            SourceInfo {
+                node_id: 42,
+                position: Some((2560, 0)),
+                size: (2560, 1440),
+            }
+        );
+    }
+}
@@ -0,0 +1,283 @@
+//! Serializes the ScreenCast portal requests of all captures in the process.
+//!
+//! Asking the portal for a new session while the previous one is still being torn down makes
+//! the request fail, which happens when recording is stopped and restarted in quick succession.
+//! Requests wait in line here until every earlier session finished closing, and are retried
+//! once when the portal still answers with one of the errors it returns during a teardown.
+
+use std::{
+    fmt::Display,
+    ops::Deref,
+    sync::{Condvar, Mutex, MutexGuard, PoisonError},
+    time::Duration,
+};
+
+use portal_screencast_waycap::PortalError;
+
+/// How long a new request waits for earlier sessions to finish closing
+pub(crate) const CLOSE_TIMEOUT: Duration = Duration::from_secs(3);
+/// Delay before retrying a request which failed with a transient error
+const RETRY_DELAY: Duration = Duration::from_millis(250);
+
+/// D-Bus errors the portal answers with while an earlier session is still being torn down
+const TRANSIENT_ERRORS: &[&str] = &[
+    "org.freedesktop.portal.Error.Failed",
+    "org.freedesktop.DBus.Error.NoReply",
+    "org.freedesktop.DBus.Error.UnknownObject",
+];
+
+pub(crate) static QUEUE: PortalQueue = PortalQueue::new();
+
+/// Current state of the portal sessions of this process, see [`state`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PortalState {
+    /// No session is open
+    Idle,
+    /// A request is waiting for earlier sessions to close or for the user to pick a source
+    Requesting,
+    /// At least one session is open
+    Active,
+    /// A session is being closed
+    Closing,
+}
+
+/// State of the portal sessions of this process, meant for debugging start up failures
+pub fn state() -> PortalState {
+    QUEUE.state()
+}
+
+/// Whether a failed request is worth retrying once the previous session is gone
+pub(crate) fn is_transient(err: &PortalError) -> bool {
+    match err {
+        PortalError::DBus(err) => err
+            .name()
+            .is_some_and(|name| TRANSIENT_ERRORS.contains(&name)),
+        _ => false,
+    }
+}
+
+/// A portal session which can be handed to [`PortalQueue::request`]
+pub(crate) trait PortalSession {
+    fn close(&mut self);
+}
+
+#[derive(Debug, Default)]
+struct Sessions {
+    requesting: bool,
+    active: usize,
+    closing: usize,
+}
+
+pub(crate) struct PortalQueue {
+    sessions: Mutex<Sessions>,
+    changed: Condvar,
+    /// Held for the whole request so concurrent requests go one after another
+    requests: Mutex<()>,
+}
+
+impl PortalQueue {
+    pub const fn new() -> Self {
+        Self {
+            sessions: Mutex::new(Sessions {
+                requesting: false,
+                active: 0,
+                closing: 0,
+            }),
+            changed: Condvar::new(),
+            requests: Mutex::new(()),
+        }
+    }
+
+    pub fn state(&self) -> PortalState {
+        let sessions = self.sessions();
+        if sessions.requesting {
+            PortalState::Requesting
+        } else if sessions.closing > 0 {
+            PortalState::Closing
+        } else if sessions.active > 0 {
+            PortalState::Active
+        } else {
+            PortalState::Idle
+        }
+    }
+
+    /// Run `request` once no earlier session is closing anymore, retrying it once if it fails
+    /// with an error `is_transient` accepts.
+    pub fn request<S, E>(
+        &'static self,
+        mut request: impl FnMut() -> Result<S, E>,
+        is_transient: impl Fn(&E) -> bool,
+    ) -> Result<OpenSession<S>, E>
+    where
+        S: PortalSession,
+        E: Display,
+    {
+        let _queued = self.requests.lock().unwrap_or_else(PoisonError::into_inner);
+        self.update(|sessions| sessions.requesting = true);
+
+        self.wait_closed();
+        let mut result = request();
+        if let Err(e) = &result {
+            if is_transient(e) {
+                log::warn!("Portal request failed, retrying: {e}");
+                std::thread::sleep(RETRY_DELAY);
+                self.wait_closed();
+                result = request();
+            }
+        }
+
+        self.update(|sessions| {
+            sessions.requesting = false;
+            if result.is_ok() {
+                sessions.active += 1;
+            }
+        });
+        result.map(|session| OpenSession {
+            queue: self,
+            session,
+            closed: false,
+        })
+    }
+
+    fn wait_closed(&self) {
+        let sessions = self.sessions();
+        let (_sessions, timeout) = self
+            .changed
+            .wait_timeout_while(sessions, CLOSE_TIMEOUT, |sessions| sessions.closing > 0)
+            .unwrap_or_else(PoisonError::into_inner);
+        if timeout.timed_out() {
+            log::warn!("Previous portal session did not close in time, requesting anyway");
+        }
+    }
+
+    fn sessions(&self) -> MutexGuard<'_, Sessions> {
+        self.sessions.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn update(&self, f: impl FnOnce(&mut Sessions)) {
+        f(&mut self.sessions());
+        self.changed.notify_all();
+    }
+}
+
+/// An open portal session, counted as active by its queue until closed or dropped
+pub(crate) struct OpenSession<S: PortalSession> {
+    queue: &'static PortalQueue,
+    session: S,
+    closed: bool,
+}
+
+impl<S: PortalSession> OpenSession<S> {
+    pub fn close(mut self) {
+        self.close_inner();
+    }
+
+    fn close_inner(&mut self) {
+        if std::mem::replace(&mut self.closed, true) {
+            return;
+        }
+        self.queue.update(|sessions| {
+            sessions.active -= 1;
+            sessions.closing += 1;
+        });
+        self.session.close();
+        self.queue.update(|sessions| sessions.closing -= 1);
+    }
+}
+
+impl<S: PortalSession> Deref for OpenSession<S> {
+    type Target = S;
+
+    fn deref(&self) -> &S {
+        &self.session
+    }
+}
+
+impl<S: PortalSession> Drop for OpenSession<S> {
+    fn drop(&mut self) {
+        self.close_inner();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use super::*;
+
+    /// Fails every request made while an earlier session is still open or being torn down,
+    /// like the portal does
+    #[derive(Default)]
+    struct TeardownPortal {
+        open: AtomicUsize,
+        requests: AtomicUsize,
+        /// How long the Close call takes
+        close_time: Duration,
+        /// How long the portal keeps tearing down the session after the Close call returned
+        teardown_time: Duration,
+    }
+
+    struct TeardownSession(Arc<TeardownPortal>);
+
+    impl PortalSession for TeardownSession {
+        fn close(&mut self) {
+            std::thread::sleep(self.0.close_time);
+            let portal = Arc::clone(&self.0);
+            std::thread::spawn(move || {
+                std::thread::sleep(portal.teardown_time);
+                portal.open.fetch_sub(1, Ordering::AcqRel);
+            });
+        }
+    }
+
+    impl TeardownPortal {
+        fn start(self: &Arc<Self>) -> Result<TeardownSession, String> {
+            self.requests.fetch_add(1, Ordering::AcqRel);
+            if self.open.fetch_add(1, Ordering::AcqRel) > 0 {
+                self.open.fetch_sub(1, Ordering::AcqRel);
+                return Err("org.freedesktop.portal.Error.Failed".to_string());
+            }
+            Ok(TeardownSession(Arc::clone(self)))
+        }
+    }
+
+    #[test]
+    fn rapid_start_stop() {
+        static QUEUE: PortalQueue = PortalQueue::new();
+        let portal = Arc::new(TeardownPortal {
+            teardown_time: Duration::from_millis(20),
+            ..Default::default()
+        });
+
+        for _ in 0..10 {
+            let session = QUEUE
+                .request(|| portal.start(), |e| e.contains("Error.Failed"))
+                .unwrap();
+            assert_eq!(QUEUE.state(), PortalState::Active);
+            session.close();
+        }
+        assert_eq!(QUEUE.state(), PortalState::Idle);
+    }
+
+    #[test]
+    fn waits_for_closing_session() {
+        static QUEUE: PortalQueue = PortalQueue::new();
+        let portal = Arc::new(TeardownPortal {
+            close_time: Duration::from_millis(100),
+            ..Default::default()
+        });
+
+        let session = QUEUE.request(|| portal.start(), |_| false).unwrap();
+        // The capture's worker thread closes the session while the next one starts
+        std::thread::spawn(move || session.close());
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(QUEUE.state(), PortalState::Closing);
+
+        let session = QUEUE.request(|| portal.start(), |_| false).unwrap();
+        assert_eq!(portal.requests.load(Ordering::Acquire), 2);
+        session.close();
+    }
+}
@@ -0,0 +1,522 @@
+//! Side by side quality comparison of the encoder backends.
+//!
+//! [`compare_backends`] pushes the same synthetic clip through every encoder the machine can
+//! open, all at the same constant bitrate, decodes what came out and scores it against the
+//! source with ffmpeg's `psnr` and `ssim` filters. It is meant for picking defaults and
+//! spotting quality regressions between drivers or ffmpeg releases, not for use during a
+//! capture. Needs the `quality-harness` feature.
+
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+use ffmpeg_next::{
+    self as ffmpeg,
+    ffi::{
+        av_hwframe_ctx_init, av_hwframe_get_buffer, av_hwframe_transfer_data, AVHWFramesContext,
+        AVPixelFormat,
+    },
+};
+
+use crate::{
+    encoders::video::{create_hw_device, create_hw_frame_ctx, open_encoder, HwBufferRef, GOP_SIZE},
+    ffmpeg_compat,
+    runtime::Runtime,
+    types::error::{Result, WaycapError},
+};
+
+/// How the NV12 source frames reach an encoder
+#[derive(Debug, Clone, Copy)]
+enum Upload {
+    /// The encoder takes system memory frames
+    None,
+    /// Copied into VAAPI surfaces first, like the capture's frames live on the GPU
+    Vaapi,
+}
+
+struct Backend {
+    encoder: &'static str,
+    upload: Upload,
+    /// Option switching the encoder to constant bitrate
+    cbr: (&'static str, &'static str),
+}
+
+/// Every encoder compared, the ones which can't be opened are skipped
+const BACKENDS: &[Backend] = &[
+    Backend {
+        encoder: "h264_vaapi",
+        upload: Upload::Vaapi,
+        cbr: ("rc_mode", "CBR"),
+    },
+    Backend {
+        encoder: "h264_nvenc",
+        upload: Upload::None,
+        cbr: ("rc", "cbr"),
+    },
+    // Software reference
+    Backend {
+        encoder: "libx264",
+        upload: Upload::None,
+        cbr: ("nal-hrd", "cbr"),
+    },
+];
+
+/// Clip every backend encodes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompareConfig {
+    pub width: u32,
+    pub height: u32,
+    pub framerate: u32,
+    /// Constant bitrate all encoders are held to, in bits per second
+    pub bitrate: u64,
+}
+
+impl Default for CompareConfig {
+    fn default() -> Self {
+        Self {
+            width: 1920,
+            height: 1080,
+            framerate: 60,
+            bitrate: 8_000_000,
+        }
+    }
+}
+
+/// Result for one encoder
+#[derive(Debug, Clone, PartialEq)]
+pub struct BackendReport {
+    /// ffmpeg name of the encoder
+    pub encoder: String,
+    pub frames: u64,
+    /// Bitrate the encoder actually produced, in bits per second
+    pub bitrate: u64,
+    /// PSNR over all planes in dB, infinite for a lossless result
+    pub psnr: f64,
+    /// Mean SSIM over all planes, 1.0 is identical to the source
+    pub ssim: f64,
+    /// Time spent handing frames to the encoder and taking packets out, uploads included
+    pub encode_time: Duration,
+    pub avg_encode_time: Duration,
+}
+
+/// Outcome of [`compare_backends`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct ComparisonReport {
+    pub config: CompareConfig,
+    pub seconds: u32,
+    pub backends: Vec<BackendReport>,
+    /// Encoders which could not be compared, with the reason
+    pub skipped: Vec<(String, String)>,
+}
+
+impl fmt::Display for ComparisonReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let config = &self.config;
+        writeln!(
+            f,
+            "{}x{}@{} for {}s at {}k",
+            config.width,
+            config.height,
+            config.framerate,
+            self.seconds,
+            config.bitrate / 1000
+        )?;
+        writeln!(
+            f,
+            "{:<12} {:>8} {:>8} {:>8} {:>10}",
+            "encoder", "kbit/s", "psnr", "ssim", "enc avg"
+        )?;
+        for backend in &self.backends {
+            writeln!(
+                f,
+                "{:<12} {:>8} {:>8.2} {:>8.4} {:>8.2}ms",
+                backend.encoder,
+                backend.bitrate / 1000,
+                backend.psnr,
+                backend.ssim,
+                backend.avg_encode_time.as_secs_f64() * 1000.0
+            )?;
+        }
+        for (encoder, reason) in &self.skipped {
+            writeln!(f, "{encoder:<12} skipped: {reason}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Encode `seconds` of ffmpeg's `testsrc2` pattern with every available backend at
+/// `config.bitrate` and score the results against the source.
+///
+/// Backends which can't be opened on this machine end up in [`ComparisonReport::skipped`], so
+/// the report is only empty of results when no encoder worked at all.
+pub fn compare_backends(config: &CompareConfig, seconds: u32) -> Result<ComparisonReport> {
+    if config.width == 0 || config.height == 0 || config.framerate == 0 || config.bitrate == 0 {
+        return Err(WaycapError::Validation(format!(
+            "Comparison needs a size, framerate and bitrate, got {config:?}"
+        )));
+    }
+    if seconds == 0 {
+        return Err(WaycapError::Validation(
+            "Comparison needs at least one second of video".to_string(),
+        ));
+    }
+    let _runtime = Runtime::acquire()?;
+
+    let mut report = ComparisonReport {
+        config: *config,
+        seconds,
+        backends: Vec::new(),
+        skipped: Vec::new(),
+    };
+    for backend in BACKENDS {
+        match compare_backend(backend, config, seconds) {
+            Ok(result) => report.backends.push(result),
+            Err(e) => {
+                log::info!("Skipping {} in the comparison: {e}", backend.encoder);
+                report
+                    .skipped
+                    .push((backend.encoder.to_string(), e.to_string()));
+            }
+        }
+    }
+    Ok(report)
+}
+
+fn compare_backend(
+    backend: &Backend,
+    config: &CompareConfig,
+    seconds: u32,
+) -> Result<BackendReport> {
+    let (mut encoder, frames_ctx) = create_encoder(backend, config)?;
+
+    let mut source = source_graph(config, seconds)?;
+    let mut frame = ffmpeg::util::frame::Video::empty();
+    let mut packets = Vec::new();
+    let mut frames = 0u64;
+    let mut encode_time = Duration::ZERO;
+    while source.get("out").unwrap().sink().frame(&mut frame).is_ok() {
+        frame.set_pts(Some(frames as i64));
+        let started = Instant::now();
+        match frames_ctx {
+            Some(ref frames_ctx) => encoder.send_frame(&upload(&frame, frames_ctx)?)?,
+            None => encoder.send_frame(&frame)?,
+        }
+        receive_packets(&mut encoder, &mut packets);
+        encode_time += started.elapsed();
+        frames += 1;
+    }
+    let started = Instant::now();
+    encoder.send_eof()?;
+    receive_packets(&mut encoder, &mut packets);
+    encode_time += started.elapsed();
+
+    let bytes: usize = packets.iter().map(|packet| packet.size()).sum();
+    let (psnr, ssim) = score(&encoder, &packets, config, seconds)?;
+    Ok(BackendReport {
+        encoder: backend.encoder.to_string(),
+        frames,
+        bitrate: (bytes as u64 * 8 * config.framerate as u64) / frames.max(1),
+        psnr,
+        ssim,
+        encode_time,
+        avg_encode_time: encode_time / frames.max(1) as u32,
+    })
+}
+
+fn create_encoder(
+    backend: &Backend,
+    config: &CompareConfig,
+) -> Result<(ffmpeg::codec::encoder::Video, Option<HwBufferRef>)> {
+    let codec = ffmpeg_compat::find_encoder(backend.encoder)?;
+    let mut encoder_ctx = ffmpeg::codec::context::Context::new_with_codec(codec)
+        .encoder()
+        .video()?;
+    encoder_ctx.set_width(config.width);
+    encoder_ctx.set_height(config.height);
+    encoder_ctx.set_time_base(ffmpeg::Rational::new(1, config.framerate as i32));
+    encoder_ctx.set_frame_rate(Some(ffmpeg::Rational::new(config.framerate as i32, 1)));
+    encoder_ctx.set_gop(GOP_SIZE);
+    // Same frame structure everywhere, like the capture encoders
+    encoder_ctx.set_max_b_frames(0);
+    encoder_ctx.set_bit_rate(config.bitrate as usize);
+    encoder_ctx.set_max_bit_rate(config.bitrate as usize);
+
+    let frames_ctx = match backend.upload {
+        Upload::None => {
+            encoder_ctx.set_format(ffmpeg::format::Pixel::NV12);
+            None
+        }
+        Upload::Vaapi => {
+            encoder_ctx.set_format(ffmpeg::format::Pixel::VAAPI);
+            let device =
+                create_hw_device(ffmpeg_next::ffi::AVHWDeviceType::AV_HWDEVICE_TYPE_VAAPI)?;
+            let frames_ctx = create_hw_frame_ctx(&device)?;
+            unsafe {
+                let hw_frame_context =
+                    &mut *((*frames_ctx.as_ptr()).data as *mut AVHWFramesContext);
+                hw_frame_context.width = config.width as i32;
+                hw_frame_context.height = config.height as i32;
+                hw_frame_context.sw_format = AVPixelFormat::AV_PIX_FMT_NV12;
+                hw_frame_context.format = AVPixelFormat::AV_PIX_FMT_VAAPI;
+                hw_frame_context.initial_pool_size = 4;
+
+                let err = av_hwframe_ctx_init(frames_ctx.as_ptr());
+                if err < 0 {
+                    return Err(WaycapError::Init(format!(
+                        "Error trying to initialize hw frame context: {err:?}",
+                    )));
+                }
+
+                (*encoder_ctx.as_mut_ptr()).hw_device_ctx = device.new_ref()?;
+                (*encoder_ctx.as_mut_ptr()).hw_frames_ctx = frames_ctx.new_ref()?;
+            }
+            Some(frames_ctx)
+        }
+    };
+
+    let mut opts = ffmpeg::Dictionary::new();
+    let (key, value) = backend.cbr;
+    opts.set(key, value);
+    // One second of buffer, the usual CBR setup for streaming
+    opts.set("bufsize", &config.bitrate.to_string());
+    // A backend ignoring the rate control options would not be comparable
+    let (encoder, _) = open_encoder(encoder_ctx, opts, true)?;
+    Ok((encoder, frames_ctx))
+}
+
+/// Graph generating the source clip in NV12, read from its `out` sink
+fn source_graph(config: &CompareConfig, seconds: u32) -> Result<ffmpeg::filter::Graph> {
+    let mut graph = ffmpeg::filter::Graph::new();
+    graph.add(&ffmpeg_compat::find_filter("buffersink")?, "out", "")?;
+    ffmpeg_compat::find_filter("testsrc2")?;
+    let spec = format!(
+        "testsrc2=size={}x{}:rate={}:duration={seconds},format=nv12",
+        config.width, config.height, config.framerate
+    );
+    graph.input("out", 0)?.parse(&spec)?;
+    graph.validate()?;
+    Ok(graph)
+}
+
+fn upload(
+    frame: &ffmpeg::util::frame::Video,
+    frames_ctx: &HwBufferRef,
+) -> Result<ffmpeg::util::frame::Video> {
+    let mut hw_frame = ffmpeg::util::frame::Video::empty();
+    unsafe {
+        let err = av_hwframe_get_buffer(frames_ctx.as_ptr(), hw_frame.as_mut_ptr(), 0);
+        if err < 0 {
+            return Err(ffmpeg::Error::from(err).into());
+        }
+        let err = av_hwframe_transfer_data(hw_frame.as_mut_ptr(), frame.as_ptr(), 0);
+        if err < 0 {
+            return Err(ffmpeg::Error::from(err).into());
+        }
+    }
+    hw_frame.set_pts(frame.pts());
+    Ok(hw_frame)
+}
+
+fn receive_packets(
+    encoder: &mut ffmpeg::codec::encoder::Video,
+    packets: &mut Vec<ffmpeg::codec::packet::Packet>,
+) {
+    let mut packet = ffmpeg::codec::packet::Packet::empty();
+    while encoder.receive_packet(&mut packet).is_ok() {
+        packets.push(packet.clone());
+    }
+}
+
+/// Decode `packets` in software and compare every frame to a freshly generated source.
+/// Returns PSNR and SSIM over the whole clip.
+fn score(
+    encoder: &ffmpeg::codec::encoder::Video,
+    packets: &[ffmpeg::codec::packet::Packet],
+    config: &CompareConfig,
+    seconds: u32,
+) -> Result<(f64, f64)> {
+    let codec_id = encoder
+        .codec()
+        .map(|codec| codec.id())
+        .ok_or(ffmpeg::Error::DecoderNotFound)?;
+    let codec = ffmpeg::codec::decoder::find(codec_id).ok_or(ffmpeg::Error::DecoderNotFound)?;
+    let mut decoder = ffmpeg::codec::context::Context::new_with_codec(codec)
+        .decoder()
+        .video()?;
+
+    let mut source = source_graph(config, seconds)?;
+    let mut metrics: Option<ffmpeg::filter::Graph> = None;
+    let mut totals = MetricTotals::default();
+    let mut decoded = ffmpeg::util::frame::Video::empty();
+    let mut reference = ffmpeg::util::frame::Video::empty();
+    let mut index = 0i64;
+
+    let mut compare_decoded = |decoder: &mut ffmpeg::codec::decoder::Video,
+                               metrics: &mut Option<ffmpeg::filter::Graph>,
+                               totals: &mut MetricTotals|
+     -> Result<()> {
+        while decoder.receive_frame(&mut decoded).is_ok() {
+            if source
+                .get("out")
+                .unwrap()
+                .sink()
+                .frame(&mut reference)
+                .is_err()
+            {
+                // More frames came out than went in, nothing to compare them to
+                break;
+            }
+            if metrics.is_none() {
+                *metrics = Some(metrics_graph(config, decoded.format())?);
+            }
+            let graph = metrics.as_mut().unwrap();
+            decoded.set_pts(Some(index));
+            reference.set_pts(Some(index));
+            index += 1;
+            graph.get("dist").unwrap().source().add(&decoded)?;
+            graph.get("ref").unwrap().source().add(&reference)?;
+            totals.collect(graph);
+        }
+        Ok(())
+    };
+
+    for packet in packets {
+        decoder.send_packet(packet)?;
+        compare_decoded(&mut decoder, &mut metrics, &mut totals)?;
+    }
+    decoder.send_eof()?;
+    compare_decoded(&mut decoder, &mut metrics, &mut totals)?;
+
+    let Some(mut graph) = metrics else {
+        return Err(WaycapError::Encoding(
+            "The encoder produced no decodable frames".to_string(),
+        ));
+    };
+    graph.get("dist").unwrap().source().flush()?;
+    graph.get("ref").unwrap().source().flush()?;
+    totals.collect(&mut graph);
+    totals.finish()
+}
+
+/// Graph taking the decoded frames on `dist` and the source on `ref`, handing out the scored
+/// frames on the `psnr` and `ssim` sinks
+fn metrics_graph(
+    config: &CompareConfig,
+    decoded_format: ffmpeg::format::Pixel,
+) -> Result<ffmpeg::filter::Graph> {
+    let mut graph = ffmpeg::filter::Graph::new();
+    let buffer = ffmpeg_compat::find_filter("buffer")?;
+    let buffersink = ffmpeg_compat::find_filter("buffersink")?;
+    ffmpeg_compat::find_filter("psnr")?;
+    ffmpeg_compat::find_filter("ssim")?;
+
+    let args = |format: ffmpeg::format::Pixel| {
+        format!(
+            "video_size={}x{}:pix_fmt={}:time_base=1/{}",
+            config.width,
+            config.height,
+            AVPixelFormat::from(format) as i32,
+            config.framerate
+        )
+    };
+    graph.add(&buffer, "dist", &args(decoded_format))?;
+    graph.add(&buffer, "ref", &args(ffmpeg::format::Pixel::NV12))?;
+    graph.add(&buffersink, "psnr", "")?;
+    graph.add(&buffersink, "ssim", "")?;
+
+    // Both sides in the decoder's format, the metrics compare plane by plane
+    let format = decoded_format
+        .descriptor()
+        .map(|descriptor| descriptor.name())
+        .unwrap_or("yuv420p");
+    let spec = format!(
+        "[dist]split[d0][d1];[ref]format={format},split[r0][r1];\
+         [d0][r0]psnr[psnr];[d1][r1]ssim[ssim]"
+    );
+    graph
+        .output("dist", 0)?
+        .output("ref", 0)?
+        .input("psnr", 0)?
+        .input("ssim", 0)?
+        .parse(&spec)?;
+    graph.validate()?;
+    Ok(graph)
+}
+
+#[derive(Debug, Default)]
+struct MetricTotals {
+    mse: f64,
+    mse_frames: u64,
+    ssim: f64,
+    ssim_frames: u64,
+}
+
+impl MetricTotals {
+    /// Read the per frame values the filters left in the metadata of their output
+    fn collect(&mut self, graph: &mut ffmpeg::filter::Graph) {
+        let mut frame = ffmpeg::util::frame::Video::empty();
+        while graph.get("psnr").unwrap().sink().frame(&mut frame).is_ok() {
+            if let Some(mse) = metadata_value(&frame, "lavfi.psnr.mse_avg") {
+                self.mse += mse;
+                self.mse_frames += 1;
+            }
+        }
+        while graph.get("ssim").unwrap().sink().frame(&mut frame).is_ok() {
+            if let Some(ssim) = metadata_value(&frame, "lavfi.ssim.All") {
+                self.ssim += ssim;
+                self.ssim_frames += 1;
+            }
+        }
+    }
+
+    /// PSNR of the mean squared error over the clip, which unlike averaging per frame PSNR
+    /// does not let a few perfect frames hide the bad ones
+    fn finish(&self) -> Result<(f64, f64)> {
+        if self.mse_frames == 0 || self.ssim_frames == 0 {
+            return Err(WaycapError::Encoding(
+                "The metric filters scored no frames".to_string(),
+            ));
+        }
+        let mse = self.mse / self.mse_frames as f64;
+        let psnr = if mse == 0.0 {
+            f64::INFINITY
+        } else {
+            10.0 * (255.0 * 255.0 / mse).log10()
+        };
+        Ok((psnr, self.ssim / self.ssim_frames as f64))
+    }
+}
+
+fn metadata_value(frame: &ffmpeg::util::frame::Video, key: &str) -> Option<f64> {
+    frame.metadata().get(key)?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// libx264 is not in every ffmpeg build, so the comparison has to skip rather than fail
+    #[test]
+    fn skips_what_the_machine_lacks() {
+        let config = CompareConfig {
+            width: 320,
+            height: 240,
+            framerate: 30,
+            bitrate: 1_000_000,
+        };
+        let report = compare_backends(&config, 1).unwrap();
+        assert_eq!(report.backends.len() + report.skipped.len(), BACKENDS.len());
+        for backend in &report.backends {
+            assert_eq!(backend.frames, 30);
+            assert!(backend.psnr > 20.0, "{report}");
+            assert!(backend.ssim > 0.5 && backend.ssim <= 1.0, "{report}");
+        }
+    }
+
+    #[test]
+    fn rejects_empty_clips() {
+        let result = compare_backends(&CompareConfig::default(), 0);
+        assert!(matches!(result, Err(WaycapError::Validation(_))));
+    }
+}
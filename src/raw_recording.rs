@@ -0,0 +1,473 @@
+//! Recording of the raw frames handed to the video encoder, and replaying them.
+//!
+//! Bugs like corruption a few minutes into a recording depend on the exact frames the encoder
+//! saw. With the `raw-recording` feature a capture can dump them to a file through
+//! [`crate::CaptureControls::raw_recorder`], and [`replay_raw_capture`] feeds that file through
+//! an encoder again, as often as needed. Recordings are uncompressed, a minute of 1080p60 is
+//! around 30 GB, so recording is never on by default. The crate internal hook below compiles
+//! down to a no-op without the feature.
+//!
+//! The file starts with [`MAGIC`] and a little endian `u32` version, followed by one record per
+//! frame: timestamp `i64`, width, height, SPA video format and stride as `u32`, DRM modifier
+//! `u64`, payload length `u32` and the pixels of all planes starting at the frame's offset.
+
+#[cfg(feature = "raw-recording")]
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    path::Path,
+    ptr::null_mut,
+    sync::Mutex,
+};
+
+#[cfg(feature = "raw-recording")]
+use pipewire::spa::{param::video::VideoFormat, utils::Rectangle};
+
+#[cfg(feature = "raw-recording")]
+use crate::{
+    encoders::{
+        dynamic_encoder::DynamicEncoder,
+        video::{ProcessingThread, VideoEncoder},
+    },
+    runtime::Runtime,
+    types::{
+        config::{Transform, VideoConfig, VideoEncoder as VideoEncoderType},
+        error::{Result, WaycapError},
+        event::EventSender,
+        time::CaptureTime,
+        video_frame::EncodedVideoFrame,
+    },
+};
+use crate::{types::video_frame::RawVideoFrame, CaptureControls};
+
+/// First bytes of every raw recording
+#[cfg(feature = "raw-recording")]
+pub const MAGIC: &[u8; 8] = b"WAYCAPRF";
+#[cfg(feature = "raw-recording")]
+const VERSION: u32 = 1;
+/// The DRM modifier of linear buffers, the only layout which can be read through a mapping
+#[cfg(feature = "raw-recording")]
+const LINEAR_MODIFIER: u64 = 0;
+
+/// Handle to record the raw frames of a running capture. Recordings are uncompressed, so this
+/// is meant for reproducing bugs, not for keeping. Get one with
+/// [`crate::CaptureControls::raw_recorder`].
+#[cfg(feature = "raw-recording")]
+#[derive(Debug, Default)]
+pub struct RawRecorder {
+    writer: Mutex<Option<BufWriter<File>>>,
+}
+
+#[cfg(feature = "raw-recording")]
+impl RawRecorder {
+    /// Start writing every frame handed to the encoder to `path`, replacing a running recording
+    pub fn start(&self, path: impl AsRef<Path>) -> Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        if let Some(mut previous) = self.writer.lock().unwrap().replace(writer) {
+            previous.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Finish the recording, a no-op when none is running
+    pub fn stop(&self) -> Result<()> {
+        if let Some(mut writer) = self.writer.lock().unwrap().take() {
+            writer.flush()?;
+        }
+        Ok(())
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.writer.lock().unwrap().is_some()
+    }
+
+    fn record(&self, frame: &RawVideoFrame) {
+        let mut writer = self.writer.lock().unwrap();
+        let Some(out) = writer.as_mut() else {
+            return;
+        };
+        let result = frame_payload(frame).and_then(|payload| write_frame(out, frame, &payload));
+        if let Err(e) = result {
+            // Losing the recording must not take the capture down with it
+            log::error!("Stopping the raw recording: {e}");
+            *writer = None;
+        }
+    }
+}
+
+/// Append `frame` to the raw recording of the capture, if one is running
+#[cfg_attr(not(feature = "raw-recording"), allow(unused_variables))]
+pub(crate) fn record(controls: &CaptureControls, frame: &RawVideoFrame) {
+    #[cfg(feature = "raw-recording")]
+    controls.raw_recorder().record(frame);
+}
+
+/// Bytes of all planes of a frame of `format`
+#[cfg(feature = "raw-recording")]
+fn payload_len(format: VideoFormat, stride: u32, height: u32) -> usize {
+    let plane = stride as usize * height as usize;
+    match format {
+        VideoFormat::NV12 | VideoFormat::I420 => plane + plane / 2,
+        _ => plane,
+    }
+}
+
+/// Pixels of `frame`, copied out of the shared memory or the DMA-BUF
+#[cfg(feature = "raw-recording")]
+fn frame_payload(frame: &RawVideoFrame) -> Result<Vec<u8>> {
+    let len = payload_len(frame.format, frame.stride as u32, frame.dimensions.height);
+    let start = frame.offset as usize;
+    if !frame.data.is_empty() {
+        return frame
+            .data
+            .get(start..start + len)
+            .map(<[u8]>::to_vec)
+            .ok_or_else(|| {
+                WaycapError::Validation(format!(
+                    "Frame of {} bytes is too small for {len} bytes at offset {start}",
+                    frame.data.len()
+                ))
+            });
+    }
+    match frame.dmabuf_fd {
+        Some(fd) if frame.modifier == LINEAR_MODIFIER => read_dmabuf(fd, start, len),
+        Some(_) => Err(WaycapError::Unsupported(format!(
+            "Can't read DMA-BUFs with modifier {:#x}, only linear ones",
+            frame.modifier
+        ))),
+        None => Err(WaycapError::Validation(
+            "Frame has neither data nor a DMA-BUF".to_string(),
+        )),
+    }
+}
+
+/// `DMA_BUF_IOCTL_SYNC` and its flags from `linux/dma-buf.h`
+#[cfg(feature = "raw-recording")]
+const DMA_BUF_IOCTL_SYNC: libc::c_ulong = 0x4008_6200;
+#[cfg(feature = "raw-recording")]
+const DMA_BUF_SYNC_READ: u64 = 1;
+#[cfg(feature = "raw-recording")]
+const DMA_BUF_SYNC_END: u64 = 4;
+
+#[cfg(feature = "raw-recording")]
+fn read_dmabuf(fd: RawFd, offset: usize, len: usize) -> Result<Vec<u8>> {
+    let map_len = offset + len;
+    unsafe {
+        let map = libc::mmap(
+            null_mut(),
+            map_len,
+            libc::PROT_READ,
+            libc::MAP_SHARED,
+            fd,
+            0,
+        );
+        if map == libc::MAP_FAILED {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        // Waits for the GPU to finish writing the buffer before it is read
+        let mut sync = DMA_BUF_SYNC_READ;
+        libc::ioctl(fd, DMA_BUF_IOCTL_SYNC, &mut sync);
+        let payload = std::slice::from_raw_parts((map as *const u8).add(offset), len).to_vec();
+        sync = DMA_BUF_SYNC_READ | DMA_BUF_SYNC_END;
+        libc::ioctl(fd, DMA_BUF_IOCTL_SYNC, &mut sync);
+        libc::munmap(map, map_len);
+        Ok(payload)
+    }
+}
+
+#[cfg(feature = "raw-recording")]
+fn write_frame(out: &mut impl Write, frame: &RawVideoFrame, payload: &[u8]) -> Result<()> {
+    out.write_all(&frame.timestamp.as_nanos().to_le_bytes())?;
+    out.write_all(&frame.dimensions.width.to_le_bytes())?;
+    out.write_all(&frame.dimensions.height.to_le_bytes())?;
+    out.write_all(&frame.format.as_raw().to_le_bytes())?;
+    out.write_all(&(frame.stride as u32).to_le_bytes())?;
+    out.write_all(&frame.modifier.to_le_bytes())?;
+    out.write_all(&(payload.len() as u32).to_le_bytes())?;
+    out.write_all(payload)?;
+    Ok(())
+}
+
+/// One frame read back from a raw recording
+#[cfg(feature = "raw-recording")]
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordedFrame {
+    pub timestamp: CaptureTime,
+    pub width: u32,
+    pub height: u32,
+    pub format: VideoFormat,
+    pub stride: u32,
+    pub modifier: u64,
+    /// Pixels of all planes, the first one starting at offset 0
+    pub data: Vec<u8>,
+}
+
+/// Reader for the files written by [`RawRecorder`], iterating over their frames
+#[cfg(feature = "raw-recording")]
+pub struct RawRecording<R = BufReader<File>> {
+    reader: R,
+}
+
+#[cfg(feature = "raw-recording")]
+impl RawRecording {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Self::new(BufReader::new(File::open(path)?))
+    }
+}
+
+#[cfg(feature = "raw-recording")]
+impl<R: Read> RawRecording<R> {
+    pub fn new(mut reader: R) -> Result<Self> {
+        let mut magic = [0; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(WaycapError::Validation(
+                "Not a waycap raw recording".to_string(),
+            ));
+        }
+        let version = u32::from_le_bytes(read_array(&mut reader)?);
+        if version != VERSION {
+            return Err(WaycapError::Unsupported(format!(
+                "Raw recording version {version}, only {VERSION} is supported"
+            )));
+        }
+        Ok(Self { reader })
+    }
+
+    fn read_frame(&mut self) -> Result<Option<RecordedFrame>> {
+        let mut timestamp = [0; 8];
+        // A recording cut off inside the header of a frame ends like a complete one
+        match self.reader.read_exact(&mut timestamp) {
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            result => result?,
+        }
+        let reader = &mut self.reader;
+        let width = u32::from_le_bytes(read_array(reader)?);
+        let height = u32::from_le_bytes(read_array(reader)?);
+        let format = VideoFormat::from_raw(u32::from_le_bytes(read_array(reader)?));
+        let stride = u32::from_le_bytes(read_array(reader)?);
+        let modifier = u64::from_le_bytes(read_array(reader)?);
+        let len = u32::from_le_bytes(read_array(reader)?) as usize;
+        if len < payload_len(format, stride, height) {
+            return Err(WaycapError::Validation(format!(
+                "Recorded frame of {len} bytes is too small for {width}x{height} {format:?}"
+            )));
+        }
+        let mut data = vec![0; len];
+        reader.read_exact(&mut data)?;
+        Ok(Some(RecordedFrame {
+            timestamp: CaptureTime::from_nanos(i64::from_le_bytes(timestamp)),
+            width,
+            height,
+            format,
+            stride,
+            modifier,
+            data,
+        }))
+    }
+}
+
+#[cfg(feature = "raw-recording")]
+impl<R: Read> Iterator for RawRecording<R> {
+    type Item = Result<RecordedFrame>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_frame().transpose()
+    }
+}
+
+#[cfg(feature = "raw-recording")]
+fn read_array<const N: usize>(reader: &mut impl Read) -> Result<[u8; N]> {
+    let mut bytes = [0; N];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// Encode the frames of the raw recording at `path` with `encoder`, exactly as a capture
+/// handed them over, and return the encoded frames.
+///
+/// The hardware encoders only take DMA-BUFs, every frame is put into one through
+/// `/dev/udmabuf`, which needs read and write access to it. Like at the end of a capture, the
+/// packets still inside the encoder after the last frame are dropped by the drain.
+#[cfg(feature = "raw-recording")]
+pub fn replay_raw_capture(
+    path: impl AsRef<Path>,
+    encoder: VideoEncoderType,
+    config: VideoConfig,
+) -> Result<Vec<EncodedVideoFrame>> {
+    let _runtime = Runtime::acquire()?;
+    let mut recording = RawRecording::open(path)?.peekable();
+    let (width, height) = match recording.peek() {
+        Some(Ok(frame)) => (frame.width, frame.height),
+        Some(Err(_)) => return Err(recording.next().unwrap().unwrap_err()),
+        None => return Ok(Vec::new()),
+    };
+
+    let (event_tx, _event_rx) = crossbeam::channel::bounded(1);
+    let mut encoder = DynamicEncoder::new(
+        Some(encoder),
+        width,
+        height,
+        config,
+        &EventSender::new(event_tx),
+    )?;
+    let output = encoder
+        .output()
+        .ok_or_else(|| WaycapError::Init("Encoder has no output".to_string()))?;
+    encoder.thread_setup()?;
+
+    let mut encoded = Vec::new();
+    let mut size = (width, height);
+    let result = recording.try_for_each(|frame| -> Result<()> {
+        let frame = frame?;
+        if (frame.width, frame.height) != size {
+            size = (frame.width, frame.height);
+            encoder.source_changed(frame.width, frame.height)?;
+        }
+        // Kept open until the encoder is done with the frame
+        let dmabuf = udmabuf_from(&frame.data)?;
+        encoder.process(RawVideoFrame {
+            timestamp: frame.timestamp,
+            dmabuf_fd: Some(dmabuf.as_raw_fd()),
+            stride: frame.stride as i32,
+            offset: 0,
+            size: frame.data.len() as u32,
+            modifier: frame.modifier,
+            format: frame.format,
+            dimensions: Rectangle {
+                width: frame.width,
+                height: frame.height,
+            },
+            data: frame.data,
+            // Recordings don't keep the compositor's transform
+            transform: Transform::Normal,
+            force_keyframe: false,
+            damaged: None,
+            planes: Vec::new(),
+            fence: None,
+            owned_fds: Vec::new(),
+        })?;
+        encoded.extend(output.try_iter());
+        Ok(())
+    });
+    let finished = result.and_then(|_| encoder.drain());
+    encoder.thread_teardown()?;
+    finished?;
+    encoded.extend(output.try_iter());
+    Ok(encoded)
+}
+
+/// `struct udmabuf_create` from `linux/udmabuf.h`
+#[cfg(feature = "raw-recording")]
+#[repr(C)]
+struct UdmabufCreate {
+    memfd: u32,
+    flags: u32,
+    offset: u64,
+    size: u64,
+}
+
+#[cfg(feature = "raw-recording")]
+const UDMABUF_CREATE: libc::c_ulong = 0x4018_7542;
+#[cfg(feature = "raw-recording")]
+const UDMABUF_FLAGS_CLOEXEC: u32 = 1;
+
+/// A DMA-BUF holding a copy of `data`, backed by a sealed memfd
+#[cfg(feature = "raw-recording")]
+fn udmabuf_from(data: &[u8]) -> Result<OwnedFd> {
+    let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+    let size = data.len().div_ceil(page) * page;
+    let memfd = unsafe {
+        let fd = libc::memfd_create(
+            c"waycap-replay".as_ptr(),
+            libc::MFD_ALLOW_SEALING | libc::MFD_CLOEXEC,
+        );
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        File::from(OwnedFd::from_raw_fd(fd))
+    };
+    memfd.set_len(size as u64)?;
+    (&memfd).write_all(data)?;
+    // udmabuf refuses memfds which could shrink under the buffer
+    if unsafe { libc::fcntl(memfd.as_raw_fd(), libc::F_ADD_SEALS, libc::F_SEAL_SHRINK) } < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+
+    let device = File::options()
+        .read(true)
+        .write(true)
+        .open("/dev/udmabuf")
+        .map_err(|e| WaycapError::Device(format!("Could not open /dev/udmabuf: {e}")))?;
+    let create = UdmabufCreate {
+        memfd: memfd.as_raw_fd() as u32,
+        flags: UDMABUF_FLAGS_CLOEXEC,
+        offset: 0,
+        size: size as u64,
+    };
+    let fd = unsafe { libc::ioctl(device.as_raw_fd(), UDMABUF_CREATE, &create) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+#[cfg(all(test, feature = "raw-recording"))]
+mod tests {
+    use super::*;
+
+    fn frame(timestamp: i64, fill: u8) -> RawVideoFrame {
+        RawVideoFrame {
+            // 16 bytes of padding in front, like a buffer with an offset
+            data: vec![fill; 16 + 8 * 4 * 3],
+            timestamp: CaptureTime::from_nanos(timestamp),
+            offset: 16,
+            modifier: LINEAR_MODIFIER,
+            ..RawVideoFrame::bgrx(8, 3)
+        }
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        let mut file = Vec::new();
+        file.extend_from_slice(MAGIC);
+        file.extend_from_slice(&VERSION.to_le_bytes());
+        for (timestamp, fill) in [(1_000, 1), (17_000, 2)] {
+            let frame = frame(timestamp, fill);
+            write_frame(&mut file, &frame, &frame_payload(&frame).unwrap()).unwrap();
+        }
+        // Cut off in the middle of the next header
+        file.extend_from_slice(&[0; 5]);
+
+        let frames: Vec<_> = RawRecording::new(file.as_slice())
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[1].timestamp, CaptureTime::from_nanos(17_000));
+        assert_eq!(frames[1].format, VideoFormat::BGRx);
+        assert_eq!((frames[1].width, frames[1].stride), (8, 32));
+        assert_eq!(frames[1].data, vec![2; 8 * 4 * 3]);
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        let result = RawRecording::new(&b"\x89PNG\r\n\x1a\n\0\0\0\0"[..]);
+        assert!(matches!(result, Err(WaycapError::Validation(_))));
+    }
+
+    #[test]
+    fn rejects_tiled_dmabufs() {
+        let mut frame = frame(0, 0);
+        frame.data.clear();
+        frame.dmabuf_fd = Some(0);
+        frame.modifier = 0x0100_0000_0000_0001;
+        assert!(matches!(
+            frame_payload(&frame),
+            Err(WaycapError::Unsupported(_))
+        ));
+    }
+}
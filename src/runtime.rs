@@ -0,0 +1,107 @@
+//! Process wide initialization of ffmpeg and PipeWire shared by all captures.
+//!
+//! Every [`crate::Capture`] holds a [`Runtime`] for its whole lifetime. The first one to be
+//! acquired initializes the libraries, the last one to be dropped hands PipeWire back. Other
+//! process wide state is set up once and kept on purpose:
+//! - the ffmpeg log callback, see [`crate::ffmpeg_log`]
+//! - the ffmpeg error strings and device registry, which ffmpeg never frees
+//! - pipewire-rs' own `pw_init` reference, taken the first time a `MainLoop` is created, which
+//!   keeps PipeWire alive for any `MainLoop` created outside of a capture
+//!
+//! EGL displays are not shared, each [`crate::waycap_egl::EglContext`] connects to Wayland on
+//! its own so terminating one display can't affect another capture.
+
+use std::{ptr::null_mut, sync::Mutex};
+
+use crate::{ffmpeg_compat, ffmpeg_log, types::error::Result};
+
+/// Number of live [`Runtime`]s
+static USERS: Mutex<usize> = Mutex::new(0);
+
+/// Keeps ffmpeg and PipeWire initialized while held
+#[derive(Debug)]
+pub(crate) struct Runtime(());
+
+impl Runtime {
+    pub fn acquire() -> Result<Self> {
+        let mut users = USERS.lock().unwrap();
+        if *users == 0 {
+            ffmpeg_next::init()?;
+            ffmpeg_compat::check_runtime()?;
+            ffmpeg_log::install();
+            // PipeWire counts its own init calls, this pairs with the pw_deinit in drop
+            unsafe { pipewire::sys::pw_init(null_mut(), null_mut()) };
+        }
+        *users += 1;
+        Ok(Self(()))
+    }
+}
+
+impl Drop for Runtime {
+    fn drop(&mut self) {
+        let mut users = USERS.lock().unwrap();
+        *users -= 1;
+        if *users == 0 {
+            unsafe { pipewire::sys::pw_deinit() };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::Barrier,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use crate::{pipeline::builder::CaptureBuilder, types::config::VideoEncoder};
+
+    use super::*;
+
+    /// A capture of the synthetic source encoding for `duration`, iterating a PipeWire loop of
+    /// its own meanwhile
+    fn run_session(duration: Duration) {
+        let mut capture = CaptureBuilder::new()
+            .with_synthetic_source(64, 64)
+            .with_video_encoder(VideoEncoder::H264Software)
+            .build()
+            .unwrap();
+        // Other tests hold runtimes too, so only this session's one is certain
+        assert!(*USERS.lock().unwrap() >= 1);
+        let main_loop = pipewire::main_loop::MainLoop::new(None).unwrap();
+        let video = capture.get_video_receiver().unwrap();
+
+        capture.start().unwrap();
+        let started = Instant::now();
+        let mut packets = 0;
+        while started.elapsed() < duration {
+            packets += video.recv_timeout(Duration::from_millis(10)).iter().count();
+            main_loop.loop_().iterate(Duration::ZERO);
+        }
+        capture.finish().unwrap();
+        packets += video.try_iter().count();
+        drop(main_loop);
+        capture.close().unwrap();
+        assert!(packets > 0);
+    }
+
+    /// Run under ThreadSanitizer with
+    /// `RUSTFLAGS=-Zsanitizer=thread cargo +nightly test -Zbuild-std --target <triple> runtime`
+    #[test]
+    fn overlapping_sessions_share_the_runtime() {
+        const SESSIONS: u64 = 4;
+        let start = Barrier::new(SESSIONS as usize);
+        thread::scope(|scope| {
+            for i in 0..SESSIONS {
+                let start = &start;
+                scope.spawn(move || {
+                    start.wait();
+                    // Different lengths so sessions start and end while others are running
+                    run_session(Duration::from_millis(100 + i * 150));
+                    run_session(Duration::from_millis(50));
+                });
+            }
+        });
+    }
+}
@@ -0,0 +1,451 @@
+//! Instrumentation for hunting slow memory growth over captures lasting hours.
+//!
+//! With the `soak-test` feature [`run_soak`] samples a running capture every
+//! [`SoakConfig::snapshot_interval`]: heap usage through [`CountingAllocator`], the resident set
+//! size, the frames and packets waiting in the pipeline queues, and the live count of every
+//! [`ObjectKind`]. Once the warmup is over, the growth of each is fitted over time and the run
+//! fails when one keeps growing faster than its threshold, naming what grew. The crate internal
+//! hooks below compile down to no-ops without the feature.
+
+#[cfg(feature = "soak-test")]
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+#[cfg(feature = "soak-test")]
+use crate::{
+    pipeline::shutdown::StreamKind,
+    types::{
+        error::{Result, WaycapError},
+        stats::CaptureStats,
+    },
+    Capture, DynamicEncoder,
+};
+
+/// Objects whose creation and destruction are counted, the ones a leak is most likely to hide in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ObjectKind {
+    /// DRM frame descriptors attached to the frames handed to VAAPI
+    DrmDescriptor,
+    /// References to ffmpeg hardware device and frames contexts
+    HwContext,
+}
+
+impl ObjectKind {
+    #[cfg(feature = "soak-test")]
+    const COUNT: usize = 2;
+    #[cfg(feature = "soak-test")]
+    pub const ALL: [ObjectKind; Self::COUNT] = [ObjectKind::DrmDescriptor, ObjectKind::HwContext];
+}
+
+#[cfg(feature = "soak-test")]
+static LIVE_OBJECTS: [AtomicI64; ObjectKind::COUNT] = [AtomicI64::new(0), AtomicI64::new(0)];
+
+/// Count a new object of `kind`
+#[cfg_attr(not(feature = "soak-test"), allow(unused_variables))]
+pub(crate) fn object_created(kind: ObjectKind) {
+    #[cfg(feature = "soak-test")]
+    LIVE_OBJECTS[kind as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Count an object of `kind` going away
+#[cfg_attr(not(feature = "soak-test"), allow(unused_variables))]
+pub(crate) fn object_destroyed(kind: ObjectKind) {
+    #[cfg(feature = "soak-test")]
+    LIVE_OBJECTS[kind as usize].fetch_sub(1, Ordering::Relaxed);
+}
+
+/// Objects of `kind` currently alive in the process
+#[cfg(feature = "soak-test")]
+pub fn live_objects(kind: ObjectKind) -> i64 {
+    LIVE_OBJECTS[kind as usize].load(Ordering::Relaxed)
+}
+
+#[cfg(feature = "soak-test")]
+static HEAP_INSTALLED: AtomicBool = AtomicBool::new(false);
+#[cfg(feature = "soak-test")]
+static HEAP_LIVE_BYTES: AtomicI64 = AtomicI64::new(0);
+#[cfg(feature = "soak-test")]
+static HEAP_LIVE_ALLOCATIONS: AtomicI64 = AtomicI64::new(0);
+#[cfg(feature = "soak-test")]
+static HEAP_TOTAL_ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+/// Allocator wrapper counting the Rust heap usage of the process, install it with
+/// `#[global_allocator] static ALLOC: CountingAllocator = CountingAllocator::new(System);`.
+///
+/// Only sees Rust allocations, what ffmpeg, PipeWire and the drivers allocate shows up in the
+/// resident set size of the [`SoakSnapshot`] instead.
+#[cfg(feature = "soak-test")]
+#[derive(Debug, Default)]
+pub struct CountingAllocator<A = System> {
+    inner: A,
+}
+
+#[cfg(feature = "soak-test")]
+impl<A> CountingAllocator<A> {
+    pub const fn new(inner: A) -> Self {
+        Self { inner }
+    }
+}
+
+#[cfg(feature = "soak-test")]
+fn count_allocation(bytes: usize) {
+    HEAP_INSTALLED.store(true, Ordering::Relaxed);
+    HEAP_LIVE_BYTES.fetch_add(bytes as i64, Ordering::Relaxed);
+    HEAP_LIVE_ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+    HEAP_TOTAL_ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+}
+
+#[cfg(feature = "soak-test")]
+unsafe impl<A: GlobalAlloc> GlobalAlloc for CountingAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.inner.alloc(layout);
+        if !ptr.is_null() {
+            count_allocation(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.inner.alloc_zeroed(layout);
+        if !ptr.is_null() {
+            count_allocation(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.inner.dealloc(ptr, layout);
+        HEAP_LIVE_BYTES.fetch_sub(layout.size() as i64, Ordering::Relaxed);
+        HEAP_LIVE_ALLOCATIONS.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = self.inner.realloc(ptr, layout, new_size);
+        if !new_ptr.is_null() {
+            HEAP_LIVE_BYTES.fetch_add(new_size as i64 - layout.size() as i64, Ordering::Relaxed);
+        }
+        new_ptr
+    }
+}
+
+/// Rust heap usage counted by [`CountingAllocator`]
+#[cfg(feature = "soak-test")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapUsage {
+    pub live_bytes: i64,
+    pub live_allocations: i64,
+    pub total_allocations: u64,
+}
+
+/// Current heap usage, `None` when [`CountingAllocator`] is not the global allocator
+#[cfg(feature = "soak-test")]
+pub fn heap_usage() -> Option<HeapUsage> {
+    HEAP_INSTALLED.load(Ordering::Relaxed).then(|| HeapUsage {
+        live_bytes: HEAP_LIVE_BYTES.load(Ordering::Relaxed),
+        live_allocations: HEAP_LIVE_ALLOCATIONS.load(Ordering::Relaxed),
+        total_allocations: HEAP_TOTAL_ALLOCATIONS.load(Ordering::Relaxed),
+    })
+}
+
+/// Resident set size of the process from `/proc/self/statm`
+#[cfg(feature = "soak-test")]
+fn rss_bytes() -> Option<u64> {
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    Some(pages * page_size.max(0) as u64)
+}
+
+/// Settings for [`run_soak`]
+#[cfg(feature = "soak-test")]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SoakConfig {
+    /// How long to sample the capture
+    pub duration: Duration,
+    pub snapshot_interval: Duration,
+    /// Time left out of the growth fit, while pools, caches and queues fill up
+    pub warmup: Duration,
+    /// Heap or resident set growth per hour above which the run fails
+    pub max_bytes_per_hour: f64,
+    /// Growth of the live objects of one kind or of a queue per hour above which the run fails
+    pub max_objects_per_hour: f64,
+}
+
+#[cfg(feature = "soak-test")]
+impl Default for SoakConfig {
+    fn default() -> Self {
+        Self {
+            duration: Duration::from_secs(6 * 60 * 60),
+            snapshot_interval: Duration::from_secs(60),
+            warmup: Duration::from_secs(10 * 60),
+            max_bytes_per_hour: 8.0 * 1024.0 * 1024.0,
+            max_objects_per_hour: 60.0,
+        }
+    }
+}
+
+/// State of the process and capture at one point of a soak run
+#[cfg(feature = "soak-test")]
+#[derive(Debug, Clone)]
+pub struct SoakSnapshot {
+    /// Time since the start of the run
+    pub elapsed: Duration,
+    pub heap: Option<HeapUsage>,
+    pub rss_bytes: Option<u64>,
+    /// Live count of every [`ObjectKind`], in the order of [`ObjectKind::ALL`]
+    pub objects: [i64; ObjectKind::COUNT],
+    /// Raw frames queued for or inside the video and audio encoders
+    pub raw_video_pending: u64,
+    pub raw_audio_pending: u64,
+    /// Encoded video packets waiting in the receivers' channels
+    pub encoded_video_queued: usize,
+    pub stats: CaptureStats,
+}
+
+/// Something which kept growing after the warmup
+#[cfg(feature = "soak-test")]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Growth {
+    /// What grew, e.g. `heap bytes` or `DrmDescriptor`
+    pub what: String,
+    pub per_hour: f64,
+}
+
+/// Outcome of [`run_soak`]
+#[cfg(feature = "soak-test")]
+#[derive(Debug, Clone)]
+pub struct SoakReport {
+    pub config: SoakConfig,
+    pub snapshots: Vec<SoakSnapshot>,
+    /// Fitted growth per hour of everything sampled, after the warmup
+    pub growth: Vec<Growth>,
+    /// The entries of `growth` above their threshold
+    pub exceeded: Vec<Growth>,
+}
+
+#[cfg(feature = "soak-test")]
+impl SoakReport {
+    /// Fail with the growth which exceeded its threshold
+    pub fn check(&self) -> Result<()> {
+        if self.exceeded.is_empty() {
+            return Ok(());
+        }
+        let exceeded: Vec<String> = self
+            .exceeded
+            .iter()
+            .map(|growth| format!("{} by {:.1}/h", growth.what, growth.per_hour))
+            .collect();
+        Err(WaycapError::Other(format!(
+            "Steady state growth over the threshold: {}",
+            exceeded.join(", ")
+        )))
+    }
+}
+
+/// Sample `capture` until [`SoakConfig::duration`] passed or the capture stopped.
+///
+/// The capture has to be started, and its output consumed by another thread, like a muxer
+/// would. A full receiver drops packets, which hides growth inside the pipeline.
+#[cfg(feature = "soak-test")]
+pub fn run_soak(capture: &Capture<DynamicEncoder>, config: &SoakConfig) -> Result<SoakReport> {
+    if config.snapshot_interval.is_zero() {
+        return Err(WaycapError::Validation(
+            "Soak snapshot interval must not be zero".to_string(),
+        ));
+    }
+    let encoded_queue = capture
+        .video_encoder
+        .as_ref()
+        .map(|encoder| encoder.lock().unwrap().output_queues());
+
+    let start = Instant::now();
+    let mut snapshots = Vec::new();
+    loop {
+        snapshots.push(SoakSnapshot {
+            elapsed: start.elapsed(),
+            heap: heap_usage(),
+            rss_bytes: rss_bytes(),
+            objects: ObjectKind::ALL.map(live_objects),
+            raw_video_pending: capture.controls.cutoff().pending(StreamKind::Video),
+            raw_audio_pending: capture.controls.cutoff().pending(StreamKind::Audio),
+            encoded_video_queued: encoded_queue.as_ref().map_or(0, |queue| queue.len()),
+            stats: capture.stats(),
+        });
+        log::info!("Soak snapshot: {:?}", snapshots.last().unwrap());
+
+        let next = config.snapshot_interval * snapshots.len() as u32;
+        if next > config.duration || capture.controls.is_stopped() {
+            break;
+        }
+        std::thread::sleep(next.saturating_sub(start.elapsed()));
+    }
+
+    let growth = fit_growth(&snapshots, config.warmup);
+    let exceeded = growth
+        .iter()
+        .filter(|growth| {
+            let limit = if growth.what.ends_with("bytes") {
+                config.max_bytes_per_hour
+            } else {
+                config.max_objects_per_hour
+            };
+            growth.per_hour > limit
+        })
+        .cloned()
+        .collect();
+    Ok(SoakReport {
+        config: *config,
+        snapshots,
+        growth,
+        exceeded,
+    })
+}
+
+/// Growth per hour of every sampled value over the snapshots after `warmup`
+#[cfg(feature = "soak-test")]
+fn fit_growth(snapshots: &[SoakSnapshot], warmup: Duration) -> Vec<Growth> {
+    let steady: Vec<&SoakSnapshot> = snapshots
+        .iter()
+        .filter(|snapshot| snapshot.elapsed >= warmup)
+        .collect();
+    let series: Vec<(String, Vec<Option<f64>>)> = [
+        (
+            "heap bytes".to_string(),
+            steady
+                .iter()
+                .map(|s| s.heap.map(|heap| heap.live_bytes as f64))
+                .collect(),
+        ),
+        (
+            "rss bytes".to_string(),
+            steady
+                .iter()
+                .map(|s| s.rss_bytes.map(|rss| rss as f64))
+                .collect(),
+        ),
+        (
+            "raw video frames".to_string(),
+            steady
+                .iter()
+                .map(|s| Some(s.raw_video_pending as f64))
+                .collect(),
+        ),
+        (
+            "raw audio frames".to_string(),
+            steady
+                .iter()
+                .map(|s| Some(s.raw_audio_pending as f64))
+                .collect(),
+        ),
+        (
+            "encoded video packets".to_string(),
+            steady
+                .iter()
+                .map(|s| Some(s.encoded_video_queued as f64))
+                .collect(),
+        ),
+    ]
+    .into_iter()
+    .chain(ObjectKind::ALL.iter().enumerate().map(|(i, kind)| {
+        (
+            format!("{kind:?}"),
+            steady.iter().map(|s| Some(s.objects[i] as f64)).collect(),
+        )
+    }))
+    .collect();
+
+    let hours: Vec<f64> = steady
+        .iter()
+        .map(|s| s.elapsed.as_secs_f64() / 3600.0)
+        .collect();
+    series
+        .into_iter()
+        .filter_map(|(what, values)| {
+            let values: Option<Vec<f64>> = values.into_iter().collect();
+            Some(Growth {
+                what,
+                per_hour: slope(&hours, &values?)?,
+            })
+        })
+        .collect()
+}
+
+/// Least squares slope of `ys` over `xs`, `None` with fewer than two distinct points
+#[cfg(feature = "soak-test")]
+fn slope(xs: &[f64], ys: &[f64]) -> Option<f64> {
+    let n = xs.len() as f64;
+    if xs.len() < 2 {
+        return None;
+    }
+    let mean_x = xs.iter().sum::<f64>() / n;
+    let mean_y = ys.iter().sum::<f64>() / n;
+    let covariance: f64 = xs
+        .iter()
+        .zip(ys)
+        .map(|(x, y)| (x - mean_x) * (y - mean_y))
+        .sum();
+    let variance: f64 = xs.iter().map(|x| (x - mean_x).powi(2)).sum();
+    (variance > 0.0).then(|| covariance / variance)
+}
+
+#[cfg(all(test, feature = "soak-test"))]
+mod tests {
+    use super::*;
+
+    fn snapshot(minutes: u64, heap: i64, descriptors: i64) -> SoakSnapshot {
+        SoakSnapshot {
+            elapsed: Duration::from_secs(minutes * 60),
+            heap: Some(HeapUsage {
+                live_bytes: heap,
+                live_allocations: 0,
+                total_allocations: 0,
+            }),
+            rss_bytes: None,
+            objects: [descriptors, 0],
+            raw_video_pending: 3,
+            raw_audio_pending: 0,
+            encoded_video_queued: 0,
+            stats: CaptureStats::default(),
+        }
+    }
+
+    #[test]
+    fn leaking_descriptors_are_named() {
+        // Heap grows during the warmup, then only wobbles, while a descriptor leaks per minute
+        let snapshots: Vec<_> = (0..=120)
+            .map(|minute| {
+                let heap = if minute < 10 {
+                    minute * 1_000_000
+                } else {
+                    10_000_000
+                };
+                let wobble = if minute % 2 == 0 { 4096 } else { -4096 };
+                snapshot(minute as u64, heap + wobble, minute)
+            })
+            .collect();
+        let growth = fit_growth(&snapshots, Duration::from_secs(10 * 60));
+
+        let per_hour = |what: &str| {
+            growth
+                .iter()
+                .find(|growth| growth.what == what)
+                .unwrap()
+                .per_hour
+        };
+        assert!(per_hour("heap bytes").abs() < 1024.0);
+        assert!((per_hour("DrmDescriptor") - 60.0).abs() < 0.01);
+        assert_eq!(per_hour("raw video frames"), 0.0);
+        // Without a resident set size there is nothing to fit
+        assert!(growth.iter().all(|growth| growth.what != "rss bytes"));
+    }
+
+    #[test]
+    fn slope_needs_two_points() {
+        assert_eq!(slope(&[1.0], &[5.0]), None);
+        assert_eq!(slope(&[1.0, 1.0], &[5.0, 6.0]), None);
+        assert_eq!(slope(&[0.0, 2.0], &[1.0, 5.0]), Some(2.0));
+    }
+}
@@ -1,14 +1,20 @@
+use super::time::{CaptureTime, StreamPts};
+
 #[derive(Debug)]
 pub struct EncodedAudioFrame {
     pub data: Vec<u8>,
-    pub pts: i64,
-    /// Capture timestamp in micro seconds
-    pub timestamp: i64,
+    /// Presentation timestamp in the encoder's time base (one tick per sample at 48kHz)
+    pub pts: StreamPts,
+    /// Length of this packet to play back, in the same time base as `pts`. Shorter than the
+    /// encoded frame when it was trimmed, set it as the packet duration when muxing.
+    pub duration: StreamPts,
+    /// When the first sample of the packet was captured, in nanoseconds on the capture clock
+    pub timestamp: CaptureTime,
 }
 
 #[derive(Debug)]
 pub struct RawAudioFrame {
     pub samples: Vec<f32>,
-    /// Capture timestamp in micro seconds
-    pub timestamp: i64,
+    /// When the first sample was captured
+    pub timestamp: CaptureTime,
 }
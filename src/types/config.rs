@@ -1,19 +1,1784 @@
-#[derive(Debug, Clone, Copy)]
+use std::{path::PathBuf, time::Duration};
+
+use portal_screencast_waycap::SourceType;
+
+use super::error::{Result, WaycapError};
+
+/// Video encoder backends. More are added over time, list them with [`Self::all_supported`]
+/// instead of matching exhaustively.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
 pub enum VideoEncoder {
     #[cfg(feature = "nvenc")]
     H264Nvenc,
+    /// HEVC through NVENC, smaller files than H.264 at the same quality
+    #[cfg(feature = "nvenc")]
+    H265Nvenc,
+    /// AV1 through NVENC, on RTX 40 series GPUs or newer with ffmpeg 6.0. Older GPUs fail with
+    /// [`WaycapError::Unsupported`].
+    #[cfg(feature = "nvenc")]
+    Av1Nvenc,
     H264Vaapi,
+    /// HEVC through VAAPI, on AMD GPUs from Polaris and Intel GPUs from Skylake on. Takes 10 bit
+    /// frames and writes [`VideoConfig::hdr_metadata`]. Fails with [`WaycapError::Init`] where
+    /// the driver can't encode HEVC.
+    H265Vaapi,
+    /// AV1 through VAAPI, on AMD RDNA3 and Intel Arc GPUs or newer with ffmpeg 6.1. Fails with
+    /// [`WaycapError::Init`] where the driver can't encode AV1, fall back to
+    /// [`VideoEncoder::H264Vaapi`] then.
+    Av1Vaapi,
+    /// VP9 through VAAPI, on Intel GPUs from Kaby Lake on. The packets can be muxed into WebM
+    /// as they are. Fails with [`WaycapError::Init`] where the driver can't encode VP9.
+    Vp9Vaapi,
+    /// H.264 through Intel Quick Sync Video, often faster than VAAPI on Intel GPUs. Needs
+    /// ffmpeg built with oneVPL and its runtime, only used on Intel GPUs.
+    H264Qsv,
+    /// H.264 through libx264 on the CPU, for machines without a working hardware encoder. Takes
+    /// the frames from shared memory and costs far more CPU time than the hardware encoders.
+    H264Software,
+    /// AV1 through SVT-AV1 on the CPU, for recordings where the hardware encoders lose too
+    /// much. Needs a fast CPU to keep up, and takes its frames from shared memory too.
+    Av1Svt,
+    /// Every frame a JPEG on its own, for previews sent frame by frame with the least latency.
+    /// Encoded through VAAPI where the driver can, on the CPU otherwise.
+    Mjpeg,
+    /// VP8 through libvpx on the CPU, tuned for real time, for WebRTC peers which take nothing
+    /// else. Held to [`QualityPreset::target_bitrate`] instead of a fixed quantizer.
+    Vp8,
+    /// Take H.264 the compositor already encoded instead of encoding on our side. Only used
+    /// when the compositor offers an encoded stream, otherwise the capture falls back to the
+    /// encoder detected for the GPU.
+    H264Passthrough,
+}
+
+impl VideoEncoder {
+    /// Encoders compiled into this build, e.g. for a settings UI. Use [`Self::available`] to
+    /// only list the ones this machine can run.
+    pub fn all_supported() -> &'static [VideoEncoder] {
+        &[
+            #[cfg(feature = "nvenc")]
+            VideoEncoder::H264Nvenc,
+            #[cfg(feature = "nvenc")]
+            VideoEncoder::H265Nvenc,
+            #[cfg(feature = "nvenc")]
+            VideoEncoder::Av1Nvenc,
+            VideoEncoder::H264Vaapi,
+            VideoEncoder::H265Vaapi,
+            VideoEncoder::Av1Vaapi,
+            VideoEncoder::Vp9Vaapi,
+            VideoEncoder::H264Qsv,
+            VideoEncoder::H264Software,
+            VideoEncoder::Av1Svt,
+            VideoEncoder::Mjpeg,
+            VideoEncoder::Vp8,
+            VideoEncoder::H264Passthrough,
+        ]
+    }
+
+    /// Encoders of [`Self::all_supported`] whose ffmpeg encoder and hardware device could be
+    /// opened on this machine
+    pub fn available() -> Result<Vec<VideoEncoder>> {
+        let _runtime = crate::runtime::Runtime::acquire()?;
+        Ok(Self::all_supported()
+            .iter()
+            .copied()
+            .filter(|&encoder| crate::encoders::dynamic_encoder::probe(encoder))
+            .collect())
+    }
+
+    pub fn display_name(self) -> &'static str {
+        match self {
+            #[cfg(feature = "nvenc")]
+            VideoEncoder::H264Nvenc => "H.264 (NVENC)",
+            #[cfg(feature = "nvenc")]
+            VideoEncoder::H265Nvenc => "HEVC (NVENC)",
+            #[cfg(feature = "nvenc")]
+            VideoEncoder::Av1Nvenc => "AV1 (NVENC)",
+            VideoEncoder::H264Vaapi => "H.264 (VAAPI)",
+            VideoEncoder::H265Vaapi => "HEVC (VAAPI)",
+            VideoEncoder::Av1Vaapi => "AV1 (VAAPI)",
+            VideoEncoder::Vp9Vaapi => "VP9 (VAAPI)",
+            VideoEncoder::H264Qsv => "H.264 (QSV)",
+            VideoEncoder::H264Software => "H.264 (software)",
+            VideoEncoder::Av1Svt => "AV1 (SVT-AV1)",
+            VideoEncoder::Mjpeg => "MJPEG",
+            VideoEncoder::Vp8 => "VP8 (software)",
+            VideoEncoder::H264Passthrough => "H.264 (compositor)",
+        }
+    }
+
+    pub fn description(self) -> &'static str {
+        match self {
+            #[cfg(feature = "nvenc")]
+            VideoEncoder::H264Nvenc => "Hardware H.264 encoding on NVIDIA GPUs",
+            #[cfg(feature = "nvenc")]
+            VideoEncoder::H265Nvenc => {
+                "Hardware HEVC encoding on NVIDIA GPUs, about half the bitrate of H.264"
+            }
+            #[cfg(feature = "nvenc")]
+            VideoEncoder::Av1Nvenc => {
+                "Hardware AV1 encoding on NVIDIA RTX 40 series GPUs, smaller files than HEVC"
+            }
+            VideoEncoder::H264Vaapi => "Hardware H.264 encoding on AMD and Intel GPUs",
+            VideoEncoder::H265Vaapi => {
+                "Hardware HEVC encoding on AMD and Intel GPUs, about half the bitrate of H.264"
+            }
+            VideoEncoder::Av1Vaapi => {
+                "Hardware AV1 encoding on AMD RDNA3 and Intel Arc GPUs, smaller files than H.264"
+            }
+            VideoEncoder::Vp9Vaapi => "Hardware VP9 encoding on Intel GPUs, for WebM",
+            VideoEncoder::H264Qsv => "Hardware H.264 encoding through Quick Sync on Intel GPUs",
+            VideoEncoder::H264Software => "H.264 encoded on the CPU, works without a GPU encoder",
+            VideoEncoder::Av1Svt => "AV1 encoded on the CPU, high quality but needs a fast CPU",
+            VideoEncoder::Mjpeg => "Independent JPEG frames for low latency previews",
+            VideoEncoder::Vp8 => "VP8 encoded on the CPU at a constant bitrate, for WebRTC",
+            VideoEncoder::H264Passthrough => {
+                "H.264 encoded by the compositor, falls back to the GPU's encoder"
+            }
+        }
+    }
+
+    /// Whether the encoder takes the 10 bit frames of [`VideoConfig::ten_bit`]. H.264 has no 10
+    /// bit profile the hardware encoders implement.
+    pub fn supports_ten_bit(self) -> bool {
+        matches!(self, VideoEncoder::H265Vaapi | VideoEncoder::Av1Vaapi)
+    }
+
+    /// Whether the encoder writes [`VideoConfig::hdr_metadata`] into the bitstream. AV1 (VAAPI)
+    /// has no way to and fails to build with it, the others leave it out.
+    pub fn writes_hdr_metadata(self) -> bool {
+        #[cfg(feature = "nvenc")]
+        if matches!(self, VideoEncoder::H265Nvenc | VideoEncoder::Av1Nvenc) {
+            return true;
+        }
+        matches!(self, VideoEncoder::H265Vaapi | VideoEncoder::Av1Svt)
+    }
+
+    /// Whether the encoder composites [`VideoConfig::pip`] onto the frames, the VAAPI and QSV
+    /// ones do it in their filter graph
+    pub fn supports_pip(self) -> bool {
+        matches!(
+            self,
+            VideoEncoder::H264Vaapi
+                | VideoEncoder::H265Vaapi
+                | VideoEncoder::Av1Vaapi
+                | VideoEncoder::Vp9Vaapi
+                | VideoEncoder::H264Qsv
+        )
+    }
+
+    /// Whether the encoder takes [`QualityPreset::Lossless`]. NVENC encodes it with its lossless
+    /// tune, H.264 (VAAPI) at qp 0 where the driver keeps every sample and with FFV1 on the CPU
+    /// where it doesn't. Passthrough captures encode the stream themselves for it.
+    pub fn supports_lossless(self) -> bool {
+        #[cfg(feature = "nvenc")]
+        if matches!(
+            self,
+            VideoEncoder::H264Nvenc | VideoEncoder::H265Nvenc | VideoEncoder::Av1Nvenc
+        ) {
+            return true;
+        }
+        matches!(
+            self,
+            VideoEncoder::H264Vaapi | VideoEncoder::H264Passthrough
+        )
+    }
+
+    /// Whether the encoder can spread keyframes out with [`VideoConfig::intra_refresh`]. The
+    /// VAAPI encoders of ffmpeg have no option for it.
+    pub fn supports_intra_refresh(self) -> bool {
+        #[cfg(feature = "nvenc")]
+        if matches!(self, VideoEncoder::H264Nvenc | VideoEncoder::H265Nvenc) {
+            return true;
+        }
+        false
+    }
 }
 
-#[derive(Debug, Clone, Copy)]
+/// Audio encoders. More are added over time, list them with [`Self::all`] instead of matching
+/// exhaustively.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
 pub enum AudioEncoder {
     Opus,
 }
 
-#[derive(Debug, Clone, Copy)]
+impl AudioEncoder {
+    pub fn all() -> &'static [AudioEncoder] {
+        &[AudioEncoder::Opus]
+    }
+
+    pub fn display_name(self) -> &'static str {
+        match self {
+            AudioEncoder::Opus => "Opus",
+        }
+    }
+
+    pub fn description(self) -> &'static str {
+        match self {
+            AudioEncoder::Opus => "Opus at 48kHz, good quality at low bitrates",
+        }
+    }
+}
+
+/// Quality presets, from the smallest files to the best quality. More are added over time,
+/// list them with [`Self::all`] instead of matching exhaustively.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
 pub enum QualityPreset {
     Low,
     Medium,
     High,
     Ultra,
+    /// Bit-exact frames. H.264 (VAAPI) encodes them at qp 0 where a probe shows the driver
+    /// keeps every sample of the NV12 it converted to, NVENC with its lossless tune. Without
+    /// either, or without an encoder asked for, FFV1 encodes the captured RGB on the CPU. The
+    /// other encoders fail with [`WaycapError::Unsupported`], see
+    /// [`VideoEncoder::supports_lossless`]. Files are very large, mux them into Matroska.
+    Lossless,
+    /// Quantizers pinned per encoder family. Encoders other than VAAPI and NVENC encode it
+    /// like [`Self::Medium`]. Not part of [`Self::all`].
+    Custom(CustomQuality),
+}
+
+/// Quantizers of [`QualityPreset::Custom`], each in the scale of the codec. Opening an encoder
+/// fails with [`WaycapError::Init`] when its value is out of range.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CustomQuality {
+    /// qp of the VAAPI encoders, 0 to 51 for H.264 and 0 to 255 for AV1 and VP9. MJPEG takes
+    /// it as the JPEG quality, 1 to 100.
+    pub vaapi_qp: u32,
+    /// cq of the NVENC encoders, 0 to 51 for H.264 and HEVC and 0 to 63 for AV1. The preset
+    /// and bitrate cap are the ones of [`QualityPreset::Medium`].
+    pub nvenc_cq: u32,
+}
+
+impl QualityPreset {
+    /// Every preset, from the lowest to the highest quality
+    pub fn all() -> &'static [QualityPreset] {
+        &[
+            QualityPreset::Low,
+            QualityPreset::Medium,
+            QualityPreset::High,
+            QualityPreset::Ultra,
+            QualityPreset::Lossless,
+        ]
+    }
+
+    pub fn display_name(self) -> &'static str {
+        match self {
+            QualityPreset::Low => "Low",
+            QualityPreset::Medium => "Medium",
+            QualityPreset::High => "High",
+            QualityPreset::Ultra => "Ultra",
+            QualityPreset::Lossless => "Lossless",
+            QualityPreset::Custom(_) => "Custom",
+        }
+    }
+
+    pub fn description(self) -> &'static str {
+        match self {
+            QualityPreset::Low => "Smallest files, visible artifacts in detailed scenes",
+            QualityPreset::Medium => "Balanced size and quality for most recordings",
+            QualityPreset::High => "Sharp text and motion at a higher bitrate",
+            QualityPreset::Ultra => "Close to lossless, for editing afterwards",
+            QualityPreset::Lossless => "Bit-exact frames, very large files",
+            QualityPreset::Custom(_) => "Quantizers picked for each encoder",
+        }
+    }
+
+    /// Bitrate the preset targets with encoders driven by bitrate instead of a quantizer, in
+    /// bits per second. Scales with the frame size, from about 4 Mbit/s for Low to 25 Mbit/s
+    /// for Ultra at 1080p.
+    pub fn target_bitrate(self, width: u32, height: u32) -> u64 {
+        let bits_per_pixel = match self {
+            QualityPreset::Low => 2,
+            QualityPreset::Medium | QualityPreset::Custom(_) => 4,
+            QualityPreset::High => 7,
+            QualityPreset::Ultra | QualityPreset::Lossless => 12,
+        };
+        u64::from(width) * u64::from(height) * bits_per_pixel
+    }
+
+    /// The fixed H.264 quantizer the preset stands for, used unless
+    /// [`VideoConfig::rate_control`] is set. Encoders of other codecs translate the preset to a
+    /// quantizer of their own scale.
+    pub fn rate_control(self) -> RateControl {
+        RateControl::ConstantQp(match self {
+            QualityPreset::Low => 30,
+            QualityPreset::Medium => 25,
+            QualityPreset::High => 20,
+            QualityPreset::Ultra => 15,
+            // Bit-exact on the drivers H.264 (VAAPI) takes it on, see QualityPreset::Lossless
+            QualityPreset::Lossless => 0,
+            QualityPreset::Custom(custom) => custom.vaapi_qp,
+        })
+    }
+}
+
+/// How the VAAPI and NVENC encoders spend bits, see [`VideoConfig::rate_control`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum RateControl {
+    /// The same quantizer for every frame: steady quality, but the size follows the content.
+    /// In the scale of the codec, 0 to 51 for H.264 and HEVC and 0 to 255 for AV1 and VP9.
+    /// Opening an encoder fails with [`WaycapError::Init`] outside of it.
+    ConstantQp(u32),
+    /// Averages `bitrate` bits per second, going up to `max_bitrate` for complex scenes
+    Vbr {
+        bitrate: u64,
+        max_bitrate: u64,
+        /// VBV buffer in bits, how long the encoder may stay above `max_bitrate` before it is
+        /// held back. Smaller values keep the peaks closer to it, as streaming ingests
+        /// expect. None buffers one second at `max_bitrate`.
+        buffer_size: Option<u64>,
+    },
+    /// `bitrate` bits per second whatever the content, for streaming sinks with a fixed
+    /// bandwidth
+    Cbr {
+        bitrate: u64,
+        /// VBV buffer in bits, see [`RateControl::Vbr`]. None buffers one second at `bitrate`.
+        buffer_size: Option<u64>,
+    },
+    /// Intelligent constant quality: the driver aims at `quality`, in the scale of
+    /// [`RateControl::ConstantQp`], and moves the quantizer with what the eye notices. Intel's
+    /// VAAPI driver has it, NVENC encodes it as its constant quality VBR without a bitrate.
+    Icq(u32),
+    /// Quality defined VBR: aims at `quality` like [`RateControl::Icq`], within the bitrates of
+    /// [`RateControl::Vbr`]. Intel's VAAPI driver has it, NVENC takes it as VBR with a cq.
+    Qvbr {
+        bitrate: u64,
+        max_bitrate: u64,
+        quality: u32,
+        /// VBV buffer in bits, see [`RateControl::Vbr`]
+        buffer_size: Option<u64>,
+    },
+}
+
+/// The mode of a [`RateControl`], as VAAPI drivers advertise them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum RateControlMode {
+    Cqp,
+    Vbr,
+    Cbr,
+    Icq,
+    Qvbr,
+}
+
+impl RateControlMode {
+    /// The name ffmpeg's VAAPI encoders take as `rc_mode`, e.g. `QVBR`
+    pub fn name(self) -> &'static str {
+        match self {
+            RateControlMode::Cqp => "CQP",
+            RateControlMode::Vbr => "VBR",
+            RateControlMode::Cbr => "CBR",
+            RateControlMode::Icq => "ICQ",
+            RateControlMode::Qvbr => "QVBR",
+        }
+    }
+}
+
+impl RateControl {
+    pub fn mode(self) -> RateControlMode {
+        match self {
+            RateControl::ConstantQp(_) => RateControlMode::Cqp,
+            RateControl::Vbr { .. } => RateControlMode::Vbr,
+            RateControl::Cbr { .. } => RateControlMode::Cbr,
+            RateControl::Icq(_) => RateControlMode::Icq,
+            RateControl::Qvbr { .. } => RateControlMode::Qvbr,
+        }
+    }
+
+    /// The quantizer or quality the mode aims at, none for the ones only targeting a bitrate
+    pub(crate) fn quality(self) -> Option<u32> {
+        match self {
+            RateControl::ConstantQp(quality)
+            | RateControl::Icq(quality)
+            | RateControl::Qvbr { quality, .. } => Some(quality),
+            RateControl::Vbr { .. } | RateControl::Cbr { .. } => None,
+        }
+    }
+
+    /// Average bitrate, peak bitrate and VBV buffer size in bits, for the modes targeting a
+    /// bitrate
+    pub(crate) fn bitrate_limits(self) -> Option<(u64, u64, u64)> {
+        match self {
+            RateControl::ConstantQp(_) | RateControl::Icq(_) => None,
+            RateControl::Vbr {
+                bitrate,
+                max_bitrate,
+                buffer_size,
+            }
+            | RateControl::Qvbr {
+                bitrate,
+                max_bitrate,
+                buffer_size,
+                ..
+            } => Some((bitrate, max_bitrate, buffer_size.unwrap_or(max_bitrate))),
+            RateControl::Cbr {
+                bitrate,
+                buffer_size,
+            } => Some((bitrate, bitrate, buffer_size.unwrap_or(bitrate))),
+        }
+    }
+
+    /// Whether the encoder needs the frame rate to spread the bitrate over the frames
+    pub(crate) fn targets_bitrate(self) -> bool {
+        self.bitrate_limits().is_some()
+    }
+
+    /// The same mode at `bitrate`, with the max bitrate and VBV buffer scaled along. None for
+    /// the modes aiming at a quantizer.
+    pub(crate) fn with_bitrate(self, bitrate: u64) -> Option<RateControl> {
+        let (current, _, _) = self.bitrate_limits()?;
+        let scale = |value: u64| {
+            let scaled = u128::from(value) * u128::from(bitrate) / u128::from(current.max(1));
+            u64::try_from(scaled).unwrap_or(u64::MAX)
+        };
+        Some(match self {
+            RateControl::ConstantQp(_) | RateControl::Icq(_) => return None,
+            RateControl::Vbr {
+                max_bitrate,
+                buffer_size,
+                ..
+            } => RateControl::Vbr {
+                bitrate,
+                max_bitrate: scale(max_bitrate),
+                buffer_size: buffer_size.map(scale),
+            },
+            RateControl::Cbr { buffer_size, .. } => RateControl::Cbr {
+                bitrate,
+                buffer_size: buffer_size.map(scale),
+            },
+            RateControl::Qvbr {
+                max_bitrate,
+                quality,
+                buffer_size,
+                ..
+            } => RateControl::Qvbr {
+                bitrate,
+                max_bitrate: scale(max_bitrate),
+                quality,
+                buffer_size: buffer_size.map(scale),
+            },
+        })
+    }
+}
+
+/// What the encoders trade for latency, see [`VideoConfig::latency_mode`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum LatencyMode {
+    /// Follow [`VideoConfig::async_depth`] and [`VideoConfig::max_b_frames`]
+    #[default]
+    Quality,
+    /// One frame in flight in the driver and no B-frames, a packet comes out for every frame
+    /// that goes in. NVENC uses its low latency tuning.
+    LowLatency,
+    /// As [`LatencyMode::LowLatency`], with NVENC's ultra low latency tuning, which gives up
+    /// some more quality
+    UltraLowLatency,
+}
+
+/// Mastering display colour volume (SMPTE ST 2086) of the display the content was graded on.
+///
+/// Chromaticity coordinates are CIE 1931 `(x, y)` pairs, luminance is in cd/m².
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MasteringDisplay {
+    /// Red, green and blue primaries, in that order
+    pub primaries: [(f64, f64); 3],
+    pub white_point: (f64, f64),
+    pub min_luminance: f64,
+    pub max_luminance: f64,
+}
+
+impl MasteringDisplay {
+    /// BT.2020 primaries with a D65 white point, as used by most HDR10 content
+    pub fn bt2020(min_luminance: f64, max_luminance: f64) -> Self {
+        Self {
+            primaries: [(0.708, 0.292), (0.170, 0.797), (0.131, 0.046)],
+            white_point: (0.3127, 0.3290),
+            min_luminance,
+            max_luminance,
+        }
+    }
+}
+
+/// Content light level information (CTA-861.3), both values in cd/m².
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ContentLightLevel {
+    /// Maximum content light level
+    pub max_cll: u32,
+    /// Maximum frame-average light level
+    pub max_fall: u32,
+}
+
+/// Static HDR metadata written into the encoded bitstream.
+///
+/// [`VideoEncoder::H265Vaapi`] writes it as SEI messages on every keyframe, NVENC and SVT-AV1
+/// take it from the side data of the frames. [`VideoEncoder::Av1Vaapi`] can't write it and fails
+/// with [`WaycapError::Validation`], the other encoders without
+/// [`VideoEncoder::writes_hdr_metadata`] leave it out.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct HdrMetadata {
+    pub mastering_display: Option<MasteringDisplay>,
+    pub content_light_level: Option<ContentLightLevel>,
+}
+
+/// What to do with an encoded packet when the consumer's channel is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum OverflowPolicy {
+    /// Drop the packet which didn't fit
+    #[default]
+    DropNewest,
+    /// Drop the oldest queued packet to make room, keeping the output as recent as possible
+    DropOldest,
+    /// Wait up to `timeout` for the consumer to make room, then drop the packet. Stalls the
+    /// encoder while waiting.
+    Block { timeout: Duration },
+}
+
+/// Buffering between the realtime audio capture callback and the audio encoder
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AudioRingConfig {
+    /// Slots in the ring, each holds up to 4096 samples. A 1024 frame stereo quantum takes one
+    /// slot, so the default buffers about 1.3 seconds of stereo audio at 48kHz.
+    pub depth: usize,
+    /// Which period to drop when the encoder falls behind and the ring is full.
+    /// [`OverflowPolicy::Block`] would stall the realtime callback and is rejected.
+    pub overflow: OverflowPolicy,
+}
+
+impl Default for AudioRingConfig {
+    fn default() -> Self {
+        Self {
+            depth: 64,
+            overflow: OverflowPolicy::DropNewest,
+        }
+    }
+}
+
+impl AudioRingConfig {
+    pub(crate) fn validate(&self) -> Result<()> {
+        if self.depth == 0 {
+            return Err(WaycapError::Validation(
+                "Audio ring depth must be at least 1".to_string(),
+            ));
+        }
+        if matches!(self.overflow, OverflowPolicy::Block { .. }) {
+            return Err(WaycapError::Validation(
+                "The audio ring can't block the realtime capture callback, use DropNewest or \
+                 DropOldest"
+                    .to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// What the portal dialog offers to share, see [`crate::Capture::switch_source`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CaptureSource {
+    /// A whole monitor
+    Monitor,
+    /// A single window
+    Window,
+    /// Monitors and windows
+    #[default]
+    Any,
+}
+
+impl CaptureSource {
+    pub(crate) fn source_types(self) -> SourceType {
+        match self {
+            CaptureSource::Monitor => SourceType::MONITOR,
+            CaptureSource::Window => SourceType::WINDOW,
+            CaptureSource::Any => SourceType::all(),
+        }
+    }
+}
+
+/// What to do when every receiver of the encoded video was dropped mid-capture
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum DisconnectPolicy {
+    /// Stop encoding and shut the capture down, like after [`crate::Capture::finish`]
+    #[default]
+    StopOnDisconnect,
+    /// Pause the capture and resume once a new receiver is attached, e.g. through
+    /// [`crate::Capture::get_video_receiver`]
+    PauseOnDisconnect,
+    /// Keep encoding, e.g. when only the stats are of interest
+    Ignore,
+}
+
+/// What to do when the screen locks or blanks mid-capture, which compositors show either by
+/// pausing the stream or by delivering black frames. Audio follows the video in every case, so
+/// the tracks stay in sync.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ScreenBlankPolicy {
+    /// Keep recording whatever the compositor delivers
+    #[default]
+    KeepRecording,
+    /// Pause the capture like [`crate::CaptureControls::pause`] and resume once the screen is
+    /// back, unless it was paused or resumed by hand in between
+    AutoPause,
+    /// Same as [`ScreenBlankPolicy::AutoPause`], but the first black frame is still encoded, so
+    /// players show it over the gap instead of the last picture before the blank. Compositors
+    /// pausing the stream deliver no such frame, then this behaves like `AutoPause`.
+    Placeholder,
+}
+
+/// Settings for the pipeline watchdog, see
+/// [`crate::pipeline::builder::CaptureBuilder::with_watchdog`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct WatchdogConfig {
+    /// How often the progress counters are checked
+    pub interval: Duration,
+    /// How long a stage may make no progress while its input keeps moving before it counts as
+    /// stalled
+    pub stall_timeout: Duration,
+    /// Reset the video encoder when it stops producing packets
+    pub auto_recover: bool,
+}
+
+impl Default for WatchdogConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(1),
+            stall_timeout: Duration::from_secs(5),
+            auto_recover: false,
+        }
+    }
+}
+
+/// How to retry opening NVENC when the driver's concurrent session limit is reached, e.g. while
+/// a previous recording is still finalizing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct NvencRetryConfig {
+    /// Retries after the first failed attempt
+    pub attempts: u32,
+    /// Delay before the first retry, doubled after every further attempt
+    pub initial_delay: Duration,
+    /// Use the VAAPI encoder for the same codec once all retries failed instead of returning
+    /// [`crate::types::error::WaycapError::NvencSessionLimit`], which is still returned when
+    /// VAAPI can't encode that codec here. Only works where VAAPI can import the captured
+    /// buffers, e.g. hybrid GPU laptops.
+    pub fallback_to_vaapi: bool,
+}
+
+impl Default for NvencRetryConfig {
+    fn default() -> Self {
+        // 0.3 + 0.6 + 1.2 + 2.4 + 4.8 ≈ 10 seconds
+        Self {
+            attempts: 5,
+            initial_delay: Duration::from_millis(300),
+            fallback_to_vaapi: false,
+        }
+    }
+}
+
+/// H.264 profile, for decoders which only take the lower ones, see
+/// [`VideoConfig::h264_profile`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum H264Profile {
+    /// No B-frames and no CABAC, what the oldest phones and TVs decode
+    ConstrainedBaseline,
+    Main,
+    High,
+}
+
+/// `level_idc` values of the H.264 levels, 1 to 6.2 times ten
+const H264_LEVELS: [u32; 19] = [
+    10, 11, 12, 13, 20, 21, 22, 30, 31, 32, 40, 41, 42, 50, 51, 52, 60, 61, 62,
+];
+
+/// NVENC speed preset, from the fastest P1 to the slowest and best P7, see
+/// [`VideoConfig::nvenc_preset`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum NvencPreset {
+    P1,
+    P2,
+    P3,
+    P4,
+    P5,
+    P6,
+    P7,
+}
+
+impl NvencPreset {
+    /// The name ffmpeg's NVENC encoders take as `preset`
+    pub fn name(self) -> &'static str {
+        match self {
+            NvencPreset::P1 => "p1",
+            NvencPreset::P2 => "p2",
+            NvencPreset::P3 => "p3",
+            NvencPreset::P4 => "p4",
+            NvencPreset::P5 => "p5",
+            NvencPreset::P6 => "p6",
+            NvencPreset::P7 => "p7",
+        }
+    }
+}
+
+/// What NVENC tunes its preset for, see [`VideoConfig::nvenc_tune`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum NvencTune {
+    HighQuality,
+    LowLatency,
+    UltraLowLatency,
+    /// Picks its own rate control, so it can't be combined with a
+    /// [`VideoConfig::rate_control`] or the quantizer of a [`QualityPreset::Custom`]
+    Lossless,
+}
+
+impl NvencTune {
+    /// The name ffmpeg's NVENC encoders take as `tune`
+    pub fn name(self) -> &'static str {
+        match self {
+            NvencTune::HighQuality => "hq",
+            NvencTune::LowLatency => "ll",
+            NvencTune::UltraLowLatency => "ull",
+            NvencTune::Lossless => "lossless",
+        }
+    }
+}
+
+/// NVENC encoding tools beyond what the presets turn on, see [`VideoConfig::nvenc_tuning`].
+/// The default leaves them to the driver, which has them off.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct NvencTuning {
+    /// Frames the rate control looks ahead to spend bits where they are needed, up to 32.
+    /// Packets come out that many frames late, so it can't be combined with a
+    /// [`LatencyMode`] other than [`LatencyMode::Quality`]. 0 turns it off.
+    pub lookahead: u32,
+    /// Vary the quantizer within a frame, so flat areas around text and UI edges don't block
+    pub spatial_aq: bool,
+    /// Vary the quantizer over time, static parts of the screen get more bits. H.264 and
+    /// HEVC only.
+    pub temporal_aq: bool,
+    /// How far spatial AQ moves the quantizer, 1 to 15. None leaves the driver's 8.
+    pub aq_strength: Option<u32>,
+}
+
+/// A frame rate of `num / den` frames per second, e.g. 30000/1001 for NTSC's 29.97, see
+/// [`VideoConfig::cfr`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Fps {
+    pub num: u32,
+    pub den: u32,
+}
+
+impl Fps {
+    pub const fn new(num: u32, den: u32) -> Self {
+        Self { num, den }
+    }
+
+    /// Time between two frames, rounded down to whole nanoseconds
+    pub fn interval(self) -> Duration {
+        Duration::from_secs(u64::from(self.den)) / self.num.max(1)
+    }
+}
+
+impl From<u32> for Fps {
+    fn from(fps: u32) -> Self {
+        Self::new(fps, 1)
+    }
+}
+
+/// Frames kept for a timelapse and how fast they play back, see [`VideoConfig::timelapse`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TimelapseConfig {
+    /// Capture time between two kept frames
+    pub capture_interval: Duration,
+    /// Frame rate the kept frames are encoded at
+    pub playback_fps: Fps,
+    /// Make every this many kept frames a keyframe
+    pub keyframe_every: u32,
+}
+
+impl TimelapseConfig {
+    /// A frame every `capture_interval` played back at `playback_fps`, with a keyframe at least
+    /// every 10 seconds of capture, and every 30 frames like the encoders' own GOP at most
+    pub fn new(capture_interval: Duration, playback_fps: impl Into<Fps>) -> Self {
+        let keyframe_every =
+            Duration::from_secs(10).as_nanos() / capture_interval.as_nanos().max(1);
+        Self {
+            capture_interval,
+            playback_fps: playback_fps.into(),
+            keyframe_every: keyframe_every.clamp(1, 30) as u32,
+        }
+    }
+}
+
+/// Region of the captured frames in pixels, from their top left corner, see
+/// [`VideoConfig::crop`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// How frames are scaled into [`VideoConfig::output_width`] and [`VideoConfig::output_height`]
+/// when their aspect ratio differs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ScaleMode {
+    /// Scale each side to the output size, distorting the picture
+    Stretch,
+    /// Scale the whole picture into the output size and letterbox it, the bars around it are
+    /// [`VideoConfig::pad_color`]
+    #[default]
+    Fit,
+    /// Scale the picture to cover the output size, cutting off what sticks out on either side
+    Fill,
+}
+
+/// How hard noise is filtered out of the frames, see [`VideoConfig::denoise`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum DenoiseStrength {
+    /// Sensor noise of camera previews, fine detail survives
+    Light,
+    Medium,
+    /// Grainy video, smears small text
+    Strong,
+}
+
+/// How the VAAPI encoders bring the captured DMA-BUFs into their filter graphs, see
+/// [`VideoConfig::vaapi_import`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum VaapiImport {
+    /// Map them, falling back to [`Self::Upload`] when the driver fails to
+    #[default]
+    Auto,
+    /// Map them onto VA surfaces with `hwmap`, without copying. Failures end the capture.
+    Map,
+    /// Read them on the CPU and upload them with `hwupload`, a copy each way
+    Upload,
+}
+
+/// Range of the YUV values the encoders write, see [`VideoConfig::color_range`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ColorRange {
+    /// 16 to 235 for luma, what players assume of untagged video
+    #[default]
+    Limited,
+    /// 0 to 255, keeps every step of the screen's gradients
+    Full,
+}
+
+/// Color primaries the streams are tagged with, see [`Colorimetry`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ColorPrimaries {
+    /// Those of sRGB and HD video
+    #[default]
+    Bt709,
+    /// The wide gamut of HDR video
+    Bt2020,
+}
+
+/// Transfer characteristics the streams are tagged with, see [`Colorimetry`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum TransferCharacteristic {
+    #[default]
+    Bt709,
+    /// The curve SDR desktops are actually drawn in, which few players know
+    Srgb,
+    /// SMPTE ST 2084, of HDR10
+    Pq,
+    /// ARIB STD-B67, hybrid log-gamma
+    Hlg,
+}
+
+/// Matrix the encoders convert RGB to YUV with, see [`Colorimetry`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ColorMatrix {
+    #[default]
+    Bt709,
+    /// Of SD video, for old players which ignore the tag
+    Bt601,
+    /// Non-constant luminance BT.2020
+    Bt2020,
+}
+
+/// Colorimetry the encoders write into the streams, see [`VideoConfig::colorimetry`]. The
+/// frames are converted with the matrix, the primaries and transfer are only tagged: the
+/// compositor already drew the frames with them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Colorimetry {
+    pub primaries: ColorPrimaries,
+    pub transfer: TransferCharacteristic,
+    pub matrix: ColorMatrix,
+}
+
+impl Colorimetry {
+    /// BT.709 throughout, what players assume of HD video
+    pub const BT709: Self = Self {
+        primaries: ColorPrimaries::Bt709,
+        transfer: TransferCharacteristic::Bt709,
+        matrix: ColorMatrix::Bt709,
+    };
+
+    /// BT.2020 with the PQ curve, for the 10 bit frames of HDR compositors. Goes along with
+    /// [`VideoConfig::hdr_metadata`].
+    pub const HDR10: Self = Self {
+        primaries: ColorPrimaries::Bt2020,
+        transfer: TransferCharacteristic::Pq,
+        matrix: ColorMatrix::Bt2020,
+    };
+}
+
+/// Image of an [`OverlayConfig`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum OverlayImage {
+    /// A PNG, JPEG or any other file the `image` crate reads
+    Path(PathBuf),
+    /// Rows of straight alpha RGBA without padding
+    Rgba {
+        width: u32,
+        height: u32,
+        data: Vec<u8>,
+    },
+}
+
+/// Corner of the encoded frames an overlay is anchored to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Corner {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+/// Where an overlay goes on the encoded frames, after they were cropped, turned and scaled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum OverlayPosition {
+    /// The top left corner of the image at `x`,`y`
+    Pixels { x: u32, y: u32 },
+    /// In `corner`, `margin` pixels from both of its edges, so it stays there when the output
+    /// size changes
+    Anchored { corner: Corner, margin: u32 },
+}
+
+/// An image blended onto every encoded frame, e.g. a logo, see [`VideoConfig::overlay`]
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct OverlayConfig {
+    pub image: OverlayImage,
+    pub position: OverlayPosition,
+    /// Multiplies the alpha of the image, from 0 for invisible to 1 for as it is
+    pub opacity: f32,
+}
+
+/// A second PipeWire video source composited onto the encoded frames, e.g. a webcam, see
+/// [`VideoConfig::pip`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PipConfig {
+    /// Node of the source on the user's PipeWire daemon, e.g. a camera listed by
+    /// `pw-cli ls Node`
+    pub node_id: u32,
+    pub position: OverlayPosition,
+    /// Size the source's frames are scaled to, whatever their aspect ratio
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Rotation and mirroring of the captured frames, see [`VideoConfig::transform`]. Rotations are
+/// counter-clockwise as in Wayland's output transforms, the flipped ones mirror the frames left
+/// to right before rotating them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Transform {
+    #[default]
+    Normal,
+    Rot90,
+    Rot180,
+    Rot270,
+    Flipped,
+    Flipped90,
+    Flipped180,
+    Flipped270,
+}
+
+impl Transform {
+    /// Whether the frames come out with their width and height swapped
+    pub fn swaps_sides(self) -> bool {
+        matches!(
+            self,
+            Transform::Rot90 | Transform::Rot270 | Transform::Flipped90 | Transform::Flipped270
+        )
+    }
+
+    /// Where the pixel at `x`,`y` of a `width`x`height` frame ends up
+    pub(crate) fn map(self, x: u32, y: u32, width: u32, height: u32) -> (u32, u32) {
+        let (right, bottom) = (width - 1 - x, height - 1 - y);
+        match self {
+            Transform::Normal => (x, y),
+            Transform::Rot90 => (y, right),
+            Transform::Rot180 => (right, bottom),
+            Transform::Rot270 => (bottom, x),
+            Transform::Flipped => (right, y),
+            Transform::Flipped90 => (y, x),
+            Transform::Flipped180 => (x, bottom),
+            Transform::Flipped270 => (bottom, right),
+        }
+    }
+}
+
+/// Where the encoders take the picture from and put it, see [`VideoConfig::scaling`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Scaling {
+    /// Region of the captured frames which is scaled, [`VideoConfig::crop`] or the middle of
+    /// it when filling. In the coordinates of the frames before they are transformed.
+    pub source: Rect,
+    /// Applied to the source before it is scaled
+    pub transform: Transform,
+    /// Where the scaled picture lands in the encoded frames, all of them unless fitting
+    pub picture: Rect,
+    /// Size of the encoded frames
+    pub output: (u32, u32),
+}
+
+impl Scaling {
+    /// Whether the picture leaves bars of [`VideoConfig::pad_color`] around it
+    pub fn is_padded(&self) -> bool {
+        (self.picture.width, self.picture.height) != self.output
+    }
+}
+
+/// Settings used when creating a video encoder
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct VideoConfig {
+    pub quality: QualityPreset,
+    /// Default: None
+    pub hdr_metadata: Option<HdrMetadata>,
+    /// Number of frames the VAAPI driver may keep in flight. Higher values improve throughput at
+    /// high resolutions at the cost of a few frames of latency.
+    /// Default: 2
+    pub async_depth: u32,
+    /// Default: [`NvencRetryConfig::default`]
+    pub nvenc_retry: NvencRetryConfig,
+    /// Fail opening the encoder when it does not recognize one of the options passed to it,
+    /// instead of only logging a warning.
+    /// Default: false
+    pub strict_options: bool,
+    /// Hold on to the NVENC session from building the capture until it is closed: a reset
+    /// flushes the open encoder instead of reopening it, and a full session limit fails instead
+    /// of falling back to VAAPI. Other encoders ignore it.
+    /// Default: false
+    pub reserve_nvenc_session: bool,
+    /// Keyframe interval decided by the capture instead of each encoder counting its own GOP.
+    /// Keyframes land on a fixed grid from the first frame, so every encoder fed from the
+    /// capture has them at identical pts, which segmenters aligning renditions rely on. Must be
+    /// between 100ms and 60s.
+    /// Default: None, the encoders' own GOP of 30 frames
+    pub keyframe_interval: Option<Duration>,
+    /// Capture and encode 10 bit frames, P010 on the GPU instead of NV12, so gradients don't
+    /// band. Needs a compositor which sends 10 bit DMA-BUFs and an encoder for which
+    /// [`VideoEncoder::supports_ten_bit`], building the capture fails otherwise.
+    /// Default: false
+    pub ten_bit: bool,
+    /// Target a bitrate or pin the quantizer instead of the fixed quantizer [`Self::quality`]
+    /// picks. Only the VAAPI and NVENC encoders follow it, VAAPI's MJPEG only takes
+    /// [`RateControl::ConstantQp`]. The VAAPI encoders fail with [`WaycapError::Init`] when
+    /// the driver doesn't advertise the [`RateControl::mode`].
+    /// Default: None, [`QualityPreset::rate_control`] of the quality
+    pub rate_control: Option<RateControl>,
+    /// Frames per second the bitrate of [`RateControl::Vbr`] and [`RateControl::Cbr`] is spread
+    /// over. The capture builder sets it to the target fps.
+    /// Default: 60
+    pub framerate: u32,
+    /// B-frames the encoders may put between reference frames. They save bitrate, but hold
+    /// frames back and make packets come out in decode order, see
+    /// [`crate::types::video_frame::EncodedVideoFrame`]. Codecs without them (VP8, VP9, MJPEG,
+    /// FFV1) and SVT-AV1 ignore it.
+    /// Default: 0, every packet is shown in the order it is decoded
+    pub max_b_frames: u32,
+    /// ffmpeg options passed to the video encoder as they are, for what the typed settings
+    /// don't cover. Set after the ones of the quality preset and rate control, so these win.
+    /// Opening the encoder fails when it doesn't recognize one, whatever
+    /// [`Self::strict_options`] says.
+    /// Default: empty
+    pub extra_encoder_options: Vec<(String, String)>,
+    /// Tune the encoders for end-to-end latency instead of quality, e.g. for game streaming.
+    /// Anything but [`LatencyMode::Quality`] overrides [`Self::async_depth`] and
+    /// [`Self::max_b_frames`] and keeps fewer raw frames queued for the encoder, so frames
+    /// are dropped rather than encoded late.
+    /// Default: [`LatencyMode::Quality`]
+    pub latency_mode: LatencyMode,
+    /// Lookahead and adaptive quantization of the NVENC encoders, which improve screen content
+    /// a lot at the cost of some GPU time. Other encoders ignore it.
+    /// Default: [`NvencTuning::default`], all off
+    pub nvenc_tuning: NvencTuning,
+    /// NVENC preset, over the one the [`Self::quality`] picks. Other encoders ignore it.
+    /// Default: None, P2 to P7 depending on the codec and quality
+    pub nvenc_preset: Option<NvencPreset>,
+    /// NVENC tune, over the one the [`Self::latency_mode`] picks. Other encoders ignore it.
+    /// Default: None, [`NvencTune::HighQuality`] unless tuned for latency
+    pub nvenc_tune: Option<NvencTune>,
+    /// Encode with the low power entrypoint of Intel GPUs, their fixed function encoder, which
+    /// leaves the shader cores to the game being captured. Drivers without one for the codec
+    /// encode as usual with a warning, AMD's have none and ignore it quietly. VAAPI only.
+    /// Default: false
+    pub low_power: bool,
+    /// Keep the fixed quantizer of the quality presets on Intel GPUs. Their H.264 presets use
+    /// ICQ otherwise where the driver has it, which looks better at the same size on screen
+    /// content. Drivers without ICQ, and AMD's, always use CQP.
+    /// Default: false
+    pub vaapi_force_cqp: bool,
+    /// How the VAAPI encoders import the captured DMA-BUFs. Mapping them fails with some
+    /// modifiers and Mesa versions even though encoding works, [`VaapiImport::Auto`] then
+    /// rebuilds the filter graph to copy the frames through the CPU, logs a warning and sets
+    /// [`crate::types::stats::CaptureStats::cpu_upload`]. The copy costs a few milliseconds a
+    /// frame at 1440p. 10 bit frames are only copied in the xRGB layouts.
+    /// Default: [`VaapiImport::Auto`]
+    pub vaapi_import: VaapiImport,
+    /// Profile of the VAAPI and NVENC H.264 encoders. The opened encoder carries it, so
+    /// muxers fed its parameters write it into the avcC box.
+    /// Default: None, the driver's choice, usually High
+    pub h264_profile: Option<H264Profile>,
+    /// Level of the VAAPI and NVENC H.264 encoders as `level_idc`, the level times ten, e.g.
+    /// 41 for 4.1.
+    /// Default: None, picked by the encoder for the size and bitrate
+    pub h264_level: Option<u32>,
+    /// Start every keyframe packet of the VAAPI and NVENC H.264 encoders with the SPS and PPS,
+    /// so receivers joining a stream mid-session can decode from the next keyframe on.
+    /// Default: false, only the first keyframe and some of the later ones carry them
+    pub repeat_headers: bool,
+    /// Refresh the picture a column at a time over the GOP instead of in one large IDR frame,
+    /// so there are no bitrate spikes. Only the first packet is a keyframe, so it rules out
+    /// [`Self::keyframe_interval`] and [`Self::repeat_headers`], and consumers which cut the
+    /// stream at keyframes, like replay buffers, can't trim it. Keyframes forced with
+    /// [`crate::Capture::force_keyframe`] are still IDR frames. Needs an encoder for which
+    /// [`VideoEncoder::supports_intra_refresh`], building the capture fails otherwise.
+    /// Default: false
+    pub intra_refresh: bool,
+    /// Force a keyframe where the picture changes by more than this share between two frames,
+    /// from 0 to 1, e.g. at window switches and slide transitions, so seeks land on them. The
+    /// frames are compared on the CPU, which only reads 8 bit RGB frames in shared memory or
+    /// linear DMA-BUFs, others are never taken for scene changes. 0.4 catches most switches
+    /// between unrelated windows.
+    /// Default: None, off
+    pub scene_change_threshold: Option<f32>,
+    /// Encode exactly this many frames per second, with pts on a grid of the frame interval
+    /// instead of the capture timestamps, for editors and RTMP servers which can't take a
+    /// variable frame rate. Each frame takes the grid point nearest to when it was captured,
+    /// frames for a point which has one already are dropped and points no frame came for repeat
+    /// the previous one, also while the compositor sends nothing for a still screen. The grid
+    /// starts over after a pause. Replaces the target fps in limiting the frame rate, and rules
+    /// out passing through the compositor's H.264.
+    /// Default: None, the capture timestamps are kept
+    pub cfr: Option<Fps>,
+    /// Record a timelapse: keep one frame per [`TimelapseConfig::capture_interval`] and encode
+    /// the kept frames at [`TimelapseConfig::playback_fps`], so a frame a second played at 30fps
+    /// runs thirty times as fast. Intervals no frame came in for, e.g. while the screen stands
+    /// still, repeat the previous one, and a pause doesn't leave a gap. Keyframes are placed by
+    /// [`TimelapseConfig::keyframe_every`], which rules out [`Self::keyframe_interval`] and
+    /// [`Self::intra_refresh`], as well as [`Self::cfr`] and passing through the compositor's
+    /// H.264. The audio is left out.
+    /// Default: None
+    pub timelapse: Option<TimelapseConfig>,
+    /// Skip frames which show the same as the last encoded one, e.g. while a terminal sits idle,
+    /// saving the bits and power spent on them, but encode one at least this often so players
+    /// don't stall. Where the compositor attaches damage metadata it tells which frames
+    /// changed, otherwise every eighth row of the frame is checksummed on the CPU, which reads
+    /// shared memory and linear DMA-BUFs of 4 byte pixels; other frames are all encoded. The
+    /// encoded frames keep their capture timestamps, so the skipped ones leave gaps of a
+    /// variable frame rate, and are counted in
+    /// [`crate::types::stats::CaptureStats::frames_deduplicated`]. Rules out [`Self::cfr`] and
+    /// [`Self::timelapse`], which fill every frame interval.
+    /// Default: None, every frame is encoded
+    pub dedup: Option<Duration>,
+    /// Width the VAAPI, QSV, NVENC and software encoders scale the frames to. The stream is
+    /// still negotiated at the size of the monitor or window. With both sides set, the
+    /// [`Self::scale_mode`] decides what happens to a picture of another aspect ratio, with one
+    /// the other follows the aspect ratio of the picture. Sizes are rounded down to even for
+    /// the 4:2:0 encoders.
+    /// Default: None, the captured width
+    pub output_width: Option<u32>,
+    /// Height the frames are scaled to, see [`Self::output_width`].
+    /// Default: None, the captured height
+    pub output_height: Option<u32>,
+    /// How frames are scaled into [`Self::output_width`] and [`Self::output_height`] when
+    /// their aspect ratio differs.
+    /// Default: [`ScaleMode::Fit`], letterboxed
+    pub scale_mode: ScaleMode,
+    /// RGB of the bars [`ScaleMode::Fit`] pads the picture with.
+    /// Default: black
+    pub pad_color: [u8; 3],
+    /// Rotate or mirror the frames before scaling them, e.g. for a portrait monitor whose
+    /// buffers the compositor sends unrotated. [`Self::crop`] is in the coordinates of the
+    /// captured frames, the output size in those of the transformed ones. The VAAPI, QSV,
+    /// NVENC and software encoders follow it.
+    /// Default: None, the transform the compositor puts in the buffer metadata, if any
+    pub transform: Option<Transform>,
+    /// Range the VAAPI, QSV, NVENC and software encoders convert the frames to, and tag the
+    /// stream with. JPEG is always full range, FFV1 keeps the RGB.
+    /// Default: [`ColorRange::Limited`]
+    pub color_range: ColorRange,
+    /// Primaries, transfer and matrix the VAAPI, QSV, NVENC and software encoders tag the
+    /// stream with, the frames are converted to YUV with the matrix. JPEG always uses the
+    /// BT.601 matrix, FFV1 keeps the RGB.
+    /// Default: [`Colorimetry::BT709`]
+    pub colorimetry: Colorimetry,
+    /// Image the VAAPI, QSV, NVENC and software encoders blend onto the encoded frames. It has
+    /// to fit into them, creating the encoder fails otherwise.
+    /// Default: None
+    pub overlay: Option<OverlayConfig>,
+    /// A second video source, e.g. a webcam, the VAAPI and QSV encoders composite onto the
+    /// encoded frames, above [`Self::overlay`]. Each frame gets the source's frame nearest to its
+    /// timestamp among those which arrived, the capture never waits for the source: it is left
+    /// transparent until its first frame, and a stalled source keeps its last frame. It has to
+    /// fit into the encoded frames, creating the encoder fails otherwise. Needs an encoder of
+    /// [`VideoEncoder::supports_pip`], building the capture fails otherwise, and rules out
+    /// passing through the compositor's H.264.
+    /// Default: None
+    pub pip: Option<PipConfig>,
+    /// A chain of ffmpeg filters separated by commas, e.g. `deinterlace_vaapi` or
+    /// `tonemap_vaapi=format=nv12`, which the VAAPI and QSV encoders run on the captured frames
+    /// before cropping, turning and scaling them. The frames come in as VAAPI surfaces of the
+    /// stream's size in BGRA, or X2RGB10 with [`Self::ten_bit`], and have to leave as VAAPI
+    /// surfaces. Switching to software filters like `drawtext` and back is up to the chain, e.g.
+    /// `hwdownload,format=bgra,drawtext=...,hwupload=derive_device=vaapi`, its filters get the
+    /// encoder's device. Changing the size breaks [`Self::crop`]. Creating the encoder fails
+    /// with ffmpeg's complaints when the chain doesn't fit.
+    /// Default: None
+    pub custom_filter: Option<String>,
+    /// Filter noise out of the frames before encoding them, which saves the bits it would cost
+    /// on camera previews and video playback. The VAAPI and QSV encoders use the driver's
+    /// `denoise_vaapi`, encoding without it and warning when the driver has none, the software
+    /// encoders `hqdn3d` except for lossless FFV1. NVENC encodes the frames as they come.
+    /// Default: None
+    pub denoise: Option<DenoiseStrength>,
+    /// Encode only this region of the captured frames, e.g. a window sized part of a monitor.
+    /// It has to lie within the negotiated stream, creating the encoder fails otherwise, also
+    /// when switching to a smaller source. [`Self::output_width`] scales the cropped frames.
+    /// The VAAPI, QSV, NVENC and software encoders follow it.
+    /// Default: None, the whole frames
+    pub crop: Option<Rect>,
+}
+
+impl Default for VideoConfig {
+    fn default() -> Self {
+        Self {
+            quality: QualityPreset::Medium,
+            hdr_metadata: None,
+            async_depth: 2,
+            nvenc_retry: NvencRetryConfig::default(),
+            strict_options: false,
+            reserve_nvenc_session: false,
+            keyframe_interval: None,
+            ten_bit: false,
+            rate_control: None,
+            framerate: 60,
+            max_b_frames: 0,
+            extra_encoder_options: Vec::new(),
+            latency_mode: LatencyMode::Quality,
+            nvenc_tuning: NvencTuning::default(),
+            nvenc_preset: None,
+            nvenc_tune: None,
+            low_power: false,
+            vaapi_force_cqp: false,
+            vaapi_import: VaapiImport::Auto,
+            h264_profile: None,
+            h264_level: None,
+            repeat_headers: false,
+            intra_refresh: false,
+            scene_change_threshold: None,
+            cfr: None,
+            timelapse: None,
+            dedup: None,
+            output_width: None,
+            output_height: None,
+            scale_mode: ScaleMode::Fit,
+            pad_color: [0, 0, 0],
+            transform: None,
+            color_range: ColorRange::Limited,
+            colorimetry: Colorimetry::BT709,
+            overlay: None,
+            pip: None,
+            custom_filter: None,
+            denoise: None,
+            crop: None,
+        }
+    }
+}
+
+impl VideoConfig {
+    /// Switch [`Self::rate_control`] to `bitrate`, see [`crate::Capture::set_bitrate`]
+    pub(crate) fn set_bitrate(&mut self, bitrate: u64) -> Result<RateControl> {
+        if bitrate == 0 {
+            return Err(WaycapError::Validation(
+                "The bitrate must be above 0".to_string(),
+            ));
+        }
+        let rate_control = self
+            .rate_control
+            .and_then(|rate_control| rate_control.with_bitrate(bitrate))
+            .ok_or_else(|| {
+                WaycapError::Validation(
+                    "Only VBR, CBR and QVBR rate control target a bitrate which can be changed"
+                        .to_string(),
+                )
+            })?;
+        self.rate_control = Some(rate_control);
+        Ok(rate_control)
+    }
+
+    /// Switch to `quality` while capturing, see [`crate::Capture::change_quality`]
+    pub(crate) fn set_quality(&mut self, quality: QualityPreset) -> Result<()> {
+        if (self.quality == QualityPreset::Lossless) != (quality == QualityPreset::Lossless) {
+            return Err(WaycapError::Validation(
+                "Lossless can take another encoder and other buffers, switching to or from it \
+                 takes a new capture"
+                    .to_string(),
+            ));
+        }
+        let changed = VideoConfig {
+            quality,
+            ..self.clone()
+        };
+        changed.validate()?;
+        *self = changed;
+        Ok(())
+    }
+
+    /// Encode `crop` from now on, see [`crate::Capture::set_crop`]
+    pub(crate) fn set_crop(&mut self, crop: Option<Rect>) -> Result<()> {
+        let changed = VideoConfig {
+            crop,
+            ..self.clone()
+        };
+        changed.validate()?;
+        *self = changed;
+        Ok(())
+    }
+
+    /// Blend `overlay` onto the frames from now on, see [`crate::Capture::set_overlay`]
+    pub(crate) fn set_overlay(&mut self, overlay: Option<OverlayConfig>) -> Result<()> {
+        let changed = VideoConfig {
+            overlay,
+            ..self.clone()
+        };
+        changed.validate()?;
+        *self = changed;
+        Ok(())
+    }
+
+    /// Region of `width`x`height` frames the encoders take, see [`Self::crop`]
+    pub(crate) fn source_region(&self, width: u32, height: u32) -> Result<Rect> {
+        let Some(crop) = self.crop else {
+            return Ok(Rect {
+                x: 0,
+                y: 0,
+                width,
+                height,
+            });
+        };
+        if u64::from(crop.x) + u64::from(crop.width) > u64::from(width)
+            || u64::from(crop.y) + u64::from(crop.height) > u64::from(height)
+        {
+            return Err(WaycapError::Validation(format!(
+                "The crop of {}x{} at {},{} lies outside the {width}x{height} stream",
+                crop.width, crop.height, crop.x, crop.y
+            )));
+        }
+        Ok(crop)
+    }
+
+    /// Size the encoders encode `width`x`height` frames at, cropped and scaled
+    pub(crate) fn encoded_size(&self, width: u32, height: u32) -> Result<(u32, u32)> {
+        Ok(self.scaling(width, height)?.output)
+    }
+
+    /// How `width`x`height` frames are cropped, transformed, scaled and padded, see
+    /// [`Self::output_width`]
+    pub(crate) fn scaling(&self, width: u32, height: u32) -> Result<Scaling> {
+        let region = self.source_region(width, height)?;
+        let transform = self.transform.unwrap_or_default();
+        let (width, height) = if transform.swaps_sides() {
+            (region.height, region.width)
+        } else {
+            (region.width, region.height)
+        };
+        let (width, height) = (u64::from(width), u64::from(height));
+        let even = |size: u64| (size as u32 & !1).max(2);
+        // `size` times `to` over `from`, to the nearest pixel
+        let scale = |size: u64, to: u64, from: u64| (size * to + from / 2) / from.max(1);
+        let whole = |(width, height): (u32, u32)| Scaling {
+            source: region,
+            transform,
+            picture: Rect {
+                x: 0,
+                y: 0,
+                width,
+                height,
+            },
+            output: (width, height),
+        };
+        let output = match (self.output_width, self.output_height) {
+            _ if width == 0 || height == 0 => return Ok(whole((width as u32, height as u32))),
+            (None, None) => return Ok(whole((width as u32, height as u32))),
+            (Some(to), None) => {
+                let to = u64::from(to);
+                return Ok(whole((even(to), even(scale(height, to, width)))));
+            }
+            (None, Some(to)) => {
+                let to = u64::from(to);
+                return Ok(whole((even(scale(width, to, height)), even(to))));
+            }
+            (Some(output_width), Some(output_height)) => {
+                (even(output_width.into()), even(output_height.into()))
+            }
+        };
+        let (output_width, output_height) = (u64::from(output.0), u64::from(output.1));
+        let wider = width * output_height > output_width * height;
+        Ok(match self.scale_mode {
+            ScaleMode::Stretch => whole(output),
+            ScaleMode::Fit => {
+                let (picture_width, picture_height) = if wider {
+                    (output.0, even(scale(height, output_width, width)))
+                } else {
+                    (even(scale(width, output_height, height)), output.1)
+                };
+                Scaling {
+                    picture: Rect {
+                        // Even, so the bars end on a chroma sample
+                        x: ((output.0 - picture_width) / 2) & !1,
+                        y: ((output.1 - picture_height) / 2) & !1,
+                        width: picture_width,
+                        height: picture_height,
+                    },
+                    ..whole(output)
+                }
+            }
+            ScaleMode::Fill => {
+                // The middle of the region at the aspect ratio of the output
+                let (cut_width, cut_height) = if wider {
+                    (scale(height, output_width, output_height).max(1), height)
+                } else {
+                    (width, scale(width, output_height, output_width).max(1))
+                };
+                // Turned back into the captured frames
+                let (cut_width, cut_height) = if transform.swaps_sides() {
+                    (cut_height as u32, cut_width as u32)
+                } else {
+                    (cut_width as u32, cut_height as u32)
+                };
+                let source = Rect {
+                    x: region.x + (region.width - cut_width) / 2,
+                    y: region.y + (region.height - cut_height) / 2,
+                    width: cut_width,
+                    height: cut_height,
+                };
+                Scaling {
+                    source,
+                    ..whole(output)
+                }
+            }
+        })
+    }
+
+    /// Frames the driver may keep in flight, [`Self::async_depth`] unless tuned for latency
+    pub(crate) fn frames_in_flight(&self) -> u32 {
+        match self.latency_mode {
+            LatencyMode::Quality => self.async_depth.max(1),
+            LatencyMode::LowLatency | LatencyMode::UltraLowLatency => 1,
+        }
+    }
+
+    /// Whether the encoders keep the last frame for [`Self::cfr`] or [`Self::timelapse`] to
+    /// repeat
+    pub(crate) fn repeats_frames(&self) -> bool {
+        self.cfr.is_some() || self.timelapse.is_some()
+    }
+
+    /// Frames the encoders hold on to after encoding them
+    pub(crate) fn kept_frames(&self) -> u32 {
+        u32::from(self.repeats_frames())
+    }
+
+    /// Whether the capture places every keyframe, so the encoders' own GOP is out of the way
+    pub(crate) fn schedules_keyframes(&self) -> bool {
+        self.keyframe_interval.is_some() || self.timelapse.is_some()
+    }
+
+    /// B-frames the encoder may use, [`Self::max_b_frames`] unless tuned for latency
+    pub(crate) fn b_frames(&self) -> u32 {
+        match self.latency_mode {
+            LatencyMode::Quality => self.max_b_frames,
+            LatencyMode::LowLatency | LatencyMode::UltraLowLatency => 0,
+        }
+    }
+
+    pub(crate) fn validate(&self) -> Result<()> {
+        if self.output_width == Some(0) || self.output_height == Some(0) {
+            return Err(WaycapError::Validation(
+                "The output size must be above 0".to_string(),
+            ));
+        }
+        if self
+            .crop
+            .is_some_and(|crop| crop.width == 0 || crop.height == 0)
+        {
+            return Err(WaycapError::Validation(
+                "The crop must be above 0 pixels wide and high".to_string(),
+            ));
+        }
+        if let Some(ref overlay) = self.overlay {
+            if !(0.0..=1.0).contains(&overlay.opacity) {
+                return Err(WaycapError::Validation(format!(
+                    "The overlay opacity must be between 0 and 1, not {}",
+                    overlay.opacity
+                )));
+            }
+            if let OverlayImage::Rgba {
+                width,
+                height,
+                ref data,
+            } = overlay.image
+            {
+                if width == 0
+                    || height == 0
+                    || data.len() as u64 != u64::from(width) * u64::from(height) * 4
+                {
+                    return Err(WaycapError::Validation(format!(
+                        "The overlay of {} bytes is no {width}x{height} RGBA image",
+                        data.len()
+                    )));
+                }
+            }
+        }
+        if self
+            .pip
+            .is_some_and(|pip| pip.width == 0 || pip.height == 0)
+        {
+            return Err(WaycapError::Validation(
+                "The picture-in-picture must be above 0 pixels wide and high".to_string(),
+            ));
+        }
+        if self
+            .custom_filter
+            .as_ref()
+            .is_some_and(|custom| custom.trim().is_empty())
+        {
+            return Err(WaycapError::Validation(
+                "The custom filter must not be empty".to_string(),
+            ));
+        }
+        if self.cfr.is_some_and(|fps| fps.num == 0 || fps.den == 0) {
+            return Err(WaycapError::Validation(
+                "The constant frame rate must be above 0 frames per second".to_string(),
+            ));
+        }
+        if let Some(timelapse) = self.timelapse {
+            let TimelapseConfig {
+                capture_interval,
+                playback_fps,
+                keyframe_every,
+            } = timelapse;
+            if capture_interval.is_zero() || playback_fps.num == 0 || playback_fps.den == 0 {
+                return Err(WaycapError::Validation(format!(
+                    "The timelapse needs a capture interval and playback rate above 0, not \
+                     {capture_interval:?} and {}/{}",
+                    playback_fps.num, playback_fps.den
+                )));
+            }
+            if keyframe_every == 0 {
+                return Err(WaycapError::Validation(
+                    "The timelapse keyframes must be at least 1 frame apart".to_string(),
+                ));
+            }
+            let conflict = if self.cfr.is_some() {
+                Some("a constant frame rate")
+            } else if self.keyframe_interval.is_some() {
+                Some("a keyframe interval")
+            } else if self.intra_refresh {
+                Some("intra refresh")
+            } else {
+                None
+            };
+            if let Some(conflict) = conflict {
+                return Err(WaycapError::Validation(format!(
+                    "A timelapse places its own keyframes and frames, it can't have {conflict}"
+                )));
+            }
+        }
+        if let Some(heartbeat) = self.dedup {
+            if heartbeat.is_zero() {
+                return Err(WaycapError::Validation(
+                    "The deduplication heartbeat must be above 0".to_string(),
+                ));
+            }
+            if self.repeats_frames() {
+                return Err(WaycapError::Validation(
+                    "Deduplication leaves gaps a constant frame rate or timelapse would fill"
+                        .to_string(),
+                ));
+            }
+        }
+        if let Some(interval) = self.keyframe_interval {
+            if !(Duration::from_millis(100)..=Duration::from_secs(60)).contains(&interval) {
+                return Err(WaycapError::Validation(format!(
+                    "The keyframe interval must be between 100ms and 60s, not {interval:?}"
+                )));
+            }
+        }
+        if self.intra_refresh && self.keyframe_interval.is_some() {
+            return Err(WaycapError::Validation(
+                "Intra refresh leaves no keyframes to place at the keyframe interval".to_string(),
+            ));
+        }
+        if self.intra_refresh && self.repeat_headers {
+            return Err(WaycapError::Validation(
+                "Intra refresh leaves no keyframes to repeat the headers on".to_string(),
+            ));
+        }
+        if let Some(threshold) = self.scene_change_threshold {
+            if threshold.is_nan() || threshold <= 0.0 || threshold > 1.0 {
+                return Err(WaycapError::Validation(format!(
+                    "The scene change threshold must be above 0 and at most 1, not {threshold}"
+                )));
+            }
+            if self.intra_refresh {
+                return Err(WaycapError::Validation(
+                    "Intra refresh leaves no keyframes to place at scene changes".to_string(),
+                ));
+            }
+        }
+        match self.rate_control {
+            Some(
+                RateControl::Vbr {
+                    bitrate,
+                    max_bitrate,
+                    ..
+                }
+                | RateControl::Qvbr {
+                    bitrate,
+                    max_bitrate,
+                    ..
+                },
+            ) if bitrate == 0 || max_bitrate < bitrate => {
+                return Err(WaycapError::Validation(format!(
+                    "The VBR bitrate must be above 0 and at most the max bitrate, not {bitrate} \
+                     with a max of {max_bitrate}"
+                )));
+            }
+            Some(RateControl::Cbr { bitrate: 0, .. }) => {
+                return Err(WaycapError::Validation(
+                    "The CBR bitrate must be above 0".to_string(),
+                ));
+            }
+            Some(
+                RateControl::Vbr {
+                    buffer_size: Some(0),
+                    ..
+                }
+                | RateControl::Cbr {
+                    buffer_size: Some(0),
+                    ..
+                }
+                | RateControl::Qvbr {
+                    buffer_size: Some(0),
+                    ..
+                },
+            ) => {
+                return Err(WaycapError::Validation(
+                    "The VBV buffer must hold more than 0 bits".to_string(),
+                ));
+            }
+            Some(rate_control) if rate_control.targets_bitrate() && self.framerate == 0 => {
+                return Err(WaycapError::Validation(
+                    "Bitrates need a framerate above 0 to be spread over".to_string(),
+                ));
+            }
+            _ => {}
+        }
+        if self.ten_bit && self.quality == QualityPreset::Lossless {
+            return Err(WaycapError::Validation(
+                "Lossless keeps the captured 8 bit RGB, it can't be combined with 10 bit"
+                    .to_string(),
+            ));
+        }
+        if self.rate_control.is_some() && self.quality == QualityPreset::Lossless {
+            return Err(WaycapError::Validation(
+                "Lossless pins the quantizer, it takes no rate control".to_string(),
+            ));
+        }
+        if self.h264_profile == Some(H264Profile::ConstrainedBaseline) && self.b_frames() > 0 {
+            return Err(WaycapError::Validation(
+                "Constrained Baseline has no B-frames, set max_b_frames to 0".to_string(),
+            ));
+        }
+        if let Some(level) = self.h264_level.filter(|level| !H264_LEVELS.contains(level)) {
+            return Err(WaycapError::Validation(format!(
+                "{level} is no H.264 level, they go from 10 for 1.0 to 62 for 6.2"
+            )));
+        }
+        if self.nvenc_tune == Some(NvencTune::Lossless)
+            && (self.rate_control.is_some() || matches!(self.quality, QualityPreset::Custom(_)))
+        {
+            return Err(WaycapError::Validation(
+                "NVENC's lossless tune picks its own rate control, it takes no rate control or \
+                 custom quantizer"
+                    .to_string(),
+            ));
+        }
+        let tuning = self.nvenc_tuning;
+        if tuning.lookahead > 32 {
+            return Err(WaycapError::Validation(format!(
+                "NVENC looks ahead up to 32 frames, not {}",
+                tuning.lookahead
+            )));
+        }
+        if tuning.lookahead > 0 && self.latency_mode != LatencyMode::Quality {
+            return Err(WaycapError::Validation(format!(
+                "A lookahead of {} frames holds packets back, it can't be combined with {:?}",
+                tuning.lookahead, self.latency_mode
+            )));
+        }
+        if let Some(strength) = tuning
+            .aq_strength
+            .filter(|strength| !(1..=15).contains(strength))
+        {
+            return Err(WaycapError::Validation(format!(
+                "The AQ strength goes from 1 to 15, not {strength}"
+            )));
+        }
+        Ok(())
+    }
 }
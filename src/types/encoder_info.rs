@@ -0,0 +1,36 @@
+use std::time::Duration;
+
+/// Details about the opened video encoder, see [`crate::Capture::video_encoder_info`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EncoderInfo {
+    /// ffmpeg name of the encoder, e.g. `h264_vaapi`
+    pub name: String,
+    /// Options passed when opening the encoder which it did not recognize. ffmpeg ignores these,
+    /// unless [`crate::types::config::VideoConfig::strict_options`] is set.
+    pub rejected_options: Vec<String>,
+    /// How long the encoder holds frames back by its configuration
+    pub delay: EncoderDelay,
+}
+
+/// Delay between submitting a frame to a video encoder and getting its packet, as implied by
+/// the encoder's configuration. See [`crate::VideoEncoder::encoder_delay`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EncoderDelay {
+    /// Frames submitted after a frame before its packet comes out: B-frames, lookahead and the
+    /// frames in flight in the driver
+    pub frames: u32,
+    /// `frames` at the capture's target frame rate. The time the encoder spends on each frame
+    /// comes on top.
+    pub time: Duration,
+}
+
+/// NVENC session usage, see [`crate::nvenc_session_info`]
+#[cfg(feature = "nvenc")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NvencSessionInfo {
+    /// Concurrent sessions the driver allows, `None` when it has no known limit, like on
+    /// professional GPUs, or the driver could not be inspected
+    pub max_sessions: Option<u32>,
+    /// Sessions held by the captures of this process, including reserved ones
+    pub in_use_by_us: u32,
+}
@@ -28,10 +28,143 @@ pub enum WaycapError {
     Validation(String),
     /// Other errors
     Other(String),
+    /// The NVIDIA driver has no NVENC sessions left
+    NvencSessionLimit,
+    /// The user cancelled or denied the portal dialog
+    PortalCancelled,
+    /// The linked ffmpeg is too old or was built without a needed component
+    Unsupported(String),
+}
+
+/// Stable numeric error codes returned by [`WaycapError::error_code`].
+///
+/// Codes are grouped in blocks of 100 per [`WaycapError`] variant, the first code of a block is
+/// used when no more specific sub-cause applies. Codes are never changed or reused, a code which
+/// is no longer emitted moves to [`RETIRED`].
+pub mod codes {
+    pub const FFMPEG: u32 = 1000;
+    pub const FFMPEG_ENCODER_NOT_FOUND: u32 = 1001;
+    /// `EIO` from ffmpeg, usually the GPU went away
+    pub const FFMPEG_IO: u32 = 1002;
+    /// `EINVAL` from ffmpeg, usually a frame or option the encoder does not accept
+    pub const FFMPEG_INVALID_ARGUMENT: u32 = 1003;
+    pub const EGL: u32 = 1100;
+    pub const PIPEWIRE: u32 = 1200;
+    pub const PORTAL: u32 = 1300;
+    /// The user cancelled or denied the portal dialog
+    pub const PORTAL_CANCELLED: u32 = 1301;
+    pub const IO: u32 = 1400;
+    pub const IO_NOT_FOUND: u32 = 1401;
+    pub const IO_PERMISSION_DENIED: u32 = 1402;
+    pub const INIT: u32 = 1500;
+    pub const CONFIG: u32 = 1600;
+    pub const STREAM: u32 = 1700;
+    pub const ENCODING: u32 = 1800;
+    pub const DEVICE: u32 = 1900;
+    pub const VALIDATION: u32 = 2000;
+    pub const OTHER: u32 = 2100;
+    pub const NVENC_SESSION_LIMIT: u32 = 2200;
+    pub const UNSUPPORTED: u32 = 2300;
+
+    /// Every code currently in use
+    pub const ALL: &[u32] = &[
+        FFMPEG,
+        FFMPEG_ENCODER_NOT_FOUND,
+        FFMPEG_IO,
+        FFMPEG_INVALID_ARGUMENT,
+        EGL,
+        PIPEWIRE,
+        PORTAL,
+        PORTAL_CANCELLED,
+        IO,
+        IO_NOT_FOUND,
+        IO_PERMISSION_DENIED,
+        INIT,
+        CONFIG,
+        STREAM,
+        ENCODING,
+        DEVICE,
+        VALIDATION,
+        OTHER,
+        NVENC_SESSION_LIMIT,
+        UNSUPPORTED,
+    ];
+
+    /// Codes which were used by earlier releases and must not be handed out again
+    pub const RETIRED: &[u32] = &[];
+
+    const fn contains(codes: &[u32], code: u32) -> bool {
+        let mut i = 0;
+        while i < codes.len() {
+            if codes[i] == code {
+                return true;
+            }
+            i += 1;
+        }
+        false
+    }
+
+    const fn is_valid_registry() -> bool {
+        let mut i = 0;
+        while i < ALL.len() {
+            let mut j = i + 1;
+            while j < ALL.len() {
+                if ALL[i] == ALL[j] {
+                    return false;
+                }
+                j += 1;
+            }
+            if contains(RETIRED, ALL[i]) {
+                return false;
+            }
+            i += 1;
+        }
+        true
+    }
+
+    const _: () = assert!(
+        is_valid_registry(),
+        "error codes must be unique and must not reuse retired codes"
+    );
+}
+
+impl WaycapError {
+    /// Stable numeric code of this error, see [`codes`].
+    pub fn error_code(&self) -> u32 {
+        match self {
+            WaycapError::FFmpeg(ffmpeg_next::Error::EncoderNotFound) => {
+                codes::FFMPEG_ENCODER_NOT_FOUND
+            }
+            WaycapError::FFmpeg(ffmpeg_next::Error::Other { errno: libc::EIO }) => codes::FFMPEG_IO,
+            WaycapError::FFmpeg(ffmpeg_next::Error::Other {
+                errno: libc::EINVAL,
+            }) => codes::FFMPEG_INVALID_ARGUMENT,
+            WaycapError::FFmpeg(_) => codes::FFMPEG,
+            WaycapError::Egl(_) => codes::EGL,
+            WaycapError::PipeWire(_) => codes::PIPEWIRE,
+            WaycapError::Portal(_) => codes::PORTAL,
+            WaycapError::Io(err) => match err.kind() {
+                io::ErrorKind::NotFound => codes::IO_NOT_FOUND,
+                io::ErrorKind::PermissionDenied => codes::IO_PERMISSION_DENIED,
+                _ => codes::IO,
+            },
+            WaycapError::Init(_) => codes::INIT,
+            WaycapError::Config(_) => codes::CONFIG,
+            WaycapError::Stream(_) => codes::STREAM,
+            WaycapError::Encoding(_) => codes::ENCODING,
+            WaycapError::Device(_) => codes::DEVICE,
+            WaycapError::Validation(_) => codes::VALIDATION,
+            WaycapError::Other(_) => codes::OTHER,
+            WaycapError::NvencSessionLimit => codes::NVENC_SESSION_LIMIT,
+            WaycapError::PortalCancelled => codes::PORTAL_CANCELLED,
+            WaycapError::Unsupported(_) => codes::UNSUPPORTED,
+        }
+    }
 }
 
 impl fmt::Display for WaycapError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[E{}] ", self.error_code())?;
         match self {
             WaycapError::FFmpeg(err) => write!(f, "FFmpeg error: {err}"),
             WaycapError::PipeWire(msg) => write!(f, "PipeWire error: {msg}"),
@@ -45,6 +178,11 @@ impl fmt::Display for WaycapError {
             WaycapError::Validation(msg) => write!(f, "Validation error: {msg}"),
             WaycapError::Other(msg) => write!(f, "Error: {msg}"),
             WaycapError::Egl(msg) => write!(f, "Egl Error: {msg}"),
+            WaycapError::NvencSessionLimit => {
+                write!(f, "NVENC session limit reached, no encoder sessions left")
+            }
+            WaycapError::PortalCancelled => write!(f, "XDG Portal error: Cancelled by the user"),
+            WaycapError::Unsupported(msg) => write!(f, "Unsupported: {msg}"),
         }
     }
 }
@@ -79,7 +217,10 @@ impl From<pipewire::Error> for WaycapError {
 
 impl From<portal_screencast_waycap::PortalError> for WaycapError {
     fn from(err: portal_screencast_waycap::PortalError) -> Self {
-        WaycapError::Portal(err.to_string())
+        match err {
+            portal_screencast_waycap::PortalError::Cancelled => WaycapError::PortalCancelled,
+            err => WaycapError::Portal(err.to_string()),
+        }
     }
 }
 
@@ -102,3 +243,53 @@ impl From<khronos_egl::Error> for WaycapError {
 }
 
 pub type Result<T> = std::result::Result<T, WaycapError>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Consumers match on the `[E{code}]` prefix, so the values must never change
+    #[test]
+    fn codes_keep_their_values() {
+        let expected = [
+            (codes::FFMPEG, 1000),
+            (codes::FFMPEG_ENCODER_NOT_FOUND, 1001),
+            (codes::FFMPEG_IO, 1002),
+            (codes::FFMPEG_INVALID_ARGUMENT, 1003),
+            (codes::EGL, 1100),
+            (codes::PIPEWIRE, 1200),
+            (codes::PORTAL, 1300),
+            (codes::PORTAL_CANCELLED, 1301),
+            (codes::IO, 1400),
+            (codes::IO_NOT_FOUND, 1401),
+            (codes::IO_PERMISSION_DENIED, 1402),
+            (codes::INIT, 1500),
+            (codes::CONFIG, 1600),
+            (codes::STREAM, 1700),
+            (codes::ENCODING, 1800),
+            (codes::DEVICE, 1900),
+            (codes::VALIDATION, 2000),
+            (codes::OTHER, 2100),
+            (codes::NVENC_SESSION_LIMIT, 2200),
+            (codes::UNSUPPORTED, 2300),
+        ];
+        for (code, value) in expected {
+            assert_eq!(code, value);
+        }
+        // A new code needs its value pinned above
+        assert_eq!(expected.len(), codes::ALL.len());
+    }
+
+    #[test]
+    fn cancellation_comes_from_the_portal_response() {
+        let cancelled = WaycapError::from(portal_screencast_waycap::PortalError::Cancelled);
+        assert_eq!(cancelled.error_code(), codes::PORTAL_CANCELLED);
+        assert_eq!(
+            cancelled.to_string(),
+            "[E1301] XDG Portal error: Cancelled by the user"
+        );
+        // Only the response code counts, not a message which happens to read the same
+        let message = WaycapError::Portal("Cancelled by the user".to_string());
+        assert_eq!(message.error_code(), codes::PORTAL);
+    }
+}
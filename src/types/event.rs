@@ -0,0 +1,141 @@
+use std::time::Duration;
+
+use crossbeam::channel::{Sender, TrySendError};
+
+use crate::pipeline::{interleaver::InterleaverControl, shutdown::capture_clock_ns};
+
+use super::{
+    config::{DisconnectPolicy, ScreenBlankPolicy},
+    encoder_info::EncoderDelay,
+    media_packet::{MediaPacket, TimedEvent},
+    time::CaptureTime,
+    video_frame::VideoStreamInfo,
+};
+
+/// Stage of the video pipeline, from the PipeWire callback to the consumer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PipelineStage {
+    /// PipeWire delivers buffers but no frames are queued for the encoder
+    Capture,
+    /// Frames are queued but the encoding thread does not pick them up
+    EncoderInput,
+    /// Frames are submitted to the encoder but no packets come out
+    EncoderOutput,
+    /// Packets are produced but the consumer does not receive them
+    Consumer,
+}
+
+impl PipelineStage {
+    pub(crate) const COUNT: usize = 4;
+    pub(crate) const ALL: [PipelineStage; Self::COUNT] = [
+        PipelineStage::Capture,
+        PipelineStage::EncoderInput,
+        PipelineStage::EncoderOutput,
+        PipelineStage::Consumer,
+    ];
+}
+
+/// How a locked or blanked screen was noticed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlankReason {
+    /// The compositor paused the stream
+    StreamPaused,
+    /// The compositor kept delivering black frames for a few seconds
+    BlackFrames,
+}
+
+/// Notable things happening during a capture, see [`crate::Capture::get_event_receiver`]
+#[derive(Debug, Clone)]
+pub enum CaptureEvent {
+    /// A stage of the video pipeline stopped making progress while its input kept moving
+    PipelineStalled {
+        stage: PipelineStage,
+        /// Raw frames waiting for the encoder
+        raw_queue_depth: usize,
+        /// Encoded packets waiting for the consumer
+        encoded_queue_depth: usize,
+        /// Latest error seen by each stage that had one
+        last_errors: Vec<(PipelineStage, String)>,
+    },
+    /// Opening the video encoder failed because the NVENC session limit was reached, it will be
+    /// retried after `retry_in`
+    WaitingForEncoder {
+        attempt: u32,
+        max_attempts: u32,
+        retry_in: Duration,
+    },
+    /// PipeWire keeps delivering buffers flagged as corrupted, which usually points to a driver
+    /// problem. Corrupted buffers are skipped and counted in
+    /// [`crate::types::stats::CaptureStats::frames_corrupted`].
+    CorruptedBuffers { consecutive: u32 },
+    /// An encoded audio packet was dropped because the audio receiver was full, leaving a `gap`
+    /// in the audio track. See
+    /// [`crate::pipeline::builder::CaptureBuilder::with_audio_overflow_policy`].
+    AudioFrameDropped { gap: Duration },
+    /// Every receiver of the encoded video was dropped, the capture reacts according to `policy`
+    ConsumerDisconnected { policy: DisconnectPolicy },
+    /// [`crate::Capture::switch_source`] switched to a new source. The video packets from here on
+    /// start with a keyframe and have the size in `info`.
+    SourceSwitched { info: VideoStreamInfo },
+    /// The compositor resized the video stream, e.g. for a rotated monitor or a new mode, and
+    /// the encoder was reset for it. The video packets from the frame captured `at` on start
+    /// with a keyframe and have the size in `info`, so consumers can start a new file or write
+    /// new codec parameters there.
+    ResolutionChanged {
+        info: VideoStreamInfo,
+        at: CaptureTime,
+    },
+    /// The screen was locked or blanked, the capture reacts according to `policy`. See
+    /// [`crate::pipeline::builder::CaptureBuilder::with_screen_blank_policy`].
+    ScreenBlanked {
+        reason: BlankReason,
+        policy: ScreenBlankPolicy,
+    },
+    /// The screen is back after a [`CaptureEvent::ScreenBlanked`]
+    ScreenRestored,
+    /// Packets take far longer to come out of the video encoder than its configuration
+    /// explains, which usually means the encoder or its consumer is stalling. `measured` is
+    /// [`crate::types::stats::CaptureStats::encoder_latency`]. Reported again only after the
+    /// latency went back to normal.
+    EncoderLatencyMismatch {
+        expected: EncoderDelay,
+        measured: Duration,
+    },
+}
+
+/// Sender for [`CaptureEvent`]s which never blocks the capture.
+#[derive(Clone)]
+pub(crate) struct EventSender {
+    events: Sender<CaptureEvent>,
+    /// The interleaver of the single output mode, which gets every event too
+    media: Option<Sender<InterleaverControl>>,
+}
+
+impl EventSender {
+    pub fn new(sender: Sender<CaptureEvent>) -> Self {
+        Self {
+            events: sender,
+            media: None,
+        }
+    }
+
+    pub fn with_media(mut self, interleaver: Sender<InterleaverControl>) -> Self {
+        self.media = Some(interleaver);
+        self
+    }
+
+    pub fn send(&self, event: CaptureEvent) {
+        if let Some(media) = &self.media {
+            let packet = MediaPacket::Event(TimedEvent {
+                timestamp: CaptureTime::from_nanos(capture_clock_ns()),
+                event: event.clone(),
+            });
+            if let Err(TrySendError::Full(_)) = media.try_send(InterleaverControl::Sparse(packet)) {
+                log::warn!("Media receiver is not keeping up, dropping {event:?} from it");
+            }
+        }
+        if let Err(TrySendError::Full(event)) = self.events.try_send(event) {
+            log::warn!("Event receiver is full, dropping {event:?}");
+        }
+    }
+}
@@ -0,0 +1,162 @@
+use std::{
+    os::fd::OwnedFd,
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        Arc,
+    },
+};
+
+use crossbeam::queue::ArrayQueue;
+use drm_fourcc::DrmFourcc;
+
+use super::{
+    error::{Result, WaycapError},
+    time::CaptureTime,
+};
+
+/// A DMA-BUF owned by the application which captured frames are copied into, e.g. exported from
+/// a Vulkan image or GL texture pool. Registered with
+/// [`crate::Capture::register_external_buffers`].
+#[derive(Debug)]
+pub struct ExternalBuffer {
+    /// The pool keeps this open until the capture closes or another pool is registered, pass a
+    /// duplicate if you keep using the fd yourself
+    pub fd: OwnedFd,
+    /// One of the 8 bit RGB formats, [`DrmFourcc::Argb8888`], [`DrmFourcc::Xrgb8888`],
+    /// [`DrmFourcc::Abgr8888`] or [`DrmFourcc::Xbgr8888`]
+    pub fourcc: DrmFourcc,
+    pub modifier: u64,
+    pub width: u32,
+    pub height: u32,
+    pub offset: u32,
+    pub stride: u32,
+}
+
+impl ExternalBuffer {
+    pub(crate) fn validate(&self) -> Result<()> {
+        if !matches!(
+            self.fourcc,
+            DrmFourcc::Argb8888 | DrmFourcc::Xrgb8888 | DrmFourcc::Abgr8888 | DrmFourcc::Xbgr8888
+        ) {
+            return Err(WaycapError::Validation(format!(
+                "External buffers must be 8 bit RGB, not {}",
+                self.fourcc
+            )));
+        }
+        if self.width == 0 || self.height == 0 {
+            return Err(WaycapError::Validation(
+                "External buffers can't be empty".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// A captured frame was copied into an external buffer. The buffer is not written again until
+/// it was handed back with [`ExternalBufferPool::release`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExternalFrame {
+    /// Index of the buffer in the registered pool
+    pub index: usize,
+    pub timestamp: CaptureTime,
+}
+
+const FREE: u8 = 0;
+const COPYING: u8 = 1;
+const DELIVERED: u8 = 2;
+
+/// Handle to the external buffers registered with a capture, for returning them once the
+/// application is done with a frame.
+#[derive(Debug, Clone)]
+pub struct ExternalBufferPool {
+    state: Arc<PoolState>,
+}
+
+#[derive(Debug)]
+struct PoolState {
+    free: ArrayQueue<usize>,
+    entries: Vec<AtomicU8>,
+}
+
+impl ExternalBufferPool {
+    pub(crate) fn new(len: usize) -> Self {
+        let free = ArrayQueue::new(len);
+        for index in 0..len {
+            let _ = free.push(index);
+        }
+        Self {
+            state: Arc::new(PoolState {
+                free,
+                entries: (0..len).map(|_| AtomicU8::new(FREE)).collect(),
+            }),
+        }
+    }
+
+    /// Hand the buffer of a delivered [`ExternalFrame`] back, so later frames can be copied
+    /// into it
+    pub fn release(&self, index: usize) -> Result<()> {
+        let delivered = self.state.entries.get(index).is_some_and(|entry| {
+            entry
+                .compare_exchange(DELIVERED, FREE, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+        });
+        if !delivered {
+            return Err(WaycapError::Validation(format!(
+                "External buffer {index} is not held by the application"
+            )));
+        }
+        let _ = self.state.free.push(index);
+        Ok(())
+    }
+
+    /// Registered buffers
+    pub fn capacity(&self) -> usize {
+        self.state.entries.len()
+    }
+
+    /// Buffers free for the next frames
+    pub fn available(&self) -> usize {
+        self.state.free.len()
+    }
+
+    /// Reserve a free buffer to copy a frame into
+    pub(crate) fn acquire(&self) -> Option<usize> {
+        let index = self.state.free.pop()?;
+        self.state.entries[index].store(COPYING, Ordering::Release);
+        Some(index)
+    }
+
+    /// The frame copied into `index` is handed to the application
+    pub(crate) fn delivered(&self, index: usize) {
+        self.state.entries[index].store(DELIVERED, Ordering::Release);
+    }
+
+    /// Return a reserved buffer whose copy failed or was never delivered
+    pub(crate) fn abandon(&self, index: usize) {
+        self.state.entries[index].store(FREE, Ordering::Release);
+        let _ = self.state.free.push(index);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buffers_are_reused_after_release() {
+        let pool = ExternalBufferPool::new(2);
+        let (first, second) = (pool.acquire().unwrap(), pool.acquire().unwrap());
+        assert_eq!(pool.acquire(), None);
+
+        // Only delivered buffers belong to the application
+        assert!(pool.release(first).is_err());
+        pool.delivered(first);
+        pool.release(first).unwrap();
+        assert!(pool.release(first).is_err());
+        assert!(pool.release(5).is_err());
+
+        pool.abandon(second);
+        assert_eq!(pool.available(), 2);
+        assert_eq!(pool.capacity(), 2);
+    }
+}
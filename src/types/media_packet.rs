@@ -0,0 +1,62 @@
+use super::{
+    audio_frame::EncodedAudioFrame, event::CaptureEvent, time::CaptureTime,
+    video_frame::EncodedVideoFrame,
+};
+
+/// Largest blob accepted by [`crate::Capture::inject_metadata`]
+pub const MAX_METADATA_SIZE: usize = 64 * 1024;
+/// Longest key accepted by [`crate::Capture::inject_metadata`]
+pub const MAX_METADATA_KEY_LEN: usize = 256;
+
+/// A single encoded packet of any media type, as delivered in single output mode.
+#[derive(Debug)]
+pub enum MediaPacket {
+    Video(EncodedVideoFrame),
+    Audio(EncodedAudioFrame),
+    Metadata(TimedMetadata),
+    /// See [`crate::pipeline::builder::CaptureBuilder::with_cursor_metadata`]
+    Cursor(CursorUpdate),
+    /// Every [`CaptureEvent`] is delivered here too, besides through
+    /// [`crate::Capture::get_event_receiver`]
+    Event(TimedEvent),
+}
+
+impl MediaPacket {
+    /// Capture timestamp that packets are ordered by
+    pub fn timestamp(&self) -> CaptureTime {
+        match self {
+            MediaPacket::Video(frame) => frame.dts.into(),
+            MediaPacket::Audio(frame) => frame.timestamp,
+            MediaPacket::Metadata(metadata) => metadata.timestamp,
+            MediaPacket::Cursor(cursor) => cursor.timestamp,
+            MediaPacket::Event(event) => event.timestamp,
+        }
+    }
+}
+
+/// An application defined blob added with [`crate::Capture::inject_metadata`], e.g. input
+/// events or game telemetry to overlay during playback
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimedMetadata {
+    pub timestamp: CaptureTime,
+    pub key: String,
+    pub data: Vec<u8>,
+}
+
+/// The cursor moved or changed its image, in the pixels of the captured source
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CursorUpdate {
+    /// Capture time of the frame the compositor sent the update with
+    pub timestamp: CaptureTime,
+    /// Where the top left corner of the cursor image is
+    pub position: (i32, i32),
+    /// Offset of the pointing spot within the cursor image
+    pub hotspot: (i32, i32),
+}
+
+/// A [`CaptureEvent`] and when it was emitted
+#[derive(Debug, Clone)]
+pub struct TimedEvent {
+    pub timestamp: CaptureTime,
+    pub event: CaptureEvent,
+}
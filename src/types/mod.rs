@@ -1,4 +1,13 @@
 pub mod audio_frame;
 pub mod config;
+pub mod encoder_info;
 pub mod error;
+pub mod event;
+pub mod external_buffer;
+pub mod media_packet;
+pub mod preview;
+pub mod receiver;
+pub mod session;
+pub mod stats;
+pub mod time;
 pub mod video_frame;
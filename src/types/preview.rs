@@ -0,0 +1,83 @@
+use super::{
+    error::{Result, WaycapError},
+    time::CaptureTime,
+};
+
+/// Size and rate of the frames of [`crate::Capture::preview_frames`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PreviewConfig {
+    /// Largest size the frames are scaled into, keeping their aspect ratio. Smaller frames are
+    /// not scaled up.
+    pub max_width: u32,
+    pub max_height: u32,
+    /// Most frames a second, by their capture timestamps
+    pub max_fps: u32,
+    /// Frames the receiver holds before new ones are dropped
+    pub capacity: usize,
+}
+
+impl Default for PreviewConfig {
+    /// 480p at 30fps, two frames deep
+    fn default() -> Self {
+        Self {
+            max_width: 854,
+            max_height: 480,
+            max_fps: 30,
+            capacity: 2,
+        }
+    }
+}
+
+impl PreviewConfig {
+    pub(crate) fn validate(&self) -> Result<()> {
+        if self.max_width == 0 || self.max_height == 0 || self.max_fps == 0 || self.capacity == 0 {
+            return Err(WaycapError::Validation(
+                "The preview size, rate and capacity must be above 0".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Size `width`x`height` frames are scaled to, at least a pixel on each side
+    pub(crate) fn scaled_size(&self, width: u32, height: u32) -> (u32, u32) {
+        let (width, height) = (u64::from(width.max(1)), u64::from(height.max(1)));
+        let (max_width, max_height) = (u64::from(self.max_width), u64::from(self.max_height));
+        if width <= max_width && height <= max_height {
+            return (width as u32, height as u32);
+        }
+        // Whichever side hits its limit first decides the scale
+        if width * max_height >= height * max_width {
+            (max_width as u32, (height * max_width / width).max(1) as u32)
+        } else {
+            (
+                (width * max_height / height).max(1) as u32,
+                max_height as u32,
+            )
+        }
+    }
+}
+
+/// A downscaled copy of a frame handed to the video encoder
+#[derive(Debug, Clone)]
+pub struct PreviewFrame {
+    pub image: image::RgbaImage,
+    /// Capture timestamp of the frame, the pts it is encoded with
+    pub timestamp: CaptureTime,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_are_scaled_into_the_preview_size() {
+        let config = PreviewConfig::default();
+        assert_eq!(config.scaled_size(2560, 1440), (853, 480));
+        // Ultrawide monitors hit the width first, portrait ones the height
+        assert_eq!(config.scaled_size(3440, 1440), (854, 357));
+        assert_eq!(config.scaled_size(1080, 1920), (270, 480));
+        // Small windows are kept as they are
+        assert_eq!(config.scaled_size(640, 360), (640, 360));
+    }
+}
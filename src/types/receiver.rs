@@ -0,0 +1,118 @@
+use std::{sync::Arc, time::Duration};
+
+use crossbeam::channel::{Receiver, RecvTimeoutError};
+
+use super::{audio_frame::EncodedAudioFrame, stats::StatsCounters, video_frame::EncodedVideoFrame};
+
+/// Encoded video frames of a capture, see [`crate::Capture::video_frames`].
+///
+/// Iterating blocks until the next frame arrives and ends once the capture is closed.
+pub struct VideoFrames {
+    receiver: Receiver<EncodedVideoFrame>,
+    stats: Arc<StatsCounters>,
+}
+
+impl VideoFrames {
+    pub(crate) fn new(receiver: Receiver<EncodedVideoFrame>, stats: Arc<StatsCounters>) -> Self {
+        Self { receiver, stats }
+    }
+
+    /// Wait up to `timeout` for the next frame.
+    ///
+    /// Returns [`RecvTimeoutError::Disconnected`] once the capture is closed and every frame was
+    /// received.
+    pub fn recv_timeout(
+        &self,
+        timeout: Duration,
+    ) -> std::result::Result<EncodedVideoFrame, RecvTimeoutError> {
+        let frame = self.receiver.recv_timeout(timeout)?;
+        self.stats.mark_packet_consumed();
+        Ok(frame)
+    }
+
+    /// Iterate over the frames which are ready now without blocking
+    pub fn try_iter(&self) -> impl Iterator<Item = EncodedVideoFrame> + '_ {
+        self.receiver
+            .try_iter()
+            .inspect(|_| self.stats.mark_packet_consumed())
+    }
+
+    /// Block until the next keyframe, discarding the frames before it.
+    ///
+    /// Returns `None` once the capture is closed.
+    pub fn next_keyframe(&mut self) -> Option<EncodedVideoFrame> {
+        self.find(|frame| frame.is_keyframe)
+    }
+
+    /// Number of frames waiting to be received
+    pub fn len(&self) -> usize {
+        self.receiver.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.receiver.is_empty()
+    }
+}
+
+impl Iterator for VideoFrames {
+    type Item = EncodedVideoFrame;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let frame = self.receiver.recv().ok()?;
+        self.stats.mark_packet_consumed();
+        Some(frame)
+    }
+}
+
+/// Encoded audio frames of a capture, see [`crate::Capture::audio_frames`].
+///
+/// Iterating blocks until the next frame arrives and ends once the capture is closed.
+pub struct AudioFrames {
+    receiver: Receiver<EncodedAudioFrame>,
+    stats: Arc<StatsCounters>,
+}
+
+impl AudioFrames {
+    pub(crate) fn new(receiver: Receiver<EncodedAudioFrame>, stats: Arc<StatsCounters>) -> Self {
+        Self { receiver, stats }
+    }
+
+    /// Wait up to `timeout` for the next frame.
+    ///
+    /// Returns [`RecvTimeoutError::Disconnected`] once the capture is closed and every frame was
+    /// received.
+    pub fn recv_timeout(
+        &self,
+        timeout: Duration,
+    ) -> std::result::Result<EncodedAudioFrame, RecvTimeoutError> {
+        let frame = self.receiver.recv_timeout(timeout)?;
+        self.stats.mark_audio_consumed();
+        Ok(frame)
+    }
+
+    /// Iterate over the frames which are ready now without blocking
+    pub fn try_iter(&self) -> impl Iterator<Item = EncodedAudioFrame> + '_ {
+        self.receiver
+            .try_iter()
+            .inspect(|_| self.stats.mark_audio_consumed())
+    }
+
+    /// Number of frames waiting to be received
+    pub fn len(&self) -> usize {
+        self.receiver.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.receiver.is_empty()
+    }
+}
+
+impl Iterator for AudioFrames {
+    type Item = EncodedAudioFrame;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let frame = self.receiver.recv().ok()?;
+        self.stats.mark_audio_consumed();
+        Some(frame)
+    }
+}
@@ -0,0 +1,42 @@
+use crate::portal::SessionMetadata;
+
+use super::{
+    config::{
+        AudioEncoder, AudioRingConfig, DisconnectPolicy, OverflowPolicy, ScreenBlankPolicy,
+        VideoConfig, VideoEncoder, WatchdogConfig,
+    },
+    video_frame::VideoStreamInfo,
+};
+
+/// Everything needed to start a capture like a running one again, e.g. after the recorder
+/// crashed. Taken with [`crate::Capture::session_snapshot`] and resumed with
+/// [`crate::Capture::resume_from`]. Serializable with the `serde` feature.
+///
+/// Holds settings only, never frame data. The portal's restore token in
+/// [`SessionMetadata::restore_token`] lets the resumed capture skip the dialog, but is only
+/// handed out with [`SessionMetadata::persist_session`] set and can only be used once, so take
+/// a new snapshot after resuming.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SessionSnapshot {
+    /// Including the restore token of the current portal session, if the portal handed one out
+    pub portal_metadata: SessionMetadata,
+    /// Negotiated stream parameters, offered first on resume like
+    /// [`crate::pipeline::builder::CaptureBuilder::with_fast_start`]
+    pub stream_info: Option<VideoStreamInfo>,
+    /// `None` detects the encoder for the GPU again
+    pub video_encoder: Option<VideoEncoder>,
+    pub video_config: VideoConfig,
+    /// `None` for captures without audio
+    pub audio_encoder: Option<AudioEncoder>,
+    pub include_cursor: bool,
+    pub cursor_metadata: bool,
+    pub trim_audio: bool,
+    pub audio_overflow: OverflowPolicy,
+    pub audio_ring: AudioRingConfig,
+    pub disconnect_policy: DisconnectPolicy,
+    pub screen_blank_policy: ScreenBlankPolicy,
+    pub single_output: bool,
+    pub watchdog: Option<WatchdogConfig>,
+    pub target_fps: u64,
+}
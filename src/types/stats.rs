@@ -0,0 +1,407 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering},
+        Mutex, OnceLock,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{
+    encoders::audio::{AUDIO_TIME_BASE, SAMPLE_RATE},
+    utils::TIME_UNIT_NS,
+};
+
+use super::{
+    event::PipelineStage,
+    time::{CaptureTime, StreamPts},
+};
+
+/// Frames whose submission time is remembered, far more than any encoder keeps in flight
+const SUBMITTED_CAPACITY: usize = 64;
+
+/// Snapshot of the statistics of a capture session
+#[derive(Debug, Clone, Default)]
+pub struct CaptureStats {
+    /// Time between the portal handing over the stream and the first frame arriving from
+    /// PipeWire. `None` until the first frame arrived.
+    pub time_to_first_frame: Option<Duration>,
+    /// Buffers PipeWire handed to the video capture callback
+    pub frames_captured: u64,
+    /// Captured buffers skipped because PipeWire flagged them as corrupted
+    pub frames_corrupted: u64,
+    /// Frames queued for the video encoder
+    pub frames_queued: u64,
+    /// Frames submitted to the video encoder
+    pub frames_encoded: u64,
+    /// Frames dropped because the raw frame queue or the video receiver was full
+    pub frames_dropped: u64,
+    /// The part of [`Self::frames_dropped`] the capture callback dropped before they reached the
+    /// raw frame queue
+    pub frames_dropped_at_capture: u64,
+    /// The part of [`Self::frames_dropped`] the video encoder dropped without encoding them,
+    /// frames with closed DMA-BUF fds and passed through frames before the first keyframe.
+    /// Counted in [`Self::frames_encoded`] but never emitted as packets.
+    pub frames_dropped_by_encoder: u64,
+    /// Frames skipped before the video encoder because the compositor delivered more than the
+    /// target fps, than [`crate::types::config::VideoConfig::cfr`] or than one per timelapse
+    /// interval
+    pub frames_rate_limited: u64,
+    /// Frames encoded once more for [`crate::types::config::VideoConfig::cfr`] or a timelapse
+    /// because no frame was captured in time, on top of [`Self::frames_encoded`]
+    pub frames_duplicated: u64,
+    /// Frames skipped before the video encoder because they were identical to the last encoded
+    /// one, see [`crate::types::config::VideoConfig::dedup`]
+    pub frames_deduplicated: u64,
+    /// Frames dropped before the video encoder because the GPU had not finished rendering them
+    /// in time, which would have shown torn or half drawn
+    pub frames_fence_timed_out: u64,
+    /// Average time the video encoder spent on a frame, `None` until a frame was encoded
+    pub avg_encode_time: Option<Duration>,
+    /// Smoothed time between submitting a frame to the video encoder and handing its packet to
+    /// the output channel, to compare with [`crate::types::encoder_info::EncoderInfo::delay`].
+    /// `None` until a packet was emitted.
+    pub encoder_latency: Option<Duration>,
+    /// Encoded video packets handed to the output channel
+    pub packets_emitted: u64,
+    /// Encoded video packets received through [`crate::types::receiver::VideoFrames`]
+    pub packets_consumed: u64,
+    /// Encoded audio packets handed to the output channel
+    pub audio_packets_emitted: u64,
+    /// Encoded audio packets received through [`crate::types::receiver::AudioFrames`]
+    pub audio_packets_consumed: u64,
+    /// Encoded audio packets dropped because the audio receiver was full
+    pub audio_packets_dropped: u64,
+    /// Captured audio periods dropped because the audio encoder fell behind and the ring between
+    /// the capture callback and the encoder was full
+    pub audio_periods_dropped: u64,
+    /// Average time the realtime audio capture callback took, `None` until audio was captured
+    pub avg_audio_callback_time: Option<Duration>,
+    /// Longest time the audio capture callback took
+    pub max_audio_callback_time: Option<Duration>,
+    /// Audio capture callbacks which took longer than the audio they delivered lasts, each one
+    /// risks an xrun
+    pub audio_callbacks_over_budget: u64,
+    /// Frames copied into the buffers registered with
+    /// [`crate::Capture::register_external_buffers`] and announced to the application
+    pub external_copies: u64,
+    /// Frames not copied into the external buffers because the application held all of them
+    pub external_copies_skipped: u64,
+    /// Frames sent to the receiver of [`crate::Capture::preview_frames`]
+    pub preview_frames: u64,
+    /// Preview frames dropped because the receiver or the preview thread was still busy with
+    /// earlier ones
+    pub preview_frames_dropped: u64,
+    /// Whether the VAAPI encoder copies the frames through the CPU, because mapping them failed
+    /// or [`crate::types::config::VaapiImport::Upload`] asked for it, see
+    /// [`crate::types::config::VideoConfig::vaapi_import`]
+    pub cpu_upload: bool,
+}
+
+/// Final length of the tracks, returned by [`crate::Capture::finish`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FinishSummary {
+    /// Time between the first and the last emitted video packet
+    pub video_duration: Duration,
+    /// Total duration of all emitted audio packets
+    pub audio_duration: Duration,
+}
+
+/// Counters shared with the capture and encoding threads
+#[derive(Debug, Default)]
+pub(crate) struct StatsCounters {
+    stream_started: OnceLock<Instant>,
+    time_to_first_frame: OnceLock<Duration>,
+    frames_captured: AtomicU64,
+    frames_corrupted: AtomicU64,
+    frames_queued: AtomicU64,
+    frames_encoded: AtomicU64,
+    frames_dropped: AtomicU64,
+    frames_dropped_at_capture: AtomicU64,
+    frames_dropped_by_encoder: AtomicU64,
+    frames_rate_limited: AtomicU64,
+    frames_duplicated: AtomicU64,
+    frames_deduplicated: AtomicU64,
+    frames_fence_timed_out: AtomicU64,
+    encode_time_ns: AtomicU64,
+    /// Frames in the video encoder and when they were submitted
+    submitted: Mutex<VecDeque<(CaptureTime, Instant)>>,
+    encoder_latency_ns: AtomicU64,
+    packets_emitted: AtomicU64,
+    packets_consumed: AtomicU64,
+    audio_packets_emitted: AtomicU64,
+    audio_packets_consumed: AtomicU64,
+    audio_packets_dropped: AtomicU64,
+    audio_periods_dropped: AtomicU64,
+    audio_callbacks: AtomicU64,
+    audio_callback_ns: AtomicU64,
+    audio_callback_max_ns: AtomicU64,
+    audio_callbacks_over_budget: AtomicU64,
+    external_copies: AtomicU64,
+    external_copies_skipped: AtomicU64,
+    preview_frames: AtomicU64,
+    preview_frames_dropped: AtomicU64,
+    cpu_upload: AtomicBool,
+    first_video_pts: OnceLock<CaptureTime>,
+    last_video_pts: AtomicI64,
+    audio_samples_emitted: AtomicU64,
+    last_errors: Mutex<[Option<String>; PipelineStage::COUNT]>,
+}
+
+impl StatsCounters {
+    pub fn mark_stream_started(&self) {
+        let _ = self.stream_started.set(Instant::now());
+    }
+
+    pub fn mark_frame_received(&self) {
+        self.frames_captured.fetch_add(1, Ordering::Relaxed);
+        if self.time_to_first_frame.get().is_some() {
+            return;
+        }
+        if let Some(started) = self.stream_started.get() {
+            let _ = self.time_to_first_frame.set(started.elapsed());
+        }
+    }
+
+    pub fn mark_frame_corrupted(&self) {
+        self.frames_corrupted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn mark_frame_queued(&self) {
+        self.frames_queued.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn mark_frame_encoded(&self) {
+        self.frames_encoded.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn mark_frame_dropped(&self) {
+        self.frames_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A frame dropped by the capture callback, counted in both drop counters
+    pub fn mark_capture_dropped(&self) {
+        self.frames_dropped_at_capture
+            .fetch_add(1, Ordering::Relaxed);
+        self.mark_frame_dropped();
+    }
+
+    /// A frame submitted to the video encoder which it dropped instead of encoding, counted in
+    /// both drop counters
+    pub fn mark_encoder_dropped(&self) {
+        self.frames_dropped_by_encoder
+            .fetch_add(1, Ordering::Relaxed);
+        self.mark_frame_dropped();
+    }
+
+    pub fn mark_frame_rate_limited(&self) {
+        self.frames_rate_limited.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn mark_frame_duplicated(&self) {
+        self.frames_duplicated.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn mark_frame_deduplicated(&self) {
+        self.frames_deduplicated.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn mark_fence_timeout(&self) {
+        self.frames_fence_timed_out.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Time the encoder took for a frame counted by [`Self::mark_frame_encoded`]
+    pub fn record_encode_time(&self, elapsed: Duration) {
+        self.encode_time_ns
+            .fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    /// A frame is handed to the video encoder, its packet will carry `timestamp` as pts
+    pub fn mark_frame_submitted(&self, timestamp: CaptureTime) {
+        let mut submitted = self.submitted.lock().unwrap();
+        // Frames the encoder dropped never get a packet
+        if submitted.len() == SUBMITTED_CAPACITY {
+            submitted.pop_front();
+        }
+        submitted.push_back((timestamp, Instant::now()));
+    }
+
+    /// Time since PipeWire started streaming
+    pub fn stream_uptime(&self) -> Option<Duration> {
+        self.stream_started.get().map(Instant::elapsed)
+    }
+
+    pub fn mark_packet_emitted(&self, pts: CaptureTime) {
+        self.packets_emitted.fetch_add(1, Ordering::Relaxed);
+        self.record_encoder_latency(pts);
+        let _ = self.first_video_pts.set(pts);
+        self.last_video_pts
+            .fetch_max(pts.as_nanos(), Ordering::Relaxed);
+    }
+
+    /// Exponential moving average over about 8 packets, which still shows a stall within a
+    /// few frames
+    fn record_encoder_latency(&self, pts: CaptureTime) {
+        let submitted_at = {
+            let mut submitted = self.submitted.lock().unwrap();
+            // B-frames come out of order, the packet isn't always the oldest submission
+            let index = submitted
+                .iter()
+                .position(|(timestamp, _)| *timestamp == pts);
+            index.and_then(|index| submitted.remove(index))
+        };
+        let Some((_, submitted_at)) = submitted_at else {
+            return;
+        };
+        let latency = submitted_at.elapsed().as_nanos() as u64;
+        let average = self.encoder_latency_ns.load(Ordering::Relaxed);
+        let average = match average {
+            0 => latency,
+            average => average - average / 8 + latency / 8,
+        };
+        // Only the encoding thread writes it, 0 means no packet yet
+        self.encoder_latency_ns
+            .store(average.max(1), Ordering::Relaxed);
+    }
+
+    /// Smoothed submission to packet time of the video encoder
+    pub fn encoder_latency(&self) -> Option<Duration> {
+        match self.encoder_latency_ns.load(Ordering::Relaxed) {
+            0 => None,
+            latency => Some(Duration::from_nanos(latency)),
+        }
+    }
+
+    pub fn mark_packet_consumed(&self) {
+        self.packets_consumed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Pts of the last video packet handed to the receiver, in capture time
+    pub fn last_video_pts(&self) -> Option<CaptureTime> {
+        self.first_video_pts.get()?;
+        Some(CaptureTime::from_nanos(
+            self.last_video_pts.load(Ordering::Relaxed),
+        ))
+    }
+
+    /// `duration` is the length of the emitted audio packet
+    pub fn mark_audio_emitted(&self, duration: StreamPts) {
+        let samples = duration.rescale(AUDIO_TIME_BASE).value;
+        self.audio_packets_emitted.fetch_add(1, Ordering::Relaxed);
+        self.audio_samples_emitted
+            .fetch_add(samples.max(0) as u64, Ordering::Relaxed);
+    }
+
+    pub fn mark_audio_consumed(&self) {
+        self.audio_packets_consumed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn mark_audio_dropped(&self) {
+        self.audio_packets_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn mark_audio_period_dropped(&self) {
+        self.audio_periods_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Time the audio capture callback took for a quantum lasting `budget`
+    pub fn record_audio_callback(&self, elapsed: Duration, budget: Option<Duration>) {
+        let elapsed_ns = elapsed.as_nanos() as u64;
+        self.audio_callbacks.fetch_add(1, Ordering::Relaxed);
+        self.audio_callback_ns
+            .fetch_add(elapsed_ns, Ordering::Relaxed);
+        self.audio_callback_max_ns
+            .fetch_max(elapsed_ns, Ordering::Relaxed);
+        if budget.is_some_and(|budget| elapsed > budget) {
+            self.audio_callbacks_over_budget
+                .fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn mark_external_copied(&self) {
+        self.external_copies.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn mark_external_copy_skipped(&self) {
+        self.external_copies_skipped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn mark_preview_sent(&self) {
+        self.preview_frames.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn mark_preview_dropped(&self) {
+        self.preview_frames_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn mark_cpu_upload(&self) {
+        self.cpu_upload.store(true, Ordering::Relaxed);
+    }
+
+    pub fn finish_summary(&self) -> FinishSummary {
+        let video_duration = match (self.first_video_pts.get(), self.last_video_pts()) {
+            (Some(first), Some(last)) => last - *first,
+            _ => Duration::ZERO,
+        };
+        let audio_samples = self.audio_samples_emitted.load(Ordering::Relaxed);
+        FinishSummary {
+            video_duration,
+            audio_duration: Duration::from_nanos(audio_samples * TIME_UNIT_NS / SAMPLE_RATE as u64),
+        }
+    }
+
+    /// Remember the latest error seen by a stage of the pipeline
+    pub fn record_error(&self, stage: PipelineStage, error: impl ToString) {
+        self.last_errors.lock().unwrap()[stage as usize] = Some(error.to_string());
+    }
+
+    pub fn last_errors(&self) -> Vec<(PipelineStage, String)> {
+        let errors = self.last_errors.lock().unwrap();
+        PipelineStage::ALL
+            .into_iter()
+            .filter_map(|stage| errors[stage as usize].clone().map(|err| (stage, err)))
+            .collect()
+    }
+
+    pub fn snapshot(&self) -> CaptureStats {
+        let frames_encoded = self.frames_encoded.load(Ordering::Relaxed);
+        let audio_callbacks = self.audio_callbacks.load(Ordering::Relaxed);
+        CaptureStats {
+            time_to_first_frame: self.time_to_first_frame.get().copied(),
+            frames_captured: self.frames_captured.load(Ordering::Relaxed),
+            frames_corrupted: self.frames_corrupted.load(Ordering::Relaxed),
+            frames_queued: self.frames_queued.load(Ordering::Relaxed),
+            frames_encoded,
+            frames_dropped: self.frames_dropped.load(Ordering::Relaxed),
+            frames_dropped_at_capture: self.frames_dropped_at_capture.load(Ordering::Relaxed),
+            frames_dropped_by_encoder: self.frames_dropped_by_encoder.load(Ordering::Relaxed),
+            frames_rate_limited: self.frames_rate_limited.load(Ordering::Relaxed),
+            frames_duplicated: self.frames_duplicated.load(Ordering::Relaxed),
+            frames_deduplicated: self.frames_deduplicated.load(Ordering::Relaxed),
+            frames_fence_timed_out: self.frames_fence_timed_out.load(Ordering::Relaxed),
+            avg_encode_time: (frames_encoded > 0).then(|| {
+                Duration::from_nanos(self.encode_time_ns.load(Ordering::Relaxed) / frames_encoded)
+            }),
+            encoder_latency: self.encoder_latency(),
+            packets_emitted: self.packets_emitted.load(Ordering::Relaxed),
+            packets_consumed: self.packets_consumed.load(Ordering::Relaxed),
+            audio_packets_emitted: self.audio_packets_emitted.load(Ordering::Relaxed),
+            audio_packets_consumed: self.audio_packets_consumed.load(Ordering::Relaxed),
+            audio_packets_dropped: self.audio_packets_dropped.load(Ordering::Relaxed),
+            audio_periods_dropped: self.audio_periods_dropped.load(Ordering::Relaxed),
+            avg_audio_callback_time: (audio_callbacks > 0).then(|| {
+                Duration::from_nanos(
+                    self.audio_callback_ns.load(Ordering::Relaxed) / audio_callbacks,
+                )
+            }),
+            max_audio_callback_time: (audio_callbacks > 0)
+                .then(|| Duration::from_nanos(self.audio_callback_max_ns.load(Ordering::Relaxed))),
+            audio_callbacks_over_budget: self.audio_callbacks_over_budget.load(Ordering::Relaxed),
+            external_copies: self.external_copies.load(Ordering::Relaxed),
+            external_copies_skipped: self.external_copies_skipped.load(Ordering::Relaxed),
+            preview_frames: self.preview_frames.load(Ordering::Relaxed),
+            preview_frames_dropped: self.preview_frames_dropped.load(Ordering::Relaxed),
+            cpu_upload: self.cpu_upload.load(Ordering::Relaxed),
+        }
+    }
+}
@@ -0,0 +1,232 @@
+//! Timestamps which carry their unit with them.
+//!
+//! The capture works with two kinds of time: [`CaptureTime`], the clock PipeWire stamps buffers
+//! with, and [`StreamPts`], an encoder timestamp counted in the time base of its stream. Mixing
+//! the two up used to be a plain `i64` away, now crossing over needs an explicit conversion.
+
+use std::{
+    fmt,
+    ops::{Add, AddAssign, Sub, SubAssign},
+    time::Duration,
+};
+
+use ffmpeg_next::{Rational, Rescale};
+
+use crate::utils::TIME_UNIT_NS;
+
+/// A point on the capture clock (`CLOCK_MONOTONIC`) in nanoseconds
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct CaptureTime(i64);
+
+impl CaptureTime {
+    /// Time base of the capture clock, also used by the video encoders
+    pub const TIME_BASE: Rational = Rational(1, TIME_UNIT_NS as i32);
+
+    pub const fn from_nanos(nanos: i64) -> Self {
+        Self(nanos)
+    }
+
+    pub const fn as_nanos(self) -> i64 {
+        self.0
+    }
+
+    /// The same instant as a timestamp counted in `time_base`
+    pub fn to_pts(self, time_base: Rational) -> StreamPts {
+        StreamPts::new(self.0, Self::TIME_BASE).rescale(time_base)
+    }
+
+    /// Time elapsed since `earlier`, zero if `earlier` is later than `self`
+    pub fn duration_since(self, earlier: CaptureTime) -> Duration {
+        Duration::from_nanos(self.0.saturating_sub(earlier.0).max(0) as u64)
+    }
+
+    /// Distance between two instants regardless of their order
+    pub fn abs_diff(self, other: CaptureTime) -> Duration {
+        Duration::from_nanos(self.0.abs_diff(other.0))
+    }
+}
+
+impl fmt::Display for CaptureTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}ns", self.0)
+    }
+}
+
+impl From<CaptureTime> for i64 {
+    fn from(time: CaptureTime) -> Self {
+        time.0
+    }
+}
+
+impl From<StreamPts> for CaptureTime {
+    fn from(pts: StreamPts) -> Self {
+        Self(pts.rescale(Self::TIME_BASE).value)
+    }
+}
+
+impl TryFrom<Duration> for CaptureTime {
+    type Error = TimeOutOfRange;
+
+    /// Time since the start of the capture clock
+    fn try_from(duration: Duration) -> Result<Self, Self::Error> {
+        i64::try_from(duration.as_nanos())
+            .map(Self)
+            .map_err(|_| TimeOutOfRange)
+    }
+}
+
+impl Add<Duration> for CaptureTime {
+    type Output = CaptureTime;
+
+    fn add(self, rhs: Duration) -> Self::Output {
+        Self(self.0.saturating_add(duration_nanos(rhs)))
+    }
+}
+
+impl AddAssign<Duration> for CaptureTime {
+    fn add_assign(&mut self, rhs: Duration) {
+        *self = *self + rhs;
+    }
+}
+
+impl Sub<Duration> for CaptureTime {
+    type Output = CaptureTime;
+
+    fn sub(self, rhs: Duration) -> Self::Output {
+        Self(self.0.saturating_sub(duration_nanos(rhs)))
+    }
+}
+
+impl SubAssign<Duration> for CaptureTime {
+    fn sub_assign(&mut self, rhs: Duration) {
+        *self = *self - rhs;
+    }
+}
+
+impl Sub for CaptureTime {
+    type Output = Duration;
+
+    /// Same as [`CaptureTime::duration_since`]
+    fn sub(self, rhs: CaptureTime) -> Self::Output {
+        self.duration_since(rhs)
+    }
+}
+
+fn duration_nanos(duration: Duration) -> i64 {
+    i64::try_from(duration.as_nanos()).unwrap_or(i64::MAX)
+}
+
+/// A timestamp or length counted in the time base of an encoded stream, like the pts of a
+/// packet. Set the stream's time base from the encoder, or [`StreamPts::rescale`] into the one
+/// the muxer picked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StreamPts {
+    pub value: i64,
+    pub time_base: Rational,
+}
+
+impl StreamPts {
+    pub const fn new(value: i64, time_base: Rational) -> Self {
+        Self { value, time_base }
+    }
+
+    /// A length counted in `time_base`, rounded to the nearest tick
+    pub fn from_duration(duration: Duration, time_base: Rational) -> Self {
+        Self::new(duration_nanos(duration), CaptureTime::TIME_BASE).rescale(time_base)
+    }
+
+    /// The same time counted in `time_base`, rounded to the nearest tick
+    pub fn rescale(self, time_base: Rational) -> Self {
+        if self.time_base == time_base {
+            return self;
+        }
+        Self::new(self.value.rescale(self.time_base, time_base), time_base)
+    }
+}
+
+impl TryFrom<StreamPts> for Duration {
+    type Error = TimeOutOfRange;
+
+    /// Reads the timestamp as a length, fails for negative values
+    fn try_from(pts: StreamPts) -> Result<Self, Self::Error> {
+        let nanos = pts.rescale(CaptureTime::TIME_BASE).value;
+        u64::try_from(nanos)
+            .map(Duration::from_nanos)
+            .map_err(|_| TimeOutOfRange)
+    }
+}
+
+/// Both sides end up in the time base of the left one
+impl Add for StreamPts {
+    type Output = StreamPts;
+
+    fn add(self, rhs: StreamPts) -> Self::Output {
+        let rhs = rhs.rescale(self.time_base);
+        Self::new(self.value + rhs.value, self.time_base)
+    }
+}
+
+/// Both sides end up in the time base of the left one
+impl Sub for StreamPts {
+    type Output = StreamPts;
+
+    fn sub(self, rhs: StreamPts) -> Self::Output {
+        let rhs = rhs.rescale(self.time_base);
+        Self::new(self.value - rhs.value, self.time_base)
+    }
+}
+
+/// A time could not be represented in the target type
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeOutOfRange;
+
+impl fmt::Display for TimeOutOfRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "time out of range")
+    }
+}
+
+impl std::error::Error for TimeOutOfRange {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AUDIO_TIME_BASE: Rational = Rational(1, 48_000);
+
+    #[test]
+    fn capture_time_round_trips_through_stream_pts() {
+        let time = CaptureTime::from_nanos(1_500_000_000);
+        let pts = time.to_pts(AUDIO_TIME_BASE);
+        assert_eq!(pts.value, 72_000);
+        assert_eq!(CaptureTime::from(pts), time);
+    }
+
+    #[test]
+    fn arithmetic_keeps_the_left_time_base() {
+        let video = StreamPts::new(2_000_000_000, CaptureTime::TIME_BASE);
+        let audio = StreamPts::new(48_000, AUDIO_TIME_BASE);
+        assert_eq!(
+            video - audio,
+            StreamPts::new(1_000_000_000, CaptureTime::TIME_BASE)
+        );
+        assert_eq!(audio + audio, StreamPts::new(96_000, AUDIO_TIME_BASE));
+    }
+
+    #[test]
+    fn capture_time_differences_are_durations() {
+        let start = CaptureTime::from_nanos(1_000);
+        let end = start + Duration::from_micros(5);
+        assert_eq!(end - start, Duration::from_micros(5));
+        assert_eq!(start - end, Duration::ZERO);
+        assert_eq!(start.abs_diff(end), Duration::from_micros(5));
+    }
+
+    #[test]
+    fn negative_lengths_are_not_durations() {
+        let pts = StreamPts::new(-960, AUDIO_TIME_BASE);
+        assert_eq!(Duration::try_from(pts), Err(TimeOutOfRange));
+        let pts = StreamPts::new(960, AUDIO_TIME_BASE);
+        assert_eq!(Duration::try_from(pts), Ok(Duration::from_millis(20)));
+    }
+}
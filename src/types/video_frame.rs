@@ -1,21 +1,36 @@
-use std::os::fd::RawFd;
+use std::{
+    os::fd::{OwnedFd, RawFd},
+    sync::Arc,
+};
 
 use pipewire::spa::{param::video::VideoFormat, utils::Rectangle};
 
-#[derive(Debug)]
+use super::{
+    config::Transform,
+    time::{CaptureTime, StreamPts},
+};
+
+/// An encoded video packet. Packets come out in decode order, with strictly increasing `dts`.
+///
+/// Without B-frames, the default of [`crate::types::config::VideoConfig::max_b_frames`], they
+/// are shown in that order too and `pts == dts`. With B-frames a packet can be shown after
+/// packets which come later, `pts` is never before `dts`, and the first `dts` lie before the
+/// first `pts` to make room for the reordering.
+#[derive(Debug, Clone)]
 pub struct EncodedVideoFrame {
     pub data: Vec<u8>,
     pub is_keyframe: bool,
     /// Encoder value for when it should be presented (Presentation TimeStamp)
-    pub pts: i64,
+    pub pts: StreamPts,
     /// Encoder value for when it should be decoded (Decode TimeStamp)
-    pub dts: i64,
+    pub dts: StreamPts,
 }
 
 #[derive(Debug)]
 pub struct RawVideoFrame {
     pub data: Vec<u8>,
-    pub timestamp: i64,
+    pub timestamp: CaptureTime,
+    /// First plane of a DMA-BUF frame, see [`Self::planes`] for the others
     pub dmabuf_fd: Option<RawFd>,
     pub stride: i32,
     pub offset: u32,
@@ -23,11 +38,94 @@ pub struct RawVideoFrame {
     pub modifier: u64,
     pub format: VideoFormat,
     pub dimensions: Rectangle,
+    /// How the compositor says the buffer has to be turned, from its metadata, see
+    /// [`crate::types::config::VideoConfig::transform`]
+    pub transform: Transform,
+    /// Set by the capture's keyframe schedule, see
+    /// [`crate::types::config::VideoConfig::keyframe_interval`]. Encoders make this frame a
+    /// keyframe.
+    pub force_keyframe: bool,
+    /// Whether the compositor's damage metadata says the picture changed since the last frame
+    /// sent, `None` when it attaches none. See [`crate::types::config::VideoConfig::dedup`].
+    pub damaged: Option<bool>,
+    /// Every plane of a DMA-BUF frame, e.g. the luma and chroma of NV12, the first one also in
+    /// [`Self::dmabuf_fd`], [`Self::offset`] and [`Self::stride`]. Empty for frames in memory,
+    /// and for single plane frames built without it.
+    pub planes: Vec<DmaBufPlane>,
+    /// Fence the GPU signals once it finished rendering into the DMA-BUF, taken from the buffer
+    /// when it was captured. `None` for frames in memory and on kernels before 6.0, which can't
+    /// hand it out, the DMA-BUF itself is waited on then.
+    pub fence: Option<OwnedFd>,
+    /// Duplicates of the PipeWire buffer's DMA-BUF fds, which [`Self::dmabuf_fd`] and
+    /// [`Self::planes`] of captured frames refer to. The ffmpeg frames the encoders import the
+    /// frame as share them, so the buffer stays open for as long as the driver reads it, even
+    /// after PipeWire recycled it, and the last one to go closes it. Empty for frames in memory.
+    pub owned_fds: Vec<Arc<OwnedFd>>,
 }
 
-#[derive(Debug)]
+#[cfg(test)]
+impl RawVideoFrame {
+    /// An empty `width`x`height` BGRx frame in shared memory with tightly packed rows, for tests
+    /// to fill in with struct update syntax
+    pub(crate) fn bgrx(width: u32, height: u32) -> Self {
+        Self {
+            data: Vec::new(),
+            timestamp: CaptureTime::default(),
+            dmabuf_fd: None,
+            stride: width as i32 * 4,
+            offset: 0,
+            size: width * height * 4,
+            modifier: 0,
+            format: VideoFormat::BGRx,
+            dimensions: Rectangle { width, height },
+            transform: Transform::Normal,
+            force_keyframe: false,
+            damaged: None,
+            planes: Vec::new(),
+            fence: None,
+            owned_fds: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct DmaBufPlane {
     pub fd: i32,
     pub offset: u32,
     pub stride: u32,
 }
+
+/// Video stream parameters negotiated with PipeWire.
+///
+/// Can be saved and handed to [`crate::pipeline::builder::CaptureBuilder::with_fast_start`] on
+/// the next run to skip the renegotiation round trip.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct VideoStreamInfo {
+    pub width: u32,
+    pub height: u32,
+    #[cfg_attr(feature = "serde", serde(with = "raw_video_format"))]
+    pub format: VideoFormat,
+    /// Framerate as `(numerator, denominator)`, `(0, 1)` for variable framerate streams
+    pub framerate: (u32, u32),
+}
+
+/// [`VideoFormat`] is stored as its SPA id, which is stable across PipeWire versions
+#[cfg(feature = "serde")]
+mod raw_video_format {
+    use pipewire::spa::param::video::VideoFormat;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        format: &VideoFormat,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        format.as_raw().serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<VideoFormat, D::Error> {
+        u32::deserialize(deserializer).map(VideoFormat::from_raw)
+    }
+}
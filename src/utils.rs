@@ -1,11 +1,31 @@
+use std::{
+    ffi::c_void,
+    os::fd::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd},
+    ptr::null_mut,
+    sync::Arc,
+};
+
 use crate::types::{
-    error::Result,
+    error::{Result, WaycapError},
     video_frame::{DmaBufPlane, RawVideoFrame},
 };
 
 pub const TIME_UNIT_NS: u64 = 1_000_000_000;
 
+/// `DMA_BUF_IOCTL_SYNC`, brackets CPU access to a DMA-BUF so caches are coherent
+const DMA_BUF_IOCTL_SYNC: libc::Ioctl = 0x4008_6200;
+const DMA_BUF_SYNC_READ: u64 = 1;
+const DMA_BUF_SYNC_START: u64 = 0;
+const DMA_BUF_SYNC_END: u64 = 4;
+/// `DMA_BUF_IOCTL_EXPORT_SYNC_FILE`, takes the fences of a DMA-BUF out as a sync file
+const DMA_BUF_IOCTL_EXPORT_SYNC_FILE: libc::Ioctl = 0xC008_6202;
+/// `DRM_FORMAT_MOD_LINEAR`, the only layout which can be read through a plain mapping
+const MODIFIER_LINEAR: u64 = 0;
+
 pub fn extract_dmabuf_planes(raw_frame: &RawVideoFrame) -> Result<Vec<DmaBufPlane>> {
+    if !raw_frame.planes.is_empty() {
+        return Ok(raw_frame.planes.clone());
+    }
     match raw_frame.dmabuf_fd {
         Some(fd) => Ok(vec![DmaBufPlane {
             fd,
@@ -15,3 +35,89 @@ pub fn extract_dmabuf_planes(raw_frame: &RawVideoFrame) -> Result<Vec<DmaBufPlan
         None => Err("No DMA-BUF file descriptor in frame".into()),
     }
 }
+
+/// Duplicate the DMA-BUF fds of a frame, `fd` and those of its `planes`, each distinct one once,
+/// and point them at the duplicates, which the frame owns from then on
+pub(crate) fn own_dmabuf_fds(
+    fd: &mut Option<RawFd>,
+    planes: &mut [DmaBufPlane],
+) -> Result<Vec<Arc<OwnedFd>>> {
+    let mut owned: Vec<(RawFd, Arc<OwnedFd>)> = Vec::new();
+    for target in fd
+        .iter_mut()
+        .chain(planes.iter_mut().map(|plane| &mut plane.fd))
+    {
+        let duplicate = match owned.iter().find(|(original, _)| original == target) {
+            Some((_, duplicate)) => duplicate.as_raw_fd(),
+            None => {
+                let duplicate = unsafe { BorrowedFd::borrow_raw(*target) }.try_clone_to_owned()?;
+                let raw = duplicate.as_raw_fd();
+                owned.push((*target, Arc::new(duplicate)));
+                raw
+            }
+        };
+        *target = duplicate;
+    }
+    Ok(owned.into_iter().map(|(_, duplicate)| duplicate).collect())
+}
+
+/// A linear DMA-BUF mapped for reading, unmapped when dropped
+pub(crate) struct DmaBufMapping {
+    fd: RawFd,
+    ptr: *mut c_void,
+    len: usize,
+}
+
+impl DmaBufMapping {
+    pub(crate) fn new(fd: RawFd, modifier: u64, len: usize) -> Result<Self> {
+        if modifier != MODIFIER_LINEAR {
+            return Err(WaycapError::Encoding(format!(
+                "Can't read DMA-BUFs with modifier {modifier:#x} on the CPU"
+            )));
+        }
+        let ptr = unsafe { libc::mmap(null_mut(), len, libc::PROT_READ, libc::MAP_SHARED, fd, 0) };
+        if ptr == libc::MAP_FAILED {
+            return Err(WaycapError::Encoding(format!(
+                "Could not map the DMA-BUF: {}",
+                std::io::Error::last_os_error()
+            )));
+        }
+        sync(fd, DMA_BUF_SYNC_START);
+        Ok(Self { fd, ptr, len })
+    }
+
+    pub(crate) fn bytes(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr as *const u8, self.len) }
+    }
+}
+
+impl Drop for DmaBufMapping {
+    fn drop(&mut self) {
+        sync(self.fd, DMA_BUF_SYNC_END);
+        unsafe { libc::munmap(self.ptr, self.len) };
+    }
+}
+
+/// Start or end reading a DMA-BUF. Drivers without the ioctl are coherent anyway.
+fn sync(fd: RawFd, when: u64) {
+    let flags = when | DMA_BUF_SYNC_READ;
+    unsafe { libc::ioctl(fd, DMA_BUF_IOCTL_SYNC, &flags) };
+}
+
+/// `struct dma_buf_export_sync_file`
+#[repr(C)]
+struct DmaBufExportSyncFile {
+    flags: u32,
+    fd: i32,
+}
+
+/// Sync file of the fences a reader of the DMA-BUF `fd` has to wait for, the GPU writes pending
+/// on it right now. `None` on kernels before 6.0.
+pub(crate) fn export_read_fence(fd: RawFd) -> Option<OwnedFd> {
+    let mut export = DmaBufExportSyncFile {
+        flags: DMA_BUF_SYNC_READ as u32,
+        fd: -1,
+    };
+    let ret = unsafe { libc::ioctl(fd, DMA_BUF_IOCTL_EXPORT_SYNC_FILE, &mut export) };
+    (ret == 0 && export.fd >= 0).then(|| unsafe { OwnedFd::from_raw_fd(export.fd) })
+}
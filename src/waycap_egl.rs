@@ -3,13 +3,40 @@ use std::{
     ffi::{c_void, CStr},
 };
 
-use khronos_egl::{self as egl, ClientBuffer, Dynamic, Instance};
-
-use crate::types::{error::Result, video_frame::DmaBufPlane};
+use khronos_egl::{self as egl, ClientBuffer};
+
+use crate::{
+    dmabuf_probe::{self, EglInstance},
+    types::{
+        config::{Rect, Scaling, Transform},
+        error::Result,
+        video_frame::DmaBufPlane,
+    },
+};
 
 type PFNGLEGLIMAGETARGETTEXTURE2DOESPROC =
     unsafe extern "C" fn(target: gl::types::GLenum, image: *const c_void);
 
+const TRANSFORM_VERTEX_SHADER: &CStr = c"
+attribute vec2 position;
+attribute vec2 texcoord;
+varying vec2 v_texcoord;
+void main() {
+    v_texcoord = texcoord;
+    gl_Position = vec4(position, 0.0, 1.0);
+}
+";
+
+const TRANSFORM_FRAGMENT_SHADER: &CStr = c"
+precision mediump float;
+uniform sampler2D image;
+uniform float opacity;
+varying vec2 v_texcoord;
+void main() {
+    gl_FragColor = texture2D(image, v_texcoord) * vec4(1.0, 1.0, 1.0, opacity);
+}
+";
+
 unsafe impl Sync for EglContext {}
 unsafe impl Send for EglContext {}
 
@@ -47,7 +74,7 @@ impl From<&CStr> for GpuVendor {
 }
 
 pub struct EglContext {
-    egl_instance: Instance<Dynamic<libloading::Library, egl::EGL1_5>>,
+    egl_instance: EglInstance,
     display: egl::Display,
     context: egl::Context,
     surface: Option<egl::Surface>, // Optional for surfaceless context
@@ -55,6 +82,11 @@ pub struct EglContext {
     dmabuf_supported: bool,
     dmabuf_modifiers_supported: bool,
     persistent_texture_id: Cell<Option<u32>>,
+    /// Shader program drawing rotated and mirrored frames and the overlay, built with the first
+    /// of them
+    transform_program: Cell<Option<u32>>,
+    /// Image blended onto the frames, see [`Self::draw_overlay`]
+    overlay_texture: Cell<Option<u32>>,
     gpu_vendor: GpuVendor,
     width: i32,
     height: i32,
@@ -134,7 +166,7 @@ impl EglContext {
         gl::load_with(|symbol| egl_instance.get_proc_address(symbol).unwrap() as *const _);
 
         let (dmabuf_supported, dmabuf_modifiers_supported) =
-            Self::check_dmabuf_support(&egl_instance, display).unwrap();
+            dmabuf_probe::dmabuf_support(&egl_instance, display).unwrap();
 
         let gpu_vendor = get_gpu_vendor();
 
@@ -147,6 +179,8 @@ impl EglContext {
             dmabuf_supported,
             dmabuf_modifiers_supported,
             persistent_texture_id: Cell::new(None),
+            transform_program: Cell::new(None),
+            overlay_texture: Cell::new(None),
             gpu_vendor,
             width,
             height,
@@ -155,7 +189,21 @@ impl EglContext {
         })
     }
 
-    pub fn update_texture_from_image(&self, egl_image: egl::Image) -> Result<()> {
+    /// Copy the source rect of the `image_width`x`image_height` image into the persistent
+    /// texture, turned and scaled to the picture rect of `scaling`
+    pub fn update_texture_from_image(
+        &self,
+        egl_image: egl::Image,
+        (image_width, image_height): (u32, u32),
+        scaling: &Scaling,
+        pad_color: [u8; 3],
+    ) -> Result<()> {
+        let Scaling {
+            source,
+            picture,
+            transform,
+            ..
+        } = *scaling;
         assert!(self.persistent_texture_id.get().is_some());
 
         unsafe {
@@ -190,11 +238,19 @@ impl EglContext {
                 );
             }
 
-            // Get dimensions from the EGL image texture
-            let mut width = 0;
-            let mut height = 0;
-            gl::GetTexLevelParameteriv(gl::TEXTURE_2D, 0, gl::TEXTURE_WIDTH, &mut width);
-            gl::GetTexLevelParameteriv(gl::TEXTURE_2D, 0, gl::TEXTURE_HEIGHT, &mut height);
+            let (x, y, width, height) = (
+                source.x as i32,
+                source.y as i32,
+                source.width as i32,
+                source.height as i32,
+            );
+            let (picture_x, picture_y, picture_width, picture_height) = (
+                picture.x as i32,
+                picture.y as i32,
+                picture.width as i32,
+                picture.height as i32,
+            );
+            let padded = (picture_width, picture_height) != (self.width, self.height);
 
             // Create framebuffer for copying
             let mut fbo = 0;
@@ -222,18 +278,78 @@ impl EglContext {
             // Bind persistent texture as destination
             gl::BindTexture(gl::TEXTURE_2D, self.persistent_texture_id.get().unwrap());
 
-            // Use CopyTexSubImage2D instead of CopyTexImage2D
-            // This updates existing texture data rather than reallocating
-            gl::CopyTexSubImage2D(
-                gl::TEXTURE_2D,
-                0, // mipmap level
-                0,
-                0, // destination x, y offset in texture
-                0,
-                0,      // source x, y offset in framebuffer
-                width,  // width to copy
-                height, // height to copy
-            );
+            if !padded
+                && transform == Transform::Normal
+                && (width, height) == (self.width, self.height)
+            {
+                // Use CopyTexSubImage2D instead of CopyTexImage2D
+                // This updates existing texture data rather than reallocating
+                gl::CopyTexSubImage2D(
+                    gl::TEXTURE_2D,
+                    0, // mipmap level
+                    0,
+                    0, // destination x, y offset in texture
+                    x,
+                    y,      // source x, y offset in framebuffer
+                    width,  // width to copy
+                    height, // height to copy
+                );
+            } else {
+                // Scaled to the size of the texture, the image framebuffer stays the read one
+                let mut draw_fbo = 0;
+                gl::GenFramebuffers(1, &mut draw_fbo);
+                gl::BindFramebuffer(gl::DRAW_FRAMEBUFFER, draw_fbo);
+                gl::FramebufferTexture2D(
+                    gl::DRAW_FRAMEBUFFER,
+                    gl::COLOR_ATTACHMENT0,
+                    gl::TEXTURE_2D,
+                    self.persistent_texture_id.get().unwrap(),
+                    0,
+                );
+                if padded {
+                    // The bars are cleared with every frame, it costs next to nothing and
+                    // survives the texture being resized
+                    let [red, green, blue] = pad_color.map(|value| f32::from(value) / 255.0);
+                    gl::ClearColor(red, green, blue, 1.0);
+                    gl::Clear(gl::COLOR_BUFFER_BIT);
+                }
+                if transform == Transform::Normal {
+                    gl::BlitFramebuffer(
+                        x,
+                        y,
+                        x + width,
+                        y + height,
+                        picture_x,
+                        picture_y,
+                        picture_x + picture_width,
+                        picture_y + picture_height,
+                        gl::COLOR_BUFFER_BIT,
+                        gl::LINEAR,
+                    );
+                } else {
+                    // Blits can't swap the axes, the image is drawn onto the texture instead
+                    let program = match self.transform_program() {
+                        Ok(program) => program,
+                        Err(e) => {
+                            gl::BindFramebuffer(gl::FRAMEBUFFER, 0);
+                            gl::DeleteFramebuffers(2, [fbo, draw_fbo].as_ptr());
+                            gl::DeleteTextures(1, &temp_texture);
+                            return Err(e);
+                        }
+                    };
+                    gl::Viewport(picture_x, picture_y, picture_width, picture_height);
+                    draw_transformed(
+                        program,
+                        temp_texture,
+                        source,
+                        (image_width, image_height),
+                        transform,
+                        1.0,
+                    );
+                }
+                gl::BindFramebuffer(gl::DRAW_FRAMEBUFFER, 0);
+                gl::DeleteFramebuffers(1, &draw_fbo);
+            }
 
             let gl_error = gl::GetError();
             if gl_error != gl::NO_ERROR {
@@ -254,6 +370,298 @@ impl EglContext {
         }
     }
 
+    /// Upload the image [`Self::draw_overlay`] draws, or delete it for None
+    pub fn set_overlay_image(&self, image: Option<&image::RgbaImage>) -> Result<()> {
+        if let Some(texture) = self.overlay_texture.take() {
+            self.delete_texture(texture);
+        }
+        let Some(image) = image else {
+            return Ok(());
+        };
+        unsafe {
+            let mut texture = 0;
+            gl::GenTextures(1, &mut texture);
+            gl::BindTexture(gl::TEXTURE_2D, texture);
+            gl::TexImage2D(
+                gl::TEXTURE_2D,
+                0,
+                gl::RGBA as i32,
+                image.width() as i32,
+                image.height() as i32,
+                0,
+                gl::RGBA,
+                gl::UNSIGNED_BYTE,
+                image.as_raw().as_ptr().cast(),
+            );
+            gl::BindTexture(gl::TEXTURE_2D, 0);
+            let gl_error = gl::GetError();
+            if gl_error != gl::NO_ERROR {
+                gl::DeleteTextures(1, &texture);
+                return Err(format!("Failed to upload the overlay: 0x{gl_error:x}").into());
+            }
+            self.overlay_texture.set(Some(texture));
+        }
+        Ok(())
+    }
+
+    /// Blend the uploaded overlay onto `placement` of the persistent texture, its alpha times
+    /// `opacity`. Nothing without one.
+    pub fn draw_overlay(&self, placement: Rect, opacity: f32) -> Result<()> {
+        let (Some(overlay), Some(target)) =
+            (self.overlay_texture.get(), self.persistent_texture_id.get())
+        else {
+            return Ok(());
+        };
+        let program = self.transform_program()?;
+        unsafe {
+            let mut fbo = 0;
+            gl::GenFramebuffers(1, &mut fbo);
+            gl::BindFramebuffer(gl::FRAMEBUFFER, fbo);
+            gl::FramebufferTexture2D(
+                gl::FRAMEBUFFER,
+                gl::COLOR_ATTACHMENT0,
+                gl::TEXTURE_2D,
+                target,
+                0,
+            );
+            gl::Viewport(
+                placement.x as i32,
+                placement.y as i32,
+                placement.width as i32,
+                placement.height as i32,
+            );
+            gl::Enable(gl::BLEND);
+            // The frame's alpha is left as it is
+            gl::BlendFuncSeparate(gl::SRC_ALPHA, gl::ONE_MINUS_SRC_ALPHA, gl::ZERO, gl::ONE);
+            let whole = Rect {
+                x: 0,
+                y: 0,
+                ..placement
+            };
+            draw_transformed(
+                program,
+                overlay,
+                whole,
+                (placement.width, placement.height),
+                Transform::Normal,
+                opacity,
+            );
+            gl::Disable(gl::BLEND);
+            gl::BindTexture(gl::TEXTURE_2D, 0);
+            gl::BindFramebuffer(gl::FRAMEBUFFER, 0);
+            gl::DeleteFramebuffers(1, &fbo);
+
+            let gl_error = gl::GetError();
+            if gl_error != gl::NO_ERROR {
+                return Err(format!("Failed to draw the overlay: 0x{gl_error:x}").into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Program of [`draw_transformed`], compiled on first use
+    fn transform_program(&self) -> Result<u32> {
+        if let Some(program) = self.transform_program.get() {
+            return Ok(program);
+        }
+        unsafe {
+            let program = gl::CreateProgram();
+            for (kind, source) in [
+                (gl::VERTEX_SHADER, TRANSFORM_VERTEX_SHADER),
+                (gl::FRAGMENT_SHADER, TRANSFORM_FRAGMENT_SHADER),
+            ] {
+                let shader = gl::CreateShader(kind);
+                gl::ShaderSource(shader, 1, &source.as_ptr(), std::ptr::null());
+                gl::CompileShader(shader);
+                let mut compiled = 0;
+                gl::GetShaderiv(shader, gl::COMPILE_STATUS, &mut compiled);
+                gl::AttachShader(program, shader);
+                // Stays alive while attached
+                gl::DeleteShader(shader);
+                if compiled == 0 {
+                    gl::DeleteProgram(program);
+                    return Err("Could not compile the transform shaders".into());
+                }
+            }
+            gl::BindAttribLocation(program, 0, c"position".as_ptr());
+            gl::BindAttribLocation(program, 1, c"texcoord".as_ptr());
+            gl::LinkProgram(program);
+            let mut linked = 0;
+            gl::GetProgramiv(program, gl::LINK_STATUS, &mut linked);
+            if linked == 0 {
+                gl::DeleteProgram(program);
+                return Err("Could not link the transform shaders".into());
+            }
+            self.transform_program.set(Some(program));
+            Ok(program)
+        }
+    }
+
+    /// Copy the top left `width`x`height` pixels of `source` into `target`, e.g. a DMA-BUF owned
+    /// by another API. Waits for the GPU, so `target` can be used as soon as this returns.
+    pub fn copy_image(
+        &self,
+        source: egl::Image,
+        target: egl::Image,
+        width: i32,
+        height: i32,
+    ) -> Result<()> {
+        unsafe {
+            let proc_addr = self
+                .egl_instance
+                .get_proc_address("glEGLImageTargetTexture2DOES");
+            if proc_addr.is_none() {
+                return Err("glEGLImageTargetTexture2DOES not available".into());
+            }
+            let egl_texture_2d = std::mem::transmute::<
+                Option<extern "system" fn()>,
+                PFNGLEGLIMAGETARGETTEXTURE2DOESPROC,
+            >(proc_addr);
+
+            let mut textures = [0; 2];
+            gl::GenTextures(2, textures.as_mut_ptr());
+            let mut fbo = 0;
+            gl::GenFramebuffers(1, &mut fbo);
+            let cleanup = || {
+                gl::BindTexture(gl::TEXTURE_2D, 0);
+                gl::BindFramebuffer(gl::FRAMEBUFFER, 0);
+                gl::DeleteFramebuffers(1, &fbo);
+                gl::DeleteTextures(2, textures.as_ptr());
+            };
+
+            gl::BindTexture(gl::TEXTURE_2D, textures[0]);
+            egl_texture_2d(gl::TEXTURE_2D, source.as_ptr());
+            gl::BindFramebuffer(gl::FRAMEBUFFER, fbo);
+            gl::FramebufferTexture2D(
+                gl::FRAMEBUFFER,
+                gl::COLOR_ATTACHMENT0,
+                gl::TEXTURE_2D,
+                textures[0],
+                0,
+            );
+            let status = gl::CheckFramebufferStatus(gl::FRAMEBUFFER);
+            if status != gl::FRAMEBUFFER_COMPLETE {
+                cleanup();
+                return Err(format!("Framebuffer not complete: 0x{status:x}").into());
+            }
+
+            gl::BindTexture(gl::TEXTURE_2D, textures[1]);
+            egl_texture_2d(gl::TEXTURE_2D, target.as_ptr());
+            gl::CopyTexSubImage2D(gl::TEXTURE_2D, 0, 0, 0, 0, 0, width, height);
+            gl::Finish();
+
+            let gl_error = gl::GetError();
+            cleanup();
+            if gl_error != gl::NO_ERROR {
+                return Err(format!("Failed to copy into the target image: 0x{gl_error:x}").into());
+            }
+            Ok(())
+        }
+    }
+
+    /// Scale the `width`x`height` `source` to `target_width`x`target_height` on the GPU and read
+    /// it back as RGBA rows, in the order of the image's rows
+    pub fn read_scaled(
+        &self,
+        source: egl::Image,
+        (width, height): (u32, u32),
+        (target_width, target_height): (u32, u32),
+    ) -> Result<Vec<u8>> {
+        unsafe {
+            let proc_addr = self
+                .egl_instance
+                .get_proc_address("glEGLImageTargetTexture2DOES");
+            if proc_addr.is_none() {
+                return Err("glEGLImageTargetTexture2DOES not available".into());
+            }
+            let egl_texture_2d = std::mem::transmute::<
+                Option<extern "system" fn()>,
+                PFNGLEGLIMAGETARGETTEXTURE2DOESPROC,
+            >(proc_addr);
+
+            let mut textures = [0; 2];
+            gl::GenTextures(2, textures.as_mut_ptr());
+            let mut fbos = [0; 2];
+            gl::GenFramebuffers(2, fbos.as_mut_ptr());
+            let cleanup = || {
+                gl::BindTexture(gl::TEXTURE_2D, 0);
+                gl::BindFramebuffer(gl::FRAMEBUFFER, 0);
+                gl::DeleteFramebuffers(2, fbos.as_ptr());
+                gl::DeleteTextures(2, textures.as_ptr());
+            };
+
+            gl::BindTexture(gl::TEXTURE_2D, textures[0]);
+            egl_texture_2d(gl::TEXTURE_2D, source.as_ptr());
+            gl::BindFramebuffer(gl::READ_FRAMEBUFFER, fbos[0]);
+            gl::FramebufferTexture2D(
+                gl::READ_FRAMEBUFFER,
+                gl::COLOR_ATTACHMENT0,
+                gl::TEXTURE_2D,
+                textures[0],
+                0,
+            );
+            gl::BindTexture(gl::TEXTURE_2D, textures[1]);
+            gl::TexImage2D(
+                gl::TEXTURE_2D,
+                0,
+                gl::RGBA as i32,
+                target_width as i32,
+                target_height as i32,
+                0,
+                gl::RGBA,
+                gl::UNSIGNED_BYTE,
+                std::ptr::null(),
+            );
+            gl::BindFramebuffer(gl::DRAW_FRAMEBUFFER, fbos[1]);
+            gl::FramebufferTexture2D(
+                gl::DRAW_FRAMEBUFFER,
+                gl::COLOR_ATTACHMENT0,
+                gl::TEXTURE_2D,
+                textures[1],
+                0,
+            );
+            for target in [gl::READ_FRAMEBUFFER, gl::DRAW_FRAMEBUFFER] {
+                let status = gl::CheckFramebufferStatus(target);
+                if status != gl::FRAMEBUFFER_COMPLETE {
+                    cleanup();
+                    return Err(format!("Framebuffer not complete: 0x{status:x}").into());
+                }
+            }
+
+            gl::BlitFramebuffer(
+                0,
+                0,
+                width as i32,
+                height as i32,
+                0,
+                0,
+                target_width as i32,
+                target_height as i32,
+                gl::COLOR_BUFFER_BIT,
+                gl::LINEAR,
+            );
+            // Read back from the scaled texture, the rows come out in the order they were blit
+            gl::BindFramebuffer(gl::READ_FRAMEBUFFER, fbos[1]);
+            let mut pixels = vec![0; target_width as usize * target_height as usize * 4];
+            gl::ReadPixels(
+                0,
+                0,
+                target_width as i32,
+                target_height as i32,
+                gl::RGBA,
+                gl::UNSIGNED_BYTE,
+                pixels.as_mut_ptr().cast(),
+            );
+
+            let gl_error = gl::GetError();
+            cleanup();
+            if gl_error != gl::NO_ERROR {
+                return Err(format!("Failed to read back the scaled image: 0x{gl_error:x}").into());
+            }
+            Ok(pixels)
+        }
+    }
+
     pub fn create_persistent_texture(&self) -> Result<()> {
         unsafe {
             let mut texture_id = 0;
@@ -297,23 +705,6 @@ impl EglContext {
         }
     }
 
-    fn check_dmabuf_support(
-        egl_instance: &Instance<Dynamic<libloading::Library, egl::EGL1_5>>,
-        display: egl::Display,
-    ) -> Result<(bool, bool)> {
-        let extensions = egl_instance.query_string(Some(display), egl::EXTENSIONS)?;
-        let ext_str = extensions.to_string_lossy();
-
-        let dmabuf_import = ext_str.contains("EGL_EXT_image_dma_buf_import");
-        let dmabuf_modifiers = ext_str.contains("EGL_EXT_image_dma_buf_import_modifiers");
-
-        if !dmabuf_import {
-            return Err("EGL_EXT_image_dma_buf_import not supported".into());
-        }
-
-        Ok((dmabuf_import, dmabuf_modifiers))
-    }
-
     pub fn create_image_from_dmabuf(
         &self,
         planes: &[DmaBufPlane],
@@ -459,6 +850,19 @@ impl EglContext {
         self.persistent_texture_id.get()
     }
 
+    pub fn texture_size(&self) -> (i32, i32) {
+        (self.width, self.height)
+    }
+
+    /// Delete the persistent texture, the next one is created at `width`x`height`
+    pub fn resize_texture(&mut self, width: i32, height: i32) {
+        if let Some(texture_id) = self.persistent_texture_id.take() {
+            self.delete_texture(texture_id);
+        }
+        self.width = width;
+        self.height = height;
+    }
+
     pub fn get_gpu_vendor(&self) -> GpuVendor {
         self.gpu_vendor
     }
@@ -478,12 +882,64 @@ impl Drop for EglContext {
             .egl_instance
             .destroy_context(self.display, self.context);
         let _ = self.egl_instance.terminate(self.display);
-        if let Some(texture) = self.persistent_texture_id.get() {
+        for texture in [self.persistent_texture_id.get(), self.overlay_texture.get()]
+            .into_iter()
+            .flatten()
+        {
             self.delete_texture(texture);
         }
     }
 }
 
+/// Draw `source` of `texture` over the viewport, each corner where `transform` puts it and its
+/// alpha times `opacity`
+///
+/// # Safety
+/// Needs a current context with the target framebuffer bound for drawing
+unsafe fn draw_transformed(
+    program: u32,
+    texture: u32,
+    source: Rect,
+    (image_width, image_height): (u32, u32),
+    transform: Transform,
+    opacity: f32,
+) {
+    // The corners in strip order, the transform keeps the two triangles covering the quad
+    let corners = [(0, 0), (1, 0), (0, 1), (1, 1)];
+    let mut positions = [0f32; 8];
+    let mut texcoords = [0f32; 8];
+    for (index, (x, y)) in corners.into_iter().enumerate() {
+        let (target_x, target_y) = transform.map(x, y, 2, 2);
+        positions[2 * index] = target_x as f32 * 2.0 - 1.0;
+        positions[2 * index + 1] = target_y as f32 * 2.0 - 1.0;
+        texcoords[2 * index] = (source.x + x * source.width) as f32 / image_width as f32;
+        texcoords[2 * index + 1] = (source.y + y * source.height) as f32 / image_height as f32;
+    }
+
+    gl::BindTexture(gl::TEXTURE_2D, texture);
+    // The image has no mipmaps, sampling it needs a filter without them
+    gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, gl::LINEAR as i32);
+    gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, gl::LINEAR as i32);
+    gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_S, gl::CLAMP_TO_EDGE as i32);
+    gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_T, gl::CLAMP_TO_EDGE as i32);
+
+    gl::UseProgram(program);
+    gl::Uniform1i(gl::GetUniformLocation(program, c"image".as_ptr()), 0);
+    gl::Uniform1f(
+        gl::GetUniformLocation(program, c"opacity".as_ptr()),
+        opacity,
+    );
+    gl::BindBuffer(gl::ARRAY_BUFFER, 0);
+    gl::EnableVertexAttribArray(0);
+    gl::EnableVertexAttribArray(1);
+    gl::VertexAttribPointer(0, 2, gl::FLOAT, gl::FALSE, 0, positions.as_ptr().cast());
+    gl::VertexAttribPointer(1, 2, gl::FLOAT, gl::FALSE, 0, texcoords.as_ptr().cast());
+    gl::DrawArrays(gl::TRIANGLE_STRIP, 0, 4);
+    gl::DisableVertexAttribArray(0);
+    gl::DisableVertexAttribArray(1);
+    gl::UseProgram(0);
+}
+
 fn get_gpu_vendor() -> GpuVendor {
     unsafe {
         let vendor_ptr = gl::GetString(gl::VENDOR);
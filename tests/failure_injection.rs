@@ -0,0 +1,90 @@
+//! Robustness tests driving the failure injection hooks through a whole capture.
+//!
+//! The captures record the synthetic source with x264, so they run headless, through the same
+//! builder, processing loop and shutdown as a portal capture. Run them with
+//! `cargo test --features failure-injection`.
+#![cfg(feature = "failure-injection")]
+
+use std::time::Duration;
+
+use crossbeam::channel::Receiver;
+use waycap_rs::{
+    pipeline::builder::CaptureBuilder,
+    types::{
+        config::{QualityPreset, VideoEncoder},
+        video_frame::EncodedVideoFrame,
+    },
+    Capture, DynamicEncoder,
+};
+
+fn start_capture() -> (Capture<DynamicEncoder>, Receiver<EncodedVideoFrame>) {
+    let mut capture = CaptureBuilder::new()
+        .with_quality_preset(QualityPreset::Low)
+        .with_synthetic_source(64, 48)
+        .with_video_encoder(VideoEncoder::H264Software)
+        .build()
+        .expect("Failed to build capture");
+    let video_recv = capture
+        .get_video_receiver()
+        .expect("Failed to get video receiver");
+    capture.start().expect("Failed to start capture");
+    (capture, video_recv)
+}
+
+/// Wait until the receiver went quiet for `quiet` and return the last frame received
+fn drain(recv: &Receiver<EncodedVideoFrame>, quiet: Duration) -> Option<EncodedVideoFrame> {
+    let mut last = None;
+    while let Ok(frame) = recv.recv_timeout(quiet) {
+        last = Some(frame);
+    }
+    last
+}
+
+#[test]
+fn stream_error_stops_video_and_closes_cleanly() {
+    let (mut capture, video_recv) = start_capture();
+    let controls = capture.controls();
+
+    assert!(video_recv.recv_timeout(Duration::from_secs(5)).is_ok());
+    controls.failure_injector().trigger_stream_error();
+    drain(&video_recv, Duration::from_millis(500));
+    assert!(video_recv.recv_timeout(Duration::from_secs(1)).is_err());
+
+    capture.close().unwrap();
+}
+
+#[test]
+fn encoder_error_stops_video_and_closes_cleanly() {
+    let (mut capture, video_recv) = start_capture();
+    let controls = capture.controls();
+
+    assert!(video_recv.recv_timeout(Duration::from_secs(5)).is_ok());
+    controls.failure_injector().fail_encoder_frames(1);
+    drain(&video_recv, Duration::from_millis(500));
+    assert!(video_recv.recv_timeout(Duration::from_secs(1)).is_err());
+    // The source keeps going while nothing is encoded anymore
+    let before = capture.stats();
+    std::thread::sleep(Duration::from_millis(200));
+    let after = capture.stats();
+    assert!(after.frames_captured > before.frames_captured);
+    assert_eq!(after.frames_encoded, before.frames_encoded);
+
+    capture.close().unwrap();
+}
+
+#[test]
+fn full_channel_drops_frames_at_capture_then_recovers() {
+    let (mut capture, video_recv) = start_capture();
+    let controls = capture.controls();
+
+    assert!(video_recv.recv_timeout(Duration::from_secs(5)).is_ok());
+    controls.failure_injector().set_channel_full(true);
+    // Anything queued before is still encoded
+    drain(&video_recv, Duration::from_millis(300));
+    assert!(capture.stats().frames_dropped_at_capture > 0);
+
+    controls.failure_injector().set_channel_full(false);
+    assert!(video_recv.recv_timeout(Duration::from_secs(5)).is_ok());
+
+    capture.close().unwrap();
+}